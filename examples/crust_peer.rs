@@ -282,7 +282,7 @@ fn main() {
                 ::maidsafe_utilities::event_sender::MaidSafeEventCategory::Crust => {
                     if let Ok(event) = channel_receiver.try_recv() {
                         match event {
-                            crust::Event::NewMessage(peer_id, _, bytes) => {
+                            crust::Event::NewMessage(peer_id, _, bytes, _) => {
                                 let message_length = bytes.len();
                                 let mut network = unwrap!(network2.lock());
                                 network.record_received(message_length);
@@ -321,7 +321,7 @@ fn main() {
                                     panic!("Got the same result_token twice!");
                                 };
                             }
-                            crust::Event::BootstrapConnect(peer_id, addr) => {
+                            crust::Event::BootstrapConnect(peer_id, addr, _) => {
                                 println!(
                                     "\nBootstrapConnect with peer {:?} (address: <{:?}>)",
                                     peer_id, addr
@@ -333,7 +333,7 @@ fn main() {
                                 );
                                 let _ = bs_sender.send(peer_index);
                             }
-                            crust::Event::BootstrapAccept(peer_id, _) => {
+                            crust::Event::BootstrapAccept(peer_id, _, _) => {
                                 println!("\nBootstrapAccept with peer {:?}", peer_id);
                                 let peer_index = handle_new_peer(
                                     &unwrap!(service.lock()),
@@ -342,7 +342,7 @@ fn main() {
                                 );
                                 let _ = bs_sender.send(peer_index);
                             }
-                            crust::Event::ConnectSuccess(peer_id) => {
+                            crust::Event::ConnectSuccess(peer_id, _) => {
                                 println!("\nConnected to peer {:?}", peer_id);
                                 let _ = handle_new_peer(
                                     &unwrap!(service.lock()),
@@ -350,7 +350,7 @@ fn main() {
                                     peer_id,
                                 );
                             }
-                            crust::Event::LostPeer(peer_id) => {
+                            crust::Event::LostPeer(peer_id, _, _) => {
                                 println!("\nLost connection to peer {:?}", peer_id);
                                 let mut index = None;
                                 {
@@ -419,7 +419,7 @@ fn main() {
                 unwrap!(unwrap!(service.lock()).send(
                     peer_id,
                     generate_random_vec_u8(length as usize),
-                    0,
+                    1,
                 ));
                 debug!(
                     "Sent a message with length of {} bytes to {:?}",
@@ -482,7 +482,7 @@ fn main() {
                             unwrap!(unwrap!(service.lock()).send(
                                 *peer_id,
                                 message.into_bytes(),
-                                0,
+                                1,
                             ));
                         }
                         None => println!("Invalid connection #"),
@@ -492,7 +492,7 @@ fn main() {
                     let mut network = unwrap!(network.lock());
                     let msg = message.into_bytes();
                     for peer_id in network.nodes.values_mut() {
-                        unwrap!(unwrap!(service.lock()).send(peer_id, msg.clone(), 0));
+                        unwrap!(unwrap!(service.lock()).send(peer_id, msg.clone(), 1));
                     }
                 }
                 UserCommand::List => {
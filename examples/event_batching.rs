@@ -0,0 +1,143 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Benchmark of the rate at which small messages are delivered to the application, one event per
+//! message and with `Config::event_batching`. Run with `--release`, optionally passing the number
+//! of messages and their size in bytes.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    exceeding_bitshifts, mutable_transmutes, no_mangle_const_items, unknown_crate_types, warnings
+)]
+#![deny(
+    bad_style, deprecated, improper_ctypes, missing_docs, non_shorthand_field_patterns,
+    overflowing_literals, plugin_as_library, private_no_mangle_fns, private_no_mangle_statics,
+    stable_features, unconditional_recursion, unknown_lints, unsafe_code, unused, unused_allocation,
+    unused_attributes, unused_comparisons, unused_features, unused_parens, while_true
+)]
+#![warn(
+    trivial_casts, trivial_numeric_casts, unused_extern_crates, unused_import_braces,
+    unused_qualifications, unused_results
+)]
+#![allow(
+    box_pointers, missing_copy_implementations, missing_debug_implementations,
+    variant_size_differences
+)]
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate unwrap;
+extern crate crust;
+extern crate maidsafe_utilities;
+extern crate rand;
+
+use crust::{Config, CrustUser, Event, EventBatching, Uid};
+use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
+use rand::{Rand, Rng};
+use std::collections::HashSet;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MSGS: usize = 200_000;
+const DEFAULT_MSG_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+struct UniqueId([u8; 20]);
+impl Uid for UniqueId {}
+impl Rand for UniqueId {
+    fn rand<R: Rng>(rng: &mut R) -> Self {
+        let mut inner = [0; 20];
+        rng.fill_bytes(&mut inner);
+        UniqueId(inner)
+    }
+}
+
+type Service = crust::Service<UniqueId>;
+
+fn service(config: Config) -> (Service, Receiver<Event<UniqueId>>) {
+    let (event_tx, event_rx) = mpsc::channel();
+    let (category_tx, _) = mpsc::channel();
+    let event_tx = MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx);
+    let mut config = config;
+    config.bootstrap_cache_name = Some(format!("event_batching_{}.cache", rand::random::<u64>()));
+    (unwrap!(Service::with_config(event_tx, config, rand::random())), event_rx)
+}
+
+/// Sends `msgs` messages of `msg_size` bytes between two services on localhost and returns the
+/// messages per second delivered to the receiving application.
+fn run(event_batching: Option<EventBatching>, msgs: usize, msg_size: usize) -> f64 {
+    let mut receiver_config = Config::default();
+    receiver_config.event_batching = event_batching;
+    let (mut receiver, receiver_rx) = service(receiver_config);
+    unwrap!(receiver.start_listening_tcp());
+    let port = match unwrap!(receiver_rx.recv()) {
+        Event::ListenerStarted(port) => port,
+        event => panic!("Unexpected event: {:?}", event),
+    };
+    unwrap!(receiver.set_accept_bootstrap(true));
+
+    let mut sender_config = Config::default();
+    sender_config.hard_coded_contacts = vec![SocketAddr::new(unwrap!("127.0.0.1".parse()), port)];
+    let (mut sender, sender_rx) = service(sender_config);
+    unwrap!(sender.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let receiver_id = match unwrap!(sender_rx.recv()) {
+        Event::BootstrapConnect(id, _, _) => id,
+        event => panic!("Unexpected event: {:?}", event),
+    };
+    match unwrap!(receiver_rx.recv()) {
+        Event::BootstrapAccept(..) => (),
+        event => panic!("Unexpected event: {:?}", event),
+    }
+
+    let msg = vec![0; msg_size];
+    let start = Instant::now();
+    for _ in 0..msgs {
+        unwrap!(sender.send(&receiver_id, msg.clone(), 1));
+    }
+
+    let mut received = 0;
+    while received < msgs {
+        match unwrap!(receiver_rx.recv_timeout(Duration::from_secs(30))) {
+            Event::NewMessage(..) => received += 1,
+            Event::NewMessages(_, _, batch, _) => received += batch.len(),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+    let elapsed = start.elapsed();
+    msgs as f64 / (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let msgs = args.next().map_or(DEFAULT_MSGS, |arg| unwrap!(arg.parse()));
+    let msg_size = args.next().map_or(DEFAULT_MSG_SIZE, |arg| unwrap!(arg.parse()));
+    println!("Delivering {} messages of {} bytes", msgs, msg_size);
+
+    let unbatched = run(None, msgs, msg_size);
+    println!("unbatched:           {:>12.0} msgs/s", unbatched);
+
+    for &(max_batch, max_delay_us) in &[(64, 0), (256, 1_000)] {
+        let batching = EventBatching {
+            max_batch,
+            max_delay_us,
+        };
+        let rate = run(Some(batching), msgs, msg_size);
+        println!(
+            "batches of {:>4}, {:>4}us: {:>12.0} msgs/s ({:.1}x)",
+            max_batch,
+            max_delay_us,
+            rate,
+            rate / unbatched
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Writes the wire protocol fixtures missing, each under the protocol version its message appeared
+//! in, see `test_vectors/`. Takes the directory to write them under, `test_vectors` by default.
+//! Existing fixtures are never changed: a change to the wire format needs a new protocol version.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    exceeding_bitshifts, mutable_transmutes, no_mangle_const_items, unknown_crate_types, warnings
+)]
+#![deny(
+    bad_style, deprecated, improper_ctypes, missing_docs, non_shorthand_field_patterns,
+    overflowing_literals, plugin_as_library, private_no_mangle_fns, private_no_mangle_statics,
+    stable_features, unconditional_recursion, unknown_lints, unsafe_code, unused, unused_allocation,
+    unused_attributes, unused_comparisons, unused_features, unused_parens, while_true
+)]
+#![warn(
+    trivial_casts, trivial_numeric_casts, unused_extern_crates, unused_import_braces,
+    unused_qualifications, unused_results
+)]
+#![allow(
+    box_pointers, missing_copy_implementations, missing_debug_implementations,
+    variant_size_differences
+)]
+
+extern crate crust;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+fn main() {
+    let dir = env::args()
+        .nth(1)
+        .map_or_else(|| PathBuf::from("test_vectors"), PathBuf::from);
+    match crust::test_vectors::generate(&dir) {
+        Ok(ref written) if written.is_empty() => println!("All fixtures are up to date"),
+        Ok(written) => {
+            for path in written {
+                println!("Wrote {}", path.display());
+            }
+        }
+        Err(e) => {
+            println!("Could not write the fixtures: {}", e);
+            process::exit(1);
+        }
+    }
+}
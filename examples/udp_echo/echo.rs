@@ -0,0 +1,89 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! A UDP echo responder run on the event loop of a `Service`, shared with the integration tests.
+
+use crust::{ExternalCore, ExternalState, Service, Uid};
+use mio::net::UdpSocket;
+use mio::{Poll, PollOpt, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+/// Largest datagram echoed in full.
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// Sends every datagram arriving at its socket back to where it came from.
+pub struct UdpEcho {
+    token: Token,
+    socket: UdpSocket,
+}
+
+impl UdpEcho {
+    /// Binds `addr` on the event loop of `service` and starts echoing. Returns the address bound.
+    pub fn start<UID: Uid>(service: &Service<UID>, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let (tx, rx) = mpsc::channel();
+        service
+            .register_state(move |core, poll| {
+                let _ = tx.send(UdpEcho::register(core, poll, &addr));
+            })
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+        rx.recv().map_err(|e| io::Error::new(ErrorKind::Other, e))?
+    }
+
+    fn register(core: &mut ExternalCore, poll: &Poll, addr: &SocketAddr) -> io::Result<SocketAddr> {
+        let socket = UdpSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        let token = core.get_new_token();
+        poll.register(&socket, token, Ready::readable(), PollOpt::edge())?;
+        let echo = Rc::new(RefCell::new(UdpEcho { token, socket }));
+        let _ = core.insert_state(token, echo);
+        Ok(local_addr)
+    }
+}
+
+impl ExternalState for UdpEcho {
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+
+    fn name(&self) -> &'static str {
+        "UdpEcho"
+    }
+
+    fn ready(&mut self, core: &mut ExternalCore, poll: &Poll, kind: Ready) {
+        if !kind.is_readable() {
+            return;
+        }
+        // The socket is edge-triggered, so it has to be read until it would block.
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => {
+                    // Lost like any datagram if the socket's buffer is full.
+                    let _ = self.socket.send_to(&buf[..len], &from);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    println!("UDP echo failed: {}", e);
+                    return self.terminate(core, poll);
+                }
+            }
+        }
+    }
+
+    fn terminate(&mut self, core: &mut ExternalCore, poll: &Poll) {
+        let _ = poll.deregister(&self.socket);
+        let _ = core.remove_state(self.token);
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Runs a protocol of the application's own on the event loop of a `Service`, rather than on a
+//! thread and event loop of its own: a UDP echo responder, see `echo::UdpEcho`. Optionally takes
+//! the address to bind, and echoes until killed.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    exceeding_bitshifts, mutable_transmutes, no_mangle_const_items, unknown_crate_types, warnings
+)]
+#![deny(
+    bad_style, deprecated, improper_ctypes, missing_docs, non_shorthand_field_patterns,
+    overflowing_literals, plugin_as_library, private_no_mangle_fns, private_no_mangle_statics,
+    stable_features, unconditional_recursion, unknown_lints, unsafe_code, unused, unused_allocation,
+    unused_attributes, unused_comparisons, unused_features, unused_parens, while_true
+)]
+#![warn(
+    trivial_casts, trivial_numeric_casts, unused_extern_crates, unused_import_braces,
+    unused_qualifications, unused_results
+)]
+#![allow(
+    box_pointers, missing_copy_implementations, missing_debug_implementations,
+    variant_size_differences
+)]
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate unwrap;
+extern crate crust;
+extern crate maidsafe_utilities;
+extern crate mio;
+extern crate rand;
+
+mod echo;
+
+use crust::{Config, Event, Service, Uid};
+use echo::UdpEcho;
+use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
+use rand::{Rand, Rng};
+use std::env;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+struct UniqueId([u8; 20]);
+impl Uid for UniqueId {}
+impl Rand for UniqueId {
+    fn rand<R: Rng>(rng: &mut R) -> Self {
+        let mut inner = [0; 20];
+        rng.fill_bytes(&mut inner);
+        UniqueId(inner)
+    }
+}
+
+fn main() {
+    let addr: SocketAddr = match env::args().nth(1) {
+        Some(addr) => unwrap!(addr.parse()),
+        None => unwrap!("127.0.0.1:0".parse()),
+    };
+
+    let (event_tx, event_rx) = mpsc::channel::<Event<UniqueId>>();
+    let (category_tx, _) = mpsc::channel();
+    let event_tx = MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx);
+    let service = unwrap!(Service::with_config(event_tx, Config::default(), rand::random()));
+
+    let echo_addr = unwrap!(UdpEcho::start(&service, addr));
+    println!("Echoing UDP datagrams sent to {}", echo_addr);
+
+    // The service's events are of no interest here, but receiving them keeps it running.
+    for event in event_rx.iter() {
+        println!("{:?}", event);
+    }
+}
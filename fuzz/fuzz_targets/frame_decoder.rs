@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate crust;
+
+fuzz_target!(|data: &[u8]| {
+    crust::fuzzing::frame_decoder(data);
+});
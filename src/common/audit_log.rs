@@ -0,0 +1,343 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Defines `AuditLog`, a persistent record of who connected when, for the operators of public
+// nodes to handle abuse with, see `Config::audit_log`.
+//
+// Each record is a line of JSON. The event loop only queues records: they are formatted and
+// written on a thread of their own, and dropped and counted if the queue is full, so a slow or
+// stuck disk never holds up the event loop. With IPs redacted, each is replaced by a hash keyed
+// by a secret picked when the log is started, so the records of one address can still be told
+// apart from those of another without the log showing either.
+
+use common::hmac_sha3_256;
+use maidsafe_utilities::thread::{self, Joiner};
+use rand;
+use serde_json;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of records waiting to be written at most. Those logged beyond it are dropped.
+const AUDIT_QUEUE_LEN: usize = 1024;
+
+/// Bytes of the keyed hash a redacted IP is replaced with.
+const REDACTED_IP_LEN: usize = 8;
+
+/// What an audit record is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A listener accepted a connection.
+    Accepted,
+    /// The handshake of an accepted connection completed.
+    HandshakeSucceeded,
+    /// The handshake of an accepted connection failed.
+    HandshakeFailed,
+    /// A connected client was promoted to a node.
+    Promoted,
+    /// A connection to a peer was closed.
+    Disconnected,
+}
+
+impl AuditEvent {
+    fn name(&self) -> &'static str {
+        match *self {
+            AuditEvent::Accepted => "accepted",
+            AuditEvent::HandshakeSucceeded => "handshake_succeeded",
+            AuditEvent::HandshakeFailed => "handshake_failed",
+            AuditEvent::Promoted => "promoted",
+            AuditEvent::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// A record of the audit log, timed as it is made.
+#[derive(Debug)]
+pub struct AuditRecord {
+    time_ms: u64,
+    event: AuditEvent,
+    addr: Option<SocketAddr>,
+    peer: Option<String>,
+    detail: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent, addr: Option<SocketAddr>) -> Self {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() * 1000 + u64::from(since.subsec_nanos() / 1_000_000))
+            .unwrap_or(0);
+        AuditRecord {
+            time_ms,
+            event,
+            addr,
+            peer: None,
+            detail: None,
+        }
+    }
+
+    /// Names the peer the record is about.
+    pub fn peer<UID: Debug>(mut self, peer: &UID) -> Self {
+        self.peer = Some(format!("{:?}", peer));
+        self
+    }
+
+    /// Adds what else is known, e.g. why a connection was closed.
+    pub fn detail<D: Debug>(mut self, detail: &D) -> Self {
+        self.detail = Some(format!("{:?}", detail));
+        self
+    }
+
+    /// The line of JSON the record is written as, with the IP replaced by its hash keyed by
+    /// `redact_key` if given.
+    fn to_line(&self, redact_key: Option<&[u8; 32]>) -> String {
+        let addr = self.addr.map(|addr| match redact_key {
+            Some(key) => redact_addr(key, &addr),
+            None => addr.to_string(),
+        });
+        let line = AuditLine {
+            time_ms: self.time_ms,
+            event: self.event.name(),
+            addr,
+            peer: self.peer.as_ref().map(|peer| &peer[..]),
+            detail: self.detail.as_ref().map(|detail| &detail[..]),
+        };
+        serde_json::to_string(&line).unwrap_or_else(|e| {
+            debug!("Could not format audit record {:?}: {}", self, e);
+            String::new()
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct AuditLine<'a> {
+    time_ms: u64,
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    addr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+}
+
+/// Replaces the IP of `addr` with the start of its hash keyed by `key`, in hex, keeping the port.
+/// The same IP is always replaced the same under the same key.
+fn redact_addr(key: &[u8; 32], addr: &SocketAddr) -> String {
+    let hash = match addr.ip() {
+        IpAddr::V4(ip) => hmac_sha3_256(key, &ip.octets()),
+        IpAddr::V6(ip) => hmac_sha3_256(key, &ip.octets()),
+    };
+    let hex: String = hash[..REDACTED_IP_LEN]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}:{}", hex, addr.port())
+}
+
+/// The file of the audit log. Once the next line would take it past `max_size` bytes, it is
+/// moved aside to the same path with `.1` appended, replacing the one moved there before, and
+/// started afresh. A line longer than `max_size` still gets a file of its own.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Opens the file at `path`, appending to what it holds already.
+    pub fn open(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            file,
+            size,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Queues the records of the event loop for a thread which writes them.
+pub struct AuditLog {
+    tx: SyncSender<AuditRecord>,
+    // Declared after `tx`, so the thread sees the queue closed before it is waited for.
+    _joiner: Joiner,
+}
+
+impl AuditLog {
+    /// Starts writing records to `file`, with their IPs redacted if `redact_ips`.
+    pub fn start(mut file: RotatingFile, redact_ips: bool) -> Self {
+        let redact_key = if redact_ips {
+            Some(rand::random())
+        } else {
+            None
+        };
+        Self::with_writer(AUDIT_QUEUE_LEN, redact_key, move |line| {
+            if let Err(e) = file.write_line(line) {
+                debug!("Could not write audit record to {:?}: {:?}", file.path, e);
+            }
+        })
+    }
+
+    /// Like `start`, handing each line to `write` instead.
+    fn with_writer<F>(queue_len: usize, redact_key: Option<[u8; 32]>, mut write: F) -> Self
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel::<AuditRecord>(queue_len);
+        let joiner = thread::named("CRUST-Audit-Log", move || {
+            for record in rx.iter() {
+                write(&record.to_line(redact_key.as_ref()));
+            }
+        });
+        AuditLog {
+            tx,
+            _joiner: joiner,
+        }
+    }
+
+    /// Queues `record` to be written, or returns `false` if it was dropped as the queue was full.
+    pub fn log(&self, record: AuditRecord) -> bool {
+        match self.tx.try_send(record) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn record(port: u16) -> AuditRecord {
+        let addr = SocketAddr::new(IpAddr::from([203, 0, 113, 7]), port);
+        AuditRecord::new(AuditEvent::Accepted, Some(addr))
+    }
+
+    #[test]
+    fn files_rotate_at_their_size_limit() {
+        let path = env::temp_dir().join(format!("crust-audit-{}.log", rand::random::<u64>()));
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let rotated = PathBuf::from(rotated);
+
+        let line = "x".repeat(9);
+        let mut file = unwrap!(RotatingFile::open(path.clone(), 30));
+        // Three lines of 10 bytes fit exactly, the fourth starts a new file.
+        for _ in 0..3 {
+            unwrap!(file.write_line(&line));
+        }
+        assert_eq!(unwrap!(fs::metadata(&path)).len(), 30);
+        assert!(!rotated.exists());
+        unwrap!(file.write_line(&line));
+        assert_eq!(unwrap!(fs::metadata(&rotated)).len(), 30);
+        assert_eq!(unwrap!(fs::metadata(&path)).len(), 10);
+
+        // Reopened, the file is appended to and its size counted.
+        drop(file);
+        let mut file = unwrap!(RotatingFile::open(path.clone(), 30));
+        unwrap!(file.write_line(&line));
+        unwrap!(file.write_line(&line));
+        assert_eq!(unwrap!(fs::metadata(&path)).len(), 30);
+        // A line too long for any file gets one of its own.
+        unwrap!(file.write_line(&"y".repeat(40)));
+        assert_eq!(unwrap!(fs::metadata(&rotated)).len(), 30);
+        assert_eq!(unwrap!(fs::metadata(&path)).len(), 41);
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+    }
+
+    #[test]
+    fn redacted_ips_hash_the_same_under_the_same_key() {
+        let key = [7; 32];
+        let addr = SocketAddr::new(IpAddr::from([203, 0, 113, 7]), 5483);
+        let redacted = redact_addr(&key, &addr);
+        assert_eq!(redacted, redact_addr(&key, &addr));
+        assert!(redacted.ends_with(":5483"));
+        assert!(!redacted.contains("203.0.113.7"));
+
+        // The port isn't part of the hash, but the IP and the key are.
+        let other_port = SocketAddr::new(addr.ip(), 1);
+        assert_eq!(redact_addr(&key, &other_port), format!("{}:1", &redacted[..16]));
+        let other_ip = SocketAddr::new(IpAddr::from([203, 0, 113, 8]), 5483);
+        assert_ne!(redact_addr(&key, &other_ip), redacted);
+        assert_ne!(redact_addr(&[8; 32], &addr), redacted);
+
+        let line = record(5483).peer(&"peer").to_line(Some(&key));
+        assert!(line.contains(&format!("\"addr\":\"{}\"", redacted)));
+        assert!(line.contains("\"event\":\"accepted\""));
+        assert!(line.contains("\"peer\":\"\\\"peer\\\"\""));
+        assert!(record(5483).to_line(None).contains("\"addr\":\"203.0.113.7:5483\""));
+    }
+
+    #[test]
+    fn stalled_writer_does_not_block_logging() {
+        const QUEUE_LEN: usize = 16;
+        const RECORDS: usize = 1000;
+
+        // The writer is stuck on the first line until the gate opens.
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let mut gate_rx = Some(gate_rx);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let log = {
+            let written = written.clone();
+            AuditLog::with_writer(QUEUE_LEN, None, move |line: &str| {
+                if let Some(gate_rx) = gate_rx.take() {
+                    let _ = gate_rx.recv();
+                }
+                unwrap!(written.lock()).push(line.to_owned());
+            })
+        };
+
+        let start = Instant::now();
+        let logged = (0..RECORDS).filter(|&i| log.log(record(i as u16))).count();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // The queue is filled, plus the record the writer is stuck on if it took one already.
+        assert!(logged >= QUEUE_LEN && logged <= QUEUE_LEN + 1, "{} logged", logged);
+
+        unwrap!(gate_tx.send(()));
+        drop(log);
+        let written = unwrap!(written.lock());
+        assert_eq!(written.len(), logged);
+        assert!(written[0].contains(":0\""));
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Maximum number of addresses banned at once.
+pub const MAX_BANNED_IPS: usize = 1024;
+
+/// IP addresses whose connections the listeners refuse until a deadline, kept by the core for all
+/// of them. When full, the ban ending first is lifted early to make room.
+#[derive(Debug, Default)]
+pub struct BanList {
+    until: HashMap<IpAddr, Instant>,
+}
+
+impl BanList {
+    /// Bans `ip` until `until`, or for longer if it is banned for longer already. Bans which ended
+    /// by `now` are forgotten.
+    pub fn ban(&mut self, ip: IpAddr, until: Instant, now: Instant) {
+        self.until.retain(|_, until| *until > now);
+        if let Some(current) = self.until.get_mut(&ip) {
+            if *current < until {
+                *current = until;
+            }
+            return;
+        }
+        if self.until.len() >= MAX_BANNED_IPS {
+            let first_to_end = self
+                .until
+                .iter()
+                .min_by_key(|&(_, until)| *until)
+                .map(|(ip, _)| *ip);
+            if let Some(first_to_end) = first_to_end {
+                let _ = self.until.remove(&first_to_end);
+            }
+        }
+        let _ = self.until.insert(ip, until);
+    }
+
+    /// Whether connections from `ip` are refused at `now`.
+    pub fn is_banned(&self, ip: &IpAddr, now: Instant) -> bool {
+        self.until.get(ip).map_or(false, |until| *until > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn bans_end_and_make_room_for_others() {
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        bans.ban(ip(1), now + Duration::from_secs(10), now);
+        // A shorter ban doesn't cut a longer one short.
+        bans.ban(ip(1), now + Duration::from_secs(5), now);
+        assert!(bans.is_banned(&ip(1), now + Duration::from_secs(9)));
+        assert!(!bans.is_banned(&ip(1), now + Duration::from_secs(10)));
+        assert!(!bans.is_banned(&ip(2), now));
+
+        for n in 2..(MAX_BANNED_IPS as u32 + 1) {
+            bans.ban(ip(n), now + Duration::from_secs(20 + u64::from(n)), now);
+        }
+        assert_eq!(bans.until.len(), MAX_BANNED_IPS);
+
+        // Full: the ban ending first is lifted.
+        bans.ban(ip(0), now + Duration::from_secs(60), now);
+        assert_eq!(bans.until.len(), MAX_BANNED_IPS);
+        assert!(!bans.is_banned(&ip(1), now));
+        assert!(bans.is_banned(&ip(0), now));
+
+        // Ended bans are forgotten as the next is added.
+        let later = now + Duration::from_secs(30);
+        bans.ban(ip(0), later + Duration::from_secs(60), later);
+        assert_eq!(bans.until.len(), MAX_BANNED_IPS - 9);
+    }
+}
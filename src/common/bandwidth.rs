@@ -0,0 +1,246 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Share of a second's worth of bytes which may go at once after a pause, in milliseconds.
+const BURST_MS: u64 = 100;
+/// Bytes which may go at once however low the rate, so that a frame isn't dribbled out a few
+/// bytes per timer.
+const MIN_BURST_BYTES: u64 = 16 * 1024;
+
+/// Holds the bytes written to and read from our peers under a rate each way, in bytes per second,
+/// with token buckets which fill at that rate and are drained by what goes through them. A bucket
+/// holds no more than `BURST_MS` worth of its rate, so a connection which was idle for a while
+/// can't make up for it all at once.
+///
+/// Clones share the buckets, so that one limiter can hold several connections to a rate between
+/// them. Without a rate a way is unlimited.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Changes the rates, or lifts them where `None` or 0. Takes effect straight away for every
+    /// clone.
+    pub fn set_limits(&self, upload_bps: Option<u64>, download_bps: Option<u64>, now: Instant) {
+        let mut buckets = unwrap!(self.inner.lock());
+        let bucket = |rate: Option<u64>| match rate {
+            Some(0) | None => None,
+            Some(rate) => Some(TokenBucket::new(rate, now)),
+        };
+        buckets.upload = bucket(upload_bps);
+        buckets.download = bucket(download_bps);
+    }
+
+    /// The rates up and down, `None` where unlimited.
+    pub fn limits(&self) -> (Option<u64>, Option<u64>) {
+        let buckets = unwrap!(self.inner.lock());
+        (
+            buckets.upload.as_ref().map(|bucket| bucket.rate),
+            buckets.download.as_ref().map(|bucket| bucket.rate),
+        )
+    }
+
+    fn allowance(&self, upload: bool, now: Instant) -> Result<u64, Duration> {
+        let mut buckets = unwrap!(self.inner.lock());
+        let bucket = if upload {
+            buckets.upload.as_mut()
+        } else {
+            buckets.download.as_mut()
+        };
+        bucket.map_or(Ok(u64::max_value()), |bucket| bucket.allowance(now))
+    }
+
+    fn spend(&self, upload: bool, bytes: u64) {
+        let mut buckets = unwrap!(self.inner.lock());
+        let bucket = if upload {
+            buckets.upload.as_mut()
+        } else {
+            buckets.download.as_mut()
+        };
+        if let Some(bucket) = bucket {
+            bucket.spend(bytes);
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: u64,
+    filled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Starts out full.
+    fn new(rate: u64, now: Instant) -> Self {
+        let capacity = cmp::max(rate.saturating_mul(BURST_MS) / 1000, MIN_BURST_BYTES);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            filled_at: now,
+        }
+    }
+
+    /// Bytes which may go through now, or if none, how long until some may.
+    fn allowance(&mut self, now: Instant) -> Result<u64, Duration> {
+        self.fill(now);
+        if self.tokens > 0 {
+            return Ok(self.tokens);
+        }
+        // Long enough to earn a share of the burst rather than the first byte of it.
+        let micros = cmp::max(BURST_MS * 1000, 1_000_000 / self.rate);
+        Err(Duration::new(
+            micros / 1_000_000,
+            (micros % 1_000_000) as u32 * 1000,
+        ))
+    }
+
+    fn spend(&mut self, bytes: u64) {
+        self.tokens = self.tokens.saturating_sub(bytes);
+    }
+
+    /// Adds the tokens earned since it was last filled. The time is only taken into account once
+    /// it earned a whole token, so that frequent calls don't round the rate down to nothing.
+    fn fill(&mut self, now: Instant) {
+        if now <= self.filled_at {
+            return;
+        }
+        let elapsed = now - self.filled_at;
+        let micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_nanos() / 1000);
+        let earned = self.rate.saturating_mul(micros) / 1_000_000;
+        if earned == 0 {
+            return;
+        }
+        self.tokens = cmp::min(self.capacity, self.tokens.saturating_add(earned));
+        self.filled_at = now;
+    }
+}
+
+/// The rate limiters the traffic of a connection is held to: the service's, shared by all its
+/// connections, and the peer's own, see `Service::set_peer_bandwidth_limits`. Bytes only go
+/// through once both allow them.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    limiters: Vec<RateLimiter>,
+}
+
+impl Throttle {
+    pub fn new(limiters: Vec<RateLimiter>) -> Self {
+        Throttle { limiters }
+    }
+
+    /// Bytes which may be written now, or if none, how long until some may.
+    pub fn write_allowance(&self, now: Instant) -> Result<usize, Duration> {
+        self.allowance(true, now)
+    }
+
+    pub fn written(&self, bytes: usize) {
+        for limiter in &self.limiters {
+            limiter.spend(true, bytes as u64);
+        }
+    }
+
+    /// Bytes which may be read now, or if none, how long until some may.
+    pub fn read_allowance(&self, now: Instant) -> Result<usize, Duration> {
+        self.allowance(false, now)
+    }
+
+    pub fn read(&self, bytes: usize) {
+        for limiter in &self.limiters {
+            limiter.spend(false, bytes as u64);
+        }
+    }
+
+    fn allowance(&self, upload: bool, now: Instant) -> Result<usize, Duration> {
+        let mut allowance = u64::max_value();
+        let mut wait = None;
+        for limiter in &self.limiters {
+            match limiter.allowance(upload, now) {
+                Ok(bytes) => allowance = cmp::min(allowance, bytes),
+                Err(until) => wait = cmp::max(wait, Some(until)),
+            }
+        }
+        match wait {
+            Some(wait) => Err(wait),
+            None => Ok(cmp::min(allowance, usize::max_value() as u64) as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_fill_at_their_rate_up_to_the_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1_000_000, start);
+        assert_eq!(bucket.allowance(start), Ok(100_000));
+
+        bucket.spend(100_000);
+        assert_eq!(bucket.allowance(start), Err(Duration::from_millis(BURST_MS)));
+
+        // A millisecond earns a thousandth of the rate.
+        assert_eq!(bucket.allowance(start + Duration::from_millis(1)), Ok(1000));
+
+        // However long the pause, no more than the burst goes at once.
+        assert_eq!(bucket.allowance(start + Duration::from_secs(60)), Ok(100_000));
+    }
+
+    #[test]
+    fn slow_rates_earn_tokens_without_rounding_them_away() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, start);
+        bucket.spend(MIN_BURST_BYTES);
+        // Polling more often than a token is earned mustn't keep it from being earned.
+        for ms in 1..100 {
+            assert!(bucket.allowance(start + Duration::from_millis(ms)).is_err());
+        }
+        assert_eq!(bucket.allowance(start + Duration::from_millis(100)), Ok(1));
+
+        // A token takes longer than the usual wait.
+        let mut bucket = TokenBucket::new(1, start);
+        bucket.spend(MIN_BURST_BYTES);
+        assert_eq!(bucket.allowance(start), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn throttles_go_by_the_strictest_of_their_limiters() {
+        let global = RateLimiter::default();
+        let peer = RateLimiter::default();
+        let throttle = Throttle::new(vec![global.clone(), peer.clone()]);
+        let now = Instant::now();
+        assert_eq!(throttle.write_allowance(now), Ok(usize::max_value()));
+
+        global.set_limits(Some(10), None, now);
+        peer.set_limits(Some(1), Some(1_000_000), now);
+        let burst = MIN_BURST_BYTES as usize;
+        assert_eq!(throttle.write_allowance(now), Ok(burst));
+        assert_eq!(throttle.read_allowance(now), Ok(100_000));
+
+        // Clones share the buckets.
+        Throttle::new(vec![peer.clone()]).written(6000);
+        assert_eq!(throttle.write_allowance(now), Ok(burst - 6000));
+        throttle.written(burst - 6000);
+        assert_eq!(throttle.write_allowance(now), Err(Duration::from_secs(1)));
+        assert_eq!(Throttle::new(vec![global]).write_allowance(now), Ok(6000));
+    }
+}
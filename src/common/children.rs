@@ -0,0 +1,209 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::Core;
+use mio::{Poll, Token};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::{Rc, Weak};
+
+/// The child states of a composite state, such as the attempts of a `Bootstrap`, by token.
+///
+/// Children may end on their own at any time, including from within a call of their parent, so
+/// each is given a `ChildHandle` to deregister itself with as it terminates, and `terminate_all`
+/// copes with children which are gone already or are terminating as it runs.
+#[derive(Debug, Default)]
+pub struct ChildrenSet {
+    tokens: Rc<RefCell<HashSet<Token>>>,
+}
+
+impl ChildrenSet {
+    pub fn with_capacity(capacity: usize) -> Self {
+        ChildrenSet {
+            tokens: Rc::new(RefCell::new(HashSet::with_capacity(capacity))),
+        }
+    }
+
+    /// The handle a child deregisters itself with, given to it as it is started.
+    pub fn handle(&self) -> ChildHandle {
+        ChildHandle {
+            tokens: Rc::downgrade(&self.tokens),
+        }
+    }
+
+    pub fn insert(&mut self, token: Token) {
+        let _ = self.tokens.borrow_mut().insert(token);
+    }
+
+    /// Forgets the child of `token`. Returns whether it was still registered.
+    pub fn remove(&mut self, token: Token) -> bool {
+        self.tokens.borrow_mut().remove(&token)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.borrow().is_empty()
+    }
+
+    /// Terminates every child still registered and forgets them all. Children no longer in the
+    /// core are skipped, and so is one which is in use further up the stack, which happens when
+    /// it calls into us while ending itself: it is on its way out already.
+    pub fn terminate_all(&mut self, core: &mut Core, poll: &Poll) {
+        let tokens: Vec<Token> = self.tokens.borrow_mut().drain().collect();
+        for token in tokens {
+            let child = match core.get_state(token) {
+                Some(state) => state,
+                None => continue,
+            };
+            let mut child = match child.try_borrow_mut() {
+                Ok(child) => child,
+                Err(_) => {
+                    debug!("Child {:?} is busy terminating - leaving it to it", token);
+                    continue;
+                }
+            };
+            child.terminate(core, poll);
+        }
+    }
+}
+
+/// A child's weak back-reference to the `ChildrenSet` of its parent.
+#[derive(Debug, Clone, Default)]
+pub struct ChildHandle {
+    tokens: Weak<RefCell<HashSet<Token>>>,
+}
+
+impl ChildHandle {
+    /// Removes the child of `token` from its parent's set, if the parent is still around. Called
+    /// by the child as it terminates.
+    pub fn deregister(&self, token: Token) {
+        if let Some(tokens) = self.tokens.upgrade() {
+            if let Ok(mut tokens) = tokens.try_borrow_mut() {
+                let _ = tokens.remove(&token);
+            }
+        }
+    }
+
+    /// Whether the child of `token` is still in its parent's set, i.e. the parent hasn't given up
+    /// on it. Counts as registered while the set is being drained by `terminate_all`, which the
+    /// child is left to notice as it is terminated.
+    pub fn is_registered(&self, token: Token) -> bool {
+        match self.tokens.upgrade() {
+            Some(tokens) => tokens
+                .try_borrow()
+                .map(|tokens| tokens.contains(&token))
+                .unwrap_or(true),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoreMessage, ManualEventLoop, State};
+    use std::any::Any;
+    use std::cell::Cell;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct Child {
+        token: Token,
+        parent: ChildHandle,
+        /// Called as the child fails, before it terminates.
+        on_failure: Option<Box<FnMut(&mut Core, &Poll)>>,
+        terminations: Rc<Cell<usize>>,
+    }
+
+    impl Child {
+        fn fail(&mut self, core: &mut Core, poll: &Poll) {
+            if let Some(mut on_failure) = self.on_failure.take() {
+                on_failure(core, poll);
+            }
+            self.terminate(core, poll);
+        }
+    }
+
+    impl State for Child {
+        fn name(&self) -> &'static str {
+            "Child"
+        }
+
+        fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+            self.parent.deregister(self.token);
+            let _ = core.remove_state(self.token);
+            self.terminations.set(self.terminations.get() + 1);
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    #[test]
+    fn child_terminating_while_the_parent_terminates_all() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+            let parent = Rc::new(RefCell::new(ChildrenSet::default()));
+            let terminations = Rc::new(Cell::new(0));
+            let mut tokens = Vec::new();
+            for _ in 0..3 {
+                let token = core.get_new_token();
+                let child = Child {
+                    token,
+                    parent: parent.borrow().handle(),
+                    on_failure: None,
+                    terminations: terminations.clone(),
+                };
+                let _ = core.insert_state(token, Rc::new(RefCell::new(child)));
+                parent.borrow_mut().insert(token);
+                tokens.push(token);
+            }
+
+            // The first child fails outside of a dispatch, e.g. from a message posted by a
+            // thread of its own, and its failure makes the parent give up on all its children.
+            let state = unwrap!(core.get_state(tokens[0]));
+            let mut state = state.borrow_mut();
+            let child = unwrap!(state.as_any().downcast_mut::<Child>());
+            let parent_weak = Rc::downgrade(&parent);
+            child.on_failure = Some(Box::new(move |core: &mut Core, poll: &Poll| {
+                if let Some(parent) = parent_weak.upgrade() {
+                    parent.borrow_mut().terminate_all(core, poll);
+                }
+            }));
+            child.fail(core, poll);
+
+            assert!(parent.borrow().is_empty());
+            assert_eq!(terminations.get(), 3);
+            let _ = tx.send(core.stats().states["Child"].live);
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(100))));
+        assert_eq!(unwrap!(rx.try_recv()), 0);
+    }
+
+    #[test]
+    fn children_deregister_themselves() {
+        let mut children = ChildrenSet::with_capacity(2);
+        let handle = children.handle();
+        children.insert(Token(10));
+        children.insert(Token(11));
+
+        handle.deregister(Token(10));
+        assert!(!handle.is_registered(Token(10)));
+        assert!(handle.is_registered(Token(11)));
+        assert!(!children.remove(Token(10)));
+        assert!(children.remove(Token(11)));
+        assert!(children.is_empty());
+
+        // Once the parent is gone there is nothing to deregister from.
+        drop(children);
+        handle.deregister(Token(11));
+        assert!(!handle.is_registered(Token(11)));
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Source of time and timers of the event loop.
+//!
+//! States get the current time from `Core::now` and schedule timers through `Core::set_timeout`,
+//! which are backed by the wall clock and a mio timer. Tests can run an event loop on a
+//! `VirtualClock` instead, whose time only passes when it is advanced, so that timers fire
+//! deterministically and without waiting.
+
+use common::{CoreTimer, Result};
+use mio::timer::{self, Timer};
+#[cfg(test)]
+use std::collections::{BTreeMap, HashMap};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Unix time of the start of every `VirtualClock`, in milliseconds.
+#[cfg(test)]
+const VIRTUAL_EPOCH_MS: u64 = 1_500_000_000_000;
+
+/// Handle to a timer set on a `Clock`, which `Core` keeps to cancel it with.
+pub struct Timeout(TimeoutKind);
+
+enum TimeoutKind {
+    Real(timer::Timeout),
+    #[cfg(test)]
+    Virtual(u64),
+}
+
+/// The time source of an event loop.
+pub enum Clock {
+    Real(Timer<CoreTimer>),
+    #[cfg(test)]
+    Virtual(VirtualTimers),
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        match *self {
+            Clock::Real(_) => Instant::now(),
+            #[cfg(test)]
+            Clock::Virtual(ref timers) => timers.clock.now(),
+        }
+    }
+
+    /// Like `now`, but also counting the time the system spent suspended, which the monotonic
+    /// clock doesn't on Linux. Elsewhere it is the same as `now`, as the time suspended is either
+    /// counted by `now` already or not known.
+    pub fn now_including_suspend(&self) -> Instant {
+        match *self {
+            Clock::Real(_) => Instant::now() + time_suspended(),
+            #[cfg(test)]
+            Clock::Virtual(ref timers) => timers.clock.now_including_suspend(),
+        }
+    }
+
+    /// The wall clock matching this clock, which keeps telling the time once the event loop is
+    /// out of reach.
+    pub fn wall_clock(&self) -> WallClock {
+        match *self {
+            Clock::Real(_) => WallClock::Real,
+            #[cfg(test)]
+            Clock::Virtual(ref timers) => WallClock::Virtual(timers.clock.clone()),
+        }
+    }
+
+    pub fn set_timeout(&mut self, interval: Duration, timer: CoreTimer) -> Result<Timeout> {
+        match *self {
+            Clock::Real(ref mut mio_timer) => Ok(Timeout(TimeoutKind::Real(
+                mio_timer.set_timeout(interval, timer)?,
+            ))),
+            #[cfg(test)]
+            Clock::Virtual(ref mut timers) => Ok(timers.set_timeout(interval, timer)),
+        }
+    }
+
+    pub fn cancel_timeout(&mut self, timeout: &Timeout) -> Option<CoreTimer> {
+        match (self, &timeout.0) {
+            (&mut Clock::Real(ref mut mio_timer), &TimeoutKind::Real(ref timeout)) => {
+                mio_timer.cancel_timeout(timeout)
+            }
+            #[cfg(test)]
+            (&mut Clock::Virtual(ref mut timers), &TimeoutKind::Virtual(id)) => timers.cancel(id),
+            #[cfg(test)]
+            _ => None,
+        }
+    }
+
+    /// Whether expiring timers make the event loop's poll return, through the timer registered
+    /// with it. Those of a clock which doesn't are looked for after every poll instead.
+    pub fn wakes_poll(&self) -> bool {
+        match *self {
+            Clock::Real(_) => true,
+            #[cfg(test)]
+            Clock::Virtual(_) => false,
+        }
+    }
+
+    /// Returns the next timer which has expired.
+    pub fn poll(&mut self) -> Option<CoreTimer> {
+        match *self {
+            Clock::Real(ref mut mio_timer) => mio_timer.poll(),
+            #[cfg(test)]
+            Clock::Virtual(ref mut timers) => timers.poll(),
+        }
+    }
+}
+
+/// Tells the time since the Unix epoch, which, unlike `Instant`, can be compared with the time of
+/// another machine, and the time of the event loop to what is out of its reach.
+#[derive(Clone)]
+pub enum WallClock {
+    Real,
+    #[cfg(test)]
+    Virtual(VirtualClock),
+}
+
+impl WallClock {
+    /// Milliseconds since the Unix epoch, 0 if the system clock is set before it.
+    pub fn now_ms(&self) -> u64 {
+        match *self {
+            WallClock::Real => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs() * 1000 + u64::from(since.subsec_nanos() / 1_000_000))
+                .unwrap_or(0),
+            #[cfg(test)]
+            WallClock::Virtual(ref clock) => clock.now_ms(),
+        }
+    }
+
+    /// The time of the event loop, as `Core::now` tells it.
+    pub fn now(&self) -> Instant {
+        match *self {
+            WallClock::Real => Instant::now(),
+            #[cfg(test)]
+            WallClock::Virtual(ref clock) => clock.now(),
+        }
+    }
+}
+
+/// Time the system has spent suspended since it booted: the difference between the boot time
+/// clock, which keeps counting while suspended, and the monotonic one `Instant` is based on.
+#[cfg(target_os = "linux")]
+fn time_suspended() -> Duration {
+    use common::sys::clock_time;
+    use libc;
+
+    match (clock_time(libc::CLOCK_BOOTTIME), clock_time(libc::CLOCK_MONOTONIC)) {
+        (Ok(boot), Ok(monotonic)) if boot > monotonic => boot - monotonic,
+        _ => Duration::from_secs(0),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn time_suspended() -> Duration {
+    Duration::from_secs(0)
+}
+
+/// Time which only passes when advanced. Clones share the same time.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct VirtualClock {
+    inner: Arc<Mutex<VirtualTime>>,
+}
+
+#[cfg(test)]
+struct VirtualTime {
+    start: Instant,
+    elapsed: Duration,
+    suspended: Duration,
+}
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock {
+            inner: Arc::new(Mutex::new(VirtualTime {
+                start: Instant::now(),
+                elapsed: Duration::from_secs(0),
+                suspended: Duration::from_secs(0),
+            })),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        let time = unwrap!(self.inner.lock());
+        time.start + time.elapsed
+    }
+
+    pub fn now_including_suspend(&self) -> Instant {
+        let time = unwrap!(self.inner.lock());
+        time.start + time.elapsed + time.suspended
+    }
+
+    /// Wall-clock time, starting from `VIRTUAL_EPOCH_MS` and passing like `now`.
+    pub fn now_ms(&self) -> u64 {
+        let elapsed = unwrap!(self.inner.lock()).elapsed;
+        VIRTUAL_EPOCH_MS + elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000)
+    }
+
+    /// Moves time forward. The timers which expire fire in the next iteration of the event loop.
+    pub fn advance(&self, duration: Duration) {
+        unwrap!(self.inner.lock()).elapsed += duration;
+    }
+
+    /// Pretends the system was suspended for `duration`, which passes for
+    /// `now_including_suspend` only, as it does on Linux. No timer fires.
+    pub fn suspend(&self, duration: Duration) {
+        unwrap!(self.inner.lock()).suspended += duration;
+    }
+}
+
+/// Timers running on a `VirtualClock`.
+#[cfg(test)]
+pub struct VirtualTimers {
+    clock: VirtualClock,
+    next_id: u64,
+    /// Pending timers by deadline, timers of the same deadline in the order they were set.
+    pending: BTreeMap<(Instant, u64), CoreTimer>,
+    deadlines: HashMap<u64, Instant>,
+}
+
+#[cfg(test)]
+impl VirtualTimers {
+    pub fn new(clock: VirtualClock) -> Self {
+        VirtualTimers {
+            clock,
+            next_id: 0,
+            pending: BTreeMap::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    fn set_timeout(&mut self, interval: Duration, timer: CoreTimer) -> Timeout {
+        let id = self.next_id;
+        self.next_id += 1;
+        let deadline = self.clock.now() + interval;
+        let _ = self.pending.insert((deadline, id), timer);
+        let _ = self.deadlines.insert(id, deadline);
+        Timeout(TimeoutKind::Virtual(id))
+    }
+
+    fn cancel(&mut self, id: u64) -> Option<CoreTimer> {
+        let deadline = self.deadlines.remove(&id)?;
+        self.pending.remove(&(deadline, id))
+    }
+
+    fn poll(&mut self) -> Option<CoreTimer> {
+        let key = match self.pending.keys().next() {
+            Some(&(deadline, id)) if deadline <= self.clock.now() => (deadline, id),
+            _ => return None,
+        };
+        let _ = self.deadlines.remove(&key.1);
+        self.pending.remove(&key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::Token;
+
+    #[test]
+    fn virtual_timers_fire_in_deadline_order_once_due() {
+        let clock = VirtualClock::new();
+        let mut timers = Clock::Virtual(VirtualTimers::new(clock.clone()));
+        let timer = |id| CoreTimer::new(Token(0), id);
+
+        let _ = unwrap!(timers.set_timeout(Duration::from_secs(2), timer(0)));
+        let cancelled = unwrap!(timers.set_timeout(Duration::from_secs(1), timer(1)));
+        let _ = unwrap!(timers.set_timeout(Duration::from_secs(1), timer(2)));
+        let _ = unwrap!(timers.set_timeout(Duration::from_secs(1), timer(3)));
+        assert_eq!(timers.cancel_timeout(&cancelled), Some(timer(1)));
+        assert_eq!(timers.cancel_timeout(&cancelled), None);
+
+        let start = timers.now();
+        assert_eq!(timers.poll(), None);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(timers.now() - start, Duration::from_millis(1500));
+        assert_eq!(timers.poll(), Some(timer(2)));
+        assert_eq!(timers.poll(), Some(timer(3)));
+        assert_eq!(timers.poll(), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timers.poll(), Some(timer(0)));
+        assert_eq!(timers.poll(), None);
+    }
+}
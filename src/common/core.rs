@@ -9,26 +9,50 @@
 
 // Defines `Core`, the mio handler and the core of the event loop.
 
-use common::{Result, State};
+use common::clock::Timeout;
+#[cfg(test)]
+use common::clock::{VirtualClock, VirtualTimers};
+#[cfg(feature = "stall-watchdog")]
+use common::StallWatchdog;
+use common::{
+    AuditLog, AuditRecord, BanList, Clock, ConnectionDirection, HandshakeStage, KeyPair,
+    LagWatchdog, MemoryBudget, PendingConnInfo, PendingTable, RateLimiter, RecordedEventKind,
+    Result, State, WallClock,
+};
+#[cfg(feature = "flight-recorder")]
+use common::{FlightRecorder, RecordedEvent};
 use maidsafe_utilities::thread::{self, Joiner};
 use mio::channel::{self, Receiver, Sender};
-use mio::timer::{Timeout, Timer};
+use mio::timer::Timer;
 use mio::{Event, Events, Poll, PollOpt, Ready, Token};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::mpsc::TryRecvError;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const EVENT_CAPACITY: usize = 1024;
+/// Longest time the event loop spends draining before it exits regardless.
+const DRAIN_TIMEOUT_MS: u64 = 5_000;
+/// Longest the event loop polls for while draining, so that it gets to check the deadline.
+const DRAIN_POLL_MS: u64 = 100;
 
 const CHANNEL_TOKEN_OFFSET: usize = 0;
 const TIMER_TOKEN_OFFSET: usize = CHANNEL_TOKEN_OFFSET + 1;
 const USER_TOKEN_OFFSET: usize = TIMER_TOKEN_OFFSET + 1;
+/// Interval of the tick the periodic timers are aligned on in `PowerMode::LowPower`.
+const COARSE_TICK_SECS: u64 = 30;
+
+type TokenMap<V> = HashMap<Token, V, BuildHasherDefault<TokenHasher>>;
+type TokenSet = HashSet<Token, BuildHasherDefault<TokenHasher>>;
 
 pub struct EventLoop {
     tx: Sender<CoreMessage>,
-    _joiner: Joiner,
+    /// `None` if the loop is driven by a `ManualEventLoop` instead of a thread of its own.
+    _joiner: Option<Joiner>,
 }
 
 impl EventLoop {
@@ -36,6 +60,12 @@ impl EventLoop {
         self.tx.send(msg)?;
         Ok(())
     }
+
+    /// Returns a sender of messages to the loop. Unlike the `EventLoop` itself, dropping it
+    /// doesn't stop the loop.
+    pub fn sender(&self) -> Sender<CoreMessage> {
+        self.tx.clone()
+    }
 }
 
 impl Drop for EventLoop {
@@ -54,6 +84,150 @@ pub fn spawn_event_loop(
     token_counter_start: usize,
     event_loop_id: Option<&str>,
 ) -> Result<EventLoop> {
+    let (poll, tx, rx, timer) = new_poll(token_counter_start)?;
+
+    let mut name = "CRUST-Event-Loop".to_string();
+    if let Some(id) = event_loop_id {
+        name.push_str(": ");
+        name.push_str(id);
+    }
+
+    let tx_clone = tx.clone();
+    let joiner = thread::named(name, move || {
+        let clock = Clock::Real(timer);
+        let mut core = Core::new(token_counter_start + USER_TOKEN_OFFSET, tx_clone, clock);
+        let mut events = Events::with_capacity(EVENT_CAPACITY);
+        loop {
+            match run_iteration(
+                token_counter_start,
+                &poll,
+                &rx,
+                &mut core,
+                &mut events,
+                None,
+            ) {
+                Ok(true) => (),
+                Ok(false) => {
+                    trace!("Graceful event loop exit.");
+                    break;
+                }
+                Err(e) => {
+                    error!("Event loop killed due to {:?}", e);
+                    core.write_flight_record();
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(EventLoop {
+        tx,
+        _joiner: Some(joiner),
+    })
+}
+
+/// An event loop which runs on the thread of its owner, whenever the owner calls `run_once`,
+/// rather than on a thread of its own.
+pub struct ManualEventLoop {
+    token_counter_start: usize,
+    poll: Poll,
+    rx: Receiver<CoreMessage>,
+    core: Core,
+    events: Events,
+    running: bool,
+}
+
+impl ManualEventLoop {
+    /// Creates the loop along with the handle through which it is sent work.
+    pub fn new(token_counter_start: usize) -> Result<(ManualEventLoop, EventLoop)> {
+        let (poll, tx, rx, timer) = new_poll(token_counter_start)?;
+        Ok(Self::with_clock(token_counter_start, poll, tx, rx, Clock::Real(timer)))
+    }
+
+    /// Creates a loop like `new`, whose timers run on the given virtual clock rather than in real
+    /// time. They fire when the loop is run after the clock has been advanced past them.
+    #[cfg(test)]
+    pub fn with_virtual_clock(
+        token_counter_start: usize,
+        clock: VirtualClock,
+    ) -> Result<(ManualEventLoop, EventLoop)> {
+        // The mio timer is registered, but never set.
+        let (poll, tx, rx, _timer) = new_poll(token_counter_start)?;
+        let clock = Clock::Virtual(VirtualTimers::new(clock));
+        Ok(Self::with_clock(token_counter_start, poll, tx, rx, clock))
+    }
+
+    fn with_clock(
+        token_counter_start: usize,
+        poll: Poll,
+        tx: Sender<CoreMessage>,
+        rx: Receiver<CoreMessage>,
+        clock: Clock,
+    ) -> (ManualEventLoop, EventLoop) {
+        let core = Core::new(token_counter_start + USER_TOKEN_OFFSET, tx.clone(), clock);
+        let el = ManualEventLoop {
+            token_counter_start,
+            poll,
+            rx,
+            core,
+            events: Events::with_capacity(EVENT_CAPACITY),
+            running: true,
+        };
+        (el, EventLoop { tx, _joiner: None })
+    }
+
+    /// Handles the events ready within `max_duration` and returns once it has elapsed, or as
+    /// soon as the handle has been dropped or draining has finished. Returns whether the loop is
+    /// still running.
+    ///
+    /// Timers have absolute deadlines, so this can be called at any intervals: ones which expired
+    /// in between fire on the next call.
+    pub fn run_once(&mut self, max_duration: Duration) -> Result<bool> {
+        let deadline = Instant::now() + max_duration;
+        while self.running {
+            let now = Instant::now();
+            let timeout = if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            };
+            match run_iteration(
+                self.token_counter_start,
+                &self.poll,
+                &self.rx,
+                &mut self.core,
+                &mut self.events,
+                Some(timeout),
+            ) {
+                Ok(running) => self.running = running,
+                Err(e) => {
+                    error!("Event loop killed due to {:?}", e);
+                    self.core.write_flight_record();
+                    self.running = false;
+                    return Err(e);
+                }
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        Ok(self.running)
+    }
+
+    /// The counters of the loop, read between calls to `run_once`.
+    pub fn stats(&self) -> &CoreStats {
+        self.core.stats()
+    }
+}
+
+fn new_poll(
+    token_counter_start: usize,
+) -> Result<(
+    Poll,
+    Sender<CoreMessage>,
+    Receiver<CoreMessage>,
+    Timer<CoreTimer>,
+)> {
     let poll = Poll::new()?;
     let (tx, rx) = channel::channel();
     let timer = Timer::default();
@@ -71,94 +245,239 @@ pub fn spawn_event_loop(
         PollOpt::edge(),
     )?;
 
-    let mut name = "CRUST-Event-Loop".to_string();
-    if let Some(id) = event_loop_id {
-        name.push_str(": ");
-        name.push_str(id);
-    }
-
-    let tx_clone = tx.clone();
-    let joiner = thread::named(name, move || {
-        let core = Core::new(token_counter_start + USER_TOKEN_OFFSET, tx_clone, timer);
-        match event_loop_impl(token_counter_start, &poll, &rx, core) {
-            Ok(()) => trace!("Graceful event loop exit."),
-            Err(e) => error!("Event loop killed due to {:?}", e),
-        }
-    });
-
-    Ok(EventLoop {
-        tx,
-        _joiner: joiner,
-    })
+    Ok((poll, tx, rx, timer))
 }
 
-fn event_loop_impl(
+/// Polls for events, waiting at most `timeout` if given, and handles them. Returns `false` once
+/// the event loop has been asked to exit or has drained.
+fn run_iteration(
     token_counter_start: usize,
     poll: &Poll,
     rx: &Receiver<CoreMessage>,
-    mut core: Core,
-) -> Result<()> {
-    let mut events = Events::with_capacity(EVENT_CAPACITY);
-
-    'event_loop: loop {
-        let _ = poll.poll(&mut events, None)?;
-
-        for event in events.iter() {
-            match event.token() {
-                Token(t) if t == token_counter_start + CHANNEL_TOKEN_OFFSET => {
-                    if !event.kind().is_readable() {
-                        warn!(
-                            "Communication channel to event loop errored out: {:?}",
-                            event
-                        );
-                        continue;
-                    }
+    core: &mut Core,
+    events: &mut Events,
+    timeout: Option<Duration>,
+) -> Result<bool> {
+    core.flush_quarantine();
+    let timeout = if core.is_draining() {
+        let max = Duration::from_millis(DRAIN_POLL_MS);
+        Some(timeout.map_or(max, |timeout| cmp::min(timeout, max)))
+    } else {
+        timeout
+    };
+    let _ = poll.poll(events, timeout)?;
+    core.start_iteration();
 
-                    loop {
-                        let msg = match rx.try_recv() {
-                            Ok(msg) => msg,
-                            Err(TryRecvError::Empty) => break,
-                            Err(TryRecvError::Disconnected) => break 'event_loop,
-                        };
-                        match msg.0 {
-                            Some(mut f) => f(&mut core, poll),
-                            None => break 'event_loop,
+    for event in events.iter() {
+        match event.token() {
+            Token(t) if t == token_counter_start + CHANNEL_TOKEN_OFFSET => {
+                if !event.kind().is_readable() {
+                    warn!(
+                        "Communication channel to event loop errored out: {:?}",
+                        event
+                    );
+                    continue;
+                }
+
+                loop {
+                    let msg = match rx.try_recv() {
+                        Ok(msg) => msg,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return Ok(false),
+                    };
+                    match msg.0 {
+                        Some(mut f) => {
+                            let dispatch = core.start_dispatch();
+                            f(core, poll);
+                            core.end_dispatch("CoreMessage", dispatch);
                         }
+                        None => return Ok(false),
                     }
                 }
-                Token(t) if t == token_counter_start + TIMER_TOKEN_OFFSET => {
-                    core.handle_timer(poll, event.kind())
-                }
-                _ => core.handle_event(poll, event),
             }
+            Token(t) if t == token_counter_start + TIMER_TOKEN_OFFSET => {
+                core.handle_timer(poll, event.kind())
+            }
+            _ => core.handle_event(poll, event),
         }
     }
+    if !core.clock.wakes_poll() {
+        core.fire_timers(poll);
+    }
+    core.end_iteration();
 
-    Ok(())
+    Ok(!core.has_drained())
 }
 
 pub struct CoreMessage(Option<Box<FnMut(&mut Core, &Poll) + Send>>);
 
+/// Identifies a timer of a state: the state's token and an id of the state's choosing, which
+/// `State::timeout` is given when the timer expires. A state has at most one pending timer per id.
 #[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
 pub struct CoreTimer {
     pub state_id: Token,
-    pub timer_id: u8,
+    pub timer_id: u64,
+}
+
+/// Whether the event loop saves wakeups at the cost of precision, see `Service::set_power_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerMode {
+    /// Every timer fires when it is due.
+    Performance,
+    /// Periodic timers, which keep connections alive or do work that can wait, fire together on a
+    /// coarse tick, every 30 seconds, so that an idle event loop wakes up once per tick. The
+    /// heartbeats of connections without traffic are stretched meanwhile.
+    LowPower,
+}
+
+impl Default for PowerMode {
+    fn default() -> Self {
+        PowerMode::Performance
+    }
+}
+
+/// Counters and gauges describing the work done by the event loop.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CoreStats {
+    /// Number of inbound handshakes currently being processed.
+    pub handshakes_active: usize,
+    /// Number of accepted connections waiting for a free handshake slot.
+    pub handshakes_parked: usize,
+    /// Number of parked connections dropped because they waited too long for a handshake slot.
+    pub handshakes_expired: u64,
+    /// Number of accepted connections dropped because too many were already parked.
+    pub handshakes_rejected: u64,
+    /// Number of accepted connections dropped because their IP address is banned, see
+    /// `Config::protocol_violation_policy`.
+    pub connections_banned: u64,
+    /// Number of connections accepted by the listeners.
+    pub connections_accepted: u64,
+    /// Number of accepted connections dropped for opening with bytes of no protocol we speak, or
+    /// with none in time.
+    pub connections_unrecognised: u64,
+    /// Number of batches in which the listeners accepted connections, one per readable event.
+    pub accept_batches: u64,
+    /// Number of connections dropped because a frame didn't arrive in full by its deadline.
+    pub slow_frames_dropped: u64,
+    /// Bytes allocated for the frames of those connections, freed when they were dropped.
+    pub slow_frame_bytes_freed: u64,
+    /// Number of iterations of the event loop which took longer than `Config::loop_lag_warn_ms`
+    /// to handle their events.
+    pub lagging_iterations: u64,
+    /// Number of connected peers which are clients.
+    pub client_peers: usize,
+    /// Number of connected peers which are nodes.
+    pub node_peers: usize,
+    /// Number of clients taken within `Config::max_client_peers` whose handshake isn't over yet.
+    /// They count against the limit as if connected.
+    pub client_handshakes: usize,
+    /// Number of nodes taken within `Config::max_node_peers` whose handshake isn't over yet.
+    pub node_handshakes: usize,
+    /// Number of clients rejected because `Config::max_client_peers` were already connected.
+    pub clients_rejected: u64,
+    /// Number of nodes rejected because `Config::max_node_peers` were already connected.
+    pub nodes_rejected: u64,
+    /// Number of times the event loop woke up to fire timers, however many fired at once.
+    pub timer_wakeups: u64,
+    /// Number of datagrams arriving at the service discovery socket dropped because they weren't
+    /// a valid request or response.
+    pub discovery_malformed_dropped: u64,
+    /// Number of datagrams arriving at the service discovery socket dropped unread because they
+    /// were larger than any valid request or response.
+    pub discovery_oversized_dropped: u64,
+    /// Number of records of the audit log dropped because its writer fell behind, see
+    /// `Config::audit_log`.
+    pub audit_records_dropped: u64,
+    /// Number of pairs of peers we relay between, see `Config::relay`.
+    pub relayed_pairs: usize,
+    /// Bytes of the frames we relayed, which count in no peer's stats.
+    pub relayed_bytes: u64,
+    /// Number of frames we dropped rather than relay, for going over `RelayConfig` quotas or the
+    /// window of their sender.
+    pub relayed_frames_dropped: u64,
+    /// Most bytes queued at once towards any one side of a pair we relay between, which
+    /// `RelayConfig::window_bytes` bounds.
+    pub relayed_max_queued_bytes: u64,
+    /// Dispatches, timeouts and live states per kind of state, keyed by `State::name`.
+    pub states: BTreeMap<&'static str, StateKindStats>,
+}
+
+/// Work done by the event loop for one kind of state.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StateKindStats {
+    /// Number of states of this kind currently registered with the event loop.
+    pub live: usize,
+    /// Number of readiness events dispatched to states of this kind.
+    pub dispatches: u64,
+    /// Number of timeouts fired for states of this kind.
+    pub timeouts: u64,
+    /// Total time spent inside `State::ready` of states of this kind.
+    #[cfg(feature = "profiling")]
+    pub ready_time: Duration,
 }
 
 pub struct Core {
     tx: Sender<CoreMessage>,
-    timer: Timer<CoreTimer>,
+    clock: Clock,
     token_counter: usize,
-    states: HashMap<Token, Rc<RefCell<State>>>,
+    states: TokenMap<Slot>,
+    /// Tokens whose state was removed in the current iteration of the event loop. Events already
+    /// polled for them belong to the old socket, which (notably on Windows) can still report
+    /// completions after being deregistered, so they are dropped rather than given to a new state
+    /// registered under the same token.
+    quarantine: TokenSet,
+    /// Pending timers per token, by timer id.
+    timers: TokenMap<HashMap<u64, Timeout>>,
+    power_mode: PowerMode,
+    /// Interval of the tick of `PowerMode::LowPower`, counted from `tick_origin`.
+    coarse_tick: Duration,
+    tick_origin: Instant,
+    stats: CoreStats,
+    #[cfg(feature = "flight-recorder")]
+    recorder: FlightRecorder,
+    lag_watchdog: Option<LagWatchdog>,
+    #[cfg(feature = "stall-watchdog")]
+    stall_watchdog: Option<StallWatchdog>,
+    audit_log: Option<AuditLog>,
+    memory_budget: MemoryBudget,
+    /// Rates all connections to our peers are held to between them, see
+    /// `Service::set_bandwidth_limits`.
+    bandwidth: RateLimiter,
+    /// Our long-term key pair, see `Service::with_keys`.
+    keys: Option<KeyPair>,
+    /// Addresses whose connections the listeners refuse for now.
+    bans: BanList,
+    pending: PendingTable,
+    /// When the event loop exits at the latest, once it has started to drain.
+    drain_deadline: Option<Instant>,
 }
 
 impl Core {
-    fn new(token_counter_start: usize, tx: Sender<CoreMessage>, timer: Timer<CoreTimer>) -> Self {
+    fn new(token_counter_start: usize, tx: Sender<CoreMessage>, clock: Clock) -> Self {
+        let tick_origin = clock.now();
         Core {
             tx,
-            timer,
+            clock,
             token_counter: token_counter_start,
-            states: HashMap::new(),
+            states: TokenMap::default(),
+            quarantine: TokenSet::default(),
+            timers: TokenMap::default(),
+            power_mode: PowerMode::default(),
+            coarse_tick: Duration::from_secs(COARSE_TICK_SECS),
+            tick_origin,
+            stats: Default::default(),
+            #[cfg(feature = "flight-recorder")]
+            recorder: FlightRecorder::disabled(),
+            lag_watchdog: None,
+            #[cfg(feature = "stall-watchdog")]
+            stall_watchdog: None,
+            audit_log: None,
+            memory_budget: MemoryBudget::unlimited(),
+            bandwidth: RateLimiter::default(),
+            keys: None,
+            bans: BanList::default(),
+            pending: Default::default(),
+            drain_deadline: None,
         }
     }
 
@@ -166,12 +485,113 @@ impl Core {
         &self.tx
     }
 
-    pub fn set_timeout(&mut self, interval: Duration, core_timer: CoreTimer) -> Result<Timeout> {
-        Ok(self.timer.set_timeout(interval, core_timer)?)
+    /// Runs `f` in the next iteration of the event loop, e.g. to reach a state which is borrowed
+    /// further up the stack.
+    pub fn post<F>(&self, f: F) -> ::std::result::Result<(), channel::SendError<CoreMessage>>
+    where
+        F: FnOnce(&mut Core, &Poll) + Send + 'static,
+    {
+        self.tx.send(CoreMessage::new(f))
+    }
+
+    /// Returns the current time of the event loop, which states use instead of `Instant::now` so
+    /// that tests can run them in virtual time.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// See `Clock::now_including_suspend`.
+    pub fn now_including_suspend(&self) -> Instant {
+        self.clock.now_including_suspend()
+    }
+
+    /// See `Clock::wall_clock`.
+    pub fn wall_clock(&self) -> WallClock {
+        self.clock.wall_clock()
+    }
+
+    /// Sets the timer `core_timer` to expire after `interval`, replacing it if it is pending
+    /// already. The timers of a state are cancelled as it is removed, see `remove_state`.
+    pub fn set_timeout(&mut self, interval: Duration, core_timer: CoreTimer) -> Result<()> {
+        let _ = self.cancel_timeout(core_timer.state_id, core_timer.timer_id);
+        let timeout = self.clock.set_timeout(interval, core_timer)?;
+        let _ = self
+            .timers
+            .entry(core_timer.state_id)
+            .or_insert_with(HashMap::new)
+            .insert(core_timer.timer_id, timeout);
+        Ok(())
+    }
+
+    /// Like `set_timeout`, for the timers of periodic work which may fire a little late. In
+    /// `PowerMode::LowPower` the timer expires on the first coarse tick after `interval` rather
+    /// than after `interval` itself, along with the other periodic timers due by then.
+    pub fn set_periodic_timeout(
+        &mut self,
+        interval: Duration,
+        core_timer: CoreTimer,
+    ) -> Result<()> {
+        let interval = match self.power_mode {
+            PowerMode::Performance => interval,
+            PowerMode::LowPower => {
+                let now = self.now();
+                let tick = cmp::max(1, millis_rounded_up(self.coarse_tick));
+                let due = millis_rounded_up((now + interval) - self.tick_origin);
+                let ticks = (due + tick - 1) / tick;
+                (self.tick_origin + Duration::from_millis(ticks * tick)) - now
+            }
+        };
+        self.set_timeout(interval, core_timer)
+    }
+
+    /// Whether periodic timers are aligned on a coarse tick. Timers pending when the mode changes
+    /// keep their deadlines.
+    pub fn power_mode(&self) -> PowerMode {
+        self.power_mode
+    }
+
+    pub fn set_power_mode(&mut self, mode: PowerMode) {
+        self.power_mode = mode;
+    }
+
+    /// Makes the tick of `PowerMode::LowPower` last `tick` rather than 30 seconds.
+    #[cfg(test)]
+    pub fn set_coarse_tick(&mut self, tick: Duration) {
+        self.coarse_tick = tick;
     }
 
-    pub fn cancel_timeout(&mut self, timeout: &Timeout) -> Option<CoreTimer> {
-        self.timer.cancel_timeout(timeout)
+    /// Cancels the timer `timer_id` of the state of `token`. Returns whether it was pending.
+    pub fn cancel_timeout(&mut self, token: Token, timer_id: u64) -> bool {
+        match self.forget_timer(token, timer_id) {
+            Some(timeout) => self.clock.cancel_timeout(&timeout).is_some(),
+            None => false,
+        }
+    }
+
+    /// Whether the timer `timer_id` of the state of `token` is pending.
+    pub fn has_timeout(&self, token: Token, timer_id: u64) -> bool {
+        self.timers
+            .get(&token)
+            .map_or(false, |timers| timers.contains_key(&timer_id))
+    }
+
+    fn forget_timer(&mut self, token: Token, timer_id: u64) -> Option<Timeout> {
+        let (timeout, none_left) = match self.timers.get_mut(&token) {
+            Some(timers) => (timers.remove(&timer_id), timers.is_empty()),
+            None => return None,
+        };
+        if none_left {
+            let _ = self.timers.remove(&token);
+        }
+        timeout
+    }
+
+    fn cancel_all_timeouts(&mut self, token: Token) {
+        if let Some(timers) = self.timers.remove(&token) {
+            for timeout in timers.values() {
+                let _ = self.clock.cancel_timeout(timeout);
+            }
+        }
     }
 
     pub fn get_new_token(&mut self) -> Token {
@@ -180,25 +600,312 @@ impl Core {
         token
     }
 
+    /// Registers `state` under `token`, returning the state it replaces unless that one is being
+    /// dispatched to.
     pub fn insert_state(
         &mut self,
         token: Token,
         state: Rc<RefCell<State>>,
     ) -> Option<Rc<RefCell<State>>> {
-        self.states.insert(token, state)
+        let name = state.borrow().name();
+        self.state_kind_stats(name).live += 1;
+        let old = self.states.insert(
+            token,
+            Slot {
+                name,
+                state: Some(state),
+            },
+        )?;
+        self.state_kind_stats(old.name).live -= 1;
+        old.state
     }
 
-    pub fn remove_state(&mut self, token: Token) -> Option<Rc<RefCell<State>>> {
-        self.states.remove(&token)
+    /// Removes the state of `token`, along with the connection it had in progress, if any, and
+    /// its pending timers. Until the next iteration of the event loop, events for `token` are
+    /// dropped, even if another state is inserted under it in the meantime. Returns whether there
+    /// was a state.
+    pub fn remove_state(&mut self, token: Token) -> bool {
+        self.pending.remove(token);
+        let removed = self.hand_over_state(token);
+        if removed {
+            let _ = self.quarantine.insert(token);
+        }
+        removed
+    }
+
+    /// Removes the state of `token` in order to hand its socket over to a successor inserted
+    /// under the same token. Unlike `remove_state`, the events for `token` are still delivered, as
+    /// they concern the very socket the successor now owns. The timers of the state are
+    /// cancelled all the same. Returns whether there was a state.
+    pub fn hand_over_state(&mut self, token: Token) -> bool {
+        self.cancel_all_timeouts(token);
+        match self.states.remove(&token) {
+            Some(slot) => {
+                self.state_kind_stats(slot.name).live -= 1;
+                true
+            }
+            None => false,
+        }
     }
 
+    /// Returns the state of `token`, unless it is the state being dispatched to, which can't
+    /// reach itself through the core.
     pub fn get_state(&self, key: Token) -> Option<Rc<RefCell<State>>> {
-        self.states.get(&key).cloned()
+        self.states.get(&key).and_then(|slot| slot.state.clone())
+    }
+
+    /// Whether a state is registered under `token`, including the state being dispatched to.
+    pub fn has_state(&self, token: Token) -> bool {
+        self.states.contains_key(&token)
+    }
+
+    pub fn stats(&self) -> &CoreStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut CoreStats {
+        &mut self.stats
+    }
+
+    #[cfg(feature = "flight-recorder")]
+    pub fn set_flight_recorder(&mut self, recorder: FlightRecorder) {
+        self.recorder = recorder;
+    }
+
+    /// Adds an event to the flight record, if enabled.
+    #[cfg(feature = "flight-recorder")]
+    pub fn record(&mut self, token: Token, kind: RecordedEventKind) {
+        self.recorder.record(token, kind);
+    }
+
+    /// Without the flight recorder there is nothing to add the event to.
+    #[cfg(not(feature = "flight-recorder"))]
+    pub fn record(&mut self, _token: Token, _kind: RecordedEventKind) {}
+
+    #[cfg(feature = "flight-recorder")]
+    pub fn flight_record(&self) -> Vec<RecordedEvent> {
+        self.recorder.dump()
+    }
+
+    /// Writes the flight record to its dump file, if one is configured.
+    #[cfg(feature = "flight-recorder")]
+    fn write_flight_record(&self) {
+        self.recorder.write_dump();
+    }
+
+    #[cfg(not(feature = "flight-recorder"))]
+    fn write_flight_record(&self) {}
+
+    pub fn set_audit_log(&mut self, audit_log: AuditLog) {
+        self.audit_log = Some(audit_log);
+    }
+
+    /// Adds the record made by `record` to the audit log, if enabled. The record isn't even made
+    /// otherwise.
+    pub fn audit<F: FnOnce() -> AuditRecord>(&mut self, record: F) {
+        let logged = match self.audit_log {
+            Some(ref audit_log) => audit_log.log(record()),
+            None => return,
+        };
+        if !logged {
+            self.stats.audit_records_dropped += 1;
+        }
+    }
+
+    pub fn set_lag_watchdog(&mut self, watchdog: LagWatchdog) {
+        self.lag_watchdog = Some(watchdog);
+    }
+
+    /// The budget the states charge what they buffer to, see `MemoryBudget`. Unlimited unless
+    /// set.
+    pub fn memory_budget(&self) -> &MemoryBudget {
+        &self.memory_budget
+    }
+
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        self.memory_budget = budget;
+    }
+
+    /// The rate limiter shared by the connections to our peers. Unlimited unless set.
+    pub fn bandwidth(&self) -> &RateLimiter {
+        &self.bandwidth
+    }
+
+    /// The key pair the handshakes authenticate us with and agree on encryption with, if any.
+    pub fn keys(&self) -> Option<&KeyPair> {
+        self.keys.as_ref()
+    }
+
+    pub fn set_keys(&mut self, keys: KeyPair) {
+        self.keys = Some(keys);
+    }
+
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    pub fn bans_mut(&mut self) -> &mut BanList {
+        &mut self.bans
+    }
+
+    /// Starts writing the flight record if an iteration of the event loop takes longer than
+    /// `dump_after`. Set the flight recorder first.
+    #[cfg(feature = "stall-watchdog")]
+    pub fn start_stall_watchdog(&mut self, dump_after: Duration) {
+        self.stall_watchdog = Some(StallWatchdog::new(dump_after, self.recorder.dump_writer()));
+    }
+
+    /// Adds a connection in progress, handled by the state of `token`, to the pending table.
+    pub fn add_pending(
+        &mut self,
+        token: Token,
+        addr: SocketAddr,
+        direction: ConnectionDirection,
+        stage: HandshakeStage,
+    ) {
+        let now = self.now();
+        self.pending.insert(token, addr, direction, stage, now);
+    }
+
+    /// Updates the stage of the connection in progress of `token`, if it is pending.
+    pub fn set_pending_stage(&mut self, token: Token, stage: HandshakeStage) {
+        self.pending.set_stage(token, stage);
+    }
+
+    /// Removes the connection of `token` from the pending table, e.g. once it is active.
+    pub fn remove_pending(&mut self, token: Token) {
+        self.pending.remove(token);
+    }
+
+    /// Returns the connections in progress, oldest first.
+    pub fn pending_connections(&self) -> Vec<PendingConnInfo> {
+        self.pending.list(self.now())
+    }
+
+    /// Returns the number of connections in progress.
+    pub fn pending_count(&self) -> usize {
+        self.pending.count()
+    }
+
+    /// Winds the event loop down: every state is asked to `State::drain`, after which the loop
+    /// exits as soon as none is left, or after `DRAIN_TIMEOUT_MS` at the latest.
+    pub fn drain(&mut self, poll: &Poll) {
+        if self.is_draining() {
+            return;
+        }
+        self.drain_deadline = Some(self.now() + Duration::from_millis(DRAIN_TIMEOUT_MS));
+        let tokens: Vec<Token> = self.states.keys().cloned().collect();
+        for token in tokens {
+            if let Some(state) = self.get_state(token) {
+                state.borrow_mut().drain(self, poll);
+            }
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain_deadline.is_some()
+    }
+
+    fn has_drained(&self) -> bool {
+        match self.drain_deadline {
+            Some(_) if self.states.is_empty() => true,
+            Some(deadline) => self.now() >= deadline,
+            None => false,
+        }
+    }
+
+    fn state_kind_stats(&mut self, name: &'static str) -> &mut StateKindStats {
+        self.stats
+            .states
+            .entry(name)
+            .or_insert_with(Default::default)
+    }
+
+    /// Ends the quarantine of the tokens removed during the previous iteration of the event loop.
+    fn flush_quarantine(&mut self) {
+        self.quarantine.clear();
+    }
+
+    fn start_iteration(&mut self) {
+        if let Some(ref mut watchdog) = self.lag_watchdog {
+            watchdog.start_iteration(Instant::now());
+        }
+        #[cfg(feature = "stall-watchdog")]
+        {
+            if let Some(ref watchdog) = self.stall_watchdog {
+                watchdog.start_iteration();
+            }
+        }
+    }
+
+    fn end_iteration(&mut self) {
+        let lagging = match self.lag_watchdog {
+            Some(ref mut watchdog) => watchdog.end_iteration(Instant::now()),
+            None => false,
+        };
+        if lagging {
+            self.stats.lagging_iterations += 1;
+        }
+        #[cfg(feature = "stall-watchdog")]
+        {
+            if let Some(ref watchdog) = self.stall_watchdog {
+                watchdog.end_iteration();
+            }
+        }
+    }
+
+    /// Starts timing a dispatch for the lag watchdog, if there is one.
+    fn start_dispatch(&self) -> Option<Instant> {
+        self.lag_watchdog.as_ref().map(|_| Instant::now())
+    }
+
+    fn end_dispatch(&mut self, name: &'static str, started: Option<Instant>) {
+        if let (Some(started), Some(watchdog)) = (started, self.lag_watchdog.as_mut()) {
+            watchdog.dispatched(name, started.elapsed());
+        }
+    }
+
+    /// Calls `f` with the state of `token`, if any, and returns the state's name.
+    ///
+    /// The state is taken out of its slot for the call, so that it is neither looked up again nor
+    /// kept alive by a clone of its `Rc`, and put back afterwards unless it was removed or
+    /// replaced meanwhile. A state removing itself is therefore dropped as soon as it returns.
+    fn dispatch<F>(&mut self, token: Token, f: F) -> Option<&'static str>
+    where
+        F: FnOnce(&mut State, &mut Core),
+    {
+        let (name, state) = {
+            let slot = self.states.get_mut(&token)?;
+            (slot.name, slot.state.take()?)
+        };
+        f(&mut *state.borrow_mut(), self);
+        if let Some(slot) = self.states.get_mut(&token) {
+            if slot.state.is_none() {
+                slot.state = Some(state);
+            }
+        }
+        Some(name)
     }
 
     fn handle_event(&mut self, poll: &Poll, event: Event) {
-        if let Some(state) = self.get_state(event.token()) {
-            state.borrow_mut().ready(self, poll, event.kind());
+        if self.quarantine.contains(&event.token()) {
+            trace!("Dropping stale event for retired token: {:?}", event);
+            return;
+        }
+        #[cfg(feature = "profiling")]
+        let started = Instant::now();
+        let dispatch = self.start_dispatch();
+        let kind = event.kind();
+        let name = match self.dispatch(event.token(), |state, core| state.ready(core, poll, kind)) {
+            Some(name) => name,
+            None => return,
+        };
+        self.end_dispatch(name, dispatch);
+        let stats = self.state_kind_stats(name);
+        stats.dispatches += 1;
+        #[cfg(feature = "profiling")]
+        {
+            stats.ready_time += started.elapsed();
         }
     }
 
@@ -207,14 +914,64 @@ impl Core {
             warn!("Timer errored out: {:?}", kind);
             return;
         }
-        while let Some(core_timer) = self.timer.poll() {
-            if let Some(state) = self.get_state(core_timer.state_id) {
-                state.borrow_mut().timeout(self, poll, core_timer.timer_id);
+        self.fire_timers(poll);
+    }
+
+    /// Hands the expired timers to their states.
+    fn fire_timers(&mut self, poll: &Poll) {
+        let mut woken = false;
+        while let Some(core_timer) = self.clock.poll() {
+            if !woken {
+                woken = true;
+                self.stats.timer_wakeups += 1;
+            }
+            // The timers of a removed state are cancelled along with it, so this one is for the
+            // state of the token now, even one inserted under it in this very iteration.
+            let _ = self.forget_timer(core_timer.state_id, core_timer.timer_id);
+            let dispatch = self.start_dispatch();
+            let timer_id = core_timer.timer_id;
+            if let Some(name) = self.dispatch(core_timer.state_id, |state, core| {
+                state.timeout(core, poll, timer_id)
+            }) {
+                self.end_dispatch(name, dispatch);
+                self.state_kind_stats(name).timeouts += 1;
             }
         }
     }
 }
 
+fn millis_rounded_up(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (u64::from(duration.subsec_nanos()) + 999_999) / 1_000_000
+}
+
+/// A state registered under a token, along with its `State::name`, which is recorded on insertion
+/// as the state may be removing itself. The state is out of the slot while it is dispatched to.
+struct Slot {
+    name: &'static str,
+    state: Option<Rc<RefCell<State>>>,
+}
+
+/// Hashes tokens as they are. They come from a counter rather than from peers, so there is nothing
+/// for SipHash to guard against and a lookup costs little more than an index.
+#[derive(Default)]
+struct TokenHasher(u64);
+
+impl Hasher for TokenHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(*byte);
+        }
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.0 = n as u64;
+    }
+}
+
 impl CoreMessage {
     pub fn new<F: FnOnce(&mut Core, &Poll) + Send + 'static>(f: F) -> Self {
         let mut f = Some(f);
@@ -227,7 +984,499 @@ impl CoreMessage {
 }
 
 impl CoreTimer {
-    pub fn new(state_id: Token, timer_id: u8) -> Self {
+    pub fn new(state_id: Token, timer_id: u64) -> Self {
         CoreTimer { state_id, timer_id }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities;
+    use std::any::Any;
+    use std::cell::Cell;
+
+    struct Counter(Rc<Cell<usize>>);
+
+    impl State for Counter {
+        fn ready(&mut self, _core: &mut Core, _poll: &Poll, _kind: Ready) {
+            self.0.set(self.0.get() + 1);
+        }
+
+        fn timeout(&mut self, _core: &mut Core, _poll: &Poll, _timer_id: u64) {
+            self.0.set(self.0.get() + 1);
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    #[test]
+    fn retired_tokens_drop_stale_events() {
+        let poll = unwrap!(Poll::new());
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Clock::Real(Timer::default()));
+        let token = Token(7);
+
+        let old_events = Rc::new(Cell::new(0));
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Counter(old_events.clone()))));
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        assert_eq!(old_events.get(), 1);
+
+        // The old state terminates and a new one takes its token in the same iteration.
+        assert!(core.remove_state(token));
+        let new_events = Rc::new(Cell::new(0));
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Counter(new_events.clone()))));
+
+        // Events polled before the switch are the old socket's.
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        core.handle_event(&poll, Event::new(Ready::hup(), token));
+        assert_eq!(old_events.get(), 1);
+        assert_eq!(new_events.get(), 0);
+
+        core.flush_quarantine();
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        assert_eq!(new_events.get(), 1);
+    }
+
+    #[test]
+    fn retired_tokens_keep_the_timers_of_their_successor() {
+        let poll = unwrap!(Poll::new());
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Clock::Real(Timer::default()));
+        let token = Token(7);
+
+        let old_timeouts = Rc::new(Cell::new(0));
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Counter(old_timeouts.clone()))));
+        unwrap!(core.set_timeout(Duration::from_millis(0), CoreTimer::new(token, 0)));
+        assert!(core.remove_state(token));
+        let new_timeouts = Rc::new(Cell::new(0));
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Counter(new_timeouts.clone()))));
+        unwrap!(core.set_timeout(Duration::from_millis(0), CoreTimer::new(token, 1)));
+
+        ::std::thread::sleep(Duration::from_millis(300));
+        core.fire_timers(&poll);
+        assert_eq!(old_timeouts.get(), 0);
+        assert_eq!(new_timeouts.get(), 1);
+    }
+
+    #[test]
+    fn handed_over_tokens_keep_their_events() {
+        let poll = unwrap!(Poll::new());
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Clock::Real(Timer::default()));
+        let token = Token(7);
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Counter(Rc::new(Cell::new(0))))));
+        assert!(core.hand_over_state(token));
+        let events = Rc::new(Cell::new(0));
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Counter(events.clone()))));
+
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        assert_eq!(events.get(), 1);
+    }
+
+    #[test]
+    fn manual_event_loop_fires_timers_when_run() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        let token = el.core.get_new_token();
+        let timeouts = Rc::new(Cell::new(0));
+        let _ = el
+            .core
+            .insert_state(token, Rc::new(RefCell::new(Counter(timeouts.clone()))));
+        unwrap!(el.core.set_timeout(Duration::from_millis(200), CoreTimer::new(token, 0)));
+
+        assert!(unwrap!(el.run_once(Duration::from_secs(0))));
+        assert_eq!(timeouts.get(), 0);
+
+        // Nothing runs the loop while the timer expires.
+        ::std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(timeouts.get(), 0);
+        assert!(unwrap!(el.run_once(Duration::from_millis(100))));
+        assert_eq!(timeouts.get(), 1);
+
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let _ = tx.send(core.get_state(token).is_some());
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(100))));
+        assert!(unwrap!(rx.try_recv()));
+
+        drop(handle);
+        assert!(!unwrap!(el.run_once(Duration::from_secs(1))));
+    }
+
+    #[test]
+    fn manual_event_loop_fires_virtual_timers_once_advanced() {
+        let clock = VirtualClock::new();
+        let (mut el, _handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let token = el.core.get_new_token();
+        let timeouts = Rc::new(Cell::new(0));
+        let _ = el
+            .core
+            .insert_state(token, Rc::new(RefCell::new(Counter(timeouts.clone()))));
+        unwrap!(el.core.set_timeout(Duration::from_secs(3600), CoreTimer::new(token, 0)));
+
+        let start = el.core.now();
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(timeouts.get(), 0);
+
+        clock.advance(Duration::from_secs(3599));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(timeouts.get(), 0);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(el.core.now() - start, Duration::from_secs(3600));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(timeouts.get(), 1);
+    }
+
+    /// Logs the ids of its timers as they fire.
+    struct TimerLog(Rc<RefCell<Vec<u64>>>);
+
+    impl State for TimerLog {
+        fn timeout(&mut self, _core: &mut Core, _poll: &Poll, timer_id: u64) {
+            self.0.borrow_mut().push(timer_id);
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    #[test]
+    fn timers_are_told_apart_by_id() {
+        let clock = VirtualClock::new();
+        let (mut el, _handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let token = el.core.get_new_token();
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let _ = el
+            .core
+            .insert_state(token, Rc::new(RefCell::new(TimerLog(fired.clone()))));
+        let timer = |id| CoreTimer::new(token, id);
+
+        unwrap!(el.core.set_timeout(Duration::from_secs(3), timer(1)));
+        unwrap!(el.core.set_timeout(Duration::from_secs(1), timer(2)));
+        unwrap!(el.core.set_timeout(Duration::from_secs(1), timer(3)));
+        unwrap!(el.core.set_timeout(Duration::from_secs(1), timer(4)));
+        // Setting a pending timer again postpones it rather than adding another.
+        unwrap!(el.core.set_timeout(Duration::from_secs(2), timer(4)));
+        assert!(el.core.cancel_timeout(token, 2));
+        assert!(!el.core.cancel_timeout(token, 2));
+        assert!(!el.core.cancel_timeout(token, 5));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(*fired.borrow(), vec![3]);
+        assert!(!el.core.has_timeout(token, 3));
+        assert!(el.core.has_timeout(token, 4));
+
+        clock.advance(Duration::from_secs(2));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(*fired.borrow(), vec![3, 4, 1]);
+
+        // The timers of a removed state go with it, even if another state takes its token.
+        unwrap!(el.core.set_timeout(Duration::from_secs(1), timer(6)));
+        unwrap!(el.core.set_timeout(Duration::from_secs(2), timer(7)));
+        assert!(el.core.remove_state(token));
+        assert!(!el.core.has_timeout(token, 6));
+        let _ = el
+            .core
+            .insert_state(token, Rc::new(RefCell::new(TimerLog(fired.clone()))));
+        clock.advance(Duration::from_secs(2));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(*fired.borrow(), vec![3, 4, 1]);
+        assert_eq!(el.core.stats().states["Other"].timeouts, 3);
+    }
+
+    #[test]
+    fn state_kinds_are_accounted_for() {
+        let poll = unwrap!(Poll::new());
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Clock::Real(Timer::default()));
+
+        for i in 0..3 {
+            let state = Counter(Rc::new(Cell::new(0)));
+            let _ = core.insert_state(Token(i), Rc::new(RefCell::new(state)));
+        }
+        // Replacing a state doesn't count it twice.
+        let state = Counter(Rc::new(Cell::new(0)));
+        let _ = core.insert_state(Token(0), Rc::new(RefCell::new(state)));
+        for _ in 0..5 {
+            core.handle_event(&poll, Event::new(Ready::readable(), Token(1)));
+        }
+        assert!(core.remove_state(Token(2)));
+        assert!(!core.remove_state(Token(2)));
+
+        let stats = &core.stats().states["Other"];
+        assert_eq!(stats.live, 2);
+        assert_eq!(stats.dispatches, 5);
+        assert_eq!(stats.timeouts, 0);
+    }
+
+    struct Sleeper(Duration);
+
+    impl State for Sleeper {
+        fn timeout(&mut self, _core: &mut Core, _poll: &Poll, _timer_id: u64) {
+            ::std::thread::sleep(self.0);
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    #[test]
+    fn slow_states_are_reported_as_lag() {
+        let (mut el, _handle) = unwrap!(ManualEventLoop::new(0));
+        let lags = Rc::new(RefCell::new(Vec::new()));
+        let lags_clone = lags.clone();
+        el.core.set_lag_watchdog(LagWatchdog::new(
+            Duration::from_millis(50),
+            Box::new(move |duration| lags_clone.borrow_mut().push(duration)),
+        ));
+        let token = el.core.get_new_token();
+        let sleeper = Sleeper(Duration::from_millis(200));
+        let _ = el.core.insert_state(token, Rc::new(RefCell::new(sleeper)));
+
+        assert!(unwrap!(el.run_once(Duration::from_millis(100))));
+        assert!(lags.borrow().is_empty());
+        assert_eq!(el.core.stats().lagging_iterations, 0);
+
+        unwrap!(el.core.set_timeout(Duration::from_millis(0), CoreTimer::new(token, 0)));
+        assert!(unwrap!(el.run_once(Duration::from_millis(500))));
+        assert_eq!(el.core.stats().lagging_iterations, 1);
+        let lags = lags.borrow();
+        assert_eq!(lags.len(), 1);
+        assert!(lags[0] >= Duration::from_millis(200), "{:?}", lags[0]);
+        assert!(lags[0] < Duration::from_secs(5), "{:?}", lags[0]);
+    }
+
+    /// Runs its script with its own token on every event, and takes note of being dropped.
+    struct Scripted {
+        token: Token,
+        script: Box<FnMut(&mut Core, Token)>,
+        dropped: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Scripted {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
+    impl State for Scripted {
+        fn ready(&mut self, core: &mut Core, _poll: &Poll, _kind: Ready) {
+            (self.script)(core, self.token);
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    /// Inserts a `Scripted` state under `token` and returns whether it has been dropped.
+    fn insert_scripted(
+        core: &mut Core,
+        token: Token,
+        script: Box<FnMut(&mut Core, Token)>,
+    ) -> Rc<Cell<bool>> {
+        let dropped = Rc::new(Cell::new(false));
+        let state = Scripted {
+            token,
+            script,
+            dropped: dropped.clone(),
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        dropped
+    }
+
+    fn new_core() -> (Poll, Core) {
+        let poll = unwrap!(Poll::new());
+        let (tx, _rx) = channel::channel();
+        (poll, Core::new(0, tx, Clock::Real(Timer::default())))
+    }
+
+    #[test]
+    fn state_may_remove_itself_during_dispatch() {
+        let (poll, mut core) = new_core();
+        let token = Token(7);
+        let dropped = insert_scripted(
+            &mut core,
+            token,
+            Box::new(|core: &mut Core, token| {
+                assert!(core.remove_state(token));
+                assert!(!core.has_state(token));
+            }),
+        );
+
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        // Nothing keeps it alive once it has returned.
+        assert!(dropped.get());
+        assert!(!core.has_state(token));
+        let stats = &core.stats().states["Other"];
+        assert_eq!(stats.live, 0);
+        assert_eq!(stats.dispatches, 1);
+    }
+
+    #[test]
+    fn state_may_register_states_during_dispatch() {
+        let (poll, mut core) = new_core();
+        let token = Token(7);
+        let child = Token(8);
+        let successor_events = Rc::new(Cell::new(0));
+        let child_events = Rc::new(Cell::new(0));
+        let (successor_events_clone, child_events_clone) =
+            (successor_events.clone(), child_events.clone());
+        let dropped = insert_scripted(
+            &mut core,
+            token,
+            Box::new(move |core: &mut Core, token| {
+                let child_state = Counter(child_events_clone.clone());
+                assert!(core.insert_state(child, Rc::new(RefCell::new(child_state))).is_none());
+                // Hands its token over to a successor.
+                assert!(core.hand_over_state(token));
+                let successor = Counter(successor_events_clone.clone());
+                assert!(core.insert_state(token, Rc::new(RefCell::new(successor))).is_none());
+            }),
+        );
+
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        assert!(dropped.get());
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        core.handle_event(&poll, Event::new(Ready::readable(), child));
+        assert_eq!(successor_events.get(), 1);
+        assert_eq!(child_events.get(), 1);
+        assert_eq!(core.stats().states["Other"].live, 2);
+    }
+
+    #[test]
+    fn state_cannot_reach_itself_during_dispatch() {
+        let (poll, mut core) = new_core();
+        let token = Token(7);
+        let other = Token(8);
+        let _ = core.insert_state(other, Rc::new(RefCell::new(Counter(Rc::new(Cell::new(0))))));
+        let dispatched = Rc::new(Cell::new(0));
+        let dispatched_clone = dispatched.clone();
+        let dropped = insert_scripted(
+            &mut core,
+            token,
+            Box::new(move |core: &mut Core, token| {
+                assert!(core.has_state(token));
+                assert!(core.get_state(token).is_none());
+                assert!(core.get_state(other).is_some());
+                dispatched_clone.set(dispatched_clone.get() + 1);
+            }),
+        );
+
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        // Put back afterwards.
+        assert!(!dropped.get());
+        assert!(core.get_state(token).is_some());
+        core.handle_event(&poll, Event::new(Ready::readable(), token));
+        assert_eq!(dispatched.get(), 2);
+    }
+
+    /// Sets its periodic timer again each time it fires.
+    struct Ticker {
+        token: Token,
+        interval: Duration,
+    }
+
+    impl State for Ticker {
+        fn timeout(&mut self, core: &mut Core, _poll: &Poll, timer_id: u64) {
+            let timer = CoreTimer::new(self.token, timer_id);
+            unwrap!(core.set_periodic_timeout(self.interval, timer));
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    // Counts the wakeups of an event loop running the periodic timers of an idle service for an
+    // hour: the heartbeats of four connections, the suspend monitor, the config refresher, the
+    // interface monitor and a latency probe, started at different times.
+    fn wakeups_in_idle_hour(mode: PowerMode) -> u64 {
+        let clock = VirtualClock::new();
+        let (mut el, _handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        el.core.set_power_mode(mode);
+        for secs in &[20, 20, 20, 20, 20, 30, 60, 10] {
+            let token = el.core.get_new_token();
+            let interval = Duration::from_secs(*secs);
+            let ticker = Ticker { token, interval };
+            let _ = el.core.insert_state(token, Rc::new(RefCell::new(ticker)));
+            unwrap!(el.core.set_periodic_timeout(interval, CoreTimer::new(token, 0)));
+            clock.advance(Duration::from_millis(1_100));
+        }
+
+        let before = el.core.stats().timer_wakeups;
+        for _ in 0..3600 {
+            clock.advance(Duration::from_secs(1));
+            assert!(unwrap!(el.run_once(Duration::from_secs(0))));
+        }
+        el.core.stats().timer_wakeups - before
+    }
+
+    #[test]
+    fn low_power_mode_wakes_up_less_when_idle() {
+        let performance = wakeups_in_idle_hour(PowerMode::Performance);
+        let low_power = wakeups_in_idle_hour(PowerMode::LowPower);
+        // One wakeup per coarse tick, for all the timers.
+        assert!(low_power <= 3600 / COARSE_TICK_SECS);
+        assert!(
+            performance >= 5 * low_power,
+            "{} wakeups in performance mode, {} in low power mode",
+            performance,
+            low_power
+        );
+    }
+
+    // Compares dispatching through the core with what it used to cost: a SipHash lookup and a
+    // clone of the state's `Rc` per event, and logs both. Run with
+    // `RUST_LOG=crust=info cargo test --release dispatch_benchmark -- --ignored`.
+    #[test]
+    #[ignore]
+    fn dispatch_benchmark() {
+        let _ = maidsafe_utilities::log::init(true);
+        const STATES: usize = 1_000;
+        const DISPATCHES: usize = 5_000_000;
+        let (poll, mut core) = new_core();
+        let events = Rc::new(Cell::new(0));
+        let mut cloned_out = HashMap::new();
+        for i in 0..STATES {
+            let state: Rc<RefCell<State>> = Rc::new(RefCell::new(Counter(events.clone())));
+            let _ = cloned_out.insert(Token(i), state.clone());
+            let _ = core.insert_state(Token(i), state);
+        }
+        let ns_per_dispatch = |started: Instant| {
+            let elapsed = started.elapsed();
+            (elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())) as f64
+                / DISPATCHES as f64
+        };
+
+        let started = Instant::now();
+        for i in 0..DISPATCHES {
+            if let Some(state) = cloned_out.get(&Token(i % STATES)).cloned() {
+                state.borrow_mut().ready(&mut core, &poll, Ready::readable());
+            }
+        }
+        let before = ns_per_dispatch(started);
+
+        let started = Instant::now();
+        for i in 0..DISPATCHES {
+            let _ = core.dispatch(Token(i % STATES), |state, core| {
+                state.ready(core, &poll, Ready::readable())
+            });
+        }
+        let after = ns_per_dispatch(started);
+
+        assert_eq!(events.get(), 2 * DISPATCHES);
+        info!(
+            "Rc clone and SipHash: {:.1} ns, in place: {:.1} ns per dispatch",
+            before, after
+        );
+    }
+}
@@ -0,0 +1,632 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Dialling a list of addresses "happy eyeballs" style: each is given a head start before the
+//! next one is dialled as well, unless it fails sooner. What happens once a connection is made is
+//! up to the caller, see `DialHandler`.
+
+#[cfg(feature = "utp")]
+use common::UtpEndpoint;
+use common::{
+    addr_for_family, ChildHandle, ConnectionDirection, Core, CoreTimer, HandshakeStage, Result,
+    Socket, State,
+};
+#[cfg(feature = "websocket")]
+use common::{WsEndpoint, WsStream, WsUpgradeHandler};
+use mio::tcp::TcpStream;
+use mio::{Poll, PollOpt, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{self, IpAddr, SocketAddr};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+const STAGGER_TIMER_ID: u64 = 0;
+const DEADLINE_TIMER_ID: u64 = 1;
+const ATTEMPT_TIMER_ID: u64 = 0;
+
+/// An address for a `Dialer` to dial.
+pub struct DialTarget<T> {
+    pub addr: SocketAddr,
+    pub via: DialVia,
+    /// Handed back to the `DialHandler` along with the outcome.
+    pub context: T,
+}
+
+/// How a `DialTarget` is dialled.
+pub enum DialVia {
+    /// TCP, from a fresh socket.
+    Tcp,
+    /// TCP, from the given socket, such as one bound to a mapped port to punch a hole with.
+    TcpFrom(net::TcpStream),
+    /// uTP, from a fresh UDP socket.
+    #[cfg(feature = "utp")]
+    Utp,
+    /// WebSocket, from a fresh TCP socket. The connection is established once the opening
+    /// handshake is over.
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
+
+/// How a `Dialer` goes through its targets.
+#[derive(Debug, Clone, Copy)]
+pub struct DialSettings {
+    /// Time after dialling a target that the next one is dialled, unless an attempt fails first.
+    /// Zero dials them all at once, as far as `max_parallel` allows.
+    pub stagger: Duration,
+    /// Time within which each target has to accept our connection.
+    pub attempt_timeout: Option<Duration>,
+    /// Time after which the dialer gives up on the targets it hasn't reached yet.
+    pub deadline: Option<Duration>,
+    /// Most targets dialled at once.
+    pub max_parallel: usize,
+    /// See `Config::outbound_bind_addr`.
+    pub bind_ip: Option<IpAddr>,
+}
+
+/// The next step after dialling, implemented by the parent of a `Dialer`. Only ever called from
+/// the event loop, never from within a call into the dialer, so the parent is free to call it.
+pub trait DialHandler<T> {
+    /// A target accepted our connection. The socket isn't registered with the poll.
+    fn established(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        context: T,
+        addr: SocketAddr,
+        socket: Socket,
+    );
+
+    /// A target couldn't be reached in time.
+    fn failed(&mut self, core: &mut Core, poll: &Poll, context: T, addr: SocketAddr);
+
+    /// Every target was dialled, or the deadline passed. The dialer has deregistered itself from
+    /// its parent already.
+    fn finished(&mut self, core: &mut Core, poll: &Poll);
+}
+
+/// Dials targets in order, a child state of whichever state wants to connect to them. Stopping it
+/// with `State::terminate` drops the attempts still running, without reporting them.
+pub struct Dialer<T> {
+    token: Token,
+    settings: DialSettings,
+    queue: VecDeque<DialTarget<T>>,
+    /// Address and context of the targets being dialled, by the token of their attempt.
+    attempts: HashMap<Token, (SocketAddr, T)>,
+    parent: ChildHandle,
+    handler: Weak<RefCell<DialHandler<T>>>,
+    self_weak: Weak<RefCell<Dialer<T>>>,
+}
+
+impl<T: 'static> Dialer<T> {
+    /// Starts dialling the targets, best first. The handler isn't called before this returns.
+    pub fn start(
+        core: &mut Core,
+        poll: &Poll,
+        targets: Vec<DialTarget<T>>,
+        settings: DialSettings,
+        parent: ChildHandle,
+        handler: Weak<RefCell<DialHandler<T>>>,
+    ) -> Result<Token> {
+        let token = core.get_new_token();
+        if let Some(deadline) = settings.deadline {
+            core.set_timeout(deadline, CoreTimer::new(token, DEADLINE_TIMER_ID))?;
+        }
+
+        let state = Rc::new(RefCell::new(Dialer {
+            token,
+            settings,
+            queue: targets.into_iter().collect(),
+            attempts: HashMap::new(),
+            parent,
+            handler,
+            self_weak: Weak::new(),
+        }));
+        state.borrow_mut().self_weak = Rc::downgrade(&state);
+        let _ = core.insert_state(token, state.clone());
+
+        if state.borrow().queue.is_empty() {
+            // Finishes from the event loop, as the handler may not be called from here.
+            let timer = CoreTimer::new(token, STAGGER_TIMER_ID);
+            if let Err(e) = core.set_timeout(Duration::from_secs(0), timer) {
+                let _ = core.remove_state(token);
+                return Err(e);
+            }
+        } else {
+            state.borrow_mut().dial_next(core, poll);
+        }
+
+        Ok(token)
+    }
+
+    /// Has the dialer of `token` dial its next target now rather than once the stagger is over,
+    /// e.g. because the connection made to the last one turned out to be no good. Does nothing if
+    /// the dialer is gone, or busy calling whoever calls this.
+    pub fn hurry(core: &mut Core, poll: &Poll, token: Token) {
+        let state = match core.get_state(token) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = match state.try_borrow_mut() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if let Some(dialer) = state.as_any().downcast_mut::<Dialer<T>>() {
+            dialer.dial_next(core, poll);
+        }
+    }
+
+    /// Dials as many targets as may run at once if there is no stagger, or else the next one,
+    /// and the one after it once the stagger is over.
+    fn dial_next(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.cancel_timeout(self.token, STAGGER_TIMER_ID);
+        while self.attempts.len() < self.settings.max_parallel {
+            let target = match self.queue.pop_front() {
+                Some(target) => target,
+                None => break,
+            };
+            self.dial(core, poll, target);
+            if self.settings.stagger > Duration::from_secs(0) {
+                break;
+            }
+        }
+        self.stagger(core);
+    }
+
+    fn stagger(&mut self, core: &mut Core) {
+        if self.queue.is_empty()
+            || self.attempts.len() >= self.settings.max_parallel
+            || core.has_timeout(self.token, STAGGER_TIMER_ID)
+        {
+            return;
+        }
+        let timer = CoreTimer::new(self.token, STAGGER_TIMER_ID);
+        if let Err(e) = core.set_timeout(self.settings.stagger, timer) {
+            debug!("Failed to stagger dialling: {:?}", e);
+        }
+    }
+
+    fn dial(&mut self, core: &mut Core, poll: &Poll, target: DialTarget<T>) {
+        let addr = target.addr;
+        let (socket, stage) = match target.via {
+            DialVia::Tcp => (
+                Socket::connect_from(&addr, self.settings.bind_ip).ok(),
+                HandshakeStage::TcpConnecting,
+            ),
+            DialVia::TcpFrom(socket) => {
+                let addr = match socket.local_addr() {
+                    Ok(local_addr) => addr_for_family(&addr, &local_addr),
+                    Err(_) => addr,
+                };
+                (
+                    TcpStream::connect_stream(socket, &addr)
+                        .ok()
+                        .map(Socket::wrap),
+                    HandshakeStage::HolePunching,
+                )
+            }
+            #[cfg(feature = "utp")]
+            DialVia::Utp => (
+                UtpEndpoint::connect(core, poll, &addr, self.settings.bind_ip)
+                    .ok()
+                    .map(Socket::wrap_utp),
+                HandshakeStage::UtpConnecting,
+            ),
+            #[cfg(feature = "websocket")]
+            DialVia::WebSocket => {
+                let dialer = self.self_weak.clone();
+                let done = move |core: &mut Core,
+                                 poll: &Poll,
+                                 attempt: Token,
+                                 stream: Option<WsStream>| {
+                    if let Some(dialer) = dialer.upgrade() {
+                        let socket = stream.map(Socket::wrap_ws);
+                        dialer.borrow_mut().attempt_done(core, poll, attempt, socket);
+                    }
+                };
+                let done: WsUpgradeHandler = Box::new(done);
+                match WsEndpoint::dial(core, poll, &addr, self.settings.bind_ip, done) {
+                    // The handshake stands in for the attempt.
+                    Ok(attempt) => {
+                        let _ = self.attempts.insert(attempt, (addr, target.context));
+                        return;
+                    }
+                    Err(e) => {
+                        debug!("Failed to dial {} over WebSocket: {:?}", addr, e);
+                        (None, HandshakeStage::TcpConnecting)
+                    }
+                }
+            }
+        };
+        let attempt = DialAttempt::start(
+            core,
+            poll,
+            socket,
+            addr,
+            stage,
+            self.settings.attempt_timeout,
+            self.self_weak.clone(),
+        );
+        let _ = self.attempts.insert(attempt, (addr, target.context));
+    }
+
+    fn attempt_done(&mut self, core: &mut Core, poll: &Poll, attempt: Token, res: Option<Socket>) {
+        let (addr, context) = match self.attempts.remove(&attempt) {
+            Some(target) => target,
+            None => return,
+        };
+        let handler = match self.handler.upgrade() {
+            Some(handler) => handler,
+            None => return self.terminate(core, poll),
+        };
+        let established = res.is_some();
+        match res {
+            Some(socket) => handler
+                .borrow_mut()
+                .established(core, poll, context, addr, socket),
+            None => {
+                debug!("Failed to dial {}", addr);
+                handler.borrow_mut().failed(core, poll, context, addr)
+            }
+        }
+        // The parent may have given up on the remaining targets meanwhile.
+        if !self.parent.is_registered(self.token) {
+            return self.terminate(core, poll);
+        }
+
+        if established && self.settings.stagger > Duration::from_secs(0) {
+            self.stagger(core);
+        } else {
+            self.dial_next(core, poll);
+        }
+        self.maybe_finish(core, poll);
+    }
+
+    fn maybe_finish(&mut self, core: &mut Core, poll: &Poll) {
+        if self.queue.is_empty() && self.attempts.is_empty() {
+            self.finish(core, poll);
+        }
+    }
+
+    fn finish(&mut self, core: &mut Core, poll: &Poll) {
+        self.terminate(core, poll);
+        if let Some(handler) = self.handler.upgrade() {
+            handler.borrow_mut().finished(core, poll);
+        }
+    }
+}
+
+impl<T: 'static> State for Dialer<T> {
+    fn name(&self) -> &'static str {
+        "Dialer"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u64) {
+        if timer_id == DEADLINE_TIMER_ID {
+            debug!(
+                "Dialling timed out with {} targets left",
+                self.queue.len() + self.attempts.len()
+            );
+            return self.finish(core, poll);
+        }
+        self.dial_next(core, poll);
+        self.maybe_finish(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.parent.deregister(self.token);
+        for (attempt, _) in self.attempts.drain() {
+            if let Some(state) = core.get_state(attempt) {
+                if let Ok(mut state) = state.try_borrow_mut() {
+                    state.terminate(core, poll);
+                }
+            }
+        }
+        self.queue.clear();
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// The connection being made to one target of a `Dialer`.
+struct DialAttempt<T> {
+    token: Token,
+    /// `None` if dialling failed outright, which is reported from the event loop all the same.
+    socket: Option<Socket>,
+    dialer: Weak<RefCell<Dialer<T>>>,
+}
+
+impl<T: 'static> DialAttempt<T> {
+    fn start(
+        core: &mut Core,
+        poll: &Poll,
+        socket: Option<Socket>,
+        addr: SocketAddr,
+        stage: HandshakeStage,
+        timeout: Option<Duration>,
+        dialer: Weak<RefCell<Dialer<T>>>,
+    ) -> Token {
+        let token = core.get_new_token();
+        let socket = socket.and_then(|socket| {
+            match poll.register(
+                &socket,
+                token,
+                Ready::writable() | Ready::error() | Ready::hup(),
+                PollOpt::edge(),
+            ) {
+                Ok(()) => Some(socket),
+                Err(e) => {
+                    debug!("Failed to register the socket dialling {}: {:?}", addr, e);
+                    None
+                }
+            }
+        });
+        let timeout = if socket.is_some() {
+            timeout
+        } else {
+            Some(Duration::from_secs(0))
+        };
+        if let Some(timeout) = timeout {
+            if let Err(e) = core.set_timeout(timeout, CoreTimer::new(token, ATTEMPT_TIMER_ID)) {
+                debug!("Failed to time dialling {}: {:?}", addr, e);
+            }
+        }
+        let connecting = socket.is_some();
+
+        let state = DialAttempt {
+            token,
+            socket,
+            dialer,
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        if connecting {
+            core.add_pending(token, addr, ConnectionDirection::Outbound, stage);
+        }
+
+        token
+    }
+
+    fn done(&mut self, core: &mut Core, poll: &Poll, established: bool) {
+        let _ = core.remove_state(self.token);
+        let socket = self.socket.take();
+        if let Some(ref socket) = socket {
+            let _ = poll.deregister(socket);
+        }
+        if let Some(dialer) = self.dialer.upgrade() {
+            let res = if established { socket } else { None };
+            dialer.borrow_mut().attempt_done(core, poll, self.token, res);
+        }
+    }
+}
+
+impl<T: 'static> State for DialAttempt<T> {
+    fn name(&self) -> &'static str {
+        "DialAttempt"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            return self.done(core, poll, false);
+        }
+        if kind.is_writable() {
+            let established = match self.socket {
+                Some(ref socket) => match socket.take_error() {
+                    Ok(None) => true,
+                    _ => false,
+                },
+                None => false,
+            };
+            self.done(core, poll, established);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        self.done(core, poll, false);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        if let Some(socket) = self.socket.take() {
+            let _ = poll.deregister(&socket);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{ChildrenSet, CoreMessage, EventLoop, ManualEventLoop, VirtualClock};
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    #[derive(Debug, PartialEq)]
+    enum Outcome {
+        Established(SocketAddr),
+        Failed(SocketAddr),
+        Finished,
+    }
+
+    /// The parent of a dialer, passing on what it is told.
+    struct Parent {
+        token: Token,
+        children: ChildrenSet,
+        tx: Sender<Outcome>,
+    }
+
+    impl DialHandler<()> for Parent {
+        fn established(&mut self, _: &mut Core, _: &Poll, _: (), addr: SocketAddr, _: Socket) {
+            let _ = self.tx.send(Outcome::Established(addr));
+        }
+
+        fn failed(&mut self, _core: &mut Core, _poll: &Poll, _context: (), addr: SocketAddr) {
+            let _ = self.tx.send(Outcome::Failed(addr));
+        }
+
+        fn finished(&mut self, _core: &mut Core, _poll: &Poll) {
+            let _ = self.tx.send(Outcome::Finished);
+        }
+    }
+
+    impl State for Parent {
+        fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+            self.children.terminate_all(core, poll);
+            let _ = core.remove_state(self.token);
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    fn settings(stagger_secs: u64, deadline_secs: Option<u64>) -> DialSettings {
+        DialSettings {
+            stagger: Duration::from_secs(stagger_secs),
+            attempt_timeout: None,
+            deadline: deadline_secs.map(Duration::from_secs),
+            max_parallel: 10,
+            bind_ip: None,
+        }
+    }
+
+    /// Starts a parent dialling `addrs`. Returns its token and what it is told.
+    fn start_parent(
+        el: &mut ManualEventLoop,
+        handle: &EventLoop,
+        addrs: Vec<SocketAddr>,
+        settings: DialSettings,
+    ) -> (Token, Receiver<Outcome>) {
+        let (tx, rx) = mpsc::channel();
+        let (token_tx, token_rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+            let token = core.get_new_token();
+            let parent = Rc::new(RefCell::new(Parent {
+                token,
+                children: ChildrenSet::default(),
+                tx,
+            }));
+            let _ = core.insert_state(token, parent.clone());
+
+            let targets = addrs
+                .into_iter()
+                .map(|addr| DialTarget {
+                    addr,
+                    via: DialVia::Tcp,
+                    context: (),
+                })
+                .collect();
+            let handler: Rc<RefCell<DialHandler<()>>> = parent.clone();
+            let children = parent.borrow().children.handle();
+            let handler = Rc::downgrade(&handler);
+            let dialer = unwrap!(Dialer::start(core, poll, targets, settings, children, handler));
+            parent.borrow_mut().children.insert(dialer);
+            let _ = token_tx.send(token);
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        (unwrap!(token_rx.try_recv()), rx)
+    }
+
+    /// Runs the loop until `count` outcomes are reported.
+    fn outcomes(el: &mut ManualEventLoop, rx: &Receiver<Outcome>, count: usize) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
+        for _ in 0..100 {
+            outcomes.extend(rx.try_iter());
+            if outcomes.len() >= count {
+                break;
+            }
+            assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        }
+        outcomes
+    }
+
+    fn listener() -> (TcpListener, SocketAddr) {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        unwrap!(listener.set_nonblocking(true));
+        let addr = unwrap!(listener.local_addr());
+        (listener, addr)
+    }
+
+    fn was_dialled(listener: &TcpListener) -> bool {
+        match listener.accept() {
+            Ok(_) => true,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => panic!("Failed to accept: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn deadline_expires_mid_stagger() {
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let refusing = listener().1;
+        let (first, first_addr) = listener();
+        let (second, second_addr) = listener();
+        let (third, third_addr) = listener();
+
+        let addrs = vec![refusing, first_addr, second_addr, third_addr];
+        let (_, rx) = start_parent(&mut el, &handle, addrs, settings(10, Some(15)));
+
+        // A failure doesn't wait out the stagger.
+        assert_eq!(
+            outcomes(&mut el, &rx, 2),
+            vec![Outcome::Failed(refusing), Outcome::Established(first_addr)]
+        );
+        assert!(was_dialled(&first));
+        assert!(!was_dialled(&second));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(
+            outcomes(&mut el, &rx, 1),
+            vec![Outcome::Established(second_addr)]
+        );
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(outcomes(&mut el, &rx, 1), vec![Outcome::Finished]);
+
+        clock.advance(Duration::from_secs(10));
+        assert!(outcomes(&mut el, &rx, 1).is_empty());
+        assert!(!was_dialled(&third));
+    }
+
+    #[test]
+    fn cancelled_dialer_reports_nothing_more() {
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let (_first, first_addr) = listener();
+        let (second, second_addr) = listener();
+
+        let addrs = vec![first_addr, second_addr];
+        let (parent, rx) = start_parent(&mut el, &handle, addrs, settings(10, None));
+        assert_eq!(
+            outcomes(&mut el, &rx, 1),
+            vec![Outcome::Established(first_addr)]
+        );
+
+        let (tx, live_rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+            let state = unwrap!(core.get_state(parent));
+            state.borrow_mut().terminate(core, poll);
+            let _ = tx.send(core.stats().states["Dialer"].live);
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(unwrap!(live_rx.try_recv()), 0);
+
+        clock.advance(Duration::from_secs(20));
+        assert!(outcomes(&mut el, &rx, 1).is_empty());
+        assert!(!was_dialled(&second));
+    }
+}
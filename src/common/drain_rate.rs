@@ -0,0 +1,115 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// The rate at which the write queue of a connection drains, for telling the sender of a message
+// how long it will wait behind what is queued already. Only the time during which something was
+// left waiting to be written counts: while the queue is empty, the connection could have taken
+// more than it was given, so the bytes written say nothing about what it can take.
+
+use std::time::{Duration, Instant};
+
+/// Backlogged time over which the bytes written are summed up into a sample of the rate.
+const SAMPLE_MS: u64 = 100;
+/// Weight of a new sample in the smoothed rate.
+const SMOOTHING: f64 = 0.25;
+
+/// Where a message stood in the queue of its connection when sent, see
+/// `Service::send_with_receipt`. Both numbers are estimates: messages of a higher priority sent
+/// later may still overtake it, and the connection may speed up or slow down.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SendReceipt {
+    /// Bytes left to be written before the message, of messages of the same or a higher priority
+    /// queued before it.
+    pub queued_behind_bytes: u64,
+    /// Milliseconds for those bytes to be written at the rate the connection was drained at
+    /// lately. `None` if nothing had to wait to be written yet, to tell the rate from.
+    pub est_drain_ms: Option<u64>,
+}
+
+/// Exponentially smoothed rate at which a write queue drains.
+#[derive(Debug, Default)]
+pub struct DrainRate {
+    bytes_per_sec: Option<f64>,
+    /// When the last write left something waiting, while something still is.
+    backlogged_since: Option<Instant>,
+    sample_bytes: u64,
+    sample_time: Duration,
+}
+
+impl DrainRate {
+    /// Takes note of `bytes` written at `now`, `backlogged` telling whether anything was left
+    /// waiting to be written after them.
+    pub fn written(&mut self, bytes: usize, now: Instant, backlogged: bool) {
+        if let Some(since) = self.backlogged_since {
+            self.sample_bytes += bytes as u64;
+            self.sample_time += now.duration_since(since);
+            if self.sample_time >= Duration::from_millis(SAMPLE_MS) {
+                let nanos = self.sample_time.as_secs() * 1_000_000_000
+                    + u64::from(self.sample_time.subsec_nanos());
+                let sample = self.sample_bytes as f64 * 1e9 / nanos as f64;
+                self.bytes_per_sec = Some(match self.bytes_per_sec {
+                    Some(rate) => rate + SMOOTHING * (sample - rate),
+                    None => sample,
+                });
+                self.sample_bytes = 0;
+                self.sample_time = Duration::from_secs(0);
+            }
+        }
+        self.backlogged_since = if backlogged { Some(now) } else { None };
+    }
+
+    /// The receipt of a message with `queued_behind_bytes` to be written before it.
+    pub fn receipt(&self, queued_behind_bytes: u64) -> SendReceipt {
+        let est_drain_ms = if queued_behind_bytes == 0 {
+            Some(0)
+        } else {
+            match self.bytes_per_sec {
+                Some(rate) if rate > 0.0 => {
+                    Some((queued_behind_bytes as f64 * 1000.0 / rate) as u64)
+                }
+                _ => None,
+            }
+        };
+        SendReceipt {
+            queued_behind_bytes,
+            est_drain_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_backlogged_time_counts() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut rate = DrainRate::default();
+        assert_eq!(rate.receipt(0).est_drain_ms, Some(0));
+        assert_eq!(rate.receipt(1000).est_drain_ms, None);
+
+        // Written without a backlog: the queue might have drained faster.
+        rate.written(1_000_000, at(0), false);
+        rate.written(1_000_000, at(10), true);
+        assert_eq!(rate.receipt(1000).est_drain_ms, None);
+
+        // 100 KB over 100 ms of backlog, then an idle second which doesn't count.
+        rate.written(50_000, at(60), true);
+        rate.written(50_000, at(110), false);
+        rate.written(0, at(1110), true);
+        assert_eq!(rate.receipt(1_000_000).est_drain_ms, Some(1000));
+
+        // A stall pulls the rate down, smoothed.
+        rate.written(0, at(1310), true);
+        let receipt = rate.receipt(1_000_000);
+        assert_eq!(receipt.queued_behind_bytes, 1_000_000);
+        assert_eq!(receipt.est_drain_ms, Some(1000 * 4 / 3));
+    }
+}
@@ -0,0 +1,385 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Encryption of every frame of a connection once both peers agreed on it in the handshake, see
+// `EncryptionExtension`.
+//
+// Each side has a long-term key pair and makes up an ephemeral one per handshake. The offer and
+// the answer each carry the long-term and then the ephemeral public key of their sender. The keys
+// of the session are derived from three Diffie-Hellman results, between both ephemeral keys and
+// between each ephemeral key and the other side's long-term key, as in the `XX` pattern of the
+// Noise protocol framework, along with the offer and the answer themselves. Only a peer holding
+// the secret key to the long-term public key it sent can derive them, so a peer whose frames open
+// is the owner of its key. The first frame each side seals is a `Message::KeyConfirmation`, and
+// neither side takes the connection up, nor trusts the peer's key, before the peer's opened.
+//
+// The side which made the offer takes the part of the initiator. Over a punched hole or through a
+// relay, though, both sides offer at once and take the peer's offer for its answer, so the side
+// whose message sorts first takes it there, see `KeyRole`.
+//
+// After the handshake, everything following the length prefix of a frame, its timestamp trailer
+// included, is sealed with `secretbox`, which makes it `SEAL_OVERHEAD` bytes longer. Each
+// direction has a key of its own, and the nonce of a frame is the number of frames sent before
+// it in the same direction, so no nonce is used twice under a key, and a frame replayed, dropped
+// or reordered on the way fails to open.
+
+use byteorder::{ByteOrder, LittleEndian};
+use common::{hmac_sha3_256, CommonError, Result};
+use rand;
+use rust_sodium::crypto::box_;
+use rust_sodium::crypto::secretbox;
+use std::fmt;
+
+/// Size of a public key.
+pub const PUBLIC_KEY_SIZE: usize = box_::PUBLICKEYBYTES;
+/// Size of a secret key.
+pub const SECRET_KEY_SIZE: usize = box_::SECRETKEYBYTES;
+/// Bytes a frame grows by as it is sealed.
+pub const SEAL_OVERHEAD: usize = secretbox::MACBYTES;
+
+/// Size of the offer and of the answer: the long-term and the ephemeral public key.
+const EXCHANGE_MSG_SIZE: usize = 2 * PUBLIC_KEY_SIZE;
+const INITIATOR_KEY_LABEL: &[u8] = b"crust initiator to responder";
+const RESPONDER_KEY_LABEL: &[u8] = b"crust responder to initiator";
+/// What a proof of holding a key seals along with the challenge, so that it can't be mistaken for
+/// anything else sealed between the same keys.
+const KEY_PROOF_LABEL: &[u8] = b"crust key proof";
+
+/// A challenge to prove holding the secret key of a public one, see `KeyPair::prove`.
+pub type KeyChallenge = [u8; 32];
+
+/// The public half of a long-term key pair, by which a peer is authenticated, see
+/// `Service::with_keys`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PublicKey(pub [u8; PUBLIC_KEY_SIZE]);
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({:02x}{:02x}{:02x}..)", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+/// A long-term key pair, which the service authenticates itself to its peers with, see
+/// `Service::with_keys`. The same pair should be kept across restarts for peers to recognise us.
+#[derive(Clone)]
+pub struct KeyPair {
+    public: box_::PublicKey,
+    secret: box_::SecretKey,
+}
+
+impl KeyPair {
+    /// Generates a new random key pair.
+    pub fn generate() -> Self {
+        let (public, secret) = box_::gen_keypair();
+        KeyPair { public, secret }
+    }
+
+    /// Restores a key pair kept from before. The keys aren't checked to belong together, but a
+    /// service with a mismatched pair can't connect to anyone.
+    pub fn from_bytes(public: [u8; PUBLIC_KEY_SIZE], secret: [u8; SECRET_KEY_SIZE]) -> Self {
+        KeyPair {
+            public: box_::PublicKey(public),
+            secret: box_::SecretKey(secret),
+        }
+    }
+
+    /// The public key, which peers see us by.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.public.0)
+    }
+
+    /// The secret key, to be kept along with the public one for `from_bytes`.
+    pub fn secret_key_bytes(&self) -> [u8; SECRET_KEY_SIZE] {
+        self.secret.0
+    }
+
+    /// Answers the `challenge` of the holder of `their_key` with a proof that we hold the secret
+    /// key of our public one: the challenge sealed from our key to theirs, after the nonce it was
+    /// sealed with. See `verify_proof`.
+    pub fn prove(&self, their_key: &PublicKey, challenge: &KeyChallenge) -> Vec<u8> {
+        let nonce = box_::gen_nonce();
+        let mut plain = KEY_PROOF_LABEL.to_vec();
+        plain.extend_from_slice(challenge);
+        let mut proof = nonce.0.to_vec();
+        proof.extend(box_::seal(&plain, &nonce, &box_::PublicKey(their_key.0), &self.secret));
+        proof
+    }
+
+    /// Whether `proof` answers our `challenge` and was made by the holder of the secret key of
+    /// `their_key`, see `prove`.
+    pub fn verify_proof(
+        &self,
+        their_key: &PublicKey,
+        challenge: &KeyChallenge,
+        proof: &[u8],
+    ) -> bool {
+        if proof.len() < box_::NONCEBYTES {
+            return false;
+        }
+        let mut nonce = [0; box_::NONCEBYTES];
+        nonce.copy_from_slice(&proof[..box_::NONCEBYTES]);
+        let sealed = &proof[box_::NONCEBYTES..];
+        let their_key = box_::PublicKey(their_key.0);
+        match box_::open(sealed, &box_::Nonce(nonce), &their_key, &self.secret) {
+            Ok(plain) => {
+                plain.starts_with(KEY_PROOF_LABEL)
+                    && plain[KEY_PROOF_LABEL.len()..] == challenge[..]
+            }
+            Err(()) => false,
+        }
+    }
+}
+
+/// A new random challenge, see `KeyPair::prove`.
+pub fn new_key_challenge() -> KeyChallenge {
+    rand::random()
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyPair {{ public: {:?}, .. }}", self.public_key())
+    }
+}
+
+/// Our side of the key exchange of one handshake.
+pub struct KeyExchange {
+    keys: KeyPair,
+    ephemeral: KeyPair,
+}
+
+/// The part we take in a key exchange, which orders the keys derived, see `KeyExchange::finish`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    /// We made the offer, which the peer answered.
+    Initiator,
+    /// We answer the peer's offer.
+    Responder,
+    /// Both sides offered at once, each taking the other's offer for its answer. The side whose
+    /// message sorts first is the initiator.
+    Simultaneous,
+}
+
+impl KeyExchange {
+    pub fn new(keys: KeyPair) -> Self {
+        KeyExchange {
+            keys,
+            ephemeral: KeyPair::generate(),
+        }
+    }
+
+    /// Our offer or answer: our long-term public key, then our ephemeral one.
+    pub fn message(&self) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(EXCHANGE_MSG_SIZE);
+        msg.extend_from_slice(&self.keys.public.0);
+        msg.extend_from_slice(&self.ephemeral.public.0);
+        msg
+    }
+
+    /// Derives the keys of the session from the peer's offer or answer, taking the part of
+    /// `role`. Returns `None` if the peer's message is malformed.
+    pub fn finish(&self, theirs: &[u8], role: KeyRole) -> Option<SessionKeys> {
+        if theirs.len() != EXCHANGE_MSG_SIZE {
+            return None;
+        }
+        let ours = self.message();
+        let initiator = match role {
+            KeyRole::Initiator => true,
+            KeyRole::Responder => false,
+            KeyRole::Simultaneous => ours[..] < theirs[..],
+        };
+        let mut their_key = [0; PUBLIC_KEY_SIZE];
+        their_key.copy_from_slice(&theirs[..PUBLIC_KEY_SIZE]);
+        let mut their_ephemeral = [0; PUBLIC_KEY_SIZE];
+        their_ephemeral.copy_from_slice(&theirs[PUBLIC_KEY_SIZE..]);
+        let their_key = box_::PublicKey(their_key);
+        let their_ephemeral = box_::PublicKey(their_ephemeral);
+
+        let ee = box_::precompute(&their_ephemeral, &self.ephemeral.secret);
+        // Our ephemeral key with their long-term one, and our long-term key with their ephemeral
+        // one, in the order of the initiator.
+        let ours_theirs = box_::precompute(&their_key, &self.ephemeral.secret);
+        let theirs_ours = box_::precompute(&their_ephemeral, &self.keys.secret);
+        let (es, se) = if initiator {
+            (ours_theirs, theirs_ours)
+        } else {
+            (theirs_ours, ours_theirs)
+        };
+        let mut secret = Vec::with_capacity(3 * box_::PRECOMPUTEDKEYBYTES);
+        secret.extend_from_slice(&ee.0);
+        secret.extend_from_slice(&es.0);
+        secret.extend_from_slice(&se.0);
+
+        let mut transcript = Vec::with_capacity(2 * EXCHANGE_MSG_SIZE + INITIATOR_KEY_LABEL.len());
+        if initiator {
+            transcript.extend_from_slice(&ours);
+            transcript.extend_from_slice(theirs);
+        } else {
+            transcript.extend_from_slice(theirs);
+            transcript.extend_from_slice(&ours);
+        }
+        let derive = |label: &[u8]| {
+            let mut data = transcript.clone();
+            data.extend_from_slice(label);
+            secretbox::Key(hmac_sha3_256(&secret, &data))
+        };
+        let initiator_key = derive(INITIATOR_KEY_LABEL);
+        let responder_key = derive(RESPONDER_KEY_LABEL);
+        let (seal, open) = if initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+        Some(SessionKeys {
+            seal,
+            open,
+            peer: PublicKey(their_key.0),
+        })
+    }
+}
+
+/// The keys of a session agreed on in the handshake, along with the long-term public key of the
+/// peer they were agreed on with.
+pub struct SessionKeys {
+    seal: secretbox::Key,
+    open: secretbox::Key,
+    peer: PublicKey,
+}
+
+impl SessionKeys {
+    pub fn peer_key(&self) -> PublicKey {
+        self.peer
+    }
+}
+
+/// Seals the frames we send and opens those we receive, see the module docs.
+pub struct FrameCipher {
+    keys: SessionKeys,
+    sealed: u64,
+    opened: u64,
+}
+
+impl FrameCipher {
+    pub fn new(keys: SessionKeys) -> Self {
+        FrameCipher {
+            keys,
+            sealed: 0,
+            opened: 0,
+        }
+    }
+
+    /// Seals the next frame we send.
+    pub fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        let nonce = nonce(self.sealed);
+        self.sealed += 1;
+        secretbox::seal(plain, &nonce, &self.keys.seal)
+    }
+
+    /// Opens the next frame we receive, which fails if it was tampered with or isn't the one
+    /// expected, after which the connection can't go on.
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let nonce = nonce(self.opened);
+        self.opened += 1;
+        secretbox::open(sealed, &nonce, &self.keys.open).map_err(|()| CommonError::UnsealableFrame)
+    }
+
+    /// Passes over `frames` frames the peer sealed which won't arrive, such as those a relay
+    /// dropped, so that the ones after them still open.
+    #[cfg(feature = "relay")]
+    pub fn skip(&mut self, frames: u64) {
+        self.opened += frames;
+    }
+}
+
+fn nonce(counter: u64) -> secretbox::Nonce {
+    let mut nonce = [0; secretbox::NONCEBYTES];
+    LittleEndian::write_u64(&mut nonce[..8], counter);
+    secretbox::Nonce(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(initiator: &KeyPair, responder: &KeyPair) -> (FrameCipher, FrameCipher) {
+        let ours = KeyExchange::new(initiator.clone());
+        let theirs = KeyExchange::new(responder.clone());
+        let responder_keys = unwrap!(theirs.finish(&ours.message(), KeyRole::Responder));
+        let initiator_keys = unwrap!(ours.finish(&theirs.message(), KeyRole::Initiator));
+        assert_eq!(initiator_keys.peer_key(), responder.public_key());
+        assert_eq!(responder_keys.peer_key(), initiator.public_key());
+        (FrameCipher::new(initiator_keys), FrameCipher::new(responder_keys))
+    }
+
+    #[test]
+    fn frames_open_in_order_both_ways() {
+        let (mut initiator, mut responder) = exchange(&KeyPair::generate(), &KeyPair::generate());
+
+        let first = initiator.seal(b"first");
+        let second = initiator.seal(b"second");
+        assert_eq!(first.len(), b"first".len() + SEAL_OVERHEAD);
+        assert_eq!(unwrap!(responder.open(&first)), b"first");
+        assert_eq!(unwrap!(responder.open(&second)), b"second");
+
+        // Each direction has a key of its own.
+        let reply = responder.seal(b"first");
+        assert_ne!(reply, first);
+        assert_eq!(unwrap!(initiator.open(&reply)), b"first");
+
+        // A frame replayed doesn't open.
+        assert!(initiator.open(&reply).is_err());
+    }
+
+    #[test]
+    fn peers_without_the_secret_key_derive_other_keys() {
+        let victim = KeyPair::generate();
+        let responder = KeyPair::generate();
+
+        // Claims the victim's public key, but has only its own secret key.
+        let impostor = KeyPair {
+            public: victim.public,
+            secret: KeyPair::generate().secret,
+        };
+        let (mut initiator, mut responder) = exchange(&impostor, &responder);
+        assert!(responder.open(&initiator.seal(b"it's me")).is_err());
+        assert!(initiator.open(&responder.seal(b"who?")).is_err());
+    }
+
+    #[test]
+    fn only_the_holder_of_a_key_proves_it() {
+        let (prover, verifier) = (KeyPair::generate(), KeyPair::generate());
+        let challenge = new_key_challenge();
+        let proof = prover.prove(&verifier.public_key(), &challenge);
+        assert!(verifier.verify_proof(&prover.public_key(), &challenge, &proof));
+
+        // Not for another challenge, nor as proof of another key.
+        assert!(!verifier.verify_proof(&prover.public_key(), &new_key_challenge(), &proof));
+        let other = KeyPair::generate().public_key();
+        assert!(!verifier.verify_proof(&other, &challenge, &proof));
+
+        // Nor by a peer which has the public key only.
+        let impostor = KeyPair {
+            public: prover.public,
+            secret: KeyPair::generate().secret,
+        };
+        let forged = impostor.prove(&verifier.public_key(), &challenge);
+        assert!(!verifier.verify_proof(&prover.public_key(), &challenge, &forged));
+        assert!(!verifier.verify_proof(&prover.public_key(), &challenge, &proof[..10]));
+    }
+
+    #[test]
+    fn malformed_messages_are_refused() {
+        let exchange = KeyExchange::new(KeyPair::generate());
+        assert!(
+            exchange
+                .finish(&[0; EXCHANGE_MSG_SIZE - 1], KeyRole::Initiator)
+                .is_none()
+        );
+        assert!(exchange.finish(&[], KeyRole::Simultaneous).is_none());
+    }
+}
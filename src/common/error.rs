@@ -47,6 +47,18 @@ quick_error! {
             cause(e)
             from()
         }
+        /// A frame whose length prefix doesn't match the length of its body
+        MalformedFrame {
+            description("Frame length prefix doesn't match its body")
+        }
+        /// A frame which failed to open with the key of its session, see `FrameCipher`
+        UnsealableFrame {
+            description("Frame failed to open with the session key")
+        }
+        /// A message which isn't valid at this stage of the protocol
+        UnexpectedMessage {
+            description("Unexpected message")
+        }
         /// A zero byte socket read - means EOF
         ZeroByteRead {
             description("Read zero bytes from the socket - indicates EOF")
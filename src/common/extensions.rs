@@ -0,0 +1,731 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Extensions of the handshake: the side opening a connection offers a payload per feature it
+// supports, under the feature's id, and the other side answers those it takes up. Ids it doesn't
+// know are ignored and listed back as unsupported, so a feature can be added without breaking
+// peers which don't have it yet.
+
+use common::{
+    CrustUser, ExternalReachability, KeyExchange, KeyPair, KeyRole, PublicKey, SessionKeys,
+};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+
+/// Id of `RoleExtension`. Ids must never be reused for another feature.
+pub const ROLE_EXTENSION_ID: u16 = 1;
+/// Id of `PowExtension`.
+pub const POW_EXTENSION_ID: u16 = 2;
+/// Id of `TimestampExtension`.
+pub const TIMESTAMP_EXTENSION_ID: u16 = 3;
+/// Id of `CorrelationExtension`.
+pub const CORRELATION_EXTENSION_ID: u16 = 4;
+/// Id of `RetirementExtension`.
+pub const RETIREMENT_EXTENSION_ID: u16 = 5;
+/// Id of `EncryptionExtension`.
+pub const ENCRYPTION_EXTENSION_ID: u16 = 6;
+/// Id of `RelayExtension`.
+pub const RELAY_EXTENSION_ID: u16 = 7;
+/// Id of `ProbeExtension`.
+pub const PROBE_EXTENSION_ID: u16 = 8;
+/// Id of `ContactUpdateExtension`.
+pub const CONTACT_UPDATE_EXTENSION_ID: u16 = 9;
+/// Id of `UpgradeExtension`.
+pub const UPGRADE_EXTENSION_ID: u16 = 10;
+
+/// The extensions of a handshake message: our offers in a request, our answers in a response.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Extensions {
+    pub entries: Vec<Extension>,
+    /// In a response, the ids of the offers we didn't know.
+    pub unsupported: Vec<u16>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Extension {
+    pub id: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Extensions {
+    fn get(&self, id: u16) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| &entry.payload[..])
+    }
+}
+
+/// What was agreed on with a peer in the handshake of its connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedFeatures {
+    /// Whether the handshake carried extensions at all, which it doesn't with peers of protocol
+    /// versions before 3.
+    pub extensions: bool,
+    /// What the bootstrapping side joined as, if the connection was made by bootstrapping.
+    pub bootstrap_role: Option<CrustUser>,
+    /// Difficulty of the proof of work the bootstrapping side was asked for, if any.
+    pub pow_difficulty: Option<u8>,
+    /// Whether every frame carries a timestamp trailer, see `Socket::enable_timestamps`.
+    pub timestamps: bool,
+    /// Whether requests and responses can be sent, see `CorrelationExtension`.
+    pub correlation: bool,
+    /// Whether `Message::Retiring` can be sent, see `RetirementExtension`.
+    pub retirement: bool,
+    /// The long-term public key the peer authenticated itself with, if the frames are encrypted,
+    /// see `Service::with_keys`. Set by the handshake only once the peer's
+    /// `Message::KeyConfirmation` opened, not by `EncryptionExtension`.
+    pub peer_key: Option<PublicKey>,
+    /// Whether the peer relays connections for us, see `RelayExtension`.
+    pub peer_relays: bool,
+    /// Whether `Message::Probe` can be sent, see `ProbeExtension`.
+    pub probes: bool,
+    /// Whether `Message::ContactInfoUpdate` can be sent, see `ContactUpdateExtension`.
+    pub contact_updates: bool,
+    /// Whether the connection can be moved onto a direct one, see `UpgradeExtension`.
+    pub upgrades: bool,
+    /// Number of our offers the peer didn't know.
+    pub unsupported: usize,
+}
+
+/// A feature negotiated in the handshake, under an extension id of its own. A handler lives for
+/// one handshake, on either side of it.
+pub trait ExtensionHandler {
+    fn id(&self) -> u16;
+
+    /// Returns the payload of our offer, if we make one.
+    fn offer(&mut self) -> Option<Vec<u8>>;
+
+    /// Takes the peer's offer and returns the payload of our answer, or `None` to turn it down.
+    fn answer(&mut self, offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>>;
+
+    /// Takes the peer's answer to our offer, `None` if it turned the offer down or didn't know it.
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures);
+}
+
+/// Collects the offers of `handlers` for a request.
+pub fn offer_extensions(handlers: &mut [&mut ExtensionHandler]) -> Extensions {
+    let entries = handlers
+        .iter_mut()
+        .filter_map(|handler| {
+            handler.offer().map(|payload| Extension {
+                id: handler.id(),
+                payload,
+            })
+        })
+        .collect();
+    Extensions {
+        entries,
+        unsupported: Vec::new(),
+    }
+}
+
+/// Answers the offers of a request with `handlers`. Offers no handler knows are listed as
+/// unsupported; those repeating an id are ignored.
+pub fn answer_extensions(
+    handlers: &mut [&mut ExtensionHandler],
+    offers: &Extensions,
+) -> (Extensions, NegotiatedFeatures) {
+    let mut features = NegotiatedFeatures {
+        extensions: true,
+        ..NegotiatedFeatures::default()
+    };
+    let mut answers = Extensions::default();
+    let mut seen = Vec::with_capacity(offers.entries.len());
+
+    for offer in &offers.entries {
+        if seen.contains(&offer.id) {
+            continue;
+        }
+        seen.push(offer.id);
+        match handlers.iter_mut().find(|handler| handler.id() == offer.id) {
+            Some(handler) => {
+                if let Some(payload) = handler.answer(&offer.payload, &mut features) {
+                    answers.entries.push(Extension {
+                        id: offer.id,
+                        payload,
+                    });
+                }
+            }
+            None => {
+                trace!("Peer offered unknown handshake extension {}", offer.id);
+                answers.unsupported.push(offer.id);
+            }
+        }
+    }
+    (answers, features)
+}
+
+/// Hands the answers of a response to the `handlers` which made offers.
+pub fn take_extension_answers(
+    handlers: &mut [&mut ExtensionHandler],
+    answers: &Extensions,
+) -> NegotiatedFeatures {
+    let mut features = NegotiatedFeatures {
+        extensions: true,
+        unsupported: answers.unsupported.len(),
+        ..NegotiatedFeatures::default()
+    };
+    for handler in handlers.iter_mut() {
+        let answer = answers.get(handler.id());
+        handler.answered(answer, &mut features);
+    }
+    features
+}
+
+/// Declares what a bootstrapping peer joins as: a node along with the listeners to check it is
+/// reachable at, or a client. The answer carries nothing.
+pub struct RoleExtension {
+    reachability: Option<ExternalReachability>,
+}
+
+impl RoleExtension {
+    /// The handler of the bootstrapping side.
+    pub fn offering(reachability: ExternalReachability) -> Self {
+        RoleExtension {
+            reachability: Some(reachability),
+        }
+    }
+
+    /// The handler of the side being bootstrapped off.
+    pub fn answering() -> Self {
+        RoleExtension { reachability: None }
+    }
+
+    /// The reachability the peer declared. Peers which didn't declare one are taken for clients.
+    pub fn into_reachability(self) -> ExternalReachability {
+        self.reachability.unwrap_or(ExternalReachability::NotRequired)
+    }
+}
+
+fn role_of(reachability: &ExternalReachability) -> CrustUser {
+    match *reachability {
+        ExternalReachability::NotRequired => CrustUser::Client,
+        ExternalReachability::Required { .. } => CrustUser::Node,
+    }
+}
+
+impl ExtensionHandler for RoleExtension {
+    fn id(&self) -> u16 {
+        ROLE_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        self.reachability
+            .as_ref()
+            .and_then(|reachability| serialise(reachability).ok())
+    }
+
+    fn answer(&mut self, offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        match deserialise::<ExternalReachability>(offer) {
+            Ok(reachability) => {
+                features.bootstrap_role = Some(role_of(&reachability));
+                self.reachability = Some(reachability);
+                Some(Vec::new())
+            }
+            Err(e) => {
+                debug!("Invalid role extension: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        if answer.is_some() {
+            features.bootstrap_role = self.reachability.as_ref().map(role_of);
+        }
+    }
+}
+
+/// Tells the side being bootstrapped off the highest difficulty of proof of work we are willing
+/// to solve, and the bootstrapping side the difficulty it will be challenged with, if any.
+pub struct PowExtension {
+    max_difficulty: Option<u8>,
+    required: Option<u8>,
+}
+
+impl PowExtension {
+    /// The handler of the bootstrapping side, solving up to `max_difficulty`.
+    pub fn offering(max_difficulty: u8) -> Self {
+        PowExtension {
+            max_difficulty: Some(max_difficulty),
+            required: None,
+        }
+    }
+
+    /// The handler of the side being bootstrapped off, requiring the given difficulty if any.
+    pub fn answering(required: Option<u8>) -> Self {
+        PowExtension {
+            max_difficulty: None,
+            required,
+        }
+    }
+
+    /// Whether the peer can't be challenged with the difficulty we require: it offered to solve
+    /// less, or didn't offer at all.
+    pub fn peer_falls_short(&self) -> bool {
+        match self.required {
+            Some(required) => self.max_difficulty.map_or(true, |max| max < required),
+            None => false,
+        }
+    }
+}
+
+impl ExtensionHandler for PowExtension {
+    fn id(&self) -> u16 {
+        POW_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        self.max_difficulty.map(|max| vec![max])
+    }
+
+    fn answer(&mut self, offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        self.max_difficulty = offer.first().cloned();
+        let required = self.required?;
+        features.pow_difficulty = Some(required);
+        Some(vec![required])
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        self.required = answer.and_then(|answer| answer.first().cloned());
+        features.pow_difficulty = self.required;
+    }
+}
+
+/// Agrees on trailing every frame with a timestamp, see `Socket::enable_timestamps`, when both
+/// sides have `Config::timestamp_frames` set. Neither the offer nor the answer carries anything.
+pub struct TimestampExtension {
+    enabled: bool,
+}
+
+impl TimestampExtension {
+    /// The handler of either side, taking timestamps up only if `enabled`.
+    pub fn new(enabled: bool) -> Self {
+        TimestampExtension { enabled }
+    }
+}
+
+impl ExtensionHandler for TimestampExtension {
+    fn id(&self) -> u16 {
+        TIMESTAMP_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        if self.enabled {
+            Some(Vec::new())
+        } else {
+            None
+        }
+    }
+
+    fn answer(&mut self, _offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        features.timestamps = self.enabled;
+        self.offer()
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        features.timestamps = self.enabled && answer.is_some();
+    }
+}
+
+/// Agrees on sending `Message::Request` and `Message::Response`, which peers from before this
+/// extension can't decode. Every peer which knows it takes it up, and neither the offer nor the
+/// answer carries anything.
+pub struct CorrelationExtension;
+
+impl ExtensionHandler for CorrelationExtension {
+    fn id(&self) -> u16 {
+        CORRELATION_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn answer(&mut self, _offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        features.correlation = true;
+        Some(Vec::new())
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        features.correlation = answer.is_some();
+    }
+}
+
+/// Agrees on sending `Message::Retiring`, which peers from before this extension can't decode.
+/// Like `CorrelationExtension`, every peer which knows it takes it up, and neither the offer nor
+/// the answer carries anything.
+pub struct RetirementExtension;
+
+impl ExtensionHandler for RetirementExtension {
+    fn id(&self) -> u16 {
+        RETIREMENT_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn answer(&mut self, _offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        features.retirement = true;
+        Some(Vec::new())
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        features.retirement = answer.is_some();
+    }
+}
+
+/// Agrees on encrypting every frame after the handshake, see `Socket::enable_encryption`, when
+/// both sides have a key pair. The offer and the answer carry the sender's half of the key
+/// exchange, see `KeyExchange`. The keys agreed on are trusted only once the peer confirmed them,
+/// see `Message::KeyConfirmation`, so this leaves `NegotiatedFeatures::peer_key` to the handshake.
+pub struct EncryptionExtension {
+    exchange: Option<KeyExchange>,
+    /// Our part when we take an answer: `Simultaneous` if the peer offers as we do.
+    answered_role: KeyRole,
+    session: Option<SessionKeys>,
+}
+
+impl EncryptionExtension {
+    /// The handler of either side, taking encryption up only with `keys`.
+    pub fn new(keys: Option<&KeyPair>) -> Self {
+        EncryptionExtension {
+            exchange: keys.map(|keys| KeyExchange::new(keys.clone())),
+            answered_role: KeyRole::Initiator,
+            session: None,
+        }
+    }
+
+    /// The handler of a side whose peer makes its offer at the same time rather than answer
+    /// ours, as over a punched hole or through a relay, its offer being taken for the answer.
+    pub fn simultaneous(keys: Option<&KeyPair>) -> Self {
+        EncryptionExtension {
+            answered_role: KeyRole::Simultaneous,
+            ..EncryptionExtension::new(keys)
+        }
+    }
+
+    /// The keys of the session, once agreed on.
+    pub fn take_session(&mut self) -> Option<SessionKeys> {
+        self.session.take()
+    }
+}
+
+impl ExtensionHandler for EncryptionExtension {
+    fn id(&self) -> u16 {
+        ENCRYPTION_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        self.exchange.as_ref().map(KeyExchange::message)
+    }
+
+    fn answer(&mut self, offer: &[u8], _features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        let (session, answer) = {
+            let exchange = self.exchange.as_ref()?;
+            (exchange.finish(offer, KeyRole::Responder)?, exchange.message())
+        };
+        self.session = Some(session);
+        Some(answer)
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, _features: &mut NegotiatedFeatures) {
+        self.session = match (self.exchange.as_ref(), answer) {
+            (Some(exchange), Some(answer)) => exchange.finish(answer, self.answered_role),
+            _ => None,
+        };
+    }
+}
+
+/// Tells the peer whether we relay connections between our peers, see `Config::relay`, so that
+/// peers which use relays can list us in their connection info. The offer and the answer each
+/// carry a single byte, 1 if their sender relays and 0 otherwise.
+pub struct RelayExtension {
+    relays: bool,
+}
+
+impl RelayExtension {
+    /// The handler of either side, telling the peer whether we relay.
+    pub fn new(relays: bool) -> Self {
+        RelayExtension { relays }
+    }
+}
+
+impl ExtensionHandler for RelayExtension {
+    fn id(&self) -> u16 {
+        RELAY_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        Some(vec![self.relays as u8])
+    }
+
+    fn answer(&mut self, offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        features.peer_relays = relays_of(offer);
+        self.offer()
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        features.peer_relays = answer.map_or(false, relays_of);
+    }
+}
+
+fn relays_of(payload: &[u8]) -> bool {
+    payload.first() == Some(&1)
+}
+
+/// Agrees on sending `Message::Probe`, which peers falling back on the handshake without
+/// extensions may not decode. Like `CorrelationExtension`, every peer which knows it takes it up,
+/// and neither the offer nor the answer carries anything.
+pub struct ProbeExtension;
+
+impl ExtensionHandler for ProbeExtension {
+    fn id(&self) -> u16 {
+        PROBE_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn answer(&mut self, _offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        features.probes = true;
+        Some(Vec::new())
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        features.probes = answer.is_some();
+    }
+}
+
+/// Agrees on sending `Message::ContactInfoUpdate`, which peers falling back on the handshake
+/// without extensions may not decode. Like `CorrelationExtension`, every peer which knows it takes
+/// it up, and neither the offer nor the answer carries anything.
+pub struct ContactUpdateExtension;
+
+impl ExtensionHandler for ContactUpdateExtension {
+    fn id(&self) -> u16 {
+        CONTACT_UPDATE_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn answer(&mut self, _offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        features.contact_updates = true;
+        Some(Vec::new())
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        features.contact_updates = answer.is_some();
+    }
+}
+
+/// Agrees on sending `Message::UpgradeRequest` and the messages following it, which peers from
+/// before this extension can't decode. Like `CorrelationExtension`, every peer which knows it
+/// takes it up, and neither the offer nor the answer carries anything.
+pub struct UpgradeExtension;
+
+impl ExtensionHandler for UpgradeExtension {
+    fn id(&self) -> u16 {
+        UPGRADE_EXTENSION_ID
+    }
+
+    fn offer(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn answer(&mut self, _offer: &[u8], features: &mut NegotiatedFeatures) -> Option<Vec<u8>> {
+        features.upgrades = true;
+        Some(Vec::new())
+    }
+
+    fn answered(&mut self, answer: Option<&[u8]>, features: &mut NegotiatedFeatures) {
+        features.upgrades = answer.is_some();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{FrameCipher, MAX_POW_DIFFICULTY};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn unknown_offers_are_echoed_as_unsupported() {
+        let offers = Extensions {
+            entries: vec![
+                Extension {
+                    id: 0x7fff,
+                    payload: b"from the future".to_vec(),
+                },
+                Extension {
+                    id: ROLE_EXTENSION_ID,
+                    payload: unwrap!(serialise(&ExternalReachability::NotRequired)),
+                },
+                Extension {
+                    id: 0x7fff,
+                    payload: vec![],
+                },
+            ],
+            unsupported: vec![],
+        };
+
+        let mut role = RoleExtension::answering();
+        let (answers, features) = answer_extensions(&mut [&mut role], &offers);
+        assert_eq!(answers.unsupported, vec![0x7fff]);
+        assert_eq!(
+            answers.entries,
+            vec![Extension {
+                id: ROLE_EXTENSION_ID,
+                payload: vec![],
+            }]
+        );
+        assert_eq!(features.bootstrap_role, Some(CrustUser::Client));
+
+        let mut role = RoleExtension::offering(ExternalReachability::NotRequired);
+        let features = take_extension_answers(&mut [&mut role], &answers);
+        assert!(features.extensions);
+        assert_eq!(features.bootstrap_role, Some(CrustUser::Client));
+        assert_eq!(features.unsupported, 1);
+    }
+
+    #[test]
+    fn role_and_pow_are_negotiated() {
+        let listener: SocketAddr = unwrap!("1.2.3.4:5483".parse());
+        let reachability = ExternalReachability::Required {
+            direct_listeners: vec![listener],
+        };
+        let mut our_role = RoleExtension::offering(reachability.clone());
+        let mut our_pow = PowExtension::offering(MAX_POW_DIFFICULTY);
+        let offers = offer_extensions(&mut [&mut our_role, &mut our_pow]);
+        assert_eq!(offers.entries.len(), 2);
+
+        let mut their_role = RoleExtension::answering();
+        let mut their_pow = PowExtension::answering(Some(12));
+        let (answers, their_features) =
+            answer_extensions(&mut [&mut their_role, &mut their_pow], &offers);
+        assert!(answers.unsupported.is_empty());
+        assert!(!their_pow.peer_falls_short());
+        assert_eq!(their_role.into_reachability(), reachability);
+        assert_eq!(their_features.bootstrap_role, Some(CrustUser::Node));
+        assert_eq!(their_features.pow_difficulty, Some(12));
+
+        let our_features = take_extension_answers(&mut [&mut our_role, &mut our_pow], &answers);
+        assert_eq!(our_features, their_features);
+
+        // Without a proof of work required, the offer is turned down.
+        let mut their_pow = PowExtension::answering(None);
+        let (answers, _) = answer_extensions(&mut [&mut their_pow], &offers);
+        assert!(answers.entries.is_empty());
+        let features = take_extension_answers(&mut [&mut our_pow], &answers);
+        assert_eq!(features.pow_difficulty, None);
+
+        // A peer not offering to solve enough, or at all, can't be challenged.
+        let mut their_pow = PowExtension::answering(Some(MAX_POW_DIFFICULTY + 1));
+        let _ = answer_extensions(&mut [&mut their_pow], &offers);
+        assert!(their_pow.peer_falls_short());
+        let mut their_pow = PowExtension::answering(Some(1));
+        let _ = answer_extensions(&mut [&mut their_pow], &Extensions::default());
+        assert!(their_pow.peer_falls_short());
+    }
+
+    #[test]
+    fn timestamps_need_both_sides() {
+        for &(ours, theirs) in &[(true, true), (true, false), (false, true), (false, false)] {
+            let offers = offer_extensions(&mut [&mut TimestampExtension::new(ours)]);
+            let (answers, their_features) =
+                answer_extensions(&mut [&mut TimestampExtension::new(theirs)], &offers);
+            let our_features =
+                take_extension_answers(&mut [&mut TimestampExtension::new(ours)], &answers);
+            assert_eq!(our_features.timestamps, ours && theirs);
+            assert_eq!(their_features.timestamps, ours && theirs);
+        }
+    }
+    #[test]
+    fn correlation_is_taken_up_by_peers_knowing_it() {
+        let offers = offer_extensions(&mut [&mut CorrelationExtension]);
+        let (answers, their_features) =
+            answer_extensions(&mut [&mut CorrelationExtension], &offers);
+        assert!(their_features.correlation);
+        let features = take_extension_answers(&mut [&mut CorrelationExtension], &answers);
+        assert!(features.correlation);
+
+        // A peer from before the extension lists it as unsupported.
+        let (answers, their_features) = answer_extensions(&mut [], &offers);
+        assert!(!their_features.correlation);
+        assert_eq!(answers.unsupported, vec![CORRELATION_EXTENSION_ID]);
+        let features = take_extension_answers(&mut [&mut CorrelationExtension], &answers);
+        assert!(!features.correlation);
+    }
+
+    #[test]
+    fn encryption_needs_keys_on_both_sides() {
+        let (ours, theirs) = (KeyPair::generate(), KeyPair::generate());
+        let mut our_encryption = EncryptionExtension::new(Some(&ours));
+        let offers = offer_extensions(&mut [&mut our_encryption]);
+        let mut their_encryption = EncryptionExtension::new(Some(&theirs));
+        let (answers, their_features) = answer_extensions(&mut [&mut their_encryption], &offers);
+        let our_features = take_extension_answers(&mut [&mut our_encryption], &answers);
+        // The peer's key is trusted only once it confirmed them, see `Message::KeyConfirmation`.
+        assert_eq!(our_features.peer_key, None);
+        assert_eq!(their_features.peer_key, None);
+        let our_session = unwrap!(our_encryption.take_session());
+        let their_session = unwrap!(their_encryption.take_session());
+        assert_eq!(our_session.peer_key(), theirs.public_key());
+        assert_eq!(their_session.peer_key(), ours.public_key());
+
+        // A peer without keys turns the offer down.
+        let mut our_encryption = EncryptionExtension::new(Some(&ours));
+        let offers = offer_extensions(&mut [&mut our_encryption]);
+        let mut their_encryption = EncryptionExtension::new(None);
+        let (answers, _) = answer_extensions(&mut [&mut their_encryption], &offers);
+        assert!(answers.entries.is_empty());
+        let _ = take_extension_answers(&mut [&mut our_encryption], &answers);
+        assert!(our_encryption.take_session().is_none());
+    }
+
+    #[test]
+    fn encryption_is_agreed_on_when_both_sides_offer() {
+        // As over a punched hole or a relay, where each side takes the other's offer for its
+        // answer.
+        let mut our_encryption = EncryptionExtension::simultaneous(Some(&KeyPair::generate()));
+        let mut their_encryption = EncryptionExtension::simultaneous(Some(&KeyPair::generate()));
+        let our_offers = offer_extensions(&mut [&mut our_encryption]);
+        let their_offers = offer_extensions(&mut [&mut their_encryption]);
+        let _ = take_extension_answers(&mut [&mut our_encryption], &their_offers);
+        let _ = take_extension_answers(&mut [&mut their_encryption], &our_offers);
+
+        let mut ours = FrameCipher::new(unwrap!(our_encryption.take_session()));
+        let mut theirs = FrameCipher::new(unwrap!(their_encryption.take_session()));
+        assert_eq!(unwrap!(theirs.open(&ours.seal(b"ping"))), b"ping");
+        assert_eq!(unwrap!(ours.open(&theirs.seal(b"pong"))), b"pong");
+    }
+
+    #[test]
+    fn relaying_is_told_both_ways() {
+        for &(ours, theirs) in &[(true, true), (true, false), (false, true), (false, false)] {
+            let offers = offer_extensions(&mut [&mut RelayExtension::new(ours)]);
+            let (answers, their_features) =
+                answer_extensions(&mut [&mut RelayExtension::new(theirs)], &offers);
+            let our_features =
+                take_extension_answers(&mut [&mut RelayExtension::new(ours)], &answers);
+            assert_eq!(our_features.peer_relays, theirs);
+            assert_eq!(their_features.peer_relays, ours);
+        }
+
+        // Nor does a peer from before the extension relay.
+        let offers = offer_extensions(&mut [&mut RelayExtension::new(true)]);
+        let (answers, _) = answer_extensions(&mut [], &offers);
+        let features = take_extension_answers(&mut [&mut RelayExtension::new(true)], &answers);
+        assert!(!features.peer_relays);
+    }
+}
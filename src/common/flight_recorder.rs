@@ -0,0 +1,409 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Defines `FlightRecorder`, a bounded log of recent significant events of the event loop.
+
+use common::RecordedEventKind;
+use mio::Token;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bytes a recorded event is encoded into, see `encode`.
+const SLOT_BYTES: usize = 48;
+const WORD_BYTES: usize = mem::size_of::<usize>();
+
+/// An event kept by the flight recorder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Time since the recorder was started.
+    pub since_start: Duration,
+    /// Id of the connection (or other event loop state) the event relates to.
+    pub connection: usize,
+    /// What happened.
+    pub kind: RecordedEventKind,
+}
+
+/// Ring buffer of the most recent events. Recording never allocates nor locks: the buffer is sized
+/// once, from the byte budget, and the oldest event is overwritten when it is full.
+pub struct FlightRecorder {
+    start: Instant,
+    /// Shared with the stall watchdog, which writes the record from a thread of its own when the
+    /// event loop is stuck.
+    ring: Arc<Ring>,
+    dump_path: Option<PathBuf>,
+}
+
+impl FlightRecorder {
+    /// Creates a recorder using at most `max_bytes` for its events. If `dump_path` is given, the
+    /// recorded events are written there should the event loop panic or exit with an error.
+    pub fn new(max_bytes: usize, dump_path: Option<PathBuf>) -> Self {
+        FlightRecorder {
+            start: Instant::now(),
+            ring: Arc::new(Ring::new(max_bytes / (SLOT_BYTES + WORD_BYTES))),
+            dump_path,
+        }
+    }
+
+    /// Creates a recorder which doesn't record anything.
+    pub fn disabled() -> Self {
+        Self::new(0, None)
+    }
+
+    pub fn record(&mut self, token: Token, kind: RecordedEventKind) {
+        if self.ring.capacity() == 0 {
+            return;
+        }
+        self.ring.push(&RecordedEvent {
+            since_start: self.start.elapsed(),
+            connection: token.0,
+            kind,
+        });
+    }
+
+    /// Returns the recorded events, oldest first.
+    pub fn dump(&self) -> Vec<RecordedEvent> {
+        self.ring.read()
+    }
+
+    /// Writes the recorded events to the dump file, if one was configured.
+    pub fn write_dump(&self) {
+        write_dump(&self.ring, &self.dump_path);
+    }
+
+    /// Returns a handle which writes the record to the dump file from another thread.
+    #[cfg(feature = "stall-watchdog")]
+    pub fn dump_writer(&self) -> DumpWriter {
+        DumpWriter {
+            ring: self.ring.clone(),
+            dump_path: self.dump_path.clone(),
+        }
+    }
+}
+
+/// Writes the flight record to the dump file, see `FlightRecorder::dump_writer`.
+#[cfg(feature = "stall-watchdog")]
+pub struct DumpWriter {
+    ring: Arc<Ring>,
+    dump_path: Option<PathBuf>,
+}
+
+#[cfg(feature = "stall-watchdog")]
+impl DumpWriter {
+    pub fn write_dump(&self) {
+        write_dump(&self.ring, &self.dump_path);
+    }
+}
+
+/// The events, written by the event loop alone and read from any thread. Each slot is guarded by
+/// a sequence number rather than a lock: a reader which sees it change while reading the slot, as
+/// the event loop overwrote it meanwhile, leaves that event out.
+struct Ring {
+    /// Per slot, twice the position of the event in it among all those recorded, plus one while
+    /// it is being written, plus two once it has been.
+    sequences: Vec<AtomicUsize>,
+    /// The encoded events, `SLOT_BYTES` per slot.
+    words: Vec<AtomicUsize>,
+    /// Number of events recorded so far.
+    recorded: AtomicUsize,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        Ring {
+            sequences: (0..capacity).map(|_| AtomicUsize::new(0)).collect(),
+            words: (0..capacity * SLOT_BYTES / WORD_BYTES)
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            recorded: AtomicUsize::new(0),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.sequences.len()
+    }
+
+    fn slot_words(&self, slot: usize) -> &[AtomicUsize] {
+        let words_per_slot = SLOT_BYTES / WORD_BYTES;
+        &self.words[slot * words_per_slot..(slot + 1) * words_per_slot]
+    }
+
+    /// Only ever called by the event loop.
+    fn push(&self, event: &RecordedEvent) {
+        let position = self.recorded.load(Ordering::Relaxed);
+        let slot = position % self.capacity();
+        let sequence = &self.sequences[slot];
+        sequence.store(position.wrapping_mul(2).wrapping_add(1), Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        let bytes = encode(event);
+        for (word, chunk) in self.slot_words(slot).iter().zip(bytes.chunks(WORD_BYTES)) {
+            let value = chunk
+                .iter()
+                .rev()
+                .fold(0, |value, byte| (value << 8) | *byte as usize);
+            word.store(value, Ordering::Relaxed);
+        }
+        sequence.store(position.wrapping_mul(2).wrapping_add(2), Ordering::Release);
+        self.recorded.store(position.wrapping_add(1), Ordering::Release);
+    }
+
+    /// The events recorded, oldest first.
+    fn read(&self) -> Vec<RecordedEvent> {
+        let recorded = self.recorded.load(Ordering::Acquire);
+        let oldest = recorded.saturating_sub(self.capacity());
+        (oldest..recorded)
+            .filter_map(|position| self.read_slot(position))
+            .collect()
+    }
+
+    fn read_slot(&self, position: usize) -> Option<RecordedEvent> {
+        let slot = position % self.capacity();
+        let sequence = &self.sequences[slot];
+        let written = position.wrapping_mul(2).wrapping_add(2);
+        if sequence.load(Ordering::Acquire) != written {
+            return None;
+        }
+        let mut bytes = [0; SLOT_BYTES];
+        for (word, chunk) in self.slot_words(slot).iter().zip(bytes.chunks_mut(WORD_BYTES)) {
+            let mut value = word.load(Ordering::Relaxed);
+            for byte in chunk {
+                *byte = value as u8;
+                value >>= 8;
+            }
+        }
+        atomic::fence(Ordering::Acquire);
+        if sequence.load(Ordering::Relaxed) != written {
+            return None;
+        }
+        decode(&bytes)
+    }
+}
+
+/// Lays an event out as: the time since the start in nanoseconds, the connection, the kind and
+/// its payload, little endian.
+fn encode(event: &RecordedEvent) -> [u8; SLOT_BYTES] {
+    let mut bytes = [0; SLOT_BYTES];
+    let nanos = event
+        .since_start
+        .as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(event.since_start.subsec_nanos()));
+    put_u64(&mut bytes[0..8], nanos);
+    put_u64(&mut bytes[8..16], event.connection as u64);
+    let (kind, addr) = match event.kind {
+        RecordedEventKind::Accepted => (1, None),
+        RecordedEventKind::HandshakeParked => (2, None),
+        RecordedEventKind::HandshakeExpired => (3, None),
+        RecordedEventKind::HandshakeSucceeded => (4, None),
+        RecordedEventKind::HandshakeFailed => (5, None),
+        RecordedEventKind::Disconnected => (6, None),
+        RecordedEventKind::MessagesDropped(count) => {
+            put_u64(&mut bytes[17..25], count as u64);
+            (7, None)
+        }
+        RecordedEventKind::BootstrapAttempt(addr) => (8, Some(addr)),
+        RecordedEventKind::BootstrapSucceeded(addr) => (9, Some(addr)),
+        RecordedEventKind::BootstrapFailed(addr) => (10, Some(addr)),
+    };
+    bytes[16] = kind;
+    match addr {
+        Some(SocketAddr::V4(addr)) => {
+            bytes[17] = 4;
+            bytes[18..22].copy_from_slice(&addr.ip().octets());
+            put_u64(&mut bytes[34..36], u64::from(addr.port()));
+        }
+        Some(SocketAddr::V6(addr)) => {
+            bytes[17] = 6;
+            bytes[18..34].copy_from_slice(&addr.ip().octets());
+            put_u64(&mut bytes[34..36], u64::from(addr.port()));
+            put_u64(&mut bytes[36..40], u64::from(addr.flowinfo()));
+            put_u64(&mut bytes[40..44], u64::from(addr.scope_id()));
+        }
+        None => (),
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8; SLOT_BYTES]) -> Option<RecordedEvent> {
+    let nanos = get_u64(&bytes[0..8]);
+    let addr = || {
+        let port = get_u64(&bytes[34..36]) as u16;
+        match bytes[17] {
+            4 => {
+                let ip = Ipv4Addr::new(bytes[18], bytes[19], bytes[20], bytes[21]);
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+            }
+            6 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&bytes[18..34]);
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(octets),
+                    port,
+                    get_u64(&bytes[36..40]) as u32,
+                    get_u64(&bytes[40..44]) as u32,
+                )))
+            }
+            _ => None,
+        }
+    };
+    let kind = match bytes[16] {
+        1 => RecordedEventKind::Accepted,
+        2 => RecordedEventKind::HandshakeParked,
+        3 => RecordedEventKind::HandshakeExpired,
+        4 => RecordedEventKind::HandshakeSucceeded,
+        5 => RecordedEventKind::HandshakeFailed,
+        6 => RecordedEventKind::Disconnected,
+        7 => RecordedEventKind::MessagesDropped(get_u64(&bytes[17..25]) as usize),
+        8 => RecordedEventKind::BootstrapAttempt(addr()?),
+        9 => RecordedEventKind::BootstrapSucceeded(addr()?),
+        10 => RecordedEventKind::BootstrapFailed(addr()?),
+        _ => return None,
+    };
+    Some(RecordedEvent {
+        since_start: Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32),
+        connection: get_u64(&bytes[8..16]) as usize,
+        kind,
+    })
+}
+
+/// Writes the low bytes of `value` to `bytes`, little endian.
+fn put_u64(bytes: &mut [u8], mut value: u64) {
+    for byte in bytes {
+        *byte = value as u8;
+        value >>= 8;
+    }
+}
+
+fn get_u64(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | u64::from(*byte))
+}
+
+fn write_dump(ring: &Ring, dump_path: &Option<PathBuf>) {
+    let path = match *dump_path {
+        Some(ref path) if ring.capacity() > 0 => path,
+        _ => return,
+    };
+    match write_to(&ring.read(), path) {
+        Ok(()) => info!("Flight record written to {:?}", path),
+        Err(e) => error!("Could not write flight record to {:?}: {:?}", path, e),
+    }
+}
+
+fn write_to(events: &[RecordedEvent], path: &PathBuf) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for event in events {
+        writeln!(
+            file,
+            "{}.{:06} {} {:?}",
+            event.since_start.as_secs(),
+            event.since_start.subsec_nanos() / 1000,
+            event.connection,
+            event.kind
+        )?;
+    }
+    file.flush()
+}
+
+impl Drop for FlightRecorder {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.write_dump();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_first() {
+        let capacity = 4;
+        let mut recorder = FlightRecorder::new(capacity * (SLOT_BYTES + WORD_BYTES), None);
+
+        for i in 0..10 {
+            recorder.record(Token(i), RecordedEventKind::Accepted);
+        }
+
+        let events = recorder.dump();
+        let connections: Vec<_> = events.iter().map(|event| event.connection).collect();
+        assert_eq!(connections, vec![6, 7, 8, 9]);
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].since_start <= pair[1].since_start));
+        assert_eq!(recorder.ring.capacity(), capacity);
+    }
+
+    #[test]
+    fn every_kind_is_read_back_as_recorded() {
+        let kinds = vec![
+            RecordedEventKind::Accepted,
+            RecordedEventKind::HandshakeParked,
+            RecordedEventKind::HandshakeExpired,
+            RecordedEventKind::HandshakeSucceeded,
+            RecordedEventKind::HandshakeFailed,
+            RecordedEventKind::Disconnected,
+            RecordedEventKind::MessagesDropped(usize::max_value()),
+            RecordedEventKind::BootstrapAttempt(unwrap!("192.0.2.1:5483".parse())),
+            RecordedEventKind::BootstrapSucceeded(unwrap!("[2001:db8::1]:5483".parse())),
+            RecordedEventKind::BootstrapFailed(SocketAddr::V6(SocketAddrV6::new(
+                unwrap!("fe80::1".parse()),
+                65535,
+                7,
+                3,
+            ))),
+        ];
+        let mut recorder = FlightRecorder::new(kinds.len() * (SLOT_BYTES + WORD_BYTES), None);
+        for (i, kind) in kinds.iter().enumerate() {
+            recorder.record(Token(usize::max_value() - i), *kind);
+        }
+        let events = recorder.dump();
+        let recorded: Vec<_> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(recorded, kinds);
+        assert_eq!(events[0].connection, usize::max_value());
+    }
+
+    #[test]
+    fn events_read_while_recording_are_whole() {
+        let capacity = 8;
+        let mut recorder = FlightRecorder::new(capacity * (SLOT_BYTES + WORD_BYTES), None);
+        let ring = recorder.ring.clone();
+        let reader = thread::spawn(move || {
+            for _ in 0..1000 {
+                for event in ring.read() {
+                    // Each event was recorded with its connection repeated in the count.
+                    assert_eq!(
+                        event.kind,
+                        RecordedEventKind::MessagesDropped(event.connection)
+                    );
+                }
+            }
+        });
+        for i in 0..100_000 {
+            recorder.record(Token(i), RecordedEventKind::MessagesDropped(i));
+        }
+        unwrap!(reader.join());
+    }
+
+    #[test]
+    fn disabled_records_nothing() {
+        let mut recorder = FlightRecorder::disabled();
+        recorder.record(Token(0), RecordedEventKind::Disconnected);
+        assert!(recorder.dump().is_empty());
+    }
+}
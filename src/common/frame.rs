@@ -0,0 +1,794 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Wire format of the messages exchanged over a socket, independent of the socket itself: every
+// message is serialised into a frame prefixed with its length as a little endian `u32`.
+
+use byteorder::{ByteOrder, LittleEndian};
+use common::{CommonError, FrameCipher, Result, SharedBuffer, TIMESTAMP_TRAILER_SIZE};
+use maidsafe_utilities::serialisation::{deserialise, serialise_into};
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::cmp;
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+use std::ops::Deref;
+#[cfg(feature = "copy-audit")]
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::Arc;
+
+/// Size of the length prefix of every frame.
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Serialised `Message::Data` starts with the variant index as a little endian `u32`, followed by
+/// the payload length as a little endian `u64`.
+const DATA_VARIANT_INDEX: u32 = 8;
+const DATA_HEADER_SIZE: usize = 4 + 8;
+
+/// Size of the header written in front of the payload of a data frame.
+const OUT_HEADER_SIZE: usize = FRAME_HEADER_SIZE + DATA_HEADER_SIZE;
+
+/// Payloads serialised into a frame, rather than written from their own allocation, since start.
+#[cfg(feature = "copy-audit")]
+static PAYLOAD_COPIES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Number of message payloads copied on their way to a socket since the process started. Only
+/// available with the `copy-audit` feature.
+#[cfg(feature = "copy-audit")]
+pub fn payload_copies() -> usize {
+    PAYLOAD_COPIES.load(Ordering::SeqCst)
+}
+
+/// A structure sent over the wire, which encodes to and decodes from plain bytes without any
+/// socket involved. `test_vectors` pins down the bytes of each.
+pub trait WireFormat: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn encode_to(&self, out: &mut Vec<u8>) -> Result<()>;
+    /// Decodes a value which has to take up the whole of `bytes`.
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+/// A message in its length prefixed frame, as sent over a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame<T>(pub T);
+
+impl<T: WireFormat> WireFormat for Frame<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) -> Result<()> {
+        frame_into(out, |body| self.0.encode_to(body))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < FRAME_HEADER_SIZE
+            || LittleEndian::read_u32(&bytes[..FRAME_HEADER_SIZE]) as usize
+                != bytes.len() - FRAME_HEADER_SIZE
+        {
+            return Err(CommonError::MalformedFrame);
+        }
+        Ok(Frame(T::decode(&bytes[FRAME_HEADER_SIZE..])?))
+    }
+}
+
+/// Serialises `msg` into a length prefixed frame.
+pub fn encode_frame<T: Serialize>(msg: &T) -> Result<Vec<u8>> {
+    let mut frame = Vec::new();
+    frame_into(&mut frame, |body| Ok(serialise_into(msg, body)?))?;
+    Ok(frame)
+}
+
+/// Appends a frame to `out`, whose body is appended by `encode_body`.
+fn frame_into<F>(out: &mut Vec<u8>, encode_body: F) -> Result<()>
+where
+    F: FnOnce(&mut Vec<u8>) -> Result<()>,
+{
+    let start = out.len();
+    out.extend_from_slice(&[0; FRAME_HEADER_SIZE]);
+    encode_body(out)?;
+    let len = out.len() - start - FRAME_HEADER_SIZE;
+    LittleEndian::write_u32(&mut out[start..start + FRAME_HEADER_SIZE], len as u32);
+    Ok(())
+}
+
+/// Deserialises the body of a frame. The message has to take up the whole body.
+pub fn decode_message<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    Ok(deserialise(body)?)
+}
+
+/// If the frame body is a `Message::Data` with a payload of at least `min_len` bytes, returns the
+/// payload as a view into the body, without copying it. Otherwise the body is handed back.
+pub fn split_data_frame(
+    body: Vec<u8>,
+    min_len: usize,
+) -> ::std::result::Result<SharedBuffer, Vec<u8>> {
+    if body.len() < DATA_HEADER_SIZE + min_len || !is_data_body(&body) {
+        return Err(body);
+    }
+    let len = body.len();
+    Ok(SharedBuffer::slice(body, DATA_HEADER_SIZE..len))
+}
+
+fn is_data_body(body: &[u8]) -> bool {
+    body.len() >= DATA_HEADER_SIZE
+        && LittleEndian::read_u32(&body[..4]) == DATA_VARIANT_INDEX
+        && LittleEndian::read_u64(&body[4..DATA_HEADER_SIZE])
+            == (body.len() - DATA_HEADER_SIZE) as u64
+}
+
+/// A frame on its way out. A data frame is written as a header built on the stack followed by the
+/// payload in the very allocation it was sent in, so the payload is never copied. Any other
+/// message is serialised up front. How much has been written is kept as an offset, so a partial
+/// write is resumed where it stopped.
+pub struct OutFrame {
+    header: [u8; OUT_HEADER_SIZE],
+    header_len: usize,
+    body: Body,
+    trailer: [u8; TIMESTAMP_TRAILER_SIZE],
+    trailer_len: usize,
+    is_data: bool,
+    written: usize,
+    #[cfg(feature = "copy-audit")]
+    audit: CopyAudit,
+}
+
+impl OutFrame {
+    /// Frames `payload` as a `Message::Data`, taking ownership of it.
+    pub fn data(payload: Vec<u8>) -> Self {
+        Self::data_body(Body::Owned(payload))
+    }
+
+    /// Frames `payload` as a `Message::Data` without taking it from the other frames it may be
+    /// queued in, on other connections, so that it is written to them all from one allocation.
+    pub fn shared(payload: Arc<Vec<u8>>) -> Self {
+        Self::data_body(Body::Shared(payload))
+    }
+
+    fn data_body(payload: Body) -> Self {
+        let mut header = [0; OUT_HEADER_SIZE];
+        LittleEndian::write_u32(
+            &mut header[..FRAME_HEADER_SIZE],
+            (DATA_HEADER_SIZE + payload.len()) as u32,
+        );
+        LittleEndian::write_u32(
+            &mut header[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + 4],
+            DATA_VARIANT_INDEX,
+        );
+        LittleEndian::write_u64(&mut header[FRAME_HEADER_SIZE + 4..], payload.len() as u64);
+        OutFrame {
+            #[cfg(feature = "copy-audit")]
+            audit: CopyAudit::new(&payload, 0),
+            header,
+            header_len: OUT_HEADER_SIZE,
+            body: payload,
+            trailer: [0; TIMESTAMP_TRAILER_SIZE],
+            trailer_len: 0,
+            is_data: true,
+            written: 0,
+        }
+    }
+
+    /// Serialises `msg` into a frame. Data messages should go through `data` instead, as their
+    /// payload is copied here.
+    pub fn message<T: Serialize>(msg: &T) -> Result<Self> {
+        let frame = encode_frame(msg)?;
+        #[cfg(feature = "copy-audit")]
+        let copies = if is_data_body(&frame[FRAME_HEADER_SIZE..]) {
+            let _ = PAYLOAD_COPIES.fetch_add(1, Ordering::SeqCst);
+            1
+        } else {
+            0
+        };
+        Ok(OutFrame {
+            #[cfg(feature = "copy-audit")]
+            audit: CopyAudit::new(&frame, copies),
+            header: [0; OUT_HEADER_SIZE],
+            header_len: 0,
+            body: Body::Owned(frame),
+            trailer: [0; TIMESTAMP_TRAILER_SIZE],
+            trailer_len: 0,
+            is_data: false,
+            written: 0,
+        })
+    }
+
+    /// Frames `body` as it is, such as the body of a frame read off another connection to be
+    /// forwarded unopened.
+    #[cfg(feature = "relay")]
+    pub fn raw(body: Vec<u8>) -> Self {
+        let mut header = [0; OUT_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[..FRAME_HEADER_SIZE], body.len() as u32);
+        OutFrame {
+            #[cfg(feature = "copy-audit")]
+            audit: CopyAudit::new(&body, 0),
+            header,
+            header_len: FRAME_HEADER_SIZE,
+            body: Body::Owned(body),
+            trailer: [0; TIMESTAMP_TRAILER_SIZE],
+            trailer_len: 0,
+            is_data: false,
+            written: 0,
+        }
+    }
+
+    /// Number of bytes the frame takes up on the wire, all held in memory until it is written.
+    pub fn wire_len(&self) -> usize {
+        self.header_len + self.body.len() + self.trailer_len
+    }
+
+    /// Number of bytes of the frame yet to be written.
+    pub fn remaining(&self) -> usize {
+        self.wire_len() - self.written
+    }
+
+    /// Whether any of the frame has been written yet.
+    pub fn is_started(&self) -> bool {
+        self.written > 0
+    }
+
+    /// Makes room for a timestamp trailer after the body, counted in the length prefix. It is
+    /// filled in with `set_trailer` right before the frame starts to be written.
+    pub fn reserve_trailer(&mut self) {
+        debug_assert!(!self.is_started() && self.trailer_len == 0);
+        let prefix = match (self.is_data, &mut self.body) {
+            (false, &mut Body::Owned(ref mut body)) => &mut body[..FRAME_HEADER_SIZE],
+            _ => &mut self.header[..FRAME_HEADER_SIZE],
+        };
+        let len = LittleEndian::read_u32(prefix) as usize + TIMESTAMP_TRAILER_SIZE;
+        LittleEndian::write_u32(prefix, len as u32);
+        self.trailer_len = TIMESTAMP_TRAILER_SIZE;
+    }
+
+    /// Takes back the room made by `reserve_trailer`, for a frame moved to a connection without
+    /// timestamps.
+    pub fn release_trailer(&mut self) {
+        debug_assert!(!self.is_started() && self.trailer_len == TIMESTAMP_TRAILER_SIZE);
+        let prefix = match (self.is_data, &mut self.body) {
+            (false, &mut Body::Owned(ref mut body)) => &mut body[..FRAME_HEADER_SIZE],
+            _ => &mut self.header[..FRAME_HEADER_SIZE],
+        };
+        let len = LittleEndian::read_u32(prefix) as usize - TIMESTAMP_TRAILER_SIZE;
+        LittleEndian::write_u32(prefix, len as u32);
+        self.trailer_len = 0;
+    }
+
+    pub fn has_trailer(&self) -> bool {
+        self.trailer_len > 0
+    }
+
+    pub fn set_trailer(&mut self, trailer: [u8; TIMESTAMP_TRAILER_SIZE]) {
+        debug_assert!(!self.is_started());
+        self.trailer = trailer;
+    }
+
+    /// Replaces everything after the length prefix, trailer included, with its sealed version,
+    /// right before the frame starts to be written. The payload of a data frame is sealed into an
+    /// allocation of its own, which the `copy-audit` feature doesn't count as a copy, as there
+    /// is no sealing it in place.
+    pub fn seal(&mut self, cipher: &mut FrameCipher) {
+        debug_assert!(!self.is_started());
+        let mut plain = Vec::with_capacity(self.wire_len() - FRAME_HEADER_SIZE);
+        if self.is_data {
+            plain.extend_from_slice(&self.header[FRAME_HEADER_SIZE..self.header_len]);
+            plain.extend_from_slice(&self.body);
+        } else {
+            plain.extend_from_slice(&self.body[FRAME_HEADER_SIZE..]);
+        }
+        plain.extend_from_slice(&self.trailer[..self.trailer_len]);
+
+        self.body = Body::Owned(cipher.seal(&plain));
+        LittleEndian::write_u32(&mut self.header[..FRAME_HEADER_SIZE], self.body.len() as u32);
+        self.header_len = FRAME_HEADER_SIZE;
+        self.trailer_len = 0;
+        self.is_data = false;
+        #[cfg(feature = "copy-audit")]
+        {
+            self.audit = CopyAudit::new(&self.body, 0);
+        }
+    }
+
+    /// Hands back the payload of a data frame none of which has been written yet. A shared
+    /// payload is copied out unless no other frame holds it any more.
+    pub fn into_payload(self) -> Option<Vec<u8>> {
+        if !self.is_data || self.is_started() {
+            return None;
+        }
+        match self.body {
+            Body::Owned(payload) => Some(payload),
+            Body::Shared(payload) => {
+                Some(Arc::try_unwrap(payload).unwrap_or_else(|payload| (*payload).clone()))
+            }
+        }
+    }
+
+    /// Writes the rest of the frame, for as long as `writer` takes it. Fails with whatever error
+    /// stopped the writer, `WouldBlock` included, keeping track of what has been written so far.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let body_end = self.header_len + self.body.len();
+        let len = body_end + self.trailer_len;
+        while self.written < len {
+            let bytes_txd = if self.written < self.header_len {
+                writer.write(&self.header[self.written..self.header_len])?
+            } else if self.written < body_end {
+                writer.write(&self.body[self.written - self.header_len..])?
+            } else {
+                writer.write(&self.trailer[self.written - body_end..self.trailer_len])?
+            };
+            if bytes_txd == 0 {
+                return Err(io::Error::new(ErrorKind::WriteZero, "Failed to write frame"));
+            }
+            self.written += bytes_txd;
+        }
+        #[cfg(feature = "copy-audit")]
+        self.audit.check(&self.body);
+        Ok(())
+    }
+}
+
+/// The body of an `OutFrame`: its own, or the payload of a data frame shared with others.
+enum Body {
+    Owned(Vec<u8>),
+    Shared(Arc<Vec<u8>>),
+}
+
+impl Deref for Body {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Body::Owned(ref body) => body,
+            Body::Shared(ref body) => body,
+        }
+    }
+}
+
+/// Follows a frame with the `copy-audit` feature, to assert in debug builds that its payload was
+/// written from the allocation it was queued in and never copied on the way.
+#[cfg(feature = "copy-audit")]
+struct CopyAudit {
+    origin: usize,
+    copies: usize,
+}
+
+#[cfg(feature = "copy-audit")]
+impl CopyAudit {
+    fn new(body: &[u8], copies: usize) -> Self {
+        CopyAudit {
+            origin: body.as_ptr() as usize,
+            copies,
+        }
+    }
+
+    fn check(&self, body: &[u8]) {
+        debug_assert_eq!(
+            body.as_ptr() as usize,
+            self.origin,
+            "Frame body moved to another allocation"
+        );
+        debug_assert_eq!(
+            self.copies,
+            0,
+            "Payload of {} bytes copied on its way to the socket",
+            body.len() - DATA_HEADER_SIZE - FRAME_HEADER_SIZE
+        );
+    }
+}
+
+/// A frame whose body has only been received in part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialFrame {
+    /// Position of the frame in the stream, counting from 0.
+    pub seq: u64,
+    /// Length of the body, as given by the header. This much is allocated for it as its first
+    /// byte arrives.
+    pub len: usize,
+    /// Bytes of the body received so far.
+    pub received: usize,
+}
+
+/// Splits a byte stream into frames. Bytes can be fed in chunks of any size, and the decoder never
+/// holds more than one (partial) frame body, whose size is checked against `max_frame_size`
+/// before anything is allocated for it. Nothing is allocated until the first byte of the body is
+/// fed in either, so a caller which feeds no more than `wanted` at a time gets to account for the
+/// body, or to hold it back, in between.
+///
+/// After an error the stream can't be resynchronised: every further call fails the same way.
+pub struct FrameDecoder {
+    max_frame_size: usize,
+    header: [u8; FRAME_HEADER_SIZE],
+    header_len: usize,
+    body_len: Option<usize>,
+    body: Vec<u8>,
+    /// Number of frames whose header has been read.
+    frames_begun: u64,
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameDecoder {
+            max_frame_size,
+            header: [0; FRAME_HEADER_SIZE],
+            header_len: 0,
+            body_len: None,
+            body: Vec::new(),
+            frames_begun: 0,
+        }
+    }
+
+    /// Returns the frame being received, if its header has been read but not all of its body.
+    pub fn partial_frame(&self) -> Option<PartialFrame> {
+        self.body_len.map(|len| PartialFrame {
+            seq: self.frames_begun - 1,
+            len,
+            received: self.body.len(),
+        })
+    }
+
+    /// Number of bytes up to the end of the header or body being read, whichever comes next.
+    pub fn wanted(&self) -> usize {
+        match self.body_len {
+            Some(body_len) => body_len - self.body.len(),
+            None => FRAME_HEADER_SIZE - self.header_len,
+        }
+    }
+
+    /// Consumes bytes from the front of `input`, at most up to the end of the current frame, and
+    /// returns the frame body once it is complete.
+    pub fn decode(&mut self, input: &mut &[u8]) -> Result<Option<Vec<u8>>> {
+        let body_len = match self.body_len {
+            Some(body_len) => body_len,
+            None => {
+                let take = cmp::min(FRAME_HEADER_SIZE - self.header_len, input.len());
+                self.header[self.header_len..self.header_len + take]
+                    .copy_from_slice(&input[..take]);
+                self.header_len += take;
+                *input = &input[take..];
+                if self.header_len < FRAME_HEADER_SIZE {
+                    return Ok(None);
+                }
+
+                let body_len = LittleEndian::read_u32(&self.header) as usize;
+                if body_len > self.max_frame_size {
+                    return Err(CommonError::PayloadSizeProhibitive);
+                }
+                self.body_len = Some(body_len);
+                self.frames_begun += 1;
+                body_len
+            }
+        };
+
+        let take = cmp::min(body_len - self.body.len(), input.len());
+        if take > 0 && self.body.capacity() == 0 {
+            self.body = Vec::with_capacity(body_len);
+        }
+        self.body.extend_from_slice(&input[..take]);
+        *input = &input[take..];
+        if self.body.len() < body_len {
+            return Ok(None);
+        }
+
+        self.header_len = 0;
+        self.body_len = None;
+        Ok(Some(mem::replace(&mut self.body, Vec::new())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{Message, MAX_PAYLOAD_SIZE};
+    use maidsafe_utilities::serialisation::serialise;
+    use tests::UniqueId;
+
+    const MAX: usize = 1024;
+
+    fn decode_all(decoder: &mut FrameDecoder, mut input: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+        while !input.is_empty() {
+            if let Some(frame) = decoder.decode(&mut input)? {
+                frames.push(frame);
+            }
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let msgs = vec![
+            Message::Data::<UniqueId>(vec![1, 2, 3]),
+            Message::Heartbeat,
+            Message::Data(vec![7; 500]),
+        ];
+        let stream: Vec<u8> = msgs
+            .iter()
+            .flat_map(|msg| unwrap!(encode_frame(msg)))
+            .collect();
+
+        let mut decoder = FrameDecoder::new(MAX);
+        let mut frames = Vec::new();
+        for byte in &stream {
+            frames.extend(unwrap!(decode_all(&mut decoder, &[*byte])));
+        }
+        assert_eq!(decoder.header_len, 0);
+
+        let decoded: Vec<Message<UniqueId>> = frames
+            .iter()
+            .map(|frame| unwrap!(decode_message(frame)))
+            .collect();
+        assert_eq!(decoded, msgs);
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut decoder = FrameDecoder::new(MAX);
+        let mut header = [0; FRAME_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header, MAX as u32 + 1);
+        match decode_all(&mut decoder, &header) {
+            Err(CommonError::PayloadSizeProhibitive) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // Nothing was allocated for the claimed size, and the error sticks.
+        assert_eq!(decoder.body.capacity(), 0);
+        match decode_all(&mut decoder, &[0]) {
+            Err(CommonError::PayloadSizeProhibitive) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // A `u32::MAX` length must not overflow or allocate either.
+        let mut decoder = FrameDecoder::new(MAX);
+        match decode_all(&mut decoder, &[0xff; FRAME_HEADER_SIZE]) {
+            Err(CommonError::PayloadSizeProhibitive) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn body_is_allocated_as_it_arrives() {
+        let mut frame = vec![0; FRAME_HEADER_SIZE];
+        LittleEndian::write_u32(&mut frame, 100);
+        frame.extend_from_slice(&[3; 100]);
+
+        let mut decoder = FrameDecoder::new(MAX);
+        assert_eq!(decoder.wanted(), FRAME_HEADER_SIZE);
+        assert!(unwrap!(decode_all(&mut decoder, &frame[..3])).is_empty());
+        assert_eq!(decoder.wanted(), 1);
+        assert!(unwrap!(decode_all(&mut decoder, &frame[3..4])).is_empty());
+        assert_eq!(decoder.wanted(), 100);
+        assert_eq!(decoder.body.capacity(), 0);
+
+        assert!(unwrap!(decode_all(&mut decoder, &frame[4..5])).is_empty());
+        assert_eq!(decoder.wanted(), 99);
+        assert_eq!(decoder.body.capacity(), 100);
+        let frames = unwrap!(decode_all(&mut decoder, &frame[5..]));
+        assert_eq!(frames, vec![vec![3; 100]]);
+        assert_eq!(decoder.wanted(), FRAME_HEADER_SIZE);
+    }
+
+    #[test]
+    fn partial_frames_are_reported() {
+        let stream: Vec<u8> = [vec![1; 10], vec![], vec![2; 3]]
+            .iter()
+            .flat_map(|body| {
+                let mut frame = vec![0; FRAME_HEADER_SIZE];
+                LittleEndian::write_u32(&mut frame, body.len() as u32);
+                frame.extend_from_slice(body);
+                frame
+            })
+            .collect();
+
+        let mut decoder = FrameDecoder::new(MAX);
+        assert_eq!(decoder.partial_frame(), None);
+        // Not before the header is complete.
+        assert!(unwrap!(decode_all(&mut decoder, &stream[..3])).is_empty());
+        assert_eq!(decoder.partial_frame(), None);
+        assert!(unwrap!(decode_all(&mut decoder, &stream[3..8])).is_empty());
+        assert_eq!(
+            decoder.partial_frame(),
+            Some(PartialFrame {
+                seq: 0,
+                len: 10,
+                received: 4,
+            })
+        );
+
+        // The empty frame is complete as soon as its header is.
+        let frames = unwrap!(decode_all(&mut decoder, &stream[8..20]));
+        assert_eq!(frames, vec![vec![1; 10], vec![]]);
+        assert_eq!(decoder.partial_frame(), None);
+        assert!(unwrap!(decode_all(&mut decoder, &stream[20..23])).is_empty());
+        assert_eq!(
+            decoder.partial_frame(),
+            Some(PartialFrame {
+                seq: 2,
+                len: 3,
+                received: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn frames_do_not_bleed_into_each_other() {
+        // A frame whose header claims fewer bytes than the message needs used to be deserialised
+        // from the following bytes of the stream.
+        let body = unwrap!(serialise(&Message::Data::<UniqueId>(vec![9; 16])));
+        let mut stream = vec![0; FRAME_HEADER_SIZE];
+        LittleEndian::write_u32(&mut stream, 4);
+        stream.extend_from_slice(&body);
+
+        let mut decoder = FrameDecoder::new(MAX);
+        let mut input = &stream[..];
+        let frame = unwrap!(unwrap!(decoder.decode(&mut input)));
+        assert_eq!(frame.len(), 4);
+        match decode_message::<Message<UniqueId>>(&frame) {
+            Err(CommonError::Serialisation(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Trailing bytes after a complete message are rejected too.
+        let mut body = unwrap!(serialise(&Message::Heartbeat::<UniqueId>));
+        body.push(0);
+        match decode_message::<Message<UniqueId>>(&body) {
+            Err(CommonError::Serialisation(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn malformed_bodies() {
+        let mut huge_vec = unwrap!(serialise(&Message::Data::<UniqueId>(vec![])));
+        // Claim a `u64::MAX` long payload in a tiny frame.
+        let len = huge_vec.len();
+        for byte in &mut huge_vec[len - 8..] {
+            *byte = 0xff;
+        }
+
+        for body in &[vec![], vec![0xff; 4], huge_vec] {
+            match decode_message::<Message<UniqueId>>(body) {
+                Err(CommonError::Serialisation(_)) => (),
+                res => panic!("Unexpected result for {:?}: {:?}", body, res),
+            }
+        }
+    }
+
+    #[test]
+    fn data_payload_shares_the_frame_body() {
+        let payload = vec![7; 1000];
+        let msg = Message::Data::<UniqueId>(payload.clone());
+        let stream = unwrap!(encode_frame(&msg));
+
+        // Copied once, from the stream into the body of the frame...
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut input = &stream[..];
+        let frame = unwrap!(unwrap!(decoder.decode(&mut input)));
+        let body_ptr = frame.as_ptr() as usize;
+
+        // ...and not again when handed out.
+        let shared = unwrap!(split_data_frame(frame, payload.len()));
+        assert_eq!(shared.to_vec(), payload);
+        assert_eq!(shared.as_ptr() as usize, body_ptr + DATA_HEADER_SIZE);
+
+        // Smaller payloads and other messages are left to `decode_message`.
+        let frame = unwrap!(serialise(&msg));
+        let frame = unwrap!(split_data_frame(frame, payload.len() + 1).err());
+        assert_eq!(unwrap!(decode_message::<Message<UniqueId>>(&frame)), msg);
+        let frame = unwrap!(serialise(&Message::Goodbye::<UniqueId>(1000)));
+        assert!(split_data_frame(frame, 0).is_err());
+    }
+
+    // Takes at most `chunk` bytes per call, and fails with `WouldBlock` every other call.
+    struct ChokedWriter {
+        written: Vec<u8>,
+        chunk: usize,
+        blocked: bool,
+    }
+
+    impl Write for ChokedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "blocked"));
+            }
+            let len = cmp::min(self.chunk, buf.len());
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn data_frames_are_written_from_the_payload() {
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let expected = unwrap!(encode_frame(&Message::Data::<UniqueId>(payload.clone())));
+
+        let origin = payload.as_ptr() as usize;
+        let frame = OutFrame::data(payload);
+        assert!(!frame.is_started());
+        let payload = unwrap!(frame.into_payload());
+        assert_eq!(payload.as_ptr() as usize, origin);
+
+        // Partial writes, the header included, pick up where they stopped.
+        let mut writer = ChokedWriter {
+            written: Vec::new(),
+            chunk: 7,
+            blocked: false,
+        };
+        let mut frame = OutFrame::data(payload);
+        let mut blocks = 0;
+        while let Err(error) = frame.write_to(&mut writer) {
+            assert_eq!(error.kind(), ErrorKind::WouldBlock);
+            assert!(frame.is_started() || blocks == 0);
+            blocks += 1;
+        }
+        assert_eq!(writer.written, expected);
+        assert!(frame.into_payload().is_none());
+
+        // Other messages are serialised whole.
+        let mut writer = ChokedWriter {
+            written: Vec::new(),
+            chunk: 3,
+            blocked: false,
+        };
+        let mut frame = unwrap!(OutFrame::message(&Message::Goodbye::<UniqueId>(1000)));
+        while frame.write_to(&mut writer).is_err() {}
+        assert_eq!(
+            writer.written,
+            unwrap!(encode_frame(&Message::Goodbye::<UniqueId>(1000)))
+        );
+        assert!(frame.into_payload().is_none());
+    }
+
+    #[test]
+    fn shared_payloads_are_written_from_one_allocation() {
+        let payload = Arc::new(vec![3; 100]);
+        let expected = unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![3; 100])));
+
+        let mut frames = vec![
+            OutFrame::shared(payload.clone()),
+            OutFrame::shared(payload.clone()),
+        ];
+        for frame in &mut frames {
+            let mut writer = ChokedWriter {
+                written: Vec::new(),
+                chunk: 7,
+                blocked: false,
+            };
+            while frame.write_to(&mut writer).is_err() {}
+            assert_eq!(writer.written, expected);
+        }
+        assert_eq!(Arc::strong_count(&payload), 3);
+
+        // Taken back unwritten, the payload is only copied while it is still shared.
+        let origin = payload.as_ptr() as usize;
+        let copy = unwrap!(OutFrame::shared(payload.clone()).into_payload());
+        assert_ne!(copy.as_ptr() as usize, origin);
+        drop(frames);
+        let payload = unwrap!(OutFrame::shared(payload).into_payload());
+        assert_eq!(payload.as_ptr() as usize, origin);
+    }
+
+    #[cfg(feature = "relay")]
+    #[test]
+    fn raw_frames_are_written_as_they_were_read() {
+        let stream = unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![7; 100])));
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut input = &stream[..];
+        let body = unwrap!(unwrap!(decoder.decode(&mut input)));
+
+        let mut writer = ChokedWriter {
+            written: Vec::new(),
+            chunk: 3,
+            blocked: false,
+        };
+        let mut frame = OutFrame::raw(body);
+        assert_eq!(frame.wire_len(), stream.len());
+        while frame.write_to(&mut writer).is_err() {}
+        assert_eq!(writer.written, stream);
+        assert!(frame.into_payload().is_none());
+    }
+}
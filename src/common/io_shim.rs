@@ -0,0 +1,146 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// How the errors of the calls we make on sockets are handled, and a shim in front of those calls
+// through which tests make them fail with the error of their choice.
+
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+
+/// What to do about an error of a socket call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoErrorClass {
+    /// The call was interrupted by a signal. Make it again straight away.
+    Retry,
+    /// The socket isn't ready. Wait for its next readiness event.
+    WouldBlock,
+    /// The peer closed or reset the connection.
+    RemoteClosed,
+    /// Anything else, which ends the connection.
+    Fatal,
+}
+
+impl IoErrorClass {
+    pub fn of(error: &io::Error) -> Self {
+        Self::of_kind(error.kind())
+    }
+
+    pub fn of_kind(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Interrupted => IoErrorClass::Retry,
+            // `WSAEWOULDBLOCK` and `WSAEINPROGRESS` come as `WouldBlock` too.
+            ErrorKind::WouldBlock => IoErrorClass::WouldBlock,
+            ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::ConnectionAborted => {
+                IoErrorClass::RemoteClosed
+            }
+            _ => IoErrorClass::Fatal,
+        }
+    }
+}
+
+/// A socket call whose errors we act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoSite {
+    Read,
+    Write,
+    Accept,
+}
+
+/// Stands in front of the calls made on one socket. Outside tests it is empty and lets every call
+/// through; in tests, errors injected with `inject` are returned in place of the next calls at
+/// their site.
+#[derive(Debug, Default)]
+pub struct IoShim {
+    #[cfg(test)]
+    faults: VecDeque<(IoSite, ErrorKind)>,
+}
+
+impl IoShim {
+    /// Returns the error the next call at `site` is to fail with, if any.
+    #[cfg(not(test))]
+    #[inline]
+    pub fn check(&mut self, _site: IoSite) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the error the next call at `site` is to fail with, if any.
+    #[cfg(test)]
+    pub fn check(&mut self, site: IoSite) -> io::Result<()> {
+        let pos = self.faults.iter().position(|&(at, _)| at == site);
+        match pos.and_then(|pos| self.faults.remove(pos)) {
+            Some((_, kind)) => Err(io::Error::new(kind, "injected")),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes the next call at `site` not yet failed by an earlier injection fail with `kind`.
+    #[cfg(test)]
+    pub fn inject(&mut self, site: IoSite, kind: ErrorKind) {
+        self.faults.push_back((site, kind));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_kinds_are_classified() {
+        let retry = [ErrorKind::Interrupted];
+        let would_block = [ErrorKind::WouldBlock];
+        let remote_closed = [
+            ErrorKind::ConnectionReset,
+            ErrorKind::BrokenPipe,
+            ErrorKind::ConnectionAborted,
+        ];
+        let fatal = [
+            ErrorKind::NotConnected,
+            ErrorKind::TimedOut,
+            ErrorKind::WriteZero,
+            ErrorKind::PermissionDenied,
+            ErrorKind::Other,
+        ];
+        for (kinds, class) in vec![
+            (&retry[..], IoErrorClass::Retry),
+            (&would_block[..], IoErrorClass::WouldBlock),
+            (&remote_closed[..], IoErrorClass::RemoteClosed),
+            (&fatal[..], IoErrorClass::Fatal),
+        ] {
+            for &kind in kinds {
+                assert_eq!(IoErrorClass::of(&io::Error::from(kind)), class, "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn injected_faults_fail_their_site_in_order() {
+        let mut shim = IoShim::default();
+        shim.inject(IoSite::Read, ErrorKind::Interrupted);
+        shim.inject(IoSite::Write, ErrorKind::BrokenPipe);
+        shim.inject(IoSite::Read, ErrorKind::ConnectionReset);
+
+        assert!(shim.check(IoSite::Accept).is_ok());
+        let kinds: Vec<_> = (0..3)
+            .map(|_| shim.check(IoSite::Read).map_err(|e| e.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Err(ErrorKind::Interrupted),
+                Err(ErrorKind::ConnectionReset),
+                Ok(()),
+            ]
+        );
+        assert_eq!(
+            shim.check(IoSite::Write).map_err(|e| e.kind()),
+            Err(ErrorKind::BrokenPipe)
+        );
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Priority, CONTROL_PRIORITY, MAX_PAYLOAD_SIZE, MSG_DROP_PRIORITY};
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Smallest limit a budget may be configured with, so that a frame of the largest size can always
+/// be read once nothing else is buffered.
+pub const MIN_MEMORY_BUDGET: usize = 4 * MAX_PAYLOAD_SIZE;
+
+/// Shares of the budget, in percent, from which each level of `MemoryPressure` applies.
+const DROP_LOW_PRIORITY_PERCENT: u64 = 70;
+const REFUSE_REASSEMBLY_PERCENT: u64 = 80;
+const PAUSE_READS_PERCENT: u64 = 90;
+const REJECT_ACCEPTS_PERCENT: u64 = 95;
+
+/// How much of `Config::max_buffered_bytes` is in use. Each level keeps the degradations of the
+/// levels below it, so they set in in the order listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryPressure {
+    /// Under 70% of the budget: nothing is held back.
+    Normal,
+    /// From 70%: messages which may be dropped, those of a priority of `MSG_DROP_PRIORITY` or
+    /// beyond, are dropped from the queues of our peers and from those retained for lost peers,
+    /// and no new ones are queued.
+    DropLowPriority,
+    /// From 80%: frames begun are read to their end, but nothing is allocated for new ones.
+    RefuseReassembly,
+    /// From 90%: nothing is read from our peers.
+    PauseReads,
+    /// From 95%: our listeners stop accepting connections.
+    RejectAccepts,
+}
+
+/// Counts the bytes buffered by a service, whether queued to be sent, being reassembled, read and
+/// not delivered yet, or retained for lost peers, and holds them under a limit.
+///
+/// Every buffering subsystem charges the budget before it holds on to bytes and gets a `Charge`,
+/// which credits them back as it is dropped along with them. A charge which would take the total
+/// beyond the limit is refused, so the limit is never exceeded, and each subsystem has its charges
+/// refused early from the level of `MemoryPressure` at which it is to give way.
+///
+/// Clones share the count. Without a limit nothing is counted and every charge is granted.
+#[derive(Clone, Default)]
+pub struct MemoryBudget {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    limit: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    reporter: Mutex<Reporter>,
+}
+
+/// The last level reported, and who to report changes to.
+struct Reporter {
+    level: MemoryPressure,
+    on_change: Option<Box<FnMut(MemoryPressure) + Send>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            inner: Some(Arc::new(Inner {
+                limit,
+                used: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+                reporter: Mutex::new(Reporter {
+                    level: MemoryPressure::Normal,
+                    on_change: None,
+                }),
+            })),
+        }
+    }
+
+    /// A budget which counts nothing.
+    pub fn unlimited() -> Self {
+        MemoryBudget { inner: None }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.inner.as_ref().map(|inner| inner.limit)
+    }
+
+    /// Bytes charged right now.
+    pub fn used(&self) -> usize {
+        self.inner
+            .as_ref()
+            .map_or(0, |inner| inner.used.load(Ordering::SeqCst))
+    }
+
+    /// The most bytes charged at any one time.
+    pub fn peak(&self) -> usize {
+        self.inner
+            .as_ref()
+            .map_or(0, |inner| inner.peak.load(Ordering::SeqCst))
+    }
+
+    pub fn pressure(&self) -> MemoryPressure {
+        match self.inner {
+            Some(ref inner) => level_of(inner.used.load(Ordering::SeqCst), inner.limit),
+            None => MemoryPressure::Normal,
+        }
+    }
+
+    /// Calls `on_change` with the new level whenever the level of pressure changes, from
+    /// whichever thread changed it. Changes are reported in the order they happen. `on_change`
+    /// must not charge or credit the budget itself.
+    pub fn set_on_change(&self, on_change: Box<FnMut(MemoryPressure) + Send>) {
+        if let Some(ref inner) = self.inner {
+            unwrap!(inner.reporter.lock()).on_change = Some(on_change);
+        }
+    }
+
+    /// Charges `bytes`, unless it would take the total beyond the limit.
+    pub fn charge(&self, bytes: usize) -> Option<Charge> {
+        self.charge_if(bytes, |used, limit| used <= limit)
+    }
+
+    /// Charges `bytes`, unless it would take the total to `level` or beyond.
+    pub fn charge_below(&self, bytes: usize, level: MemoryPressure) -> Option<Charge> {
+        self.charge_if(bytes, |used, limit| {
+            used <= limit && level_of(used, limit) < level
+        })
+    }
+
+    /// Charges a message of the given priority waiting to be sent. Those which may be dropped, see
+    /// `MSG_DROP_PRIORITY`, give way from `MemoryPressure::DropLowPriority` on, the others only at
+    /// the limit. Control messages, see `CONTROL_PRIORITY`, never do: at the limit they go
+    /// uncharged, as the socket bounds their queue by itself.
+    pub fn charge_msg(&self, bytes: usize, priority: Priority) -> Option<Charge> {
+        if priority == CONTROL_PRIORITY {
+            Some(self.charge(bytes).unwrap_or_default())
+        } else if priority >= MSG_DROP_PRIORITY {
+            self.charge_below(bytes, MemoryPressure::DropLowPriority)
+        } else {
+            self.charge(bytes)
+        }
+    }
+
+    fn charge_if<F>(&self, bytes: usize, allowed: F) -> Option<Charge>
+    where
+        F: Fn(usize, usize) -> bool,
+    {
+        let inner = match self.inner {
+            Some(ref inner) => inner,
+            None => return Some(Charge::default()),
+        };
+        let mut used = inner.used.load(Ordering::SeqCst);
+        let total = loop {
+            let total = used.checked_add(bytes)?;
+            if !allowed(total, inner.limit) {
+                return None;
+            }
+            match inner
+                .used
+                .compare_exchange_weak(used, total, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break total,
+                Err(actual) => used = actual,
+            }
+        };
+
+        let mut peak = inner.peak.load(Ordering::SeqCst);
+        while peak < total {
+            match inner
+                .peak
+                .compare_exchange_weak(peak, total, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(actual) => peak = actual,
+            }
+        }
+
+        self.report_if_changed(used, total);
+        Some(Charge {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+
+    fn credit(&self, bytes: usize) {
+        if let Some(ref inner) = self.inner {
+            let used = inner.used.fetch_sub(bytes, Ordering::SeqCst);
+            debug_assert!(used >= bytes, "Credited more than was charged");
+            self.report_if_changed(used, used - bytes);
+        }
+    }
+
+    /// Reports the current level if going from `before` to `after` crossed a threshold. The
+    /// level is read again under the lock, so whichever of two racing changes reports last
+    /// reports the level they left the budget at.
+    fn report_if_changed(&self, before: usize, after: usize) {
+        let inner = match self.inner {
+            Some(ref inner) => inner,
+            None => return,
+        };
+        if level_of(before, inner.limit) == level_of(after, inner.limit) {
+            return;
+        }
+        let mut reporter = unwrap!(inner.reporter.lock());
+        let level = level_of(inner.used.load(Ordering::SeqCst), inner.limit);
+        if reporter.level == level {
+            return;
+        }
+        reporter.level = level;
+        if let Some(ref mut on_change) = reporter.on_change {
+            on_change(level);
+        }
+    }
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.limit() {
+            Some(limit) => write!(f, "MemoryBudget({} of {} bytes)", self.used(), limit),
+            None => write!(f, "MemoryBudget(unlimited)"),
+        }
+    }
+}
+
+fn level_of(used: usize, limit: usize) -> MemoryPressure {
+    let percent = if limit == 0 {
+        100
+    } else {
+        (used as u64).saturating_mul(100) / limit as u64
+    };
+    if percent >= REJECT_ACCEPTS_PERCENT {
+        MemoryPressure::RejectAccepts
+    } else if percent >= PAUSE_READS_PERCENT {
+        MemoryPressure::PauseReads
+    } else if percent >= REFUSE_REASSEMBLY_PERCENT {
+        MemoryPressure::RefuseReassembly
+    } else if percent >= DROP_LOW_PRIORITY_PERCENT {
+        MemoryPressure::DropLowPriority
+    } else {
+        MemoryPressure::Normal
+    }
+}
+
+/// Bytes charged to a `MemoryBudget`, credited back when the charge is dropped. It goes along with
+/// what it was charged for, however far that travels.
+#[derive(Default)]
+pub struct Charge {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Charge {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Takes over `other`, to be credited together. Both have to be charged to the same budget.
+    pub fn absorb(&mut self, mut other: Charge) {
+        if other.bytes == 0 {
+            return;
+        }
+        if self.bytes == 0 {
+            mem::swap(self, &mut other);
+            return;
+        }
+        debug_assert!(match (&self.budget.inner, &other.budget.inner) {
+            (&Some(ref ours), &Some(ref theirs)) => Arc::ptr_eq(ours, theirs),
+            _ => false,
+        });
+        self.bytes += mem::replace(&mut other.bytes, 0);
+    }
+
+    /// Credits back up to `bytes` of the charge right away, for part of what it was charged for
+    /// which is gone.
+    pub fn release(&mut self, bytes: usize) {
+        let bytes = cmp::min(bytes, self.bytes);
+        self.bytes -= bytes;
+        self.budget.credit(bytes);
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            self.budget.credit(self.bytes);
+        }
+    }
+}
+
+impl fmt::Debug for Charge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Charge({} bytes)", self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn charges_are_refused_by_level_and_limit() {
+        let budget = MemoryBudget::new(100);
+        let (tx, rx) = mpsc::channel();
+        budget.set_on_change(Box::new(move |level: MemoryPressure| unwrap!(tx.send(level))));
+
+        let first = unwrap!(budget.charge_below(60, MemoryPressure::DropLowPriority));
+        assert_eq!(budget.pressure(), MemoryPressure::Normal);
+        // Low-priority messages give way at 70%...
+        assert!(budget.charge_below(10, MemoryPressure::DropLowPriority).is_none());
+        // ...new frames at 80%...
+        let second = unwrap!(budget.charge_below(15, MemoryPressure::RefuseReassembly));
+        assert_eq!(budget.pressure(), MemoryPressure::DropLowPriority);
+        assert!(budget.charge_below(5, MemoryPressure::RefuseReassembly).is_none());
+        // ...and everything else only at the limit.
+        let third = unwrap!(budget.charge(25));
+        assert_eq!(budget.pressure(), MemoryPressure::RejectAccepts);
+        assert!(budget.charge(1).is_none());
+        assert!(budget.charge_msg(1, 1).is_none());
+        // Control messages still get through, uncharged.
+        let control = unwrap!(budget.charge_msg(10, CONTROL_PRIORITY));
+        assert_eq!(budget.used(), 100);
+        drop(control);
+
+        drop(third);
+        drop(second);
+        assert_eq!(budget.pressure(), MemoryPressure::Normal);
+        drop(first);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.peak(), 100);
+
+        let levels: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            levels,
+            vec![
+                MemoryPressure::DropLowPriority,
+                MemoryPressure::RejectAccepts,
+                MemoryPressure::DropLowPriority,
+                MemoryPressure::Normal,
+            ]
+        );
+    }
+
+    #[test]
+    fn charges_travel_and_merge() {
+        let budget = MemoryBudget::new(1000);
+        let mut merged = Charge::default();
+        merged.absorb(unwrap!(budget.charge(100)));
+        merged.absorb(unwrap!(budget.charge(50)));
+        merged.absorb(Charge::default());
+        assert_eq!(merged.bytes(), 150);
+        assert_eq!(budget.used(), 150);
+
+        merged.release(30);
+        assert_eq!(budget.used(), 120);
+        merged.release(1000);
+        assert_eq!(merged.bytes(), 0);
+        assert_eq!(budget.used(), 0);
+
+        let unlimited = MemoryBudget::unlimited();
+        let charge = unwrap!(unlimited.charge(usize::max_value()));
+        assert_eq!(charge.bytes(), 0);
+        assert_eq!(unlimited.pressure(), MemoryPressure::Normal);
+    }
+
+    #[test]
+    fn limit_holds_across_threads() {
+        const THREADS: usize = 8;
+        const LIMIT: usize = 10_000;
+
+        let budget = MemoryBudget::new(LIMIT);
+        let threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let budget = budget.clone();
+                thread::spawn(move || {
+                    let mut held = Vec::new();
+                    for round in 0..10_000 {
+                        if let Some(charge) = budget.charge(1 + (i * 7 + round) % 97) {
+                            held.push(charge);
+                        }
+                        if round % 3 == 0 {
+                            let _ = held.pop();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            unwrap!(thread.join());
+        }
+        assert_eq!(budget.used(), 0);
+        assert!(budget.peak() <= LIMIT);
+        assert!(budget.peak() > LIMIT / 2);
+    }
+}
@@ -7,7 +7,29 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::{self, ExternalReachability, NameHash};
+use common::{
+    self, Extensions, ExternalReachability, KeyChallenge, NameHash, NetworkNonce, PowChallenge,
+    PublicKey, Result, Uid, WireFormat,
+};
+use maidsafe_utilities::serialisation::serialise_into;
+
+/// Version of the wire protocol, to be bumped along with any change to the bytes of the messages
+/// or frames we send. The fixtures of what each version added are kept in `test_vectors/`.
+///
+/// 1. The handshake messages, heartbeats and data, up to `Message::Data`.
+/// 2. Adds goodbyes, liveness probes, proofs of work, contact info updates, rejections and
+///    promotions.
+/// 3. Adds the handshake messages carrying extensions, see `Extensions`.
+/// 4. Adds requests and responses, sent only to peers which took up `CorrelationExtension`.
+/// 5. Adds the challenge and proof of the handshake on private networks, see `NetworkId`.
+/// 6. Adds the notice of retirement, sent only to peers which took up `RetirementExtension`.
+/// 7. Adds the requests to be relayed to a peer and their answer, see `Config::relay`.
+/// 8. Adds the challenge of a relay to the peers asking it for a pipe, and their proof.
+/// 9. Adds the confirmation of the keys agreed on in the handshake.
+/// 10. Adds the frames a relay tells the peers it pipes for about the pipe, see `RelayControl`,
+///     and the messages moving a connection onto a direct one, sent only to peers which took up
+///     `UpgradeExtension`.
+pub const PROTOCOL_VERSION: u32 = 10;
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Message<UID> {
@@ -20,6 +42,115 @@ pub enum Message<UID> {
     ChooseConnection,
     Connect(UID, NameHash),
     Data(Vec<u8>),
+    Goodbye(u32),
+    /// Asks for a `ProbeAck`. Sent only to peers which took up `ProbeExtension`.
+    Probe,
+    ProbeAck,
+    PowChallenge(PowChallenge, u8),
+    PowSolution(u64),
+    /// Our listeners have changed to the given addresses. Sent only to peers which took up
+    /// `ContactUpdateExtension`.
+    ContactInfoUpdate(Vec<common::SocketAddr>),
+    /// We refuse the connection and are about to close it.
+    Rejection(Rejection),
+    /// We accept connections at the given addresses, and ask to be treated as a node from now on.
+    PromoteToNode(Vec<common::SocketAddr>),
+    /// None of the addresses of the peer's `PromoteToNode` could be reached.
+    PromotionFailed,
+    /// `BootstrapRequest` offering our extensions, the reachability among them. Peers of version 2
+    /// can't decode it and close the connection, upon which we ask again with a `BootstrapRequest`
+    /// where we can, see `TryPeer`. So it goes for `ExtConnect` and `Connect`.
+    ExtBootstrapRequest(UID, NameHash, Extensions),
+    /// `BootstrapGranted` answering the extensions of an `ExtBootstrapRequest`.
+    ExtBootstrapGranted(UID, Extensions),
+    /// `Connect` offering our extensions, or answering those of the peer's `ExtConnect`.
+    ExtConnect(UID, NameHash, Extensions),
+    /// A message of the application to be answered with a `Response` of the same id.
+    Request(u64, Vec<u8>),
+    /// The answer to the `Request` of the given id.
+    Response(u64, Vec<u8>),
+    /// Answers a handshake request on a private network: our nonce, and our proof of knowing the
+    /// network key over it and the nonce sent in place of the name hash of the request.
+    NetworkChallenge(NetworkNonce, NameHash),
+    /// Proof of knowing the network key, answering a `NetworkChallenge`.
+    NetworkProof(NameHash),
+    /// We are about to shut down, and the peer should turn to the given peers instead. Answering a
+    /// bootstrap request, it refuses it.
+    Retiring(Vec<common::SocketAddr>),
+    /// Asks a relay for a pipe from us, the first id, to the peer of the second id, which asks for
+    /// the one the other way round. Sent in place of a connect request.
+    RelayRequest(UID, NameHash, UID),
+    /// The relay paired our `RelayRequest` with the peer's, and forwards whatever follows to the
+    /// peer as it is. Carries the name hash a connect request is answered with.
+    RelayReady(NameHash),
+    /// Answers a `RelayRequest`: a challenge for the peer to prove, with a `RelayProof`, that it
+    /// holds the key it authenticated its connection to us with, and our key to prove it to.
+    RelayChallenge(KeyChallenge, PublicKey),
+    /// Proof of holding our key, answering a `RelayChallenge`, see `KeyPair::prove`.
+    RelayProof(Vec<u8>),
+    /// The first frame each side seals once the handshake agreed on encryption. Neither side
+    /// takes the connection up before the peer's opened, which proves the peer derived the same
+    /// keys and so holds the secret key of the public one it sent.
+    KeyConfirmation,
+    /// Asks to move the connection onto a direct one, see `Service::upgrade_connection`: the peer
+    /// answers with the listeners to dial it at. Carries ours, for the peer to dial should we
+    /// reach none of its own. Sent only to peers which took up `UpgradeExtension`.
+    UpgradeRequest(Vec<common::SocketAddr>),
+    /// Answers an `UpgradeRequest` with our listeners.
+    UpgradeOffer(Vec<common::SocketAddr>),
+    /// None of the listeners of the peer's `UpgradeOffer` could be reached: the peer is to dial
+    /// those of our `UpgradeRequest` instead.
+    UpgradeDial,
+    /// The upgrade is given up, and the connection stays as it is.
+    UpgradeFailed,
+    /// The last frame on a connection an upgrade replaced. What follows is sent on the new one,
+    /// which the peer reads from only once this arrived, so that nothing is reordered.
+    UpgradeSwitch,
+}
+
+impl<UID: Uid> WireFormat for Message<UID> {
+    fn encode_to(&self, out: &mut Vec<u8>) -> Result<()> {
+        Ok(serialise_into(self, out)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        common::decode_message(bytes)
+    }
+}
+
+/// What a relay tells the peers it pipes for about the pipe itself, see `Config::relay`. Each is
+/// sent in a frame of its own right after an empty frame, which no peer sends, so that the peers
+/// tell them apart from the frames of each other. They are never sealed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum RelayControl {
+    /// Frames were dropped for going over the quota of the relay, see
+    /// `RelayConfig::max_pair_bytes_per_sec`, or the window of the peer which sent them: as many
+    /// of those the peer sent to us as the first number, right before this frame, and as many of
+    /// ours as the second.
+    QuotaExceeded(u32, u32),
+    /// We may send the relay as many bytes, on the wire, as it wants queued for the peer at most,
+    /// see `RelayConfig::window_bytes`. Sent right after `Message::RelayReady`, as the first grant.
+    Window(u64),
+    /// The peer read as many more of the bytes we sent, so that we may send them again.
+    Credit(u64),
+}
+
+/// Whether a peer with `left` bytes of the window of `window` a relay granted it may send a frame
+/// of `len` bytes, see `RelayControl::Window`: if it fits, or if half the window is left, so that
+/// frames larger than the window still go through once the queue of the relay is short.
+#[cfg(feature = "relay")]
+pub fn fits_relay_window(window: u64, left: u64, len: u64) -> bool {
+    len <= left || left >= window / 2
+}
+
+impl WireFormat for RelayControl {
+    fn encode_to(&self, out: &mut Vec<u8>) -> Result<()> {
+        Ok(serialise_into(self, out)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        common::decode_message(bytes)
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -28,4 +159,132 @@ pub enum BootstrapDenyReason {
     FailedExternalReachability,
     NodeNotWhitelisted,
     ClientNotWhitelisted,
+    /// We require a proof of work of the given difficulty, which was not provided.
+    PowRequired(u8),
+    InvalidPow,
+}
+
+/// Why a peer refused our bootstrap request or connection, see `RejectionCode`. Sent only to peers
+/// whose request offered extensions: those of older versions can't decode it, and are sent
+/// `Message::BootstrapDenied` or nothing instead.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Rejection {
+    /// What the refusal is about. Sent as a plain number so that codes added later still decode.
+    pub code: u16,
+    /// How long to wait before trying the peer again, if it is worth trying again at all.
+    pub retry_after_secs: Option<u64>,
+    /// Details for the logs of the refused side.
+    pub message: String,
+    /// Peers to try instead, as given by a peer refusing us because it is retiring. Not part of
+    /// the message: they arrive in a `Message::Retiring` of their own.
+    #[serde(skip)]
+    pub alternatives: Vec<common::SocketAddr>,
+}
+
+impl Rejection {
+    /// A rejection with the given code, to be retried after `retry_after_secs` if at all, and a
+    /// message for the logs of the refused side.
+    pub fn new(code: RejectionCode, retry_after_secs: Option<u64>, message: &str) -> Self {
+        Rejection {
+            code: code.to_u16(),
+            retry_after_secs,
+            message: message.to_owned(),
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// The code of the rejection, `RejectionCode::Unknown` if it was added by a later version.
+    pub fn kind(&self) -> RejectionCode {
+        RejectionCode::from_u16(self.code)
+    }
+}
+
+/// The known values of `Rejection::code`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RejectionCode {
+    /// The peer is at capacity, or not taking anyone else right now. Worth retrying later.
+    Full,
+    /// We are on a different network, see `Config::network_name`. Retrying won't help.
+    WrongNetwork,
+    /// Our IP isn't whitelisted by the peer.
+    NotWhitelisted,
+    /// We tried too often.
+    RateLimited,
+    /// The peer runs a version of the protocol we aren't compatible with.
+    IncompatibleVersion,
+    /// The peer is shutting down for good. Try the peers in `Rejection::alternatives` instead.
+    Retiring,
+    /// The peer only takes encrypted connections, and we have no keys to agree on encryption
+    /// with, see `Service::with_keys`.
+    EncryptionRequired,
+    /// The peer doesn't relay for us, or for as many as it does already, see `Config::relay`.
+    NotRelaying,
+    /// Our IP is banned by the peer for a while, see `Service::ban_peer`.
+    Banned,
+    /// A code added by a later version.
+    Unknown(u16),
+}
+
+impl RejectionCode {
+    /// Decodes a code as sent on the wire. Codes this version doesn't know are kept as `Unknown`.
+    pub fn from_u16(code: u16) -> Self {
+        match code {
+            1 => RejectionCode::Full,
+            2 => RejectionCode::WrongNetwork,
+            3 => RejectionCode::NotWhitelisted,
+            4 => RejectionCode::RateLimited,
+            5 => RejectionCode::IncompatibleVersion,
+            6 => RejectionCode::Retiring,
+            7 => RejectionCode::EncryptionRequired,
+            8 => RejectionCode::NotRelaying,
+            9 => RejectionCode::Banned,
+            code => RejectionCode::Unknown(code),
+        }
+    }
+
+    /// Encodes the code as sent on the wire.
+    pub fn to_u16(self) -> u16 {
+        match self {
+            RejectionCode::Full => 1,
+            RejectionCode::WrongNetwork => 2,
+            RejectionCode::NotWhitelisted => 3,
+            RejectionCode::RateLimited => 4,
+            RejectionCode::IncompatibleVersion => 5,
+            RejectionCode::Retiring => 6,
+            RejectionCode::EncryptionRequired => 7,
+            RejectionCode::NotRelaying => 8,
+            RejectionCode::Banned => 9,
+            RejectionCode::Unknown(code) => code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::serialisation::{deserialise, serialise};
+    use tests::UniqueId;
+
+    #[test]
+    fn unknown_rejection_codes_are_kept() {
+        for code in 0..12 {
+            assert_eq!(RejectionCode::from_u16(code).to_u16(), code);
+        }
+        assert_eq!(RejectionCode::from_u16(2), RejectionCode::WrongNetwork);
+
+        let rejection = Rejection {
+            code: 4242,
+            retry_after_secs: Some(7),
+            message: "from the future".to_owned(),
+            alternatives: Vec::new(),
+        };
+        let frame = unwrap!(serialise(&Message::Rejection::<UniqueId>(rejection.clone())));
+        match unwrap!(deserialise::<Message<UniqueId>>(&frame)) {
+            Message::Rejection(received) => {
+                assert_eq!(received, rejection);
+                assert_eq!(received.kind(), RejectionCode::Unknown(4242));
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
 }
@@ -7,11 +7,69 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-pub use self::core::{spawn_event_loop, Core, CoreMessage, CoreTimer, EventLoop};
+pub use self::audit_log::{AuditEvent, AuditLog, AuditRecord, RotatingFile};
+pub use self::ban_list::BanList;
+pub use self::bandwidth::{RateLimiter, Throttle};
+pub use self::children::{ChildHandle, ChildrenSet};
+pub use self::clock::{Clock, WallClock};
+#[cfg(test)]
+pub use self::clock::VirtualClock;
+pub use self::core::{
+    spawn_event_loop, Core, CoreMessage, CoreStats, CoreTimer, EventLoop, ManualEventLoop,
+    PowerMode, StateKindStats,
+};
+pub use self::dialer::{DialHandler, DialSettings, DialTarget, DialVia, Dialer};
+pub use self::drain_rate::{DrainRate, SendReceipt};
+pub use self::encryption::{
+    new_key_challenge, FrameCipher, KeyChallenge, KeyExchange, KeyPair, KeyRole, PublicKey,
+    SessionKeys, PUBLIC_KEY_SIZE, SEAL_OVERHEAD, SECRET_KEY_SIZE,
+};
 pub use self::error::CommonError;
-pub use self::message::{BootstrapDenyReason, Message};
-pub use self::socket::Socket;
+pub use self::extensions::{
+    answer_extensions, offer_extensions, take_extension_answers, ContactUpdateExtension,
+    CorrelationExtension, EncryptionExtension, Extension, ExtensionHandler, Extensions,
+    NegotiatedFeatures, PowExtension, ProbeExtension, RelayExtension, RetirementExtension,
+    RoleExtension, TimestampExtension, UpgradeExtension,
+};
+#[cfg(feature = "copy-audit")]
+pub use self::frame::payload_copies;
+pub use self::frame::{
+    decode_message, encode_frame, split_data_frame, Frame, FrameDecoder, PartialFrame, WireFormat,
+    FRAME_HEADER_SIZE,
+};
+#[cfg(feature = "flight-recorder")]
+pub use self::flight_recorder::{FlightRecorder, RecordedEvent};
+pub use self::io_shim::{IoErrorClass, IoShim, IoSite};
+pub use self::memory_budget::{Charge, MemoryBudget, MemoryPressure, MIN_MEMORY_BUDGET};
+#[cfg(feature = "relay")]
+pub use self::message::fits_relay_window;
+pub use self::message::{
+    BootstrapDenyReason, Message, Rejection, RejectionCode, RelayControl, PROTOCOL_VERSION,
+};
+pub use self::network_id::{
+    hmac_sha3_256, new_network_nonce, proofs_match, NetworkId, NetworkKey, NetworkNonce,
+    NetworkProver,
+};
+pub use self::pending::{ConnectionDirection, HandshakeStage, PendingConnInfo, PendingTable};
+pub use self::pow::{
+    is_valid_pow, new_pow_challenge, solve_pow, PowChallenge, MAX_POW_DIFFICULTY,
+};
+pub use self::recorded_event_kind::RecordedEventKind;
+pub use self::shared_buffer::SharedBuffer;
+pub use self::socket::{addr_for_family, bind_ip_for, connect_tcp_from, unmapped_addr, Socket};
 pub use self::state::State;
+#[cfg(unix)]
+pub use self::sys::nofile_soft_limit;
+pub use self::timestamps::{
+    FrameTimestamps, OneWayLatency, TimestampTrailer, TIMESTAMP_TRAILER_SIZE,
+};
+#[cfg(feature = "utp")]
+pub use self::utp::{UtpAcceptHandler, UtpEndpoint, UtpStream};
+pub use self::watchdog::LagWatchdog;
+#[cfg(feature = "stall-watchdog")]
+pub use self::watchdog::StallWatchdog;
+#[cfg(feature = "websocket")]
+pub use self::websocket::{WsAcceptHandler, WsAdmitHandler, WsEndpoint, WsStream, WsUpgradeHandler};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use std::fmt;
@@ -21,15 +79,22 @@ use std::net::SocketAddr;
 pub const HASH_SIZE: usize = 32;
 pub type NameHash = [u8; HASH_SIZE];
 /// Priority of a message to be sent by Crust. A lower value means a higher priority, so Priority 0
-/// is the highest one. Low-priority messages will be preempted if need be to allow higher priority
-/// messages through. Messages with a value `>= MSG_DROP_PRIORITY` will even be dropped, if
-/// bandwidth is insufficient.
+/// is the highest one. It is reserved for Crust's own control messages though, see
+/// `CONTROL_PRIORITY`, which leaves 1 as the highest priority of the messages of the application.
+/// Low-priority messages will be preempted if need be to allow higher priority messages through.
+/// Messages with a value `>= MSG_DROP_PRIORITY` will even be dropped, if bandwidth is insufficient.
+/// Messages sent to the same peer with the same priority are always delivered in the order they
+/// were sent in; only messages of different priorities overtake each other.
 pub type Priority = u8;
 pub type Result<T> = ::std::result::Result<T, CommonError>;
 
 pub const MAX_PAYLOAD_SIZE: usize = 2 * 1024 * 1024;
 /// Minimum priority for droppable messages. Messages with lower values will never be dropped.
 pub const MSG_DROP_PRIORITY: u8 = 2;
+/// Priority of the control messages of Crust itself, such as heartbeats, probes and their answers,
+/// which tell the peer we are alive however much data is queued. Sends of the application at this
+/// priority fail with `CrustError::ReservedPriority`.
+pub const CONTROL_PRIORITY: Priority = 0;
 
 /// Specify crust user. Behaviour (for example in bootstrap phase) will be different for different
 /// variants. Node will request the Bootstrapee to connect back to this crust failing which it
@@ -67,8 +132,35 @@ pub trait Uid:
 {
 }
 
+mod audit_log;
+mod ban_list;
+mod bandwidth;
+mod children;
+mod clock;
 mod core;
+mod dialer;
+mod drain_rate;
+mod encryption;
 mod error;
+mod extensions;
+#[cfg(feature = "flight-recorder")]
+mod flight_recorder;
+mod frame;
+mod io_shim;
+mod memory_budget;
 mod message;
+mod network_id;
+mod pending;
+mod pow;
+mod recorded_event_kind;
+mod shared_buffer;
 mod socket;
 mod state;
+#[cfg(unix)]
+mod sys;
+mod timestamps;
+#[cfg(feature = "utp")]
+mod utp;
+mod watchdog;
+#[cfg(feature = "websocket")]
+mod websocket;
@@ -0,0 +1,238 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// How the two sides of a handshake tell they are on the same network. On a public network the
+// connecting side sends the hash of the network name, compared with ours. On a private one, see
+// `Config::network_secret`, it sends a random nonce in its place instead. The accepting side
+// answers with a nonce of its own and its proof of knowing the network key over both, and the
+// connecting side proves the same in turn. Nothing sent identifies the network, and no proof holds
+// for another handshake.
+
+use common::{NameHash, HASH_SIZE};
+use rand;
+use std::fmt;
+use tiny_keccak::sha3_256;
+
+pub type NetworkNonce = [u8; HASH_SIZE];
+
+/// Block size of SHA3-256, the size HMAC pads its key to.
+const HMAC_BLOCK_SIZE: usize = 136;
+
+/// The network we are on.
+#[derive(Clone, Copy, Debug)]
+pub enum NetworkId {
+    /// A public network, by the hash of its name.
+    Plain(NameHash),
+    /// A private network, by the key derived from its name and secret.
+    Keyed(NetworkKey),
+}
+
+impl NetworkId {
+    pub fn new(network_name: &Option<String>, network_secret: &Option<String>) -> Self {
+        match *network_secret {
+            Some(ref secret) => NetworkId::Keyed(NetworkKey::new(network_name, secret)),
+            None => NetworkId::Plain(match *network_name {
+                Some(ref name) => sha3_256(name.as_bytes()),
+                None => [0; HASH_SIZE],
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct NetworkKey([u8; HASH_SIZE]);
+
+impl NetworkKey {
+    pub fn new(network_name: &Option<String>, secret: &str) -> Self {
+        let mut name = b"crust network key".to_vec();
+        match *network_name {
+            Some(ref network_name) => {
+                name.push(1);
+                name.extend_from_slice(network_name.as_bytes());
+            }
+            None => name.push(0),
+        }
+        NetworkKey(hmac_sha3_256(secret.as_bytes(), &name))
+    }
+
+    /// Proof of the accepting side, sent along with its challenge.
+    pub fn accepting_proof(&self, request: &NetworkNonce, challenge: &NetworkNonce) -> NameHash {
+        self.proof(b"accepting", request, challenge)
+    }
+
+    /// Proof of the connecting side, answering the challenge.
+    pub fn connecting_proof(&self, request: &NetworkNonce, challenge: &NetworkNonce) -> NameHash {
+        self.proof(b"connecting", request, challenge)
+    }
+
+    fn proof(&self, side: &[u8], request: &NetworkNonce, challenge: &NetworkNonce) -> NameHash {
+        let mut data = side.to_vec();
+        data.extend_from_slice(request);
+        data.extend_from_slice(challenge);
+        hmac_sha3_256(&self.0, &data)
+    }
+}
+
+impl fmt::Debug for NetworkKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "NetworkKey(..)")
+    }
+}
+
+/// The connecting side of the network check of a handshake.
+#[derive(Debug)]
+pub struct NetworkProver {
+    id: NetworkId,
+    request: NetworkNonce,
+    /// The nonce of the challenge of the peer, once it proved to be on our network.
+    challenge: Option<NetworkNonce>,
+}
+
+impl NetworkProver {
+    pub fn new(id: NetworkId) -> Self {
+        let request = match id {
+            NetworkId::Plain(name_hash) => name_hash,
+            NetworkId::Keyed(_) => new_network_nonce(),
+        };
+        NetworkProver {
+            id,
+            request,
+            challenge: None,
+        }
+    }
+
+    /// What to send in the name hash of our request.
+    pub fn request(&self) -> NameHash {
+        self.request
+    }
+
+    /// Checks the peer's challenge and returns our proof to answer it with, or `None` if the peer
+    /// isn't on our network.
+    pub fn answer(&mut self, challenge: NetworkNonce, proof: &NameHash) -> Option<NameHash> {
+        let key = match self.id {
+            NetworkId::Keyed(ref key) => key,
+            NetworkId::Plain(_) => return None,
+        };
+        if self.challenge.is_some()
+            || !proofs_match(&key.accepting_proof(&self.request, &challenge), proof)
+        {
+            return None;
+        }
+        self.challenge = Some(challenge);
+        Some(key.connecting_proof(&self.request, &challenge))
+    }
+
+    /// Whether the peer proved to be on our network, with `name_hash` being that of its answer to
+    /// our request if there is one.
+    pub fn accepts(&self, name_hash: Option<&NameHash>) -> bool {
+        match self.id {
+            NetworkId::Plain(ref ours) => name_hash.map_or(true, |theirs| theirs == ours),
+            NetworkId::Keyed(_) => match self.challenge {
+                Some(ref challenge) => name_hash.map_or(true, |theirs| theirs == challenge),
+                None => false,
+            },
+        }
+    }
+}
+
+pub fn new_network_nonce() -> NetworkNonce {
+    rand::random()
+}
+
+/// Compares in a time independent of where the proofs differ.
+pub fn proofs_match(a: &NameHash, b: &NameHash) -> bool {
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub fn hmac_sha3_256(key: &[u8], data: &[u8]) -> [u8; HASH_SIZE] {
+    let mut block = [0; HMAC_BLOCK_SIZE];
+    if key.len() > HMAC_BLOCK_SIZE {
+        block[..HASH_SIZE].copy_from_slice(&sha3_256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).collect();
+    outer.extend_from_slice(&sha3_256(&inner));
+    sha3_256(&outer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name() -> Option<String> {
+        Some("private network".to_owned())
+    }
+
+    /// Runs the handshake between the two sides up to the proof of the connecting side, and
+    /// returns whether the accepting side takes it.
+    fn handshake(accepting: NetworkId, connecting: NetworkId) -> bool {
+        let mut prover = NetworkProver::new(connecting);
+        let request = prover.request();
+        let key = match accepting {
+            NetworkId::Plain(name_hash) => return request == name_hash,
+            NetworkId::Keyed(key) => key,
+        };
+        let challenge = new_network_nonce();
+        let proof = key.accepting_proof(&request, &challenge);
+        match prover.answer(challenge, &proof) {
+            Some(answer) => {
+                proofs_match(&key.connecting_proof(&request, &challenge), &answer)
+                    && prover.accepts(Some(&challenge))
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn only_peers_with_the_same_secret_pass() {
+        let keyed = |secret| NetworkId::new(&name(), &Some(secret));
+        let plain = NetworkId::new(&name(), &None);
+
+        assert!(handshake(plain, plain));
+        assert!(handshake(keyed("secret".to_owned()), keyed("secret".to_owned())));
+        assert!(!handshake(keyed("secret".to_owned()), keyed("other".to_owned())));
+        assert!(!handshake(
+            NetworkId::new(&Some("other network".to_owned()), &Some("secret".to_owned())),
+            keyed("secret".to_owned())
+        ));
+        assert!(!handshake(plain, keyed("secret".to_owned())));
+        assert!(!handshake(keyed("secret".to_owned()), plain));
+        // Secrets longer than the block of the hash are hashed first.
+        let long = "s".repeat(2 * HMAC_BLOCK_SIZE);
+        assert!(handshake(keyed(long.clone()), keyed(long.clone())));
+        assert!(!handshake(keyed(long.clone()), keyed(long + "s")));
+    }
+
+    #[test]
+    fn captured_proofs_fail_against_a_fresh_challenge() {
+        let key = NetworkKey::new(&name(), "secret");
+
+        // The exchange of a handshake seen on the wire.
+        let mut prover = NetworkProver::new(NetworkId::Keyed(key));
+        let request = prover.request();
+        let challenge = new_network_nonce();
+        let proof = key.accepting_proof(&request, &challenge);
+        let answer = unwrap!(prover.answer(challenge, &proof));
+        assert!(proofs_match(&key.connecting_proof(&request, &challenge), &answer));
+
+        // Replayed in another handshake, the request is challenged afresh.
+        let fresh = new_network_nonce();
+        assert!(!proofs_match(&key.connecting_proof(&request, &fresh), &answer));
+        // The proof of the accepting side isn't taken for that of the connecting one.
+        assert!(!proofs_match(&key.connecting_proof(&request, &challenge), &proof));
+        // Nor is a replayed challenge answered twice.
+        assert_eq!(prover.answer(challenge, &proof), None);
+        // A request differs in every handshake.
+        assert_ne!(NetworkProver::new(NetworkId::Keyed(key)).request(), request);
+    }
+}
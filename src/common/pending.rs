@@ -0,0 +1,180 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Defines `PendingTable`, the connections of the event loop which are still in their handshake.
+
+use mio::Token;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Which side opened a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// We are connecting to the peer.
+    Outbound,
+    /// The peer connected to one of our listeners.
+    Inbound,
+}
+
+/// How far the handshake of a connection in progress has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    /// Waiting for the TCP connection to be established.
+    TcpConnecting,
+    /// Waiting for the TCP connection through the peer's NAT to be established.
+    HolePunching,
+    /// Waiting for the peer to answer our uTP connection request.
+    #[cfg(feature = "utp")]
+    UtpConnecting,
+    /// Waiting for the WebSocket opening handshake to be over.
+    #[cfg(feature = "websocket")]
+    WebSocketUpgrade,
+    /// Waiting for the relay to pair us with the peer, see `Config::use_relays`.
+    #[cfg(feature = "relay")]
+    RelayPairing,
+    /// Our handshake was sent, waiting for the peer's.
+    HandshakeSent,
+    /// Accepted, waiting for the peer's handshake.
+    AwaitingHandshake,
+    /// Handshakes were exchanged, waiting for either side to choose this connection out of those
+    /// made to the same peer.
+    AwaitingChoice,
+}
+
+/// A connection in progress, as reported by `Service::pending_connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingConnInfo {
+    /// Address of the peer.
+    pub addr: SocketAddr,
+    /// Which side opened the connection.
+    pub direction: ConnectionDirection,
+    /// How far the handshake has got.
+    pub stage: HandshakeStage,
+    /// Time since the connection was started.
+    pub elapsed: Duration,
+}
+
+struct PendingConn {
+    addr: SocketAddr,
+    direction: ConnectionDirection,
+    stage: HandshakeStage,
+    started: Instant,
+}
+
+/// Connections in progress by the token of the state handling them. States add themselves when
+/// they start a connection and update their stage as the handshake goes on. An entry is removed
+/// when the connection becomes active or its state is removed from the event loop.
+#[derive(Default)]
+pub struct PendingTable {
+    conns: HashMap<Token, PendingConn>,
+}
+
+impl PendingTable {
+    pub fn insert(
+        &mut self,
+        token: Token,
+        addr: SocketAddr,
+        direction: ConnectionDirection,
+        stage: HandshakeStage,
+        now: Instant,
+    ) {
+        let _ = self.conns.insert(
+            token,
+            PendingConn {
+                addr,
+                direction,
+                stage,
+                started: now,
+            },
+        );
+    }
+
+    pub fn set_stage(&mut self, token: Token, stage: HandshakeStage) {
+        if let Some(conn) = self.conns.get_mut(&token) {
+            conn.stage = stage;
+        }
+    }
+
+    pub fn remove(&mut self, token: Token) {
+        let _ = self.conns.remove(&token);
+    }
+
+    pub fn count(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Returns the connections in progress, oldest first.
+    pub fn list(&self, now: Instant) -> Vec<PendingConnInfo> {
+        let mut conns: Vec<_> = self.conns.values().collect();
+        conns.sort_by_key(|conn| conn.started);
+        conns
+            .into_iter()
+            .map(|conn| PendingConnInfo {
+                addr: conn.addr,
+                direction: conn.direction,
+                stage: conn.stage,
+                elapsed: now - conn.started,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_oldest_first_with_current_stage() {
+        let start = Instant::now();
+        let addr0 = unwrap!("192.168.0.1:5483".parse());
+        let addr1 = unwrap!("192.168.0.2:5483".parse());
+        let mut table = PendingTable::default();
+
+        table.insert(
+            Token(1),
+            addr1,
+            ConnectionDirection::Inbound,
+            HandshakeStage::AwaitingHandshake,
+            start + Duration::from_secs(1),
+        );
+        table.insert(
+            Token(0),
+            addr0,
+            ConnectionDirection::Outbound,
+            HandshakeStage::TcpConnecting,
+            start,
+        );
+        table.set_stage(Token(0), HandshakeStage::HandshakeSent);
+        table.set_stage(Token(2), HandshakeStage::AwaitingChoice);
+
+        let now = start + Duration::from_secs(3);
+        assert_eq!(
+            table.list(now),
+            vec![
+                PendingConnInfo {
+                    addr: addr0,
+                    direction: ConnectionDirection::Outbound,
+                    stage: HandshakeStage::HandshakeSent,
+                    elapsed: Duration::from_secs(3),
+                },
+                PendingConnInfo {
+                    addr: addr1,
+                    direction: ConnectionDirection::Inbound,
+                    stage: HandshakeStage::AwaitingHandshake,
+                    elapsed: Duration::from_secs(2),
+                },
+            ]
+        );
+
+        table.remove(Token(0));
+        table.remove(Token(1));
+        assert!(table.list(now).is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Proof of work asked of bootstrapping clients: find a nonce such that the SHA3-256 hash of the
+// challenge followed by the little endian nonce starts with `difficulty` zero bits.
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand;
+use tiny_keccak::sha3_256;
+
+pub const POW_CHALLENGE_SIZE: usize = 32;
+pub type PowChallenge = [u8; POW_CHALLENGE_SIZE];
+
+/// Highest difficulty we are willing to solve. Takes about 16 million hashes on average.
+pub const MAX_POW_DIFFICULTY: u8 = 24;
+
+pub fn new_pow_challenge() -> PowChallenge {
+    rand::random()
+}
+
+pub fn is_valid_pow(challenge: &PowChallenge, difficulty: u8, nonce: u64) -> bool {
+    leading_zero_bits(&pow_hash(challenge, nonce)) >= u32::from(difficulty)
+}
+
+/// Finds the lowest nonce solving the challenge. This can take a long time, so it must not be
+/// called from the event loop.
+pub fn solve_pow(challenge: &PowChallenge, difficulty: u8) -> u64 {
+    let mut nonce = 0;
+    while !is_valid_pow(challenge, difficulty, nonce) {
+        nonce += 1;
+    }
+    nonce
+}
+
+fn pow_hash(challenge: &PowChallenge, nonce: u64) -> [u8; 32] {
+    let mut data = [0; POW_CHALLENGE_SIZE + 8];
+    data[..POW_CHALLENGE_SIZE].copy_from_slice(challenge);
+    LittleEndian::write_u64(&mut data[POW_CHALLENGE_SIZE..], nonce);
+    sha3_256(&data)
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte != 0 {
+            return bits + byte.leading_zeros();
+        }
+        bits += 8;
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0x10, 0xff]), 11);
+        assert_eq!(leading_zero_bits(&[0; 4]), 32);
+    }
+
+    #[test]
+    fn solve_and_verify() {
+        const DIFFICULTY: u8 = 12;
+
+        let challenge = new_pow_challenge();
+        let started = Instant::now();
+        let nonce = solve_pow(&challenge, DIFFICULTY);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        assert!(is_valid_pow(&challenge, DIFFICULTY, nonce));
+        // The solver returns the lowest valid nonce.
+        assert!((0..nonce).all(|nonce| !is_valid_pow(&challenge, DIFFICULTY, nonce)));
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Defines `RecordedEventKind`, what the event loop's states pass to `Core::record`. It is there
+// whether or not the flight recorder is compiled in, so that they needn't care.
+
+use std::net::SocketAddr;
+
+/// Kind of an event kept by the flight recorder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordedEventKind {
+    /// An inbound connection was accepted and its handshake started.
+    Accepted,
+    /// An inbound connection was parked waiting for a free handshake slot.
+    HandshakeParked,
+    /// A parked connection was dropped because it waited too long for a handshake slot.
+    HandshakeExpired,
+    /// A handshake completed successfully.
+    HandshakeSucceeded,
+    /// A handshake was aborted.
+    HandshakeFailed,
+    /// A connection to a peer was closed.
+    Disconnected,
+    /// Queued messages were dropped because they could not be sent in time.
+    MessagesDropped(usize),
+    /// We started trying to bootstrap off the given contact.
+    BootstrapAttempt(SocketAddr),
+    /// We bootstrapped off the given contact.
+    BootstrapSucceeded(SocketAddr),
+    /// Bootstrapping off the given contact failed.
+    BootstrapFailed(SocketAddr),
+}
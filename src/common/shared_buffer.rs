@@ -0,0 +1,136 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::Charge;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// Immutable message payload which is cheap to clone and to send to other threads. It is a view
+/// into the buffer the message was reassembled in, so no copy of the payload is made on delivery.
+///
+/// The buffer stays charged to the memory budget of the service it was received by, see
+/// `Config::max_buffered_bytes`, until the last clone is dropped, and only once however many
+/// clones are handed around.
+#[derive(Clone)]
+pub struct SharedBuffer {
+    buf: Arc<Backing>,
+    range: Range<usize>,
+}
+
+struct Backing {
+    bytes: Vec<u8>,
+    charge: Charge,
+}
+
+impl SharedBuffer {
+    /// Wraps the part `range` of `buf`, which is kept alive as long as any clone is.
+    pub fn slice(buf: Vec<u8>, range: Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= buf.len());
+        SharedBuffer {
+            buf: Arc::new(Backing {
+                bytes: buf,
+                charge: Charge::default(),
+            }),
+            range,
+        }
+    }
+
+    /// Keeps `charge` until the last clone of the buffer is dropped. Made before the buffer is
+    /// cloned, as no clone may be out when the charge is taken over.
+    pub fn hold(mut self, charge: Charge) -> Self {
+        let backing = Arc::get_mut(&mut self.buf);
+        debug_assert!(backing.is_some(), "Charge held by a buffer already shared");
+        if let Some(backing) = backing {
+            backing.charge.absorb(charge);
+        }
+        self
+    }
+
+    /// Copies the payload out into a vector of its own.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self[..].to_vec()
+    }
+}
+
+impl From<Vec<u8>> for SharedBuffer {
+    fn from(buf: Vec<u8>) -> Self {
+        let len = buf.len();
+        Self::slice(buf, 0..len)
+    }
+}
+
+impl Deref for SharedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf.bytes[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for SharedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for SharedBuffer {
+    fn eq(&self, other: &SharedBuffer) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for SharedBuffer {}
+
+impl fmt::Debug for SharedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedBuffer({} bytes)", self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::MemoryBudget;
+    use std::thread;
+
+    #[test]
+    fn clones_share_the_allocation() {
+        let buf = vec![0, 1, 2, 3, 4, 5];
+        let ptr = buf.as_ptr();
+
+        let payload = SharedBuffer::slice(buf, 2..5);
+        let clone = payload.clone();
+        assert_eq!(&payload[..], &[2, 3, 4]);
+        assert_eq!(payload, clone);
+        assert_eq!(clone.as_ptr() as usize, ptr as usize + 2);
+
+        let copy = clone.to_vec();
+        assert_eq!(copy, vec![2, 3, 4]);
+        assert_ne!(copy.as_ptr(), clone.as_ptr());
+    }
+
+    #[test]
+    fn clones_are_charged_once() {
+        let budget = MemoryBudget::new(1000);
+        let payload = SharedBuffer::from(vec![7; 100]).hold(unwrap!(budget.charge(100)));
+        let clones: Vec<_> = (0..10).map(|_| payload.clone()).collect();
+        assert_eq!(budget.used(), 100);
+
+        drop(payload);
+        let threads: Vec<_> = clones
+            .into_iter()
+            .map(|clone| thread::spawn(move || assert_eq!(clone.len(), 100)))
+            .collect();
+        for thread in threads {
+            unwrap!(thread.join());
+        }
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -7,21 +7,38 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use common::{CommonError, Priority, Result, MAX_PAYLOAD_SIZE, MSG_DROP_PRIORITY};
-use maidsafe_utilities::serialisation::{deserialise_from, serialise_into};
+use common::frame::{self, FrameDecoder, OutFrame, PartialFrame};
+#[cfg(feature = "relay")]
+use common::{fits_relay_window, RelayControl, WireFormat};
+#[cfg(feature = "utp")]
+use common::UtpStream;
+#[cfg(feature = "websocket")]
+use common::WsStream;
+use common::{
+    Charge, CommonError, DrainRate, FrameCipher, FrameTimestamps, IoErrorClass, IoShim, IoSite,
+    MemoryBudget, MemoryPressure, OneWayLatency, Priority, Result, SendReceipt, SessionKeys,
+    Throttle, WallClock, CONTROL_PRIORITY, MAX_PAYLOAD_SIZE, MSG_DROP_PRIORITY,
+};
 use mio::tcp::TcpStream;
 use mio::{Evented, Poll, PollOpt, Ready, Token};
+use net2::TcpBuilder;
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
-use std::collections::{BTreeMap, VecDeque};
-use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
 use std::mem;
-use std::net::{Shutdown, SocketAddr};
-use std::time::Instant;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Maximum age of a message waiting to be sent. If a message is older, the queue is dropped.
 const MAX_MSG_AGE_SECS: u64 = 60;
+/// Control messages, see `CONTROL_PRIORITY`, waiting to be sent beyond which more are dropped. As
+/// they are queued even when the memory budget is spent, this is what bounds them.
+const MAX_QUEUED_CONTROL_MSGS: usize = 64;
+/// Control messages written in a row while others wait, before one of the others goes out.
+const MAX_CONTROL_STREAK: usize = 8;
 
 pub struct Socket {
     inner: Option<SockInner>,
@@ -33,21 +50,397 @@ impl Socket {
         Ok(Self::wrap(stream))
     }
 
+    /// Like `connect`, but binds the socket to the given local IP first, so the connection leaves
+    /// via the matching interface.
+    pub fn connect_from(addr: &SocketAddr, bind_ip: Option<IpAddr>) -> Result<Self> {
+        Ok(Self::wrap(connect_tcp_from(addr, bind_ip)?))
+    }
+
     pub fn wrap(stream: TcpStream) -> Self {
+        Self::with_stream(Stream::Tcp(stream))
+    }
+
+    /// Like `wrap`, for a uTP connection.
+    #[cfg(feature = "utp")]
+    pub fn wrap_utp(stream: UtpStream) -> Self {
+        Self::with_stream(Stream::Utp(stream))
+    }
+
+    /// Like `wrap`, for a WebSocket connection whose opening handshake is over.
+    #[cfg(feature = "websocket")]
+    pub fn wrap_ws(stream: WsStream) -> Self {
+        Self::with_stream(Stream::Ws(stream))
+    }
+
+    fn with_stream(stream: Stream) -> Self {
         Socket {
             inner: Some(SockInner {
                 stream,
-                read_buffer: Vec::new(),
-                read_len: 0,
+                decoder: FrameDecoder::new(MAX_PAYLOAD_SIZE),
+                frames: VecDeque::new(),
+                queued_bytes: 0,
                 write_queue: BTreeMap::new(),
+                write_queue_bytes: BTreeMap::new(),
+                send_order: SendOrder::default(),
                 current_write: None,
+                dropped_msgs: 0,
+                control_streak: 0,
+                bytes_written: 0,
+                bytes_read: 0,
+                drain_rate: DrainRate::default(),
+                timestamps: None,
+                cipher: None,
+                budget: MemoryBudget::unlimited(),
+                clock: WallClock::Real,
+                queue_limit: None,
+                reassembly: Charge::default(),
+                read_stalled: false,
+                read_budget: None,
+                read_budget_spent: false,
+                throttle: Throttle::default(),
+                read_paced_for: None,
+                write_paced_for: None,
+                shim: IoShim::default(),
+                #[cfg(feature = "relay")]
+                relayed: false,
+                #[cfg(feature = "relay")]
+                relay_control_next: false,
+                #[cfg(feature = "relay")]
+                relay_window: RelayWindow::default(),
             }),
         }
     }
 
     pub fn peer_addr(&self) -> Result<SocketAddr> {
         let inner = self.inner.as_ref().ok_or(CommonError::UninitialisedSocket)?;
-        Ok(inner.stream.peer_addr()?)
+        Ok(unmapped_addr(inner.stream.peer_addr()?))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let inner = self.inner.as_ref().ok_or(CommonError::UninitialisedSocket)?;
+        Ok(unmapped_addr(inner.stream.local_addr()?))
+    }
+
+    /// Whether the socket carries a bare TCP connection, neither uTP nor WebSocket.
+    pub fn is_tcp(&self) -> bool {
+        match self.inner.as_ref().map(|inner| &inner.stream) {
+            Some(&Stream::Tcp(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the socket carries a uTP connection rather than a TCP one.
+    pub fn is_utp(&self) -> bool {
+        match self.inner.as_ref().map(|inner| &inner.stream) {
+            #[cfg(feature = "utp")]
+            Some(&Stream::Utp(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the socket carries a WebSocket connection rather than a bare TCP one.
+    #[cfg(feature = "websocket")]
+    pub fn is_websocket(&self) -> bool {
+        match self.inner.as_ref().map(|inner| &inner.stream) {
+            Some(&Stream::Ws(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Copies the first bytes received into `buf` without taking them, so that they are read all
+    /// the same later. Only a bare TCP connection can be peeked at.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.as_ref().map(|inner| &inner.stream) {
+            Some(&Stream::Tcp(ref stream)) => stream.peek(buf),
+            _ => Err(io::Error::from(ErrorKind::InvalidInput)),
+        }
+    }
+
+    /// Reads and throws away what has been received so far, up to a bound, so that a peer which
+    /// sent what we won't read sees the connection closed rather than reset once it is dropped.
+    pub fn discard_received(&mut self) {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let mut buf = [0; 4096];
+        for _ in 0..16 {
+            match inner.stream.read(&mut buf) {
+                Ok(len) if len > 0 => (),
+                _ => return,
+            }
+        }
+    }
+
+    /// The TCP connection the socket carries, if it is a bare one, for another protocol to take
+    /// over. Nothing is to have been read or written through the socket.
+    #[cfg(feature = "websocket")]
+    pub fn into_tcp(mut self) -> Option<TcpStream> {
+        match self.inner.take().map(|inner| inner.stream) {
+            Some(Stream::Tcp(stream)) => Some(stream),
+            Some(stream) => {
+                let _ = stream.shutdown();
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Marks the socket as carrying a connection through a relay rather than straight to the peer.
+    /// The frames the relay sends of its own, see `RelayControl`, are taken in by the socket from
+    /// then on rather than returned.
+    #[cfg(feature = "relay")]
+    pub fn set_relayed(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.relayed = true;
+        }
+    }
+
+    /// Whether the connection runs through a relay, see `set_relayed`.
+    #[cfg(feature = "relay")]
+    pub fn is_relayed(&self) -> bool {
+        self.inner.as_ref().map_or(false, |inner| inner.relayed)
+    }
+
+    /// Whether the last write stopped short of the queue for want of the window the relay the
+    /// connection runs through granted us, see `RelayControl::Window`. Writing goes on once the
+    /// relay grants more, which is read like any frame: `write` is to be called after reading
+    /// while this holds.
+    #[cfg(feature = "relay")]
+    pub fn is_relay_window_shut(&self) -> bool {
+        self.inner
+            .as_ref()
+            .map_or(false, |inner| inner.relay_window.shut)
+    }
+
+    /// Returns the number of queued messages dropped since the last call, because they could not
+    /// be sent in time or there was no memory for them, or through a relay, because they went over
+    /// its quota.
+    pub fn take_dropped_msgs(&mut self) -> usize {
+        self.inner
+            .as_mut()
+            .map_or(0, |inner| mem::replace(&mut inner.dropped_msgs, 0))
+    }
+
+    /// Removes the messages queued which haven't started to be written yet and returns the payloads
+    /// of the data messages among them with their priorities, highest priority first and in send
+    /// order within a priority. A message written in part is left alone, as it can't be taken back.
+    pub fn take_unsent_data(&mut self) -> Vec<(Priority, Vec<u8>)> {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Vec::new(),
+        };
+        let write_queue = mem::replace(&mut inner.write_queue, BTreeMap::new());
+        inner.write_queue_bytes.clear();
+        write_queue
+            .into_iter()
+            .flat_map(|(priority, queue)| {
+                queue
+                    .into_iter()
+                    .filter_map(move |queued| queued.frame.into_payload())
+                    .map(move |payload| (priority, payload))
+            })
+            .collect()
+    }
+
+    /// Moves the frames queued which haven't started to be written yet to the end of the queue of
+    /// `to`, as the connection of `to` takes over from this one. The frame being written, if any,
+    /// is finished here.
+    pub fn hand_over_queue(&mut self, to: &mut Socket) {
+        let (from, to) = match (self.inner.as_mut(), to.inner.as_mut()) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return,
+        };
+        let write_queue = mem::replace(&mut from.write_queue, BTreeMap::new());
+        from.write_queue_bytes.clear();
+        for (priority, queue) in write_queue {
+            for mut queued in queue {
+                match (queued.frame.has_trailer(), to.timestamps.is_some()) {
+                    (false, true) => queued.frame.reserve_trailer(),
+                    (true, false) => queued.frame.release_trailer(),
+                    _ => (),
+                }
+                queued.seq = to.send_order.next_seq(priority);
+                *to.write_queue_bytes.entry(priority).or_insert(0) += queued.frame.wire_len();
+                to.write_queue
+                    .entry(priority)
+                    .or_insert_with(|| VecDeque::with_capacity(10))
+                    .push_back(queued);
+            }
+        }
+    }
+
+    /// Drops the queued messages which may be dropped, see `MSG_DROP_PRIORITY`, to free memory.
+    /// They are counted by `take_dropped_msgs`. A message written in part is finished regardless.
+    pub fn shed_droppable(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.drop_droppable(true);
+        }
+    }
+
+    /// Number of bytes which go out before a message of `priority` queued now: the rest of the
+    /// frame being written and the frames queued of the same or a higher priority.
+    pub fn queued_ahead_of(&self, priority: Priority) -> u64 {
+        self.inner.as_ref().map_or(0, |inner| {
+            let current = inner
+                .current_write
+                .as_ref()
+                .map_or(0, |queued| queued.frame.remaining());
+            let queued: usize = inner
+                .write_queue_bytes
+                .iter()
+                .take_while(|&(&queued, _)| queued <= priority)
+                .map(|(_, bytes)| bytes)
+                .sum();
+            (current + queued) as u64
+        })
+    }
+
+    /// Number of bytes written to the socket since it was made.
+    pub fn bytes_written(&self) -> u64 {
+        self.inner.as_ref().map_or(0, |inner| inner.bytes_written)
+    }
+
+    /// Number of bytes read from the socket since it was made.
+    pub fn bytes_read(&self) -> u64 {
+        self.inner.as_ref().map_or(0, |inner| inner.bytes_read)
+    }
+
+    /// The receipt of a message with `queued_behind_bytes` to be written before it, estimating
+    /// the time they take from the rate at which the write queue drained lately.
+    pub fn receipt(&self, queued_behind_bytes: u64) -> SendReceipt {
+        self.inner.as_ref().map_or_else(
+            || SendReceipt {
+                queued_behind_bytes,
+                est_drain_ms: None,
+            },
+            |inner| inner.drain_rate.receipt(queued_behind_bytes),
+        )
+    }
+
+    /// Charges what the socket buffers from now on to `budget`, reading and queueing less as the
+    /// pressure on it rises, see `MemoryPressure`. Reads then stop at the end of each header and
+    /// body, so no byte is read which there is no memory for. Frames read or queued before go
+    /// uncharged.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.budget = budget;
+        }
+    }
+
+    /// Has the socket time what it queues, and pace what it writes and reads, by `clock`, that of
+    /// the event loop, rather than by the real time.
+    pub fn set_clock(&mut self, clock: WallClock) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.clock = clock;
+        }
+    }
+
+    /// Limits the bytes of the frames waiting in the write queue to `bytes`, or lifts the limit if
+    /// `None`. Past it, the oldest messages of the lowest priority which may be dropped, see
+    /// `MSG_DROP_PRIORITY`, are dropped as new ones are queued and counted by `take_dropped_msgs`.
+    /// Messages of a higher priority are queued regardless.
+    pub fn set_queue_limit(&mut self, bytes: Option<usize>) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.queue_limit = bytes;
+        }
+    }
+
+    /// Whether the last read stopped short for want of memory rather than because there was
+    /// nothing more to read. The socket won't become readable again by itself, so the read has to
+    /// be retried once memory has been freed.
+    pub fn is_read_stalled(&self) -> bool {
+        self.inner.as_ref().map_or(false, |inner| inner.read_stalled)
+    }
+
+    /// Limits the bytes read off the socket from now on to `bytes`, or lifts the limit if `None`.
+    /// Set anew for every readable event, so that a peer sending faster than we read doesn't keep
+    /// the event loop from the other peers. Frames already read are still returned once it is
+    /// used up, and a frame cut short carries over to the next read.
+    pub fn set_read_budget(&mut self, bytes: Option<usize>) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.read_budget = bytes;
+        }
+    }
+
+    /// Whether the last read stopped short because the read budget was used up rather than
+    /// because there was nothing more to read. As with `is_read_stalled`, the socket won't become
+    /// readable again for the bytes it still holds.
+    pub fn is_read_budget_spent(&self) -> bool {
+        self.inner
+            .as_ref()
+            .map_or(false, |inner| inner.read_budget_spent)
+    }
+
+    /// Holds the bytes written and read from now on to the rates of `throttle`. A write or read
+    /// which runs out of allowance stops short, see `write_paced_for` and `read_paced_for`.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.throttle = throttle;
+        }
+    }
+
+    /// How long until the rest of the write queue may be written, if the last write stopped short
+    /// for want of upload allowance. The socket isn't watched for writability meanwhile, so the
+    /// write has to be made again once the time is up.
+    pub fn write_paced_for(&self) -> Option<Duration> {
+        self.inner.as_ref().and_then(|inner| inner.write_paced_for)
+    }
+
+    /// How long until more may be read, if the last read stopped short for want of download
+    /// allowance. As with `is_read_stalled`, the socket won't become readable again by itself.
+    pub fn read_paced_for(&self) -> Option<Duration> {
+        self.inner.as_ref().and_then(|inner| inner.read_paced_for)
+    }
+
+    /// Makes every frame carry a timestamp trailer from now on, both ways, once the peer agreed on
+    /// it in the handshake. Frames already queued go without, so no frame may be sent between the
+    /// end of the handshake and this call.
+    pub fn enable_timestamps(&mut self, clock: WallClock) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.timestamps = Some(FrameTimestamps::new(clock));
+        }
+    }
+
+    /// Seals every frame written and opens every frame read from now on, once the peer agreed on
+    /// it in the handshake, see `FrameCipher`. Frames read off the socket but not returned yet are
+    /// opened too, so this has to be called as soon as the last frame the peer sent in the clear
+    /// has been returned, and before anything else is written.
+    pub fn enable_encryption(&mut self, keys: SessionKeys) {
+        if let Some(inner) = self.inner.as_mut() {
+            debug_assert!(inner.current_write.is_none() && inner.write_queue.is_empty());
+            inner.cipher = Some(FrameCipher::new(keys));
+        }
+    }
+
+    /// The one-way latencies estimated from the timestamps of the frames, if enabled.
+    pub fn one_way_latency(&self) -> OneWayLatency {
+        self.inner
+            .as_ref()
+            .and_then(|inner| inner.timestamps.as_ref())
+            .map_or_else(OneWayLatency::default, FrameTimestamps::latency)
+    }
+
+    /// Forgets what the latencies were estimated from so far.
+    pub fn reset_one_way_latency(&mut self) {
+        if let Some(timestamps) = self.inner.as_mut().and_then(|inner| inner.timestamps.as_mut()) {
+            timestamps.reset();
+        }
+    }
+
+    /// Returns the frame being read, if it has only been received in part.
+    pub fn partial_frame(&self) -> Option<PartialFrame> {
+        self.inner
+            .as_ref()
+            .and_then(|inner| inner.decoder.partial_frame())
+    }
+
+    /// Makes the next read or write of the socket fail with the given error, see `IoShim`.
+    #[cfg(test)]
+    pub fn inject_fault(&mut self, site: IoSite, kind: ErrorKind) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.shim.inject(site, kind);
+        }
     }
 
     pub fn take_error(&self) -> Result<Option<io::Error>> {
@@ -67,7 +460,17 @@ impl Socket {
         inner.read()
     }
 
-    // Write a message to the socket.
+    // Like `read`, but returns the raw body of the next frame, for callers decoding it themselves,
+    // with what it is charged to the memory budget. Dropping the charge credits it.
+    pub fn read_frame(&mut self) -> Result<Option<(Vec<u8>, Charge)>> {
+        let inner = self.inner.as_mut().ok_or(CommonError::UninitialisedSocket)?;
+        inner.read_frame()
+    }
+
+    // Write a message to the socket. Messages of the same priority are written in the order they
+    // are passed in, while those of a higher priority overtake them. Control messages, see
+    // `CONTROL_PRIORITY`, are queued even when the memory budget is spent, but only so many, and
+    // give way to the others every `MAX_CONTROL_STREAK` messages.
     //
     // Returns:
     //   - Ok(true):   the message has been successfully written.
@@ -81,7 +484,113 @@ impl Socket {
         msg: Option<(T, Priority)>,
     ) -> ::Res<bool> {
         let inner = self.inner.as_mut().ok_or(CommonError::UninitialisedSocket)?;
-        inner.write(poll, token, msg)
+        let frame = match msg {
+            Some((msg, priority)) => Some((OutFrame::message(&msg)?, priority)),
+            None => None,
+        };
+        inner.write(poll, token, frame)
+    }
+
+    // Like `write`, for the payload of a `Message::Data`. The payload is written from the
+    // allocation passed in, without being serialised or copied.
+    pub fn write_data(
+        &mut self,
+        poll: &Poll,
+        token: Token,
+        payload: Vec<u8>,
+        priority: Priority,
+    ) -> ::Res<bool> {
+        let inner = self.inner.as_mut().ok_or(CommonError::UninitialisedSocket)?;
+        inner.write(poll, token, Some((OutFrame::data(payload), priority)))
+    }
+
+    // Like `write_data`, for a payload which may be queued on other sockets too. Each writes it
+    // from the same allocation, which is freed once the last of them is done with it.
+    pub fn write_shared(
+        &mut self,
+        poll: &Poll,
+        token: Token,
+        payload: Arc<Vec<u8>>,
+        priority: Priority,
+    ) -> ::Res<bool> {
+        let inner = self.inner.as_mut().ok_or(CommonError::UninitialisedSocket)?;
+        inner.write(poll, token, Some((OutFrame::shared(payload), priority)))
+    }
+
+    // Like `write`, for the body of a frame read off another connection, which is forwarded as it
+    // is, see `OutFrame::raw`.
+    #[cfg(feature = "relay")]
+    pub fn write_raw(
+        &mut self,
+        poll: &Poll,
+        token: Token,
+        body: Vec<u8>,
+        priority: Priority,
+    ) -> ::Res<bool> {
+        let inner = self.inner.as_mut().ok_or(CommonError::UninitialisedSocket)?;
+        inner.write(poll, token, Some((OutFrame::raw(body), priority)))
+    }
+}
+
+/// Connects to `addr` over TCP from a socket bound to the given local IP, if any, see
+/// `bind_ip_for`.
+pub fn connect_tcp_from(addr: &SocketAddr, bind_ip: Option<IpAddr>) -> Result<TcpStream> {
+    let bind_ip = match bind_ip_for(bind_ip, addr) {
+        Some(bind_ip) => bind_ip,
+        None => return Ok(TcpStream::connect(addr)?),
+    };
+    let builder = match bind_ip {
+        IpAddr::V4(..) => TcpBuilder::new_v4()?,
+        IpAddr::V6(..) => TcpBuilder::new_v6()?,
+    };
+    let _ = builder.bind(SocketAddr::new(bind_ip, 0))?;
+    Ok(TcpStream::connect_stream(builder.to_tcp_stream()?, addr)?)
+}
+
+/// Returns the IP to bind to in order to connect to `addr`. An IP of the other address family than
+/// `addr` can't be used, so it is skipped with a warning.
+pub fn bind_ip_for(bind_ip: Option<IpAddr>, addr: &SocketAddr) -> Option<IpAddr> {
+    match bind_ip {
+        Some(bind_ip) if bind_ip.is_ipv4() != addr.is_ipv4() => {
+            warn!(
+                "Not binding to {} to connect to {}: address families differ",
+                bind_ip, addr
+            );
+            None
+        }
+        bind_ip => bind_ip,
+    }
+}
+
+/// Returns `addr` in the form a socket bound to `local_addr` can connect to: a dual-stack socket,
+/// bound to an IPv6 address, only reaches IPv4 peers at their IPv4-mapped IPv6 address.
+pub fn addr_for_family(addr: &SocketAddr, local_addr: &SocketAddr) -> SocketAddr {
+    match (*addr, *local_addr) {
+        (SocketAddr::V4(v4), SocketAddr::V6(..)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => *addr,
+    }
+}
+
+/// Turns an IPv4-mapped IPv6 address, which IPv4 peers of a dual-stack socket show up with, back
+/// into the IPv4 one, so it compares equal to the same address learnt any other way.
+pub fn unmapped_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ipv4_of_mapped(&ip) {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(..) => addr,
+    }
+}
+
+// Unlike `Ipv6Addr::to_ipv4`, leaves alone the IPv4-compatible addresses, such as `::1`.
+fn ipv4_of_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] {
+        ip.to_ipv4()
+    } else {
+        None
     }
 }
 
@@ -135,12 +644,245 @@ impl Evented for Socket {
     }
 }
 
+/// What a `Socket` reads from and writes to. An enum rather than a trait object: the transports
+/// are a closed set, and every read and write would otherwise go through a virtual call.
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "utp")]
+    Utp(UtpStream),
+    #[cfg(feature = "websocket")]
+    Ws(WsStream),
+}
+
+impl Stream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.peer_addr(),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref stream) => stream.peer_addr(),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref stream) => stream.peer_addr(),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.local_addr(),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref stream) => stream.local_addr(),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref stream) => stream.local_addr(),
+        }
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.take_error(),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref stream) => stream.take_error(),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref stream) => stream.take_error(),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref stream) => stream.shutdown(),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref stream) => stream.shutdown(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref mut stream) => stream.flush(),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl Evented for Stream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.register(poll, token, interest, opts),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref stream) => stream.register(poll, token, interest, opts),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref stream) => stream.register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.reregister(poll, token, interest, opts),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref stream) => stream.reregister(poll, token, interest, opts),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref stream) => stream.reregister(poll, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.deregister(poll),
+            #[cfg(feature = "utp")]
+            Stream::Utp(ref stream) => stream.deregister(poll),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ref stream) => stream.deregister(poll),
+        }
+    }
+}
+
 struct SockInner {
-    stream: TcpStream,
-    read_buffer: Vec<u8>,
-    read_len: usize,
-    write_queue: BTreeMap<Priority, VecDeque<(Instant, Vec<u8>)>>,
-    current_write: Option<Vec<u8>>,
+    stream: Stream,
+    decoder: FrameDecoder,
+    frames: VecDeque<(Vec<u8>, Charge)>,
+    queued_bytes: usize,
+    write_queue: BTreeMap<Priority, VecDeque<Queued>>,
+    /// Bytes of the frames in `write_queue`, by priority.
+    write_queue_bytes: BTreeMap<Priority, usize>,
+    send_order: SendOrder,
+    current_write: Option<Queued>,
+    dropped_msgs: usize,
+    /// Control messages written since the last of the others, see `MAX_CONTROL_STREAK`.
+    control_streak: usize,
+    bytes_written: u64,
+    bytes_read: u64,
+    drain_rate: DrainRate,
+    timestamps: Option<FrameTimestamps>,
+    cipher: Option<FrameCipher>,
+    budget: MemoryBudget,
+    /// Tells the time of the event loop, see `Socket::set_clock`.
+    clock: WallClock,
+    /// Bytes the write queue may hold, see `Socket::set_queue_limit`.
+    queue_limit: Option<usize>,
+    /// Charge of the frame being read, once its body has been allowed in.
+    reassembly: Charge,
+    read_stalled: bool,
+    /// Bytes which may still be read, see `Socket::set_read_budget`.
+    read_budget: Option<usize>,
+    read_budget_spent: bool,
+    /// Rates the bytes written and read are held to, see `Socket::set_throttle`.
+    throttle: Throttle,
+    read_paced_for: Option<Duration>,
+    write_paced_for: Option<Duration>,
+    shim: IoShim,
+    #[cfg(feature = "relay")]
+    relayed: bool,
+    /// Whether the next frame read is one of the relay's, see `RelayControl`.
+    #[cfg(feature = "relay")]
+    relay_control_next: bool,
+    #[cfg(feature = "relay")]
+    relay_window: RelayWindow,
+}
+
+/// What the relay a connection runs through lets us send it, see `RelayControl::Window`.
+#[cfg(feature = "relay")]
+#[derive(Default)]
+struct RelayWindow {
+    /// Bytes of the frames started since the socket was marked relayed, on the wire.
+    sent: u64,
+    /// The size of the window and the bytes granted in all, once the relay told us.
+    granted: Option<(u64, u64)>,
+    /// Whether the last write stopped for want of the window.
+    shut: bool,
+}
+
+#[cfg(feature = "relay")]
+impl RelayWindow {
+    fn admits(&self, len: usize) -> bool {
+        match self.granted {
+            Some((window, granted)) => {
+                fits_relay_window(window, granted.saturating_sub(self.sent), len as u64)
+            }
+            None => true,
+        }
+    }
+}
+
+/// A frame waiting in the write queue.
+struct Queued {
+    timestamp: Instant,
+    seq: u64,
+    frame: OutFrame,
+    charge: Charge,
+}
+
+/// Numbers the frames of each priority as they are queued, to check in debug builds that they
+/// leave the queue in the same order. Expired frames may be dropped, leaving gaps, but a frame is
+/// never written after a later one of the same priority. Both the numbering and the check are
+/// compiled out of release builds.
+#[derive(Default)]
+struct SendOrder {
+    queued: HashMap<Priority, u64>,
+    written: HashMap<Priority, u64>,
+}
+
+impl SendOrder {
+    fn next_seq(&mut self, priority: Priority) -> u64 {
+        if !cfg!(debug_assertions) {
+            return 0;
+        }
+        let seq = self.queued.entry(priority).or_insert(0);
+        *seq += 1;
+        *seq
+    }
+
+    fn check_written(&mut self, priority: Priority, seq: u64) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        if let Some(last) = self.written.insert(priority, seq) {
+            debug_assert!(
+                last < seq,
+                "Frame {} of priority {} written after frame {}",
+                seq,
+                priority,
+                last
+            );
+        }
+    }
 }
 
 impl SockInner {
@@ -152,165 +894,449 @@ impl SockInner {
     //                     again in the next invocation of the `ready` handler.
     //   - Err(error):     there was an error reading from the socket.
     fn read<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
-        if let Some(message) = self.read_from_buffer()? {
-            return Ok(Some(message));
+        match self.read_frame()? {
+            Some((body, _)) => Ok(Some(frame::decode_message(&body)?)),
+            None => Ok(None),
         }
+    }
 
-        // the mio reading window is max at 64k (64 * 1024)
-        let mut buffer = [0; 64 * 1024];
-        let mut is_something_read = false;
-
-        loop {
-            match self.stream.read(&mut buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        let e = Err(CommonError::ZeroByteRead);
-                        if is_something_read {
-                            return match self.read_from_buffer() {
-                                r @ Ok(Some(_)) | r @ Err(_) => r,
-                                Ok(None) => e,
-                            };
-                        } else {
-                            return e;
-                        }
-                    }
-                    self.read_buffer.extend_from_slice(&buffer[0..bytes_read]);
-                    is_something_read = true;
-                }
-                Err(error) => {
-                    return if error.kind() == ErrorKind::WouldBlock
-                        || error.kind() == ErrorKind::Interrupted
-                    {
-                        if is_something_read {
-                            self.read_from_buffer()
-                        } else {
-                            Ok(None)
-                        }
-                    } else {
-                        Err(From::from(error))
-                    }
+    // Returns the next frame, see `read_raw_frame`, opened if encryption is enabled and with its
+    // timestamp trailer taken off if timestamps are.
+    fn read_frame(&mut self) -> Result<Option<(Vec<u8>, Charge)>> {
+        let (mut frame, charge) = loop {
+            let frame = match self.read_raw_frame()? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            #[cfg(feature = "relay")]
+            {
+                if self.relayed && self.take_relay_control(&frame.0)? {
+                    continue;
                 }
             }
+            break frame;
+        };
+        if let Some(cipher) = self.cipher.as_mut() {
+            frame = cipher.open(&frame)?;
         }
+        if let Some(timestamps) = self.timestamps.as_mut() {
+            timestamps.receive(&mut frame)?;
+        }
+        Ok(Some((frame, charge)))
     }
 
-    fn read_from_buffer<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
-        let u32_size = mem::size_of::<u32>();
-
-        if self.read_len == 0 {
-            if self.read_buffer.len() < u32_size {
-                return Ok(None);
+    // Takes in `body` if it is one of the frames the relay sends of its own, or the empty frame
+    // announcing one, see `RelayControl`. Frames the relay dropped on their way to us are passed
+    // over by the cipher, ours it dropped count as dropped messages, and what it grants us is
+    // added to our window.
+    #[cfg(feature = "relay")]
+    fn take_relay_control(&mut self, body: &[u8]) -> Result<bool> {
+        if body.is_empty() {
+            self.relay_control_next = true;
+            return Ok(true);
+        }
+        if !mem::replace(&mut self.relay_control_next, false) {
+            return Ok(false);
+        }
+        match RelayControl::decode(body)? {
+            RelayControl::QuotaExceeded(to_us, from_us) => {
+                if let Some(cipher) = self.cipher.as_mut() {
+                    cipher.skip(u64::from(to_us));
+                }
+                self.dropped_msgs += from_us as usize;
+            }
+            RelayControl::Window(window) => self.relay_window.granted = Some((window, window)),
+            RelayControl::Credit(more) => {
+                if let Some((_, ref mut granted)) = self.relay_window.granted {
+                    *granted += more;
+                }
             }
+        }
+        Ok(true)
+    }
 
-            self.read_len = Cursor::new(&self.read_buffer).read_u32::<LittleEndian>()? as usize;
+    #[cfg(feature = "relay")]
+    fn relay_window_shut(&self) -> bool {
+        self.relay_window.shut
+    }
 
-            if self.read_len > MAX_PAYLOAD_SIZE {
-                return Err(CommonError::PayloadSizeProhibitive);
-            }
+    #[cfg(not(feature = "relay"))]
+    fn relay_window_shut(&self) -> bool {
+        false
+    }
 
-            self.read_buffer = self.read_buffer[u32_size..].to_owned();
+    // Read from the socket until it would block, returning the first complete frame. Further
+    // frames are kept for the following calls, but we stop reading early once they add up to the
+    // maximum payload size, so a fast sender can't make us buffer without bounds, or once the
+    // memory budget runs short, see `read_allowance`. A read interrupted by a signal is made
+    // again: with edge-triggered events, giving up on it would leave what remains to be read
+    // unnoticed.
+    fn read_raw_frame(&mut self) -> Result<Option<(Vec<u8>, Charge)>> {
+        self.read_stalled = false;
+        self.read_budget_spent = false;
+        self.read_paced_for = None;
+        if let Some(frame) = self.pop_frame() {
+            return Ok(Some(frame));
         }
 
-        if self.read_len > self.read_buffer.len() {
-            return Ok(None);
+        // the mio reading window is max at 64k (64 * 1024)
+        let mut buffer = [0; 64 * 1024];
+
+        while self.queued_bytes < MAX_PAYLOAD_SIZE {
+            let wanted = match self.read_allowance() {
+                Some(wanted) => cmp::min(wanted, buffer.len()),
+                None => {
+                    self.read_stalled = true;
+                    break;
+                }
+            };
+            let wanted = match self.read_budget {
+                Some(0) => {
+                    self.read_budget_spent = true;
+                    break;
+                }
+                Some(left) => cmp::min(wanted, left),
+                None => wanted,
+            };
+            let wanted = match self.throttle.read_allowance(self.clock.now()) {
+                Ok(allowance) => cmp::min(wanted, allowance),
+                Err(wait) => {
+                    self.read_paced_for = Some(wait);
+                    break;
+                }
+            };
+            let res = match self.shim.check(IoSite::Read) {
+                Ok(()) => self.stream.read(&mut buffer[..wanted]),
+                Err(error) => Err(error),
+            };
+            match res {
+                Ok(0) => {
+                    return match self.pop_frame() {
+                        Some(frame) => Ok(Some(frame)),
+                        None => Err(CommonError::ZeroByteRead),
+                    };
+                }
+                Ok(bytes_read) => {
+                    if let Some(ref mut left) = self.read_budget {
+                        *left -= bytes_read;
+                    }
+                    self.throttle.read(bytes_read);
+                    self.bytes_read += bytes_read as u64;
+                    let mut input = &buffer[..bytes_read];
+                    while !input.is_empty() {
+                        if let Some(frame) = self.decoder.decode(&mut input)? {
+                            let charge = mem::replace(&mut self.reassembly, Charge::default());
+                            self.queued_bytes += frame.len();
+                            self.frames.push_back((frame, charge));
+                        }
+                    }
+                }
+                Err(error) => match IoErrorClass::of(&error) {
+                    IoErrorClass::Retry => continue,
+                    IoErrorClass::WouldBlock => break,
+                    IoErrorClass::RemoteClosed | IoErrorClass::Fatal => {
+                        return Err(From::from(error))
+                    }
+                },
+            }
         }
 
-        let result = deserialise_from(&mut Cursor::new(&self.read_buffer))?;
+        Ok(self.pop_frame())
+    }
 
-        self.read_buffer = self.read_buffer[self.read_len..].to_owned();
-        self.read_len = 0;
+    fn pop_frame(&mut self) -> Option<(Vec<u8>, Charge)> {
+        let frame = self.frames.pop_front()?;
+        self.queued_bytes -= frame.0.len();
+        Some(frame)
+    }
 
-        Ok(Some(result))
+    // Returns how many bytes may be read next, or `None` if none may for want of memory. Without
+    // a limit to the budget there is no bound. With one, reads go no further than the end of the
+    // current header or body, and the body of a frame is only read once it has been charged in
+    // full, which is refused from `MemoryPressure::RefuseReassembly` on. From
+    // `MemoryPressure::PauseReads` on, nothing is read at all.
+    fn read_allowance(&mut self) -> Option<usize> {
+        if self.budget.limit().is_none() {
+            return Some(usize::max_value());
+        }
+        if self.budget.pressure() >= MemoryPressure::PauseReads {
+            return None;
+        }
+        if let Some(partial) = self.decoder.partial_frame() {
+            if self.reassembly.bytes() < partial.len {
+                self.reassembly = self
+                    .budget
+                    .charge_below(partial.len, MemoryPressure::RefuseReassembly)?;
+            }
+        }
+        Some(self.decoder.wanted())
     }
 
-    // Write a message to the socket.
-    //
-    // Returns:
-    //   - Ok(true):   the message has been successfully written.
-    //   - Ok(false):  the message has been queued, but not yet fully written.
-    //                 Write event is already scheduled for next time.
-    //   - Err(error): there was an error while writing to the socket.
-    fn write<T: Serialize>(
-        &mut self,
-        poll: &Poll,
-        token: Token,
-        msg: Option<(T, Priority)>,
-    ) -> ::Res<bool> {
+    // Drops the queues of messages which may be dropped, see `MSG_DROP_PRIORITY`: all of them if
+    // `all`, or else those from the first whose oldest message is too old on.
+    fn drop_droppable(&mut self, all: bool) {
         let expired_keys: Vec<u8> = self
             .write_queue
             .iter()
             .skip_while(|&(&priority, queue)| {
                 priority < MSG_DROP_PRIORITY || // Don't drop high-priority messages.
-                queue.front().map_or(true, |&(ref timestamp, _)| {
-                    timestamp.elapsed().as_secs() <= MAX_MSG_AGE_SECS
+                !all && queue.front().map_or(true, |queued| {
+                    queued.timestamp.elapsed().as_secs() <= MAX_MSG_AGE_SECS
                 })
             })
             .map(|(&priority, _)| priority)
             .collect();
         let dropped_msgs: usize = expired_keys
             .iter()
-            .filter_map(|priority| self.write_queue.remove(priority))
+            .filter_map(|priority| {
+                let _ = self.write_queue_bytes.remove(priority);
+                self.write_queue.remove(priority)
+            })
             .map(|queue| queue.len())
             .sum();
         if dropped_msgs > 0 {
+            self.dropped_msgs += dropped_msgs;
             trace!(
-                "Insufficient bandwidth. Dropping {} messages with priority >= {}.",
+                "Insufficient {}. Dropping {} messages with priority >= {}.",
+                if all { "memory" } else { "bandwidth" },
                 dropped_msgs,
                 expired_keys[0]
             );
         }
+    }
 
-        if let Some((msg, priority)) = msg {
-            let mut data = Cursor::new(Vec::with_capacity(mem::size_of::<u32>()));
-
-            let _ = data.write_u32::<LittleEndian>(0);
-
-            serialise_into(&msg, &mut data)?;
+    // Drops the oldest messages of the lowest priority which may be dropped, see
+    // `MSG_DROP_PRIORITY`, until the write queue fits in its limit or only messages of higher
+    // priorities are left.
+    fn enforce_queue_limit(&mut self) {
+        let limit = match self.queue_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut queued: usize = self.write_queue_bytes.values().sum();
+        let mut dropped_msgs = 0;
+        while queued > limit {
+            let priority = match self.write_queue.keys().next_back() {
+                Some(&priority) if priority >= MSG_DROP_PRIORITY => priority,
+                _ => break,
+            };
+            let (dropped, empty) = {
+                let queue = unwrap!(self.write_queue.get_mut(&priority));
+                (unwrap!(queue.pop_front()), queue.is_empty())
+            };
+            let bytes = dropped.frame.wire_len();
+            if empty {
+                let _ = self.write_queue.remove(&priority);
+                let _ = self.write_queue_bytes.remove(&priority);
+            } else if let Some(queued_bytes) = self.write_queue_bytes.get_mut(&priority) {
+                *queued_bytes -= bytes;
+            }
+            queued -= bytes;
+            dropped_msgs += 1;
+        }
+        if dropped_msgs > 0 {
+            self.dropped_msgs += dropped_msgs;
+            trace!(
+                "Write queue over its limit of {} bytes. Dropped {} messages.",
+                limit,
+                dropped_msgs
+            );
+        }
+    }
 
-            let len = data.position() - mem::size_of::<u32>() as u64;
-            data.set_position(0);
-            data.write_u32::<LittleEndian>(len as u32)?;
+    // Priority of the frame to write next: the highest one queued, unless control messages took
+    // their turn too many times in a row already while others were waiting.
+    fn next_priority(&mut self) -> Option<Priority> {
+        let (first, second) = {
+            let mut keys = self.write_queue.keys();
+            (keys.next().cloned(), keys.next().cloned())
+        };
+        let next = match (first, second) {
+            (Some(CONTROL_PRIORITY), Some(other)) if self.control_streak >= MAX_CONTROL_STREAK => {
+                Some(other)
+            }
+            (first, _) => first,
+        };
+        match next {
+            Some(CONTROL_PRIORITY) => self.control_streak += 1,
+            Some(_) => self.control_streak = 0,
+            None => (),
+        }
+        next
+    }
 
-            let entry = self
-                .write_queue
-                .entry(priority)
-                .or_insert_with(|| VecDeque::with_capacity(10));
-            entry.push_back((Instant::now(), data.into_inner()));
+    // Queue a frame and write as much of the queue as the socket takes.
+    //
+    // Returns:
+    //   - Ok(true):   the message has been successfully written.
+    //   - Ok(false):  the message has been queued, but not yet fully written.
+    //                 Write event is already scheduled for next time.
+    //   - Err(error): there was an error while writing to the socket.
+    fn write(
+        &mut self,
+        poll: &Poll,
+        token: Token,
+        frame: Option<(OutFrame, Priority)>,
+    ) -> ::Res<bool> {
+        self.write_paced_for = None;
+        #[cfg(feature = "relay")]
+        {
+            self.relay_window.shut = false;
         }
+        let shed = self.budget.pressure() >= MemoryPressure::DropLowPriority;
+        self.drop_droppable(shed);
 
-        if self.current_write.is_none() {
-            let (key, (_time_stamp, data), empty) = match self.write_queue.iter_mut().next() {
-                Some((key, queue)) => (*key, unwrap!(queue.pop_front()), queue.is_empty()),
-                None => return Ok(true),
+        if let Some((mut frame, priority)) = frame {
+            if self.timestamps.is_some() {
+                frame.reserve_trailer();
+            }
+            let control_queue_full = priority == CONTROL_PRIORITY
+                && self
+                    .write_queue
+                    .get(&CONTROL_PRIORITY)
+                    .map_or(false, |queue| queue.len() >= MAX_QUEUED_CONTROL_MSGS);
+            let charge = if control_queue_full {
+                None
+            } else {
+                self.budget.charge_msg(frame.wire_len(), priority)
             };
-            if empty {
-                let _ = self.write_queue.remove(&key);
+            match charge {
+                Some(charge) => {
+                    let seq = self.send_order.next_seq(priority);
+                    *self.write_queue_bytes.entry(priority).or_insert(0) += frame.wire_len();
+                    let entry = self
+                        .write_queue
+                        .entry(priority)
+                        .or_insert_with(|| VecDeque::with_capacity(10));
+                    entry.push_back(Queued {
+                        timestamp: self.clock.now(),
+                        seq,
+                        frame,
+                        charge,
+                    });
+                    self.enforce_queue_limit();
+                }
+                None => {
+                    self.dropped_msgs += 1;
+                    trace!(
+                        "Insufficient {}. Dropping a message with priority {}.",
+                        if control_queue_full { "bandwidth" } else { "memory" },
+                        priority
+                    );
+                }
             }
-            self.current_write = Some(data);
         }
 
-        if let Some(data) = self.current_write.take() {
-            match self.stream.write(&data) {
-                Ok(bytes_txd) => {
-                    if bytes_txd < data.len() {
-                        self.current_write = Some(data[bytes_txd..].to_owned());
-                    }
+        if self.current_write.is_none() && self.write_queue.is_empty() {
+            // Whatever was waiting has been dropped rather than written.
+            self.drain_rate.written(0, self.clock.now(), false);
+            return Ok(true);
+        }
+
+        // Write as many frames as the kernel takes, so that whatever is queued goes out right away
+        // rather than one frame per writable event. A frame written in part is always finished
+        // before the next one is started, whatever its priority.
+        let mut written = 0;
+        loop {
+            if self.current_write.is_none() {
+                let key = match self.next_priority() {
+                    Some(key) => key,
+                    None => break,
+                };
+                let (mut queued, empty) = {
+                    let queue = unwrap!(self.write_queue.get_mut(&key));
+                    (unwrap!(queue.pop_front()), queue.is_empty())
+                };
+                if empty {
+                    let _ = self.write_queue.remove(&key);
+                    let _ = self.write_queue_bytes.remove(&key);
+                } else if let Some(bytes) = self.write_queue_bytes.get_mut(&key) {
+                    *bytes -= queued.frame.wire_len();
                 }
-                Err(error) => {
-                    if error.kind() == ErrorKind::WouldBlock
-                        || error.kind() == ErrorKind::Interrupted
-                    {
-                        self.current_write = Some(data);
-                    } else {
-                        return Err(From::from(error));
+                self.send_order.check_written(key, queued.seq);
+                if queued.frame.has_trailer() {
+                    if let Some(timestamps) = self.timestamps.as_mut() {
+                        queued.frame.set_trailer(timestamps.stamp());
                     }
                 }
+                if let Some(cipher) = self.cipher.as_mut() {
+                    queued.frame.seal(cipher);
+                }
+                self.current_write = Some(queued);
+            }
+
+            // Through a relay, a frame is started only once the relay has room for it, and the
+            // frames behind it wait along with it.
+            #[cfg(feature = "relay")]
+            {
+                let frame = &unwrap!(self.current_write.as_ref()).frame;
+                if self.relayed
+                    && !frame.is_started()
+                    && !self.relay_window.admits(frame.wire_len())
+                {
+                    self.relay_window.shut = true;
+                    break;
+                }
+            }
+
+            let allowance = match self.throttle.write_allowance(self.clock.now()) {
+                Ok(allowance) => allowance,
+                Err(wait) => {
+                    self.write_paced_for = Some(wait);
+                    break;
+                }
+            };
+
+            // Once written, the frame is dropped along with its charge.
+            let mut queued = unwrap!(self.current_write.take());
+            let remaining = queued.frame.remaining();
+            let res = match self.shim.check(IoSite::Write) {
+                Ok(()) => {
+                    let mut paced = Paced {
+                        writer: &mut self.stream,
+                        allowance,
+                    };
+                    queued.frame.write_to(&mut paced)
+                }
+                Err(error) => Err(error),
+            };
+            let written_now = remaining - queued.frame.remaining();
+            #[cfg(feature = "relay")]
+            {
+                if self.relayed && written_now > 0 && remaining == queued.frame.wire_len() {
+                    self.relay_window.sent += remaining as u64;
+                }
+            }
+            self.throttle.written(written_now);
+            written += written_now;
+            match res {
+                Ok(()) => (),
+                Err(error) => match IoErrorClass::of(&error) {
+                    // Picked up again as the current write on the next round, which finds out
+                    // whether the allowance ran out.
+                    IoErrorClass::Retry => self.current_write = Some(queued),
+                    IoErrorClass::WouldBlock if written_now == allowance => {
+                        self.current_write = Some(queued)
+                    }
+                    IoErrorClass::WouldBlock => {
+                        self.current_write = Some(queued);
+                        break;
+                    }
+                    IoErrorClass::RemoteClosed | IoErrorClass::Fatal => {
+                        return Err(From::from(error))
+                    }
+                },
             }
         }
 
         let done = self.current_write.is_none() && self.write_queue.is_empty();
+        self.bytes_written += written as u64;
+        self.drain_rate.written(written, self.clock.now(), !done);
 
-        let event_set = if done {
+        // A paced write is made again once there is allowance for it rather than once the socket
+        // is writable, which it still is, and one held back by the window of a relay once the
+        // relay grants more.
+        let event_set = if done || self.write_paced_for.is_some() || self.relay_window_shut() {
             Ready::error() | Ready::hup() | Ready::readable()
         } else {
             Ready::error() | Ready::hup() | Ready::readable() | Ready::writable()
@@ -322,6 +1348,28 @@ impl SockInner {
     }
 }
 
+/// Passes on no more than `allowance` bytes to `writer`, after which it would block.
+struct Paced<'a, W: 'a> {
+    writer: &'a mut W,
+    allowance: usize,
+}
+
+impl<'a, W: Write> Write for Paced<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.allowance == 0 {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "Out of upload allowance"));
+        }
+        let len = cmp::min(buf.len(), self.allowance);
+        let written = self.writer.write(&buf[..len])?;
+        self.allowance -= written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
 impl Evented for SockInner {
     fn register(
         &self,
@@ -348,8 +1396,628 @@ impl Evented for SockInner {
     }
 }
 
-impl Drop for SockInner {
+impl Drop for Socket {
     fn drop(&mut self) {
-        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(ref inner) = self.inner {
+            let _ = inner.stream.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use common::{
+        decode_message, encode_frame, KeyExchange, KeyPair, KeyRole, Message, RateLimiter,
+    };
+    use net2::TcpStreamExt;
+    use rand::{self, Rng};
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use tests::UniqueId;
+
+    const MSGS: usize = 3000;
+    const PRIORITIES: u8 = 4;
+
+    // Reads `(priority, seq)` off the front of every message until the peer hangs up.
+    fn receive_all(mut stream: StdTcpStream) -> Vec<(Priority, u32)> {
+        // Let the sender's queues fill up first.
+        thread::sleep(Duration::from_millis(200));
+
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut received = Vec::new();
+        let mut buffer = [0; 16 * 1024];
+        loop {
+            let bytes_read = unwrap!(stream.read(&mut buffer));
+            if bytes_read == 0 {
+                return received;
+            }
+            let mut input = &buffer[..bytes_read];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                        Message::Data(payload) => {
+                            received.push((payload[0], LittleEndian::read_u32(&payload[1..5])))
+                        }
+                        msg => panic!("Unexpected message: {:?}", msg),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn same_priority_messages_arrive_in_send_order() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (peer, _) = unwrap!(listener.accept());
+        let receiver = thread::spawn(move || receive_all(peer));
+
+        // A small send buffer and large messages make for plenty of partial writes.
+        unwrap!(stream.set_send_buffer_size(8 * 1024));
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+
+        let mut rng = rand::thread_rng();
+        let mut next_seq = [0u32; PRIORITIES as usize];
+        let mut sent = Vec::with_capacity(MSGS);
+        for _ in 0..MSGS {
+            let priority = rng.gen_range(0, PRIORITIES);
+            let seq = next_seq[priority as usize];
+            next_seq[priority as usize] += 1;
+
+            let mut payload = vec![0; 5 + rng.gen_range(0, 10_000)];
+            payload[0] = priority;
+            LittleEndian::write_u32(&mut payload[1..5], seq);
+            let _ = unwrap!(socket.write_data(&poll, token, payload, priority));
+            sent.push((priority, seq));
+        }
+        while !unwrap!(socket.write::<Message<UniqueId>>(&poll, token, None)) {
+            thread::yield_now();
+        }
+        assert_eq!(socket.take_dropped_msgs(), 0);
+        drop(socket);
+
+        let received = unwrap!(receiver.join());
+        assert_eq!(received.len(), MSGS);
+        // Congestion did make priorities overtake each other...
+        assert_ne!(received, sent);
+        // ...but never within a priority.
+        for priority in 0..PRIORITIES {
+            let seqs: Vec<u32> = received
+                .iter()
+                .filter(|&&(p, _)| p == priority)
+                .map(|&(_, seq)| seq)
+                .collect();
+            let expected: Vec<u32> = (0..next_seq[priority as usize]).collect();
+            assert_eq!(seqs, expected, "priority {}", priority);
+        }
+    }
+
+    #[test]
+    fn frames_cut_short_by_the_read_budget_carry_over() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (mut peer, _) = unwrap!(listener.accept());
+        let sent: Vec<Message<UniqueId>> = (0..3).map(|i| Message::Data(vec![i; 10_000])).collect();
+        for msg in &sent {
+            unwrap!(peer.write_all(&unwrap!(encode_frame(msg))));
+        }
+
+        let mut socket = Socket::wrap(stream);
+        let mut received = Vec::new();
+        let mut cut_short = 0;
+        for _ in 0..1000 {
+            socket.set_read_budget(Some(4096));
+            while let Some(msg) = unwrap!(socket.read::<Message<UniqueId>>()) {
+                received.push(msg);
+            }
+            if socket.is_read_budget_spent() {
+                cut_short += 1;
+            } else if received.len() == sent.len() {
+                break;
+            } else {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert_eq!(received, sent);
+        // Every frame was read over more than one budget.
+        assert!(cut_short >= 7, "cut short {} times", cut_short);
+    }
+
+    #[test]
+    fn partly_written_frame_is_finished_before_higher_priority_ones() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (peer, _) = unwrap!(listener.accept());
+
+        unwrap!(stream.set_send_buffer_size(8 * 1024));
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+
+        // The first write goes out straight away, but only in part.
+        let mut payload = vec![0; 1024 * 1024];
+        payload[0] = 3;
+        assert!(!unwrap!(socket.write_data(&poll, token, payload, 3)));
+
+        for seq in 0..10 {
+            let mut payload = vec![0; 5];
+            LittleEndian::write_u32(&mut payload[1..5], seq);
+            assert!(!unwrap!(socket.write_data(&poll, token, payload, 0)));
+        }
+
+        let receiver = thread::spawn(move || receive_all(peer));
+        while !unwrap!(socket.write::<Message<UniqueId>>(&poll, token, None)) {
+            thread::yield_now();
+        }
+        drop(socket);
+
+        let mut expected = vec![(3, 0)];
+        expected.extend((0..10).map(|seq| (0, seq)));
+        assert_eq!(unwrap!(receiver.join()), expected);
+    }
+
+    #[test]
+    fn handed_over_queue_is_written_by_the_new_socket() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let old_stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (old_peer, _) = unwrap!(listener.accept());
+        let new_stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (new_peer, _) = unwrap!(listener.accept());
+
+        unwrap!(old_stream.set_send_buffer_size(8 * 1024));
+        let poll = unwrap!(Poll::new());
+        let (old_token, new_token) = (Token(0), Token(1));
+        let mut old = Socket::wrap(old_stream);
+        let mut new = Socket::wrap(new_stream);
+        unwrap!(old.register(&poll, old_token, Ready::writable(), PollOpt::edge()));
+        unwrap!(new.register(&poll, new_token, Ready::writable(), PollOpt::edge()));
+
+        // The frame written in part stays with the old socket, the rest moves in order.
+        let mut payload = vec![0; 1024 * 1024];
+        payload[0] = 3;
+        assert!(!unwrap!(old.write_data(&poll, old_token, payload, 3)));
+        for seq in 0..10 {
+            let mut payload = vec![0; 5];
+            payload[0] = (seq % 2) as u8 + 1;
+            LittleEndian::write_u32(&mut payload[1..5], seq);
+            let priority = payload[0];
+            assert!(!unwrap!(old.write_data(&poll, old_token, payload, priority)));
+        }
+        old.hand_over_queue(&mut new);
+
+        let old_receiver = thread::spawn(move || receive_all(old_peer));
+        let new_receiver = thread::spawn(move || receive_all(new_peer));
+        while !unwrap!(old.write::<Message<UniqueId>>(&poll, old_token, None)) {
+            thread::yield_now();
+        }
+        while !unwrap!(new.write::<Message<UniqueId>>(&poll, new_token, None)) {
+            thread::yield_now();
+        }
+        drop(old);
+        drop(new);
+
+        assert_eq!(unwrap!(old_receiver.join()), vec![(3, 0)]);
+        let mut expected: Vec<_> = (0..10).filter(|seq| seq % 2 == 0).map(|seq| (1, seq)).collect();
+        expected.extend((0..10).filter(|seq| seq % 2 == 1).map(|seq| (2, seq)));
+        assert_eq!(unwrap!(new_receiver.join()), expected);
+    }
+
+    #[test]
+    fn throttled_writes_stop_at_the_allowance() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (mut peer, _) = unwrap!(listener.accept());
+
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+        let limiter = RateLimiter::default();
+        limiter.set_limits(Some(1), None, Instant::now());
+        socket.set_throttle(Throttle::new(vec![limiter]));
+
+        // Only the initial burst goes out; the rest waits for the bucket to fill.
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![0; 100_000], 0)));
+        assert_eq!(socket.write_paced_for(), Some(Duration::from_secs(1)));
+
+        unwrap!(peer.set_read_timeout(Some(Duration::from_millis(200))));
+        let mut received = 0;
+        let mut buffer = [0; 16 * 1024];
+        while let Ok(bytes_read) = peer.read(&mut buffer) {
+            if bytes_read == 0 {
+                break;
+            }
+            received += bytes_read;
+        }
+        assert_eq!(received, 16 * 1024);
+    }
+
+    #[test]
+    fn unsent_payloads_are_handed_back_without_copying() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (_peer, _) = unwrap!(listener.accept());
+
+        unwrap!(stream.set_send_buffer_size(8 * 1024));
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+
+        // Nobody reads, so the first payload is written in part and the rest stay queued.
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![0; 1024 * 1024], 0)));
+        let mut origins = Vec::new();
+        for priority in 0..3 {
+            let payload = vec![priority; 1000];
+            origins.push((priority, payload.as_ptr() as usize));
+            assert!(!unwrap!(socket.write_data(&poll, token, payload, priority)));
+        }
+        let msg = Message::Heartbeat::<UniqueId>;
+        assert!(!unwrap!(socket.write(&poll, token, Some((msg, 1)))));
+
+        let unsent: Vec<_> = socket
+            .take_unsent_data()
+            .into_iter()
+            .map(|(priority, payload)| {
+                assert_eq!(payload, vec![priority; 1000]);
+                (priority, payload.as_ptr() as usize)
+            })
+            .collect();
+        assert_eq!(unsent, origins);
+    }
+
+    #[test]
+    fn queue_limit_drops_the_oldest_low_priority_messages() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (_peer, _) = unwrap!(listener.accept());
+
+        unwrap!(stream.set_send_buffer_size(8 * 1024));
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+
+        // Nobody reads, so the first payload is written in part and the rest stay queued.
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![0; 1024 * 1024], 0)));
+        socket.set_queue_limit(Some(10 * 1024));
+
+        // Over the limit, the oldest message of the lowest priority goes first.
+        let low = MSG_DROP_PRIORITY + 1;
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![0; 3000], low)));
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![1; 3000], low)));
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![2; 3000], MSG_DROP_PRIORITY)));
+        assert_eq!(socket.take_dropped_msgs(), 0);
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![3; 3000], MSG_DROP_PRIORITY)));
+        assert_eq!(socket.take_dropped_msgs(), 1);
+
+        // High-priority messages are queued regardless, at the cost of the droppable ones.
+        for seq in 4..8 {
+            assert!(!unwrap!(socket.write_data(&poll, token, vec![seq; 3000], 0)));
+        }
+        assert_eq!(socket.take_dropped_msgs(), 3);
+
+        let unsent: Vec<_> = socket
+            .take_unsent_data()
+            .into_iter()
+            .map(|(priority, payload)| (priority, payload[0]))
+            .collect();
+        assert_eq!(unsent, vec![(0, 4), (0, 5), (0, 6), (0, 7)]);
+    }
+
+    #[test]
+    fn encrypted_frames_open_at_the_peer() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (mut peer, _) = unwrap!(listener.accept());
+
+        let ours = KeyExchange::new(KeyPair::generate());
+        let theirs = KeyExchange::new(KeyPair::generate());
+        let session = unwrap!(theirs.finish(&ours.message(), KeyRole::Responder));
+        let mut cipher = FrameCipher::new(session);
+
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+        socket.enable_encryption(unwrap!(ours.finish(&theirs.message(), KeyRole::Initiator)));
+
+        let payload = b"not for everyone's eyes".to_vec();
+        let msg = Some((Message::Heartbeat::<UniqueId>, CONTROL_PRIORITY));
+        let _ = unwrap!(socket.write(&poll, token, msg));
+        let _ = unwrap!(socket.write_data(&poll, token, payload.clone(), 1));
+        while !unwrap!(socket.write::<Message<UniqueId>>(&poll, token, None)) {
+            thread::yield_now();
+        }
+        drop(socket);
+
+        let mut wire = Vec::new();
+        let _ = unwrap!(peer.read_to_end(&mut wire));
+        assert!(!wire.windows(payload.len()).any(|window| window == &payload[..]));
+
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut input = &wire[..];
+        let mut received = Vec::new();
+        while let Some(body) = unwrap!(decoder.decode(&mut input)) {
+            let body = unwrap!(cipher.open(&body));
+            received.push(unwrap!(decode_message::<Message<UniqueId>>(&body)));
+        }
+        assert!(input.is_empty());
+        match received[..] {
+            [Message::Heartbeat, Message::Data(ref data)] => assert_eq!(*data, payload),
+            ref received => panic!("Unexpected messages: {:?}", received),
+        }
+    }
+
+    #[cfg(feature = "relay")]
+    #[test]
+    fn frames_of_the_relay_are_taken_in_by_the_socket() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (mut peer, _) = unwrap!(listener.accept());
+
+        let ours = KeyExchange::new(KeyPair::generate());
+        let theirs = KeyExchange::new(KeyPair::generate());
+        let session = unwrap!(theirs.finish(&ours.message(), KeyRole::Responder));
+        let mut cipher = FrameCipher::new(session);
+        let framed = |body: &[u8]| {
+            let mut framed = vec![0; frame::FRAME_HEADER_SIZE];
+            LittleEndian::write_u32(&mut framed, body.len() as u32);
+            framed.extend_from_slice(body);
+            framed
+        };
+        let mut socket = Socket::wrap(stream);
+        socket.set_relayed();
+        socket.enable_encryption(unwrap!(ours.finish(&theirs.message(), KeyRole::Initiator)));
+
+        // The second of three frames the peer sealed is dropped by the relay, which says so, and
+        // that it dropped two of ours.
+        let sealed: Vec<Vec<u8>> = (0..3)
+            .map(|i| {
+                let plain = unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![i])));
+                framed(&cipher.seal(&plain[frame::FRAME_HEADER_SIZE..]))
+            })
+            .collect();
+        let mut wire = sealed[0].clone();
+        wire.extend_from_slice(&framed(&[]));
+        wire.extend_from_slice(&unwrap!(encode_frame(&RelayControl::QuotaExceeded(1, 2))));
+        wire.extend_from_slice(&sealed[2]);
+        unwrap!(peer.write_all(&wire));
+
+        let mut received = Vec::new();
+        for _ in 0..1000 {
+            while let Some(msg) = unwrap!(socket.read::<Message<UniqueId>>()) {
+                received.push(msg);
+            }
+            if received.len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(received, vec![Message::Data(vec![0]), Message::Data(vec![2])]);
+        assert_eq!(socket.take_dropped_msgs(), 2);
+    }
+
+    #[cfg(feature = "relay")]
+    #[test]
+    fn writes_through_a_relay_wait_for_its_window() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (mut peer, _) = unwrap!(listener.accept());
+
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+        socket.set_relayed();
+
+        // The relay grants a window of 1000 bytes, then a message of the peer follows, once read
+        // by which we know the window.
+        let control = |control: RelayControl| {
+            let mut wire = vec![0; frame::FRAME_HEADER_SIZE];
+            wire.extend_from_slice(&unwrap!(encode_frame(&control)));
+            wire
+        };
+        unwrap!(peer.write_all(&control(RelayControl::Window(1000))));
+        unwrap!(peer.write_all(&unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![0])))));
+        let mut received = None;
+        for _ in 0..1000 {
+            received = unwrap!(socket.read::<Message<UniqueId>>());
+            if received.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(received, Some(Message::Data(vec![0])));
+
+        // The second message doesn't fit in what is left, nor is half the window left.
+        assert!(unwrap!(socket.write_data(&poll, token, vec![0; 600], 1)));
+        assert!(!socket.is_relay_window_shut());
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![0; 600], 1)));
+        assert!(socket.is_relay_window_shut());
+
+        unwrap!(peer.set_read_timeout(Some(Duration::from_millis(200))));
+        let read_all = |peer: &mut StdTcpStream| {
+            let mut bytes = 0;
+            let mut buffer = [0; 4096];
+            while let Ok(bytes_read) = peer.read(&mut buffer) {
+                if bytes_read == 0 {
+                    break;
+                }
+                bytes += bytes_read;
+            }
+            bytes
+        };
+        let first = read_all(&mut peer);
+        assert!(first > 600 && first < 1000);
+
+        // Once granted more, the second goes out too.
+        unwrap!(peer.write_all(&control(RelayControl::Credit(1000))));
+        for _ in 0..1000 {
+            assert!(unwrap!(socket.read::<Message<UniqueId>>()).is_none());
+            if unwrap!(socket.write::<Message<UniqueId>>(&poll, token, None)) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!socket.is_relay_window_shut());
+        assert_eq!(read_all(&mut peer), first);
+    }
+
+    #[test]
+    fn receipts_estimate_the_drain_of_a_slow_link() {
+        const CHUNK: usize = 16 * 1024;
+        const QUEUED: u64 = 32;
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (mut peer, _) = unwrap!(listener.accept());
+
+        // The link: a chunk read every 8 ms, about 2 MB/s. Returns the bytes read and when the
+        // last of them came in.
+        unwrap!(peer.set_recv_buffer_size(CHUNK));
+        let reader = thread::spawn(move || {
+            let mut buffer = [0; CHUNK];
+            let mut bytes_read = 0;
+            let mut last_read = Instant::now();
+            loop {
+                match unwrap!(peer.read(&mut buffer)) {
+                    0 => return (bytes_read as u64, last_read),
+                    n => {
+                        bytes_read += n;
+                        last_read = Instant::now();
+                    }
+                }
+                thread::sleep(Duration::from_millis(8));
+            }
+        });
+
+        unwrap!(stream.set_send_buffer_size(CHUNK));
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let mut socket = Socket::wrap(stream);
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+
+        // About a second's worth of data, drained for a while for the rate to settle.
+        let payload_len = 64 * 1024;
+        let frame_len = OutFrame::data(vec![0; payload_len]).wire_len() as u64;
+        for _ in 0..QUEUED {
+            let _ = unwrap!(socket.write_data(&poll, token, vec![0; payload_len], 0));
+        }
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(400) {
+            let _ = unwrap!(socket.write::<Message<UniqueId>>(&poll, token, None));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let ahead = socket.queued_ahead_of(0);
+        let written = socket.bytes_written();
+        assert_eq!(ahead, QUEUED * frame_len - written);
+        assert!(ahead > 0);
+        let sent_at = Instant::now();
+        let _ = unwrap!(socket.write_data(&poll, token, vec![1; 16], 0));
+        let receipt = socket.receipt(ahead - (socket.bytes_written() - written));
+        let est_drain_ms = unwrap!(receipt.est_drain_ms);
+
+        while !unwrap!(socket.write::<Message<UniqueId>>(&poll, token, None)) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let written = socket.bytes_written();
+        drop(socket);
+        let (bytes_read, last_read) = unwrap!(reader.join());
+        assert_eq!(bytes_read, written);
+
+        let drained = last_read - sent_at;
+        let drained_ms = drained.as_secs() * 1000 + u64::from(drained.subsec_nanos() / 1_000_000);
+        let error = (est_drain_ms as f64 - drained_ms as f64).abs() / drained_ms as f64;
+        assert!(error < 0.4, "estimated {} ms, took {} ms", est_drain_ms, drained_ms);
+    }
+
+    #[test]
+    fn control_messages_get_through_a_full_queue_and_budget() {
+        const LIMIT: usize = 2 * 1024 * 1024;
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        let (peer, _) = unwrap!(listener.accept());
+
+        unwrap!(stream.set_send_buffer_size(8 * 1024));
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        let budget = MemoryBudget::new(LIMIT);
+        let mut socket = Socket::wrap(stream);
+        socket.set_memory_budget(budget.clone());
+        unwrap!(socket.register(&poll, token, Ready::writable(), PollOpt::edge()));
+
+        // Bulk data written in part, and more of it waiting, with the budget spent.
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![0; 1024 * 1024], 1)));
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![1; 16], 1)));
+        let _rest = unwrap!(budget.charge(LIMIT - budget.used()));
+        assert!(!unwrap!(socket.write_data(&poll, token, vec![2; 16], 1)));
+        assert_eq!(socket.take_dropped_msgs(), 1);
+
+        // Control messages are queued regardless, up to their own bound.
+        let heartbeat = || Some((Message::Heartbeat::<UniqueId>, CONTROL_PRIORITY));
+        for _ in 0..MAX_QUEUED_CONTROL_MSGS + 10 {
+            assert!(!unwrap!(socket.write(&poll, token, heartbeat())));
+        }
+        assert_eq!(socket.take_dropped_msgs(), 10);
+
+        let receiver = thread::spawn(move || {
+            let mut stream = peer;
+            let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+            let mut received = Vec::new();
+            let mut buffer = [0; 16 * 1024];
+            loop {
+                let mut input = match unwrap!(stream.read(&mut buffer)) {
+                    0 => return received,
+                    bytes_read => &buffer[..bytes_read],
+                };
+                while !input.is_empty() {
+                    if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                        received.push(match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                            Message::Data(payload) => Some(payload[0]),
+                            Message::Heartbeat => None,
+                            msg => panic!("Unexpected message: {:?}", msg),
+                        });
+                    }
+                }
+            }
+        });
+        while !unwrap!(socket.write::<Message<UniqueId>>(&poll, token, None)) {
+            thread::yield_now();
+        }
+        drop(socket);
+
+        // The frame in part is finished first. The control messages then go ahead of the data,
+        // but let it through every so often.
+        let mut expected = vec![Some(0)];
+        expected.extend((0..MAX_CONTROL_STREAK).map(|_| None));
+        expected.push(Some(1));
+        expected.extend((MAX_CONTROL_STREAK..MAX_QUEUED_CONTROL_MSGS).map(|_| None));
+        assert_eq!(unwrap!(receiver.join()), expected);
+    }
+
+    #[test]
+    fn ipv4_peers_of_dual_stack_sockets_are_told_by_their_ipv4_address() {
+        let v4: SocketAddr = unwrap!("192.0.2.1:5483".parse());
+        let v6: SocketAddr = unwrap!("[2001:db8::1]:5483".parse());
+        let dual: SocketAddr = unwrap!("[::]:0".parse());
+
+        let mapped = addr_for_family(&v4, &dual);
+        assert_eq!(mapped, unwrap!("[::ffff:192.0.2.1]:5483".parse()));
+        assert_eq!(unmapped_addr(mapped), v4);
+        assert_eq!(addr_for_family(&v4, &v4), v4);
+        assert_eq!(addr_for_family(&v6, &dual), v6);
+
+        let loopback: SocketAddr = unwrap!("[::1]:5483".parse());
+        assert_eq!(unmapped_addr(loopback), loopback);
+        assert_eq!(unmapped_addr(v6), v6);
     }
 }
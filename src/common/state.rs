@@ -13,14 +13,32 @@ use std::any::Any;
 
 pub type Priority = u8;
 
+/// A state registered with the `Core` under a token, to which the events and timeouts of that token
+/// are dispatched.
+///
+/// While it handles one, a state may remove itself, hand its token over to a successor, register
+/// and remove other states and call into them. It must not re-enter itself: the core doesn't hand
+/// it out until the call returns.
 pub trait State {
     fn as_any(&mut self) -> &mut Any;
 
+    /// Kind of the state, under which the event loop accounts for its work in `CoreStats`.
+    fn name(&self) -> &'static str {
+        "Other"
+    }
+
     fn ready(&mut self, _core: &mut Core, _poll: &Poll, _kind: Ready) {}
 
     fn terminate(&mut self, _core: &mut Core, _poll: &Poll) {}
 
-    fn timeout(&mut self, _core: &mut Core, _poll: &Poll, _timer_id: u8) {}
+    /// Winds the state down as the event loop is about to exit, see `Core::drain`. Unless
+    /// overridden, it is terminated straight away.
+    fn drain(&mut self, core: &mut Core, poll: &Poll) {
+        self.terminate(core, poll)
+    }
+
+    /// Called as the timer `timer_id` of the state, set with `Core::set_timeout`, expires.
+    fn timeout(&mut self, _core: &mut Core, _poll: &Poll, _timer_id: u64) {}
 
     fn write(&mut self, _core: &mut Core, _poll: &Poll, _data: Vec<u8>, _priority: Priority) {}
 }
@@ -0,0 +1,51 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Safe wrappers of the system calls the standard library has none for. This is the one module of
+// the crate allowed unsafe code, each block of which states why it is sound.
+
+#![allow(unsafe_code)]
+
+use libc;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+/// Returns the soft limit on the file descriptors of the process, `None` if it is unlimited.
+pub fn nofile_soft_limit() -> io::Result<Option<libc::rlim_t>> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `getrlimit` writes nothing but the `rlimit` it is passed, which is initialised and
+    // outlives the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        Ok(None)
+    } else {
+        Ok(Some(limit.rlim_cur))
+    }
+}
+
+/// Returns the time of `clock`, one of the `libc::CLOCK_*` ids.
+#[cfg(target_os = "linux")]
+pub fn clock_time(clock: libc::clockid_t) -> io::Result<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `clock_gettime` writes nothing but the `timespec` it is passed, which is initialised
+    // and outlives the call. An unknown clock id fails with `EINVAL` rather than misbehaving.
+    if unsafe { libc::clock_gettime(clock, &mut time) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
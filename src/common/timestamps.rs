@@ -0,0 +1,293 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Timestamps trailing every frame of a connection once both peers agreed on them in the
+// handshake, see `TimestampExtension`, from which the one-way latency of either direction is
+// estimated.
+//
+// The trailer follows the body and is counted in the length prefix. It holds the sender's wall
+// clock time when the frame started to be written, in milliseconds since the Unix epoch as a
+// little endian 48-bit integer, then the last one-way delay the sender measured on a frame of
+// ours, in milliseconds as a little endian `i16`, or `NO_DELAY`. Echoing the delay back is how
+// each side learns the latency of the direction it sends in.
+
+use byteorder::{ByteOrder, LittleEndian};
+use common::{CommonError, Result, WallClock};
+use std::collections::VecDeque;
+use std::i16;
+use std::time::Duration;
+
+/// Size of the trailer of a timestamped frame.
+pub const TIMESTAMP_TRAILER_SIZE: usize = 8;
+
+const SENT_SIZE: usize = 6;
+const SENT_MASK: u64 = (1 << (8 * SENT_SIZE)) - 1;
+/// Echoed delay of a trailer sent before we measured anything new.
+const NO_DELAY: i16 = i16::MIN;
+/// Samples kept per direction. Both the clock offset and the percentiles are worked out from
+/// these, so older samples stop counting.
+const MAX_SAMPLES: usize = 256;
+
+/// The contents of a frame's trailer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampTrailer {
+    pub sent_ms: u64,
+    pub echoed_delay_ms: Option<i64>,
+}
+
+impl TimestampTrailer {
+    pub fn encode(&self) -> [u8; TIMESTAMP_TRAILER_SIZE] {
+        let mut bytes = [0; TIMESTAMP_TRAILER_SIZE];
+        LittleEndian::write_uint(&mut bytes[..SENT_SIZE], self.sent_ms & SENT_MASK, SENT_SIZE);
+        // Longer delays saturate, and `NO_DELAY` is kept out of the range of actual ones.
+        let echoed = self.echoed_delay_ms.map_or(NO_DELAY, |delay| {
+            if delay > i64::from(i16::MAX) {
+                i16::MAX
+            } else if delay <= i64::from(NO_DELAY) {
+                NO_DELAY + 1
+            } else {
+                delay as i16
+            }
+        });
+        LittleEndian::write_i16(&mut bytes[SENT_SIZE..], echoed);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; TIMESTAMP_TRAILER_SIZE]) -> Self {
+        let echoed = LittleEndian::read_i16(&bytes[SENT_SIZE..]);
+        TimestampTrailer {
+            sent_ms: LittleEndian::read_uint(&bytes[..SENT_SIZE], SENT_SIZE),
+            echoed_delay_ms: if echoed == NO_DELAY {
+                None
+            } else {
+                Some(i64::from(echoed))
+            },
+        }
+    }
+}
+
+/// Estimated one-way latencies of a connection, see `Config::timestamp_frames`. Each is `None`
+/// until a sample of its direction came in, and always with peers which didn't agree on
+/// timestamps.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OneWayLatency {
+    /// Median latency of what we send to the peer.
+    pub outbound_p50: Option<Duration>,
+    /// 95th percentile of the latency of what we send to the peer.
+    pub outbound_p95: Option<Duration>,
+    /// Median latency of what the peer sends us.
+    pub inbound_p50: Option<Duration>,
+    /// 95th percentile of the latency of what the peer sends us.
+    pub inbound_p95: Option<Duration>,
+}
+
+/// Turns raw one-way delays, the time a frame was received at minus the time it was sent at as
+/// told by two clocks which may be off from each other, into latencies.
+///
+/// With our clock ahead of the peer's by `skew`, a frame taking `d` to reach us shows a delay of
+/// `d + skew`, and one taking `d` to reach the peer a delay of `d - skew`. No delay can be below
+/// the latency it measures, so the least delay seen in either direction bounds the skew. As it
+/// can't be told apart from an asymmetry of the path, the skew is taken to be the one closest to
+/// zero within those bounds: peers whose clocks are kept in sync get exact latencies, and an
+/// offset between their clocks is corrected for as far as it shows.
+#[derive(Debug, Default)]
+pub struct SkewEstimator {
+    inbound: VecDeque<i64>,
+    outbound: VecDeque<i64>,
+}
+
+impl SkewEstimator {
+    /// Records the delay of a frame the peer sent us.
+    pub fn record_inbound(&mut self, delay_ms: i64) {
+        push_sample(&mut self.inbound, delay_ms);
+    }
+
+    /// Records the delay of a frame we sent, as echoed by the peer.
+    pub fn record_outbound(&mut self, delay_ms: i64) {
+        push_sample(&mut self.outbound, delay_ms);
+    }
+
+    /// How far our clock is estimated to be ahead of the peer's, in milliseconds.
+    pub fn skew_ms(&self) -> i64 {
+        let upper = self.inbound.iter().cloned().min();
+        let lower = self.outbound.iter().cloned().min().map(|min| -min);
+        match (lower, upper) {
+            // The bounds cross when the path got faster since the oldest samples, or when a clock
+            // jumped. Take the middle ground.
+            (Some(lower), Some(upper)) if lower > upper => (lower + upper) / 2,
+            (Some(lower), _) if lower > 0 => lower,
+            (_, Some(upper)) if upper < 0 => upper,
+            _ => 0,
+        }
+    }
+
+    pub fn latency(&self) -> OneWayLatency {
+        let skew = self.skew_ms();
+        let outbound = sorted_latencies(&self.outbound, skew);
+        let inbound = sorted_latencies(&self.inbound, -skew);
+        OneWayLatency {
+            outbound_p50: percentile(&outbound, 50),
+            outbound_p95: percentile(&outbound, 95),
+            inbound_p50: percentile(&inbound, 50),
+            inbound_p95: percentile(&inbound, 95),
+        }
+    }
+}
+
+fn push_sample(samples: &mut VecDeque<i64>, delay_ms: i64) {
+    if samples.len() == MAX_SAMPLES {
+        let _ = samples.pop_front();
+    }
+    samples.push_back(delay_ms);
+}
+
+fn sorted_latencies(samples: &VecDeque<i64>, correction_ms: i64) -> Vec<u64> {
+    let mut latencies: Vec<u64> = samples
+        .iter()
+        .map(|delay| {
+            let latency = delay + correction_ms;
+            if latency > 0 {
+                latency as u64
+            } else {
+                0
+            }
+        })
+        .collect();
+    latencies.sort();
+    latencies
+}
+
+/// The nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[u64], percent: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (sorted.len() * percent + 99) / 100;
+    Some(Duration::from_millis(sorted[rank.saturating_sub(1)]))
+}
+
+/// The timestamping state of a socket whose frames carry trailers.
+pub struct FrameTimestamps {
+    clock: WallClock,
+    /// Delay of the last frame received, not echoed yet. Each is echoed once only, so that how
+    /// many frames we send doesn't weigh on the samples of the peer.
+    delay_to_echo: Option<i64>,
+    estimator: SkewEstimator,
+}
+
+impl FrameTimestamps {
+    pub fn new(clock: WallClock) -> Self {
+        FrameTimestamps {
+            clock,
+            delay_to_echo: None,
+            estimator: SkewEstimator::default(),
+        }
+    }
+
+    /// Returns the trailer of a frame starting to be written now.
+    pub fn stamp(&mut self) -> [u8; TIMESTAMP_TRAILER_SIZE] {
+        TimestampTrailer {
+            sent_ms: self.clock.now_ms(),
+            echoed_delay_ms: self.delay_to_echo.take(),
+        }.encode()
+    }
+
+    /// Takes the trailer off the body of a frame just received and records its delays.
+    pub fn receive(&mut self, body: &mut Vec<u8>) -> Result<()> {
+        if body.len() < TIMESTAMP_TRAILER_SIZE {
+            return Err(CommonError::MalformedFrame);
+        }
+        let at = body.len() - TIMESTAMP_TRAILER_SIZE;
+        let mut bytes = [0; TIMESTAMP_TRAILER_SIZE];
+        bytes.copy_from_slice(&body[at..]);
+        body.truncate(at);
+
+        let trailer = TimestampTrailer::decode(&bytes);
+        let delay = (self.clock.now_ms() & SENT_MASK) as i64 - trailer.sent_ms as i64;
+        self.delay_to_echo = Some(delay);
+        self.estimator.record_inbound(delay);
+        if let Some(echoed) = trailer.echoed_delay_ms {
+            self.estimator.record_outbound(echoed);
+        }
+        Ok(())
+    }
+
+    pub fn latency(&self) -> OneWayLatency {
+        self.estimator.latency()
+    }
+
+    /// Forgets the samples taken so far.
+    pub fn reset(&mut self) {
+        self.estimator = SkewEstimator::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn trailers_round_trip() {
+        for &(sent_ms, echoed_delay_ms, decoded_delay) in &[
+            (0, None, None),
+            (1_500_000_123_456, Some(42), Some(42)),
+            (SENT_MASK, Some(-7), Some(-7)),
+            (7, Some(1 << 20), Some(i64::from(i16::MAX))),
+            (7, Some(-(1 << 20)), Some(i64::from(NO_DELAY) + 1)),
+        ] {
+            let trailer = TimestampTrailer {
+                sent_ms,
+                echoed_delay_ms,
+            };
+            let decoded = TimestampTrailer::decode(&trailer.encode());
+            assert_eq!(decoded.sent_ms, sent_ms);
+            assert_eq!(decoded.echoed_delay_ms, decoded_delay);
+        }
+    }
+
+    #[test]
+    fn skew_is_corrected_within_its_bounds() {
+        // Our clock is 25 ms ahead: inbound delays of 40 to 59 ms show as 65 to 84, outbound ones
+        // of 10 to 29 ms as -15 to 4.
+        let mut estimator = SkewEstimator::default();
+        assert_eq!(estimator.latency(), OneWayLatency::default());
+        for i in 0..20 {
+            estimator.record_inbound(65 + i);
+            estimator.record_outbound(-15 + i);
+        }
+        // The outbound delays only show 15 ms of it; the rest is taken for a faster outbound path.
+        assert_eq!(estimator.skew_ms(), 15);
+        let latency = estimator.latency();
+        assert_eq!(latency.outbound_p50, ms(9));
+        assert_eq!(latency.outbound_p95, ms(18));
+        assert_eq!(latency.inbound_p50, ms(59));
+        assert_eq!(latency.inbound_p95, ms(68));
+
+        // Clocks in sync give the latencies as measured.
+        let mut estimator = SkewEstimator::default();
+        for i in 0..20 {
+            estimator.record_inbound(40 + i);
+            estimator.record_outbound(10 + i);
+        }
+        assert_eq!(estimator.skew_ms(), 0);
+        assert_eq!(estimator.latency().inbound_p50, ms(49));
+        assert_eq!(estimator.latency().outbound_p95, ms(28));
+
+        // Only the oldest samples are dropped.
+        for _ in 0..MAX_SAMPLES {
+            estimator.record_inbound(100);
+        }
+        assert_eq!(estimator.latency().inbound_p50, ms(100));
+        assert_eq!(estimator.latency().outbound_p50, ms(19));
+    }
+}
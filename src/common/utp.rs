@@ -0,0 +1,1505 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! uTP, the micro transport protocol of BEP 29: reliable, ordered byte streams over UDP, for
+//! peers which reach each other over UDP but not over TCP. The packets are those of BEP 29, so
+//! the header can be checked against other implementations, but only what Crust needs is
+//! implemented: no extensions are sent, and those received are skipped. Congestion is controlled
+//! with a simplified LEDBAT, backing off as the one-way delay rises above `TARGET_DELAY_US`.
+//!
+//! A `UtpEndpoint` owns a UDP socket and hands the packets it receives to the connections made
+//! through it. A `UtpStream` is one such connection, read and written like a non-blocking
+//! `TcpStream` and registered with the poll the same way, so that a `Socket` can be made of
+//! either.
+
+use common::{
+    bind_ip_for, Charge, Core, CoreTimer, IoErrorClass, MemoryBudget, MemoryPressure, Result, State,
+    WallClock,
+};
+use mio::udp::UdpSocket;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use rand;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const VERSION: u8 = 1;
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+
+const HEADER_SIZE: usize = 20;
+/// Largest payload of a packet, small enough for the packet to fit the MTU of most paths.
+const MAX_PAYLOAD: usize = 1200;
+/// Largest packet read off the socket. Bigger ones are cut short and dropped as malformed.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+/// Bytes received which we buffer for the application at most. Data packets arriving beyond it
+/// are dropped, to be sent again.
+const RECV_WINDOW: usize = 1024 * 1024;
+/// Bytes written by the application which we buffer at most before writes would block.
+const SEND_BUFFER: usize = 1024 * 1024;
+/// Sequence numbers ahead of the next one expected within which packets are kept for later.
+const REORDER_WINDOW: u16 = 2048;
+
+/// One-way delay above the lowest one seen that LEDBAT aims for.
+const TARGET_DELAY_US: u32 = 100_000;
+/// Most the congestion window grows by per round trip.
+const MAX_CWND_INCREASE: usize = 3000;
+const MIN_CWND: usize = 2 * MAX_PAYLOAD;
+const INITIAL_CWND: usize = 4 * MAX_PAYLOAD;
+const MAX_CWND: usize = RECV_WINDOW;
+
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 500;
+const MAX_RTO_MS: u64 = 30_000;
+/// Times a packet is sent before the connection is given up on.
+const MAX_TRANSMISSIONS: u32 = 8;
+/// Duplicate acks after which the packet they are stuck on is sent again.
+const FAST_RETRANSMIT_DUP_ACKS: u32 = 3;
+
+/// Incoming connections whose SYN we answered but which the peer didn't acknowledge the answer
+/// of yet, kept at most. SYNs beyond are dropped.
+const MAX_HALF_OPEN: usize = 64;
+/// Time after which an incoming connection the peer didn't acknowledge our answer of is
+/// forgotten.
+const HALF_OPEN_TIMEOUT_MS: u64 = 10_000;
+
+/// Interval at which an endpoint checks its connections for packets to send again.
+const TICK_MS: u64 = 50;
+const TICK_TIMER_ID: u64 = 0;
+
+/// The fixed header of every uTP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    kind: u8,
+    conn_id: u16,
+    timestamp_us: u32,
+    timestamp_diff_us: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+        packet.push(self.kind << 4 | VERSION);
+        // No extension.
+        packet.push(0);
+        push_u16(&mut packet, self.conn_id);
+        push_u32(&mut packet, self.timestamp_us);
+        push_u32(&mut packet, self.timestamp_diff_us);
+        push_u32(&mut packet, self.wnd_size);
+        push_u16(&mut packet, self.seq_nr);
+        push_u16(&mut packet, self.ack_nr);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Returns the header and the payload of `packet`, skipping its extensions, or `None` if it
+    /// isn't a packet of a type and version we know.
+    fn decode(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_SIZE || packet[0] & 0x0f != VERSION {
+            return None;
+        }
+        let kind = packet[0] >> 4;
+        if kind > ST_SYN {
+            return None;
+        }
+        let header = Header {
+            kind,
+            conn_id: read_u16(&packet[2..]),
+            timestamp_us: read_u32(&packet[4..]),
+            timestamp_diff_us: read_u32(&packet[8..]),
+            wnd_size: read_u32(&packet[12..]),
+            seq_nr: read_u16(&packet[16..]),
+            ack_nr: read_u16(&packet[18..]),
+        };
+        let mut extension = packet[1];
+        let mut rest = &packet[HEADER_SIZE..];
+        while extension != 0 {
+            if rest.len() < 2 || rest.len() < 2 + rest[1] as usize {
+                return None;
+            }
+            extension = rest[0];
+            rest = &rest[2 + rest[1] as usize..];
+        }
+        Some((header, rest))
+    }
+}
+
+fn push_u16(packet: &mut Vec<u8>, value: u16) {
+    packet.push((value >> 8) as u8);
+    packet.push(value as u8);
+}
+
+fn push_u32(packet: &mut Vec<u8>, value: u32) {
+    push_u16(packet, (value >> 16) as u16);
+    push_u16(packet, value as u16);
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) << 8 | u16::from(bytes[1])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from(read_u16(bytes)) << 16 | u32::from(read_u16(&bytes[2..]))
+}
+
+/// Whether sequence number `a` comes no later than `b`, allowing for them wrapping around.
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+/// Microseconds since the Unix epoch, wrapped to fit the header. Only their differences matter.
+fn now_us() -> u32 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    (since_epoch.as_secs() * 1_000_000 + u64::from(since_epoch.subsec_nanos() / 1000)) as u32
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
+
+/// Where a connection sends its packets: the socket of its endpoint, or in tests a wire which
+/// loses or reorders them.
+trait Transmit {
+    fn transmit(&self, packet: &[u8], to: &SocketAddr) -> io::Result<Option<usize>>;
+}
+
+impl Transmit for UdpSocket {
+    fn transmit(&self, packet: &[u8], to: &SocketAddr) -> io::Result<Option<usize>> {
+        self.send_to(packet, to)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// Our SYN was sent, waiting for the peer's answer.
+    SynSent,
+    Connected,
+}
+
+/// A packet sent and not acknowledged yet.
+struct Sent {
+    kind: u8,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// An incoming connection whose SYN we answered. It is only taken up once a packet of the peer
+/// acknowledges the answer, so that SYNs from forged addresses cost a bounded amount of memory
+/// and never reach the application.
+struct HalfOpen {
+    syn: Header,
+    /// Sequence number of our answer, that of the first packet we send once taken up.
+    seq_nr: u16,
+    since: Instant,
+}
+
+/// One end of a uTP connection, shared by its `UtpStream` and the `UtpEndpoint` it was made
+/// through.
+struct Conn {
+    state: ConnState,
+    peer: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet we send.
+    seq_nr: u16,
+    /// Sequence number of the last packet received in order.
+    ack_nr: u16,
+    /// Last ack received, to count duplicate ones.
+    last_ack: u16,
+    dup_acks: u32,
+    in_flight: VecDeque<Sent>,
+    in_flight_bytes: usize,
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+    /// Packets received ahead of the next one expected, by sequence number, each charged to
+    /// `budget`. Together with `recv_buf` they hold `RECV_WINDOW` bytes at most.
+    reordered: HashMap<u16, (u8, Vec<u8>, Charge)>,
+    reordered_bytes: usize,
+    budget: MemoryBudget,
+    /// Receive window advertised by the peer.
+    peer_wnd: usize,
+    cwnd: usize,
+    /// Smoothed round trip time and its variance, once measured.
+    rtt_ms: Option<(u64, u64)>,
+    rto: Duration,
+    /// Lowest one-way delay of our packets seen by the peer, see `TARGET_DELAY_US`.
+    base_delay_us: Option<u32>,
+    /// Time our last packet took to reach us by our clock, as echoed to the peer.
+    reply_micro: u32,
+    /// The application shut the connection down, so a FIN follows what it wrote.
+    closing: bool,
+    fin_sent: bool,
+    /// The peer's FIN was received in order: reads return end of file once `recv_buf` is empty.
+    eof: bool,
+    error: Option<ErrorKind>,
+    stream_dropped: bool,
+    readiness: SetReadiness,
+    last_readiness: Ready,
+}
+
+impl Conn {
+    fn new(
+        state: ConnState,
+        peer: SocketAddr,
+        recv_id: u16,
+        readiness: SetReadiness,
+        budget: MemoryBudget,
+    ) -> Self {
+        Conn {
+            state,
+            peer,
+            recv_id,
+            send_id: recv_id.wrapping_add(1),
+            seq_nr: 1,
+            ack_nr: 0,
+            last_ack: 0,
+            dup_acks: 0,
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            reordered: HashMap::new(),
+            reordered_bytes: 0,
+            budget,
+            peer_wnd: MAX_PAYLOAD,
+            cwnd: INITIAL_CWND,
+            rtt_ms: None,
+            rto: Duration::from_millis(INITIAL_RTO_MS),
+            base_delay_us: None,
+            reply_micro: 0,
+            closing: false,
+            fin_sent: false,
+            eof: false,
+            error: None,
+            stream_dropped: false,
+            readiness,
+            last_readiness: Ready::empty(),
+        }
+    }
+
+    /// Takes up an incoming connection once the peer acknowledged our answer to its SYN.
+    fn accepted(
+        peer: SocketAddr,
+        half: &HalfOpen,
+        readiness: SetReadiness,
+        budget: MemoryBudget,
+    ) -> Self {
+        let syn = &half.syn;
+        let recv_id = syn.conn_id.wrapping_add(1);
+        let mut conn = Conn::new(ConnState::Connected, peer, recv_id, readiness, budget);
+        conn.send_id = syn.conn_id;
+        conn.seq_nr = half.seq_nr;
+        conn.ack_nr = syn.seq_nr;
+        conn.last_ack = conn.seq_nr.wrapping_sub(1);
+        conn.peer_wnd = syn.wnd_size as usize;
+        conn
+    }
+
+    fn packet(&self, kind: u8, seq_nr: u16, payload: &[u8]) -> Vec<u8> {
+        Header {
+            kind,
+            conn_id: if kind == ST_SYN {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp_us: now_us(),
+            timestamp_diff_us: self.reply_micro,
+            wnd_size: RECV_WINDOW.saturating_sub(self.recv_buf.len() + self.reordered_bytes) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        }.encode(payload)
+    }
+
+    fn send_to_peer(&self, socket: &Transmit, packet: &[u8]) {
+        // A packet which can't be sent is as good as lost, and sent again like one.
+        match socket.transmit(packet, &self.peer) {
+            Ok(Some(_)) => (),
+            Ok(None) => trace!("uTP packet to {} dropped: socket would block", self.peer),
+            Err(e) => trace!("Failed to send uTP packet to {}: {:?}", self.peer, e),
+        }
+    }
+
+    fn send_state(&self, socket: &Transmit) {
+        let packet = self.packet(ST_STATE, self.seq_nr, &[]);
+        self.send_to_peer(socket, &packet);
+    }
+
+    /// Sends a packet taking a sequence number, to be sent again until it is acknowledged.
+    fn send_new(&mut self, socket: &Transmit, kind: u8, payload: Vec<u8>, now: Instant) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = seq_nr.wrapping_add(1);
+        let packet = self.packet(kind, seq_nr, &payload);
+        self.send_to_peer(socket, &packet);
+        self.in_flight_bytes += payload.len();
+        self.in_flight.push_back(Sent {
+            kind,
+            seq_nr,
+            payload,
+            sent_at: now,
+            transmissions: 1,
+        });
+    }
+
+    fn resend_first(&mut self, socket: &Transmit, now: Instant) {
+        let packet = match self.in_flight.front() {
+            Some(sent) => self.packet(sent.kind, sent.seq_nr, &sent.payload),
+            None => return,
+        };
+        self.send_to_peer(socket, &packet);
+        if let Some(sent) = self.in_flight.front_mut() {
+            sent.sent_at = now;
+            sent.transmissions += 1;
+        }
+    }
+
+    /// Sends what the application wrote as far as the windows allow, then our FIN once it has
+    /// all gone out if the connection is shut down. With nothing in flight, one packet goes out
+    /// whatever the windows, so that a window which opened is learnt about.
+    fn flush(&mut self, socket: &Transmit, now: Instant) {
+        if self.state != ConnState::Connected || self.error.is_some() {
+            return;
+        }
+        let window = cmp::min(self.cwnd, self.peer_wnd);
+        while !self.send_buf.is_empty() {
+            let len = cmp::min(MAX_PAYLOAD, self.send_buf.len());
+            if !self.in_flight.is_empty() && self.in_flight_bytes + len > window {
+                break;
+            }
+            let payload = self.send_buf.drain(..len).collect();
+            self.send_new(socket, ST_DATA, payload, now);
+        }
+        if self.closing && !self.fin_sent && self.send_buf.is_empty() {
+            self.fin_sent = true;
+            self.send_new(socket, ST_FIN, Vec::new(), now);
+        }
+    }
+
+    fn receive(&mut self, socket: &Transmit, header: &Header, payload: &[u8], now: Instant) {
+        if header.kind == ST_RESET {
+            return self.fail(ErrorKind::ConnectionReset);
+        }
+        // A packet acking ones we haven't sent yet is forged, or meant for another connection.
+        if header.kind != ST_SYN && !seq_le(header.ack_nr, self.seq_nr.wrapping_sub(1)) {
+            trace!("Dropping uTP packet from {} acking packets not sent", self.peer);
+            return;
+        }
+        self.reply_micro = now_us().wrapping_sub(header.timestamp_us);
+        self.peer_wnd = header.wnd_size as usize;
+
+        match (self.state, header.kind) {
+            (ConnState::SynSent, ST_STATE) => {
+                self.state = ConnState::Connected;
+                self.ack_nr = header.seq_nr.wrapping_sub(1);
+                // Has the peer take the connection up, whether or not we have anything to send.
+                self.send_state(socket);
+            }
+            (ConnState::SynSent, _) => return,
+            (ConnState::Connected, ST_SYN) => {
+                // Our answer to the SYN was lost.
+                return self.send_state(socket);
+            }
+            (ConnState::Connected, _) => (),
+        }
+
+        self.process_ack(socket, header, now);
+        if header.kind == ST_DATA || header.kind == ST_FIN {
+            self.receive_data(header, payload);
+            self.send_state(socket);
+        }
+        self.flush(socket, now);
+        self.update_readiness();
+    }
+
+    fn process_ack(&mut self, socket: &Transmit, header: &Header, now: Instant) {
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        while self
+            .in_flight
+            .front()
+            .map_or(false, |sent| seq_le(sent.seq_nr, header.ack_nr))
+        {
+            let sent = unwrap!(self.in_flight.pop_front());
+            acked_bytes += sent.payload.len();
+            // Karn's rule: the round trip of a packet sent more than once is ambiguous.
+            if sent.transmissions == 1 {
+                rtt_sample = Some(now.duration_since(sent.sent_at));
+            }
+        }
+        self.in_flight_bytes -= acked_bytes;
+
+        if let Some(sample) = rtt_sample {
+            self.update_rto(millis(sample));
+        }
+        if header.timestamp_diff_us != 0 {
+            let delay = header.timestamp_diff_us;
+            let base = cmp::min(delay, self.base_delay_us.unwrap_or(delay));
+            self.base_delay_us = Some(base);
+            if acked_bytes > 0 {
+                self.grow_cwnd(acked_bytes, delay - base);
+            }
+        }
+
+        if header.ack_nr != self.last_ack || self.in_flight.is_empty() {
+            self.last_ack = header.ack_nr;
+            self.dup_acks = 0;
+        } else if header.kind == ST_STATE {
+            self.dup_acks += 1;
+            if self.dup_acks == FAST_RETRANSMIT_DUP_ACKS {
+                trace!("Fast retransmit to {}", self.peer);
+                self.cwnd = cmp::max(MIN_CWND, self.cwnd / 2);
+                self.resend_first(socket, now);
+            }
+        }
+    }
+
+    fn update_rto(&mut self, sample_ms: u64) {
+        let (srtt, rttvar) = match self.rtt_ms {
+            None => (sample_ms, sample_ms / 2),
+            Some((srtt, rttvar)) => {
+                let delta = if srtt > sample_ms {
+                    srtt - sample_ms
+                } else {
+                    sample_ms - srtt
+                };
+                ((7 * srtt + sample_ms) / 8, (3 * rttvar + delta) / 4)
+            }
+        };
+        self.rtt_ms = Some((srtt, rttvar));
+        let rto = cmp::max(MIN_RTO_MS, cmp::min(MAX_RTO_MS, srtt + 4 * rttvar));
+        self.rto = Duration::from_millis(rto);
+    }
+
+    /// LEDBAT: the window grows while the queuing delay is below target, by at most
+    /// `MAX_CWND_INCREASE` per window acknowledged, and shrinks as far as it goes beyond.
+    fn grow_cwnd(&mut self, acked_bytes: usize, queuing_delay_us: u32) {
+        let off_target =
+            (f64::from(TARGET_DELAY_US) - f64::from(queuing_delay_us)) / f64::from(TARGET_DELAY_US);
+        let gain = MAX_CWND_INCREASE as f64 * off_target * acked_bytes as f64 / self.cwnd as f64;
+        let cwnd = (self.cwnd as f64 + gain)
+            .max(MIN_CWND as f64)
+            .min(MAX_CWND as f64);
+        self.cwnd = cwnd as usize;
+    }
+
+    fn receive_data(&mut self, header: &Header, payload: &[u8]) {
+        if self.eof || payload.len() > MAX_PAYLOAD {
+            return;
+        }
+        // Packets left out are unacknowledged, to be sent again once the application made room.
+        // Those ahead leave room for one more, so that the next one expected always fits once
+        // the application read what it was given.
+        let buffered = self.recv_buf.len() + self.reordered_bytes + payload.len();
+        let expected = self.ack_nr.wrapping_add(1);
+        if header.seq_nr != expected {
+            if buffered + MAX_PAYLOAD > RECV_WINDOW
+                || header.seq_nr.wrapping_sub(expected) >= REORDER_WINDOW
+                || self.reordered.contains_key(&header.seq_nr)
+            {
+                return;
+            }
+            let charge = match self
+                .budget
+                .charge_below(payload.len(), MemoryPressure::RefuseReassembly)
+            {
+                Some(charge) => charge,
+                None => return,
+            };
+            self.reordered_bytes += payload.len();
+            let _ = self
+                .reordered
+                .insert(header.seq_nr, (header.kind, payload.to_vec(), charge));
+            return;
+        }
+        if buffered > RECV_WINDOW {
+            return;
+        }
+        self.accept_data(header.kind, payload);
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            match self.reordered.remove(&next) {
+                Some((kind, payload, _charge)) => {
+                    self.reordered_bytes -= payload.len();
+                    self.accept_data(kind, &payload);
+                }
+                None => break,
+            }
+        }
+        if self.eof {
+            self.reordered.clear();
+            self.reordered_bytes = 0;
+        }
+    }
+
+    fn accept_data(&mut self, kind: u8, payload: &[u8]) {
+        self.ack_nr = self.ack_nr.wrapping_add(1);
+        if kind == ST_FIN {
+            self.eof = true;
+        } else {
+            self.recv_buf.extend(payload);
+        }
+    }
+
+    /// Sends the oldest packet in flight again if its time is up, giving up on the connection
+    /// after `MAX_TRANSMISSIONS`.
+    fn tick(&mut self, socket: &Transmit, now: Instant) {
+        if self.error.is_some() {
+            return;
+        }
+        let (due, transmissions) = match self.in_flight.front() {
+            Some(sent) => (now.duration_since(sent.sent_at) >= self.rto, sent.transmissions),
+            None => (false, 0),
+        };
+        if due {
+            if transmissions >= MAX_TRANSMISSIONS {
+                debug!("uTP connection to {} timed out", self.peer);
+                return self.fail(ErrorKind::TimedOut);
+            }
+            self.cwnd = MIN_CWND;
+            self.rto = cmp::min(self.rto * 2, Duration::from_millis(MAX_RTO_MS));
+            self.resend_first(socket, now);
+        }
+        self.flush(socket, now);
+        self.update_readiness();
+    }
+
+    fn fail(&mut self, kind: ErrorKind) {
+        self.error = Some(kind);
+        self.in_flight.clear();
+        self.in_flight_bytes = 0;
+        self.update_readiness();
+    }
+
+    /// Whether the endpoint may forget the connection.
+    fn is_finished(&self) -> bool {
+        self.error.is_some()
+            || self.stream_dropped
+                && (self.state == ConnState::SynSent || self.fin_sent && self.in_flight.is_empty())
+    }
+
+    fn current_readiness(&self) -> Ready {
+        if self.error.is_some() {
+            return Ready::readable() | Ready::writable() | Ready::error();
+        }
+        let mut ready = Ready::empty();
+        if !self.recv_buf.is_empty() || self.eof {
+            ready |= Ready::readable();
+        }
+        if self.state == ConnState::Connected && !self.closing && self.send_buf.len() < SEND_BUFFER
+        {
+            ready |= Ready::writable();
+        }
+        ready
+    }
+
+    /// Raises the readiness of the stream as it changes. As with a `TcpStream` registered
+    /// edge-triggered, nothing is raised again for as long as it stays the same.
+    fn update_readiness(&mut self) {
+        let ready = self.current_readiness();
+        if ready != self.last_readiness {
+            self.last_readiness = ready;
+            let _ = self.readiness.set_readiness(ready);
+        }
+    }
+}
+
+/// A uTP connection, read and written like a non-blocking `TcpStream`. Dropping it sends what
+/// was written before it and a FIN, as far as the peer acknowledges them in time.
+pub struct UtpStream {
+    conn: Rc<RefCell<Conn>>,
+    socket: Rc<UdpSocket>,
+    registration: Registration,
+    /// Tells the time of the event loop the stream was made on.
+    clock: WallClock,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.conn.borrow().peer)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The error the connection failed with, such as the peer resetting it or not acknowledging
+    /// our packets. It is kept rather than taken, as reads and writes keep failing with it.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(self.conn.borrow().error.map(io::Error::from))
+    }
+
+    /// Sends a FIN once what was written has gone out. Writes fail from now on.
+    pub fn shutdown(&self) -> io::Result<()> {
+        let mut conn = self.conn.borrow_mut();
+        conn.closing = true;
+        conn.flush(&*self.socket, self.clock.now());
+        conn.update_readiness();
+        Ok(())
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.conn.borrow_mut();
+        if let Some(kind) = conn.error {
+            return Err(io::Error::from(kind));
+        }
+        if conn.recv_buf.is_empty() {
+            if conn.eof {
+                return Ok(0);
+            }
+            conn.update_readiness();
+            return Err(io::Error::from(ErrorKind::WouldBlock));
+        }
+        let len = cmp::min(buf.len(), conn.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(conn.recv_buf.drain(..len)) {
+            *dst = src;
+        }
+        conn.update_readiness();
+        Ok(len)
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.borrow_mut();
+        if let Some(kind) = conn.error {
+            return Err(io::Error::from(kind));
+        }
+        if conn.closing {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        let room = SEND_BUFFER.saturating_sub(conn.send_buf.len());
+        if conn.state != ConnState::Connected || room == 0 {
+            conn.update_readiness();
+            return Err(io::Error::from(ErrorKind::WouldBlock));
+        }
+        let len = cmp::min(room, buf.len());
+        conn.send_buf.extend(&buf[..len]);
+        conn.flush(&*self.socket, self.clock.now());
+        conn.update_readiness();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for UtpStream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        poll.register(&self.registration, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        poll.reregister(&self.registration, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+        self.conn.borrow_mut().stream_dropped = true;
+    }
+}
+
+/// Called with each connection a listening `UtpEndpoint` accepts.
+pub type UtpAcceptHandler = Box<FnMut(&mut Core, &Poll, UtpStream)>;
+
+/// A UDP socket carrying uTP connections. One bound by `connect` carries that one connection
+/// only, and goes once it is closed. One bound by `listen` accepts connections until
+/// `stop_listening`, and goes once they are closed too.
+pub struct UtpEndpoint {
+    token: Token,
+    socket: Rc<UdpSocket>,
+    /// The connections by peer and the connection id the peer sends packets of it with.
+    conns: HashMap<(SocketAddr, u16), Rc<RefCell<Conn>>>,
+    /// Incoming connections not taken up yet, by the same key.
+    half_open: HashMap<(SocketAddr, u16), HalfOpen>,
+    accept: Option<UtpAcceptHandler>,
+    /// That of the service, which the packets held for reordering are charged to.
+    budget: MemoryBudget,
+}
+
+impl UtpEndpoint {
+    /// Connects to `addr` from a socket of its own, bound to `bind_ip` if given, see
+    /// `bind_ip_for`. The stream becomes writable once the peer answered, or fails with an error
+    /// if it doesn't in time, like a `TcpStream` connecting.
+    pub fn connect(
+        core: &mut Core,
+        poll: &Poll,
+        addr: &SocketAddr,
+        bind_ip: Option<IpAddr>,
+    ) -> Result<UtpStream> {
+        let bind_ip = bind_ip_for(bind_ip, addr).unwrap_or_else(|| match *addr {
+            SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        });
+        let socket = UdpSocket::bind(&SocketAddr::new(bind_ip, 0))?;
+        let endpoint = Self::start(core, poll, socket, None)?;
+        let mut endpoint = endpoint.borrow_mut();
+
+        let (registration, readiness) = Registration::new2();
+        let mut conn = Conn::new(
+            ConnState::SynSent,
+            *addr,
+            rand::random(),
+            readiness,
+            endpoint.budget.clone(),
+        );
+        conn.send_new(&*endpoint.socket, ST_SYN, Vec::new(), core.now());
+        let conn = Rc::new(RefCell::new(conn));
+        let recv_id = conn.borrow().recv_id;
+        let _ = endpoint.conns.insert((*addr, recv_id), conn.clone());
+
+        Ok(UtpStream {
+            conn,
+            socket: endpoint.socket.clone(),
+            registration,
+            clock: core.wall_clock(),
+        })
+    }
+
+    /// Accepts connections at `addr`, handing each to `accept` once the peer acknowledged our
+    /// answer to its SYN. Returns the token of the endpoint.
+    pub fn listen(
+        core: &mut Core,
+        poll: &Poll,
+        addr: &SocketAddr,
+        accept: UtpAcceptHandler,
+    ) -> Result<Token> {
+        let socket = UdpSocket::bind(addr)?;
+        let endpoint = Self::start(core, poll, socket, Some(accept))?;
+        let token = endpoint.borrow().token;
+        Ok(token)
+    }
+
+    /// Has the endpoint of `token` refuse connections from now on, and go once those it accepted
+    /// are closed. Does nothing if it is gone, or busy calling whoever calls this.
+    pub fn stop_listening(core: &mut Core, poll: &Poll, token: Token) {
+        let state = match core.get_state(token) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = match state.try_borrow_mut() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if let Some(endpoint) = state.as_any().downcast_mut::<UtpEndpoint>() {
+            endpoint.accept = None;
+            endpoint.half_open.clear();
+            endpoint.reap(core, poll);
+        }
+    }
+
+    fn start(
+        core: &mut Core,
+        poll: &Poll,
+        socket: UdpSocket,
+        accept: Option<UtpAcceptHandler>,
+    ) -> Result<Rc<RefCell<Self>>> {
+        let token = core.get_new_token();
+        poll.register(&socket, token, Ready::readable(), PollOpt::edge())?;
+        if let Err(e) = core.set_timeout(
+            Duration::from_millis(TICK_MS),
+            CoreTimer::new(token, TICK_TIMER_ID),
+        ) {
+            let _ = poll.deregister(&socket);
+            return Err(e);
+        }
+
+        let state = Rc::new(RefCell::new(UtpEndpoint {
+            token,
+            socket: Rc::new(socket),
+            conns: HashMap::new(),
+            half_open: HashMap::new(),
+            accept,
+            budget: core.memory_budget().clone(),
+        }));
+        let _ = core.insert_state(token, state.clone());
+        Ok(state)
+    }
+
+    fn receive(&mut self, core: &mut Core, poll: &Poll, packet: &[u8], from: SocketAddr) {
+        let (header, payload) = match Header::decode(packet) {
+            Some(decoded) => decoded,
+            None => {
+                trace!("Dropping malformed uTP packet from {}", from);
+                return;
+            }
+        };
+        let key = if header.kind == ST_SYN {
+            (from, header.conn_id.wrapping_add(1))
+        } else {
+            (from, header.conn_id)
+        };
+        if let Some(conn) = self.conns.get(&key) {
+            return conn
+                .borrow_mut()
+                .receive(&*self.socket, &header, payload, core.now());
+        }
+        if self.accept.is_some() {
+            if header.kind == ST_SYN {
+                return self.answer_syn(key, &header, core.now());
+            }
+            if let Some(half) = self.half_open.remove(&key) {
+                return self.take_up(core, poll, key, half, &header, payload);
+            }
+        }
+
+        match header.kind {
+            ST_RESET => (),
+            _ => {
+                // Tells the peer there is no such connection, e.g. after we restarted.
+                let reset = Header {
+                    kind: ST_RESET,
+                    conn_id: header.conn_id,
+                    timestamp_us: now_us(),
+                    timestamp_diff_us: 0,
+                    wnd_size: 0,
+                    seq_nr: rand::random(),
+                    ack_nr: header.seq_nr,
+                };
+                let _ = self.socket.send_to(&reset.encode(&[]), &from);
+            }
+        }
+    }
+
+    /// Answers a SYN, again if our answer was lost, without taking the connection up yet.
+    fn answer_syn(&mut self, key: (SocketAddr, u16), syn: &Header, now: Instant) {
+        if !self.half_open.contains_key(&key) {
+            if self.half_open.len() >= MAX_HALF_OPEN {
+                trace!("Dropping uTP SYN from {}: too many connections half open", key.0);
+                return;
+            }
+            let half = HalfOpen {
+                syn: *syn,
+                seq_nr: rand::random(),
+                since: now,
+            };
+            let _ = self.half_open.insert(key, half);
+        }
+        let answer = Header {
+            kind: ST_STATE,
+            conn_id: syn.conn_id,
+            timestamp_us: now_us(),
+            timestamp_diff_us: now_us().wrapping_sub(syn.timestamp_us),
+            wnd_size: RECV_WINDOW as u32,
+            seq_nr: self.half_open[&key].seq_nr,
+            ack_nr: syn.seq_nr,
+        };
+        let _ = self.socket.send_to(&answer.encode(&[]), &key.0);
+    }
+
+    /// Hands the connection to the application if the packet acknowledges our answer to its SYN,
+    /// and has it receive the packet.
+    fn take_up(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        key: (SocketAddr, u16),
+        half: HalfOpen,
+        header: &Header,
+        payload: &[u8],
+    ) {
+        if header.kind == ST_RESET {
+            return;
+        }
+        if header.ack_nr != half.seq_nr.wrapping_sub(1) {
+            trace!("Dropping uTP packet from {} not acking our answer to its SYN", key.0);
+            let _ = self.half_open.insert(key, half);
+            return;
+        }
+        let (registration, readiness) = Registration::new2();
+        let mut conn = Conn::accepted(key.0, &half, readiness, self.budget.clone());
+        conn.receive(&*self.socket, header, payload, core.now());
+        let conn = Rc::new(RefCell::new(conn));
+        let _ = self.conns.insert(key, conn.clone());
+        let stream = UtpStream {
+            conn,
+            socket: self.socket.clone(),
+            registration,
+            clock: core.wall_clock(),
+        };
+        if let Some(accept) = self.accept.as_mut() {
+            accept(core, poll, stream);
+        }
+    }
+
+    /// Forgets the connections which are finished, and goes once there are none left and no more
+    /// are accepted.
+    fn reap(&mut self, core: &mut Core, poll: &Poll) {
+        self.conns.retain(|_, conn| !conn.borrow().is_finished());
+        if self.accept.is_none() && self.conns.is_empty() {
+            self.terminate(core, poll);
+        }
+    }
+}
+
+impl State for UtpEndpoint {
+    fn name(&self) -> &'static str {
+        "UtpEndpoint"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if !kind.is_readable() {
+            return;
+        }
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok(Some((len, from))) => self.receive(core, poll, &buffer[..len], from),
+                Ok(None) => break,
+                Err(e) => match IoErrorClass::of(&e) {
+                    IoErrorClass::Retry => (),
+                    IoErrorClass::WouldBlock => break,
+                    // E.g. an ICMP error for an earlier packet. The next packet received makes
+                    // the socket readable again.
+                    IoErrorClass::RemoteClosed | IoErrorClass::Fatal => {
+                        trace!("Failed to receive uTP packet: {:?}", e);
+                        break;
+                    }
+                },
+            }
+        }
+        self.reap(core, poll);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        let now = core.now();
+        for conn in self.conns.values() {
+            conn.borrow_mut().tick(&*self.socket, now);
+        }
+        let timeout = Duration::from_millis(HALF_OPEN_TIMEOUT_MS);
+        self.half_open
+            .retain(|_, half| now.duration_since(half.since) < timeout);
+        self.reap(core, poll);
+        if !core.has_state(self.token) {
+            return;
+        }
+        let timer = CoreTimer::new(self.token, TICK_TIMER_ID);
+        if let Err(e) = core.set_timeout(Duration::from_millis(TICK_MS), timer) {
+            debug!("uTP endpoint timer errored out: {:?}", e);
+            self.terminate(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.cancel_timeout(self.token, TICK_TIMER_ID);
+        let _ = poll.deregister(&*self.socket);
+        let _ = core.remove_state(self.token);
+        for conn in self.conns.values() {
+            conn.borrow_mut().fail(ErrorKind::ConnectionAborted);
+        }
+        self.conns.clear();
+        self.half_open.clear();
+        self.accept = None;
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoreMessage, ManualEventLoop};
+    use std::net;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            kind: ST_DATA,
+            conn_id: 0xbeef,
+            timestamp_us: 0x0102_0304,
+            timestamp_diff_us: 0x0506_0708,
+            wnd_size: 0x0009_0a0b,
+            seq_nr: 0xfffe,
+            ack_nr: 7,
+        };
+        let packet = header.encode(b"payload");
+        assert_eq!(packet.len(), HEADER_SIZE + 7);
+        assert_eq!(packet[0], 0x01);
+        assert_eq!(unwrap!(Header::decode(&packet)), (header, &b"payload"[..]));
+
+        // Extensions are skipped.
+        let mut extended = packet[..HEADER_SIZE].to_vec();
+        extended[1] = 2;
+        extended.extend_from_slice(&[0, 8, 1, 2, 3, 4, 5, 6, 7, 8]);
+        extended.extend_from_slice(b"payload");
+        assert_eq!(unwrap!(Header::decode(&extended)), (header, &b"payload"[..]));
+
+        assert!(Header::decode(&packet[..HEADER_SIZE - 1]).is_none());
+        assert!(Header::decode(&extended[..HEADER_SIZE + 5]).is_none());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        assert!(seq_le(1, 1));
+        assert!(seq_le(1, 2));
+        assert!(!seq_le(2, 1));
+        assert!(seq_le(0xffff, 0));
+        assert!(!seq_le(0, 0xffff));
+    }
+
+    #[derive(Default)]
+    struct Received {
+        bytes: Vec<u8>,
+        eof: bool,
+    }
+
+    /// Drives a stream from the event loop: writes `outgoing` and shuts the stream down, and
+    /// collects what it reads until end of file.
+    struct Pump {
+        token: Token,
+        stream: UtpStream,
+        outgoing: Vec<u8>,
+        written: usize,
+        received: Arc<Mutex<Received>>,
+    }
+
+    impl Pump {
+        fn start(
+            core: &mut Core,
+            poll: &Poll,
+            stream: UtpStream,
+            outgoing: Vec<u8>,
+            received: Arc<Mutex<Received>>,
+        ) {
+            let token = core.get_new_token();
+            let interest = Ready::readable() | Ready::writable();
+            unwrap!(poll.register(&stream, token, interest, PollOpt::edge()));
+            let pump = Pump {
+                token,
+                stream,
+                outgoing,
+                written: 0,
+                received,
+            };
+            let _ = core.insert_state(token, Rc::new(RefCell::new(pump)));
+        }
+    }
+
+    impl State for Pump {
+        fn ready(&mut self, _core: &mut Core, _poll: &Poll, kind: Ready) {
+            if kind.is_writable() && self.written < self.outgoing.len() {
+                while self.written < self.outgoing.len() {
+                    match self.stream.write(&self.outgoing[self.written..]) {
+                        Ok(len) => self.written += len,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("Failed to write: {:?}", e),
+                    }
+                }
+                if self.written == self.outgoing.len() {
+                    unwrap!(self.stream.shutdown());
+                }
+            }
+            if kind.is_readable() {
+                let mut buffer = [0; 4096];
+                let mut received = unwrap!(self.received.lock());
+                loop {
+                    match self.stream.read(&mut buffer) {
+                        Ok(0) => {
+                            received.eof = true;
+                            break;
+                        }
+                        Ok(len) => received.bytes.extend_from_slice(&buffer[..len]),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("Failed to read: {:?}", e),
+                    }
+                }
+            }
+        }
+
+        fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+            let _ = poll.deregister(&self.stream);
+            let _ = core.remove_state(self.token);
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    #[test]
+    fn streams_carry_data_both_ways() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        // More than fits a packet, or the initial window.
+        let sent: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let at_server = Arc::new(Mutex::new(Received::default()));
+        let at_client = Arc::new(Mutex::new(Received::default()));
+        {
+            let sent = sent.clone();
+            let at_server = at_server.clone();
+            let at_client = at_client.clone();
+            unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+                let on_accept = move |core: &mut Core, poll: &Poll, stream| {
+                    Pump::start(core, poll, stream, b"reply".to_vec(), at_server.clone())
+                };
+                let listen_addr = unwrap!("127.0.0.1:0".parse());
+                let token = unwrap!(UtpEndpoint::listen(
+                    core,
+                    poll,
+                    &listen_addr,
+                    Box::new(on_accept)
+                ));
+                let addr = {
+                    let state = unwrap!(core.get_state(token));
+                    let mut state = state.borrow_mut();
+                    let endpoint = unwrap!(state.as_any().downcast_mut::<UtpEndpoint>());
+                    unwrap!(endpoint.socket.local_addr())
+                };
+                let stream = unwrap!(UtpEndpoint::connect(core, poll, &addr, None));
+                assert_eq!(unwrap!(stream.peer_addr()), addr);
+                Pump::start(core, poll, stream, sent, at_client);
+            })));
+        }
+
+        for _ in 0..500 {
+            if unwrap!(at_server.lock()).eof && unwrap!(at_client.lock()).eof {
+                break;
+            }
+            assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        }
+        let at_server = unwrap!(at_server.lock());
+        let at_client = unwrap!(at_client.lock());
+        assert!(at_server.eof && at_client.eof);
+        assert!(at_server.bytes == sent);
+        assert_eq!(at_client.bytes, b"reply");
+    }
+
+    #[test]
+    fn writes_block_until_the_peer_answers() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        // Receives our SYN, but never answers it.
+        let silent = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        let silent_addr = unwrap!(silent.local_addr());
+        let (stream_tx, stream_rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+            let stream = unwrap!(UtpEndpoint::connect(core, poll, &silent_addr, None));
+            let _ = stream_tx.send(stream);
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        let mut stream = unwrap!(stream_rx.try_recv());
+
+        let mut buffer = [0; 64];
+        let (len, _) = unwrap!(silent.recv_from(&mut buffer));
+        let (syn, _) = unwrap!(Header::decode(&buffer[..len]));
+        assert_eq!(syn.kind, ST_SYN);
+        assert_eq!(syn.seq_nr, 1);
+
+        match stream.write(b"early") {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(unwrap!(stream.take_error()).is_none());
+    }
+
+    #[test]
+    fn syns_are_taken_up_once_our_answer_is_acknowledged() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        let accepted = Arc::new(Mutex::new(0));
+        let (addr_tx, addr_rx) = mpsc::channel();
+        {
+            let accepted = accepted.clone();
+            unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+                let on_accept = move |_: &mut Core, _: &Poll, _| *unwrap!(accepted.lock()) += 1;
+                let listen_addr = unwrap!("127.0.0.1:0".parse());
+                let token = unwrap!(UtpEndpoint::listen(
+                    core,
+                    poll,
+                    &listen_addr,
+                    Box::new(on_accept)
+                ));
+                let state = unwrap!(core.get_state(token));
+                let mut state = state.borrow_mut();
+                let endpoint = unwrap!(state.as_any().downcast_mut::<UtpEndpoint>());
+                let _ = addr_tx.send((token, unwrap!(endpoint.socket.local_addr())));
+            })));
+        }
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        let (token, addr) = unwrap!(addr_rx.try_recv());
+
+        // As from forged addresses, which never see our answers.
+        let peer = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        let syn = |conn_id| Header {
+            kind: ST_SYN,
+            conn_id,
+            timestamp_us: now_us(),
+            timestamp_diff_us: 0,
+            wnd_size: RECV_WINDOW as u32,
+            seq_nr: 1,
+            ack_nr: 0,
+        };
+        for conn_id in 0..MAX_HALF_OPEN as u16 + 10 {
+            let _ = unwrap!(peer.send_to(&syn(conn_id * 2).encode(&[]), addr));
+        }
+        for _ in 0..10 {
+            let _ = unwrap!(el.run_once(Duration::from_millis(10)));
+        }
+        assert_eq!(*unwrap!(accepted.lock()), 0);
+        let (half_open_tx, half_open_rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(token));
+            let mut state = state.borrow_mut();
+            let endpoint = unwrap!(state.as_any().downcast_mut::<UtpEndpoint>());
+            let _ = half_open_tx.send(endpoint.half_open.len());
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(unwrap!(half_open_rx.try_recv()), MAX_HALF_OPEN);
+
+        // A packet not acking the answer is dropped, one acking it takes the connection up.
+        let mut buffer = [0; 64];
+        let (len, _) = unwrap!(peer.recv_from(&mut buffer));
+        let (answer, _) = unwrap!(Header::decode(&buffer[..len]));
+        assert_eq!(answer.kind, ST_STATE);
+        let ack = |ack_nr| Header {
+            kind: ST_STATE,
+            conn_id: answer.conn_id.wrapping_add(1),
+            seq_nr: 2,
+            ack_nr,
+            ..syn(answer.conn_id)
+        };
+        let forged = ack(answer.seq_nr.wrapping_add(1000));
+        let _ = unwrap!(peer.send_to(&forged.encode(&[]), addr));
+        let _ = unwrap!(el.run_once(Duration::from_millis(10)));
+        assert_eq!(*unwrap!(accepted.lock()), 0);
+        let _ = unwrap!(peer.send_to(&ack(answer.seq_nr.wrapping_sub(1)).encode(&[]), addr));
+        for _ in 0..10 {
+            if *unwrap!(accepted.lock()) == 1 {
+                break;
+            }
+            let _ = unwrap!(el.run_once(Duration::from_millis(10)));
+        }
+        assert_eq!(*unwrap!(accepted.lock()), 1);
+    }
+
+    /// Packets sent to one end of a connection, which the test delivers, loses or reorders.
+    #[derive(Default)]
+    struct Wire {
+        packets: RefCell<VecDeque<Vec<u8>>>,
+    }
+
+    impl Wire {
+        fn take(&self) -> Vec<(Header, Vec<u8>)> {
+            self.packets
+                .borrow_mut()
+                .drain(..)
+                .map(|packet| {
+                    let (header, payload) = unwrap!(Header::decode(&packet));
+                    (header, payload.to_vec())
+                })
+                .collect()
+        }
+    }
+
+    impl Transmit for Wire {
+        fn transmit(&self, packet: &[u8], _to: &SocketAddr) -> io::Result<Option<usize>> {
+            self.packets.borrow_mut().push_back(packet.to_vec());
+            Ok(Some(packet.len()))
+        }
+    }
+
+    /// Both ends of a connection, wired to each other and driven by a clock of the test's own.
+    struct Pair {
+        client: Conn,
+        server: Conn,
+        to_client: Wire,
+        to_server: Wire,
+        now: Instant,
+        _registrations: (Registration, Registration),
+    }
+
+    impl Pair {
+        fn connected() -> Self {
+            let addr = unwrap!("127.0.0.1:5483".parse());
+            let budget = MemoryBudget::unlimited();
+            let now = Instant::now();
+            let (client_registration, readiness) = Registration::new2();
+            let mut client = Conn::new(ConnState::SynSent, addr, 7, readiness, budget.clone());
+            let to_client = Wire::default();
+            let to_server = Wire::default();
+            client.send_new(&to_server, ST_SYN, Vec::new(), now);
+            let (syn, _) = unwrap!(to_server.take().pop());
+            let half = HalfOpen {
+                syn,
+                seq_nr: 0xfff0,
+                since: now,
+            };
+            let (server_registration, readiness) = Registration::new2();
+            let server = Conn::accepted(addr, &half, readiness, budget);
+            let answer = Header {
+                kind: ST_STATE,
+                conn_id: syn.conn_id,
+                timestamp_us: now_us(),
+                timestamp_diff_us: 0,
+                wnd_size: RECV_WINDOW as u32,
+                seq_nr: half.seq_nr,
+                ack_nr: syn.seq_nr,
+            };
+            client.receive(&to_server, &answer, &[], now);
+            assert_eq!(client.state, ConnState::Connected);
+
+            let mut pair = Pair {
+                client,
+                server,
+                to_client,
+                to_server,
+                now,
+                _registrations: (client_registration, server_registration),
+            };
+            pair.deliver_to_server(|packets| packets);
+            pair
+        }
+
+        /// Hands the server what was sent to it, as `wire` leaves it.
+        fn deliver_to_server<F>(&mut self, wire: F)
+        where
+            F: FnOnce(Vec<(Header, Vec<u8>)>) -> Vec<(Header, Vec<u8>)>,
+        {
+            for (header, payload) in wire(self.to_server.take()) {
+                self.server
+                    .receive(&self.to_client, &header, &payload, self.now);
+            }
+        }
+
+        fn deliver_to_client(&mut self) {
+            for (header, payload) in self.to_client.take() {
+                self.client
+                    .receive(&self.to_server, &header, &payload, self.now);
+            }
+        }
+
+        /// Lets enough time pass for what the client has in flight to be sent again.
+        fn time_out(&mut self) {
+            self.now += Duration::from_millis(MAX_RTO_MS);
+            self.client.tick(&self.to_server, self.now);
+        }
+
+        /// Sends `data` from the client, the wire to the server treating each round of packets
+        /// with `wire`, and returns what the server received.
+        fn send<F>(&mut self, data: &[u8], mut wire: F) -> Vec<u8>
+        where
+            F: FnMut(Vec<(Header, Vec<u8>)>) -> Vec<(Header, Vec<u8>)>,
+        {
+            self.client.send_buf.extend(data);
+            self.client.flush(&self.to_server, self.now);
+            for _ in 0..1000 {
+                self.deliver_to_server(&mut wire);
+                self.deliver_to_client();
+                if self.client.send_buf.is_empty() && self.client.in_flight.is_empty() {
+                    break;
+                }
+                self.time_out();
+            }
+            assert!(self.client.error.is_none());
+            self.server.recv_buf.drain(..).collect()
+        }
+    }
+
+    fn data() -> Vec<u8> {
+        (0..50_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn lost_packets_are_sent_again() {
+        let mut pair = Pair::connected();
+        let data = data();
+        let mut lost = Vec::new();
+        let received = pair.send(&data, |packets| {
+            packets
+                .into_iter()
+                .filter(|&(ref header, _)| {
+                    // Each fifth packet is lost the first time it is sent.
+                    if header.seq_nr % 5 != 0 || lost.contains(&header.seq_nr) {
+                        return true;
+                    }
+                    lost.push(header.seq_nr);
+                    false
+                })
+                .collect()
+        });
+        assert!(!lost.is_empty());
+        assert!(received == data);
+        assert!(pair.server.reordered.is_empty());
+        assert_eq!(pair.server.reordered_bytes, 0);
+    }
+
+    #[test]
+    fn reordered_packets_are_read_in_order() {
+        let mut pair = Pair::connected();
+        let data = data();
+        let received = pair.send(&data, |mut packets| {
+            packets.reverse();
+            packets
+        });
+        assert!(received == data);
+        assert!(pair.server.reordered.is_empty());
+        assert_eq!(pair.server.reordered_bytes, 0);
+    }
+
+    #[test]
+    fn packets_are_sent_again_until_given_up_on() {
+        let mut pair = Pair::connected();
+        pair.client.send_buf.extend(b"lost");
+        pair.client.flush(&pair.to_server, pair.now);
+        let mut rto = pair.client.rto;
+        for transmissions in 1..MAX_TRANSMISSIONS {
+            let sent = pair.to_server.take();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].1, b"lost");
+            assert_eq!(pair.client.in_flight[0].transmissions, transmissions);
+
+            // Not before its time is up.
+            pair.client.tick(&pair.to_server, pair.now);
+            assert!(pair.to_server.take().is_empty());
+            pair.time_out();
+            assert_eq!(pair.client.rto, cmp::min(rto * 2, Duration::from_millis(MAX_RTO_MS)));
+            rto = pair.client.rto;
+        }
+        assert!(pair.client.error.is_none());
+        let _ = pair.to_server.take();
+        pair.time_out();
+        assert_eq!(pair.client.error, Some(ErrorKind::TimedOut));
+        assert!(pair.to_server.take().is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Watchdogs over the event loop, flagging iterations which take too long to handle their events.
+
+#[cfg(feature = "stall-watchdog")]
+use common::flight_recorder::DumpWriter;
+#[cfg(feature = "stall-watchdog")]
+use maidsafe_utilities::thread::{self, Joiner};
+#[cfg(feature = "stall-watchdog")]
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "stall-watchdog")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two lagging iterations being reported. Lagging iterations are counted
+/// regardless.
+const LAG_REPORT_COOL_DOWN_SECS: u64 = 10;
+/// Shortest time an iteration may run before the stall watchdog writes the flight record. Shorter
+/// ones are taken as this, so that the watchdog doesn't spin.
+#[cfg(feature = "stall-watchdog")]
+const MIN_STALL_DUMP_MS: u64 = 100;
+
+/// Times the iterations of the event loop, in real time, and reports those which take longer than
+/// a threshold, along with the kind of state which took the longest.
+pub struct LagWatchdog {
+    warn_after: Duration,
+    on_lag: Box<FnMut(Duration)>,
+    iteration_started: Option<Instant>,
+    /// `State::name` and dispatch time of the slowest state of the current iteration.
+    slowest: Option<(&'static str, Duration)>,
+    last_reported: Option<Instant>,
+}
+
+impl LagWatchdog {
+    /// Calls `on_lag` with the duration of the iterations taking longer than `warn_after`.
+    pub fn new(warn_after: Duration, on_lag: Box<FnMut(Duration)>) -> Self {
+        LagWatchdog {
+            warn_after,
+            on_lag,
+            iteration_started: None,
+            slowest: None,
+            last_reported: None,
+        }
+    }
+
+    /// Starts timing an iteration, once it has been woken up with events to handle.
+    pub fn start_iteration(&mut self, now: Instant) {
+        self.iteration_started = Some(now);
+        self.slowest = None;
+    }
+
+    /// Takes note of the time a state of the given kind took to handle an event.
+    pub fn dispatched(&mut self, name: &'static str, duration: Duration) {
+        if self.slowest.map_or(true, |(_, slowest)| duration > slowest) {
+            self.slowest = Some((name, duration));
+        }
+    }
+
+    /// Ends the iteration started last. Returns whether it was lagging.
+    pub fn end_iteration(&mut self, now: Instant) -> bool {
+        let duration = match self.iteration_started.take() {
+            Some(started) => now - started,
+            None => return false,
+        };
+        if duration <= self.warn_after {
+            return false;
+        }
+        let cool_down = Duration::from_secs(LAG_REPORT_COOL_DOWN_SECS);
+        if self
+            .last_reported
+            .map_or(false, |reported| now - reported < cool_down)
+        {
+            return true;
+        }
+        self.last_reported = Some(now);
+        match self.slowest {
+            Some((name, slowest)) => warn!(
+                "Event loop iteration took {:?}, of which {:?} in a {} state",
+                duration, slowest, name
+            ),
+            None => warn!("Event loop iteration took {:?}", duration),
+        }
+        (self.on_lag)(duration);
+        true
+    }
+}
+
+/// Writes the flight record from a thread of its own if an iteration of the event loop doesn't
+/// finish in time, to capture what led to the loop getting stuck.
+#[cfg(feature = "stall-watchdog")]
+pub struct StallWatchdog {
+    heartbeat: Arc<Heartbeat>,
+    _joiner: Joiner,
+}
+
+#[cfg(feature = "stall-watchdog")]
+#[derive(Default)]
+struct Heartbeat {
+    /// Number of iterations started so far.
+    iteration: AtomicUsize,
+    /// Whether the event loop is handling events rather than waiting for them.
+    busy: AtomicBool,
+    stopped: AtomicBool,
+}
+
+#[cfg(feature = "stall-watchdog")]
+impl StallWatchdog {
+    /// Writes the record with `dump_writer` once an iteration has been running for `dump_after`,
+    /// or `MIN_STALL_DUMP_MS` if that is shorter.
+    pub fn new(dump_after: Duration, dump_writer: DumpWriter) -> Self {
+        let dump_after = ::std::cmp::max(dump_after, Duration::from_millis(MIN_STALL_DUMP_MS));
+        let heartbeat = Arc::new(Heartbeat::default());
+        let watched = heartbeat.clone();
+        let joiner = thread::named("CRUST-Stall-Watchdog", move || {
+            watch(&watched, dump_after, &dump_writer)
+        });
+        StallWatchdog {
+            heartbeat,
+            _joiner: joiner,
+        }
+    }
+
+    pub fn start_iteration(&self) {
+        let _ = self.heartbeat.iteration.fetch_add(1, Ordering::SeqCst);
+        self.heartbeat.busy.store(true, Ordering::SeqCst);
+    }
+
+    pub fn end_iteration(&self) {
+        self.heartbeat.busy.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "stall-watchdog")]
+impl Drop for StallWatchdog {
+    fn drop(&mut self) {
+        self.heartbeat.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "stall-watchdog")]
+fn watch(heartbeat: &Heartbeat, dump_after: Duration, dump_writer: &DumpWriter) {
+    let check_interval = ::std::cmp::min(dump_after / 4, Duration::from_secs(1));
+    // The iteration which has been running since the given time, and whether it was dumped.
+    let mut watched: Option<(usize, Instant, bool)> = None;
+    while !heartbeat.stopped.load(Ordering::SeqCst) {
+        ::std::thread::sleep(check_interval);
+        if !heartbeat.busy.load(Ordering::SeqCst) {
+            watched = None;
+            continue;
+        }
+        let iteration = heartbeat.iteration.load(Ordering::SeqCst);
+        let (since, dumped) = match watched {
+            Some((watched_iteration, since, dumped)) if watched_iteration == iteration => {
+                (since, dumped)
+            }
+            _ => (Instant::now(), false),
+        };
+        let dump = !dumped && since.elapsed() >= dump_after;
+        if dump {
+            error!(
+                "Event loop stuck in an iteration for over {:?}; dumping flight record",
+                dump_after
+            );
+            dump_writer.write_dump();
+        }
+        watched = Some((iteration, since, dumped || dump));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn lag_is_reported_at_most_once_per_cool_down() {
+        let reported = Rc::new(RefCell::new(Vec::new()));
+        let reported_clone = reported.clone();
+        let mut watchdog = LagWatchdog::new(
+            ms(100),
+            Box::new(move |duration| reported_clone.borrow_mut().push(duration)),
+        );
+        let start = Instant::now();
+
+        watchdog.start_iteration(start);
+        watchdog.dispatched("Fast", ms(1));
+        assert!(!watchdog.end_iteration(start + ms(50)));
+
+        watchdog.start_iteration(start + ms(50));
+        watchdog.dispatched("Slow", ms(150));
+        assert!(watchdog.end_iteration(start + ms(250)));
+        assert_eq!(*reported.borrow(), vec![ms(200)]);
+
+        // Still counted as lagging, but not reported again so soon.
+        watchdog.start_iteration(start + ms(250));
+        assert!(watchdog.end_iteration(start + ms(500)));
+        assert_eq!(reported.borrow().len(), 1);
+
+        let later = start + ms(250) + Duration::from_secs(LAG_REPORT_COOL_DOWN_SECS);
+        watchdog.start_iteration(later);
+        assert!(watchdog.end_iteration(later + ms(300)));
+        assert_eq!(*reported.borrow(), vec![ms(200), ms(300)]);
+    }
+}
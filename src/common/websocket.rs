@@ -0,0 +1,1166 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! WebSocket, RFC 6455, for peers which can't open raw TCP connections, such as clients running
+//! in a browser. The bytes a `Socket` reads and writes are carried in binary messages, so Crust's
+//! frames, handshake and messages go over it just as they go over TCP; where the WebSocket frames
+//! start and end doesn't matter to them. Only what Crust needs is implemented: no extensions or
+//! subprotocols are negotiated, and text messages are refused.
+//!
+//! A `WsEndpoint` accepts WebSocket connections on a TCP port of its own, and dials them. Either
+//! way the HTTP opening handshake is over before the `WsStream` is handed on, which is then read
+//! and written like a non-blocking `TcpStream` and registered with the poll the same way, so that
+//! a `Socket` can be made of either.
+
+use base64;
+use byteorder::{BigEndian, ByteOrder};
+use common::{
+    connect_tcp_from, ConnectionDirection, Core, CoreTimer, HandshakeStage, IoErrorClass, Result,
+    State,
+};
+use mio::tcp::{TcpListener, TcpStream};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use rand;
+use sha1::Sha1;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+/// Appended to the key of the client before it is hashed into the answer of the server.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Longest head of an opening handshake request or response we read.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const UPGRADE_TIMEOUT_SECS: u64 = 10;
+/// Most opening handshakes of accepted connections in progress at once. Connections accepted
+/// beyond it are dropped.
+const MAX_UPGRADES: usize = 64;
+const UPGRADE_TIMER_ID: u64 = 0;
+/// Most bytes of payload written in one frame.
+const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const REFUSAL: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// Our side of a connection. Only the frames of the client are masked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server,
+}
+
+/// A WebSocket connection whose opening handshake is over, read and written like a non-blocking
+/// `TcpStream`. Each write goes out in a binary frame of its own, and reads return the payloads
+/// of the binary frames received, answering pings and taking a close frame for the end of file.
+pub struct WsStream {
+    stream: TcpStream,
+    role: Role,
+    /// The header of the frame being read, followed by the payload if it is a control frame.
+    incoming: Vec<u8>,
+    /// The payload of the data frame being read.
+    reading: Option<Payload>,
+    /// What is left to write of the header of the data frame being written.
+    header: Vec<u8>,
+    /// The payload of the data frame being written.
+    writing: Option<Payload>,
+    /// Control frames to write once no data frame is being written.
+    control: Vec<u8>,
+    /// Whether the peer sent a close frame.
+    peer_closed: bool,
+}
+
+impl WsStream {
+    fn new(stream: TcpStream, role: Role) -> Self {
+        WsStream {
+            stream,
+            role,
+            incoming: Vec::new(),
+            reading: None,
+            header: Vec::new(),
+            writing: None,
+            control: Vec::new(),
+            peer_closed: false,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.stream.take_error()
+    }
+
+    /// Sends the control frames waiting and a close frame, unless it would cut a data frame
+    /// short, and shuts the TCP connection down.
+    pub fn shutdown(&self) -> io::Result<()> {
+        if self.writing.is_none() {
+            let mut frames = self.control.clone();
+            if !self.peer_closed {
+                // Otherwise ours answers the peer's, and is among those waiting.
+                frames.extend_from_slice(&control_frame(self.role, OP_CLOSE, &[]));
+            }
+            let _ = (&self.stream).write(&frames);
+        }
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    /// Reads up to the payload of the next data frame, answering the control frames before it.
+    /// Returns whether there is one, `false` once the peer closed the connection.
+    fn read_frame_header(&mut self) -> io::Result<bool> {
+        loop {
+            if self.peer_closed {
+                return Ok(false);
+            }
+            let mut needed = header_size(&self.incoming);
+            if self.incoming.len() >= needed {
+                let header = FrameHeader::decode(&self.incoming)?;
+                if header.mask.is_some() != (self.role == Role::Server) {
+                    return Err(invalid_data(
+                        "frame masked by the server or not by the client",
+                    ));
+                }
+                if header.opcode & 0x8 == 0 {
+                    if header.opcode == OP_TEXT {
+                        return Err(invalid_data("text messages aren't supported"));
+                    }
+                    self.incoming.clear();
+                    if header.len > 0 {
+                        self.reading = Some(Payload::new(header.len, header.mask));
+                        return Ok(true);
+                    }
+                    continue;
+                }
+                let start = needed;
+                needed += header.len as usize;
+                if self.incoming.len() >= needed {
+                    let mut payload = self.incoming[start..].to_vec();
+                    self.incoming.clear();
+                    Payload::new(header.len, header.mask).apply_mask(&mut payload);
+                    self.answer_control(header.opcode, &payload);
+                    continue;
+                }
+            }
+
+            // Only as much as the frame needs, so that its payload is read straight into the
+            // buffer of the caller.
+            let start = self.incoming.len();
+            self.incoming.resize(needed, 0);
+            let len = match self.stream.read(&mut self.incoming[start..]) {
+                Ok(len) => len,
+                Err(e) => {
+                    self.incoming.truncate(start);
+                    return Err(e);
+                }
+            };
+            self.incoming.truncate(start + len);
+            if len == 0 {
+                // Between frames, the connection closing without a close frame is taken for the
+                // end of file like a TCP connection's.
+                return if start == 0 {
+                    Ok(false)
+                } else {
+                    Err(io::Error::from(ErrorKind::UnexpectedEof))
+                };
+            }
+        }
+    }
+
+    fn answer_control(&mut self, opcode: u8, payload: &[u8]) {
+        match opcode {
+            OP_PING => {
+                let pong = control_frame(self.role, OP_PONG, payload);
+                self.control.extend_from_slice(&pong);
+            }
+            OP_CLOSE => {
+                self.peer_closed = true;
+                // Echoes the status code, if any.
+                let code = &payload[..cmp::min(2, payload.len())];
+                let close = control_frame(self.role, OP_CLOSE, code);
+                self.control.extend_from_slice(&close);
+            }
+            _ => (),
+        }
+        if self.writing.is_none() {
+            // Otherwise written as soon as the data frame is.
+            let _ = self.flush_control();
+        }
+    }
+
+    /// Writes the control frames waiting, which mustn't cut a data frame short.
+    fn flush_control(&mut self) -> io::Result<()> {
+        while !self.control.is_empty() {
+            let len = self.stream.write(&self.control)?;
+            if len == 0 {
+                return Err(io::Error::from(ErrorKind::WriteZero));
+            }
+            let _ = self.control.drain(..len);
+        }
+        Ok(())
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Answers which didn't fit the kernel's buffer before, as the peer may not send anything
+        // more until it has them.
+        if self.writing.is_none() {
+            let _ = self.flush_control();
+        }
+        if self.reading.is_none() && !self.read_frame_header()? {
+            return Ok(0);
+        }
+        let mut payload = unwrap!(self.reading.take());
+        let res = payload.read_from(&mut self.stream, buf);
+        if payload.remaining > 0 {
+            self.reading = Some(payload);
+        }
+        res
+    }
+}
+
+impl Write for WsStream {
+    /// Writes the bytes in a new frame, or the rest of the frame the previous write started. A
+    /// frame is only ever started with a write, but can be completed by later ones: a stream's
+    /// bytes are written in order whatever their frames.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.writing.is_none() {
+            self.flush_control()?;
+            let len = cmp::min(buf.len(), MAX_FRAME_PAYLOAD);
+            let mask = new_mask(self.role);
+            self.header = encode_header(OP_BINARY, len, mask);
+            self.writing = Some(Payload::new(len as u64, mask));
+        }
+        while !self.header.is_empty() {
+            let len = self.stream.write(&self.header)?;
+            if len == 0 {
+                return Err(io::Error::from(ErrorKind::WriteZero));
+            }
+            let _ = self.header.drain(..len);
+        }
+        let mut payload = unwrap!(self.writing.take());
+        let res = payload.write_to(&mut self.stream, buf);
+        if payload.remaining > 0 {
+            self.writing = Some(payload);
+        } else {
+            let _ = self.flush_control();
+        }
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.writing.is_none() {
+            self.flush_control()?;
+        }
+        self.stream.flush()
+    }
+}
+
+impl Evented for WsStream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.stream.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.stream.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.stream.deregister(poll)
+    }
+}
+
+/// The payload of the data frame being read or written.
+struct Payload {
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    /// Bytes of the payload read or written so far, which the mask continues from.
+    offset: usize,
+}
+
+impl Payload {
+    fn new(len: u64, mask: Option<[u8; 4]>) -> Self {
+        Payload {
+            remaining: len,
+            mask,
+            offset: 0,
+        }
+    }
+
+    fn apply_mask(&self, bytes: &mut [u8]) {
+        if let Some(mask) = self.mask {
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte ^= mask[(self.offset + i) % 4];
+            }
+        }
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.remaining -= len as u64;
+        self.offset = (self.offset + len) % 4;
+    }
+
+    fn read_from(&mut self, stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        let want = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let len = stream.read(&mut buf[..want])?;
+        if len == 0 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        self.apply_mask(&mut buf[..len]);
+        self.advance(len);
+        Ok(len)
+    }
+
+    fn write_to(&mut self, stream: &mut TcpStream, buf: &[u8]) -> io::Result<usize> {
+        let want = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let len = if self.mask.is_some() {
+            let mut masked = buf[..want].to_vec();
+            self.apply_mask(&mut masked);
+            stream.write(&masked)?
+        } else {
+            stream.write(&buf[..want])?
+        };
+        self.advance(len);
+        Ok(len)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct FrameHeader {
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    len: u64,
+}
+
+impl FrameHeader {
+    /// Decodes the header `bytes` start with, which are at least `header_size` long.
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let fin = bytes[0] & 0x80 != 0;
+        let opcode = bytes[0] & 0x0f;
+        if bytes[0] & 0x70 != 0 {
+            return Err(invalid_data("reserved bits set without an extension"));
+        }
+        match opcode {
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => (),
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if !fin || bytes[1] & 0x7f > 125 {
+                    return Err(invalid_data("fragmented or oversized control frame"));
+                }
+            }
+            _ => return Err(invalid_data("unknown opcode")),
+        }
+        let (len, mut pos) = match bytes[1] & 0x7f {
+            126 => (u64::from(BigEndian::read_u16(&bytes[2..4])), 4),
+            127 => (BigEndian::read_u64(&bytes[2..10]), 10),
+            len => (u64::from(len), 2),
+        };
+        if len >> 63 != 0 {
+            return Err(invalid_data("frame length out of range"));
+        }
+        let mask = if bytes[1] & 0x80 != 0 {
+            let mut mask = [0; 4];
+            mask.copy_from_slice(&bytes[pos..pos + 4]);
+            pos += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        debug_assert_eq!(pos, header_size(bytes));
+        Ok(FrameHeader { opcode, mask, len })
+    }
+}
+
+/// Size of the frame header `bytes` start with, as far as they tell: at least its first two
+/// bytes.
+fn header_size(bytes: &[u8]) -> usize {
+    if bytes.len() < 2 {
+        return 2;
+    }
+    let mask = if bytes[1] & 0x80 != 0 { 4 } else { 0 };
+    let len = match bytes[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    2 + len + mask
+}
+
+/// The header of a whole, unfragmented frame.
+fn encode_header(opcode: u8, len: usize, mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(14);
+    header.push(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    if len < 126 {
+        header.push(mask_bit | len as u8);
+    } else if len <= 0xffff {
+        header.push(mask_bit | 126);
+        let mut bytes = [0; 2];
+        BigEndian::write_u16(&mut bytes, len as u16);
+        header.extend_from_slice(&bytes);
+    } else {
+        header.push(mask_bit | 127);
+        let mut bytes = [0; 8];
+        BigEndian::write_u64(&mut bytes, len as u64);
+        header.extend_from_slice(&bytes);
+    }
+    if let Some(mask) = mask {
+        header.extend_from_slice(&mask);
+    }
+    header
+}
+
+fn control_frame(role: Role, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = new_mask(role);
+    let mut frame = encode_header(opcode, payload.len(), mask);
+    let start = frame.len();
+    frame.extend_from_slice(payload);
+    Payload::new(payload.len() as u64, mask).apply_mask(&mut frame[start..]);
+    frame
+}
+
+fn new_mask(role: Role) -> Option<[u8; 4]> {
+    match role {
+        Role::Client => Some(rand::random()),
+        Role::Server => None,
+    }
+}
+
+fn invalid_data(reason: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+/// Called once the opening handshake of a connection is over, with the token of the handshake,
+/// and the stream unless the handshake failed.
+pub type WsUpgradeHandler = Box<FnMut(&mut Core, &Poll, Token, Option<WsStream>)>;
+/// Called with the address of each TCP connection a `WsEndpoint` accepts, before its opening
+/// handshake starts. The connection is dropped unless it returns `true`.
+pub type WsAdmitHandler = Box<FnMut(&mut Core, &SocketAddr) -> bool>;
+/// Called with each connection a `WsEndpoint` accepts, once its opening handshake is over.
+pub type WsAcceptHandler = Box<FnMut(&mut Core, &Poll, WsStream)>;
+
+/// A TCP listener accepting WebSocket connections. It also dials them, see `WsEndpoint::dial`.
+pub struct WsEndpoint {
+    token: Token,
+    listener: TcpListener,
+    admit: WsAdmitHandler,
+    accept: WsAcceptHandler,
+    /// Opening handshakes of accepted connections in progress, see `MAX_UPGRADES`.
+    upgrading: Rc<Cell<usize>>,
+    self_weak: Weak<RefCell<WsEndpoint>>,
+}
+
+impl WsEndpoint {
+    /// Accepts WebSocket connections at `addr`, those `admit` lets in, handing each to `accept`
+    /// once its opening handshake is over. Returns the token of the endpoint, and the address it
+    /// is bound to.
+    pub fn listen(
+        core: &mut Core,
+        poll: &Poll,
+        addr: &SocketAddr,
+        admit: WsAdmitHandler,
+        accept: WsAcceptHandler,
+    ) -> Result<(Token, SocketAddr)> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let token = core.get_new_token();
+        poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
+
+        let state = Rc::new(RefCell::new(WsEndpoint {
+            token,
+            listener,
+            admit,
+            accept,
+            upgrading: Rc::new(Cell::new(0)),
+            self_weak: Weak::new(),
+        }));
+        state.borrow_mut().self_weak = Rc::downgrade(&state);
+        let _ = core.insert_state(token, state);
+        Ok((token, local_addr))
+    }
+
+    /// Has the endpoint of `token` stop accepting connections. The handshakes it started are
+    /// dropped as they finish. Does nothing if it is gone, or busy calling whoever calls this.
+    pub fn stop(core: &mut Core, poll: &Poll, token: Token) {
+        let state = match core.get_state(token) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = match state.try_borrow_mut() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if state.as_any().downcast_mut::<WsEndpoint>().is_some() {
+            state.terminate(core, poll);
+        }
+    }
+
+    /// Connects to `addr` over TCP, from `bind_ip` if given, see `bind_ip_for`, and opens a
+    /// WebSocket connection over it. `done` is called from the event loop once the opening
+    /// handshake is over, or failed or timed out, never before this returns. Returns the token of
+    /// the handshake; terminating its state drops it without calling `done`.
+    pub fn dial(
+        core: &mut Core,
+        poll: &Poll,
+        addr: &SocketAddr,
+        bind_ip: Option<IpAddr>,
+        done: WsUpgradeHandler,
+    ) -> Result<Token> {
+        let stream = connect_tcp_from(addr, bind_ip)?;
+        WsUpgrade::start(core, poll, stream, *addr, Role::Client, done)
+    }
+
+    /// Opens a WebSocket connection over `stream`, accepted from `addr` by a listener of its own
+    /// whose port takes other protocols too. The opening handshake request of the client is yet
+    /// to be read from it. `done` is called as for `dial`.
+    pub fn upgrade_accepted(
+        core: &mut Core,
+        poll: &Poll,
+        stream: TcpStream,
+        addr: SocketAddr,
+        done: WsUpgradeHandler,
+    ) -> Result<Token> {
+        WsUpgrade::start(core, poll, stream, addr, Role::Server, done)
+    }
+}
+
+impl State for WsEndpoint {
+    fn name(&self) -> &'static str {
+        "WsEndpoint"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if !kind.is_readable() {
+            return;
+        }
+        loop {
+            let (stream, addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => match IoErrorClass::of(&e) {
+                    IoErrorClass::Retry => continue,
+                    IoErrorClass::WouldBlock => break,
+                    IoErrorClass::RemoteClosed | IoErrorClass::Fatal => {
+                        debug!("Failed to accept WebSocket connection: {:?}", e);
+                        break;
+                    }
+                },
+            };
+            if self.upgrading.get() >= MAX_UPGRADES {
+                debug!("Too many WebSocket handshakes. Dropping connection from {}.", addr);
+                continue;
+            }
+            if !(self.admit)(core, &addr) {
+                continue;
+            }
+            let slot = UpgradeSlot::new(self.upgrading.clone());
+            let endpoint = self.self_weak.clone();
+            let done = move |core: &mut Core, poll: &Poll, _: Token, stream: Option<WsStream>| {
+                // The slot goes with the handler, once called or dropped with the handshake.
+                let _ = &slot;
+                if let (Some(endpoint), Some(stream)) = (endpoint.upgrade(), stream) {
+                    (endpoint.borrow_mut().accept)(core, poll, stream);
+                }
+            };
+            let done: WsUpgradeHandler = Box::new(done);
+            if let Err(e) = WsUpgrade::start(core, poll, stream, addr, Role::Server, done) {
+                debug!("Failed to start WebSocket handshake with {}: {:?}", addr, e);
+            }
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = poll.deregister(&self.listener);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// An opening handshake counted in `WsEndpoint::upgrading`, until dropped.
+struct UpgradeSlot(Rc<Cell<usize>>);
+
+impl UpgradeSlot {
+    fn new(upgrading: Rc<Cell<usize>>) -> Self {
+        upgrading.set(upgrading.get() + 1);
+        UpgradeSlot(upgrading)
+    }
+}
+
+impl Drop for UpgradeSlot {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// The HTTP opening handshake of a WebSocket connection, see RFC 6455 section 4: the client asks
+/// for the connection to be upgraded with a random key, and the server agrees with a hash of it.
+struct WsUpgrade {
+    token: Token,
+    stream: Option<TcpStream>,
+    handshake: Handshake,
+    done: Option<WsUpgradeHandler>,
+}
+
+impl WsUpgrade {
+    fn start(
+        core: &mut Core,
+        poll: &Poll,
+        stream: TcpStream,
+        addr: SocketAddr,
+        role: Role,
+        done: WsUpgradeHandler,
+    ) -> Result<Token> {
+        let token = core.get_new_token();
+        poll.register(
+            &stream,
+            token,
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        )?;
+        let timer = CoreTimer::new(token, UPGRADE_TIMER_ID);
+        if let Err(e) = core.set_timeout(Duration::from_secs(UPGRADE_TIMEOUT_SECS), timer) {
+            let _ = poll.deregister(&stream);
+            return Err(e);
+        }
+
+        let key = base64::encode(&rand::random::<[u8; 16]>());
+        let (handshake, direction) = match role {
+            Role::Client => (
+                Handshake {
+                    role,
+                    phase: Phase::Writing,
+                    outgoing: request(&addr, &key),
+                    incoming: Vec::new(),
+                    key,
+                },
+                ConnectionDirection::Outbound,
+            ),
+            Role::Server => (
+                Handshake {
+                    role,
+                    phase: Phase::Reading,
+                    outgoing: Vec::new(),
+                    incoming: Vec::new(),
+                    key: String::new(),
+                },
+                ConnectionDirection::Inbound,
+            ),
+        };
+        let state = WsUpgrade {
+            token,
+            stream: Some(stream),
+            handshake,
+            done: Some(done),
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        core.add_pending(token, addr, direction, HandshakeStage::WebSocketUpgrade);
+        Ok(token)
+    }
+
+    fn finish(&mut self, core: &mut Core, poll: &Poll, upgraded: bool) {
+        let _ = core.remove_state(self.token);
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let _ = poll.deregister(&stream);
+        let stream = if upgraded {
+            Some(WsStream::new(stream, self.handshake.role))
+        } else {
+            None
+        };
+        if let Some(mut done) = self.done.take() {
+            done(core, poll, self.token, stream);
+        }
+    }
+}
+
+impl State for WsUpgrade {
+    fn name(&self) -> &'static str {
+        "WsUpgrade"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, _kind: Ready) {
+        loop {
+            let res = match self.stream {
+                Some(ref mut stream) => self.handshake.progress(stream),
+                None => return,
+            };
+            match res {
+                Ok(true) => return self.finish(core, poll, true),
+                Ok(false) => return,
+                Err(e) => match IoErrorClass::of(&e) {
+                    IoErrorClass::Retry => (),
+                    IoErrorClass::WouldBlock => return,
+                    IoErrorClass::RemoteClosed | IoErrorClass::Fatal => {
+                        debug!("WebSocket opening handshake failed: {:?}", e);
+                        return self.finish(core, poll, false);
+                    }
+                },
+            }
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        debug!("WebSocket opening handshake timed out");
+        self.finish(core, poll, false);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        if let Some(stream) = self.stream.take() {
+            let _ = poll.deregister(&stream);
+        }
+        self.done = None;
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Reading the head of the request or response of the peer.
+    Reading,
+    /// Writing the head of ours.
+    Writing,
+    /// Writing our refusal of the request of the client, after which the connection is dropped.
+    Refusing,
+}
+
+/// The state of the opening handshake, apart from the stream it goes over.
+struct Handshake {
+    role: Role,
+    phase: Phase,
+    /// The key the client sends.
+    key: String,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl Handshake {
+    /// Goes on with the handshake as far as the stream allows. Returns whether it is over.
+    fn progress(&mut self, stream: &mut TcpStream) -> io::Result<bool> {
+        loop {
+            match self.phase {
+                Phase::Writing | Phase::Refusing => {
+                    while !self.outgoing.is_empty() {
+                        let len = stream.write(&self.outgoing)?;
+                        if len == 0 {
+                            return Err(io::Error::from(ErrorKind::WriteZero));
+                        }
+                        let _ = self.outgoing.drain(..len);
+                    }
+                    match (self.phase, self.role) {
+                        (Phase::Refusing, _) => return Err(invalid_data("upgrade refused")),
+                        (_, Role::Server) => return Ok(true),
+                        (_, Role::Client) => self.phase = Phase::Reading,
+                    }
+                }
+                Phase::Reading => {
+                    let head = self.read_head(stream)?;
+                    match self.role {
+                        Role::Client => {
+                            check_response(&head, &self.key).map_err(invalid_data)?;
+                            return Ok(true);
+                        }
+                        Role::Server => match check_request(&head) {
+                            Ok(key) => {
+                                self.outgoing = response(&accept_key(&key));
+                                self.phase = Phase::Writing;
+                            }
+                            Err(reason) => {
+                                debug!("Refusing WebSocket upgrade: {}", reason);
+                                self.outgoing = REFUSAL.to_vec();
+                                self.phase = Phase::Refusing;
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the head of the request or response, up to the empty line ending it. Anything after
+    /// it is refused, as neither side may send more before the handshake is over, and bytes left
+    /// over from it would wait for a readiness event which doesn't come.
+    fn read_head(&mut self, stream: &mut TcpStream) -> io::Result<String> {
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(pos) = self.incoming.windows(4).position(|w| w == b"\r\n\r\n") {
+                if pos + 4 != self.incoming.len() {
+                    return Err(invalid_data("data sent before the handshake was over"));
+                }
+                let head = mem::replace(&mut self.incoming, Vec::new());
+                return String::from_utf8(head).map_err(|_| invalid_data("head isn't UTF-8"));
+            }
+            if self.incoming.len() > MAX_HEAD_SIZE {
+                return Err(invalid_data("head too long"));
+            }
+            let len = stream.read(&mut buffer)?;
+            if len == 0 {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof));
+            }
+            self.incoming.extend_from_slice(&buffer[..len]);
+        }
+    }
+}
+
+fn request(addr: &SocketAddr, key: &str) -> Vec<u8> {
+    format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        addr, key
+    )
+    .into_bytes()
+}
+
+fn response(accept: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )
+    .into_bytes()
+}
+
+/// Checks a request to open a WebSocket connection, returning the key of the client.
+fn check_request(head: &str) -> ::std::result::Result<String, &'static str> {
+    let (first_line, fields) = parse_head(head);
+    let mut words = first_line.split(' ');
+    if words.next() != Some("GET") {
+        return Err("not a GET request");
+    }
+    if words.nth(1) != Some("HTTP/1.1") {
+        return Err("not HTTP/1.1");
+    }
+    check_upgrade(&fields)?;
+    if fields.get("sec-websocket-version").cloned() != Some("13") {
+        return Err("unsupported WebSocket version");
+    }
+    match fields.get("sec-websocket-key") {
+        Some(key)
+            if base64::decode(key)
+                .ok()
+                .map_or(false, |key| key.len() == 16) =>
+        {
+            Ok(key.to_string())
+        }
+        _ => Err("missing or malformed key"),
+    }
+}
+
+/// Checks the answer of the server to our request, sent with `key`.
+fn check_response(head: &str, key: &str) -> ::std::result::Result<(), &'static str> {
+    let (status_line, fields) = parse_head(head);
+    if status_line.split(' ').nth(1) != Some("101") {
+        return Err("upgrade refused by the server");
+    }
+    check_upgrade(&fields)?;
+    if fields.get("sec-websocket-accept").cloned() != Some(&accept_key(key)[..]) {
+        return Err("wrong accept key");
+    }
+    Ok(())
+}
+
+fn check_upgrade(fields: &HashMap<String, &str>) -> ::std::result::Result<(), &'static str> {
+    // The values are lists of tokens which are compared case-insensitively.
+    let has_token = |name: &str, token: &str| {
+        fields.get(name).map_or(false, |value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Err("not an upgrade to WebSocket");
+    }
+    Ok(())
+}
+
+/// Splits the head of a request or response into its first line and its header fields, by
+/// their names in lower case.
+fn parse_head(head: &str) -> (&str, HashMap<String, &str>) {
+    let mut lines = head.split("\r\n");
+    let first_line = lines.next().unwrap_or("");
+    let fields = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next()?;
+            let value = parts.next()?;
+            Some((name.trim().to_lowercase(), value.trim()))
+        })
+        .collect();
+    (first_line, fields)
+}
+
+/// The answer of the server to `key`. SHA-1, broken as it is for anything else, only proves
+/// here that the server understood the request.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(format!("{}{}", key, ACCEPT_GUID).as_bytes());
+    base64::encode(&sha1.digest().bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoreMessage, ManualEventLoop};
+    use std::net;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn opening_handshake() {
+        // The example of RFC 6455.
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        assert_eq!(accept_key(key), "s3pPLMBiTxaQ9kCzzZ8C6ozRNoE=");
+
+        let addr = unwrap!("127.0.0.1:5483".parse());
+        let ours = unwrap!(String::from_utf8(request(&addr, key)));
+        assert_eq!(check_request(&ours), Ok(key.to_string()));
+        let answer = unwrap!(String::from_utf8(response(&accept_key(key))));
+        assert_eq!(check_response(&answer, key), Ok(()));
+        assert!(check_response(&answer, "AAECAwQFBgcICQoLDA0ODw==").is_err());
+
+        // As a browser sends it, with more fields and other cases.
+        let browser = "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nupgrade: WebSocket\r\n\
+                       Connection: keep-alive, Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Origin: http://example.com\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(check_request(browser), Ok(key.to_string()));
+        assert!(check_request(&browser.replace("GET", "POST")).is_err());
+        assert!(check_request(&browser.replace("Version: 13", "Version: 8")).is_err());
+        assert!(check_request(&browser.replace("keep-alive, Upgrade", "keep-alive")).is_err());
+        assert!(check_request(&browser.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=")).is_err());
+    }
+
+    #[test]
+    fn frame_headers() {
+        let mask = Some([1, 2, 3, 4]);
+        for &len in &[0, 125, 126, 0xffff, 0x10000] {
+            let header = encode_header(OP_BINARY, len, mask);
+            assert_eq!(header.len(), header_size(&header));
+            let expected = FrameHeader {
+                opcode: OP_BINARY,
+                mask,
+                len: len as u64,
+            };
+            assert_eq!(unwrap!(FrameHeader::decode(&header)), expected);
+        }
+        assert_eq!(header_size(&[0x82]), 2);
+
+        let ping = control_frame(Role::Server, OP_PING, b"ping");
+        assert_eq!(&ping[..], b"\x89\x04ping");
+        let mut long_ping = ping.clone();
+        long_ping[1] = 126;
+        assert!(FrameHeader::decode(&long_ping).is_err());
+        let mut fragmented_ping = ping.clone();
+        fragmented_ping[0] = OP_PING;
+        assert!(FrameHeader::decode(&fragmented_ping).is_err());
+        let mut reserved = ping;
+        reserved[0] |= 0x40;
+        assert!(FrameHeader::decode(&reserved).is_err());
+    }
+
+    /// Two ends of a loopback TCP connection, the first playing `first`.
+    fn stream_pair(first: Role, second: Role) -> (WsStream, WsStream) {
+        let listener = unwrap!(net::TcpListener::bind("127.0.0.1:0"));
+        let connecting = unwrap!(net::TcpStream::connect(unwrap!(listener.local_addr())));
+        let (accepted, _) = unwrap!(listener.accept());
+        (
+            WsStream::new(unwrap!(TcpStream::from_stream(connecting)), first),
+            WsStream::new(unwrap!(TcpStream::from_stream(accepted)), second),
+        )
+    }
+
+    /// Makes the call until it doesn't fail with `WouldBlock`.
+    fn retry<T, F: FnMut() -> io::Result<T>>(mut call: F) -> io::Result<T> {
+        for _ in 0..1000 {
+            match call() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(5))
+                }
+                res => return res,
+            }
+        }
+        panic!("Still blocking");
+    }
+
+    #[test]
+    fn streams_carry_bytes_both_ways() {
+        let (mut client, mut server) = stream_pair(Role::Client, Role::Server);
+        // More than fits a frame.
+        let sent: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        for _ in 0..10_000 {
+            if received.len() == sent.len() {
+                break;
+            }
+            match client.write(&sent[written..]) {
+                Ok(len) => written += len,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => panic!("Failed to write: {:?}", e),
+            }
+            match server.read(&mut buffer) {
+                Ok(len) => received.extend_from_slice(&buffer[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("Failed to read: {:?}", e),
+            }
+        }
+        assert!(received == sent);
+
+        assert_eq!(unwrap!(retry(|| server.write(b"reply"))), 5);
+        assert_eq!(unwrap!(retry(|| client.read(&mut buffer))), 5);
+        assert_eq!(&buffer[..5], b"reply");
+
+        // Closing is the end of file to the peer.
+        unwrap!(server.shutdown());
+        assert_eq!(unwrap!(retry(|| client.read(&mut buffer))), 0);
+    }
+
+    #[test]
+    fn pings_are_answered_and_bad_frames_refused() {
+        let (mut client, mut server) = stream_pair(Role::Client, Role::Server);
+        client.control = control_frame(Role::Client, OP_PING, b"ping");
+        unwrap!(client.flush_control());
+        assert_eq!(unwrap!(retry(|| client.write(b"data"))), 4);
+
+        let mut buffer = [0; 64];
+        assert_eq!(unwrap!(retry(|| server.read(&mut buffer))), 4);
+        assert_eq!(&buffer[..4], b"data");
+        // The pong isn't data.
+        thread::sleep(Duration::from_millis(50));
+        match client.read(&mut buffer) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(client.incoming.is_empty() && client.reading.is_none());
+
+        // A client's frames must be masked.
+        let (mut unmasked, mut server) = stream_pair(Role::Server, Role::Server);
+        assert_eq!(unwrap!(retry(|| unmasked.write(b"data"))), 4);
+        match retry(|| server.read(&mut buffer)) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // And text is refused.
+        let (mut client, mut server) = stream_pair(Role::Client, Role::Server);
+        client.control = control_frame(Role::Client, OP_TEXT, b"text");
+        unwrap!(client.flush_control());
+        match retry(|| server.read(&mut buffer)) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    fn run(el: &mut ManualEventLoop) {
+        for _ in 0..10 {
+            let _ = unwrap!(el.run_once(Duration::from_millis(10)));
+        }
+    }
+
+    /// Whether the endpoint dropped the connection, rather than answer it.
+    fn is_dropped(stream: &mut net::TcpStream) -> bool {
+        unwrap!(stream.set_read_timeout(Some(Duration::from_millis(500))));
+        let mut buffer = [0; 64];
+        stream.read(&mut buffer).ok() == Some(0)
+    }
+
+    #[test]
+    fn connections_are_admitted_before_their_handshake() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        let admitting = Arc::new(Mutex::new(false));
+        let accepted = Arc::new(Mutex::new(0));
+        let (addr_tx, addr_rx) = mpsc::channel();
+        {
+            let admitting = admitting.clone();
+            let accepted = accepted.clone();
+            unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+                let admit = move |_: &mut Core, _: &SocketAddr| *unwrap!(admitting.lock());
+                let accept = move |_: &mut Core, _: &Poll, _| *unwrap!(accepted.lock()) += 1;
+                let listen_addr = unwrap!("127.0.0.1:0".parse());
+                let (_, addr) = unwrap!(WsEndpoint::listen(
+                    core,
+                    poll,
+                    &listen_addr,
+                    Box::new(admit),
+                    Box::new(accept)
+                ));
+                let _ = addr_tx.send(addr);
+            })));
+        }
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        let addr = unwrap!(addr_rx.try_recv());
+
+        let mut refused = unwrap!(net::TcpStream::connect(addr));
+        run(&mut el);
+        assert!(is_dropped(&mut refused));
+
+        // Those beyond the handshakes in progress are dropped too.
+        *unwrap!(admitting.lock()) = true;
+        let mut upgrading: Vec<_> = (0..MAX_UPGRADES)
+            .map(|_| unwrap!(net::TcpStream::connect(addr)))
+            .collect();
+        run(&mut el);
+        let mut beyond = unwrap!(net::TcpStream::connect(addr));
+        run(&mut el);
+        assert!(is_dropped(&mut beyond));
+
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        unwrap!(upgrading[0].write_all(&request(&addr, key)));
+        run(&mut el);
+        let mut buffer = [0; 1024];
+        let len = unwrap!(upgrading[0].read(&mut buffer));
+        let head = unwrap!(String::from_utf8(buffer[..len].to_vec()));
+        assert_eq!(check_response(&head, key), Ok(()));
+        assert_eq!(*unwrap!(accepted.lock()), 1);
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Entry points for the fuzz targets in `fuzz/`. Only available with the `fuzzing` feature and
+//! not part of the public API.
+
+use common::{self, FrameDecoder, Message, Uid, MAX_PAYLOAD_SIZE};
+use main::decode_handshake_request;
+
+/// Peer id used by the fuzz targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FuzzUid([u8; 20]);
+
+impl Uid for FuzzUid {}
+
+/// Feeds `data` to a frame decoder in chunks whose size is taken from the first byte, and
+/// decodes every complete frame as a message. Panics if the decoder breaks its guarantees.
+pub fn frame_decoder(data: &[u8]) {
+    let (chunk_size, mut input) = match data.split_first() {
+        Some((first, rest)) => (usize::from(*first) + 1, rest),
+        None => return,
+    };
+
+    let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+    while !input.is_empty() {
+        let chunk_len = chunk_size.min(input.len());
+        let mut chunk = &input[..chunk_len];
+        input = &input[chunk_len..];
+
+        while !chunk.is_empty() {
+            match decoder.decode(&mut chunk) {
+                Ok(Some(frame)) => {
+                    assert!(frame.len() <= MAX_PAYLOAD_SIZE);
+                    let _ = common::decode_message::<Message<FuzzUid>>(&frame);
+                }
+                Ok(None) => assert!(chunk.is_empty()),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Decodes `data` as the first frame of an inbound connection.
+pub fn handshake_request(data: &[u8]) {
+    let _ = decode_handshake_request::<FuzzUid>(data);
+}
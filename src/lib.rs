@@ -9,6 +9,12 @@
 
 //! #crust
 //! Reliable peer-to-peer network connections in Rust with NAT traversal.
+//!
+//! The subsystems an application may do without are cargo features, all on by default:
+//! `service-discovery`, `nat-traversal`, `relay` and `flight-recorder`. Building with
+//! `--no-default-features --features tcp-only` leaves direct TCP connections only. The API of a
+//! feature left out is compiled out with it, and a `Config` asking for it fails
+//! `Config::validate`.
 
 #![doc(
     html_logo_url = "https://raw.githubusercontent.com/maidsafe/QA/master/Images/maidsafe_logo.png",
@@ -55,34 +61,67 @@ extern crate serde_derive;
 #[macro_use]
 extern crate unwrap;
 
+extern crate base64;
+extern crate bincode;
 extern crate byteorder;
 extern crate config_file_handler;
+#[cfg(any(test, feature = "nat-traversal"))]
 extern crate crossbeam;
+extern crate fs2;
 extern crate get_if_addrs;
+#[cfg(feature = "nat-traversal")]
 extern crate igd;
+#[cfg(unix)]
+extern crate libc;
 extern crate maidsafe_utilities;
 extern crate mio;
 extern crate net2;
 extern crate rand;
 extern crate rust_sodium;
 extern crate serde;
+extern crate serde_json;
+#[cfg(feature = "websocket")]
+extern crate sha1;
 extern crate tiny_keccak;
 
-#[cfg(test)]
-extern crate serde_json;
+#[cfg(any(test, feature = "test-utils"))]
+#[macro_use]
+pub mod test_utils;
 #[cfg(test)]
 #[macro_use]
 mod tests;
 
 mod common;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
 mod main;
 mod nat;
 mod service_discovery;
+#[cfg(feature = "service-discovery")]
+#[doc(hidden)]
+pub mod test_vectors;
 
-pub use common::{CrustUser, Priority, Uid, MSG_DROP_PRIORITY};
+pub use common::{
+    ConnectionDirection, CoreStats, CrustUser, HandshakeStage, KeyPair, MemoryPressure,
+    NegotiatedFeatures, OneWayLatency, PendingConnInfo, PowerMode, Priority, PublicKey, Rejection,
+    RejectionCode, SendReceipt, SharedBuffer, StateKindStats, Uid, CONTROL_PRIORITY,
+    MIN_MEMORY_BUDGET, MSG_DROP_PRIORITY, PUBLIC_KEY_SIZE, SECRET_KEY_SIZE,
+};
+#[cfg(feature = "flight-recorder")]
+pub use common::{RecordedEvent, RecordedEventKind};
+#[cfg(feature = "copy-audit")]
+pub use common::payload_copies;
 pub use main::{
-    read_config_file, Config, ConnectionInfoResult, CrustError, Event, PrivConnectionInfo,
-    PubConnectionInfo, Service,
+    config_search_paths, read_config_file, AdaptiveHeartbeat, AuditConfig, BootstrapCacheEntry,
+    CandidateAddr, Config, ConnectedPeer, ConnectionInfoResult, ConnectionInfoSource,
+    ConnectionInfoTextError, ContactFailure, ContactHealth, CrustError, DiagnosticCheck,
+    DiagnosticResult, DiagnosticStatus, DiagnosticsReport, DisconnectReason,
+    DuplicateConnectionPolicy, Event, EventBatching, ExternalCore, ExternalState,
+    LatencyHistogram, ListenerOptions, NetworkStats, PeerContact, PeerStats, PrivConnectionInfo,
+    ProtocolViolation, PubConnectionInfo, ReconnectPolicy, RelayConfig, RequestId, ResourceKind,
+    Service, ServiceCore, ServiceSnapshot, Transport, ViolationPolicy, CONFIG_PATH_ENV_VAR,
+    CONFIG_VERSION, DIAGNOSTICS_CONNECT_TIMEOUT_SECS,
 };
 
 /// Used to receive events from a `Service`.
@@ -7,26 +7,189 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::{Core, CoreTimer, CrustUser, Message, Priority, Socket, State, Uid};
-use main::{ConnectionId, ConnectionMap, Event};
-use mio::timer::Timeout;
+use common::{
+    decode_message, split_data_frame, AuditEvent, AuditRecord, Charge, CommonError,
+    ConnectionDirection, Core, CoreTimer, CrustUser, IoErrorClass, Message, NegotiatedFeatures,
+    PowerMode, Priority, PublicKey, RateLimiter, RecordedEventKind, SendReceipt, SharedBuffer,
+    Socket, State, Throttle, Uid, CONTROL_PRIORITY,
+};
+use main::{
+    now_secs, smooth_rtt, with_upgrades, CacheUpdate, CacheWriter, CheckReachability, Config,
+    ConnectionId, ConnectionMap, CrustError, DisconnectReason, DuplicateConnectionPolicy, Event,
+    EventBatching, EventSink, HeartbeatIntervals, InboundRate, InboundRateLimits, ListenerChecks,
+    ParkedPeers, PeerContact, PeerStats, PendingRequests, ProbeTimes, Promotion, PromotionCheck,
+    ProtocolViolation, ReachabilityChecks, Reconnects, RequestId, ResponseMatch, RetainedQueues,
+    Stage, Switch, Transport, Upgrade, UpgradeLeg, ViolationPolicy, DEFAULT_REQUEST_TIMEOUT_SECS,
+    HEARTBEAT_INTERVALS_TOKEN, RECONNECTS_TOKEN, RETAINED_QUEUES_TOKEN,
+};
 use mio::{Poll, Ready, Token};
+use nat::ip_addr_is_global;
 use std::any::Any;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::hash_map::Entry;
+use std::collections::HashSet;
+use std::io;
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[cfg(not(test))]
 pub const INACTIVITY_TIMEOUT_MS: u64 = 120_000;
 #[cfg(not(test))]
-const HEARTBEAT_PERIOD_MS: u64 = 20_000;
+pub const HEARTBEAT_PERIOD_MS: u64 = 20_000;
 
 #[cfg(test)]
 pub const INACTIVITY_TIMEOUT_MS: u64 = 900;
 #[cfg(test)]
-const HEARTBEAT_PERIOD_MS: u64 = 300;
+pub const HEARTBEAT_PERIOD_MS: u64 = 300;
+
+/// Number of unanswered liveness probes after which a connection is dropped, unless configured.
+const DEFAULT_PROBE_RETRIES: u32 = 3;
+const PROBE_TIMER_ID: u64 = 2;
+/// Most times the heartbeat period of an idle connection doubles in `PowerMode::LowPower`, should
+/// the ceiling allow.
+const MAX_HEARTBEAT_STRETCH: u32 = 8;
+
+/// Minimum time between two advertisements of our listeners on a connection. Changes in between
+/// are coalesced into a single update sent once the interval has passed.
+#[cfg(not(test))]
+const CONTACT_INFO_UPDATE_INTERVAL_MS: u64 = 10_000;
+#[cfg(test)]
+const CONTACT_INFO_UPDATE_INTERVAL_MS: u64 = 300;
+const CONTACT_INFO_TIMER_ID: u64 = 3;
+const READ_PAUSE_TIMER_ID: u64 = 4;
+const FRAME_TIMER_ID: u64 = 5;
+const LATENCY_TIMER_ID: u64 = 6;
+const BATCH_TIMER_ID: u64 = 7;
+const SILENCE_TIMER_ID: u64 = 8;
+const RESUME_TIMER_ID: u64 = 9;
+const REQUEST_TIMER_ID: u64 = 10;
+const MEMORY_RETRY_TIMER_ID: u64 = 11;
+const WRITE_PACE_TIMER_ID: u64 = 12;
+const UPGRADE_TIMER_ID: u64 = 13;
+/// Time after which reading is tried again when it stopped for want of memory.
+const MEMORY_RETRY_MS: u64 = 100;
+/// Responses are written ahead of bulk data, as the peer is waiting on them.
+const RESPONSE_PRIORITY: Priority = 1;
+
+/// Time within which the probe sent on resuming from a suspend has to be answered.
+#[cfg(not(test))]
+const RESUME_PROBE_TIMEOUT_MS: u64 = 5_000;
+#[cfg(test)]
+const RESUME_PROBE_TIMEOUT_MS: u64 = 300;
+
+/// Time within which we have to take up the new connection once the peer moved onto it, as the
+/// old one isn't read meanwhile, see `Upgrade`.
+const UPGRADE_SWITCH_TIMEOUT_SECS: u64 = 30;
+
+/// Time within which a frame has to arrive in full once its header has been read, unless
+/// configured, plus a second per `MIN_FRAME_BYTES_PER_SEC` bytes of its length.
+const DEFAULT_FRAME_COMPLETION_TIMEOUT_SECS: u64 = 10;
+const MIN_FRAME_BYTES_PER_SEC: u64 = 32 * 1024;
+
+/// Bytes read from a peer per readable event, unless configured.
+pub const DEFAULT_READ_BUDGET_BYTES: usize = 256 * 1024;
+
+/// Per-connection behaviour, taken from the config when the connection is established.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionSettings {
+    pub probe: Option<ProbeSettings>,
+    /// Cache updated when the peer is reached at new listeners it advertised.
+    pub bootstrap_cache_name: Option<String>,
+    pub shared_payload_min_size: Option<usize>,
+    pub inbound_limits: Option<InboundRateLimits>,
+    /// Whether unsent messages are retained if the connection is lost, see
+    /// `Config::retention_window_secs`.
+    pub retain_unsent: bool,
+    pub frame_completion_timeout: Option<Duration>,
+    /// How often the round trip to the peer is timed, see `Config::latency_probe_interval_secs`.
+    pub latency_probe_interval: Option<Duration>,
+    pub event_batching: Option<EventBatching>,
+    /// Whether a peer asking to be promoted to a node has to be reachable at a global address, as
+    /// for bootstrapping as a node. Otherwise any address it announced will do.
+    pub require_reachability: bool,
+    /// Which connection is kept when one to the peer is already up, see
+    /// `Config::duplicate_connection_policy`.
+    pub duplicate_policy: DuplicateConnectionPolicy,
+    /// Whether timestamps are offered to the peer in the handshake, see
+    /// `Config::timestamp_frames`.
+    pub timestamp_frames: bool,
+    /// Time within which our requests have to be answered, see `Config::request_timeout_secs`.
+    pub request_timeout: Option<Duration>,
+    /// Bytes read from the peer per readable event, see `Config::read_budget_bytes`. `None`
+    /// reads for as long as there is something to read.
+    pub read_budget: Option<usize>,
+    /// What is done about the peer breaking the protocol, see
+    /// `Config::protocol_violation_policy`.
+    pub violation_policy: ViolationPolicy,
+    /// Ceiling of the heartbeat interval, see `AdaptiveHeartbeat::max_interval_ms`.
+    pub max_heartbeat_interval: Option<Duration>,
+    /// Whether the uTP candidates of peers are dialled, see `Config::enable_utp`.
+    pub enable_utp: bool,
+    /// Bytes of messages queued for the peer, see `Config::max_queued_bytes_per_peer`.
+    pub max_queued_bytes: Option<usize>,
+    /// Whether peers are told in the handshake that we relay for them, see `Config::relay`.
+    pub relay: bool,
+    /// Whether peers reachable only through a relay are connected to through one, see
+    /// `Config::use_relays`.
+    pub use_relays: bool,
+}
+
+impl ConnectionSettings {
+    pub fn from_config(config: &Config) -> Self {
+        ConnectionSettings {
+            probe: ProbeSettings::from_config(config),
+            bootstrap_cache_name: config.bootstrap_cache_name.clone(),
+            shared_payload_min_size: config.shared_payload_min_size,
+            inbound_limits: InboundRateLimits::from_config(config),
+            retain_unsent: config.retention_window_secs.is_some(),
+            frame_completion_timeout: config
+                .frame_completion_timeout_secs
+                .map(Duration::from_secs),
+            latency_probe_interval: config.latency_probe_interval_secs.map(Duration::from_secs),
+            event_batching: config.event_batching,
+            require_reachability: !config.lan_only && config.dev.as_ref().map_or(true, |dev| {
+                !dev.disable_external_reachability_requirement
+            }),
+            duplicate_policy: config.duplicate_connection_policy,
+            timestamp_frames: config.timestamp_frames,
+            request_timeout: config.request_timeout_secs.map(Duration::from_secs),
+            read_budget: match config.read_budget_bytes {
+                Some(0) => None,
+                Some(bytes) => Some(bytes),
+                None => Some(DEFAULT_READ_BUDGET_BYTES),
+            },
+            violation_policy: config.protocol_violation_policy,
+            max_heartbeat_interval: config
+                .adaptive_heartbeat
+                .as_ref()
+                .map(|adaptive| Duration::from_millis(adaptive.max_interval_ms)),
+            enable_utp: config.enable_utp,
+            max_queued_bytes: config.max_queued_bytes_per_peer,
+            relay: config.relay.is_some(),
+            use_relays: config.use_relays,
+        }
+    }
+}
+
+/// When an idle connection is probed for liveness, see `Config::probe_after_idle_secs`.
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeSettings {
+    pub after_idle: Duration,
+    pub retries: u32,
+}
+
+impl ProbeSettings {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.probe_after_idle_secs.map(|secs| ProbeSettings {
+            after_idle: Duration::from_secs(secs),
+            retries: config.probe_retries.unwrap_or(DEFAULT_PROBE_RETRIES),
+        })
+    }
+}
 
 pub struct ActiveConnection<UID: Uid> {
     token: Token,
@@ -35,8 +198,73 @@ pub struct ActiveConnection<UID: Uid> {
     our_id: UID,
     their_id: UID,
     their_role: CrustUser,
-    event_tx: ::CrustEventSender<UID>,
+    /// Which of us made the connection, if only one of us did, see `initiated_by_lower`.
+    direction: Option<ConnectionDirection>,
+    /// For the audit log, as the socket can't tell any more once the connection is lost.
+    their_addr: Option<SocketAddr>,
+    event_tx: EventSink<UID>,
     heartbeat: Heartbeat,
+    probe: Option<Probe>,
+    settings: ConnectionSettings,
+    their_listeners: Vec<SocketAddr>,
+    advertisement: Advertisement,
+    tag: u64,
+    inbound: Option<InboundRate>,
+    /// Position in the stream of the frame received in part which has a deadline to arrive by.
+    frame_deadline: Option<u64>,
+    stats: PeerStats,
+    /// Bytes written to and read from the socket before `stats` started counting them.
+    traffic_origin: (u64, u64),
+    connected_at: Instant,
+    features: NegotiatedFeatures,
+    probe_times: ProbeTimes,
+    /// Data messages not delivered yet, see `Config::event_batching`.
+    batch: Vec<Vec<u8>>,
+    /// What the batched messages are charged to the memory budget.
+    batch_charge: Charge,
+    promotion: Promotion,
+    promotion_check: PromotionCheck,
+    listener_checks: ListenerChecks,
+    closing: Option<Closing<UID>>,
+    /// The reason code of the goodbye to be queued once everything queued ahead of it has been
+    /// written, see `disconnect`.
+    goodbye: Option<u32>,
+    lost_reason: DisconnectReason,
+    /// Silence tried on this connection to learn its heartbeat interval, see
+    /// `Config::adaptive_heartbeat`.
+    silence: Option<Silence>,
+    /// Our requests the peer hasn't answered yet, see `Service::send_request`.
+    requests: PendingRequests,
+    /// Whether reading on is posted to the event loop, see `read_on_later`.
+    read_on_posted: bool,
+    /// The peer's own bandwidth limits, on top of the service's, see
+    /// `Service::set_peer_bandwidth_limits`.
+    bandwidth: RateLimiter,
+    /// Counts the connection in once it closed after writing everything queued, see
+    /// `Service::shutdown`.
+    drained: Option<Rc<Cell<usize>>>,
+    /// Whether writing is held back by the window of the relay the connection runs through, as
+    /// the application was last told, see `Event::PeerCongested`.
+    #[cfg(feature = "relay")]
+    relay_congested: bool,
+    /// The move of the connection onto a direct one, see `Service::upgrade_connection`.
+    upgrade: Upgrade,
+}
+
+/// Stage of a silence longer than the heartbeat interval.
+enum Silence {
+    /// Nothing is sent until the heartbeat is due again.
+    Quiet,
+    /// The probe ending the silence hasn't been answered yet.
+    Probing,
+}
+
+/// How a connection is closed once everything queued on it has been written.
+enum Closing<UID: Uid> {
+    Park(ParkedPeers<UID>, SocketAddr),
+    Goodbye(u32),
+    /// The event loop is draining, see `Core::drain`.
+    Drain,
 }
 
 impl<UID: Uid> ActiveConnection<UID> {
@@ -44,21 +272,83 @@ impl<UID: Uid> ActiveConnection<UID> {
         core: &mut Core,
         poll: &Poll,
         token: Token,
-        socket: Socket,
+        mut socket: Socket,
         cm: ConnectionMap<UID>,
         our_id: UID,
         their_id: UID,
         their_role: CrustUser,
         event: Event<UID>,
-        event_tx: ::CrustEventSender<UID>,
+        event_tx: EventSink<UID>,
+        settings: ConnectionSettings,
+        features: NegotiatedFeatures,
+        direction: Option<ConnectionDirection>,
     ) {
         trace!(
             "Entered state ActiveConnection: {:?} -> {:?}",
             our_id,
             their_id
         );
+        core.remove_pending(token);
+
+        // The connection map has the final say on whether we are connected to the peer, so a
+        // second connection to it is never reported: it's either dropped or takes over the first.
+        // Both ends have to pick the same one, so the connection made by the peer with the lower
+        // ID survives, and the policy only decides between connections this doesn't tell apart.
+        let predecessor = match unwrap!(cm.lock()).get(&their_id) {
+            Some(&ConnectionId {
+                active_connection: Some(existing),
+                ..
+            }) if existing != token => Some(existing),
+            _ => None,
+        };
+        // Unless the first connection is to move onto this one, see `Upgrade`.
+        if let Some(predecessor) = predecessor {
+            match upgrade_to::<UID>(core, poll, predecessor, socket, features) {
+                Ok(()) => {
+                    if let Some(conn_id) = unwrap!(cm.lock()).get_mut(&their_id) {
+                        conn_id.currently_handshaking =
+                            conn_id.currently_handshaking.saturating_sub(1);
+                    }
+                    return;
+                }
+                Err(returned) => socket = returned,
+            }
+        }
+        let keep_new = predecessor.map(|predecessor| {
+            let old = direction_of::<UID>(core, predecessor);
+            match (
+                initiated_by_lower(our_id, their_id, old),
+                initiated_by_lower(our_id, their_id, direction),
+            ) {
+                (Some(false), Some(true)) => true,
+                (Some(true), Some(false)) => false,
+                _ => settings.duplicate_policy == DuplicateConnectionPolicy::KeepNewest,
+            }
+        });
+        if keep_new == Some(false) {
+            debug!(
+                "{:?} - Already connected to {:?} - dropping the new connection",
+                our_id, their_id
+            );
+            let _ = poll.deregister(&socket);
+            if let Some(conn_id) = unwrap!(cm.lock()).get_mut(&their_id) {
+                conn_id.currently_handshaking = conn_id.currently_handshaking.saturating_sub(1);
+            }
+            return;
+        }
 
-        let heartbeat = match Heartbeat::new(core, token) {
+        let period = socket
+            .peer_addr()
+            .ok()
+            .and_then(|addr| {
+                with_heartbeat_intervals(core, |intervals| intervals.interval(addr.ip()))
+            })
+            .unwrap_or_else(|| Duration::from_millis(HEARTBEAT_PERIOD_MS));
+        let ceiling = Duration::from_millis(INACTIVITY_TIMEOUT_MS / 2);
+        let ceiling = settings
+            .max_heartbeat_interval
+            .map_or(ceiling, |max| cmp::min(max, ceiling));
+        let mut heartbeat = match Heartbeat::new(core, token, period, ceiling) {
             Ok(heartbeat) => heartbeat,
             Err(e) => {
                 debug!(
@@ -67,12 +357,47 @@ impl<UID: Uid> ActiveConnection<UID> {
                     our_id, e, their_id
                 );
                 let _ = poll.deregister(&socket);
-                let _ = event_tx.send(Event::LostPeer(their_id));
+                let reason = DisconnectReason::ConnectionLost;
+                event_tx.send(Event::LostPeer(their_id, reason, 0));
                 // TODO See if this plays well with ConnectionMap<UID> manipulation below
                 return;
             }
         };
 
+        let probe = match settings.probe.map(|probe| Probe::new(core, token, probe)) {
+            Some(Ok(probe)) => Some(probe),
+            Some(Err(e)) => {
+                debug!(
+                    "{:?} - Failed to initialize liveness probe: {:?} - killing \
+                     ActiveConnection to {:?}",
+                    our_id, e, their_id
+                );
+                heartbeat.terminate(core);
+                let _ = poll.deregister(&socket);
+                let reason = DisconnectReason::ConnectionLost;
+                event_tx.send(Event::LostPeer(their_id, reason, 0));
+                return;
+            }
+            None => None,
+        };
+
+        if features.timestamps {
+            socket.enable_timestamps(core.wall_clock());
+        }
+        socket.set_memory_budget(core.memory_budget().clone());
+        socket.set_clock(core.wall_clock());
+        socket.set_queue_limit(settings.max_queued_bytes);
+        let bandwidth = RateLimiter::default();
+        socket.set_throttle(Throttle::new(vec![
+            core.bandwidth().clone(),
+            bandwidth.clone(),
+        ]));
+        let inbound = settings
+            .inbound_limits
+            .map(|limits| InboundRate::new(limits, core.now()));
+
+        let their_addr = socket.peer_addr().ok();
+        let traffic_origin = (socket.bytes_written(), socket.bytes_read());
         let state = Rc::new(RefCell::new(ActiveConnection {
             token,
             socket,
@@ -80,11 +405,49 @@ impl<UID: Uid> ActiveConnection<UID> {
             our_id,
             their_id,
             their_role,
+            direction,
+            their_addr,
             event_tx,
             heartbeat,
+            probe,
+            settings,
+            their_listeners: Vec::new(),
+            advertisement: Advertisement::default(),
+            tag: 0,
+            inbound,
+            frame_deadline: None,
+            stats: PeerStats::default(),
+            traffic_origin,
+            connected_at: core.now(),
+            features,
+            probe_times: ProbeTimes::default(),
+            batch: Vec::new(),
+            batch_charge: Charge::default(),
+            promotion: Promotion::default(),
+            promotion_check: PromotionCheck::default(),
+            listener_checks: ListenerChecks::default(),
+            closing: None,
+            goodbye: None,
+            lost_reason: DisconnectReason::ConnectionLost,
+            silence: None,
+            requests: PendingRequests::default(),
+            read_on_posted: false,
+            bandwidth,
+            drained: None,
+            #[cfg(feature = "relay")]
+            relay_congested: false,
+            upgrade: Upgrade::default(),
         }));
 
+        let handed_over = predecessor.and_then(|predecessor| {
+            debug!(
+                "{:?} - Already connected to {:?} - replacing the old connection",
+                our_id, their_id
+            );
+            supersede::<UID>(core, poll, predecessor)
+        });
         let _ = core.insert_state(token, state.clone());
+        *connected_peers(core, their_role) += 1;
 
         let mut state_mut = state.borrow_mut();
         {
@@ -103,207 +466,3993 @@ impl<UID: Uid> ActiveConnection<UID> {
                 guard.get(&their_id)
             );
         }
-        let _ = state_mut.event_tx.send(event);
+        match handed_over {
+            Some((tag, unsent)) => {
+                state_mut.tag = tag;
+                for (msg, priority) in unsent {
+                    state_mut.write(core, poll, Some((Message::Data(msg), priority)));
+                    if !core.has_state(token) {
+                        return;
+                    }
+                }
+            }
+            None => {
+                state_mut.send_event(event);
+            }
+        }
+        state_mut.schedule_latency_probe(core);
+        if state_mut.settings.retain_unsent {
+            state_mut.resend_retained(core, poll);
+        }
         state_mut.read(core, poll);
     }
 
+    /// Stops the connection's children and leaves the event loop, which cancels its timers,
+    /// telling the learned heartbeat intervals whether the connection `died`.
+    fn release(&mut self, core: &mut Core, poll: &Poll, died: bool) {
+        if self.silence.take().is_some() {
+            let token = self.token;
+            let _ = with_heartbeat_intervals(core, |intervals| {
+                if died {
+                    intervals.died(token)
+                } else {
+                    intervals.abandoned(token)
+                }
+            });
+        }
+        self.frame_deadline = None;
+        let (children, _) = self.promotion_check.take();
+        terminate_children(core, poll, children);
+        let children = self.listener_checks.take();
+        terminate_children(core, poll, children);
+        self.close_old_socket(core, poll);
+        let _ = poll.deregister(&self.socket);
+        if core.remove_state(self.token) {
+            *connected_peers(core, self.their_role) -= 1;
+        }
+        core.record(self.token, RecordedEventKind::Disconnected);
+    }
+
+    /// Closes the connection in favour of a newer one to the same peer, see
+    /// `DuplicateConnectionPolicy::KeepNewest`. The peer isn't reported lost: the newer connection
+    /// takes over the returned tag and messages not sent yet.
+    fn supersede(&mut self, core: &mut Core, poll: &Poll) -> (u64, Vec<(Vec<u8>, Priority)>) {
+        self.release(core, poll, false);
+        self.flush_batch();
+        self.abandon_requests();
+        let unsent = self
+            .socket
+            .take_unsent_data()
+            .into_iter()
+            .map(|(priority, msg)| (msg, priority))
+            .collect();
+        (self.tag, unsent)
+    }
+
+    /// Queues the messages retained from a previous connection to the peer, if it was lost
+    /// recently enough.
+    fn resend_retained(&mut self, core: &mut Core, poll: &Poll) {
+        let msgs = match core.get_state(RETAINED_QUEUES_TOKEN) {
+            Some(state) => {
+                let mut state = state.borrow_mut();
+                match state.as_any().downcast_mut::<RetainedQueues<UID>>() {
+                    Some(retained_queues) => retained_queues.take(&self.their_id, core.now()),
+                    None => return,
+                }
+            }
+            None => return,
+        };
+        for (msg, priority) in msgs {
+            self.write(core, poll, Some((Message::Data(msg), priority)));
+            if !core.has_state(self.token) {
+                // Lost again, the rest has been retained anew.
+                return;
+            }
+        }
+    }
+
+    /// Hands the messages which haven't started to be written yet to the `RetainedQueues`, to be
+    /// sent again if the peer reconnects in time.
+    fn retain_unsent(&mut self, core: &mut Core) {
+        let msgs: Vec<_> = self
+            .socket
+            .take_unsent_data()
+            .into_iter()
+            .map(|(priority, msg)| (msg, priority))
+            .collect();
+        if let Some(state) = core.get_state(RETAINED_QUEUES_TOKEN) {
+            let mut state = state.borrow_mut();
+            if let Some(retained_queues) = state.as_any().downcast_mut::<RetainedQueues<UID>>() {
+                retained_queues.retain(core, self.their_id, msgs);
+            }
+        }
+    }
+
     fn read(&mut self, core: &mut Core, poll: &Poll) {
+        self.socket.set_read_budget(self.settings.read_budget);
+        if let Some(old) = self.upgrade.unread_old() {
+            old.set_read_budget(self.settings.read_budget);
+        }
+        self.read_frames(core, poll);
+        // Without a delay a batch takes no more than what we could read in one go.
+        if self
+            .settings
+            .event_batching
+            .map_or(false, |batching| batching.max_delay_us == 0)
+        {
+            self.flush_batch();
+        }
+        // What was read may have been the relay granting us more of its window.
+        #[cfg(feature = "relay")]
+        {
+            if self.socket.is_relay_window_shut() && core.has_state(self.token) {
+                self.write(core, poll, None);
+            }
+        }
+        // Or the old one granting us enough for our last frame on it, see `Upgrade`.
+        self.write_old_socket(core, poll, None);
+    }
+
+    fn read_frames(&mut self, core: &mut Core, poll: &Poll) {
         loop {
-            match self.socket.read::<Message<UID>>() {
+            if core.has_timeout(self.token, READ_PAUSE_TIMER_ID) {
+                return;
+            }
+            // The peer moved onto the new connection, which is read from once we took it up.
+            if self.upgrade.their_switch {
+                return;
+            }
+
+            // A frame stays charged to the memory budget until it is delivered, or for as long as
+            // the application holds on to a shared payload.
+            let (res, charge) = match self.reader().read_frame() {
+                Ok(Some((frame, charge))) => match self.meter_inbound(core, frame) {
+                    Ok(payload) => {
+                        self.stats.msgs_received += 1;
+                        let event = Event::NewSharedMessage(
+                            self.their_id,
+                            self.their_role,
+                            payload.hold(charge),
+                            self.tag,
+                        );
+                        self.send_event(event);
+                        self.user_msg_received(core, poll);
+                        continue;
+                    }
+                    Err(frame) => (decode_message::<Message<UID>>(&frame).map(Some), charge),
+                },
+                res => (res.map(|_| None), Charge::default()),
+            };
+            match res {
                 Ok(Some(Message::Data(data))) => {
-                    let _ =
-                        self.event_tx
-                            .send(Event::NewMessage(self.their_id, self.their_role, data));
-                    self.reset_receive_heartbeat(core, poll);
+                    self.stats.msgs_received += 1;
+                    self.deliver(core, data, charge);
+                    self.user_msg_received(core, poll);
                 }
                 Ok(Some(Message::Heartbeat)) => {
                     self.reset_receive_heartbeat(core, poll);
                 }
+                // Probes and their answers don't count as activity for the heartbeat. An answer
+                // only tells the probe that the peer is still there.
+                Ok(Some(Message::Probe)) => {
+                    self.write(core, poll, Some((Message::ProbeAck, CONTROL_PRIORITY)));
+                }
+                Ok(Some(Message::ProbeAck)) => {
+                    if let Some(rtt) = self.probe_times.answered(core.now()) {
+                        self.stats.latency.record(rtt);
+                        self.stats.smoothed_rtt = Some(smooth_rtt(self.stats.smoothed_rtt, rtt));
+                    }
+                    self.silence_survived(core);
+                    self.resume_survived(core);
+                    self.reset_probe(core, poll);
+                }
+                Ok(Some(Message::ContactInfoUpdate(listeners))) => {
+                    self.update_their_listeners(core, poll, listeners);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::PromoteToNode(listeners))) => {
+                    self.check_promotion(core, poll, listeners);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Retiring(alternatives))) => {
+                    self.send_event(Event::PeerRetiring(self.their_id, alternatives));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::PromotionFailed)) if !self.promotion.is_announced() => {
+                    if self.violated(core, poll, ProtocolViolation::UnsolicitedPromotionFailed) {
+                        return;
+                    }
+                }
+                Ok(Some(Message::PromotionFailed)) => {
+                    self.promotion.refused();
+                    let event = Event::PromotionFailed {
+                        peer_id: self.their_id,
+                    };
+                    self.send_event(event);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Request(request_id, data))) => {
+                    self.stats.msgs_received += 1;
+                    let event = Event::Request {
+                        peer_id: self.their_id,
+                        request_id: RequestId(request_id),
+                        data,
+                    };
+                    self.send_event(event);
+                    self.user_msg_received(core, poll);
+                }
+                Ok(Some(Message::Response(request_id, data))) => {
+                    self.stats.msgs_received += 1;
+                    if let Err(violation) = self.receive_response(RequestId(request_id), data) {
+                        if self.violated(core, poll, violation) {
+                            return;
+                        }
+                    }
+                    self.user_msg_received(core, poll);
+                }
+                Ok(Some(Message::Goodbye(reason))) => {
+                    self.lost_reason = DisconnectReason::RemoteRequested(reason);
+                    return self.terminate(core, poll);
+                }
+                Ok(Some(Message::UpgradeRequest(listeners))) => {
+                    self.upgrade_requested(core, poll, listeners);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::UpgradeOffer(listeners))) => {
+                    if let Stage::Requested = *self.upgrade.stage() {
+                        self.upgrade.set_stage(Stage::Dialling);
+                        self.dial_upgrade(core, poll, listeners);
+                    }
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::UpgradeDial)) => {
+                    let dial = match *self.upgrade.stage() {
+                        Stage::Awaiting => !self.upgrade.requester,
+                        _ => false,
+                    };
+                    if dial {
+                        self.upgrade.set_stage(Stage::Dialling);
+                        let listeners = mem::replace(&mut self.upgrade.their_listeners, Vec::new());
+                        self.dial_upgrade(core, poll, listeners);
+                    }
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::UpgradeFailed)) => {
+                    let under_way = match *self.upgrade.stage() {
+                        Stage::Idle | Stage::Switching(_) => false,
+                        _ => true,
+                    };
+                    if under_way {
+                        self.upgrade_failed();
+                    }
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                // The peer moved onto the new connection before we took it up.
+                Ok(Some(Message::UpgradeSwitch)) if self.upgrade.awaits_connection() => {
+                    self.upgrade.their_switch = true;
+                    let timeout = Duration::from_secs(UPGRADE_SWITCH_TIMEOUT_SECS);
+                    let timer = CoreTimer::new(self.token, UPGRADE_TIMER_ID);
+                    if let Err(e) = core.set_timeout(timeout, timer) {
+                        debug!("{:?} - Failed to schedule upgrade deadline: {:?}", self.our_id, e);
+                    }
+                    return;
+                }
+                // Everything on the old socket has been read, the new one is read from now on.
+                Ok(Some(Message::UpgradeSwitch)) if self.upgrade.reads_old() => {
+                    if let Some(switch) = self.upgrade.switch_mut() {
+                        switch.received = true;
+                    }
+                    self.old_socket_read_out(core);
+                    self.finish_switch(core, poll);
+                }
                 Ok(Some(message)) => {
                     debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
+                    if self.violated(core, poll, ProtocolViolation::HandshakeMessage) {
+                        return;
+                    }
                     self.reset_receive_heartbeat(core, poll);
                 }
-                Ok(None) => return,
+                Ok(None) if self.reader().is_read_stalled() => return self.retry_read(core),
+                Ok(None) if self.reader().read_paced_for().is_some() => return self.pace_read(core),
+                Ok(None) if self.reader().is_read_budget_spent() => {
+                    self.watch_partial_frame(core);
+                    return self.read_on_later(core);
+                }
+                Ok(None) => return self.watch_partial_frame(core),
+                // The new socket is read from, short of what the old one still had.
+                Err(e) if self.upgrade.reads_old() => {
+                    debug!("{:?} - Failed to read from old socket: {:?}", self.our_id, e);
+                    self.close_old_socket(core, poll);
+                    self.old_socket_read_out(core);
+                }
                 Err(e) => {
                     debug!("{:?} - Failed to read from socket: {:?}", self.our_id, e);
+                    self.lost_reason = common_lost_reason(&e);
                     return self.terminate(core, poll);
                 }
             }
         }
     }
 
-    #[cfg(not(test))]
-    /// Helper function that returns a socket address of the connection
-    pub fn peer_addr(&self) -> ::Res<SocketAddr> {
-        use main::CrustError;
-        self.socket.peer_addr().map_err(CrustError::Common)
+    /// Reads on in the next iteration of the event loop, as this one used up the read budget
+    /// while the peer may have sent more. The events of the other peers and the timers due are
+    /// handled in between.
+    fn read_on_later(&mut self, core: &mut Core) {
+        if self.read_on_posted {
+            return;
+        }
+        let token = self.token;
+        let res = core.post(move |core, poll| {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                connection.read_on_posted = false;
+                connection.ready(core, poll, Ready::readable());
+            }
+        });
+        match res {
+            Ok(()) => self.read_on_posted = true,
+            Err(e) => debug!("{:?} - Failed to schedule reading on: {:?}", self.our_id, e),
+        }
     }
 
-    #[cfg(test)]
-    // TODO(nbaksalyar) find a better way to mock connection IPs
-    pub fn peer_addr(&self) -> ::Res<SocketAddr> {
-        use std::str::FromStr;
-        Ok(unwrap!(FromStr::from_str("192.168.0.1:0")))
+    /// Tries reading again in a while, as it stopped for want of memory. Meanwhile a frame being
+    /// read is not held to its deadline, as it can't arrive: it gets a new one once we read on.
+    fn retry_read(&mut self, core: &mut Core) {
+        if self.frame_deadline.take().is_some() {
+            let _ = core.cancel_timeout(self.token, FRAME_TIMER_ID);
+        }
+        let timer = CoreTimer::new(self.token, MEMORY_RETRY_TIMER_ID);
+        if let Err(e) = core.set_timeout(Duration::from_millis(MEMORY_RETRY_MS), timer) {
+            debug!("{:?} - Failed to schedule reading again: {:?}", self.our_id, e);
+        }
     }
 
-    pub fn peer_kind(&self) -> CrustUser {
-        self.their_role
+    /// Reads on once the bandwidth limits allow, as the peer sends faster than they do. Like in
+    /// `retry_read`, a frame being read meanwhile is not held to its deadline.
+    fn pace_read(&mut self, core: &mut Core) {
+        let wait = match self.reader().read_paced_for() {
+            Some(wait) => wait,
+            None => return,
+        };
+        if self.frame_deadline.take().is_some() {
+            let _ = core.cancel_timeout(self.token, FRAME_TIMER_ID);
+        }
+        let timer = CoreTimer::new(self.token, READ_PAUSE_TIMER_ID);
+        if let Err(e) = core.set_timeout(wait, timer) {
+            debug!("{:?} - Failed to pace reads: {:?}", self.our_id, e);
+        }
     }
 
-    fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message<UID>, Priority)>) {
-        if let Err(e) = self.socket.write(poll, self.token, msg) {
-            debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+    /// Drops the messages queued for the peer which may be dropped, to free memory.
+    pub fn shed_droppable(&mut self, core: &mut Core) {
+        self.socket.shed_droppable();
+        let dropped_msgs = self.socket.take_dropped_msgs();
+        if dropped_msgs > 0 {
+            core.record(self.token, RecordedEventKind::MessagesDropped(dropped_msgs));
         }
     }
 
-    fn reset_receive_heartbeat(&mut self, core: &mut Core, poll: &Poll) {
-        if let Err(e) = self.heartbeat.reset_receive(core) {
-            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+    /// Delivers a data message to the application, or adds it to the batch if configured to.
+    /// Either way, `charge` is credited once the message is handed over.
+    fn deliver(&mut self, core: &mut Core, data: Vec<u8>, charge: Charge) {
+        let batching = match self.settings.event_batching {
+            Some(batching) => batching,
+            None => {
+                let event = Event::NewMessage(self.their_id, self.their_role, data, self.tag);
+                self.send_event(event);
+                return;
+            }
+        };
+
+        self.batch.push(data);
+        self.batch_charge.absorb(charge);
+        if self.batch.len() >= batching.max_batch {
+            return self.flush_batch();
+        }
+        if batching.max_delay_us == 0 || core.has_timeout(self.token, BATCH_TIMER_ID) {
+            return;
+        }
+        let us = batching.max_delay_us;
+        let delay = Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1_000);
+        if let Err(e) = core.set_timeout(delay, CoreTimer::new(self.token, BATCH_TIMER_ID)) {
+            debug!("{:?} - Failed to schedule batch delivery: {:?}", self.our_id, e);
+            self.flush_batch();
         }
     }
 
-    fn reset_send_heartbeat(&mut self, core: &mut Core, poll: &Poll) {
-        if let Err(e) = self.heartbeat.reset_send(core) {
-            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+    /// Delivers the batched messages, if any. A pending batch timer is left to expire, which at
+    /// worst delivers the next batch early.
+    fn flush_batch(&mut self) {
+        if self.batch.is_empty() {
+            return;
         }
+        let msgs = mem::replace(&mut self.batch, Vec::new());
+        let event = Event::NewMessages(self.their_id, self.their_role, msgs, self.tag);
+        self.event_tx.send(event);
+        self.batch_charge = Charge::default();
     }
-}
 
-impl<UID: Uid> State for ActiveConnection<UID> {
-    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
-        if kind.is_error() || kind.is_hup() {
-            trace!(
-                "{:?} Terminating connection to peer: {:?}. \
-                 Event reason: {:?} - Optional Error: {:?}",
-                self.our_id,
-                self.their_id,
-                kind,
-                self.socket.take_error()
-            );
-            self.terminate(core, poll);
-        } else {
-            if kind.is_writable() {
-                self.write(core, poll, None);
+    /// Sends an event about the peer, after the messages batched before it.
+    fn send_event(&mut self, event: Event<UID>) {
+        self.flush_batch();
+        self.event_tx.send(event);
+    }
+
+    /// Gives a frame received in part a deadline to arrive in full, so that the peer can't hold on
+    /// to the buffer allocated for it by trickling it in.
+    fn watch_partial_frame(&mut self, core: &mut Core) {
+        let partial = self.reader().partial_frame();
+        if self.frame_deadline == partial.map(|partial| partial.seq) {
+            return;
+        }
+        if self.frame_deadline.take().is_some() {
+            let _ = core.cancel_timeout(self.token, FRAME_TIMER_ID);
+        }
+        let partial = match partial {
+            Some(partial) => partial,
+            None => return,
+        };
+
+        let timeout = self
+            .settings
+            .frame_completion_timeout
+            .unwrap_or_else(|| Duration::from_secs(DEFAULT_FRAME_COMPLETION_TIMEOUT_SECS))
+            + Duration::from_millis(partial.len as u64 * 1000 / MIN_FRAME_BYTES_PER_SEC);
+        let timer = CoreTimer::new(self.token, FRAME_TIMER_ID);
+        match core.set_timeout(timeout, timer) {
+            Ok(()) => self.frame_deadline = Some(partial.seq),
+            Err(e) => debug!(
+                "{:?} - Failed to schedule frame deadline: {:?}",
+                self.our_id, e
+            ),
+        }
+    }
+
+    /// Accounts for a received frame, reporting the peer if it is over the inbound rate limits and
+    /// pausing reads from it if configured to, then takes its payload out if it is large enough.
+    fn meter_inbound(&mut self, core: &mut Core, frame: Vec<u8>) -> Result<SharedBuffer, Vec<u8>> {
+        let now = core.now();
+        let over_rate = match self.inbound {
+            Some(ref mut inbound) => inbound.record(now, frame.len()).map(|(msgs, bytes)| {
+                (
+                    msgs,
+                    bytes,
+                    inbound.should_report(now),
+                    inbound.limits().penalty,
+                )
+            }),
+            None => None,
+        };
+
+        if let Some((msgs_rate, bytes_rate, report, penalty)) = over_rate {
+            if report {
+                let event = Event::PeerOverRate {
+                    peer_id: self.their_id,
+                    msgs_rate,
+                    bytes_rate,
+                };
+                self.send_event(event);
             }
-            if kind.is_readable() {
-                self.read(core, poll);
+            if let Some(penalty) = penalty {
+                let timer = CoreTimer::new(self.token, READ_PAUSE_TIMER_ID);
+                if let Err(e) = core.set_timeout(penalty, timer) {
+                    debug!("{:?} - Failed to pause reads: {:?}", self.our_id, e);
+                }
             }
         }
+
+        self.take_shared_payload(frame)
     }
 
-    fn write(&mut self, core: &mut Core, poll: &Poll, data: Vec<u8>, priority: Priority) {
-        self.write(core, poll, Some((Message::Data(data), priority)));
-        self.reset_send_heartbeat(core, poll);
+    /// Takes the payload out of a large enough data frame without copying it, see
+    /// `Config::shared_payload_min_size`.
+    fn take_shared_payload(&self, frame: Vec<u8>) -> Result<SharedBuffer, Vec<u8>> {
+        match self.settings.shared_payload_min_size {
+            Some(min_len) => split_data_frame(frame, min_len),
+            None => Err(frame),
+        }
     }
 
-    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        self.heartbeat.terminate(core);
-        let _ = poll.deregister(&self.socket);
-        let _ = core.remove_state(self.token);
+    /// Helper function that returns a socket address of the connection
+    pub fn peer_addr(&self) -> ::Res<SocketAddr> {
+        self.socket.peer_addr().map_err(CrustError::Common)
+    }
+
+    /// Helper function that returns our local socket address of the connection
+    pub fn local_addr(&self) -> ::Res<SocketAddr> {
+        self.socket.local_addr().map_err(CrustError::Common)
+    }
 
+    pub fn transport(&self) -> Transport {
+        #[cfg(feature = "relay")]
         {
-            let mut guard = unwrap!(self.cm.lock());
-            if let Entry::Occupied(mut oe) = guard.entry(self.their_id) {
-                oe.get_mut().active_connection = None;
-                if oe.get().currently_handshaking == 0 {
-                    let _ = oe.remove();
-                }
+            if self.socket.is_relayed() {
+                return Transport::Relayed;
+            }
+        }
+        #[cfg(feature = "utp")]
+        {
+            if self.socket.is_utp() {
+                return Transport::Utp;
+            }
+        }
+        #[cfg(feature = "websocket")]
+        {
+            if self.socket.is_websocket() {
+                return Transport::WebSocket;
             }
-            trace!(
-                "Connection Map removed: {:?} -> {:?}",
-                self.their_id,
-                guard.get(&self.their_id)
-            );
         }
+        Transport::Tcp
+    }
 
-        let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+    /// See `NegotiatedFeatures::peer_key`.
+    pub fn peer_public_key(&self) -> Option<PublicKey> {
+        self.features.peer_key
     }
 
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
-        match self.heartbeat.timeout(core, timer_id) {
-            HeartbeatAction::Send => self.write(core, poll, Some((Message::Heartbeat, 0))),
-            HeartbeatAction::Terminate => {
-                debug!(
-                    "Dropping connection to {:?} due to peer inactivity",
-                    self.their_id
-                );
-                self.terminate(core, poll);
-            }
+    pub fn peer_kind(&self) -> CrustUser {
+        self.their_role
+    }
+
+    /// The peer and the address to reconnect to it at, if it is a node.
+    pub fn node_contact(&self) -> Option<PeerContact<UID>> {
+        if self.their_role != CrustUser::Node {
+            return None;
         }
+        self.redial_addr().ok().map(|addr| PeerContact {
+            id: self.their_id,
+            addr,
+        })
     }
 
-    fn as_any(&mut self) -> &mut Any {
-        self
+    /// The application's tag for this connection, see `Service::set_peer_tag`.
+    pub fn tag(&self) -> u64 {
+        self.tag
     }
-}
 
-struct Heartbeat {
-    recv_timeout: Timeout,
-    recv_timer: CoreTimer,
-    send_timeout: Timeout,
-    send_timer: CoreTimer,
-}
+    pub fn set_tag(&mut self, tag: u64) {
+        self.tag = tag;
+    }
 
-impl Heartbeat {
-    fn new(core: &mut Core, state_id: Token) -> ::Res<Self> {
-        let recv_timer = CoreTimer::new(state_id, 0);
-        let recv_timeout =
-            core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS), recv_timer)?;
+    pub fn stats(&self, now: Instant) -> PeerStats {
+        let (written_before, read_before) = self.traffic_origin;
+        PeerStats {
+            bytes_sent: self.stats.bytes_sent + self.socket.bytes_written() - written_before,
+            bytes_received: self.stats.bytes_received + self.socket.bytes_read() - read_before,
+            queued_bytes: self.socket.queued_ahead_of(Priority::max_value()),
+            one_way_latency: self.socket.one_way_latency(),
+            features: self.features,
+            connected_for: now - self.connected_at,
+            ..self.stats
+        }
+    }
 
-        let send_timer = CoreTimer::new(state_id, 1);
-        let send_timeout =
-            core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), send_timer)?;
+    /// Carries over the stats of a previous connection to the same peer.
+    pub fn restore_stats(&mut self, stats: PeerStats) {
+        self.stats = stats;
+    }
 
-        Ok(Heartbeat {
-            recv_timeout,
-            recv_timer,
-            send_timeout,
-            send_timer,
-        })
+    pub fn reset_stats(&mut self) {
+        self.stats = PeerStats::default();
+        self.traffic_origin = (self.socket.bytes_written(), self.socket.bytes_read());
+        self.socket.reset_one_way_latency();
     }
 
-    fn timeout(&mut self, core: &mut Core, timer_id: u8) -> HeartbeatAction {
-        if timer_id == self.recv_timer.timer_id {
-            HeartbeatAction::Terminate
-        } else {
-            core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer)
-                .map(|t| {
-                    self.send_timeout = t;
-                    HeartbeatAction::Send
-                })
-                .unwrap_or_else(|e| {
-                    debug!("Failed to reschedule heartbeat send timer: {:?}", e);
-                    HeartbeatAction::Terminate
-                })
+    /// Holds the traffic with the peer to these rates too, in bytes per second, see
+    /// `Service::set_peer_bandwidth_limits`.
+    pub fn set_bandwidth_limits(
+        &mut self,
+        upload_bps: Option<u64>,
+        download_bps: Option<u64>,
+        now: Instant,
+    ) {
+        self.bandwidth.set_limits(upload_bps, download_bps, now);
+    }
+
+    pub fn bandwidth_limits(&self) -> (Option<u64>, Option<u64>) {
+        self.bandwidth.limits()
+    }
+
+    /// Flushes the queued messages and then closes the connection, keeping the peer in the parked
+    /// table instead of reporting it lost.
+    pub fn park(&mut self, core: &mut Core, poll: &Poll, parked: ParkedPeers<UID>) {
+        match self.redial_addr() {
+            Ok(addr) => self.closing = Some(Closing::Park(parked, addr)),
+            Err(e) => debug!(
+                "{:?} - Cannot park {:?} without its address: {:?}",
+                self.our_id, self.their_id, e
+            ),
         }
+        self.write(core, poll, None);
     }
 
-    fn reset_receive(&mut self, core: &mut Core) -> ::Res<()> {
-        let _ = core.cancel_timeout(&self.recv_timeout);
-        self.recv_timeout = core.set_timeout(
-            Duration::from_millis(INACTIVITY_TIMEOUT_MS),
-            self.recv_timer,
-        )?;
-        Ok(())
+    /// Address at which the peer relays connections for us, if it does, see
+    /// `NegotiatedFeatures::peer_relays`. Peers we reach through a relay themselves are left out.
+    #[cfg(feature = "relay")]
+    pub fn relay_addr(&self) -> Option<SocketAddr> {
+        if !self.features.peer_relays || self.socket.is_relayed() {
+            return None;
+        }
+        self.redial_addr().ok()
     }
 
-    fn reset_send(&mut self, core: &mut Core) -> ::Res<()> {
-        let _ = core.cancel_timeout(&self.send_timeout);
-        self.send_timeout =
-            core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer)?;
-        Ok(())
+    /// Address to reconnect to the peer at: the listener it advertised on the interface it is
+    /// connected from, or else the address of this connection.
+    fn redial_addr(&self) -> ::Res<SocketAddr> {
+        Ok(self.listener_at(self.peer_addr()?))
     }
 
-    fn terminate(&mut self, core: &mut Core) {
-        let _ = core.cancel_timeout(&self.recv_timeout);
-        let _ = core.cancel_timeout(&self.send_timeout);
+    /// The listener the peer advertised on the interface of `peer_addr`, or else `peer_addr`.
+    fn listener_at(&self, peer_addr: SocketAddr) -> SocketAddr {
+        self.their_listeners
+            .iter()
+            .find(|addr| addr.ip() == peer_addr.ip())
+            .cloned()
+            .unwrap_or(peer_addr)
     }
-}
 
-enum HeartbeatAction {
-    Send,
-    Terminate,
+    /// Hands a node lost to the network to `Reconnects`, if it runs, see `Config::reconnect`. The
+    /// address of the connection is the one it was made at, as the socket may no longer know it.
+    fn reconnect_later(&self, core: &mut Core) {
+        if self.their_role != CrustUser::Node || self.socket.is_relayed() {
+            return;
+        }
+        let addr = match self.their_addr {
+            Some(their_addr) => self.listener_at(their_addr),
+            None => return,
+        };
+        if let Some(state) = core.get_state(RECONNECTS_TOKEN) {
+            let mut state = state.borrow_mut();
+            if let Some(reconnects) = state.as_any().downcast_mut::<Reconnects<UID>>() {
+                reconnects.lost(core, self.their_id, addr);
+            }
+        }
+    }
+
+    /// Tells the peer that our listeners have changed. Updates are sent at most once per
+    /// `CONTACT_INFO_UPDATE_INTERVAL_MS`; only the latest of the changes in between is sent.
+    /// Peers which didn't take up `ContactUpdateExtension` couldn't decode them, and are left to
+    /// learn of our listeners as they connect anew.
+    pub fn advertise_listeners(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        listeners: Vec<SocketAddr>,
+    ) {
+        if !self.features.contact_updates || self.closing.is_some() {
+            return;
+        }
+
+        let interval = Duration::from_millis(CONTACT_INFO_UPDATE_INTERVAL_MS);
+        let now = core.now();
+        let since_sent = self.advertisement.last_sent.map(|sent_at| now - sent_at);
+        match since_sent {
+            Some(elapsed) if elapsed < interval => {
+                self.advertisement.pending = Some(listeners);
+                if !core.has_timeout(self.token, CONTACT_INFO_TIMER_ID) {
+                    let timer = CoreTimer::new(self.token, CONTACT_INFO_TIMER_ID);
+                    if let Err(e) = core.set_timeout(interval - elapsed, timer) {
+                        debug!(
+                            "{:?} - Failed to schedule listener advertisement: {:?}",
+                            self.our_id, e
+                        );
+                    }
+                }
+            }
+            _ => self.send_listeners(core, poll, listeners),
+        }
+    }
+
+    fn send_listeners(&mut self, core: &mut Core, poll: &Poll, listeners: Vec<SocketAddr>) {
+        self.advertisement.last_sent = Some(core.now());
+        self.advertisement.pending = None;
+        self.write(core, poll, Some((Message::ContactInfoUpdate(listeners), CONTROL_PRIORITY)));
+    }
+
+    /// Tells the peer we are retiring and which peers to turn to instead. Peers which didn't take
+    /// up `RetirementExtension` couldn't decode it, and learn of it when we close the connection.
+    pub fn announce_retirement(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        alternatives: Vec<SocketAddr>,
+    ) {
+        if !self.features.retirement || self.closing.is_some() {
+            return;
+        }
+        self.write(core, poll, Some((Message::Retiring(alternatives), CONTROL_PRIORITY)));
+    }
+
+    /// Takes note of the listeners the peer advertised. Arriving on the established connection,
+    /// they are authenticated by the handshake which identified the peer, which doesn't make them
+    /// its own: the new ones are dialled, and only those reached replace the peer's addresses in
+    /// the bootstrap cache. The update is told of once they all have been.
+    fn update_their_listeners(&mut self, core: &mut Core, poll: &Poll, listeners: Vec<SocketAddr>) {
+        if listeners == self.their_listeners {
+            return;
+        }
+
+        let new_listeners: Vec<_> = listeners
+            .iter()
+            .filter(|addr| !self.their_listeners.contains(addr))
+            .cloned()
+            .collect();
+        self.their_listeners = listeners;
+        let peer_addr = self.peer_addr().ok();
+        self.listener_checks.updated(peer_addr);
+
+        // Listeners already being dialled, for this peer or another, aren't dialled again, and
+        // beyond a few checks at once the rest are skipped.
+        let token = self.token;
+        let checks = ReachabilityChecks::of(core);
+        for (addr, dialling) in new_listeners
+            .into_iter()
+            .filter_map(|addr| checks.reserve(addr).map(|dialling| (addr, dialling)))
+        {
+            let finish = move |core: &mut Core, _poll: &Poll, child, res| {
+                // Released once the check is over, as this is dropped with it.
+                let _ = &dialling;
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(active_connection) =
+                        state.as_any().downcast_mut::<ActiveConnection<UID>>()
+                    {
+                        active_connection.handle_listener_check(core, child, res);
+                    }
+                }
+            };
+            if let Ok(child) = CheckReachability::start(core, poll, addr, addr, Box::new(finish)) {
+                self.listener_checks.started(child, addr);
+            }
+        }
+
+        self.report_contact_info_updates();
+    }
+
+    fn handle_listener_check(
+        &mut self,
+        core: &mut Core,
+        child: Token,
+        res: Result<SocketAddr, ()>,
+    ) {
+        if !self.listener_checks.finished(child) {
+            return;
+        }
+        if let Ok(reached) = res {
+            if self.their_listeners.contains(&reached) {
+                let (stale, acceptors) =
+                    self.listener_checks.reached(reached, &self.their_listeners);
+                let update = CacheUpdate::Replace { stale, acceptors };
+                CacheWriter::send(core, &self.settings.bootstrap_cache_name, update);
+            }
+        }
+        self.report_contact_info_updates();
+    }
+
+    fn report_contact_info_updates(&mut self) {
+        let their_id = self.their_id;
+        for _ in 0..self.listener_checks.take_updates() {
+            self.send_event(Event::PeerContactInfoUpdated(their_id));
+        }
+    }
+
+    /// Announces our listeners to the peer and asks it to treat us as a node from now on, see
+    /// `Service::promote_to_node`.
+    pub fn promote_to_node(&mut self, core: &mut Core, poll: &Poll, listeners: &[SocketAddr]) {
+        if self.closing.is_some() || !self.promotion.should_send(listeners, core.now()) {
+            return;
+        }
+        let msg = Message::PromoteToNode(listeners.to_vec());
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    /// Checks that the peer accepts connections at one of the listeners it announced before
+    /// treating it as a node. Arriving on the established connection, the request is authenticated
+    /// by the handshake which identified the peer.
+    fn check_promotion(&mut self, core: &mut Core, poll: &Poll, listeners: Vec<SocketAddr>) {
+        if self.their_role == CrustUser::Node {
+            trace!("{:?} - {:?} is a node already", self.our_id, self.their_id);
+            return;
+        }
+        if !self.promotion_check.may_start(core.now()) {
+            debug!(
+                "{:?} - Ignoring promotion of {:?}: too soon after the last one",
+                self.our_id, self.their_id
+            );
+            return;
+        }
+
+        // Listeners already being dialled, for this peer or another, aren't dialled again, and
+        // beyond a few checks at once the rest are skipped.
+        let require_global = self.settings.require_reachability;
+        let token = self.token;
+        let checks = ReachabilityChecks::of(core);
+        let mut children = HashSet::new();
+        for (addr, dialling) in listeners
+            .iter()
+            .filter(|addr| !require_global || ip_addr_is_global(&addr.ip()))
+            .filter_map(|addr| checks.reserve(*addr).map(|dialling| (addr, dialling)))
+        {
+            let finish = move |core: &mut Core, poll: &Poll, child, res| {
+                // Released once the check is over, as this is dropped with it.
+                let _ = &dialling;
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(active_connection) =
+                        state.as_any().downcast_mut::<ActiveConnection<UID>>()
+                    {
+                        active_connection.handle_promotion_check(core, poll, child, res);
+                    }
+                }
+            };
+            let res = CheckReachability::start(core, poll, *addr, *addr, Box::new(finish));
+            if let Ok(child) = res {
+                let _ = children.insert(child);
+            }
+        }
+
+        if children.is_empty() {
+            debug!(
+                "{:?} - No listener of {:?} to check for its promotion",
+                self.our_id, self.their_id
+            );
+            self.write(core, poll, Some((Message::PromotionFailed, CONTROL_PRIORITY)));
+        } else {
+            self.promotion_check.started(children, listeners, core.now());
+        }
+    }
+
+    fn handle_promotion_check(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        child: Token,
+        res: Result<SocketAddr, ()>,
+    ) {
+        if !self.promotion_check.finished(child) {
+            return;
+        }
+        let reachable = match res {
+            Ok(addr) => addr,
+            Err(()) => {
+                if !self.promotion_check.is_running() {
+                    debug!(
+                        "{:?} - None of the listeners of {:?} could be reached",
+                        self.our_id, self.their_id
+                    );
+                    let _ = self.promotion_check.take();
+                    self.write(core, poll, Some((Message::PromotionFailed, CONTROL_PRIORITY)));
+                }
+                return;
+            }
+        };
+
+        let rtt = self.promotion_check.elapsed(core.now());
+        let (children, listeners) = self.promotion_check.take();
+        terminate_children(core, poll, children);
+
+        *connected_peers(core, self.their_role) -= 1;
+        *connected_peers(core, CrustUser::Node) += 1;
+        self.their_role = CrustUser::Node;
+        self.their_listeners = listeners;
+        let update = CacheUpdate::Acceptor {
+            addr: reachable,
+            rtt,
+            last_seen: now_secs(),
+        };
+        CacheWriter::send(core, &self.settings.bootstrap_cache_name, update);
+        let their_id = self.their_id;
+        core.audit(|| AuditRecord::new(AuditEvent::Promoted, Some(reachable)).peer(&their_id));
+        let event = Event::PeerPromoted {
+            peer_id: self.their_id,
+        };
+        self.send_event(event);
+    }
+
+    /// Starts moving the connection onto a direct one, see `Service::upgrade_connection`. We ask
+    /// the peer, offering our listeners, and dial those it offers in answer.
+    pub fn upgrade(&mut self, core: &mut Core, poll: &Poll) {
+        let peer_id = self.their_id;
+        match *self.upgrade.stage() {
+            Stage::Idle => (),
+            // Reported once it is over.
+            Stage::Requested | Stage::Dialling | Stage::Awaiting => {
+                self.upgrade.asked = true;
+                return;
+            }
+            // The connection has just been upgraded, and can't be again before the old one closed.
+            Stage::Switching(_) => {
+                return self.send_event(Event::ConnectionUpgradeFailed { peer_id });
+            }
+        }
+        if !self.features.upgrades || self.closing.is_some() {
+            return self.send_event(Event::ConnectionUpgradeFailed { peer_id });
+        }
+        self.upgrade.asked = true;
+        let listeners =
+            with_upgrades::<UID, _, _>(core, |upgrades, _| upgrades.listeners())
+                .unwrap_or_default();
+        self.upgrade.requester = true;
+        self.upgrade.listeners_sent = !listeners.is_empty();
+        self.upgrade.set_stage(Stage::Requested);
+        let msg = Message::UpgradeRequest(listeners);
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    /// Answers the peer asking to move the connection onto a direct one with our listeners, for it
+    /// to dial. Should we have asked at the same time, the request of whichever of us has the
+    /// lower ID stands.
+    fn upgrade_requested(&mut self, core: &mut Core, poll: &Poll, listeners: Vec<SocketAddr>) {
+        let busy = match *self.upgrade.stage() {
+            Stage::Idle => false,
+            Stage::Requested if self.our_id < self.their_id => return,
+            Stage::Requested => false,
+            Stage::Dialling | Stage::Awaiting | Stage::Switching(_) => true,
+        };
+        if busy || !self.features.upgrades || self.closing.is_some() {
+            return self.write(core, poll, Some((Message::UpgradeFailed, CONTROL_PRIORITY)));
+        }
+        let our_listeners =
+            with_upgrades::<UID, _, _>(core, |upgrades, _| upgrades.listeners())
+                .unwrap_or_default();
+        self.upgrade.requester = false;
+        self.upgrade.their_listeners = listeners;
+        self.upgrade.set_stage(Stage::Awaiting);
+        let msg = Message::UpgradeOffer(our_listeners);
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    /// Dials the listeners of the peer for the connection to move onto, see `Upgrades::dial`.
+    fn dial_upgrade(&mut self, core: &mut Core, poll: &Poll, listeners: Vec<SocketAddr>) {
+        let peer = self.their_id;
+        let dialling = with_upgrades::<UID, _, _>(core, |upgrades, core| {
+            upgrades.dial(core, poll, peer, listeners)
+        });
+        if dialling != Some(true) {
+            self.upgrade_dial_over(core, poll);
+        }
+    }
+
+    /// Follows up on dialling the peer for the upgrade having failed. If we asked for it, and
+    /// offered our listeners, the peer is asked to dial them in turn. Otherwise the upgrade is
+    /// given up on.
+    pub fn upgrade_dial_over(&mut self, core: &mut Core, poll: &Poll) {
+        match *self.upgrade.stage() {
+            Stage::Dialling => (),
+            _ => return,
+        }
+        if self.upgrade.requester && self.upgrade.listeners_sent {
+            self.upgrade.listeners_sent = false;
+            self.upgrade.set_stage(Stage::Awaiting);
+            return self.write(core, poll, Some((Message::UpgradeDial, CONTROL_PRIORITY)));
+        }
+        self.upgrade_failed();
+        self.write(core, poll, Some((Message::UpgradeFailed, CONTROL_PRIORITY)));
+    }
+
+    /// Gives up on the upgrade, which leaves the connection as it was, and tells the application
+    /// if it asked for it.
+    fn upgrade_failed(&mut self) {
+        let _ = self.upgrade.finish();
+        if mem::replace(&mut self.upgrade.asked, false) {
+            let peer_id = self.their_id;
+            self.send_event(Event::ConnectionUpgradeFailed { peer_id });
+        }
+    }
+
+    /// Moves the connection onto `socket`, a new connection to the peer, if the upgrade expects
+    /// one, or else hands it back. What hasn't started to be written on the old socket goes out on
+    /// the new one, after our `Message::UpgradeSwitch` on the old one, which is read until the
+    /// peer's arrives.
+    fn adopt(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        mut socket: Socket,
+        features: NegotiatedFeatures,
+    ) -> Result<(), Socket> {
+        if !self.upgrade.awaits_connection() {
+            return Err(socket);
+        }
+        #[cfg(feature = "relay")]
+        {
+            if socket.is_relayed() {
+                return Err(socket);
+            }
+        }
+
+        if features.timestamps {
+            socket.enable_timestamps(core.wall_clock());
+        }
+        socket.set_memory_budget(core.memory_budget().clone());
+        socket.set_clock(core.wall_clock());
+        socket.set_queue_limit(self.settings.max_queued_bytes);
+        socket.set_throttle(Throttle::new(vec![
+            core.bandwidth().clone(),
+            self.bandwidth.clone(),
+        ]));
+
+        let from = self.transport();
+        let (written_before, read_before) = self.traffic_origin;
+        self.stats.bytes_sent += self.socket.bytes_written() - written_before;
+        self.stats.bytes_received += self.socket.bytes_read() - read_before;
+        self.traffic_origin = (socket.bytes_written(), socket.bytes_read());
+        let mut old = mem::replace(&mut self.socket, socket);
+        old.hand_over_queue(&mut self.socket);
+        // The little left to write on the old socket isn't held to the bandwidth limits, as
+        // nothing would resume writing it once they allowed.
+        old.set_throttle(Throttle::new(Vec::new()));
+        if let Ok(addr) = self.socket.peer_addr() {
+            self.their_addr = Some(addr);
+        }
+        self.features = features;
+
+        let leg = core.get_new_token();
+        UpgradeLeg::<UID>::start(core, leg, self.token);
+        let received = mem::replace(&mut self.upgrade.their_switch, false);
+        if received {
+            let _ = core.cancel_timeout(self.token, UPGRADE_TIMER_ID);
+        }
+        self.upgrade.set_stage(Stage::Switching(Switch {
+            old,
+            leg,
+            sent: false,
+            received,
+        }));
+        if received {
+            self.old_socket_read_out(core);
+        }
+
+        let event = Event::ConnectionUpgraded {
+            peer_id: self.their_id,
+            from,
+            to: self.transport(),
+        };
+        self.upgrade.asked = false;
+        self.send_event(event);
+        self.write_old_socket(core, poll, Some(Message::UpgradeSwitch));
+        if core.has_state(self.token) {
+            self.write(core, poll, None);
+        }
+        // The peer may have written on the new socket already, which wasn't read as it waited.
+        if received && core.has_state(self.token) {
+            self.read(core, poll);
+        }
+        Ok(())
+    }
+
+    /// The socket read from: the old one of a connection moving onto a new one, until the peer's
+    /// `Message::UpgradeSwitch` arrives on it.
+    fn reader(&mut self) -> &mut Socket {
+        match self.upgrade.unread_old() {
+            Some(old) => old,
+            None => &mut self.socket,
+        }
+    }
+
+    /// Writes on the old socket of a connection moving onto a new one, which has nothing left to
+    /// write but what it started and our `Message::UpgradeSwitch`.
+    fn write_old_socket(&mut self, core: &mut Core, poll: &Poll, msg: Option<Message<UID>>) {
+        let res = match self.upgrade.switch_mut() {
+            Some(ref mut switch) if !switch.sent => {
+                let leg = switch.leg;
+                switch
+                    .old
+                    .write(poll, leg, msg.map(|msg| (msg, CONTROL_PRIORITY)))
+            }
+            _ => return,
+        };
+        match res {
+            Ok(true) => {
+                if let Some(switch) = self.upgrade.switch_mut() {
+                    switch.sent = true;
+                }
+                self.finish_switch(core, poll);
+            }
+            Ok(false) => (),
+            Err(e) => {
+                debug!("{:?} - Failed to write old socket: {:?}", self.our_id, e);
+                self.drop_old_socket(core, poll);
+            }
+        }
+    }
+
+    /// Closes the old socket once the peer's `Message::UpgradeSwitch` was read and ours written.
+    fn finish_switch(&mut self, core: &mut Core, poll: &Poll) {
+        let done = self
+            .upgrade
+            .switch_mut()
+            .map_or(false, |switch| switch.sent && switch.received);
+        if done {
+            self.close_old_socket(core, poll);
+        }
+    }
+
+    fn close_old_socket(&mut self, core: &mut Core, poll: &Poll) {
+        if self.upgrade.switch_mut().is_none() {
+            return;
+        }
+        if let Some(switch) = self.upgrade.finish() {
+            let _ = poll.deregister(&switch.old);
+            let _ = core.remove_state(switch.leg);
+        }
+    }
+
+    /// Called once nothing more is read from the old socket: the deadline of a frame it had in
+    /// part is moot.
+    fn old_socket_read_out(&mut self, core: &mut Core) {
+        if self.frame_deadline.take().is_some() {
+            let _ = core.cancel_timeout(self.token, FRAME_TIMER_ID);
+        }
+    }
+
+    /// Handles the events of the old socket of a connection moving onto a new one, see
+    /// `UpgradeLeg`.
+    pub fn old_socket_ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_readable() || kind.is_error() || kind.is_hup() {
+            if self.upgrade.reads_old() {
+                self.read(core, poll);
+            } else {
+                self.drain_old_socket(core, poll);
+            }
+        }
+        if kind.is_writable() {
+            self.write_old_socket(core, poll, None);
+        }
+        if kind.is_error() || kind.is_hup() {
+            self.drop_old_socket(core, poll);
+        }
+    }
+
+    /// Gives up on the old socket of a connection which moved onto a new one. Should the peer's
+    /// `Message::UpgradeSwitch` not have arrived on it, the new one is read from regardless.
+    pub fn drop_old_socket(&mut self, core: &mut Core, poll: &Poll) {
+        let unread = self.upgrade.reads_old();
+        self.close_old_socket(core, poll);
+        if unread {
+            self.old_socket_read_out(core);
+            self.read(core, poll);
+        }
+    }
+
+    /// Reads what still arrives on the old socket once the peer moved off it, which is only the
+    /// relay it may run through granting more of its window, for our last frame to get through.
+    fn drain_old_socket(&mut self, core: &mut Core, poll: &Poll) {
+        let res = match self.upgrade.switch_mut() {
+            Some(ref mut switch) => loop {
+                match switch.old.read_frame() {
+                    Ok(Some(_)) => (),
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            },
+            None => return,
+        };
+        match res {
+            Ok(()) => self.write_old_socket(core, poll, None),
+            Err(_) => self.drop_old_socket(core, poll),
+        }
+    }
+
+    /// Says goodbye to the peer with the given reason code once everything queued has been
+    /// written, and then closes the connection. The goodbye isn't queued before, as at the
+    /// control priority it would overtake the data, which the peer doesn't read past it.
+    pub fn disconnect(&mut self, core: &mut Core, poll: &Poll, reason: u32) {
+        if self.closing.is_some() {
+            return;
+        }
+        self.closing = Some(Closing::Goodbye(reason));
+        self.goodbye = Some(reason);
+        self.write(core, poll, None);
+    }
+
+    /// Says goodbye like `disconnect` as the service shuts down, counting the connection in
+    /// `drained` if it closes once everything queued has been written, rather than being lost
+    /// first. A connection being parked already is counted once it is.
+    pub fn shut_down(&mut self, core: &mut Core, poll: &Poll, drained: Rc<Cell<usize>>) {
+        self.drained = Some(drained);
+        self.disconnect(core, poll, 0);
+    }
+
+    /// Sends data like `State::write`, returning where it stood in the write queue. The bytes
+    /// written right away, to make room for it or not, are no longer ahead of it.
+    pub fn send_with_receipt(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        data: Vec<u8>,
+        priority: Priority,
+    ) -> SendReceipt {
+        let ahead = self.socket.queued_ahead_of(priority);
+        let written = self.socket.bytes_written();
+        State::write(self, core, poll, data, priority);
+        let written = self.socket.bytes_written() - written;
+        self.socket.receipt(ahead.saturating_sub(written))
+    }
+
+    /// Like `State::write`, for a payload which may be sent to other peers too. It is queued and
+    /// written as it is, so that however many connections it goes out on, it is never copied.
+    pub fn send_shared(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        data: Arc<Vec<u8>>,
+        priority: Priority,
+    ) {
+        self.stats.msgs_sent += 1;
+        self.break_silence(core);
+        let res = self.socket.write_shared(poll, self.token, data, priority);
+        self.written(core, poll, res);
+        self.reset_send_heartbeat(core, poll);
+    }
+
+    /// Sends a request which the peer is to answer within the request timeout. A peer which didn't
+    /// take up `CorrelationExtension` couldn't tell it from garbage, so the request isn't sent and
+    /// is reported timed out straight away.
+    pub fn send_request(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        request_id: RequestId,
+        data: Vec<u8>,
+        priority: Priority,
+    ) {
+        if !self.features.correlation || self.closing.is_some() {
+            debug!(
+                "{:?} - Not sending request {:?} to {:?}: requests not supported",
+                self.our_id, request_id, self.their_id
+            );
+            let event = Event::ResponseTimedOut {
+                peer_id: self.their_id,
+                request_id,
+            };
+            return self.send_event(event);
+        }
+        let timeout = self
+            .settings
+            .request_timeout
+            .unwrap_or_else(|| Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS));
+        self.requests.insert(request_id.0, core.now() + timeout);
+        self.schedule_request_timer(core);
+
+        self.stats.msgs_sent += 1;
+        self.write(core, poll, Some((Message::Request(request_id.0, data), priority)));
+        if core.has_state(self.token) {
+            self.reset_send_heartbeat(core, poll);
+        }
+    }
+
+    /// Answers a request the peer sent us.
+    pub fn send_response(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        request_id: RequestId,
+        data: Vec<u8>,
+    ) {
+        if !self.features.correlation {
+            debug!(
+                "{:?} - Not answering request {:?} of {:?}: requests not supported",
+                self.our_id, request_id, self.their_id
+            );
+            return;
+        }
+        self.stats.msgs_sent += 1;
+        let msg = Message::Response(request_id.0, data);
+        self.write(core, poll, Some((msg, RESPONSE_PRIORITY)));
+        if core.has_state(self.token) {
+            self.reset_send_heartbeat(core, poll);
+        }
+    }
+
+    fn receive_response(
+        &mut self,
+        request_id: RequestId,
+        data: Vec<u8>,
+    ) -> Result<(), ProtocolViolation> {
+        match self.requests.answer(request_id.0) {
+            ResponseMatch::Matched => {
+                let event = Event::Response {
+                    peer_id: self.their_id,
+                    request_id,
+                    data,
+                };
+                self.send_event(event);
+            }
+            ResponseMatch::Duplicate => {
+                debug!(
+                    "{:?} - Dropping duplicate response to {:?} from {:?}",
+                    self.our_id, request_id, self.their_id
+                );
+                self.stats.duplicate_responses += 1;
+            }
+            ResponseMatch::Unmatched => {
+                debug!(
+                    "{:?} - Dropping response to unknown or expired {:?} from {:?}",
+                    self.our_id, request_id, self.their_id
+                );
+                self.stats.unmatched_responses += 1;
+            }
+            ResponseMatch::Unsolicited => return Err(ProtocolViolation::UnsolicitedResponse),
+        }
+        Ok(())
+    }
+
+    /// Counts the peer's violation of the protocol and applies `Config::protocol_violation_policy`
+    /// to it. Returns whether the connection was closed.
+    fn violated(&mut self, core: &mut Core, poll: &Poll, violation: ProtocolViolation) -> bool {
+        self.stats.protocol_violations += 1;
+        debug!(
+            "{:?} - {:?} broke the protocol: {:?}",
+            self.our_id, self.their_id, violation
+        );
+        let ban = match self.settings.violation_policy {
+            ViolationPolicy::Ignore => return false,
+            ViolationPolicy::Disconnect => None,
+            ViolationPolicy::DisconnectAndBan(mins) => Some(Duration::from_secs(mins * 60)),
+        };
+        if let Some(ban) = ban {
+            match self.socket.peer_addr() {
+                Ok(addr) => {
+                    let now = core.now();
+                    core.bans_mut().ban(addr.ip(), now + ban, now);
+                }
+                Err(e) => debug!("{:?} - Could not ban the peer: {:?}", self.our_id, e),
+            }
+        }
+        self.lost_reason = DisconnectReason::ProtocolViolation(violation);
+        self.terminate(core, poll);
+        true
+    }
+
+    /// Times the earliest pending request out, unless a timer is running already. Requests all
+    /// get the same timeout, so none expires before the one the running timer is for.
+    fn schedule_request_timer(&mut self, core: &mut Core) {
+        if core.has_timeout(self.token, REQUEST_TIMER_ID) {
+            return;
+        }
+        let deadline = match self.requests.next_deadline() {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        let now = core.now();
+        let delay = if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_millis(0)
+        };
+        if let Err(e) = core.set_timeout(delay, CoreTimer::new(self.token, REQUEST_TIMER_ID)) {
+            debug!("{:?} - Failed to schedule request timeout: {:?}", self.our_id, e);
+        }
+    }
+
+    /// Reports the requests still pending as timed out, as the connection they were sent on is
+    /// gone and their responses with it.
+    fn abandon_requests(&mut self) {
+        for request_id in self.requests.take_all() {
+            let event = Event::ResponseTimedOut {
+                peer_id: self.their_id,
+                request_id: RequestId(request_id),
+            };
+            self.send_event(event);
+        }
+    }
+
+    fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message<UID>, Priority)>) {
+        if msg.is_some() {
+            self.break_silence(core);
+        }
+        // Payloads are moved into the socket's queue and written from there as they are.
+        let res = match msg {
+            Some((Message::Data(data), priority)) => {
+                self.socket.write_data(poll, self.token, data, priority)
+            }
+            msg => self.socket.write(poll, self.token, msg),
+        };
+        self.written(core, poll, res);
+    }
+
+    /// Follows up on a write to the socket: closes the connection if it was waiting for the queue
+    /// to be flushed, or if the write failed.
+    fn written(&mut self, core: &mut Core, poll: &Poll, res: ::Res<bool>) {
+        let dropped_msgs = self.socket.take_dropped_msgs();
+        if dropped_msgs > 0 {
+            core.record(self.token, RecordedEventKind::MessagesDropped(dropped_msgs));
+        }
+
+        match res {
+            Ok(true) if self.closing.is_some() => {
+                if let Some(reason) = self.goodbye.take() {
+                    let goodbye = Message::Goodbye(reason);
+                    return self.write(core, poll, Some((goodbye, CONTROL_PRIORITY)));
+                }
+                if let Some(drained) = self.drained.take() {
+                    drained.set(drained.get() + 1);
+                }
+                self.terminate(core, poll)
+            }
+            Ok(_) => {
+                self.pace_write(core);
+                #[cfg(feature = "relay")]
+                {
+                    self.follow_relay_window();
+                }
+            }
+            Err(e) => {
+                debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+                self.lost_reason = lost_reason(&e);
+                self.terminate(core, poll);
+            }
+        }
+    }
+
+    /// Writes on once the bandwidth limits allow, if they held the last write back. The socket
+    /// isn't watched for writability meanwhile, so this timer is what resumes it.
+    fn pace_write(&mut self, core: &mut Core) {
+        let wait = match self.socket.write_paced_for() {
+            Some(wait) => wait,
+            None => return,
+        };
+        if core.has_timeout(self.token, WRITE_PACE_TIMER_ID) {
+            return;
+        }
+        let timer = CoreTimer::new(self.token, WRITE_PACE_TIMER_ID);
+        if let Err(e) = core.set_timeout(wait, timer) {
+            debug!("{:?} - Failed to pace writes: {:?}", self.our_id, e);
+        }
+    }
+
+    /// Tells the application when writing stops for want of the window of the relay the
+    /// connection runs through, and when it goes on again.
+    #[cfg(feature = "relay")]
+    fn follow_relay_window(&mut self) {
+        let congested = self.socket.is_relay_window_shut();
+        if congested != self.relay_congested {
+            self.relay_congested = congested;
+            let peer_id = self.their_id;
+            self.send_event(Event::PeerCongested { peer_id, congested });
+        }
+    }
+
+    fn reset_receive_heartbeat(&mut self, core: &mut Core, poll: &Poll) {
+        if let Err(e) = self.heartbeat.reset_receive(core) {
+            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
+            return self.terminate(core, poll);
+        }
+        self.reset_probe(core, poll);
+    }
+
+    /// Like `reset_receive_heartbeat`, for a message of the application, which also ends the
+    /// stretching of our heartbeat in `PowerMode::LowPower`.
+    fn user_msg_received(&mut self, core: &mut Core, poll: &Poll) {
+        if let Err(e) = self.heartbeat.unstretch(core) {
+            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
+            return self.terminate(core, poll);
+        }
+        self.reset_receive_heartbeat(core, poll);
+    }
+
+    fn reset_probe(&mut self, core: &mut Core, poll: &Poll) {
+        let res = match self.probe {
+            Some(ref mut probe) => probe.reset(core),
+            None => Ok(()),
+        };
+        if let Err(e) = res {
+            debug!(
+                "{:?} - Failed to reset liveness probe: {:?}",
+                self.our_id, e
+            );
+            self.terminate(core, poll);
+        }
+    }
+
+    /// Sends the peer a probe, timing the round trip until it is answered. Peers which didn't
+    /// take up `ProbeExtension` are sent a heartbeat instead, which they don't answer.
+    fn send_probe(&mut self, core: &mut Core, poll: &Poll) {
+        if !self.features.probes {
+            return self.write(core, poll, Some((Message::Heartbeat, CONTROL_PRIORITY)));
+        }
+        self.probe_times.sent(core.now());
+        self.write(core, poll, Some((Message::Probe, CONTROL_PRIORITY)));
+    }
+
+    /// Probes the peer after the system resumed from a suspend, long enough for its NAT bindings
+    /// or the peer itself to be gone. Unless the probe is answered in time, the connection is
+    /// dropped rather than left until its heartbeat times out.
+    fn probe_after_resume(&mut self, core: &mut Core, poll: &Poll) {
+        if self.closing.is_some() || core.has_timeout(self.token, RESUME_TIMER_ID) {
+            return;
+        }
+        // Without an answer to wait for, the heartbeat is left to tell whether the peer is gone.
+        if !self.features.probes {
+            return self.send_probe(core, poll);
+        }
+        let timer = CoreTimer::new(self.token, RESUME_TIMER_ID);
+        if let Err(e) = core.set_timeout(Duration::from_millis(RESUME_PROBE_TIMEOUT_MS), timer) {
+            debug!("{:?} - Failed to time resume probe: {:?}", self.our_id, e);
+            return;
+        }
+        self.send_probe(core, poll);
+    }
+
+    fn resume_survived(&mut self, core: &mut Core) {
+        let _ = core.cancel_timeout(self.token, RESUME_TIMER_ID);
+    }
+
+    fn schedule_latency_probe(&mut self, core: &mut Core) {
+        let interval = match self.settings.latency_probe_interval {
+            Some(interval) if self.features.probes => interval,
+            _ => return,
+        };
+        let timer = CoreTimer::new(self.token, LATENCY_TIMER_ID);
+        if let Err(e) = core.set_periodic_timeout(interval, timer) {
+            debug!(
+                "{:?} - Failed to schedule latency probe: {:?}",
+                self.our_id, e
+            );
+        }
+    }
+
+    /// Sends a heartbeat, unless this connection is picked to stay silent for longer to learn
+    /// whether its path survives it.
+    fn heartbeat_due(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(Silence::Quiet) = self.silence {
+            return self.end_silence(core, poll);
+        }
+        if self.silence.is_none() && self.start_silence(core) {
+            return;
+        }
+        self.write(core, poll, Some((Message::Heartbeat, CONTROL_PRIORITY)));
+    }
+
+    /// Returns whether a silence started. A silence ends with a probe, so peers which didn't take
+    /// up `ProbeExtension` aren't kept silent.
+    fn start_silence(&mut self, core: &mut Core) -> bool {
+        if self.closing.is_some() || !self.features.probes {
+            return false;
+        }
+        let ip = match self.socket.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return false,
+        };
+        let (token, now) = (self.token, core.now());
+        let gap = match with_heartbeat_intervals(core, |intervals| {
+            intervals.start_experiment(token, ip, now)
+        }) {
+            Some(Some(gap)) => gap,
+            _ => return false,
+        };
+        // A whole interval has passed in silence already.
+        let rest = gap
+            .checked_sub(self.heartbeat.period)
+            .unwrap_or_else(|| Duration::from_secs(0));
+        if let Err(e) = self.heartbeat.delay_send(core, rest) {
+            debug!("{:?} - Failed to start silence: {:?}", self.our_id, e);
+            let _ = with_heartbeat_intervals(core, |intervals| intervals.abandoned(token));
+            return false;
+        }
+        self.silence = Some(Silence::Quiet);
+        true
+    }
+
+    /// Probes the peer at the end of a silence. The probe has to be answered before the next
+    /// heartbeat is due.
+    fn end_silence(&mut self, core: &mut Core, poll: &Poll) {
+        let timer = CoreTimer::new(self.token, SILENCE_TIMER_ID);
+        match core.set_timeout(self.heartbeat.period, timer) {
+            Ok(()) => self.silence = Some(Silence::Probing),
+            Err(e) => {
+                debug!("{:?} - Failed to time silence probe: {:?}", self.our_id, e);
+                self.break_silence(core);
+            }
+        }
+        self.send_probe(core, poll);
+    }
+
+    fn silence_survived(&mut self, core: &mut Core) {
+        match self.silence.take() {
+            Some(Silence::Probing) => {
+                let _ = core.cancel_timeout(self.token, SILENCE_TIMER_ID);
+            }
+            silence => {
+                self.silence = silence;
+                return;
+            }
+        }
+        let token = self.token;
+        if let Some(Some(period)) =
+            with_heartbeat_intervals(core, |intervals| intervals.survived(token))
+        {
+            self.heartbeat.period = period;
+        }
+    }
+
+    /// Gives up the silence, if quiet so far: anything sent refreshes the NAT bindings.
+    fn break_silence(&mut self, core: &mut Core) {
+        if let Some(Silence::Quiet) = self.silence {
+            self.silence = None;
+            let token = self.token;
+            let _ = with_heartbeat_intervals(core, |intervals| intervals.abandoned(token));
+        }
+    }
+
+    fn reset_send_heartbeat(&mut self, core: &mut Core, poll: &Poll) {
+        if let Err(e) = self.heartbeat.reset_send(core) {
+            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
+            self.terminate(core, poll);
+        }
+    }
+}
+
+impl<UID: Uid> State for ActiveConnection<UID> {
+    fn name(&self) -> &'static str {
+        "ActiveConnection"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            let error = self.socket.take_error();
+            trace!(
+                "{:?} Terminating connection to peer: {:?}. \
+                 Event reason: {:?} - Optional Error: {:?}",
+                self.our_id,
+                self.their_id,
+                kind,
+                error
+            );
+            if let Ok(Some(ref error)) = error {
+                self.lost_reason = io_lost_reason(error);
+            }
+            self.terminate(core, poll);
+        } else {
+            if kind.is_writable() {
+                self.write(core, poll, None);
+            }
+            // Nothing read while draining could be delivered.
+            let draining = match self.closing {
+                Some(Closing::Drain) => true,
+                _ => false,
+            };
+            if kind.is_readable() && !draining {
+                self.read(core, poll);
+            }
+        }
+    }
+
+    fn drain(&mut self, core: &mut Core, poll: &Poll) {
+        // A connection being parked or said goodbye to is closed once flushed already.
+        if self.closing.is_none() {
+            self.closing = Some(Closing::Drain);
+        }
+        self.write(core, poll, None);
+    }
+
+    fn write(&mut self, core: &mut Core, poll: &Poll, data: Vec<u8>, priority: Priority) {
+        self.stats.msgs_sent += 1;
+        self.write(core, poll, Some((Message::Data(data), priority)));
+        self.reset_send_heartbeat(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let lost = self.closing.is_none() && lost_to_network(self.lost_reason);
+        // Only a connection lost to the network tells anything about the path, and not if it was
+        // lost to a suspend.
+        let died = lost && self.lost_reason != DisconnectReason::SuspendDetected;
+        self.release(core, poll, died);
+
+        // Enter the parked table before leaving the connection map, so the peer is always found in
+        // one of them.
+        let mut parked = None;
+        let reason = match self.closing.take() {
+            Some(Closing::Park(parked_peers, addr)) => {
+                let mut table = unwrap!(parked_peers.lock());
+                let now = core.now();
+                let stats = self.stats(now);
+                parked = Some(table.insert(self.their_id, addr, self.their_role, stats, now));
+                self.lost_reason
+            }
+            Some(Closing::Goodbye(reason)) => DisconnectReason::LocalRequested(reason),
+            Some(Closing::Drain) | None => self.lost_reason,
+        };
+        let (their_addr, their_id) = (self.their_addr, self.their_id);
+        core.audit(|| {
+            AuditRecord::new(AuditEvent::Disconnected, their_addr)
+                .peer(&their_id)
+                .detail(&reason)
+        });
+        if parked.is_none() && lost_to_network(reason) && self.settings.retain_unsent {
+            self.retain_unsent(core);
+        }
+        if lost {
+            self.reconnect_later(core);
+        }
+
+        {
+            let mut guard = unwrap!(self.cm.lock());
+            if let Entry::Occupied(mut oe) = guard.entry(self.their_id) {
+                oe.get_mut().active_connection = None;
+                if oe.get().currently_handshaking == 0 {
+                    let _ = oe.remove();
+                }
+            }
+            trace!(
+                "Connection Map removed: {:?} -> {:?}",
+                self.their_id,
+                guard.get(&self.their_id)
+            );
+        }
+
+        self.abandon_requests();
+        match parked {
+            Some(evicted) => {
+                self.send_event(Event::PeerParked(self.their_id));
+                if let Some(evicted) = evicted {
+                    let reason = DisconnectReason::ParkedPeerEvicted;
+                    self.send_event(Event::LostPeer(evicted, reason, 0));
+                }
+            }
+            None => {
+                let event = Event::LostPeer(self.their_id, reason, self.tag);
+                self.send_event(event);
+            }
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u64) {
+        if timer_id == READ_PAUSE_TIMER_ID || timer_id == MEMORY_RETRY_TIMER_ID {
+            return self.read(core, poll);
+        }
+
+        if timer_id == FRAME_TIMER_ID {
+            let seq = self.frame_deadline.take();
+            let partial = match self.reader().partial_frame() {
+                Some(partial) if Some(partial.seq) == seq => partial,
+                _ => return,
+            };
+            // While we aren't reading, the frame can't arrive. It gets a new deadline once we do.
+            if core.has_timeout(self.token, READ_PAUSE_TIMER_ID) {
+                return;
+            }
+            debug!(
+                "Dropping connection to {:?}: frame of {} bytes not complete in time, got {}",
+                self.their_id, partial.len, partial.received
+            );
+            {
+                let stats = core.stats_mut();
+                stats.slow_frames_dropped += 1;
+                stats.slow_frame_bytes_freed += partial.len as u64;
+            }
+            self.lost_reason = DisconnectReason::ProtocolError("slow frame");
+            return self.terminate(core, poll);
+        }
+
+        if timer_id == BATCH_TIMER_ID {
+            return self.flush_batch();
+        }
+
+        if timer_id == UPGRADE_TIMER_ID {
+            if self.upgrade.their_switch {
+                debug!(
+                    "Dropping connection to {:?}: peer moved onto a connection we never got",
+                    self.their_id
+                );
+                self.terminate(core, poll);
+            }
+            return;
+        }
+
+        if timer_id == WRITE_PACE_TIMER_ID {
+            return self.write(core, poll, None);
+        }
+
+        if timer_id == LATENCY_TIMER_ID {
+            if self.closing.is_none() {
+                self.send_probe(core, poll);
+            }
+            // Unless the probe lost us the connection.
+            if core.has_state(self.token) {
+                self.schedule_latency_probe(core);
+            }
+            return;
+        }
+
+        if timer_id == SILENCE_TIMER_ID {
+            if let Some(Silence::Probing) = self.silence {
+                debug!(
+                    "Dropping connection to {:?}: probe ending a silence unanswered",
+                    self.their_id
+                );
+                self.terminate(core, poll);
+            }
+            return;
+        }
+
+        if timer_id == RESUME_TIMER_ID {
+            debug!(
+                "Dropping connection to {:?}: probe sent on resuming from a suspend unanswered",
+                self.their_id
+            );
+            self.lost_reason = DisconnectReason::SuspendDetected;
+            return self.terminate(core, poll);
+        }
+
+        if timer_id == REQUEST_TIMER_ID {
+            for request_id in self.requests.expire(core.now()) {
+                let event = Event::ResponseTimedOut {
+                    peer_id: self.their_id,
+                    request_id: RequestId(request_id),
+                };
+                self.send_event(event);
+            }
+            return self.schedule_request_timer(core);
+        }
+
+        if timer_id == CONTACT_INFO_TIMER_ID {
+            if let Some(listeners) = self.advertisement.pending.take() {
+                self.send_listeners(core, poll, listeners);
+            }
+            return;
+        }
+
+        if timer_id == PROBE_TIMER_ID {
+            let action = match self.probe {
+                Some(ref mut probe) => probe.timeout(core),
+                None => return,
+            };
+            return match action {
+                HeartbeatAction::Send => self.send_probe(core, poll),
+                // Heartbeats sent in place of probes go unanswered, so only the heartbeat timeout
+                // drops the peer.
+                HeartbeatAction::Terminate if !self.features.probes => self.reset_probe(core, poll),
+                HeartbeatAction::Terminate => {
+                    debug!(
+                        "Dropping connection to {:?} due to unanswered liveness probes",
+                        self.their_id
+                    );
+                    self.terminate(core, poll);
+                }
+            };
+        }
+
+        match self.heartbeat.timeout(core, timer_id) {
+            HeartbeatAction::Send => self.heartbeat_due(core, poll),
+            HeartbeatAction::Terminate => {
+                debug!(
+                    "Dropping connection to {:?} due to peer inactivity",
+                    self.their_id
+                );
+                self.terminate(core, poll);
+            }
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+struct Heartbeat {
+    recv_timer: CoreTimer,
+    send_timer: CoreTimer,
+    /// Time after the last message sent that a heartbeat is sent.
+    period: Duration,
+    /// Longest the period is stretched to, see `stretch`.
+    ceiling: Duration,
+    /// Number of heartbeats sent in a row in `PowerMode::LowPower` with no message of the
+    /// application in between. The period doubles with each, up to the ceiling.
+    stretch: u32,
+}
+
+impl Heartbeat {
+    fn new(core: &mut Core, state_id: Token, period: Duration, ceiling: Duration) -> ::Res<Self> {
+        let recv_timer = CoreTimer::new(state_id, 0);
+        core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS), recv_timer)?;
+
+        let send_timer = CoreTimer::new(state_id, 1);
+        core.set_periodic_timeout(period, send_timer)?;
+
+        Ok(Heartbeat {
+            recv_timer,
+            send_timer,
+            period,
+            ceiling,
+            stretch: 0,
+        })
+    }
+
+    fn timeout(&mut self, core: &mut Core, timer_id: u64) -> HeartbeatAction {
+        if timer_id == self.recv_timer.timer_id {
+            return HeartbeatAction::Terminate;
+        }
+        self.stretch = match core.power_mode() {
+            PowerMode::Performance => 0,
+            PowerMode::LowPower => cmp::min(self.stretch + 1, MAX_HEARTBEAT_STRETCH),
+        };
+        let interval = self.interval();
+        core.set_periodic_timeout(interval, self.send_timer)
+            .map(|()| HeartbeatAction::Send)
+            .unwrap_or_else(|e| {
+                debug!("Failed to reschedule heartbeat send timer: {:?}", e);
+                HeartbeatAction::Terminate
+            })
+    }
+
+    /// The period, stretched while the connection is idle in `PowerMode::LowPower`. Never shorter
+    /// than the period, which may be learned to be longer than the ceiling.
+    fn interval(&self) -> Duration {
+        let mut interval = self.period;
+        for _ in 0..self.stretch {
+            if interval >= self.ceiling {
+                break;
+            }
+            interval *= 2;
+        }
+        cmp::max(self.period, cmp::min(interval, self.ceiling))
+    }
+
+    /// Goes back to the period, as the connection is in use.
+    fn unstretch(&mut self, core: &mut Core) -> ::Res<()> {
+        if self.stretch == 0 {
+            return Ok(());
+        }
+        self.reset_send(core)
+    }
+
+    fn reset_receive(&mut self, core: &mut Core) -> ::Res<()> {
+        core.set_timeout(
+            Duration::from_millis(INACTIVITY_TIMEOUT_MS),
+            self.recv_timer,
+        )?;
+        Ok(())
+    }
+
+    fn reset_send(&mut self, core: &mut Core) -> ::Res<()> {
+        self.stretch = 0;
+        core.set_periodic_timeout(self.period, self.send_timer)?;
+        Ok(())
+    }
+
+    /// Makes the next heartbeat due after `delay` rather than the period.
+    fn delay_send(&mut self, core: &mut Core, delay: Duration) -> ::Res<()> {
+        core.set_timeout(delay, self.send_timer)?;
+        Ok(())
+    }
+
+    /// Cancels the heartbeat of a connection which didn't make it into the event loop.
+    fn terminate(&mut self, core: &mut Core) {
+        for timer in &[self.recv_timer, self.send_timer] {
+            let _ = core.cancel_timeout(timer.state_id, timer.timer_id);
+        }
+    }
+}
+
+/// Probes the peer once nothing has been received for a while, and gives up after a number of
+/// unanswered probes.
+struct Probe {
+    settings: ProbeSettings,
+    timer: CoreTimer,
+    unanswered: u32,
+}
+
+impl Probe {
+    fn new(core: &mut Core, state_id: Token, settings: ProbeSettings) -> ::Res<Self> {
+        let timer = CoreTimer::new(state_id, PROBE_TIMER_ID);
+        core.set_periodic_timeout(settings.after_idle, timer)?;
+
+        Ok(Probe {
+            settings,
+            timer,
+            unanswered: 0,
+        })
+    }
+
+    fn timeout(&mut self, core: &mut Core) -> HeartbeatAction {
+        if self.unanswered >= self.settings.retries {
+            return HeartbeatAction::Terminate;
+        }
+        self.unanswered += 1;
+        core.set_periodic_timeout(self.settings.after_idle, self.timer)
+            .map(|()| HeartbeatAction::Send)
+            .unwrap_or_else(|e| {
+                debug!("Failed to reschedule liveness probe timer: {:?}", e);
+                HeartbeatAction::Terminate
+            })
+    }
+
+    fn reset(&mut self, core: &mut Core) -> ::Res<()> {
+        self.unanswered = 0;
+        core.set_periodic_timeout(self.settings.after_idle, self.timer)?;
+        Ok(())
+    }
+}
+
+enum HeartbeatAction {
+    Send,
+    Terminate,
+}
+
+/// Rate limiting of the advertisements of our listeners.
+#[derive(Default)]
+struct Advertisement {
+    last_sent: Option<Instant>,
+    pending: Option<Vec<SocketAddr>>,
+}
+
+/// Runs `f` on the `HeartbeatIntervals`, which are there if `Config::adaptive_heartbeat` is set.
+fn with_heartbeat_intervals<T, F>(core: &Core, f: F) -> Option<T>
+where
+    F: FnOnce(&mut HeartbeatIntervals) -> T,
+{
+    let state = core.get_state(HEARTBEAT_INTERVALS_TOKEN)?;
+    let mut state = state.borrow_mut();
+    state.as_any().downcast_mut::<HeartbeatIntervals>().map(f)
+}
+
+/// Why the connection is lost after failing with `error`. Errors of the socket tell whether the
+/// peer closed the connection; others, such as malformed frames, are reported as a lost
+/// connection.
+fn lost_reason(error: &CrustError) -> DisconnectReason {
+    match *error {
+        CrustError::Io(ref error) => io_lost_reason(error),
+        CrustError::Common(ref error) => common_lost_reason(error),
+        _ => DisconnectReason::ConnectionLost,
+    }
+}
+
+fn common_lost_reason(error: &CommonError) -> DisconnectReason {
+    match *error {
+        CommonError::Io(ref error) => io_lost_reason(error),
+        _ => DisconnectReason::ConnectionLost,
+    }
+}
+
+fn io_lost_reason(error: &io::Error) -> DisconnectReason {
+    match IoErrorClass::of(error) {
+        IoErrorClass::RemoteClosed => DisconnectReason::RemoteClosed,
+        _ => DisconnectReason::TransportError(error.kind()),
+    }
+}
+
+/// Whether the connection went down without either side meaning to close it.
+fn lost_to_network(reason: DisconnectReason) -> bool {
+    match reason {
+        DisconnectReason::ConnectionLost
+        | DisconnectReason::RemoteClosed
+        | DisconnectReason::TransportError(_)
+        | DisconnectReason::SuspendDetected => true,
+        _ => false,
+    }
+}
+
+/// Closes the connection with the given token in favour of a newer one to the same peer, returning
+/// what the newer one takes over, see `ActiveConnection::supersede`.
+fn supersede<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    token: Token,
+) -> Option<(u64, Vec<(Vec<u8>, Priority)>)> {
+    let state = core.get_state(token)?;
+    let mut state = state.borrow_mut();
+    let connection = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+    Some(connection.supersede(core, poll))
+}
+
+/// Which of us made the connection with the given token, see `ActiveConnection::start`.
+fn direction_of<UID: Uid>(core: &Core, token: Token) -> Option<ConnectionDirection> {
+    let state = core.get_state(token)?;
+    let mut state = state.borrow_mut();
+    let connection = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+    connection.direction
+}
+
+/// Whether the connection made in the given direction was made by whichever of us has the lower
+/// ID, or `None` if we both made it, as over a punched hole or a relay.
+fn initiated_by_lower<UID: Uid>(
+    our_id: UID,
+    their_id: UID,
+    direction: Option<ConnectionDirection>,
+) -> Option<bool> {
+    direction.map(|direction| (direction == ConnectionDirection::Outbound) == (our_id < their_id))
+}
+
+/// Gauge of the connected peers of the given kind in the stats of the event loop.
+fn connected_peers(core: &mut Core, kind: CrustUser) -> &mut usize {
+    let stats = core.stats_mut();
+    match kind {
+        CrustUser::Client => &mut stats.client_peers,
+        CrustUser::Node => &mut stats.node_peers,
+    }
+}
+
+fn terminate_children(core: &mut Core, poll: &Poll, children: Vec<Token>) {
+    for child in children {
+        if let Some(state) = core.get_state(child) {
+            state.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+/// Moves the connection with the given token onto `socket`, a new connection to the same peer,
+/// if it expects one, see `ActiveConnection::adopt`. Otherwise the socket is handed back.
+fn upgrade_to<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    token: Token,
+    socket: Socket,
+    features: NegotiatedFeatures,
+) -> Result<(), Socket> {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return Err(socket),
+    };
+    let mut state = state.borrow_mut();
+    match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+        Some(connection) => connection.adopt(core, poll, socket, features),
+        None => Err(socket),
+    }
+}
+
+/// Whether the connection with the given token expects a new connection to the peer to move
+/// onto, see `Upgrade`.
+pub fn awaits_upgrade<UID: Uid>(core: &Core, token: Token) -> bool {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return false,
+    };
+    let mut state = state.borrow_mut();
+    state
+        .as_any()
+        .downcast_mut::<ActiveConnection<UID>>()
+        .map_or(false, |connection| connection.upgrade.awaits_connection())
+}
+
+/// Tells the connection to `peer` that dialling it for the upgrade of the connection failed, see
+/// `ActiveConnection::upgrade_dial_over`.
+pub fn upgrade_dial_over<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    peer: &UID,
+) {
+    let token = match unwrap!(cm.lock()).get(peer).and_then(|cid| cid.active_connection) {
+        Some(token) => token,
+        None => return,
+    };
+    if let Some(state) = core.get_state(token) {
+        let mut state = state.borrow_mut();
+        if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+            active_connection.upgrade_dial_over(core, poll);
+        }
+    }
+}
+
+/// Asks every connected peer to treat us as a node, see `Service::promote_to_node`.
+pub fn promote_to_node<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    listeners: &[SocketAddr],
+) {
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    for token in tokens {
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>()
+            {
+                active_connection.promote_to_node(core, poll, listeners);
+            }
+        }
+    }
+}
+
+/// Probes every connected peer after the system resumed from a suspend, see `SuspendMonitor`.
+pub fn probe_after_resume<UID: Uid>(core: &mut Core, poll: &Poll, cm: &ConnectionMap<UID>) {
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    for token in tokens {
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>()
+            {
+                active_connection.probe_after_resume(core, poll);
+            }
+        }
+    }
+}
+
+/// Drops the messages queued for every connected peer, and those retained for lost peers, which
+/// may be dropped, as memory runs short, see `MemoryPressure::DropLowPriority`.
+pub fn shed_droppable_msgs<UID: Uid>(core: &mut Core, cm: &ConnectionMap<UID>) {
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    for token in tokens {
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>()
+            {
+                active_connection.shed_droppable(core);
+            }
+        }
+    }
+
+    if let Some(state) = core.get_state(RETAINED_QUEUES_TOKEN) {
+        let mut state = state.borrow_mut();
+        if let Some(retained_queues) = state.as_any().downcast_mut::<RetainedQueues<UID>>() {
+            retained_queues.shed_droppable();
+        }
+    }
+}
+
+/// Advertises our new listeners to every connected peer.
+pub fn advertise_listeners<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    listeners: &[SocketAddr],
+) {
+    // Tokens collected to avoid keeping the mutex lock alive which might lead to deadlock
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    for token in tokens {
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>()
+            {
+                active_connection.advertise_listeners(core, poll, listeners.to_vec());
+            }
+        }
+    }
+}
+
+/// Tells every connected peer we are retiring, see `Service::begin_draining`.
+pub fn announce_retirement<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    alternatives: &[SocketAddr],
+) {
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    for token in tokens {
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>()
+            {
+                active_connection.announce_retirement(core, poll, alternatives.to_vec());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use common::{
+        self, decode_message, encode_frame, CoreMessage, FrameDecoder, IoSite, ManualEventLoop,
+        TimestampTrailer, VirtualClock, FRAME_HEADER_SIZE, MAX_PAYLOAD_SIZE,
+        TIMESTAMP_TRAILER_SIZE,
+    };
+    use main::promotion::MIN_PROMOTION_INTERVAL_MS;
+    use main::{SuspendMonitor, SUSPEND_MONITOR_TOKEN};
+    use maidsafe_utilities::event_sender::MaidSafeEventCategory;
+    use mio::tcp::TcpStream;
+    use mio::PollOpt;
+    use nat::MappingContext;
+    use net2::TcpStreamExt;
+    use rand::{self, Rng, SeedableRng, XorShiftRng};
+    use std::cmp;
+    use std::collections::HashMap;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Instant;
+    use tests::UniqueId;
+
+    // Reads messages off the raw end of the link until the connection is closed.
+    fn read_msgs(stream: &mut StdTcpStream) -> Vec<Message<UniqueId>> {
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut msgs = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let mut input = match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return msgs,
+                Ok(bytes_read) => &buf[..bytes_read],
+            };
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    msgs.push(unwrap!(decode_message(&body)));
+                }
+            }
+        }
+    }
+
+    // Starts an `ActiveConnection` with the given settings on a new event loop and returns the raw
+    // end of its link.
+    fn start_connection(
+        name: &str,
+        settings: ConnectionSettings,
+    ) -> (
+        common::EventLoop,
+        mpsc::Receiver<Event<UniqueId>>,
+        StdTcpStream,
+        UniqueId,
+    ) {
+        let el = unwrap!(common::spawn_event_loop(0, Some(name)));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+
+        let their_id: UniqueId = rand::random();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let peer = connect_peer(&el, &event_rx, event_tx, cm, their_id, settings);
+
+        (el, event_rx, peer, their_id)
+    }
+
+    // Starts an `ActiveConnection` to `their_id` on the given event loop and returns the raw end of
+    // its link.
+    fn connect_peer(
+        el: &common::EventLoop,
+        event_rx: &mpsc::Receiver<Event<UniqueId>>,
+        event_tx: ::CrustEventSender<UniqueId>,
+        cm: ConnectionMap<UniqueId>,
+        their_id: UniqueId,
+        settings: ConnectionSettings,
+    ) -> StdTcpStream {
+        let (stream, peer) = link();
+        unwrap!(el.send(start_on(stream, event_tx, cm, their_id, CrustUser::Node, settings)));
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        peer
+    }
+
+    // Returns the end of a new link to run an `ActiveConnection` on, and the raw end.
+    fn link() -> (TcpStream, StdTcpStream) {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(StdTcpStream::connect(unwrap!(listener.local_addr())));
+        let (peer, _) = unwrap!(listener.accept());
+        unwrap!(peer.set_read_timeout(Some(Duration::from_secs(5))));
+        let stream = unwrap!(TcpStream::from_stream(stream));
+        // Keeps most of a burst in our write queue rather than in the kernel's buffers.
+        unwrap!(stream.set_send_buffer_size(8 * 1024));
+        (stream, peer)
+    }
+
+    // Runs `f` on the `ActiveConnection` of the given token.
+    fn with_connection<F>(el: &common::EventLoop, token: Token, f: F)
+    where
+        F: FnOnce(&mut ActiveConnection<UniqueId>, &mut Core, &Poll) + Send + 'static,
+    {
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            let state = unwrap!(core.get_state(token));
+            let mut state = state.borrow_mut();
+            let ac = unwrap!(state.as_any().downcast_mut::<ActiveConnection<UniqueId>>());
+            f(ac, core, poll);
+        })));
+    }
+
+    // Returns the message which starts an `ActiveConnection` to `their_id` on `stream`, as
+    // negotiated with a peer taking up the extensions the tests rely on.
+    fn start_on(
+        stream: TcpStream,
+        event_tx: ::CrustEventSender<UniqueId>,
+        cm: ConnectionMap<UniqueId>,
+        their_id: UniqueId,
+        their_role: CrustUser,
+        settings: ConnectionSettings,
+    ) -> CoreMessage {
+        let event = Event::ConnectSuccess(their_id, None);
+        let features = NegotiatedFeatures {
+            probes: true,
+            contact_updates: true,
+            ..NegotiatedFeatures::default()
+        };
+        start_with_event(stream, event_tx, cm, their_id, their_role, settings, event, features)
+    }
+
+    // Like `start_on`, reporting the connection with the given event as negotiated with `features`.
+    fn start_with_event(
+        stream: TcpStream,
+        event_tx: ::CrustEventSender<UniqueId>,
+        cm: ConnectionMap<UniqueId>,
+        their_id: UniqueId,
+        their_role: CrustUser,
+        settings: ConnectionSettings,
+        event: Event<UniqueId>,
+        features: NegotiatedFeatures,
+    ) -> CoreMessage {
+        let ids = (rand::random(), their_id);
+        start_between(stream, event_tx, cm, ids, None, their_role, settings, event, features)
+    }
+
+    // Like `start_with_event`, from `our_id` and for a connection made in `direction`.
+    fn start_between(
+        stream: TcpStream,
+        event_tx: ::CrustEventSender<UniqueId>,
+        cm: ConnectionMap<UniqueId>,
+        (our_id, their_id): (UniqueId, UniqueId),
+        direction: Option<ConnectionDirection>,
+        their_role: CrustUser,
+        settings: ConnectionSettings,
+        event: Event<UniqueId>,
+        features: NegotiatedFeatures,
+    ) -> CoreMessage {
+        CoreMessage::new(move |core, poll| {
+            // As the handshake which would have led to the connection does.
+            unwrap!(cm.lock())
+                .entry(their_id)
+                .or_insert(ConnectionId {
+                    active_connection: None,
+                    currently_handshaking: 0,
+                })
+                .currently_handshaking += 1;
+            let token = core.get_new_token();
+            let socket = Socket::wrap(stream);
+            unwrap!(poll.register(
+                &socket,
+                token,
+                Ready::readable() | Ready::error() | Ready::hup(),
+                PollOpt::edge(),
+            ));
+            let event_tx = EventSink::new(event_tx, core.sender().clone());
+            ActiveConnection::start(
+                core,
+                poll,
+                token,
+                socket,
+                cm,
+                our_id,
+                their_id,
+                their_role,
+                event,
+                event_tx,
+                settings,
+                features,
+                direction,
+            );
+        })
+    }
+
+    #[test]
+    fn unanswered_probes_drop_half_open_connection() {
+        let settings = ProbeSettings {
+            after_idle: Duration::from_millis(100),
+            retries: 2,
+        };
+        let (_el, event_rx, mut peer, their_id) = start_connection(
+            "Probe Test",
+            ConnectionSettings {
+                probe: Some(settings),
+                ..ConnectionSettings::default()
+            },
+        );
+        let started = Instant::now();
+
+        // Probe the connection ourselves, then black-hole everything it sends us.
+        unwrap!(peer.write_all(&unwrap!(encode_frame(&Message::Probe::<UniqueId>))));
+
+        // Neither probe shows up as a user event, and the connection is dropped well before the
+        // heartbeat would have noticed.
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::LostPeer(id, DisconnectReason::ConnectionLost, 0) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert!(started.elapsed() < Duration::from_millis(INACTIVITY_TIMEOUT_MS));
+
+        let msgs = read_msgs(&mut peer);
+        let count =
+            |expected: Message<UniqueId>| msgs.iter().filter(|msg| **msg == expected).count();
+        assert_eq!(count(Message::ProbeAck), 1);
+        assert_eq!(count(Message::Probe), settings.retries as usize);
+        assert_eq!(
+            count(Message::Probe) + count(Message::ProbeAck) + count(Message::Heartbeat),
+            msgs.len()
+        );
+    }
+
+    #[test]
+    fn batched_messages_are_delivered_in_order() {
+        const MAX_BATCH: usize = 4;
+        const MSGS: u8 = 10;
+        let (_el, event_rx, mut peer, their_id) = start_connection(
+            "Batching Test",
+            ConnectionSettings {
+                event_batching: Some(EventBatching {
+                    max_batch: MAX_BATCH,
+                    max_delay_us: 50_000,
+                }),
+                ..ConnectionSettings::default()
+            },
+        );
+        let recv_batch = || match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::NewMessages(id, _, msgs, _) => {
+                assert_eq!(id, their_id);
+                assert!(!msgs.is_empty() && msgs.len() <= MAX_BATCH);
+                msgs
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        };
+
+        // A lone message is delivered once the delay has passed.
+        unwrap!(peer.write_all(&unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![0])))));
+        assert_eq!(recv_batch(), vec![vec![0]]);
+
+        // The batch left over when the peer says goodbye is delivered before the peer is lost.
+        let mut stream = Vec::new();
+        for i in 1..MSGS {
+            stream.extend(unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![i]))));
+        }
+        stream.extend(unwrap!(encode_frame(&Message::Goodbye::<UniqueId>(7))));
+        unwrap!(peer.write_all(&stream));
+
+        let mut received = Vec::new();
+        while received.len() < MSGS as usize - 1 {
+            received.extend(recv_batch());
+        }
+        assert_eq!(received, (1..MSGS).map(|i| vec![i]).collect::<Vec<_>>());
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::LostPeer(id, DisconnectReason::RemoteRequested(7), _) => {
+                assert_eq!(id, their_id)
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn peer_over_inbound_rate_is_reported_and_throttled() {
+        const MAX_MSGS_PER_SEC: u64 = 20;
+        let penalty = Duration::from_millis(300);
+        let (_el, event_rx, mut peer, their_id) = start_connection(
+            "Inbound Rate Test",
+            ConnectionSettings {
+                inbound_limits: Some(InboundRateLimits {
+                    max_msgs_per_sec: Some(MAX_MSGS_PER_SEC),
+                    max_bytes_per_sec: None,
+                    penalty: Some(penalty),
+                }),
+                ..ConnectionSettings::default()
+            },
+        );
+
+        let mut stream = Vec::new();
+        for i in 0..MAX_MSGS_PER_SEC + 5 {
+            let msg = Message::Data::<UniqueId>(vec![i as u8; 10]);
+            stream.extend(unwrap!(encode_frame(&msg)));
+        }
+        unwrap!(peer.write_all(&stream));
+
+        let recv_msg = |expected: u64| match unwrap!(event_rx.recv_timeout(Duration::from_secs(5)))
+        {
+            Event::NewMessage(id, _, data, _) => {
+                assert_eq!(id, their_id);
+                assert_eq!(data, vec![expected as u8; 10]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        };
+
+        for i in 0..MAX_MSGS_PER_SEC {
+            recv_msg(i);
+        }
+        // The message over the limit raises the event before it is delivered itself.
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::PeerOverRate {
+                peer_id,
+                msgs_rate,
+                bytes_rate,
+            } => {
+                assert_eq!(peer_id, their_id);
+                assert_eq!(msgs_rate, MAX_MSGS_PER_SEC + 1);
+                assert!(bytes_rate > 10 * msgs_rate);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        recv_msg(MAX_MSGS_PER_SEC);
+        let paused_at = Instant::now();
+
+        // Reading is paused for the penalty after every message still over the limit, and the
+        // peer is not reported again.
+        recv_msg(MAX_MSGS_PER_SEC + 1);
+        assert!(paused_at.elapsed() >= penalty - Duration::from_millis(50));
+        for i in MAX_MSGS_PER_SEC + 2..MAX_MSGS_PER_SEC + 5 {
+            recv_msg(i);
+        }
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    // Makes the next calls at `site` on the socket of the connection to `their_id` fail with the
+    // given errors.
+    fn inject_faults(
+        el: &common::EventLoop,
+        cm: &ConnectionMap<UniqueId>,
+        their_id: UniqueId,
+        site: IoSite,
+        kinds: Vec<ErrorKind>,
+    ) {
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        let (tx, rx) = mpsc::channel();
+        unwrap!(el.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(token));
+            let mut state = state.borrow_mut();
+            let connection = unwrap!(state.as_any().downcast_mut::<ActiveConnection<UniqueId>>());
+            for kind in kinds {
+                connection.socket.inject_fault(site, kind);
+            }
+            unwrap!(tx.send(()));
+        })));
+        unwrap!(rx.recv());
+    }
+
+    // Sends `data` to `their_id` from the event loop.
+    fn send_data(
+        el: &common::EventLoop,
+        cm: &ConnectionMap<UniqueId>,
+        their_id: UniqueId,
+        data: Vec<u8>,
+    ) {
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            let state = unwrap!(core.get_state(token));
+            state.borrow_mut().write(core, poll, data, 1);
+        })));
+    }
+
+    #[test]
+    fn interrupted_reads_and_writes_are_retried() {
+        let el = unwrap!(common::spawn_event_loop(0, Some("Interrupted IO Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let their_id: UniqueId = rand::random();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let settings = ConnectionSettings::default();
+        let mut peer = connect_peer(&el, &event_rx, event_tx, cm.clone(), their_id, settings);
+
+        let interrupted = vec![ErrorKind::Interrupted; 3];
+        inject_faults(&el, &cm, their_id, IoSite::Read, interrupted.clone());
+        inject_faults(&el, &cm, their_id, IoSite::Write, interrupted);
+
+        // Nothing else would make us read the message, as readiness is edge-triggered.
+        unwrap!(peer.write_all(&unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![1; 10])))));
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::NewMessage(id, _, data, _) => {
+                assert_eq!(id, their_id);
+                assert_eq!(data, vec![1; 10]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        send_data(&el, &cm, their_id, vec![2; 10]);
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        'read: loop {
+            let bytes_read = unwrap!(peer.read(&mut buf));
+            assert!(bytes_read > 0, "Connection closed");
+            let mut input = &buf[..bytes_read];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                        Message::Data(data) => {
+                            assert_eq!(data, vec![2; 10]);
+                            break 'read;
+                        }
+                        Message::Heartbeat => (),
+                        msg => panic!("Unexpected message: {:?}", msg),
+                    }
+                }
+            }
+        }
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn socket_errors_are_reported_as_disconnect_reasons() {
+        let el = unwrap!(common::spawn_event_loop(0, Some("Socket Error Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+
+        let cases = vec![
+            (IoSite::Read, ErrorKind::ConnectionReset, DisconnectReason::RemoteClosed),
+            (IoSite::Read, ErrorKind::ConnectionAborted, DisconnectReason::RemoteClosed),
+            (IoSite::Write, ErrorKind::BrokenPipe, DisconnectReason::RemoteClosed),
+            (
+                IoSite::Read,
+                ErrorKind::Other,
+                DisconnectReason::TransportError(ErrorKind::Other),
+            ),
+            (
+                IoSite::Write,
+                ErrorKind::PermissionDenied,
+                DisconnectReason::TransportError(ErrorKind::PermissionDenied),
+            ),
+        ];
+        for (site, kind, reason) in cases {
+            let their_id: UniqueId = rand::random();
+            let settings = ConnectionSettings::default();
+            let mut peer =
+                connect_peer(&el, &event_rx, event_tx.clone(), cm.clone(), their_id, settings);
+            inject_faults(&el, &cm, their_id, site, vec![kind]);
+            match site {
+                IoSite::Read => {
+                    let heartbeat = unwrap!(encode_frame(&Message::Heartbeat::<UniqueId>));
+                    unwrap!(peer.write_all(&heartbeat));
+                }
+                _ => send_data(&el, &cm, their_id, vec![3; 10]),
+            }
+            match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+                Event::LostPeer(id, lost_reason, _) => {
+                    assert_eq!(id, their_id);
+                    assert_eq!(lost_reason, reason, "{:?} at {:?}", kind, site);
+                }
+                event => panic!("Unexpected event: {:?}", event),
+            }
+        }
+    }
+
+    #[test]
+    fn unsent_messages_survive_reconnect_within_window() {
+        const MSGS: usize = 200;
+        let el = unwrap!(common::spawn_event_loop(0, Some("Retention Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let retained_tx = event_tx.clone();
+        unwrap!(el.send(CoreMessage::new(move |core, _| {
+            let window = Duration::from_secs(5);
+            let retained_tx = EventSink::new(retained_tx, core.sender().clone());
+            RetainedQueues::start(core, RETAINED_QUEUES_TOKEN, window, retained_tx);
+        })));
+
+        let settings = ConnectionSettings {
+            retain_unsent: true,
+            ..ConnectionSettings::default()
+        };
+        let their_id: UniqueId = rand::random();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let peer = connect_peer(
+            &el,
+            &event_rx,
+            event_tx.clone(),
+            cm.clone(),
+            their_id,
+            settings.clone(),
+        );
+
+        // Send a burst far larger than the socket buffers, then cut the connection before the
+        // peer has read any of it.
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            let state = unwrap!(core.get_state(token));
+            let mut state = state.borrow_mut();
+            for i in 0..MSGS {
+                let mut msg = vec![0; 50 * 1024];
+                msg[0] = i as u8;
+                state.write(core, poll, msg, 1);
+            }
+        })));
+        thread::sleep(Duration::from_millis(100));
+        drop(peer);
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::LostPeer(id, DisconnectReason::ConnectionLost, _)
+            | Event::LostPeer(id, DisconnectReason::RemoteClosed, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        // Everything which hadn't started to be written is resent in order, exactly once. The
+        // message which was being written when the connection broke is not.
+        let mut peer = connect_peer(&el, &event_rx, event_tx, cm, their_id, settings);
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut received = Vec::new();
+        let mut buf = [0; 64 * 1024];
+        while received.last() != Some(&(MSGS - 1)) {
+            let mut input = &buf[..unwrap!(peer.read(&mut buf))];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                        Message::Data(msg) => received.push(msg[0] as usize),
+                        Message::Heartbeat => (),
+                        msg => panic!("Unexpected message: {:?}", msg),
+                    }
+                }
+            }
+        }
+        assert!(received.len() > MSGS / 2);
+        let first = received[0];
+        assert_eq!(received, (first..MSGS).collect::<Vec<_>>());
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn goodbye_follows_everything_queued() {
+        const MSGS: usize = 100;
+        let el = unwrap!(common::spawn_event_loop(0, Some("Goodbye Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let their_id: UniqueId = rand::random();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let settings = ConnectionSettings::default();
+        let mut peer = connect_peer(&el, &event_rx, event_tx, cm.clone(), their_id, settings);
+
+        // A burst far larger than the socket buffers, then shutting down at once.
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        with_connection(&el, token, move |ac, core, poll| {
+            for i in 0..MSGS {
+                let mut msg = vec![0; 32 * 1024];
+                msg[0] = i as u8;
+                State::write(ac, core, poll, msg, 1);
+            }
+            ac.shut_down(core, poll, Rc::new(Cell::new(0)));
+        });
+
+        let msgs = read_msgs(&mut peer);
+        let (goodbye, data) = unwrap!(msgs.split_last());
+        assert_eq!(*goodbye, Message::Goodbye(0));
+        let received: Vec<_> = data
+            .iter()
+            .filter_map(|msg| match *msg {
+                Message::Data(ref msg) => Some(msg[0] as usize),
+                Message::Heartbeat => None,
+                ref msg => panic!("Unexpected message: {:?}", msg),
+            })
+            .collect();
+        assert_eq!(received, (0..MSGS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn peer_trickling_a_frame_is_cut_at_its_deadline() {
+        const BODY_LEN: usize = 8 * 1024;
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let mut run = || {
+            for _ in 0..5 {
+                assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            }
+        };
+
+        // Well within the inactivity timeout, which a trickled frame doesn't reset either.
+        let settings = ConnectionSettings {
+            frame_completion_timeout: Some(Duration::from_millis(300)),
+            ..ConnectionSettings::default()
+        };
+        let deadline =
+            Duration::from_millis(300 + BODY_LEN as u64 * 1000 / MIN_FRAME_BYTES_PER_SEC);
+        let step = Duration::from_millis(50);
+        assert!(deadline < Duration::from_millis(INACTIVITY_TIMEOUT_MS) - step);
+
+        let their_id: UniqueId = rand::random();
+        let (stream, mut peer) = link();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        unwrap!(handle.send(start_on(stream, event_tx, cm, their_id, CrustUser::Node, settings)));
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        // A frame which arrives in full in time is fine, however slowly.
+        let frame = unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![1; BODY_LEN])));
+        let (head, tail) = frame.split_at(frame.len() / 2);
+        unwrap!(peer.write_all(head));
+        run();
+        clock.advance(deadline - step);
+        run();
+        unwrap!(peer.write_all(tail));
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::NewMessage(id, _, data, _) => {
+                assert_eq!(id, their_id);
+                assert_eq!(data, vec![1; BODY_LEN]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        // The next one only comes a byte a step, and is cut off at its deadline.
+        let frame = unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![2; BODY_LEN])));
+        let mut elapsed = Duration::from_secs(0);
+        for byte in frame.chunks(1) {
+            unwrap!(peer.write_all(byte));
+            run();
+            if let Ok(event) = event_rx.try_recv() {
+                match event {
+                    Event::LostPeer(id, DisconnectReason::ProtocolError("slow frame"), _) => {
+                        assert_eq!(id, their_id)
+                    }
+                    event => panic!("Unexpected event: {:?}", event),
+                }
+                break;
+            }
+            clock.advance(step);
+            elapsed += step;
+        }
+        // Counted from the step in which the header arrived in full.
+        assert_eq!(elapsed, step * (FRAME_HEADER_SIZE as u32 - 1) + deadline);
+
+        // The buffer allocated for the frame went with the connection.
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let _ = tx.send(core.stats().clone());
+        })));
+        run();
+        let stats = unwrap!(rx.try_recv());
+        assert_eq!(stats.slow_frames_dropped, 1);
+        assert_eq!(stats.slow_frame_bytes_freed, frame.len() as u64 - FRAME_HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn timestamped_frames_give_one_way_latencies() {
+        const ROUNDS: u64 = 40;
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let mut run = || {
+            for _ in 0..5 {
+                assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            }
+        };
+
+        let their_id: UniqueId = rand::random();
+        let (stream, mut peer) = link();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let features = NegotiatedFeatures {
+            timestamps: true,
+            ..NegotiatedFeatures::default()
+        };
+        unwrap!(handle.send(start_with_event(
+            stream,
+            event_tx,
+            cm.clone(),
+            their_id,
+            CrustUser::Node,
+            ConnectionSettings::default(),
+            Event::ConnectSuccess(their_id, None),
+            features,
+        )));
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+
+        let stamped = |msg: Message<UniqueId>, echoed_delay_ms: Option<i64>| {
+            let mut frame = unwrap!(encode_frame(&msg));
+            let trailer = TimestampTrailer {
+                sent_ms: clock.now_ms(),
+                echoed_delay_ms,
+            };
+            frame.extend_from_slice(&trailer.encode());
+            let len = frame.len() - FRAME_HEADER_SIZE;
+            LittleEndian::write_u32(&mut frame[..FRAME_HEADER_SIZE], len as u32);
+            frame
+        };
+
+        // Probe the connection, each probe taking 40 to 59 ms to arrive and its answer 10 to 19 ms
+        // to come back, both ends' clocks being in sync. The heartbeats keep it alive.
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        let mut echo = None;
+        for round in 0..ROUNDS {
+            let inbound = 40 + round % 20;
+            let outbound = 10 + round % 10;
+            unwrap!(peer.write_all(&stamped(Message::Heartbeat, None)));
+            unwrap!(peer.write_all(&stamped(Message::Probe, echo)));
+            clock.advance(Duration::from_millis(inbound));
+            run();
+
+            let mut answer = None;
+            while answer.is_none() {
+                let mut input = &buf[..unwrap!(peer.read(&mut buf))];
+                while !input.is_empty() {
+                    if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                        let (body, trailer) = body.split_at(body.len() - TIMESTAMP_TRAILER_SIZE);
+                        let mut bytes = [0; TIMESTAMP_TRAILER_SIZE];
+                        bytes.copy_from_slice(trailer);
+                        let trailer = TimestampTrailer::decode(&bytes);
+                        match unwrap!(decode_message::<Message<UniqueId>>(body)) {
+                            Message::ProbeAck => answer = Some(trailer),
+                            Message::Heartbeat => (),
+                            msg => panic!("Unexpected message: {:?}", msg),
+                        }
+                    }
+                }
+            }
+            let answer = unwrap!(answer);
+            assert_eq!(answer.echoed_delay_ms, Some(inbound as i64));
+
+            clock.advance(Duration::from_millis(outbound));
+            echo = Some((clock.now_ms() - answer.sent_ms) as i64);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(token));
+            let mut state = state.borrow_mut();
+            let ac = unwrap!(state.as_any().downcast_mut::<ActiveConnection<UniqueId>>());
+            let _ = tx.send(ac.stats(core.now()));
+        })));
+        run();
+        let stats = unwrap!(rx.try_recv());
+        assert!(stats.features.timestamps);
+        // Every delay but the last answer's was echoed back.
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(stats.one_way_latency.inbound_p50, ms(49));
+        assert_eq!(stats.one_way_latency.inbound_p95, ms(58));
+        assert_eq!(stats.one_way_latency.outbound_p50, ms(14));
+        assert_eq!(stats.one_way_latency.outbound_p95, ms(19));
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn probe_round_trips_are_recorded_in_peer_stats() {
+        const ROUNDS: usize = 50;
+        let fast = Duration::from_millis(20);
+        let slow = Duration::from_millis(80);
+        let interval = Duration::from_millis(100);
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let mut run = || {
+            for _ in 0..5 {
+                assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            }
+        };
+
+        let settings = ConnectionSettings {
+            latency_probe_interval: Some(interval),
+            ..ConnectionSettings::default()
+        };
+        let their_id: UniqueId = rand::random();
+        let (stream, mut peer) = link();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        unwrap!(handle.send(start_on(
+            stream,
+            event_tx,
+            cm.clone(),
+            their_id,
+            CrustUser::Node,
+            settings,
+        )));
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        let get_stats = |reset: bool| {
+            let (tx, rx) = mpsc::channel();
+            unwrap!(handle.send(CoreMessage::new(move |core, _| {
+                let state = unwrap!(core.get_state(token));
+                let mut state = state.borrow_mut();
+                let ac = unwrap!(state.as_any().downcast_mut::<ActiveConnection<UniqueId>>());
+                let _ = tx.send(ac.stats(core.now()));
+                if reset {
+                    ac.reset_stats();
+                }
+            })));
+            rx
+        };
+
+        // Answer every tenth probe slowly and the rest quickly, keeping the connection alive with
+        // heartbeats meanwhile.
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        let answer = unwrap!(encode_frame(&Message::ProbeAck::<UniqueId>));
+        let heartbeat = unwrap!(encode_frame(&Message::Heartbeat::<UniqueId>));
+        for round in 0..ROUNDS {
+            clock.advance(interval);
+            run();
+            let mut probed = false;
+            while !probed {
+                let mut input = &buf[..unwrap!(peer.read(&mut buf))];
+                while !input.is_empty() {
+                    if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                        match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                            Message::Probe => probed = true,
+                            Message::Heartbeat => (),
+                            msg => panic!("Unexpected message: {:?}", msg),
+                        }
+                    }
+                }
+            }
+
+            let delay = if round % 10 == 9 { slow } else { fast };
+            clock.advance(delay);
+            unwrap!(peer.write_all(&answer));
+            unwrap!(peer.write_all(&heartbeat));
+            run();
+            clock.advance(interval - delay);
+        }
+
+        let rx = get_stats(true);
+        run();
+        let stats = unwrap!(rx.try_recv());
+        let latency = stats.latency;
+        assert_eq!(latency.samples(), ROUNDS as u64);
+        // The last answer was slow, but weighs in by no more than an eighth.
+        let smoothed_rtt = unwrap!(stats.smoothed_rtt);
+        assert!(smoothed_rtt > fast && smoothed_rtt < fast * 2, "{:?}", smoothed_rtt);
+        let bracket = |reported: Option<Duration>, delay: Duration| {
+            let reported = unwrap!(reported);
+            assert!(reported >= delay, "{:?} < {:?}", reported, delay);
+            assert!(reported <= delay * 5 / 4, "{:?} too far off {:?}", reported, delay);
+        };
+        bracket(latency.p50(), fast);
+        bracket(latency.p95(), slow);
+        bracket(latency.p99(), slow);
+        assert_eq!(
+            latency
+                .buckets()
+                .iter()
+                .map(|&(_, count)| count)
+                .collect::<Vec<_>>(),
+            vec![ROUNDS as u32 * 9 / 10, ROUNDS as u32 / 10]
+        );
+
+        let rx = get_stats(false);
+        run();
+        let stats = unwrap!(rx.try_recv());
+        assert_eq!(stats.connected_for, interval * ROUNDS as u32);
+        assert_eq!(
+            stats,
+            PeerStats {
+                connected_for: stats.connected_for,
+                ..PeerStats::default()
+            }
+        );
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn peers_not_answering_after_a_suspend_are_dropped() {
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(8, clock.clone()));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let mut run = || {
+            for _ in 0..5 {
+                assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            }
+        };
+
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let their_id: UniqueId = rand::random();
+            let (stream, peer) = link();
+            let settings = ConnectionSettings::default();
+            unwrap!(handle.send(start_on(
+                stream,
+                event_tx.clone(),
+                cm.clone(),
+                their_id,
+                CrustUser::Node,
+                settings,
+            )));
+            peers.push((their_id, peer));
+        }
+        let mc = Arc::new(Mutex::new(Arc::new(unwrap!(MappingContext::without_igd()))));
+        let monitor_cm = cm.clone();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            unwrap!(SuspendMonitor::start(
+                core,
+                SUSPEND_MONITOR_TOKEN,
+                true,
+                monitor_cm,
+                mc,
+            ));
+        })));
+        run();
+        for &(their_id, _) in &peers {
+            match unwrap!(event_rx.try_recv()) {
+                Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+                event => panic!("Unexpected event: {:?}", event),
+            }
+        }
+
+        // Nothing happens on the clocks which don't count the time suspended.
+        clock.suspend(Duration::from_secs(3600));
+        clock.advance(Duration::from_millis(HEARTBEAT_PERIOD_MS));
+        run();
+        for &mut (_, ref mut peer) in &mut peers {
+            let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+            let mut buf = [0; 1024];
+            let mut probed = false;
+            while !probed {
+                let mut input = &buf[..unwrap!(peer.read(&mut buf))];
+                while !input.is_empty() {
+                    if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                        match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                            Message::Probe => probed = true,
+                            Message::Heartbeat => (),
+                            msg => panic!("Unexpected message: {:?}", msg),
+                        }
+                    }
+                }
+            }
+        }
+
+        // Only the peer answering its probe is kept, well before the inactivity timeout.
+        let (alive_id, silent_id) = (peers[0].0, peers[1].0);
+        unwrap!(peers[0].1.write_all(&unwrap!(encode_frame(&Message::ProbeAck::<UniqueId>))));
+        run();
+        clock.advance(Duration::from_millis(RESUME_PROBE_TIMEOUT_MS));
+        run();
+        assert!(HEARTBEAT_PERIOD_MS + RESUME_PROBE_TIMEOUT_MS < INACTIVITY_TIMEOUT_MS);
+        match unwrap!(event_rx.try_recv()) {
+            Event::LostPeer(id, DisconnectReason::SuspendDetected, _) => {
+                assert_eq!(id, silent_id)
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert!(event_rx.try_recv().is_err());
+        let guard = unwrap!(cm.lock());
+        assert!(guard[&alive_id].active_connection.is_some());
+        assert!(!guard.contains_key(&silent_id));
+    }
+
+    #[test]
+    fn client_is_promoted_once_its_listener_is_reached() {
+        let el = unwrap!(common::spawn_event_loop(0, Some("Promotion Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let their_id: UniqueId = rand::random();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let (stream, mut peer) = link();
+        let settings = ConnectionSettings::default();
+        unwrap!(el.send(start_on(stream, event_tx, cm, their_id, CrustUser::Client, settings)));
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        // Nothing listens on the announced address: the promotion is refused.
+        let closed = unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr());
+        let promote = |peer: &mut StdTcpStream, addr: SocketAddr| {
+            let msg = Message::PromoteToNode::<UniqueId>(vec![addr]);
+            unwrap!(peer.write_all(&unwrap!(encode_frame(&msg))));
+        };
+        promote(&mut peer, closed);
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        let mut refused = false;
+        while !refused {
+            let mut input = &buf[..unwrap!(peer.read(&mut buf))];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                        Message::PromotionFailed => refused = true,
+                        Message::Heartbeat => (),
+                        msg => panic!("Unexpected message: {:?}", msg),
+                    }
+                }
+            }
+        }
+
+        // Asking again straight away is ignored, even with a reachable listener...
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        unwrap!(listener.set_nonblocking(true));
+        let reachable = unwrap!(listener.local_addr());
+        promote(&mut peer, reachable);
+        thread::sleep(Duration::from_millis(100));
+        assert!(listener.accept().is_err());
+        assert!(event_rx.try_recv().is_err());
+
+        // ...but not once the interval has passed. The probe reaches the listener.
+        thread::sleep(Duration::from_millis(MIN_PROMOTION_INTERVAL_MS));
+        let heartbeat = unwrap!(encode_frame(&Message::Heartbeat::<UniqueId>));
+        unwrap!(peer.write_all(&heartbeat));
+        promote(&mut peer, reachable);
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::PeerPromoted { peer_id } => assert_eq!(peer_id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert!(listener.accept().is_ok());
+
+        // Messages from the peer now come from a node.
+        let msg = Message::Data::<UniqueId>(vec![7]);
+        unwrap!(peer.write_all(&unwrap!(encode_frame(&msg))));
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::NewMessage(id, CrustUser::Node, data, _) => {
+                assert_eq!(id, their_id);
+                assert_eq!(data, vec![7]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn racing_connections_to_a_peer_are_reported_once() {
+        const ROUNDS: usize = 150;
+        for &policy in &[
+            DuplicateConnectionPolicy::KeepOldest,
+            DuplicateConnectionPolicy::KeepNewest,
+        ] {
+            let seed = [rand::random::<u32>() | 1, rand::random(), rand::random(), 0];
+            let mut rng = XorShiftRng::from_seed(seed);
+            let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+            let (event_tx, event_rx) = mpsc::channel();
+            let event_tx =
+                ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+            let cm = Arc::new(Mutex::new(HashMap::new()));
+            let settings = ConnectionSettings {
+                duplicate_policy: policy,
+                ..ConnectionSettings::default()
+            };
+
+            for _ in 0..ROUNDS {
+                // Inbound accepts, outbound connects and bootstraps reaching the peer, and the
+                // peer dropping some of them, in random order.
+                let their_id: UniqueId = rng.gen();
+                let mut peers = Vec::new();
+                for _ in 0..rng.gen_range(2, 7) {
+                    if !peers.is_empty() && rng.gen_weighted_bool(3) {
+                        let index = rng.gen_range(0, peers.len());
+                        drop(peers.swap_remove(index));
+                    } else {
+                        let (stream, peer) = link();
+                        let event = match rng.gen_range(0, 3) {
+                            0 => Event::BootstrapAccept(their_id, CrustUser::Node, None),
+                            1 => Event::ConnectSuccess(their_id, None),
+                            _ => {
+                                let addr = unwrap!(peer.local_addr());
+                                Event::BootstrapConnect(their_id, addr, None)
+                            }
+                        };
+                        unwrap!(handle.send(start_with_event(
+                            stream,
+                            event_tx.clone(),
+                            cm.clone(),
+                            their_id,
+                            CrustUser::Node,
+                            settings.clone(),
+                            event,
+                            NegotiatedFeatures::default(),
+                        )));
+                        peers.push(peer);
+                    }
+                    for _ in 0..rng.gen_range(0, 3) {
+                        assert!(unwrap!(el.run_once(Duration::from_millis(1))));
+                    }
+                }
+                drop(peers);
+                for _ in 0..10 {
+                    assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+                }
+
+                // Connected and lost alternate, whichever connection was kept.
+                let mut connected = false;
+                while let Ok(event) = event_rx.try_recv() {
+                    match event {
+                        Event::BootstrapAccept(id, _, _)
+                        | Event::BootstrapConnect(id, _, _)
+                        | Event::ConnectSuccess(id, _) => {
+                            assert_eq!(id, their_id);
+                            assert!(!connected, "{:?} with seed {:?}", policy, seed);
+                            connected = true;
+                        }
+                        Event::LostPeer(id, _, _) => {
+                            assert_eq!(id, their_id);
+                            assert!(connected, "{:?} with seed {:?}", policy, seed);
+                            connected = false;
+                        }
+                        event => panic!("Unexpected event: {:?}", event),
+                    }
+                }
+                assert!(!connected, "{:?} with seed {:?}", policy, seed);
+                assert!(!unwrap!(cm.lock()).contains_key(&their_id));
+            }
+        }
+    }
+
+    #[test]
+    fn both_ends_keep_the_same_of_two_racing_connections() {
+        // Returns the connecting and the accepted end of a new connection.
+        fn pair() -> (TcpStream, TcpStream) {
+            let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+            let stream = unwrap!(StdTcpStream::connect(unwrap!(listener.local_addr())));
+            let (peer, _) = unwrap!(listener.accept());
+            (
+                unwrap!(TcpStream::from_stream(stream)),
+                unwrap!(TcpStream::from_stream(peer)),
+            )
+        }
+
+        for &policy in &[
+            DuplicateConnectionPolicy::KeepOldest,
+            DuplicateConnectionPolicy::KeepNewest,
+        ] {
+            let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+            let (event_tx, _event_rx) = mpsc::channel();
+            let event_tx =
+                ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+            let settings = ConnectionSettings {
+                duplicate_policy: policy,
+                ..ConnectionSettings::default()
+            };
+            let (lower, higher): (UniqueId, UniqueId) = (rand::random(), rand::random());
+            let (lower, higher) = (cmp::min(lower, higher), cmp::max(lower, higher));
+            let lower_cm = Arc::new(Mutex::new(HashMap::new()));
+            let higher_cm = Arc::new(Mutex::new(HashMap::new()));
+
+            // The lower ID dials the first connection and the higher the second, and each end
+            // sees the one it dialled first.
+            let (lower_out, higher_in) = pair();
+            let (higher_out, lower_in) = pair();
+            let first = (
+                unwrap!(lower_out.local_addr()),
+                unwrap!(higher_in.local_addr()),
+            );
+            for (stream, cm, ids, direction) in vec![
+                (lower_out, &lower_cm, (lower, higher), ConnectionDirection::Outbound),
+                (higher_out, &higher_cm, (higher, lower), ConnectionDirection::Outbound),
+                (lower_in, &lower_cm, (lower, higher), ConnectionDirection::Inbound),
+                (higher_in, &higher_cm, (higher, lower), ConnectionDirection::Inbound),
+            ] {
+                unwrap!(handle.send(start_between(
+                    stream,
+                    event_tx.clone(),
+                    cm.clone(),
+                    ids,
+                    Some(direction),
+                    CrustUser::Node,
+                    settings.clone(),
+                    Event::ConnectSuccess(ids.1, None),
+                    NegotiatedFeatures::default(),
+                )));
+            }
+            for _ in 0..10 {
+                assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            }
+
+            // Both kept the connection the lower ID dialled.
+            let (kept_tx, kept_rx) = mpsc::channel();
+            for &(cm, their_id) in &[(&lower_cm, higher), (&higher_cm, lower)] {
+                let cm = cm.clone();
+                let kept_tx = kept_tx.clone();
+                unwrap!(handle.send(CoreMessage::new(move |core, _| {
+                    let token = unwrap!(cm.lock())
+                        .get(&their_id)
+                        .and_then(|id| id.active_connection);
+                    let state = unwrap!(core.get_state(unwrap!(token)));
+                    let mut state = state.borrow_mut();
+                    let ac = unwrap!(state.as_any().downcast_mut::<ActiveConnection<UniqueId>>());
+                    unwrap!(kept_tx.send(unwrap!(ac.their_addr)));
+                })));
+            }
+            assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            assert_eq!(unwrap!(kept_rx.try_recv()), first.1, "{:?}", policy);
+            assert_eq!(unwrap!(kept_rx.try_recv()), first.0, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn peer_blasting_data_does_not_hold_up_the_others() {
+        const PINGS: usize = 20;
+        let el = unwrap!(common::spawn_event_loop(0, Some("Read Budget Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let settings = ConnectionSettings {
+            read_budget: Some(DEFAULT_READ_BUDGET_BYTES),
+            ..ConnectionSettings::default()
+        };
+        let mut blaster = connect_peer(
+            &el,
+            &event_rx,
+            event_tx.clone(),
+            cm.clone(),
+            rand::random(),
+            settings.clone(),
+        );
+        let mut pinger = connect_peer(&el, &event_rx, event_tx, cm, rand::random(), settings);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let blasting = {
+            let done = done.clone();
+            thread::spawn(move || {
+                // Written as fast as the connection takes it, so there is always more to read.
+                let frame = unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![0; 64 * 1024])));
+                while !done.load(Ordering::Relaxed) {
+                    unwrap!(blaster.write_all(&frame));
+                }
+            })
+        };
+        let delivering = {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut delivered = 0;
+                while !done.load(Ordering::Relaxed) {
+                    match event_rx.recv_timeout(Duration::from_millis(10)) {
+                        Ok(Event::NewMessage(..)) => delivered += 1,
+                        Ok(Event::NewMessages(_, _, msgs, _)) => delivered += msgs.len(),
+                        _ => (),
+                    }
+                }
+                delivered
+            })
+        };
+
+        let probe = unwrap!(encode_frame(&Message::Probe::<UniqueId>));
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        let mut slowest = Duration::from_secs(0);
+        for _ in 0..PINGS {
+            thread::sleep(Duration::from_millis(10));
+            let sent = Instant::now();
+            unwrap!(pinger.write_all(&probe));
+            'ack: loop {
+                let bytes_read = unwrap!(pinger.read(&mut buf));
+                assert_ne!(bytes_read, 0);
+                let mut input = &buf[..bytes_read];
+                while !input.is_empty() {
+                    if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                        if let Message::ProbeAck = unwrap!(decode_message(&body)) {
+                            break 'ack;
+                        }
+                    }
+                }
+            }
+            slowest = cmp::max(slowest, sent.elapsed());
+        }
+
+        done.store(true, Ordering::Relaxed);
+        unwrap!(blasting.join());
+        assert!(unwrap!(delivering.join()) > 0);
+        assert!(
+            slowest < Duration::from_millis(200),
+            "slowest round trip {:?}",
+            slowest
+        );
+    }
+
+    #[test]
+    fn heartbeats_get_through_a_saturated_link() {
+        const FRAMES: usize = 32;
+        const CHUNK: usize = 8 * 1024;
+        let el = unwrap!(common::spawn_event_loop(0, Some("Saturated Link Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let their_id: UniqueId = rand::random();
+        let settings = ConnectionSettings::default();
+        let mut peer = connect_peer(&el, &event_rx, event_tx, cm.clone(), their_id, settings);
+        unwrap!(peer.set_recv_buffer_size(CHUNK));
+
+        // Bulk data at the highest priority the application may send at, taking the link below
+        // well over the inactivity timeout to drain.
+        for _ in 0..FRAMES {
+            send_data(&el, &cm, their_id, vec![0; 64 * 1024]);
+        }
+
+        // The link: a chunk read every 5 ms, about 1.6 MB/s. A probe is sent as soon as the last
+        // one was answered, along with a heartbeat of our own.
+        let probe = unwrap!(encode_frame(&Message::Probe::<UniqueId>));
+        let heartbeat = unwrap!(encode_frame(&Message::Heartbeat::<UniqueId>));
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; CHUNK];
+        let started = Instant::now();
+        let mut last_heartbeat = started;
+        let mut longest_silence = Duration::from_secs(0);
+        let mut probed_at = None;
+        let mut slowest_round_trip = Duration::from_secs(0);
+        let mut round_trips = 0;
+        let mut frames = 0;
+        while frames < FRAMES {
+            thread::sleep(Duration::from_millis(5));
+            if probed_at.is_none() {
+                unwrap!(peer.write_all(&probe));
+                unwrap!(peer.write_all(&heartbeat));
+                probed_at = Some(Instant::now());
+            }
+            let bytes_read = unwrap!(peer.read(&mut buf));
+            assert_ne!(bytes_read, 0);
+            let mut input = &buf[..bytes_read];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                        Message::Data(_) => frames += 1,
+                        Message::ProbeAck => {
+                            let sent = unwrap!(probed_at.take());
+                            slowest_round_trip = cmp::max(slowest_round_trip, sent.elapsed());
+                            round_trips += 1;
+                        }
+                        Message::Heartbeat => {
+                            longest_silence = cmp::max(longest_silence, last_heartbeat.elapsed());
+                            last_heartbeat = Instant::now();
+                        }
+                        msg => panic!("Unexpected message: {:?}", msg),
+                    }
+                }
+            }
+        }
+
+        let timeout = Duration::from_millis(INACTIVITY_TIMEOUT_MS);
+        assert!(started.elapsed() > timeout, "drained in {:?}", started.elapsed());
+        // The heartbeats of the connection kept coming, well within the timeout...
+        assert!(
+            longest_silence < timeout * 2 / 3,
+            "heartbeats {:?} apart",
+            longest_silence
+        );
+        // ...and so did the answers to our probes.
+        assert!(round_trips > 10, "{} round trips", round_trips);
+        assert!(
+            slowest_round_trip < Duration::from_millis(200),
+            "slowest round trip {:?}",
+            slowest_round_trip
+        );
+        // Nor did the connection take us for dead.
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn responses_are_matched_to_requests_once() {
+        let timeout = Duration::from_millis(200);
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let mut run = || {
+            for _ in 0..5 {
+                assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            }
+        };
+
+        let settings = ConnectionSettings {
+            request_timeout: Some(timeout),
+            ..ConnectionSettings::default()
+        };
+        let features = NegotiatedFeatures {
+            correlation: true,
+            ..NegotiatedFeatures::default()
+        };
+        let their_id: UniqueId = rand::random();
+        let (stream, mut peer) = link();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        unwrap!(handle.send(start_with_event(
+            stream,
+            event_tx,
+            cm.clone(),
+            their_id,
+            CrustUser::Node,
+            settings,
+            Event::ConnectSuccess(their_id, None),
+            features,
+        )));
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        let mut read_msgs = |peer: &mut StdTcpStream, count: usize| {
+            let mut msgs = Vec::new();
+            while msgs.len() < count {
+                let mut input = &buf[..unwrap!(peer.read(&mut buf))];
+                while !input.is_empty() {
+                    if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                        match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+                            Message::Heartbeat => (),
+                            msg => msgs.push(msg),
+                        }
+                    }
+                }
+            }
+            msgs
+        };
+        let send = |peer: &mut StdTcpStream, msg: Message<UniqueId>| {
+            unwrap!(peer.write_all(&unwrap!(encode_frame(&msg))));
+        };
+
+        with_connection(&handle, token, |ac, core, poll| {
+            ac.send_request(core, poll, RequestId(1), vec![1], 0);
+            ac.send_request(core, poll, RequestId(2), vec![2], 0);
+        });
+        run();
+        assert_eq!(
+            read_msgs(&mut peer, 2),
+            vec![Message::Request(1, vec![1]), Message::Request(2, vec![2])]
+        );
+
+        // The first request is answered twice, and one we never sent once.
+        send(&mut peer, Message::Response(1, vec![10]));
+        send(&mut peer, Message::Response(1, vec![11]));
+        send(&mut peer, Message::Response(9, vec![90]));
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::Response {
+                peer_id,
+                request_id,
+                data,
+            } => {
+                assert_eq!(peer_id, their_id);
+                assert_eq!(request_id, RequestId(1));
+                assert_eq!(data, vec![10]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert!(event_rx.try_recv().is_err());
+
+        // The second isn't answered in time, and its late response is dropped.
+        clock.advance(timeout);
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::ResponseTimedOut {
+                peer_id,
+                request_id,
+            } => {
+                assert_eq!(peer_id, their_id);
+                assert_eq!(request_id, RequestId(2));
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        send(&mut peer, Message::Response(2, vec![20]));
+        run();
+        assert!(event_rx.try_recv().is_err());
+
+        // Requests of the peer are handed to us to answer.
+        send(&mut peer, Message::Request(5, vec![5]));
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::Request {
+                peer_id,
+                request_id,
+                data,
+            } => {
+                assert_eq!(peer_id, their_id);
+                assert_eq!(request_id, RequestId(5));
+                assert_eq!(data, vec![5]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        with_connection(&handle, token, |ac, core, poll| {
+            ac.send_response(core, poll, RequestId(5), vec![50]);
+        });
+        run();
+        assert_eq!(read_msgs(&mut peer, 1), vec![Message::Response(5, vec![50])]);
+
+        let (tx, rx) = mpsc::channel();
+        with_connection(&handle, token, move |ac, core, _| {
+            let _ = tx.send(ac.stats(core.now()));
+        });
+        run();
+        let stats = unwrap!(rx.try_recv());
+        assert_eq!(stats.duplicate_responses, 1);
+        assert_eq!(stats.unmatched_responses, 1);
+        assert_eq!(stats.protocol_violations, 1);
+        assert!(stats.features.correlation);
+
+        // A request still pending when the connection is lost times out with it.
+        with_connection(&handle, token, |ac, core, poll| {
+            ac.send_request(core, poll, RequestId(3), vec![3], 0);
+        });
+        run();
+        drop(peer);
+        run();
+        match unwrap!(event_rx.try_recv()) {
+            Event::ResponseTimedOut { request_id, .. } => assert_eq!(request_id, RequestId(3)),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        match unwrap!(event_rx.try_recv()) {
+            Event::LostPeer(id, ..) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn protocol_violations_are_dealt_with_by_policy() {
+        let el = unwrap!(common::spawn_event_loop(0, Some("Protocol Violation Test")));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let connect = |violation_policy| {
+            let settings = ConnectionSettings {
+                violation_policy,
+                ..ConnectionSettings::default()
+            };
+            let their_id: UniqueId = rand::random();
+            let peer = connect_peer(
+                &el,
+                &event_rx,
+                event_tx.clone(),
+                cm.clone(),
+                their_id,
+                settings,
+            );
+            (peer, their_id)
+        };
+        let send = |peer: &mut StdTcpStream, msg: &Message<UniqueId>| {
+            unwrap!(peer.write_all(&unwrap!(encode_frame(msg))));
+        };
+        let is_banned = |peer: &StdTcpStream| {
+            let ip = unwrap!(peer.local_addr()).ip();
+            let (tx, rx) = mpsc::channel();
+            unwrap!(el.send(CoreMessage::new(move |core, _| {
+                let _ = tx.send(core.bans().is_banned(&ip, core.now()));
+            })));
+            unwrap!(rx.recv_timeout(Duration::from_secs(5)))
+        };
+        let violations = vec![
+            (Message::ChooseConnection, ProtocolViolation::HandshakeMessage),
+            (Message::Response(7, vec![7]), ProtocolViolation::UnsolicitedResponse),
+            (Message::PromotionFailed, ProtocolViolation::UnsolicitedPromotionFailed),
+        ];
+
+        // Ignored violations are only counted.
+        let (mut peer, their_id) = connect(ViolationPolicy::Ignore);
+        for &(ref msg, _) in &violations {
+            send(&mut peer, msg);
+        }
+        send(&mut peer, &Message::Data(vec![1]));
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::NewMessage(id, _, data, _) => {
+                assert_eq!(id, their_id);
+                assert_eq!(data, vec![1]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        let (tx, rx) = mpsc::channel();
+        with_connection(&el, token, move |ac, core, _| {
+            let _ = tx.send(ac.stats(core.now()));
+        });
+        let stats = unwrap!(rx.recv_timeout(Duration::from_secs(5)));
+        assert_eq!(stats.protocol_violations, violations.len() as u64);
+
+        for policy in vec![ViolationPolicy::Disconnect, ViolationPolicy::DisconnectAndBan(1)] {
+            for &(ref msg, violation) in &violations {
+                let (mut peer, their_id) = connect(policy);
+                send(&mut peer, msg);
+                match unwrap!(event_rx.recv_timeout(Duration::from_secs(5))) {
+                    Event::LostPeer(id, DisconnectReason::ProtocolViolation(v), _) => {
+                        assert_eq!(id, their_id);
+                        assert_eq!(v, violation);
+                    }
+                    event => panic!("Unexpected event: {:?}", event),
+                }
+                assert_eq!(is_banned(&peer), policy != ViolationPolicy::Disconnect);
+            }
+        }
+    }
+
+    #[test]
+    fn idle_heartbeats_stretch_in_low_power_mode() {
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        unwrap!(handle.send(CoreMessage::new(|core, _| {
+            core.set_coarse_tick(Duration::from_millis(100));
+            core.set_power_mode(PowerMode::LowPower);
+        })));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let their_id: UniqueId = rand::random();
+        let (stream, mut peer) = link();
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let settings = ConnectionSettings::default();
+        unwrap!(handle.send(start_on(
+            stream,
+            event_tx,
+            cm.clone(),
+            their_id,
+            CrustUser::Node,
+            settings,
+        )));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        match unwrap!(event_rx.try_recv()) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, their_id),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let token = unwrap!(unwrap!(cm.lock())[&their_id].active_connection);
+        unwrap!(peer.set_nonblocking(true));
+
+        // Runs the loop for `ms` of virtual time, the peer sending heartbeats all along, and
+        // returns when ours arrived, in milliseconds since the connection started.
+        let keepalive = unwrap!(encode_frame(&Message::Heartbeat::<UniqueId>));
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        let mut elapsed = 0;
+        let mut run_for = |el: &mut ManualEventLoop, peer: &mut StdTcpStream, ms: u64| {
+            let mut heartbeats = Vec::new();
+            for _ in 0..ms / 50 {
+                clock.advance(Duration::from_millis(50));
+                elapsed += 50;
+                if elapsed % 200 == 0 {
+                    unwrap!(peer.write_all(&keepalive));
+                }
+                assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+                loop {
+                    let mut input = match peer.read(&mut buf) {
+                        Ok(bytes_read) => &buf[..bytes_read],
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("Failed to read: {:?}", e),
+                    };
+                    while !input.is_empty() {
+                        if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                            let msg = unwrap!(decode_message::<Message<UniqueId>>(&body));
+                            if let Message::Heartbeat = msg {
+                                heartbeats.push(elapsed);
+                            }
+                        }
+                    }
+                }
+            }
+            heartbeats
+        };
+        let heartbeat_interval = |el: &mut ManualEventLoop| {
+            let (tx, rx) = mpsc::channel();
+            with_connection(&handle, token, move |ac, _, _| {
+                let _ = tx.send(ac.heartbeat.interval());
+            });
+            assert!(unwrap!(el.run_once(Duration::from_millis(2))));
+            unwrap!(rx.try_recv())
+        };
+
+        // The first heartbeat is sent after the period, the next ones after the ceiling, both
+        // put off to the next tick.
+        let period = HEARTBEAT_PERIOD_MS;
+        let ceiling = INACTIVITY_TIMEOUT_MS / 2;
+        let heartbeats = run_for(&mut el, &mut peer, 3_000);
+        assert_eq!(heartbeats[0], period);
+        for gap in heartbeats.windows(2).map(|pair| pair[1] - pair[0]) {
+            assert!(gap >= ceiling && gap <= ceiling + 100, "Gap of {} ms", gap);
+        }
+        assert_eq!(heartbeat_interval(&mut el), Duration::from_millis(ceiling));
+
+        // A message of the application brings the period back straight away.
+        unwrap!(peer.write_all(&unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![1])))));
+        let _ = run_for(&mut el, &mut peer, 50);
+        match unwrap!(event_rx.try_recv()) {
+            Event::NewMessage(id, _, data, _) => {
+                assert_eq!(id, their_id);
+                assert_eq!(data, vec![1]);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert_eq!(heartbeat_interval(&mut el), Duration::from_millis(period));
+    }
 }
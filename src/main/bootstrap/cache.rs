@@ -7,23 +7,133 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use config_file_handler::{self, FileHandler};
+//! Bootstrap cache which can be shared by several processes.
+//!
+//! Every update takes an advisory lock on a sidecar `.lock` file, re-reads the cache file, merges
+//! our changes into it and atomically replaces it through a temporary file. A cache file which
+//! can't be parsed is moved aside rather than failing the bootstrap.
+//!
+//! Every entry keeps a record of how reliable and fast the peer has been, which decides the order
+//! in which cached peers are tried. The event loop leaves recording the attempts to a
+//! `CacheWriter`, which writes them in batches on a thread of its own.
+
+use common::{Core, State};
+use config_file_handler;
+use fs2::FileExt;
+use main::{data_file_path, WriteBehind};
+use mio::{Poll, Token};
+use rand::Rng;
+use serde_json;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Token of the `CacheWriter` state, through which the event loop updates the bootstrap cache.
+pub const CACHE_WRITER_TOKEN: Token = Token(13);
+
+const MAX_BOOTSTRAP_CACHE_CONTACTS: usize = 1500;
+/// Round trip time assumed for peers which haven't been measured yet.
+const NEUTRAL_RTT_MS: u64 = 250;
+/// Peers scoring below this are only tried after the others, bar the odd exploration.
+const LOW_SCORE: f64 = 0.15;
+/// Probability of a low-scoring peer being tried among the healthy ones, so that it can recover.
+/// At least one is whenever there are both.
+const EXPLORATION_PROBABILITY: f64 = 0.1;
+/// A peer is forgotten once it has failed this many times more often than it succeeded.
+const MAX_FAILURE_SURPLUS: u32 = 5;
+/// Longest time an update made on the event loop waits to be written.
+const FLUSH_INTERVAL_SECS: u64 = 5;
+
+/// An entry of the bootstrap cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootstrapCacheEntry {
+    /// Address of the peer's listener.
+    pub addr: SocketAddr,
+    /// When we were last bootstrapped off this peer, in seconds since the Unix epoch.
+    pub last_seen: u64,
+    /// Outcome of our attempts to bootstrap off or connect to this peer.
+    #[serde(default)]
+    pub health: ContactHealth,
+}
 
-const _ENABLE_BOOTSTRAP_CACHE: bool = false;
-const _MAX_BOOTSTRAP_CACHE_CONTACTS: usize = 1500;
+/// How reliable and fast a cached peer has been.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ContactHealth {
+    /// Number of successful attempts.
+    pub successes: u32,
+    /// Number of failed attempts.
+    pub failures: u32,
+    /// Time, in milliseconds, the last successful attempt took to be answered.
+    pub last_rtt_ms: Option<u64>,
+    /// Why the last failed attempt failed.
+    pub last_failure: Option<ContactFailure>,
+}
+
+/// Reason of a failed attempt to reach a cached peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContactFailure {
+    /// The peer couldn't be reached or didn't complete the handshake.
+    Unreachable,
+    /// The peer refused us.
+    Denied,
+}
+
+impl ContactHealth {
+    /// Score between 0 and 1, higher being better. A peer without history scores 0.25.
+    pub fn score(&self) -> f64 {
+        let attempts = f64::from(self.successes) + f64::from(self.failures);
+        let reliability = (f64::from(self.successes) + 1.0) / (attempts + 2.0);
+        let rtt_ms = self.last_rtt_ms.unwrap_or(NEUTRAL_RTT_MS) as f64;
+        reliability * NEUTRAL_RTT_MS as f64 / (rtt_ms + NEUTRAL_RTT_MS as f64)
+    }
+
+    fn record(&mut self, res: Result<Duration, ContactFailure>) {
+        match res {
+            Ok(rtt) => {
+                self.successes = self.successes.saturating_add(1);
+                self.last_rtt_ms =
+                    Some(rtt.as_secs() * 1000 + u64::from(rtt.subsec_nanos() / 1_000_000));
+            }
+            Err(failure) => {
+                self.failures = self.failures.saturating_add(1);
+                self.last_failure = Some(failure);
+            }
+        }
+    }
+
+    fn attempts(&self) -> u64 {
+        u64::from(self.successes) + u64::from(self.failures)
+    }
+}
 
 pub struct Cache {
-    file_handler: FileHandler<Vec<SocketAddr>>,
+    path: PathBuf,
+    lock_path: PathBuf,
+    max_contacts: usize,
 }
 
 impl Cache {
     pub fn _cleanup() -> ::Res<()> {
-        config_file_handler::cleanup(&Self::get_default_file_name()?)?;
-        Ok(())
+        match fs::remove_file(data_file_path(&Self::get_default_file_name()?)?) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
     }
 
+    /// Opens the cache called `name`, or the default cache if `None`. An absolute path is used
+    /// as-is, which lets unrelated executables share a cache; any other name is put in the
+    /// platform's data directory, see `data_file_path`.
     pub fn new(name: &Option<String>) -> ::Res<Self> {
         let name = if let Some(name) = name.clone() {
             OsString::from(name)
@@ -31,9 +141,23 @@ impl Cache {
             Self::get_default_file_name()?
         };
 
-        Ok(Cache {
-            file_handler: FileHandler::new(&name, true)?, // last_updated: Instant::now(),
-        })
+        Ok(Self::with_path(data_file_path(&name)?, MAX_BOOTSTRAP_CACHE_CONTACTS))
+    }
+
+    fn with_path(path: PathBuf, max_contacts: usize) -> Self {
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+
+        Cache {
+            path,
+            lock_path: PathBuf::from(lock_path),
+            max_contacts,
+        }
+    }
+
+    /// Path of the cache file.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn get_default_file_name() -> ::Res<OsString> {
@@ -42,60 +166,648 @@ impl Cache {
         Ok(name)
     }
 
-    // pub fn update_contacts(&mut self,
-    //                        contacts: Vec<StaticContactInfo>,
-    //                        prune: Vec<StaticContactInfo>)
-    //                        -> ::Res<()> {
-    //     if ENABLE_BOOTSTRAP_CACHE {
-    //         try!(self.insert_contacts(contacts, prune));
-    //         // TODO(Team) this implementation is missing and should be considered in next
-    //         // planning
-    //         if Instant::now() > self.last_updated + Self::duration_between_updates() {
-    //             // self.check_bootstrap_contacts();
-    //         }
-    //     }
-
-    //     Ok(())
-    // }
-
-    pub fn read_file(&mut self) -> Vec<SocketAddr> {
-        self.file_handler.read_file().ok().unwrap_or_else(|| vec![])
-    }
-
-    pub fn remove_peer_acceptor(&mut self, _peer: SocketAddr) {}
-
-    // fn duration_between_updates() -> Duration {
-    //     Duration::from_secs(4 * 60 * 60)
-    // }
-
-    // fn max_contacts() -> usize {
-    //     MAX_BOOTSTRAP_CACHE_CONTACTS
-    // }
-
-    // fn insert_contacts(&mut self,
-    //                    mut contacts: Vec<StaticContactInfo>,
-    //                    prune: Vec<StaticContactInfo>)
-    //                    -> ::Res<()> {
-    //     let mut bootstrap_contacts = self.read_file().unwrap_or_else(|e| {
-    //         debug!("CrustError reading bootstrap cache file: {}.", e);
-    //         Vec::new()
-    //     });
-
-    //     bootstrap_contacts.retain(|contact| !prune.contains(&contact));
-    //     contacts.retain(|contact| !bootstrap_contacts.contains(&contact));
-
-    //     if bootstrap_contacts.is_empty() {
-    //         bootstrap_contacts = contacts;
-    //     } else {
-    //         loop {
-    //             if bootstrap_contacts.len() < Self::max_contacts() && !contacts.is_empty() {
-    //                 bootstrap_contacts.insert(0usize, contacts.remove(0usize));
-    //             } else {
-    //                 break;
-    //             }
-    //         }
-    //     }
-
-    //     Ok(try!(self.file_handler.write_file(&bootstrap_contacts)))
-    // }
+    /// Returns the cached peers in the order they should be tried in, see `order_candidates`.
+    pub fn candidates<R: Rng>(&mut self, rng: &mut R) -> Vec<SocketAddr> {
+        match self.snapshot() {
+            Ok(entries) => order_candidates(entries, rng),
+            Err(e) => {
+                debug!("Could not read bootstrap cache {:?}: {:?}", self.path, e);
+                vec![]
+            }
+        }
+    }
+
+    /// Returns all cache entries, most recently seen first.
+    pub fn snapshot(&self) -> ::Res<Vec<BootstrapCacheEntry>> {
+        let _lock = self.lock()?;
+        Ok(self.load())
+    }
+
+    /// Applies the `updates` made on the event loop, under a single lock and with a single write.
+    fn apply(&mut self, updates: &[CacheUpdate]) {
+        let res = self.update(|entries| {
+            for update in updates {
+                match *update {
+                    CacheUpdate::Acceptor {
+                        addr,
+                        rtt,
+                        last_seen,
+                    } => add_acceptor(entries, addr, rtt, last_seen),
+                    CacheUpdate::Attempt(peer, outcome) => record_attempt(entries, peer, outcome),
+                    CacheUpdate::Replace {
+                        ref stale,
+                        ref acceptors,
+                    } => replace_acceptors(entries, stale, acceptors),
+                }
+            }
+        });
+        if let Err(e) = res {
+            debug!("Could not update bootstrap cache {:?}: {:?}", self.path, e);
+        }
+    }
+
+    /// Merges `entries` into the cache: the union of both is kept, the newest `last_seen` and the
+    /// longest health record of an address win and the oldest entries are dropped beyond the size
+    /// cap.
+    pub fn merge_entries(&mut self, entries: &[BootstrapCacheEntry]) -> ::Res<()> {
+        self.update(|current| merge(current, entries))
+    }
+
+    fn update<F>(&mut self, f: F) -> ::Res<()>
+    where
+        F: FnOnce(&mut Vec<BootstrapCacheEntry>),
+    {
+        let _lock = self.lock()?;
+        let mut entries = self.load();
+        f(&mut entries);
+        sort_and_truncate(&mut entries, self.max_contacts);
+        self.store(&entries)
+    }
+
+    fn lock(&self) -> io::Result<FileLock> {
+        lock_file(&self.lock_path)
+    }
+
+    /// Reads the cache file. Must be called with the lock held.
+    fn load(&self) -> Vec<BootstrapCacheEntry> {
+        let mut contents = Vec::new();
+        match File::open(&self.path).and_then(|mut file| file.read_to_end(&mut contents)) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return vec![],
+            Err(e) => {
+                debug!("Could not read bootstrap cache {:?}: {:?}", self.path, e);
+                return vec![];
+            }
+        }
+
+        if contents.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return vec![];
+        }
+
+        let mut entries = match serde_json::from_slice::<Vec<BootstrapCacheEntry>>(&contents) {
+            Ok(entries) => entries,
+            // Caches written by older versions only hold the addresses.
+            Err(e) => match serde_json::from_slice::<Vec<SocketAddr>>(&contents) {
+                Ok(addrs) => addrs
+                    .into_iter()
+                    .map(|addr| BootstrapCacheEntry {
+                        addr,
+                        last_seen: 0,
+                        health: Default::default(),
+                    })
+                    .collect(),
+                Err(_) => {
+                    self.quarantine(&e);
+                    return vec![];
+                }
+            },
+        };
+        sort_and_truncate(&mut entries, self.max_contacts);
+        entries
+    }
+
+    /// Moves a corrupt cache file aside so the next write starts afresh and the file is left for
+    /// inspection.
+    fn quarantine(&self, error: &serde_json::Error) {
+        let mut corrupt_path = self.path.clone().into_os_string();
+        corrupt_path.push(format!(".corrupt-{}", now_secs()));
+        warn!(
+            "Bootstrap cache {:?} is corrupt ({}) - moving it to {:?}",
+            self.path, error, corrupt_path
+        );
+        if let Err(e) = fs::rename(&self.path, &corrupt_path) {
+            warn!(
+                "Could not quarantine bootstrap cache {:?}: {:?}",
+                self.path, e
+            );
+        }
+    }
+
+    /// Atomically replaces the cache file. Must be called with the lock held.
+    fn store(&self, entries: &[BootstrapCacheEntry]) -> ::Res<()> {
+        let contents =
+            serde_json::to_vec_pretty(entries).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        replace_file(&self.path, &contents)?;
+        Ok(())
+    }
+}
+
+/// An update of the bootstrap cache made on the event loop, see `CacheWriter`.
+#[derive(Debug, Clone)]
+pub enum CacheUpdate {
+    /// We bootstrapped off or checked the listener of a peer, which is cached if it wasn't yet.
+    Acceptor {
+        /// Address of the listener.
+        addr: SocketAddr,
+        /// Time the peer took to answer.
+        rtt: Duration,
+        /// When it answered, in seconds since the Unix epoch.
+        last_seen: u64,
+    },
+    /// The outcome of an attempt to bootstrap off or connect to a peer, recorded if it is
+    /// cached. A peer failing far more often than not is forgotten.
+    Attempt(SocketAddr, Result<Duration, ContactFailure>),
+    /// Replaces the `stale` addresses of a peer by its `acceptors`, which take over the newest of
+    /// the replaced entries. Nothing is changed unless the peer was cached.
+    Replace {
+        /// The addresses the peer was cached at.
+        stale: Vec<SocketAddr>,
+        /// The addresses it was reached at since.
+        acceptors: Vec<SocketAddr>,
+    },
+}
+
+/// Writes the updates of the bootstrap cache made on the event loop, in batches and on a thread
+/// of its own, so that the event loop waits neither for the lock nor for the disk.
+pub struct CacheWriter {
+    writer: WriteBehind<CacheUpdate>,
+}
+
+impl CacheWriter {
+    /// Queues `update` of the cache called `cache_name`, see `Cache::new`. The writer of the event
+    /// loop is started the first time.
+    pub fn send(core: &mut Core, cache_name: &Option<String>, update: CacheUpdate) {
+        if core.get_state(CACHE_WRITER_TOKEN).is_none() {
+            let mut cache = match Cache::new(cache_name) {
+                Ok(cache) => cache,
+                Err(e) => {
+                    debug!("Could not open bootstrap cache: {:?}", e);
+                    return;
+                }
+            };
+            let interval = Duration::from_secs(FLUSH_INTERVAL_SECS);
+            let write = move |updates: Vec<CacheUpdate>| cache.apply(&updates);
+            let writer = match WriteBehind::start("Bootstrap-Cache-Writer", interval, write) {
+                Ok(writer) => writer,
+                Err(e) => {
+                    debug!("Could not start the bootstrap cache writer: {:?}", e);
+                    return;
+                }
+            };
+            let state = Rc::new(RefCell::new(CacheWriter { writer }));
+            let _ = core.insert_state(CACHE_WRITER_TOKEN, state);
+        }
+        let _ = Self::with(core, |cache_writer| cache_writer.writer.send(update));
+    }
+
+    /// Has the updates queued so far written. The returned receiver is told once they are. `None`
+    /// if there are none.
+    pub fn flush(core: &mut Core) -> Option<Receiver<()>> {
+        Self::with(core, |cache_writer| cache_writer.writer.flush())
+    }
+
+    fn with<F, T>(core: &mut Core, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut CacheWriter) -> T,
+    {
+        let state = core.get_state(CACHE_WRITER_TOKEN)?;
+        let mut state = state.borrow_mut();
+        let res = state.as_any().downcast_mut::<CacheWriter>().map(f);
+        res
+    }
+}
+
+impl State for CacheWriter {
+    fn name(&self) -> &'static str {
+        "CacheWriter"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(CACHE_WRITER_TOKEN);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Takes an advisory lock on the file at `lock_path`, created if need be, until the returned
+/// guard is dropped.
+pub fn lock_file(lock_path: &Path) -> io::Result<FileLock> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(lock_path)?;
+    file.lock_exclusive()?;
+    Ok(FileLock(file))
+}
+
+/// Atomically replaces the file at `path` with `contents`, through a temporary file.
+pub fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(format!(".{}.tmp", process::id()));
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+/// Releases the advisory lock when dropped.
+pub struct FileLock(File);
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Adds or refreshes the entry of the peer listening at `addr`, see `CacheUpdate::Acceptor`.
+fn add_acceptor(
+    entries: &mut Vec<BootstrapCacheEntry>,
+    addr: SocketAddr,
+    rtt: Duration,
+    last_seen: u64,
+) {
+    if !entries.iter().any(|entry| entry.addr == addr) {
+        entries.push(BootstrapCacheEntry {
+            addr,
+            last_seen,
+            health: Default::default(),
+        });
+    }
+    for entry in entries.iter_mut().filter(|entry| entry.addr == addr) {
+        entry.last_seen = last_seen;
+        entry.health.record(Ok(rtt));
+    }
+}
+
+/// Records the outcome of an attempt in the entry of `peer`, if there is one, and forgets the
+/// peers failing far more often than not.
+fn record_attempt(
+    entries: &mut Vec<BootstrapCacheEntry>,
+    peer: SocketAddr,
+    outcome: Result<Duration, ContactFailure>,
+) {
+    for entry in entries.iter_mut().filter(|entry| entry.addr == peer) {
+        entry.health.record(outcome);
+    }
+    entries.retain(|entry| {
+        entry.health.failures < entry.health.successes.saturating_add(MAX_FAILURE_SURPLUS)
+    });
+}
+
+/// Replaces the `stale` entries of a peer by its `acceptors`, see `CacheUpdate::Replace`.
+fn replace_acceptors(
+    entries: &mut Vec<BootstrapCacheEntry>,
+    stale: &[SocketAddr],
+    acceptors: &[SocketAddr],
+) {
+    let newest = match entries
+        .iter()
+        .filter(|entry| stale.contains(&entry.addr))
+        .max_by_key(|entry| entry.last_seen)
+    {
+        Some(newest) => *newest,
+        None => return,
+    };
+    entries.retain(|entry| !stale.contains(&entry.addr));
+    let new_entries: Vec<_> = acceptors
+        .iter()
+        .map(|addr| BootstrapCacheEntry {
+            addr: *addr,
+            ..newest
+        })
+        .collect();
+    merge(entries, &new_entries);
+}
+
+fn merge(entries: &mut Vec<BootstrapCacheEntry>, new_entries: &[BootstrapCacheEntry]) {
+    let mut merged: HashMap<SocketAddr, BootstrapCacheEntry> =
+        entries.iter().map(|entry| (entry.addr, *entry)).collect();
+    for entry in new_entries {
+        let merged_entry = merged.entry(entry.addr).or_insert(*entry);
+        if merged_entry.last_seen < entry.last_seen {
+            merged_entry.last_seen = entry.last_seen;
+        }
+        if merged_entry.health.attempts() < entry.health.attempts() {
+            merged_entry.health = entry.health;
+        }
+    }
+
+    *entries = merged.into_iter().map(|(_, entry)| entry).collect();
+}
+
+/// Sorts `entries` by score, best first, and the equally scored ones most recently seen first.
+pub fn sort_by_score(entries: &mut Vec<BootstrapCacheEntry>) {
+    entries.sort_by(|lhs, rhs| {
+        rhs.health
+            .score()
+            .partial_cmp(&lhs.health.score())
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| rhs.last_seen.cmp(&lhs.last_seen))
+            .then_with(|| lhs.addr.cmp(&rhs.addr))
+    });
+}
+
+/// Orders the cached peers best scoring first. Each peer scoring below `LOW_SCORE` has a chance
+/// of `EXPLORATION_PROBABILITY` of being moved up among the healthy ones, and at least one of them
+/// always is, so a peer which had a bad spell gets a chance to recover.
+fn order_candidates<R: Rng>(mut entries: Vec<BootstrapCacheEntry>, rng: &mut R) -> Vec<SocketAddr> {
+    sort_by_score(&mut entries);
+    let split = entries
+        .iter()
+        .position(|entry| entry.health.score() < LOW_SCORE)
+        .unwrap_or_else(|| entries.len());
+    let mut candidates: Vec<SocketAddr> = entries[..split].iter().map(|entry| entry.addr).collect();
+    let mut low: Vec<SocketAddr> = entries[split..].iter().map(|entry| entry.addr).collect();
+    if candidates.is_empty() || low.is_empty() {
+        candidates.extend(low);
+        return candidates;
+    }
+
+    let mut explored: Vec<usize> = (0..low.len())
+        .filter(|_| rng.next_f64() < EXPLORATION_PROBABILITY)
+        .collect();
+    if explored.is_empty() {
+        explored.push(rng.gen_range(0, low.len()));
+    }
+    let healthy = candidates.len();
+    for index in explored.into_iter().rev() {
+        let pos = rng.gen_range(0, healthy);
+        candidates.insert(pos, low.remove(index));
+    }
+    candidates.extend(low);
+    candidates
+}
+
+fn sort_and_truncate(entries: &mut Vec<BootstrapCacheEntry>, max_contacts: usize) {
+    entries.sort_by(|lhs, rhs| {
+        rhs.last_seen
+            .cmp(&lhs.last_seen)
+            .then_with(|| lhs.addr.cmp(&rhs.addr))
+    });
+    entries.truncate(max_contacts);
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+
+    const ENTRIES_PER_HANDLE: u16 = 50;
+
+    fn temp_cache_path() -> PathBuf {
+        env::temp_dir().join(format!("crust-bootstrap-cache-{}", rand::random::<u64>()))
+    }
+
+    fn entry(port: u16, last_seen: u64) -> BootstrapCacheEntry {
+        BootstrapCacheEntry {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port),
+            last_seen,
+            health: Default::default(),
+        }
+    }
+
+    fn scored_entry(port: u16, successes: u32, failures: u32, rtt_ms: u64) -> BootstrapCacheEntry {
+        let mut entry = entry(port, 0);
+        entry.health = ContactHealth {
+            successes,
+            failures,
+            last_rtt_ms: Some(rtt_ms),
+            last_failure: None,
+        };
+        entry
+    }
+
+    fn cleanup(path: &Path) {
+        let cache = Cache::with_path(path.to_path_buf(), 0);
+        let _ = fs::remove_file(&cache.path);
+        let _ = fs::remove_file(&cache.lock_path);
+    }
+
+    #[test]
+    fn concurrent_handles_lose_no_entries() {
+        let path = temp_cache_path();
+
+        let threads: Vec<_> = (0..2u16)
+            .map(|handle| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let mut cache = Cache::with_path(path, MAX_BOOTSTRAP_CACHE_CONTACTS);
+                    for i in 0..ENTRIES_PER_HANDLE {
+                        let port = 1 + handle * ENTRIES_PER_HANDLE + i;
+                        unwrap!(cache.merge_entries(&[entry(port, u64::from(i))]));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            unwrap!(thread.join());
+        }
+
+        let cache = Cache::with_path(path.clone(), MAX_BOOTSTRAP_CACHE_CONTACTS);
+        let entries = unwrap!(cache.snapshot());
+        assert_eq!(entries.len(), 2 * ENTRIES_PER_HANDLE as usize);
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn merge_keeps_newest_and_respects_size_cap() {
+        let path = temp_cache_path();
+        let max_contacts = 10;
+
+        let mut cache_0 = Cache::with_path(path.clone(), max_contacts);
+        let mut cache_1 = Cache::with_path(path.clone(), max_contacts);
+
+        unwrap!(cache_0.merge_entries(&[entry(1, 5), entry(2, 5)]));
+        unwrap!(cache_1.merge_entries(&[entry(1, 3), entry(2, 7)]));
+
+        let entries = unwrap!(cache_0.snapshot());
+        assert_eq!(entries, vec![entry(2, 7), entry(1, 5)]);
+
+        let new_entries: Vec<_> = (100..120)
+            .map(|port| entry(port, u64::from(port)))
+            .collect();
+        unwrap!(cache_1.merge_entries(&new_entries));
+
+        let entries = unwrap!(cache_0.snapshot());
+        assert_eq!(entries.len(), max_contacts);
+        assert_eq!(entries[0], entry(119, 119));
+        assert_eq!(entries[max_contacts - 1], entry(110, 110));
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn replacing_acceptors_only_touches_cached_peers() {
+        let path = temp_cache_path();
+        let mut cache = Cache::with_path(path.clone(), MAX_BOOTSTRAP_CACHE_CONTACTS);
+        unwrap!(cache.merge_entries(&[entry(1, 5), entry(2, 7)]));
+
+        let addrs = |ports: &[u16]| ports.iter().map(|port| entry(*port, 0).addr).collect();
+        cache.apply(&[CacheUpdate::Replace {
+            stale: addrs(&[3]),
+            acceptors: addrs(&[4]),
+        }]);
+        assert_eq!(unwrap!(cache.snapshot()), vec![entry(2, 7), entry(1, 5)]);
+
+        cache.apply(&[CacheUpdate::Replace {
+            stale: addrs(&[1, 3]),
+            acceptors: addrs(&[5, 6]),
+        }]);
+        assert_eq!(
+            unwrap!(cache.snapshot()),
+            vec![entry(2, 7), entry(5, 5), entry(6, 5)]
+        );
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn candidates_are_ordered_by_health() {
+        let entries = vec![
+            entry(1, 100),
+            scored_entry(2, 1, 0, 500),
+            scored_entry(3, 3, 1, 100),
+            scored_entry(4, 10, 0, 20),
+        ];
+        let ports: Vec<u16> = order_candidates(entries, &mut rand::thread_rng())
+            .into_iter()
+            .map(|addr| addr.port())
+            .collect();
+        // Reliable and fast first, then the one without history, which beats the slow one.
+        assert_eq!(ports, vec![4, 3, 1, 2]);
+    }
+
+    #[test]
+    fn low_scoring_candidates_are_explored() {
+        const RUNS: usize = 2000;
+        let entries = vec![
+            scored_entry(1, 10, 0, 20),
+            scored_entry(2, 10, 0, 50),
+            scored_entry(3, 0, 4, 100),
+            scored_entry(4, 0, 4, 100),
+        ];
+        assert!(entries[2].health.score() < LOW_SCORE);
+
+        let mut explored = [0usize; 2];
+        let mut rng = rand::thread_rng();
+        for _ in 0..RUNS {
+            let ports: Vec<u16> = order_candidates(entries.clone(), &mut rng)
+                .into_iter()
+                .map(|addr| addr.port())
+                .collect();
+            let pos = |port| unwrap!(ports.iter().position(|p| *p == port));
+            assert!(pos(1) < pos(2));
+
+            let before_healthy: Vec<bool> = [3, 4].iter().map(|port| pos(*port) < pos(2)).collect();
+            // Never fewer than one low-scoring peer gets its chance.
+            assert!(before_healthy.contains(&true));
+            for (count, explored_now) in explored.iter_mut().zip(before_healthy) {
+                if explored_now {
+                    *count += 1;
+                }
+            }
+        }
+
+        // Each is explored with probability 0.1, or half the time nothing else was (0.81).
+        for count in &explored {
+            let rate = *count as f64 / RUNS as f64;
+            assert!(rate > 0.4 && rate < 0.6, "exploration rate {}", rate);
+        }
+    }
+
+    #[test]
+    fn attempts_update_health_and_evict_failing_peers() {
+        let path = temp_cache_path();
+        let mut cache = Cache::with_path(path.clone(), MAX_BOOTSTRAP_CACHE_CONTACTS);
+        let addr = entry(1, 0).addr;
+
+        let mut updates = vec![CacheUpdate::Acceptor {
+            addr,
+            rtt: Duration::from_millis(40),
+            last_seen: now_secs(),
+        }];
+        updates.push(CacheUpdate::Attempt(
+            entry(2, 0).addr,
+            Ok(Duration::from_millis(10)),
+        ));
+        for _ in 0..MAX_FAILURE_SURPLUS {
+            updates.push(CacheUpdate::Attempt(addr, Err(ContactFailure::Unreachable)));
+        }
+        cache.apply(&updates);
+        let entries = unwrap!(cache.snapshot());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].health,
+            ContactHealth {
+                successes: 1,
+                failures: MAX_FAILURE_SURPLUS,
+                last_rtt_ms: Some(40),
+                last_failure: Some(ContactFailure::Unreachable),
+            }
+        );
+
+        cache.apply(&[CacheUpdate::Attempt(addr, Err(ContactFailure::Denied))]);
+        assert!(unwrap!(cache.snapshot()).is_empty());
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn entries_without_health_are_neutral() {
+        let path = temp_cache_path();
+        {
+            let mut file = unwrap!(File::create(&path));
+            unwrap!(file.write_all(b"[{\"addr\": \"10.0.0.1:1\", \"last_seen\": 7}]"));
+        }
+
+        let cache = Cache::with_path(path.clone(), MAX_BOOTSTRAP_CACHE_CONTACTS);
+        let entries = unwrap!(cache.snapshot());
+        assert_eq!(entries, vec![entry(1, 7)]);
+        assert!((entries[0].health.score() - 0.25).abs() < 1e-9);
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn corrupt_file_is_quarantined() {
+        let path = temp_cache_path();
+        {
+            let mut file = unwrap!(File::create(&path));
+            unwrap!(file.write_all(b"[{\"addr\": \"10.0.0.1:1\", \"last_se"));
+        }
+
+        let mut cache = Cache::with_path(path.clone(), MAX_BOOTSTRAP_CACHE_CONTACTS);
+        assert!(cache.candidates(&mut rand::thread_rng()).is_empty());
+        assert!(!path.exists());
+
+        let parent = unwrap!(path.parent());
+        let file_name = unwrap!(unwrap!(path.file_name()).to_str()).to_owned();
+        let quarantined: Vec<_> = unwrap!(fs::read_dir(parent))
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|entry_path| {
+                entry_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| {
+                        name.starts_with(&format!("{}.corrupt-", file_name))
+                    })
+            })
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+
+        unwrap!(cache.merge_entries(&[entry(1, 1)]));
+        assert_eq!(unwrap!(cache.snapshot()), vec![entry(1, 1)]);
+
+        for quarantined_path in quarantined {
+            let _ = fs::remove_file(quarantined_path);
+        }
+        cleanup(&path);
+    }
 }
@@ -8,75 +8,141 @@
 // Software.
 
 mod cache;
+mod path_history;
 mod try_peer;
 
-use self::cache::Cache;
-use self::try_peer::TryPeer;
+pub use self::cache::{
+    sort_by_score, BootstrapCacheEntry, Cache, CacheUpdate, CacheWriter, ContactFailure,
+    ContactHealth,
+};
+pub use self::path_history::{IpVersion, PathHistory, PathKind};
+use self::try_peer::{Refusal, TryPeer};
 use common::{
-    BootstrapDenyReason, Core, CoreTimer, CrustUser, ExternalReachability, NameHash, Socket, State,
+    BootstrapDenyReason, ChildrenSet, ConnectionDirection, Core, CoreTimer, CrustUser,
+    DialHandler, DialSettings, DialTarget, DialVia, Dialer, ExternalReachability,
+    NegotiatedFeatures, NetworkId, RecordedEventKind, Rejection, RejectionCode, Socket, State,
     Uid,
 };
-use main::{ActiveConnection, ConnectionMap, CrustConfig, CrustError, Event};
-use mio::timer::Timeout;
+use main::{
+    now_secs, ActiveConnection, ConnectionMap, ConnectionSettings, CrustConfig, CrustError, Event,
+    EventSink,
+};
 use mio::{Poll, Token};
+use nat;
 use rand::{self, Rng};
+#[cfg(feature = "service-discovery")]
 use service_discovery::ServiceDiscovery;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::{Rc, Weak};
-use std::sync::mpsc::{self, Receiver};
-use std::time::Duration;
+#[cfg(feature = "service-discovery")]
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const BOOTSTRAP_TIMEOUT_SEC: u64 = 10;
+pub const BOOTSTRAP_TIMEOUT_SEC: u64 = 10;
+#[cfg(feature = "service-discovery")]
 const SERVICE_DISCOVERY_TIMEOUT_SEC: u64 = 1;
-const BOOTSTRAP_TIMER_ID: u8 = 0;
-const SERVICE_DISCOVERY_TIMER_ID: u8 = BOOTSTRAP_TIMER_ID + 1;
+const BOOTSTRAP_TIMER_ID: u64 = 0;
+#[cfg(feature = "service-discovery")]
+const SERVICE_DISCOVERY_TIMER_ID: u64 = BOOTSTRAP_TIMER_ID + 1;
 const MAX_CONTACTS_EXPECTED: usize = 1500;
+/// Most peers dialled at once. Those left are dialled as the attempts finish.
+const MAX_PARALLEL_DIALS: usize = 32;
+/// Time within which a peer has to accept our connection, so that the peers which don't answer at
+/// all don't hold up the others for the whole bootstrap.
+const DIAL_TIMEOUT_SEC: u64 = 3;
+/// Longest we keep off a peer which rejected us, whatever it asked for.
+const MAX_RETRY_AFTER_SECS: u64 = 24 * 60 * 60;
+
+/// Peers which rejected a bootstrap of ours, with the time they asked us to wait until before
+/// trying them again. Kept by the `Service` across bootstraps.
+pub type RetryAfter = Arc<Mutex<HashMap<SocketAddr, Instant>>>;
 
 pub struct Bootstrap<UID: Uid> {
     token: Token,
     cm: ConnectionMap<UID>,
+    /// Cached peers, in the order they should be tried in.
+    cached_peers: Vec<SocketAddr>,
+    /// Other peers, which are tried in random order after the cached ones.
     peers: Vec<SocketAddr>,
     blacklist: HashSet<SocketAddr>,
-    name_hash: NameHash,
+    /// Peers dialled over WebSocket rather than TCP, see `Config::websocket_contacts`.
+    #[cfg(feature = "websocket")]
+    websocket_contacts: HashSet<SocketAddr>,
+    /// Whether peers at public addresses are skipped, see `Config::lan_only`.
+    lan_only: bool,
+    retry_after: RetryAfter,
+    network: NetworkId,
     ext_reachability: ExternalReachability,
     our_uid: UID,
-    event_tx: ::CrustEventSender<UID>,
-    sd_meta: Option<ServiceDiscMeta>,
-    bs_timer: CoreTimer,
-    bs_timeout: Timeout,
-    cache: Cache,
-    children: HashSet<Token>,
+    event_tx: EventSink<UID>,
+    /// Peers found by service discovery, until we stop waiting for them.
+    sd_rx: Option<Receiver<Vec<SocketAddr>>>,
+    cache_name: Option<String>,
+    children: ChildrenSet,
+    settings: ConnectionSettings,
+    outbound_bind_addr: Option<IpAddr>,
+    /// Whether the bootstrap has ended, see `Bootstrap::finish`.
+    finished: bool,
     self_weak: Weak<RefCell<Bootstrap<UID>>>,
 }
 
+/// How a bootstrap ends.
+enum Outcome<UID: Uid> {
+    /// Connected to the given peer, reported by its `ActiveConnection` with
+    /// `Event::BootstrapConnect`.
+    Connected(Token, Socket, SocketAddr, UID, NegotiatedFeatures),
+    /// Reported with `Event::BootstrapFailed`.
+    Failed,
+    /// Stopped with `Service::stop_bootstrap`, or along with the `Service`. Not reported, as the
+    /// caller asked for it.
+    Stopped,
+}
+
 impl<UID: Uid> Bootstrap<UID> {
     pub fn start(
         core: &mut Core,
         poll: &Poll,
-        name_hash: NameHash,
+        network: NetworkId,
         ext_reachability: ExternalReachability,
         our_uid: UID,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
         blacklist: HashSet<SocketAddr>,
+        retry_after: RetryAfter,
         token: Token,
         service_discovery_token: Token,
-        event_tx: ::CrustEventSender<UID>,
+        event_tx: EventSink<UID>,
     ) -> ::Res<()> {
         let mut peers = Vec::with_capacity(MAX_CONTACTS_EXPECTED);
 
-        let mut cache = Cache::new(&unwrap!(config.lock()).cfg.bootstrap_cache_name)?;
-        peers.extend(cache.read_file());
+        let cache_name = unwrap!(config.lock()).cfg.bootstrap_cache_name.clone();
+        let mut cache = Cache::new(&cache_name)?;
+        let cached_peers = cache.candidates(&mut rand::thread_rng());
         peers.extend(unwrap!(config.lock()).cfg.hard_coded_contacts.clone());
+        #[cfg(feature = "websocket")]
+        let websocket_contacts: HashSet<_> = unwrap!(config.lock())
+            .cfg
+            .websocket_contacts
+            .iter()
+            .cloned()
+            .collect();
+        #[cfg(feature = "websocket")]
+        peers.extend(websocket_contacts.iter().cloned());
+        let settings = ConnectionSettings::from_config(&unwrap!(config.lock()).cfg);
+        let outbound_bind_addr = unwrap!(config.lock()).cfg.outbound_bind_addr;
+        let lan_only = unwrap!(config.lock()).cfg.lan_only;
 
         let bs_timer = CoreTimer::new(token, BOOTSTRAP_TIMER_ID);
-        let bs_timeout = core.set_timeout(Duration::from_secs(BOOTSTRAP_TIMEOUT_SEC), bs_timer)?;
-        let sd_meta = match seek_peers(core, service_discovery_token, token) {
-            Ok((rx, timeout)) => Some(ServiceDiscMeta { rx, timeout }),
+        core.set_timeout(Duration::from_secs(BOOTSTRAP_TIMEOUT_SEC), bs_timer)?;
+        let sd_rx = match seek_peers(core, poll, service_discovery_token, token) {
+            Ok(rx) => Some(rx),
             Err(CrustError::ServiceDiscNotEnabled) => None,
             Err(e) => {
                 warn!("Failed to seek peers using service discovery: {:?}", e);
@@ -87,17 +153,23 @@ impl<UID: Uid> Bootstrap<UID> {
         let state = Rc::new(RefCell::new(Self {
             token,
             cm,
+            cached_peers,
             peers,
             blacklist,
-            name_hash,
+            #[cfg(feature = "websocket")]
+            websocket_contacts,
+            lan_only,
+            retry_after,
+            network,
             ext_reachability,
             our_uid,
             event_tx,
-            sd_meta,
-            bs_timer,
-            bs_timeout,
-            cache,
-            children: HashSet::with_capacity(MAX_CONTACTS_EXPECTED),
+            sd_rx,
+            cache_name,
+            children: ChildrenSet::with_capacity(MAX_CONTACTS_EXPECTED),
+            settings,
+            outbound_bind_addr,
+            finished: false,
             self_weak: Weak::new(),
         }));
 
@@ -105,7 +177,7 @@ impl<UID: Uid> Bootstrap<UID> {
 
         let _ = core.insert_state(token, state.clone());
 
-        if state.borrow().sd_meta.is_none() {
+        if state.borrow().sd_rx.is_none() {
             state.borrow_mut().begin_bootstrap(core, poll);
         }
 
@@ -113,35 +185,81 @@ impl<UID: Uid> Bootstrap<UID> {
     }
 
     fn begin_bootstrap(&mut self, core: &mut Core, poll: &Poll) {
-        let mut peers = mem::replace(&mut self.peers, Vec::new());
+        let mut others = mem::replace(&mut self.peers, Vec::new());
+        rand::thread_rng().shuffle(&mut others);
+        let mut peers = mem::replace(&mut self.cached_peers, Vec::new());
+        let mut seen: HashSet<SocketAddr> = peers.iter().cloned().collect();
+        peers.extend(others.into_iter().filter(|addr| seen.insert(*addr)));
         peers.retain(|addr| !self.blacklist.contains(addr));
+        {
+            let now = core.now();
+            let mut retry_after = unwrap!(self.retry_after.lock());
+            retry_after.retain(|_, deadline| *deadline > now);
+            peers.retain(|addr| {
+                let waiting = retry_after.contains_key(addr);
+                if waiting {
+                    debug!("Not bootstrapping off {} before it asked us to retry", addr);
+                }
+                !waiting
+            });
+        }
+        if self.lan_only {
+            peers.retain(|addr| {
+                let global = nat::ip_addr_is_global(&addr.ip());
+                if global {
+                    debug!("Not bootstrapping off {} in LAN-only mode", addr);
+                }
+                !global
+            });
+        }
         if peers.is_empty() {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+            return self.finish(core, poll, Outcome::Failed);
         }
-        rand::thread_rng().shuffle(&mut peers);
 
-        for peer in peers {
-            let self_weak = self.self_weak.clone();
-            let finish = move |core: &mut Core, poll: &Poll, child, res| {
-                if let Some(self_rc) = self_weak.upgrade() {
-                    self_rc.borrow_mut().handle_result(core, poll, child, res)
+        // Up to `MAX_PARALLEL_DIALS` peers are dialled at once, and each is tried as soon as it
+        // accepts our connection.
+        let targets = peers
+            .into_iter()
+            .map(|peer| {
+                core.record(self.token, RecordedEventKind::BootstrapAttempt(peer));
+                #[cfg(feature = "websocket")]
+                let via = if self.websocket_contacts.contains(&peer) {
+                    DialVia::WebSocket
+                } else {
+                    DialVia::Tcp
+                };
+                #[cfg(not(feature = "websocket"))]
+                let via = DialVia::Tcp;
+                DialTarget {
+                    addr: peer,
+                    via,
+                    context: (),
                 }
-            };
-
-            if let Ok(child) = TryPeer::start(
-                core,
-                poll,
-                peer,
-                self.our_uid,
-                self.name_hash,
-                self.ext_reachability.clone(),
-                Box::new(finish),
-            ) {
-                let _ = self.children.insert(child);
+            })
+            .collect();
+        let settings = DialSettings {
+            stagger: Duration::from_secs(0),
+            attempt_timeout: Some(Duration::from_secs(DIAL_TIMEOUT_SEC)),
+            deadline: None,
+            max_parallel: MAX_PARALLEL_DIALS,
+            bind_ip: self.outbound_bind_addr,
+        };
+        let handler: Weak<RefCell<DialHandler<()>>> = self.self_weak.clone();
+        let children = self.children.handle();
+        match Dialer::start(core, poll, targets, settings, children, handler) {
+            Ok(dialer) => self.children.insert(dialer),
+            Err(e) => {
+                debug!("Failed to dial the bootstrap peers: {:?}", e);
+                self.finish(core, poll, Outcome::Failed);
             }
         }
-        self.maybe_terminate(core, poll);
+    }
+
+    /// Records that `peer` couldn't be bootstrapped off.
+    fn record_unreachable(&mut self, core: &mut Core, peer: SocketAddr) {
+        core.record(self.token, RecordedEventKind::BootstrapFailed(peer));
+        let update = CacheUpdate::Attempt(peer, Err(ContactFailure::Unreachable));
+        CacheWriter::send(core, &self.cache_name, update);
     }
 
     fn handle_result(
@@ -149,90 +267,184 @@ impl<UID: Uid> Bootstrap<UID> {
         core: &mut Core,
         poll: &Poll,
         child: Token,
-        res: Result<(Socket, SocketAddr, UID), (SocketAddr, Option<BootstrapDenyReason>)>,
+        res: Result<
+            (Socket, SocketAddr, UID, Duration, NegotiatedFeatures),
+            (SocketAddr, Option<Refusal>),
+        >,
     ) {
-        let _ = self.children.remove(&child);
+        // A failed child has deregistered itself already. Once the bootstrap has finished, one
+        // which still got to finish can't change the outcome: a connection it made is dropped.
+        let _ = self.children.remove(child);
+        if self.finished {
+            debug!("Ignoring result of terminated bootstrap attempt {:?}", child);
+            return;
+        }
         match res {
-            Ok((socket, peer_addr, peer_id)) => {
-                self.terminate(core, poll);
-                return ActiveConnection::start(
+            Ok((socket, peer_addr, peer_id, rtt, features)) => {
+                core.record(child, RecordedEventKind::BootstrapSucceeded(peer_addr));
+                let update = CacheUpdate::Acceptor {
+                    addr: peer_addr,
+                    rtt,
+                    last_seen: now_secs(),
+                };
+                CacheWriter::send(core, &self.cache_name, update);
+                let outcome = Outcome::Connected(child, socket, peer_addr, peer_id, features);
+                return self.finish(core, poll, outcome);
+            }
+            Err((bad_peer, refusal)) => {
+                core.record(child, RecordedEventKind::BootstrapFailed(bad_peer));
+                let failure = if refusal.is_some() {
+                    ContactFailure::Denied
+                } else {
+                    ContactFailure::Unreachable
+                };
+                let update = CacheUpdate::Attempt(bad_peer, Err(failure));
+                CacheWriter::send(core, &self.cache_name, update);
+                let is_err_fatal = match refusal {
+                    Some(Refusal::Denied(reason)) => handle_deny_reason(bad_peer, &reason),
+                    Some(Refusal::Rejected(rejection)) => {
+                        self.handle_rejection(core, bad_peer, rejection)
+                    }
+                    None => false,
+                };
+                if is_err_fatal {
+                    return self.finish(core, poll, Outcome::Failed);
+                }
+            }
+        }
+        self.maybe_terminate(core, poll);
+    }
+
+    /// Reports the rejection and skips the peer in bootstraps until it asked us to retry, if it
+    /// did. Returns whether the rejection is fatal to the bootstrap.
+    fn handle_rejection(
+        &mut self,
+        core: &mut Core,
+        peer: SocketAddr,
+        rejection: Rejection,
+    ) -> bool {
+        let kind = rejection.kind();
+        info!("Failed to Bootstrap with {}: ({:?}) {}", peer, kind, rejection.message);
+        if let Some(secs) = rejection.retry_after_secs {
+            let secs = cmp::min(secs, MAX_RETRY_AFTER_SECS);
+            let deadline = core.now() + Duration::from_secs(secs);
+            let _ = unwrap!(self.retry_after.lock()).insert(peer, deadline);
+        }
+        self.event_tx.send(Event::BootstrapAttemptFailed(peer, rejection));
+        kind == RejectionCode::WrongNetwork
+    }
+
+    fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if self.children.is_empty() {
+            error!("Bootstrapper has no active children left - bootstrap has failed");
+            self.finish(core, poll, Outcome::Failed);
+        }
+    }
+
+    /// Ends the bootstrap, reporting its outcome. Every way a bootstrap can end goes through here
+    /// and only the first counts, so that exactly one of `Event::BootstrapConnect` and
+    /// `Event::BootstrapFailed` is sent, or neither if the bootstrap was stopped first.
+    fn finish(&mut self, core: &mut Core, poll: &Poll, outcome: Outcome<UID>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        self.children.terminate_all(core, poll);
+        self.sd_rx = None;
+        let _ = core.remove_state(self.token);
+
+        match outcome {
+            Outcome::Connected(token, socket, peer_addr, peer_id, features) => {
+                ActiveConnection::start(
                     core,
                     poll,
-                    child,
+                    token,
                     socket,
                     self.cm.clone(),
                     self.our_uid,
                     peer_id,
                     // Note; We bootstrap only to Nodes
                     CrustUser::Node,
-                    Event::BootstrapConnect(peer_id, peer_addr),
+                    Event::BootstrapConnect(peer_id, peer_addr, features.peer_key),
                     self.event_tx.clone(),
-                );
+                    self.settings.clone(),
+                    features,
+                    Some(ConnectionDirection::Outbound),
+                )
             }
-            Err((bad_peer, opt_reason)) => {
-                self.cache.remove_peer_acceptor(bad_peer);
-                if let Some(reason) = opt_reason {
-                    let mut is_err_fatal = true;
-                    let err_msg = match reason {
-                        BootstrapDenyReason::InvalidNameHash => "Network name mismatch.",
-                        #[cfg_attr(rustfmt, rustfmt_skip)]
-                        BootstrapDenyReason::FailedExternalReachability => {
-                            "Bootstrappee node could not establish connection to us."
-                        },
-                        BootstrapDenyReason::NodeNotWhitelisted => {
-                            is_err_fatal = false;
-                            "Our Node is not whitelisted"
-                        }
-                        BootstrapDenyReason::ClientNotWhitelisted => {
-                            is_err_fatal = false;
-                            "Our Client is not whitelisted"
-                        }
-                    };
-                    if is_err_fatal {
-                        error!("Failed to Bootstrap: ({:?}) {}", reason, err_msg);
-                        self.terminate(core, poll);
-                        let _ = self.event_tx.send(Event::BootstrapFailed);
-                        return;
-                    } else {
-                        info!(
-                            "Failed to Bootstrap with {}: ({:?}) {}",
-                            bad_peer, reason, err_msg
-                        );
-                    }
-                }
+            Outcome::Failed => {
+                self.event_tx.send(Event::BootstrapFailed);
             }
+            Outcome::Stopped => (),
         }
-        self.maybe_terminate(core, poll);
     }
+}
 
-    fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
-        if self.children.is_empty() {
-            error!("Bootstrapper has no active children left - bootstrap has failed");
-            self.terminate(core, poll);
-            let _ = self.event_tx.send(Event::BootstrapFailed);
+impl<UID: Uid> DialHandler<()> for Bootstrap<UID> {
+    fn established(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        _context: (),
+        peer: SocketAddr,
+        socket: Socket,
+    ) {
+        if self.finished {
+            return;
+        }
+        let self_weak = self.self_weak.clone();
+        let finish = move |core: &mut Core, poll: &Poll, child, res| {
+            if let Some(self_rc) = self_weak.upgrade() {
+                self_rc.borrow_mut().handle_result(core, poll, child, res)
+            }
+        };
+
+        match TryPeer::start(
+            core,
+            poll,
+            peer,
+            socket,
+            self.our_uid,
+            self.network,
+            self.ext_reachability.clone(),
+            self.settings.timestamp_frames,
+            self.settings.relay,
+            self.children.handle(),
+            Box::new(finish),
+        ) {
+            Ok(child) => self.children.insert(child),
+            Err(e) => {
+                debug!("Failed to try bootstrapping off {}: {:?}", peer, e);
+                self.record_unreachable(core, peer);
+            }
         }
     }
 
-    fn terminate_children(&mut self, core: &mut Core, poll: &Poll) {
-        for child in self.children.drain() {
-            let child = match core.get_state(child) {
-                Some(state) => state,
-                None => continue,
-            };
+    fn failed(&mut self, core: &mut Core, _poll: &Poll, _context: (), peer: SocketAddr) {
+        if !self.finished {
+            self.record_unreachable(core, peer);
+        }
+    }
 
-            child.borrow_mut().terminate(core, poll);
+    fn finished(&mut self, core: &mut Core, poll: &Poll) {
+        if !self.finished {
+            self.maybe_terminate(core, poll);
         }
     }
 }
 
 impl<UID: Uid> State for Bootstrap<UID> {
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
-        if timer_id == self.bs_timer.timer_id {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+    fn name(&self) -> &'static str {
+        "Bootstrap"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u64) {
+        if timer_id == BOOTSTRAP_TIMER_ID {
+            return self.finish(core, poll, Outcome::Failed);
         }
 
-        let rx = unwrap!(self.sd_meta.take()).rx;
+        let rx = unwrap!(self.sd_rx.take());
 
         while let Ok(listeners) = rx.try_recv() {
             self.peers.extend(listeners);
@@ -242,12 +454,7 @@ impl<UID: Uid> State for Bootstrap<UID> {
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        self.terminate_children(core, poll);
-        if let Some(sd_meta) = self.sd_meta.take() {
-            let _ = core.cancel_timeout(&sd_meta.timeout);
-        }
-        let _ = core.remove_state(self.token);
-        let _ = core.cancel_timeout(&self.bs_timeout);
+        self.finish(core, poll, Outcome::Stopped);
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -255,30 +462,78 @@ impl<UID: Uid> State for Bootstrap<UID> {
     }
 }
 
-struct ServiceDiscMeta {
-    rx: Receiver<Vec<SocketAddr>>,
-    timeout: Timeout,
+/// Logs why the peer denied our bootstrap request. Returns whether the reason is fatal to the
+/// bootstrap.
+fn handle_deny_reason(peer: SocketAddr, reason: &BootstrapDenyReason) -> bool {
+    let mut is_err_fatal = true;
+    let err_msg = match *reason {
+        BootstrapDenyReason::InvalidNameHash => "Network name mismatch.",
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        BootstrapDenyReason::FailedExternalReachability => {
+            "Bootstrappee node could not establish connection to us."
+        },
+        BootstrapDenyReason::NodeNotWhitelisted => {
+            is_err_fatal = false;
+            "Our Node is not whitelisted"
+        }
+        BootstrapDenyReason::ClientNotWhitelisted => {
+            is_err_fatal = false;
+            "Our Client is not whitelisted"
+        }
+        BootstrapDenyReason::PowRequired(_) => {
+            is_err_fatal = false;
+            "Bootstrappee requires a proof of work we did not provide. Upgrade \
+             required."
+        }
+        BootstrapDenyReason::InvalidPow => {
+            is_err_fatal = false;
+            "Our proof of work was rejected"
+        }
+    };
+    if is_err_fatal {
+        error!("Failed to Bootstrap: ({:?}) {}", reason, err_msg);
+    } else {
+        info!("Failed to Bootstrap with {}: ({:?}) {}", peer, reason, err_msg);
+    }
+    is_err_fatal
 }
 
+#[cfg(feature = "service-discovery")]
 fn seek_peers(
     core: &mut Core,
+    poll: &Poll,
     service_discovery_token: Token,
     token: Token,
-) -> ::Res<(Receiver<Vec<SocketAddr>>, Timeout)> {
+) -> ::Res<Receiver<Vec<SocketAddr>>> {
     if let Some(state) = core.get_state(service_discovery_token) {
         let mut state = state.borrow_mut();
         let state = unwrap!(state.as_any().downcast_mut::<ServiceDiscovery>());
 
+        // Service discovery handles its own failures, bootstrapping just goes without it.
+        if let Err(e) = state.seek_peers(core, poll) {
+            debug!("Not seeking peers using service discovery: {}", e);
+            return Err(CrustError::ServiceDiscNotEnabled);
+        }
         let (obs, rx) = mpsc::channel();
         state.register_observer(obs);
-        state.seek_peers()?;
-        let timeout = core.set_timeout(
+        core.set_timeout(
             Duration::from_secs(SERVICE_DISCOVERY_TIMEOUT_SEC),
             CoreTimer::new(token, SERVICE_DISCOVERY_TIMER_ID),
         )?;
 
-        Ok((rx, timeout))
+        Ok(rx)
     } else {
         Err(CrustError::ServiceDiscNotEnabled)
     }
 }
+
+/// Bootstrapping goes without service discovery when it is compiled out.
+#[cfg(not(feature = "service-discovery"))]
+fn seek_peers(
+    _core: &mut Core,
+    _poll: &Poll,
+    _service_discovery_token: Token,
+    _token: Token,
+) -> ::Res<Receiver<Vec<SocketAddr>>> {
+    Err(CrustError::ServiceDiscNotEnabled)
+}
@@ -0,0 +1,436 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Which kinds of path to each peer we connected to have worked, kept next to the bootstrap cache
+//! and updated the same way, so that the candidates which worked last time are dialled first.
+//! The event loop keeps the history in memory and leaves the writes to a thread of their own.
+
+use super::cache::{lock_file, replace_file, Cache, FileLock};
+use common::{Core, State, Uid};
+use main::{CandidateAddr, WriteBehind};
+use mio::{Poll, Token};
+use nat;
+use serde_json;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Maximum number of peers whose paths are remembered. The least recently updated go first.
+const MAX_PEERS: usize = 1024;
+/// Time after which what was learned about a path is forgotten, so that a peer which moved
+/// networks gets a fresh start.
+const MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+/// Score of a kind of path which hasn't been tried yet.
+const NEUTRAL_SCORE: f64 = 0.5;
+
+/// The kind of path a connection candidate takes, by transport, scope and IP version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PathKind {
+    /// A direct connection to a private, link-local or loopback address.
+    Lan(IpVersion),
+    /// A direct connection to a global address.
+    Direct(IpVersion),
+    /// A hole punched connection to an address mapped through a NAT.
+    Mapped(IpVersion),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl PathKind {
+    pub fn of(candidate: &CandidateAddr) -> Self {
+        let ip = candidate.addr().ip();
+        let version = match ip {
+            IpAddr::V4(_) => IpVersion::V4,
+            IpAddr::V6(_) => IpVersion::V6,
+        };
+        match *candidate {
+            CandidateAddr::TcpMapped(_) => PathKind::Mapped(version),
+            _ if nat::ip_addr_is_global(&ip) => PathKind::Direct(version),
+            _ => PathKind::Lan(version),
+        }
+    }
+}
+
+/// Outcome of the attempts to connect to a peer over one kind of path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct PathRecord {
+    kind: PathKind,
+    successes: u32,
+    failures: u32,
+    /// When a connection over this kind of path last succeeded, in seconds since the Unix epoch.
+    last_success: Option<u64>,
+    /// When the record last changed, in seconds since the Unix epoch.
+    updated: u64,
+}
+
+impl PathRecord {
+    /// Score between 0 and 1, higher being better, see `NEUTRAL_SCORE`.
+    fn score(&self) -> f64 {
+        let attempts = f64::from(self.successes) + f64::from(self.failures);
+        (f64::from(self.successes) + 1.0) / (attempts + 2.0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "UID: Uid")]
+struct PeerPaths<UID> {
+    peer: UID,
+    paths: Vec<PathRecord>,
+}
+
+impl<UID> PeerPaths<UID> {
+    fn updated(&self) -> u64 {
+        self.paths.iter().map(|path| path.updated).max().unwrap_or(0)
+    }
+}
+
+/// Token of the `PathHistory` state, which the attempts to connect share.
+pub const PATH_HISTORY_TOKEN: Token = Token(12);
+
+/// Longest time an outcome recorded waits to be written to the file.
+const FLUSH_INTERVAL_SECS: u64 = 10;
+
+/// Whether connecting to a peer over a kind of path succeeded, and when.
+struct PathOutcome<UID> {
+    peer: UID,
+    kind: PathKind,
+    succeeded: bool,
+    at: u64,
+}
+
+/// The path history of an event loop. The file is read once, when the history is first asked
+/// for; the outcomes recorded then update the history in memory and are written to the file, in
+/// batches, by a thread of its own.
+pub struct PathHistory<UID: Uid> {
+    peers: Vec<PeerPaths<UID>>,
+    max_peers: usize,
+    writer: Option<WriteBehind<PathOutcome<UID>>>,
+}
+
+impl<UID: Uid> PathHistory<UID> {
+    /// Runs `f` on the history of the event loop, read from the file kept next to the bootstrap
+    /// cache called `cache_name` the first time, see `Cache::new`.
+    pub fn with<F, T>(core: &mut Core, cache_name: &Option<String>, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut PathHistory<UID>) -> T,
+    {
+        if core.get_state(PATH_HISTORY_TOKEN).is_none() {
+            let file = match PathFile::new(cache_name) {
+                Ok(file) => file,
+                Err(e) => {
+                    debug!("Could not open path history: {:?}", e);
+                    return None;
+                }
+            };
+            let state = Rc::new(RefCell::new(PathHistory::<UID>::open(file)));
+            let _ = core.insert_state(PATH_HISTORY_TOKEN, state);
+        }
+        let state = core.get_state(PATH_HISTORY_TOKEN)?;
+        let mut state = state.borrow_mut();
+        let res = state.as_any().downcast_mut::<PathHistory<UID>>().map(f);
+        res
+    }
+
+    fn open(file: PathFile) -> Self {
+        let peers = match file.lock() {
+            Ok(_lock) => file.load(now_secs()),
+            Err(e) => {
+                debug!("Could not read path history {:?}: {:?}", file.path, e);
+                vec![]
+            }
+        };
+        let max_peers = file.max_peers;
+        let interval = Duration::from_secs(FLUSH_INTERVAL_SECS);
+        let write = move |outcomes: Vec<PathOutcome<UID>>| file.write(&outcomes);
+        let writer = match WriteBehind::start("Path-History-Writer", interval, write) {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                debug!("Could not start the path history writer: {:?}", e);
+                None
+            }
+        };
+        PathHistory {
+            peers,
+            max_peers,
+            writer,
+        }
+    }
+
+    /// Sorts `candidates` by how well their kind of path to `peer` has worked, best first. Kinds
+    /// not tried yet rank above those which failed more often than not. The order of equally
+    /// ranked candidates is kept, so a peer we know nothing about keeps the default order.
+    pub fn order(&self, peer: &UID, candidates: &mut Vec<CandidateAddr>) {
+        let paths = match self.peers.iter().find(|entry| entry.peer == *peer) {
+            Some(entry) => &entry.paths,
+            None => return,
+        };
+        let score = |candidate: &CandidateAddr| {
+            let kind = PathKind::of(candidate);
+            paths
+                .iter()
+                .find(|path| path.kind == kind)
+                .map_or(NEUTRAL_SCORE, PathRecord::score)
+        };
+        candidates.sort_by(|lhs, rhs| {
+            score(rhs)
+                .partial_cmp(&score(lhs))
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+
+    /// Records whether connecting to `peer` over a path of the given kind succeeded.
+    pub fn record(&mut self, peer: &UID, kind: PathKind, succeeded: bool) {
+        let outcome = PathOutcome {
+            peer: *peer,
+            kind,
+            succeeded,
+            at: now_secs(),
+        };
+        apply(&mut self.peers, &outcome);
+        sort_and_truncate(&mut self.peers, self.max_peers);
+        if let Some(ref writer) = self.writer {
+            writer.send(outcome);
+        }
+    }
+}
+
+impl<UID: Uid> State for PathHistory<UID> {
+    fn name(&self) -> &'static str {
+        "PathHistory"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(PATH_HISTORY_TOKEN);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// The file the history is kept in, which other processes sharing the bootstrap cache update too.
+struct PathFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    max_peers: usize,
+}
+
+impl PathFile {
+    fn new(cache_name: &Option<String>) -> ::Res<Self> {
+        let mut path = Cache::new(cache_name)?.path().to_path_buf().into_os_string();
+        path.push(".paths");
+        Ok(Self::with_path(PathBuf::from(path), MAX_PEERS))
+    }
+
+    fn with_path(path: PathBuf, max_peers: usize) -> Self {
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+
+        PathFile {
+            path,
+            lock_path: PathBuf::from(lock_path),
+            max_peers,
+        }
+    }
+
+    /// Merges `outcomes` into the history in the file.
+    fn write<UID: Uid>(&self, outcomes: &[PathOutcome<UID>]) {
+        let res = self.update(|peers| {
+            for outcome in outcomes {
+                apply(peers, outcome);
+            }
+        });
+        if let Err(e) = res {
+            debug!("Could not write path history {:?}: {:?}", self.path, e);
+        }
+    }
+
+    fn update<UID, F>(&self, f: F) -> ::Res<()>
+    where
+        UID: Uid,
+        F: FnOnce(&mut Vec<PeerPaths<UID>>),
+    {
+        let _lock = self.lock()?;
+        let mut peers = self.load(now_secs());
+        f(&mut peers);
+        sort_and_truncate(&mut peers, self.max_peers);
+        let contents =
+            serde_json::to_vec_pretty(&peers).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        replace_file(&self.path, &contents)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> io::Result<FileLock> {
+        lock_file(&self.lock_path)
+    }
+
+    /// Reads the history, leaving out what is older than `MAX_AGE_SECS`. Must be called with the
+    /// lock held. A file which can't be parsed is started afresh: it holds nothing that can't be
+    /// learned again.
+    fn load<UID: Uid>(&self, now: u64) -> Vec<PeerPaths<UID>> {
+        let mut contents = Vec::new();
+        match File::open(&self.path).and_then(|mut file| file.read_to_end(&mut contents)) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return vec![],
+            Err(e) => {
+                debug!("Could not read path history {:?}: {:?}", self.path, e);
+                return vec![];
+            }
+        }
+
+        let mut peers = match serde_json::from_slice::<Vec<PeerPaths<UID>>>(&contents) {
+            Ok(peers) => peers,
+            Err(e) => {
+                debug!("Discarding unreadable path history {:?}: {}", self.path, e);
+                return vec![];
+            }
+        };
+        for entry in &mut peers {
+            entry
+                .paths
+                .retain(|path| path.updated.saturating_add(MAX_AGE_SECS) > now);
+        }
+        peers.retain(|entry| !entry.paths.is_empty());
+        sort_and_truncate(&mut peers, self.max_peers);
+        peers
+    }
+}
+
+/// Counts `outcome` in the record of its peer's kind of path.
+fn apply<UID: Uid>(peers: &mut Vec<PeerPaths<UID>>, outcome: &PathOutcome<UID>) {
+    let index = match peers.iter().position(|entry| entry.peer == outcome.peer) {
+        Some(index) => index,
+        None => {
+            peers.push(PeerPaths {
+                peer: outcome.peer,
+                paths: Vec::new(),
+            });
+            peers.len() - 1
+        }
+    };
+    let paths = &mut peers[index].paths;
+    if !paths.iter().any(|path| path.kind == outcome.kind) {
+        paths.push(PathRecord {
+            kind: outcome.kind,
+            successes: 0,
+            failures: 0,
+            last_success: None,
+            updated: outcome.at,
+        });
+    }
+    for path in paths.iter_mut().filter(|path| path.kind == outcome.kind) {
+        if outcome.succeeded {
+            path.successes = path.successes.saturating_add(1);
+            path.last_success = Some(outcome.at);
+        } else {
+            path.failures = path.failures.saturating_add(1);
+        }
+        path.updated = outcome.at;
+    }
+}
+
+fn sort_and_truncate<UID>(peers: &mut Vec<PeerPaths<UID>>, max_peers: usize) {
+    peers.sort_by(|lhs, rhs| rhs.updated().cmp(&lhs.updated()));
+    peers.truncate(max_peers);
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::env;
+    use std::fs;
+    use tests::UniqueId;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir().join(format!("crust-path-history-{}", rand::random::<u64>()))
+    }
+
+    fn cleanup(file: &PathFile) {
+        let _ = fs::remove_file(&file.path);
+        let _ = fs::remove_file(&file.lock_path);
+    }
+
+    #[test]
+    fn candidates_which_worked_are_ordered_first() {
+        let path = temp_path();
+        let mut history = PathHistory::open(PathFile::with_path(path.clone(), MAX_PEERS));
+        let lan = CandidateAddr::TcpDirect(unwrap!("192.168.1.7:5483".parse()));
+        let lan6 = CandidateAddr::TcpDirect(unwrap!("[fd00::7]:5483".parse()));
+        let mapped = CandidateAddr::TcpMapped(unwrap!("203.0.113.7:41000".parse()));
+        let default_order = vec![lan, lan6, mapped];
+        assert_eq!(PathKind::of(&lan), PathKind::Lan(IpVersion::V4));
+        assert_eq!(PathKind::of(&lan6), PathKind::Lan(IpVersion::V6));
+        assert_eq!(PathKind::of(&mapped), PathKind::Mapped(IpVersion::V4));
+
+        let peer: UniqueId = rand::random();
+        let stranger: UniqueId = rand::random();
+        history.record(&peer, PathKind::of(&lan), false);
+        history.record(&peer, PathKind::of(&mapped), true);
+
+        // Worked, not tried yet, failed.
+        let mut candidates = default_order.clone();
+        history.order(&peer, &mut candidates);
+        assert_eq!(candidates, vec![mapped, lan6, lan]);
+
+        let mut candidates = default_order.clone();
+        history.order(&stranger, &mut candidates);
+        assert_eq!(candidates, default_order);
+
+        // What was recorded is written by the time the history is dropped, and read back.
+        drop(history);
+        let history = PathHistory::open(PathFile::with_path(path.clone(), MAX_PEERS));
+        let mut candidates = default_order.clone();
+        history.order(&peer, &mut candidates);
+        assert_eq!(candidates, vec![mapped, lan6, lan]);
+
+        drop(history);
+        cleanup(&PathFile::with_path(path, MAX_PEERS));
+    }
+
+    #[test]
+    fn old_records_and_excess_peers_are_dropped() {
+        let path = temp_path();
+        let mut history = PathHistory::open(PathFile::with_path(path.clone(), 2));
+        let kind = PathKind::Lan(IpVersion::V4);
+        let peers: Vec<UniqueId> = (0..3).map(|_| rand::random()).collect();
+        for peer in &peers {
+            history.record(peer, kind, true);
+        }
+        assert_eq!(history.peers.len(), 2);
+        drop(history);
+
+        let file = PathFile::with_path(path, 2);
+        let lock = unwrap!(file.lock());
+        assert_eq!(file.load::<UniqueId>(now_secs()).len(), 2);
+        // A month later, nothing is left.
+        let later = now_secs() + MAX_AGE_SECS;
+        assert!(file.load::<UniqueId>(later).is_empty());
+        drop(lock);
+
+        cleanup(&file);
+    }
+}
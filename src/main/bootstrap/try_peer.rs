@@ -8,8 +8,12 @@
 // Software.
 
 use common::{
-    BootstrapDenyReason, Core, ExternalReachability, Message, NameHash, Priority, Socket, State,
-    Uid,
+    self, offer_extensions, take_extension_answers, BootstrapDenyReason, ChildHandle,
+    ContactUpdateExtension, Core, CoreMessage, CorrelationExtension, EncryptionExtension,
+    ExternalReachability, Message, NegotiatedFeatures, NetworkId, NetworkProver, PowChallenge,
+    PowExtension, Priority, ProbeExtension, PublicKey, Rejection, RejectionCode, RelayExtension,
+    RetirementExtension, RoleExtension, Socket, State, TimestampExtension, Uid, UpgradeExtension,
+    CONTROL_PRIORITY, MAX_POW_DIFFICULTY,
 };
 use mio::{Poll, PollOpt, Ready, Token};
 use std::any::Any;
@@ -17,35 +21,72 @@ use std::cell::RefCell;
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// On success, the time the peer took to answer our request and what was negotiated in the
+/// handshake are passed along with the connection.
 pub type Finish<UID> = Box<
     FnMut(
         &mut Core,
         &Poll,
         Token,
-        Result<(Socket, SocketAddr, UID), (SocketAddr, Option<BootstrapDenyReason>)>,
+        Result<
+            (Socket, SocketAddr, UID, Duration, NegotiatedFeatures),
+            (SocketAddr, Option<Refusal>),
+        >,
     ),
 >;
 
+/// Why a peer refused to let us bootstrap off it.
+#[derive(Debug)]
+pub enum Refusal {
+    /// Refused for a reason specific to bootstrapping.
+    Denied(BootstrapDenyReason),
+    /// Refused the way any connection can be.
+    Rejected(Rejection),
+}
+
 pub struct TryPeer<UID: Uid> {
     token: Token,
     peer: SocketAddr,
     socket: Socket,
     request: Option<(Message<UID>, Priority)>,
+    /// The request without extensions, made over a new connection if the peer closes this one
+    /// without answering, as peers older than them do, see `fall_back`.
+    legacy: Option<Message<UID>>,
+    network: NetworkProver,
+    role: RoleExtension,
+    pow: PowExtension,
+    timestamps: TimestampExtension,
+    encryption: EncryptionExtension,
+    relay: RelayExtension,
+    /// Whether we have keys, and so refuse peers which don't agree on encryption.
+    require_encryption: bool,
+    /// The peer, what was agreed on with it and its key, once it granted our request with
+    /// encryption agreed on, until its `Message::KeyConfirmation` opens.
+    confirming: Option<(UID, NegotiatedFeatures, PublicKey)>,
+    started: Instant,
+    rtt: Option<Duration>,
+    parent: ChildHandle,
     finish: Finish<UID>,
 }
 
 impl<UID: Uid> TryPeer<UID> {
+    /// Asks `peer` to let us bootstrap off it, over `socket` connected to it already.
     pub fn start(
         core: &mut Core,
         poll: &Poll,
         peer: SocketAddr,
+        socket: Socket,
         our_uid: UID,
-        name_hash: NameHash,
+        network: NetworkId,
         ext_reachability: ExternalReachability,
+        timestamp_frames: bool,
+        relay: bool,
+        parent: ChildHandle,
         finish: Finish<UID>,
     ) -> ::Res<Token> {
-        let socket = Socket::connect(&peer)?;
         let token = core.get_new_token();
 
         poll.register(
@@ -55,14 +96,52 @@ impl<UID: Uid> TryPeer<UID> {
             PollOpt::edge(),
         )?;
 
+        let legacy_reachability = ext_reachability.clone();
+        let mut role = RoleExtension::offering(ext_reachability);
+        let mut pow = PowExtension::offering(MAX_POW_DIFFICULTY);
+        let mut timestamps = TimestampExtension::new(timestamp_frames);
+        let mut encryption = EncryptionExtension::new(core.keys());
+        let mut relay = RelayExtension::new(relay);
+        let offers = offer_extensions(&mut [
+            &mut role,
+            &mut pow,
+            &mut timestamps,
+            &mut CorrelationExtension,
+            &mut RetirementExtension,
+            &mut ProbeExtension,
+            &mut ContactUpdateExtension,
+            &mut UpgradeExtension,
+            &mut encryption,
+            &mut relay,
+        ]);
+
+        // Older peers neither prove private networks nor agree on encryption, so they are only
+        // fallen back on when neither is needed.
+        let legacy = match network {
+            NetworkId::Plain(name_hash) if core.keys().is_none() && socket.is_tcp() => {
+                Some(Message::BootstrapRequest(our_uid, name_hash, legacy_reachability))
+            }
+            _ => None,
+        };
+        let network = NetworkProver::new(network);
+        let request = Message::ExtBootstrapRequest(our_uid, network.request(), offers);
         let state = TryPeer {
             token,
             peer,
             socket,
-            request: Some((
-                Message::BootstrapRequest(our_uid, name_hash, ext_reachability),
-                0,
-            )),
+            request: Some((request, CONTROL_PRIORITY)),
+            legacy,
+            network,
+            role,
+            pow,
+            timestamps,
+            encryption,
+            relay,
+            require_encryption: core.keys().is_some(),
+            confirming: None,
+            started: core.now(),
+            rtt: None,
+            parent,
             finish,
         };
 
@@ -78,31 +157,231 @@ impl<UID: Uid> TryPeer<UID> {
     }
 
     fn read(&mut self, core: &mut Core, poll: &Poll) {
-        match self.socket.read::<Message<UID>>() {
+        if self.confirming.is_some() {
+            return self.read_key_confirmation(core, poll);
+        }
+        let res = self.socket.read::<Message<UID>>();
+        // Measured up to the first answer, which leaves out the time spent solving a challenge.
+        if let Ok(Some(_)) = res {
+            if self.rtt.is_none() {
+                self.rtt = Some(core.now() - self.started);
+            }
+        }
+        match res {
+            Ok(Some(Message::ExtBootstrapGranted(peer_uid, answers))) => {
+                let features = take_extension_answers(
+                    &mut [
+                        &mut self.role,
+                        &mut self.pow,
+                        &mut self.timestamps,
+                        &mut CorrelationExtension,
+                        &mut RetirementExtension,
+                        &mut ProbeExtension,
+                        &mut ContactUpdateExtension,
+                        &mut UpgradeExtension,
+                        &mut self.encryption,
+                        &mut self.relay,
+                    ],
+                    &answers,
+                );
+                self.granted(core, poll, peer_uid, features)
+            }
             Ok(Some(Message::BootstrapGranted(peer_uid))) => {
-                let _ = core.remove_state(self.token);
-                let token = self.token;
-                let socket = mem::replace(&mut self.socket, Socket::default());
-                let data = (socket, self.peer, peer_uid);
-                (*self.finish)(core, poll, token, Ok(data));
+                self.granted(core, poll, peer_uid, NegotiatedFeatures::default())
             }
             Ok(Some(Message::BootstrapDenied(reason))) => {
-                self.handle_error(core, poll, Some(reason))
+                self.handle_error(core, poll, Some(Refusal::Denied(reason)))
+            }
+            Ok(Some(Message::Rejection(rejection))) => {
+                self.handle_error(core, poll, Some(Refusal::Rejected(rejection)))
+            }
+            Ok(Some(Message::Retiring(alternatives))) => {
+                let mut rejection = Rejection::new(
+                    RejectionCode::Retiring,
+                    None,
+                    "Bootstrappee is retiring",
+                );
+                rejection.alternatives = alternatives;
+                self.handle_error(core, poll, Some(Refusal::Rejected(rejection)))
+            }
+            Ok(Some(Message::PowChallenge(challenge, difficulty))) => {
+                self.solve_pow(core, poll, challenge, difficulty)
+            }
+            Ok(Some(Message::NetworkChallenge(challenge, proof))) => {
+                match self.network.answer(challenge, &proof) {
+                    Some(answer) => {
+                        let msg = Message::NetworkProof(answer);
+                        self.write(core, poll, Some((msg, CONTROL_PRIORITY)))
+                    }
+                    None => self.wrong_network(core, poll),
+                }
+            }
+            Ok(None) => (),
+            Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
+        }
+    }
+
+    fn granted(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        peer_uid: UID,
+        features: NegotiatedFeatures,
+    ) {
+        if !self.network.accepts(None) {
+            return self.wrong_network(core, poll);
+        }
+        let keys = match self.encryption.take_session() {
+            Some(keys) => keys,
+            None if self.require_encryption => {
+                debug!("Bootstrappee {} didn't agree on encryption", self.peer);
+                return self.handle_error(core, poll, None);
+            }
+            None => return self.hand_over(core, poll, peer_uid, features),
+        };
+
+        // Both sides confirm the keys before taking the connection up.
+        self.confirming = Some((peer_uid, features, keys.peer_key()));
+        self.socket.enable_encryption(keys);
+        let msg = Some((Message::KeyConfirmation, CONTROL_PRIORITY));
+        if self.socket.write(poll, self.token, msg).is_err() {
+            return self.handle_error(core, poll, None);
+        }
+        // The peer's confirmation may have come along with its grant.
+        self.read_key_confirmation(core, poll)
+    }
+
+    fn read_key_confirmation(&mut self, core: &mut Core, poll: &Poll) {
+        match self.socket.read::<Message<UID>>() {
+            Ok(Some(Message::KeyConfirmation)) => {
+                let (peer_uid, mut features, peer_key) = unwrap!(self.confirming.take());
+                trace!("Bootstrappee {} confirmed the keys", self.peer);
+                features.peer_key = Some(peer_key);
+                self.hand_over(core, poll, peer_uid, features)
             }
             Ok(None) => (),
             Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
         }
     }
 
-    fn handle_error(&mut self, core: &mut Core, poll: &Poll, reason: Option<BootstrapDenyReason>) {
+    fn hand_over(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        peer_uid: UID,
+        features: NegotiatedFeatures,
+    ) {
+        let _ = core.hand_over_state(self.token);
+        let token = self.token;
+        let socket = mem::replace(&mut self.socket, Socket::default());
+        let rtt = self.rtt.unwrap_or_else(|| core.now() - self.started);
+        let data = (socket, self.peer, peer_uid, rtt, features);
+        (*self.finish)(core, poll, token, Ok(data));
+    }
+
+    // Solves the challenge on a thread of its own, so the event loop keeps running meanwhile, and
+    // sends the solution once found.
+    fn solve_pow(&mut self, core: &mut Core, poll: &Poll, challenge: PowChallenge, difficulty: u8) {
+        if difficulty > MAX_POW_DIFFICULTY {
+            debug!(
+                "Bootstrappee {} asks for a proof of work of difficulty {}, which is too high",
+                self.peer, difficulty
+            );
+            let reason = BootstrapDenyReason::PowRequired(difficulty);
+            return self.handle_error(core, poll, Some(Refusal::Denied(reason)));
+        }
+
+        let token = self.token;
+        let tx = core.sender().clone();
+        let res = thread::Builder::new()
+            .name("PoW-Solver".to_owned())
+            .spawn(move || {
+                let nonce = common::solve_pow(&challenge, difficulty);
+                let _ = tx.send(CoreMessage::new(move |core, poll| {
+                    let state = match core.get_state(token) {
+                        Some(state) => state,
+                        None => return,
+                    };
+                    let mut state = state.borrow_mut();
+                    if let Some(try_peer) = state.as_any().downcast_mut::<TryPeer<UID>>() {
+                        let msg = Message::PowSolution(nonce);
+                        try_peer.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+                    }
+                }));
+            });
+        if let Err(e) = res {
+            debug!("Could not start the proof of work solver: {:?}", e);
+            self.handle_error(core, poll, None);
+        }
+    }
+
+    /// Fails the way a peer on another network would have us fail, which this one is: it isn't on
+    /// a private network while we are, or is on one we don't know the secret of.
+    fn wrong_network(&mut self, core: &mut Core, poll: &Poll) {
+        let rejection = Rejection::new(
+            RejectionCode::WrongNetwork,
+            None,
+            "Bootstrappee failed to prove it is on our network",
+        );
+        self.handle_error(core, poll, Some(Refusal::Rejected(rejection)))
+    }
+
+    /// Makes the request again without extensions over a new connection, if the peer closed ours
+    /// after our request and before answering it: peers older than the extensions can't decode
+    /// the request, and close the connection on it. Returns whether the request was made again.
+    fn fall_back(&mut self, core: &mut Core, poll: &Poll) -> bool {
+        if self.request.is_some() || self.rtt.is_some() {
+            return false;
+        }
+        let request = match self.legacy.take() {
+            Some(request) => request,
+            None => return false,
+        };
+        let bind_ip = self.socket.local_addr().ok().map(|addr| addr.ip());
+        let socket = match Socket::connect_from(&self.peer, bind_ip) {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("Failed to connect to bootstrappee {} again: {:?}", self.peer, e);
+                return false;
+            }
+        };
+        let _ = poll.deregister(&self.socket);
+        if let Err(e) = poll.register(
+            &socket,
+            self.token,
+            Ready::error() | Ready::hup() | Ready::writable(),
+            PollOpt::edge(),
+        ) {
+            debug!("Failed to register the new socket to {}: {:?}", self.peer, e);
+            return false;
+        }
+        debug!(
+            "Bootstrappee {} closed the connection without answering - asking again without \
+             extensions",
+            self.peer
+        );
+        self.socket = socket;
+        self.request = Some((request, CONTROL_PRIORITY));
+        self.started = core.now();
+        true
+    }
+
+    fn handle_error(&mut self, core: &mut Core, poll: &Poll, refusal: Option<Refusal>) {
+        if refusal.is_none() && self.fall_back(core, poll) {
+            return;
+        }
         self.terminate(core, poll);
         let token = self.token;
         let peer = self.peer;
-        (*self.finish)(core, poll, token, Err((peer, reason)));
+        (*self.finish)(core, poll, token, Err((peer, refusal)));
     }
 }
 
 impl<UID: Uid> State for TryPeer<UID> {
+    fn name(&self) -> &'static str {
+        "TryPeer"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() {
             return self.handle_error(core, poll, None);
@@ -125,6 +404,7 @@ impl<UID: Uid> State for TryPeer<UID> {
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.parent.deregister(self.token);
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
     }
@@ -7,21 +7,49 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use config_file_handler::{self, FileHandler};
+use common::MIN_MEMORY_BUDGET;
+use config_file_handler;
+use main::CrustError;
+use nat;
+use serde_json::{self, Map, Value};
+use std::cmp;
 use std::collections::HashSet;
-use std::ffi::OsString;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
 use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 
-#[cfg(test)]
-use std::path::PathBuf;
+/// Environment variable naming the config file to read, in place of searching for one.
+pub const CONFIG_PATH_ENV_VAR: &str = "CRUST_CONFIG_PATH";
+/// Version of the config file layout `Config` reads and writes, see `Config::config_version`.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Steps migrating the fields of a config file from each version to the next, the step from
+/// version `n` at index `n`. Each returns what it changed, to be logged.
+const MIGRATIONS: [fn(&mut Map<String, Value>) -> Vec<String>; CONFIG_VERSION as usize] =
+    [migrate_v0, migrate_v1];
 
 /// Crust configuration settings
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// Version of the layout of the config file. Files written before it was introduced have none
+    /// and count as version 0. Older versions are migrated as they are read, see
+    /// `Config::write_migrated`, and newer ones refused.
+    #[serde(default)]
+    pub config_version: u32,
     /// Direct contacts one should connect to
     pub hard_coded_contacts: Vec<SocketAddr>,
-    /// Port for TCP acceptor
+    /// Port for TCP acceptor. With the `websocket` feature, it also takes WebSocket connections,
+    /// told apart by their first bytes.
     pub tcp_acceptor_port: Option<u16>,
+    /// Further ports to accept connections on, e.g. 80 and 443 for peers behind firewalls which
+    /// let nothing else out. They share the handshake and the handshake limits of
+    /// `tcp_acceptor_port` and are all listed in our connection info. A port which can't be bound,
+    /// e.g. because it is privileged, is skipped with a warning.
+    #[serde(default)]
+    pub additional_acceptor_ports: Vec<u16>,
     /// Force usage of `tcp_acceptor_port` as our router mapped port. Normally if there is a port
     /// forwarding, crust will find out what the external world sees our local tcp acceptor
     /// endpoint as and include this information in our connection info that we share with others.
@@ -33,9 +61,18 @@ pub struct Config {
     /// can specify this value as true, which will force crust to add the above `tcp_acceptor_port`
     /// to one of our externally reachable endpoint.
     pub force_acceptor_port_in_ext_ep: bool,
-    /// Port for service discovery on local network
+    /// Public endpoints we are known to be reachable at, e.g. through manual port forwarding or a
+    /// load balancer. They are advertised ahead of the addresses we detect ourselves, marked as
+    /// asserted in our connection info, and kept whatever we detect. Read as the service is
+    /// constructed, and changed afterwards with `Service::add_external_endpoint` and
+    /// `remove_external_endpoint` rather than by refreshing the config file.
+    #[serde(default)]
+    pub external_endpoints: Vec<SocketAddr>,
+    /// Port for service discovery on local network. Only with the `service-discovery` feature;
+    /// rejected by `validate` without it.
     pub service_discovery_port: Option<u16>,
-    /// File for bootstrap cache
+    /// File for bootstrap cache. An absolute path can be used to share one cache between
+    /// several services on the same machine.
     pub bootstrap_cache_name: Option<String>,
     /// Whitelisted nodes who are allowed to bootstrap off us or to connect to us
     pub whitelisted_node_ips: Option<HashSet<IpAddr>>,
@@ -46,10 +83,385 @@ pub struct Config {
     /// This is a mechanism to prevent nodes from different decentralized
     /// networks to connect to each other (issue #209)
     pub network_name: Option<String>,
+    /// Makes the network private: only peers knowing the same secret and `network_name` are
+    /// connected to, each side proving it in the handshake over nonces chosen by the other, so
+    /// that nothing sent identifies the network or can be replayed. Peers without a secret, or
+    /// with another one, are refused like peers on another network. `None` keeps the network
+    /// public, where `network_name` is compared by its hash.
+    #[serde(default)]
+    pub network_secret: Option<String>,
+    /// Maximum number of handshakes of inbound connections processed concurrently, over all our
+    /// listeners. Connections accepted beyond it are parked until a slot frees up. Our own
+    /// outbound connection attempts aren't counted, nor ever parked or held up by inbound ones.
+    /// `None` means no limit.
+    #[serde(default)]
+    pub max_concurrent_inbound_handshakes: Option<usize>,
+    /// Size limit, in bytes, applied by `Service::send_serialized` and
+    /// `Service::deserialize_message`, including the schema tag. Defaults to the maximum payload
+    /// size.
+    #[serde(default)]
+    pub max_serialised_message_size: Option<usize>,
+    /// Interval, in seconds, at which our network interfaces are scanned for changes. Defaults to
+    /// 10 seconds.
+    #[serde(default)]
+    pub interface_scan_interval_sec: Option<u64>,
+    /// Disables watching our network interfaces for changes.
+    #[serde(default)]
+    pub disable_interface_monitor: bool,
+    /// Disables noticing that the system was suspended, which otherwise makes every connection
+    /// probe its peer on resume and drop it if the probe isn't answered.
+    #[serde(default)]
+    pub disable_suspend_detection: bool,
+    /// Memory, in KiB, used to keep a record of recent significant events such as accepts,
+    /// handshake results and disconnects. `None` disables the flight recorder. Only with the
+    /// `flight-recorder` feature; rejected by `validate` without it.
+    #[serde(default)]
+    pub flight_recorder_kb: Option<usize>,
+    /// File the flight record is written to if the event loop panics or dies with an error. Only
+    /// with the `flight-recorder` feature, like `flight_recorder_kb`.
+    #[serde(default)]
+    pub flight_recorder_dump_path: Option<String>,
+    /// Time, in milliseconds, an iteration of the event loop may take handling events before it
+    /// is counted in `CoreStats::lagging_iterations` and reported with `Event::EventLoopLagging`,
+    /// at most once every 10 seconds. `None` disables the check.
+    #[serde(default)]
+    pub loop_lag_warn_ms: Option<u64>,
+    /// Time, in seconds, after which the flight record is written to `flight_recorder_dump_path`
+    /// if an iteration of the event loop hasn't finished, as checked from a thread of its own.
+    /// Zero is taken as a tenth of a second. Only with the `stall-watchdog` feature; `None`
+    /// disables the check.
+    #[serde(default)]
+    pub loop_stall_dump_secs: Option<u64>,
+    /// Time, in seconds, for which connection info prepared by us is valid. Peers refuse to
+    /// connect using expired info. Defaults to 10 minutes.
+    #[serde(default)]
+    pub connection_info_ttl_secs: Option<u64>,
+    /// Maximum number of peers kept by `Service::park`. When exceeded, the least recently used
+    /// parked peer is forgotten and reported lost. Defaults to 256.
+    #[serde(default)]
+    pub max_parked_peers: Option<usize>,
+    /// If `true`, sending to a parked peer queues the message and unparks the peer, rather than
+    /// failing with `CrustError::PeerParked`.
+    #[serde(default)]
+    pub auto_unpark_on_send: bool,
+    /// Time, in seconds, without any inbound traffic after which a connection is probed for
+    /// liveness. Probes are much cheaper than heartbeats and are only sent on suspicion, so this
+    /// can be set far more aggressively. `None` disables probing.
+    #[serde(default)]
+    pub probe_after_idle_secs: Option<u64>,
+    /// Number of unanswered probes after which the connection is dropped. Each probe is given
+    /// `probe_after_idle_secs` to be answered. Defaults to 3. Peers whose handshake didn't agree
+    /// on probes are sent heartbeats instead, and only dropped by the heartbeat timeout.
+    #[serde(default)]
+    pub probe_retries: Option<u32>,
+    /// Interval, in seconds, at which every connection probes the peer to time the round trip,
+    /// recorded in its `PeerStats::latency` along with the answers to liveness probes. `None`
+    /// sends no probes besides those for liveness.
+    #[serde(default)]
+    pub latency_probe_interval_secs: Option<u64>,
+    /// Local address our outbound connections are bound to, so they leave via the matching
+    /// interface. Not applied to destinations of the other address family. `None` lets the OS
+    /// choose.
+    #[serde(default)]
+    pub outbound_bind_addr: Option<IpAddr>,
+    /// Difficulty, in leading zero bits, of the proof of work asked of clients bootstrapping off
+    /// us before their request is handled. `None` doesn't ask for any.
+    #[serde(default)]
+    pub require_pow: Option<u8>,
+    /// Maximum number of connections accepted by the listener in one go before other work gets
+    /// its turn. Defaults to 64.
+    #[serde(default)]
+    pub accept_batch_size: Option<usize>,
+    /// Length of the queue of connections waiting to be accepted by the listener. Defaults to 100.
+    #[serde(default)]
+    pub listen_backlog: Option<u32>,
+    /// Data messages with a payload of at least this many bytes are delivered as
+    /// `Event::NewSharedMessage`, sharing the buffer they were received in rather than being
+    /// copied out of it. Other messages, or all of them if `None`, come as `Event::NewMessage`.
+    #[serde(default)]
+    pub shared_payload_min_size: Option<usize>,
+    /// Messages per second a single peer may send us before it is reported with
+    /// `Event::PeerOverRate`. `None` doesn't limit them.
+    #[serde(default)]
+    pub max_inbound_msgs_per_sec: Option<u64>,
+    /// Bytes per second a single peer may send us before it is reported with
+    /// `Event::PeerOverRate`. `None` doesn't limit them.
+    #[serde(default)]
+    pub max_inbound_bytes_per_sec: Option<u64>,
+    /// If set, we stop reading from a peer over the inbound limits for this many seconds, so that
+    /// TCP throttles it. Should be well below the inactivity timeout of two minutes.
+    #[serde(default)]
+    pub over_rate_penalty_secs: Option<u64>,
+    /// If set, messages still queued for a peer whose connection is lost are kept for this many
+    /// seconds and sent again if the peer reconnects in time, or else reported with
+    /// `Event::UnsentMessagesDropped`. A message which had been written in part is never sent
+    /// again. `None` drops them with the connection.
+    #[serde(default)]
+    pub retention_window_secs: Option<u64>,
+    /// Dials the nodes whose connection was lost to the network again, at the address they were
+    /// last reached at, waiting longer after each failed attempt. `Event::LostPeer` is still sent
+    /// as the connection is lost; `Event::PeerReconnected` follows if an attempt succeeds, or
+    /// `Event::ReconnectFailed` once none is left. A peer which connects to us first is reported
+    /// as usual. `None` leaves reconnecting to the application.
+    #[serde(default)]
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Age, in seconds, beyond which the listener addresses of a snapshot passed to
+    /// `Service::with_snapshot` are no longer trusted. Its contacts are used regardless. Defaults
+    /// to an hour.
+    #[serde(default)]
+    pub snapshot_max_age_secs: Option<u64>,
+    /// Seconds within which a frame has to arrive in full once its length prefix has been read,
+    /// plus one second per 32 KiB of its length. A peer which is slower is disconnected with
+    /// `DisconnectReason::ProtocolError`. Defaults to 10.
+    #[serde(default)]
+    pub frame_completion_timeout_secs: Option<u64>,
+    /// Keeps crust off the WAN: we bootstrap only off service discovery and cached peers with
+    /// private addresses, don't map our sockets via IGD or STUN, only list local addresses in our
+    /// connection info and refuse to dial public addresses with `CrustError::LanOnlyViolation`.
+    /// Hard-coded contacts, if any, must be private too.
+    #[serde(default)]
+    pub lan_only: bool,
+    /// Maximum number of connections, including those still in their handshake. Beyond it, our
+    /// listeners stop accepting until some close. `None` limits them only by the file descriptor
+    /// budget, see `max_file_descriptors`.
+    #[serde(default)]
+    pub max_peers: Option<usize>,
+    /// Maximum number of clients bootstrapped off us at a time. Clients beyond it are rejected as
+    /// full while nodes still get in. `None` leaves them bound by `max_peers` only. Changed at
+    /// runtime by `Service::set_peer_limits`.
+    #[serde(default)]
+    pub max_client_peers: Option<usize>,
+    /// Maximum number of nodes connected at a time, whichever side connected, beyond which those
+    /// bootstrapping off or connecting to us are rejected as full while clients still get in.
+    /// `None` leaves them bound by `max_peers` only.
+    #[serde(default)]
+    pub max_node_peers: Option<usize>,
+    /// File descriptors (handles on Windows) crust may use, if lower than the soft limit of the
+    /// process, which is queried at startup where the platform has one. Some are always kept free
+    /// for other uses and the rest bound the number of connections.
+    #[serde(default)]
+    pub max_file_descriptors: Option<usize>,
+    /// Delivers the data messages from a peer as `Event::NewMessages` batches rather than one
+    /// `Event::NewMessage` each, saving the application a wakeup per message at high rates.
+    /// Messages from a peer stay in order and no other event is held back behind a batch. `None`
+    /// delivers every message on its own.
+    #[serde(default)]
+    pub event_batching: Option<EventBatching>,
+    /// Learns, per path, how long the NAT bindings of a silent connection last and sends
+    /// heartbeats just often enough to keep them open, rather than every 20 seconds. `None` keeps
+    /// the fixed interval.
+    #[serde(default)]
+    pub adaptive_heartbeat: Option<AdaptiveHeartbeat>,
+    /// Which connection is kept when a second one to an already connected peer is established.
+    /// Defaults to the one established first.
+    #[serde(default)]
+    pub duplicate_connection_policy: DuplicateConnectionPolicy,
+    /// Offers peers to trail every frame with a timestamp, 8 bytes long, from which the one-way
+    /// latencies in `PeerStats::one_way_latency` are estimated. Only taken up by peers which have
+    /// it set too. Meant for peers which trust each other's clocks to be in sync: an offset
+    /// between them is only partly corrected for.
+    #[serde(default)]
+    pub timestamp_frames: bool,
+    /// Time in seconds within which a request sent with `Service::send_request` has to be
+    /// answered, after which it is reported as `Event::ResponseTimedOut`. `None` means 30 seconds.
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+    /// Bytes the service may hold in buffers all told: messages queued to be sent or retained for
+    /// lost peers, frames being read, and messages read but not delivered yet or, for shared
+    /// payloads, not yet dropped by the application. As they fill up, low-priority messages are
+    /// dropped first, then no new frames are read, then nothing is read and at last no
+    /// connections are accepted, see `MemoryPressure`; each change is reported with
+    /// `Event::MemoryPressure`. At least `MIN_MEMORY_BUDGET`; `None` doesn't limit them.
+    #[serde(default)]
+    pub max_buffered_bytes: Option<usize>,
+    /// Bytes read from one peer before the others get their turn, so that a peer sending faster
+    /// than we read doesn't hold up the rest. The peer is read on right after. `None` means
+    /// 256 KiB, and 0 no limit.
+    #[serde(default)]
+    pub read_budget_bytes: Option<usize>,
+    /// What is done about a connected peer sending a message which makes no sense at that point,
+    /// see `ProtocolViolation`. Defaults to dropping the message.
+    #[serde(default)]
+    pub protocol_violation_policy: ViolationPolicy,
+    /// Appends a line of JSON to a file for every connection accepted, the outcome of its
+    /// handshake, every promotion and every connection closed, for handling abuse. The lines are
+    /// written on a thread of their own, and dropped if it falls behind, as counted in
+    /// `CoreStats::audit_records_dropped`. `None` keeps no such log.
+    #[serde(default)]
+    pub audit_log: Option<AuditConfig>,
+    /// Accepts uTP connections, reliable streams over UDP, on the ports of the TCP listeners, and
+    /// dials the uTP addresses in the connection info of peers after their TCP ones. uTP gets
+    /// through where only UDP does. Only with the `utp` feature; rejected by `validate` without
+    /// it.
+    #[serde(default)]
+    pub enable_utp: bool,
+    /// Bytes of messages queued for any one peer. Once more are waiting, the oldest messages of
+    /// the lowest droppable priority, see `MSG_DROP_PRIORITY`, are dropped until they fit again;
+    /// messages of a higher priority are always queued. `None` doesn't limit them but for
+    /// `max_buffered_bytes`.
+    #[serde(default)]
+    pub max_queued_bytes_per_peer: Option<usize>,
+    /// Listens on IPv6 as well as IPv4, with dual-stack sockets bound to `[::]`, and lists the
+    /// IPv6 addresses of our interfaces in our connection info, so that peers on IPv6-only
+    /// networks can connect. Falls back to IPv4 alone where IPv6 is unavailable.
+    #[serde(default)]
+    pub dual_stack: bool,
+    /// Relays connections between peers connected to us which can't reach each other directly or
+    /// by punching a hole, forwarding their frames as they are. Peers only list us among their
+    /// connection candidates if they set `use_relays`. Only with the `relay` feature; rejected by
+    /// `validate` without it. `None` relays for no one.
+    #[serde(default)]
+    pub relay: Option<RelayConfig>,
+    /// Lists the peers relaying for us, see `relay`, in our connection info, and falls back to
+    /// the relays in the connection info of a peer we connect to when no other connection to it
+    /// can be made. Only with the `relay` feature; rejected by `validate` without it.
+    #[serde(default)]
+    pub use_relays: bool,
+    /// Bytes per second written to all our connected peers together. Writes past it are paced
+    /// with timers of the event loop, so that messages queue up rather than flood the link.
+    /// Changed at runtime with `Service::set_bandwidth_limits`. `None` doesn't limit them.
+    #[serde(default)]
+    pub max_upload_bytes_per_sec: Option<u64>,
+    /// Bytes per second read from all our connected peers together. Reads past it pause until
+    /// the rate allows, which pushes back on the peers through TCP flow control. `None` doesn't
+    /// limit them.
+    #[serde(default)]
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// Port to accept WebSocket connections on, for peers which can't open raw TCP connections,
+    /// such as clients running in a browser. Once their opening handshake is over, they are
+    /// handled like the connections of `tcp_acceptor_port`, which takes them as well, so that
+    /// firewalls may let in only that one. Only with the `websocket` feature; rejected by
+    /// `validate` without it. `None` accepts them on `tcp_acceptor_port` only.
+    #[serde(default)]
+    pub websocket_port: Option<u16>,
+    /// Contacts to bootstrap off over WebSocket rather than TCP, at their `websocket_port`.
+    /// Only with the `websocket` feature; rejected by `validate` without it.
+    #[serde(default)]
+    pub websocket_contacts: Vec<SocketAddr>,
     /// Optional developer configuration
     pub dev: Option<DevConfig>,
 }
 
+/// Settings of `Config::audit_log`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AuditConfig {
+    /// File the log is written to, resolved like `Config::bootstrap_cache_name`.
+    pub path: String,
+    /// Size in bytes the file grows to at most. It is then renamed with `.1` appended, replacing
+    /// the file renamed before, and a new one is started.
+    pub max_size: u64,
+    /// Replaces the IP of every address logged with a hash keyed by a secret picked as the
+    /// service starts, keeping the port. The connections from one IP can be told apart from
+    /// those of others for as long as the service runs, but not which IP it is.
+    pub redact_ips: bool,
+}
+
+/// Settings of `Config::relay`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct RelayConfig {
+    /// Most connections relayed at once, counting those waiting for their peer to ask for them
+    /// too. Further ones are turned down until one closes.
+    pub max_sessions: usize,
+    /// Bytes per second forwarded for any one pair of peers, both ways together. Frames beyond it
+    /// are dropped, and both peers told so, so they can look for another way to each other. A
+    /// frame larger than the quota still goes through as the first of its second. `None` doesn't
+    /// limit the pairs but for `max_bytes_per_sec`.
+    #[serde(default)]
+    pub max_pair_bytes_per_sec: Option<u64>,
+    /// Bytes per second forwarded for all the pairs together, beyond which frames are dropped as
+    /// for `max_pair_bytes_per_sec`. `None` for no limit.
+    #[serde(default)]
+    pub max_bytes_per_sec: Option<u64>,
+    /// Bytes, on the wire, either peer of a pair may have sent us which the other hasn't read
+    /// yet. Each is granted as much again as the other reads, and stops sending while none is
+    /// left, which the application is told of with `Event::PeerCongested`. This bounds what we
+    /// hold for each side of a pair whatever the peers do, as frames sent beyond the grant are
+    /// dropped as for `max_pair_bytes_per_sec`. `None` for 256 KiB.
+    #[serde(default)]
+    pub window_bytes: Option<u64>,
+}
+
+/// Bounds of the batches of `Config::event_batching`. A batch is delivered once either is hit.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct EventBatching {
+    /// Maximum number of messages in a batch.
+    pub max_batch: usize,
+    /// Maximum time, in microseconds, the first message of a batch is held back for. With 0, a
+    /// batch only takes the messages read from the peer in one go.
+    pub max_delay_us: u64,
+}
+
+/// Settings of `Config::reconnect`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// Time, in milliseconds, before the first attempt. It doubles with every failed attempt, and
+    /// a random share of up to half of it is taken off, so that peers which lost each other at
+    /// once don't dial in lockstep.
+    pub initial_delay_ms: u64,
+    /// Ceiling, in milliseconds, of the time between attempts.
+    pub max_delay_ms: u64,
+    /// Attempts made before the peer is given up on.
+    pub max_attempts: u32,
+}
+
+/// Settings of `Config::adaptive_heartbeat`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AdaptiveHeartbeat {
+    /// Heartbeat interval, in milliseconds, of connections on paths nothing has been learned about.
+    pub initial_interval_ms: u64,
+    /// Hard ceiling, in milliseconds, of the heartbeat interval, whatever is learned. Capped at
+    /// half the inactivity timeout after which peers drop a silent connection.
+    pub max_interval_ms: u64,
+    /// Minimum time, in seconds, between two connections staying silent for longer than their
+    /// interval to learn whether the path survives it.
+    pub experiment_interval_secs: u64,
+    /// File the learned intervals are kept in, resolved like `Config::bootstrap_cache_name`.
+    /// `None` uses a file named after the executable.
+    #[serde(default)]
+    pub table_name: Option<String>,
+}
+
+/// Which of two connections to the same peer is kept when the second is established while the
+/// first is up, as when our `Service::connect` races the peer's own dial or a reconnect races an
+/// inbound connection. Either way the peer is reported connected once, and lost once.
+///
+/// So that both ends keep the same connection, one dialled by the peer with the lower ID is kept
+/// over one dialled by the other peer whatever the policy, which only decides between connections
+/// dialled by the same peer, or by both as over a punched hole or a relay.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum DuplicateConnectionPolicy {
+    /// The connection established first is kept and the other closed straight away.
+    KeepOldest,
+    /// The connection established last replaces the other, taking over its tag and the messages
+    /// not sent on it yet.
+    KeepNewest,
+}
+
+impl Default for DuplicateConnectionPolicy {
+    fn default() -> Self {
+        DuplicateConnectionPolicy::KeepOldest
+    }
+}
+
+/// What is done about a peer breaking the protocol, see `Config::protocol_violation_policy`.
+/// Whichever it is, the violation is counted in `PeerStats::protocol_violations`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum ViolationPolicy {
+    /// The offending message is dropped and the connection kept.
+    Ignore,
+    /// The peer is disconnected, with `DisconnectReason::ProtocolViolation`.
+    Disconnect,
+    /// The peer is disconnected, and connections from its IP address are refused by our listeners
+    /// for the given number of minutes.
+    DisconnectAndBan(u64),
+}
+
+impl Default for ViolationPolicy {
+    fn default() -> Self {
+        ViolationPolicy::Ignore
+    }
+}
+
 /// Developer options
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
 pub struct DevConfig {
@@ -60,24 +472,348 @@ pub struct DevConfig {
 impl Default for Config {
     fn default() -> Config {
         Config {
+            config_version: CONFIG_VERSION,
             hard_coded_contacts: vec![],
             tcp_acceptor_port: None,
+            additional_acceptor_ports: vec![],
             force_acceptor_port_in_ext_ep: false,
+            external_endpoints: vec![],
             service_discovery_port: None,
             bootstrap_cache_name: None,
             whitelisted_node_ips: None,
             whitelisted_client_ips: None,
             network_name: None,
+            network_secret: None,
+            max_concurrent_inbound_handshakes: None,
+            max_serialised_message_size: None,
+            interface_scan_interval_sec: None,
+            disable_interface_monitor: false,
+            disable_suspend_detection: false,
+            flight_recorder_kb: None,
+            flight_recorder_dump_path: None,
+            loop_lag_warn_ms: None,
+            loop_stall_dump_secs: None,
+            connection_info_ttl_secs: None,
+            max_parked_peers: None,
+            auto_unpark_on_send: false,
+            probe_after_idle_secs: None,
+            probe_retries: None,
+            latency_probe_interval_secs: None,
+            outbound_bind_addr: None,
+            require_pow: None,
+            accept_batch_size: None,
+            listen_backlog: None,
+            shared_payload_min_size: None,
+            max_inbound_msgs_per_sec: None,
+            max_inbound_bytes_per_sec: None,
+            over_rate_penalty_secs: None,
+            retention_window_secs: None,
+            reconnect: None,
+            snapshot_max_age_secs: None,
+            frame_completion_timeout_secs: None,
+            lan_only: false,
+            max_peers: None,
+            max_client_peers: None,
+            max_node_peers: None,
+            max_file_descriptors: None,
+            event_batching: None,
+            adaptive_heartbeat: None,
+            duplicate_connection_policy: DuplicateConnectionPolicy::KeepOldest,
+            timestamp_frames: false,
+            request_timeout_secs: None,
+            max_buffered_bytes: None,
+            read_budget_bytes: None,
+            protocol_violation_policy: ViolationPolicy::Ignore,
+            audit_log: None,
+            enable_utp: false,
+            max_queued_bytes_per_peer: None,
+            dual_stack: false,
+            relay: None,
+            use_relays: false,
+            max_upload_bytes_per_sec: None,
+            max_download_bytes_per_sec: None,
+            websocket_port: None,
+            websocket_contacts: vec![],
             dev: None,
         }
     }
 }
 
-/// Reads the default crust config file.
+impl Config {
+    /// Checks the settings for contradictions. A `Service` refuses to start with a config which
+    /// doesn't pass.
+    pub fn validate(&self) -> ::Res<()> {
+        if self.lan_only {
+            if let Some(addr) = self
+                .hard_coded_contacts
+                .iter()
+                .chain(self.websocket_contacts.iter())
+                .chain(self.external_endpoints.iter())
+                .find(|addr| nat::ip_addr_is_global(&addr.ip()))
+            {
+                return Err(CrustError::LanOnlyViolation(*addr));
+            }
+        }
+        if let Some(bytes) = self.max_buffered_bytes {
+            if bytes < MIN_MEMORY_BUDGET {
+                return Err(CrustError::MemoryBudgetTooSmall(bytes, MIN_MEMORY_BUDGET));
+            }
+        }
+        if !cfg!(feature = "service-discovery") && self.service_discovery_port.is_some() {
+            return Err(CrustError::FeatureDisabled(
+                "service_discovery_port",
+                "service-discovery",
+            ));
+        }
+        if !cfg!(feature = "flight-recorder") {
+            if self.flight_recorder_kb.is_some() {
+                return Err(CrustError::FeatureDisabled(
+                    "flight_recorder_kb",
+                    "flight-recorder",
+                ));
+            }
+            if self.flight_recorder_dump_path.is_some() {
+                return Err(CrustError::FeatureDisabled(
+                    "flight_recorder_dump_path",
+                    "flight-recorder",
+                ));
+            }
+        }
+        if !cfg!(feature = "utp") && self.enable_utp {
+            return Err(CrustError::FeatureDisabled("enable_utp", "utp"));
+        }
+        if !cfg!(feature = "websocket") {
+            if self.websocket_port.is_some() {
+                return Err(CrustError::FeatureDisabled("websocket_port", "websocket"));
+            }
+            if !self.websocket_contacts.is_empty() {
+                return Err(CrustError::FeatureDisabled(
+                    "websocket_contacts",
+                    "websocket",
+                ));
+            }
+        }
+        if !cfg!(feature = "relay") {
+            if self.relay.is_some() {
+                return Err(CrustError::FeatureDisabled("relay", "relay"));
+            }
+            if self.use_relays {
+                return Err(CrustError::FeatureDisabled("use_relays", "relay"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the config to `path` in the current version, e.g. to upgrade a file which was
+    /// migrated as it was read. The file is replaced in one go, through a temporary file next to
+    /// it.
+    pub fn write_migrated(&self, path: &Path) -> ::Res<()> {
+        let mut config = self.clone();
+        config.config_version = CONFIG_VERSION;
+        let encoded = serde_json::to_vec_pretty(&config)
+            .map_err(|e| CrustError::InvalidConfigFile(path.to_path_buf(), e.to_string()))?;
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&encoded)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Reads the default crust config file: the file `CRUST_CONFIG_PATH` names if it is set, otherwise
+/// the first of `config_search_paths` which exists.
 pub fn read_config_file() -> ::Res<Config> {
-    let file_handler = FileHandler::new(&get_file_name()?, false)?;
-    let cfg = file_handler.read_file()?;
-    Ok(cfg)
+    read_first_config(&config_search_paths(&get_file_name()?))
+}
+
+/// Reads the config file at `path`, or the default one if `None`.
+pub fn read_config(path: &Option<PathBuf>) -> ::Res<Config> {
+    match *path {
+        Some(ref path) => read_first_config(&[path.clone()]),
+        None => read_config_file(),
+    }
+}
+
+/// Paths the config file called `name` is looked for at, in order: next to the executable, in the
+/// current directory and in our directory of the platform's config directory. Only the path
+/// `CRUST_CONFIG_PATH` names if it is set.
+pub fn config_search_paths(name: &OsStr) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(dir) = config_file_handler::current_bin_dir() {
+        dirs.push(dir);
+    }
+    if let Ok(dir) = env::current_dir() {
+        dirs.push(dir);
+    }
+    if let Some(dir) = app_dir(platform_config_dir()) {
+        dirs.push(dir);
+    }
+    search_paths(env::var_os(CONFIG_PATH_ENV_VAR), name, &dirs)
+}
+
+fn search_paths(env_path: Option<OsString>, name: &OsStr, dirs: &[PathBuf]) -> Vec<PathBuf> {
+    if let Some(path) = env_path {
+        return vec![PathBuf::from(path)];
+    }
+    let mut paths: Vec<PathBuf> = Vec::with_capacity(dirs.len());
+    for path in dirs.iter().map(|dir| dir.join(name)) {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Reads the first of `paths` which exists. The error of a missing config lists all of them.
+fn read_first_config(paths: &[PathBuf]) -> ::Res<Config> {
+    for path in paths {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(CrustError::Io(e)),
+        };
+        return parse_config(path, file);
+    }
+    Err(CrustError::ConfigFileNotFound(paths.to_vec()))
+}
+
+/// Parses a config file of any version up to `CONFIG_VERSION`, migrating it to the current one in
+/// memory. The file itself is left as it is.
+fn parse_config<R: Read>(path: &Path, reader: R) -> ::Res<Config> {
+    let invalid = |reason: String| CrustError::InvalidConfigFile(path.to_path_buf(), reason);
+    let mut fields = match serde_json::from_reader(reader) {
+        Ok(Value::Object(fields)) => fields,
+        Ok(_) => return Err(invalid("not a JSON object".to_owned())),
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+
+    let version = match fields.get("config_version") {
+        None => 0,
+        Some(version) => match version.as_u64() {
+            Some(version) if version <= u64::from(u32::max_value()) => version as u32,
+            _ => return Err(invalid(format!("invalid config_version {}", version))),
+        },
+    };
+    // Checked before parsing, as a newer file may mean something else by the fields we know.
+    if version > CONFIG_VERSION {
+        return Err(CrustError::NewerConfigVersion(path.to_path_buf(), version));
+    }
+
+    let changes: Vec<String> = MIGRATIONS[version as usize..]
+        .iter()
+        .flat_map(|migrate| migrate(&mut fields))
+        .collect();
+    if version < CONFIG_VERSION {
+        info!(
+            "Migrated config file {} from version {} to {}: {}",
+            path.display(),
+            version,
+            CONFIG_VERSION,
+            changes.join("; ")
+        );
+    }
+    let _ = fields.insert("config_version".to_owned(), Value::from(CONFIG_VERSION));
+
+    serde_json::from_value(Value::Object(fields)).map_err(|e| invalid(e.to_string()))
+}
+
+/// Version 0 is every file written before the version was recorded, whose fields are those of
+/// version 1.
+fn migrate_v0(_fields: &mut Map<String, Value>) -> Vec<String> {
+    vec!["recorded config_version".to_owned()]
+}
+
+/// Version 1 called `max_concurrent_inbound_handshakes` `max_concurrent_handshakes`, of which a
+/// quarter (at least one) was held back for outbound handshakes. The limit is carried over as the
+/// part inbound handshakes had.
+fn migrate_v1(fields: &mut Map<String, Value>) -> Vec<String> {
+    let max = match fields.remove("max_concurrent_handshakes") {
+        Some(max) => max,
+        None => return Vec::new(),
+    };
+    let inbound = match max.as_u64() {
+        Some(max) => {
+            let max = max as usize;
+            Value::from(cmp::max(1, max.saturating_sub(cmp::max(1, max / 4))))
+        }
+        None => max,
+    };
+    let change = format!(
+        "renamed max_concurrent_handshakes to max_concurrent_inbound_handshakes, as {}",
+        inbound
+    );
+    let _ = fields.insert("max_concurrent_inbound_handshakes".to_owned(), inbound);
+    vec![change]
+}
+
+/// Path of the data file called `name`, such as the bootstrap cache: `name` itself if absolute,
+/// otherwise in our directory of the platform's data directory, created if need be. If there is
+/// none, or it can't be created, the file goes next to the executable.
+pub fn data_file_path(name: &OsStr) -> ::Res<PathBuf> {
+    if Path::new(name).is_absolute() {
+        return Ok(PathBuf::from(name));
+    }
+    if let Some(dir) = app_dir(platform_data_dir()) {
+        match fs::create_dir_all(&dir) {
+            Ok(()) => return Ok(dir.join(name)),
+            Err(e) => debug!("Could not create data directory {:?}: {:?}", dir, e),
+        }
+    }
+    Ok(config_file_handler::current_bin_dir()?.join(name))
+}
+
+/// Our directory in `dir`, named after the executable.
+fn app_dir(dir: Option<PathBuf>) -> Option<PathBuf> {
+    let stem = config_file_handler::exe_file_stem().ok()?;
+    dir.map(|dir| dir.join(stem))
+}
+
+#[cfg(windows)]
+fn platform_config_dir() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(PathBuf::from)
+}
+
+#[cfg(windows)]
+fn platform_data_dir() -> Option<PathBuf> {
+    env::var_os("LOCALAPPDATA")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+}
+
+#[cfg(target_os = "macos")]
+fn platform_config_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join("Library/Application Support"))
+}
+
+#[cfg(target_os = "macos")]
+fn platform_data_dir() -> Option<PathBuf> {
+    platform_config_dir()
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn platform_config_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn platform_data_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// The directory the XDG variable `var` names, or its default under the home directory. Relative
+/// paths in the variable are to be ignored, as per the XDG base directory spec.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn xdg_dir(var: &str, default: &str) -> Option<PathBuf> {
+    match env::var_os(var).map(PathBuf::from) {
+        Some(ref dir) if dir.is_absolute() => Some(dir.clone()),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(default)),
+    }
 }
 
 /// Writes a Crust config file **for use by tests and examples**.
@@ -90,9 +826,6 @@ pub fn read_config_file() -> ::Res<Config> {
 #[cfg(test)]
 #[allow(dead_code)]
 pub fn write_config_file(hard_coded_contacts: Option<Vec<SocketAddr>>) -> ::Res<PathBuf> {
-    use serde_json;
-    use std::io::Write;
-
     let mut config = Config::default();
 
     if let Some(contacts) = hard_coded_contacts {
@@ -119,10 +852,24 @@ fn get_file_name() -> ::Res<OsString> {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
-    use serde_json;
-    use std::io::Read;
-    use std::path::Path;
+    use super::*;
+    use rand;
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("crust-config-{}", rand::random::<u64>()));
+        unwrap!(fs::create_dir_all(&dir));
+        dir
+    }
+
+    /// Writes a config file to `dir`, told apart from others by its acceptor port.
+    fn write_config(dir: &Path, port: u16) -> PathBuf {
+        let mut config = Config::default();
+        config.tcp_acceptor_port = Some(port);
+        let path = dir.join("test.crust.config");
+        let mut file = unwrap!(File::create(&path));
+        unwrap!(file.write_all(&unwrap!(serde_json::to_vec(&config))));
+        path
+    }
 
     #[test]
     fn parse_sample_config_file() {
@@ -143,4 +890,238 @@ mod tests {
             panic!(format!("CrustError parsing sample.config: {:?}", what));
         }
     }
+
+    #[test]
+    fn explicit_path_is_read() {
+        let dir = temp_dir();
+        let path = write_config(&dir, 5483);
+        let config = unwrap!(read_config(&Some(path.clone())));
+        assert_eq!(config.tcp_acceptor_port, Some(5483));
+
+        unwrap!(unwrap!(File::create(&path)).write_all(b"{ not json"));
+        match read_config(&Some(path.clone())) {
+            Err(CrustError::InvalidConfigFile(ref invalid, _)) if *invalid == path => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let missing = dir.join("missing.crust.config");
+        match read_config(&Some(missing.clone())) {
+            Err(CrustError::ConfigFileNotFound(ref tried)) if *tried == vec![missing.clone()] => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        unwrap!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn env_var_short_circuits_the_search() {
+        let (first, second) = (temp_dir(), temp_dir());
+        let _ = write_config(&first, 1);
+        let overridden = write_config(&second, 2);
+        let name = OsStr::new("test.crust.config");
+        let dirs = [first.clone(), second.clone()];
+
+        let paths = search_paths(Some(overridden.clone().into_os_string()), name, &dirs);
+        assert_eq!(paths, vec![overridden]);
+        let config = unwrap!(read_first_config(&paths));
+        assert_eq!(config.tcp_acceptor_port, Some(2));
+
+        assert_eq!(search_paths(None, name, &dirs).len(), 2);
+        assert_eq!(CONFIG_PATH_ENV_VAR, "CRUST_CONFIG_PATH");
+
+        unwrap!(fs::remove_dir_all(&first));
+        unwrap!(fs::remove_dir_all(&second));
+    }
+
+    #[test]
+    fn first_config_found_in_search_order_wins() {
+        let (first, second) = (temp_dir(), temp_dir());
+        let name = OsStr::new("test.crust.config");
+        // The same directory twice, as when the executable is run from its own directory.
+        let dirs = [first.clone(), second.clone(), first.clone()];
+        let paths = search_paths(None, name, &dirs);
+        assert_eq!(paths, vec![first.join(name), second.join(name)]);
+
+        let _ = write_config(&second, 2);
+        assert_eq!(unwrap!(read_first_config(&paths)).tcp_acceptor_port, Some(2));
+        let _ = write_config(&first, 1);
+        assert_eq!(unwrap!(read_first_config(&paths)).tcp_acceptor_port, Some(1));
+
+        unwrap!(fs::remove_dir_all(&first));
+        unwrap!(fs::remove_dir_all(&second));
+    }
+
+    #[test]
+    fn missing_config_error_lists_every_path_tried() {
+        let (first, second) = (temp_dir(), temp_dir());
+        let name = OsStr::new("test.crust.config");
+        let paths = search_paths(None, name, &[first.clone(), second.clone()]);
+
+        let message = match read_first_config(&paths) {
+            Err(e @ CrustError::ConfigFileNotFound(_)) => e.to_string(),
+            res => panic!("Unexpected result: {:?}", res),
+        };
+        for path in &paths {
+            let path = path.display().to_string();
+            assert!(message.contains(&path), "{:?} not in {:?}", path, message);
+        }
+
+        unwrap!(fs::remove_dir_all(&first));
+        unwrap!(fs::remove_dir_all(&second));
+    }
+
+    #[test]
+    fn settings_of_compiled_out_features_are_refused() {
+        let mut config = Config::default();
+        config.service_discovery_port = Some(5484);
+        match config.validate() {
+            Ok(()) => assert!(cfg!(feature = "service-discovery")),
+            Err(CrustError::FeatureDisabled(field, "service-discovery")) => {
+                assert!(!cfg!(feature = "service-discovery"));
+                assert_eq!(field, "service_discovery_port");
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        let mut config = Config::default();
+        config.flight_recorder_dump_path = Some("crust-flight-record".to_owned());
+        match config.validate() {
+            Ok(()) => assert!(cfg!(feature = "flight-recorder")),
+            Err(CrustError::FeatureDisabled(field, "flight-recorder")) => {
+                assert!(!cfg!(feature = "flight-recorder"));
+                assert_eq!(field, "flight_recorder_dump_path");
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        let mut config = Config::default();
+        config.enable_utp = true;
+        match config.validate() {
+            Ok(()) => assert!(cfg!(feature = "utp")),
+            Err(CrustError::FeatureDisabled(field, "utp")) => {
+                assert!(!cfg!(feature = "utp"));
+                assert_eq!(field, "enable_utp");
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        let mut config = Config::default();
+        config.use_relays = true;
+        match config.validate() {
+            Ok(()) => assert!(cfg!(feature = "relay")),
+            Err(CrustError::FeatureDisabled(field, "relay")) => {
+                assert!(!cfg!(feature = "relay"));
+                assert_eq!(field, "use_relays");
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        let mut config = Config::default();
+        config.websocket_port = Some(0);
+        match config.validate() {
+            Ok(()) => assert!(cfg!(feature = "websocket")),
+            Err(CrustError::FeatureDisabled(field, "websocket")) => {
+                assert!(!cfg!(feature = "websocket"));
+                assert_eq!(field, "websocket_port");
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
+    fn fixture(name: &str) -> PathBuf {
+        Path::new("tests/config").join(name)
+    }
+
+    // The fixtures are config files as they were written in each version. They must keep loading
+    // as they are: never edit them, add a fixture for each new version instead.
+    #[test]
+    fn config_files_of_every_version_load() {
+        let v0 = unwrap!(read_config(&Some(fixture("v0.crust.config"))));
+        let mut expected = Config::default();
+        expected.hard_coded_contacts = vec![
+            unwrap!("11.2.3.4:1234".parse()),
+            unwrap!("111.3.4.2:65535".parse()),
+        ];
+        let whitelisted: HashSet<IpAddr> = vec![unwrap!("8.8.4.4".parse())].into_iter().collect();
+        expected.whitelisted_node_ips = Some(whitelisted);
+        expected.tcp_acceptor_port = Some(5483);
+        expected.bootstrap_cache_name = Some("crust.bootstrap.cache".to_owned());
+        expected.network_name = Some("test_network".to_owned());
+        expected.dev = Some(DevConfig {
+            disable_external_reachability_requirement: true,
+        });
+        assert_eq!(v0, expected);
+
+        let v1 = unwrap!(read_config(&Some(fixture("v1.crust.config"))));
+        let mut expected = Config::default();
+        expected.hard_coded_contacts = vec![unwrap!("11.2.3.4:1234".parse())];
+        expected.tcp_acceptor_port = Some(5483);
+        expected.additional_acceptor_ports = vec![443];
+        expected.external_endpoints = vec![unwrap!("198.51.100.2:5483".parse())];
+        expected.network_name = Some("test_network".to_owned());
+        expected.max_peers = Some(100);
+        expected.outbound_bind_addr = Some(unwrap!("10.0.0.2".parse()));
+        assert_eq!(v1, expected);
+
+        let v2 = unwrap!(read_config(&Some(fixture("v2.crust.config"))));
+        let mut expected = Config::default();
+        expected.hard_coded_contacts = vec![unwrap!("11.2.3.4:1234".parse())];
+        expected.tcp_acceptor_port = Some(5483);
+        expected.network_name = Some("test_network".to_owned());
+        expected.max_concurrent_inbound_handshakes = Some(6);
+        assert_eq!(v2, expected);
+    }
+
+    #[test]
+    fn handshake_limit_keeps_its_inbound_part() {
+        let dir = temp_dir();
+        let path = dir.join("test.crust.config");
+        let v1 = r#"{
+            "config_version": 1,
+            "hard_coded_contacts": [],
+            "max_concurrent_handshakes": 8
+        }"#;
+        unwrap!(unwrap!(File::create(&path)).write_all(v1.as_bytes()));
+
+        let config = unwrap!(read_config(&Some(path.clone())));
+        assert_eq!(config.max_concurrent_inbound_handshakes, Some(6));
+
+        unwrap!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn migrated_config_is_written_back_in_one_go() {
+        let dir = temp_dir();
+        let path = dir.join("test.crust.config");
+        unwrap!(fs::copy(fixture("v0.crust.config"), &path));
+        let config = unwrap!(read_config(&Some(path.clone())));
+        assert_eq!(config.config_version, CONFIG_VERSION);
+
+        unwrap!(config.write_migrated(&path));
+        let written: Value = unwrap!(serde_json::from_reader(unwrap!(File::open(&path))));
+        assert_eq!(written["config_version"], Value::from(CONFIG_VERSION));
+        assert_eq!(unwrap!(read_config(&Some(path.clone()))), config);
+        assert_eq!(unwrap!(fs::read_dir(&dir)).count(), 1);
+
+        unwrap!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn config_from_a_newer_crust_is_refused() {
+        let dir = temp_dir();
+        let path = dir.join("test.crust.config");
+        let newer = format!(
+            r#"{{ "config_version": {}, "listeners": [], "hard_coded_contacts": [] }}"#,
+            CONFIG_VERSION + 1
+        );
+        unwrap!(unwrap!(File::create(&path)).write_all(newer.as_bytes()));
+
+        match read_config(&Some(path.clone())) {
+            Err(e @ CrustError::NewerConfigVersion(..)) => {
+                assert!(e.to_string().contains("from a newer Crust"));
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        unwrap!(fs::remove_dir_all(&dir));
+    }
 }
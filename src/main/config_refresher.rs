@@ -7,9 +7,8 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::{Core, CoreTimer, CrustUser, State, Uid};
-use main::{read_config_file, ActiveConnection, ConnectionMap, CrustConfig};
-use mio::timer::Timeout;
+use common::{Core, CoreTimer, State, Uid};
+use main::{read_config, ActiveConnection, Config, ConnectionMap, CrustConfig, Event, EventSink};
 use mio::{Poll, Token};
 use std::any::Any;
 use std::cell::RefCell;
@@ -18,12 +17,20 @@ use std::time::Duration;
 
 const REFRESH_INTERVAL_SEC: u64 = 30;
 
+/// Moves the listeners, if running, to the ports in the config.
+pub type RestartListeners = Box<Fn(&mut Core, &Poll) + Send>;
+
+/// Re-reads the config file every `REFRESH_INTERVAL_SEC` and applies what changed: peers no longer
+/// whitelisted are dropped, the listeners move to new ports, and the rest is read from the config
+/// where it is used. Each change is reported with `Event::ConfigUpdated`. A file which doesn't pass
+/// `Config::validate` is ignored.
 pub struct ConfigRefresher<UID: Uid> {
     token: Token,
     timer: CoreTimer,
-    timeout: Timeout,
     cm: ConnectionMap<UID>,
     config: CrustConfig,
+    event_tx: EventSink<UID>,
+    restart_listeners: RestartListeners,
 }
 
 impl<UID: Uid> ConfigRefresher<UID> {
@@ -32,18 +39,21 @@ impl<UID: Uid> ConfigRefresher<UID> {
         token: Token,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
+        event_tx: EventSink<UID>,
+        restart_listeners: RestartListeners,
     ) -> ::Res<()> {
         trace!("Entered state ConfigRefresher");
 
         let timer = CoreTimer::new(token, 0);
-        let timeout = core.set_timeout(Duration::from_secs(REFRESH_INTERVAL_SEC), timer)?;
+        core.set_periodic_timeout(Duration::from_secs(REFRESH_INTERVAL_SEC), timer)?;
 
         let state = Rc::new(RefCell::new(ConfigRefresher {
             token,
             timer,
-            timeout,
             cm,
             config,
+            event_tx,
+            restart_listeners,
         }));
         let _ = core.insert_state(token, state);
 
@@ -52,22 +62,23 @@ impl<UID: Uid> ConfigRefresher<UID> {
 }
 
 impl<UID: Uid> State for ConfigRefresher<UID> {
+    fn name(&self) -> &'static str {
+        "ConfigRefresher"
+    }
+
     fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
-        let _ = core.cancel_timeout(&self.timeout);
         let _ = core.remove_state(self.token);
     }
 
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
-        self.timeout = match core.set_timeout(Duration::from_secs(REFRESH_INTERVAL_SEC), self.timer)
-        {
-            Ok(t) => t,
-            Err(e) => {
-                debug!("Config Refresher Timer Errored out: {:?}", e);
-                return self.terminate(core, poll);
-            }
-        };
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        let interval = Duration::from_secs(REFRESH_INTERVAL_SEC);
+        if let Err(e) = core.set_periodic_timeout(interval, self.timer) {
+            debug!("Config Refresher Timer Errored out: {:?}", e);
+            return self.terminate(core, poll);
+        }
 
-        let config = match read_config_file() {
+        let config_path = unwrap!(self.config.lock()).config_path.clone();
+        let config = match read_config(&config_path) {
             Ok(cfg) => cfg,
             Err(e) => {
                 debug!(
@@ -77,13 +88,27 @@ impl<UID: Uid> State for ConfigRefresher<UID> {
                 return;
             }
         };
+        if let Err(e) = config.validate() {
+            debug!("Ignoring the Crust config, as it isn't valid: {:?}", e);
+            return;
+        }
 
-        let whitelisted_node_ips = config.whitelisted_node_ips.clone();
-        let whitelisted_client_ips = config.whitelisted_client_ips.clone();
+        let (refresh, updated, ports_changed, whitelists) = {
+            let mut config_wrapper = unwrap!(self.config.lock());
+            let updated = config_wrapper.cfg != config;
+            let ports_changed = listener_ports(&config_wrapper.cfg) != listener_ports(&config);
+            let refresh = config_wrapper.check_for_refresh_and_reset_modified(config);
+            (refresh, updated, ports_changed, config_wrapper.whitelists.clone())
+        };
+        if ports_changed {
+            debug!("Listener ports changed in the Crust config - moving the listeners");
+            (self.restart_listeners)(core, poll);
+        }
+        if updated {
+            self.event_tx.send(Event::ConfigUpdated);
+        }
 
-        if !unwrap!(self.config.lock()).check_for_refresh_and_reset_modified(config)
-            || (whitelisted_node_ips.is_none() && whitelisted_client_ips.is_none())
-        {
+        if !refresh || (whitelists.node_ips.is_none() && whitelists.client_ips.is_none()) {
             return;
         }
 
@@ -118,14 +143,7 @@ impl<UID: Uid> State for ConfigRefresher<UID> {
                                     );
                                     true
                                 }
-                                Ok(s) => match ac.peer_kind() {
-                                    CrustUser::Node => whitelisted_node_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                    CrustUser::Client => whitelisted_client_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                },
+                                Ok(s) => !whitelists.allows(ac.peer_kind(), &s.ip()),
                             }
                         };
                         if should_drop {
@@ -146,3 +164,106 @@ impl<UID: Uid> State for ConfigRefresher<UID> {
         self
     }
 }
+
+/// The settings which the listeners are started with.
+fn listener_ports(config: &Config) -> (Option<u16>, &[u16], bool, Option<u16>) {
+    (
+        config.tcp_acceptor_port,
+        &config.additional_acceptor_ports,
+        config.force_acceptor_port_in_ext_ep,
+        config.websocket_port,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoreMessage, ManualEventLoop, VirtualClock};
+    use maidsafe_utilities::event_sender::MaidSafeEventCategory;
+    use main::ConfigWrapper;
+    use rand;
+    use serde_json;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use tests::UniqueId;
+
+    #[test]
+    fn changes_to_the_config_file_are_applied_and_reported() {
+        let dir = env::temp_dir().join(format!("crust-refresh-{}", rand::random::<u64>()));
+        unwrap!(fs::create_dir_all(&dir));
+        let path = dir.join("test.crust.config");
+        let write = |config: &Config| {
+            unwrap!(unwrap!(File::create(&path)).write_all(&unwrap!(serde_json::to_vec(config))));
+        };
+        let mut config = Config::default();
+        write(&config);
+        let mut config_wrapper = ConfigWrapper::new(config.clone());
+        config_wrapper.config_path = Some(path.clone());
+        let crust_config = Arc::new(Mutex::new(config_wrapper));
+
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let restarts = Arc::new(AtomicUsize::new(0));
+        let counter = restarts.clone();
+        let restart_listeners = Box::new(move |_: &mut Core, _: &Poll| {
+            let _ = counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let cm: ConnectionMap<UniqueId> = Arc::new(Mutex::new(HashMap::new()));
+        let refresher_config = crust_config.clone();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let event_tx = EventSink::new(event_tx, core.sender().clone());
+            unwrap!(ConfigRefresher::start(
+                core,
+                Token(0),
+                cm,
+                refresher_config,
+                event_tx,
+                restart_listeners,
+            ));
+        })));
+        let mut refresh = || {
+            clock.advance(Duration::from_secs(REFRESH_INTERVAL_SEC));
+            for _ in 0..3 {
+                let _ = unwrap!(el.run_once(Duration::from_millis(2)));
+            }
+        };
+        let expect_update =
+            |event_rx: &mpsc::Receiver<Event<UniqueId>>| match unwrap!(event_rx.try_recv()) {
+                Event::ConfigUpdated => (),
+                event => panic!("Unexpected event: {:?}", event),
+            };
+
+        refresh();
+        assert!(event_rx.try_recv().is_err());
+
+        // Contacts are read from the config as they are used.
+        config.hard_coded_contacts = vec![unwrap!("127.0.0.1:5483".parse())];
+        write(&config);
+        refresh();
+        expect_update(&event_rx);
+        assert_eq!(unwrap!(crust_config.lock()).cfg, config);
+        assert_eq!(restarts.load(Ordering::SeqCst), 0);
+
+        config.tcp_acceptor_port = Some(5483);
+        write(&config);
+        refresh();
+        expect_update(&event_rx);
+        assert_eq!(restarts.load(Ordering::SeqCst), 1);
+
+        // A config which isn't valid is ignored.
+        config.max_buffered_bytes = Some(1);
+        write(&config);
+        refresh();
+        assert!(event_rx.try_recv().is_err());
+        assert_eq!(unwrap!(crust_config.lock()).cfg.max_buffered_bytes, None);
+
+        unwrap!(fs::remove_dir_all(&dir));
+    }
+}
@@ -7,24 +7,52 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::{Core, Message, NameHash, Priority, Socket, State, Uid};
+use common::{
+    offer_extensions, take_extension_answers, ChildHandle, ContactUpdateExtension, Core,
+    CorrelationExtension, EncryptionExtension, HandshakeStage, Message, NameHash,
+    NegotiatedFeatures, NetworkId, NetworkProver, Priority, ProbeExtension, PublicKey, Rejection,
+    RelayExtension, RetirementExtension, Socket, State, TimestampExtension, Uid, UpgradeExtension,
+    CONTROL_PRIORITY,
+};
 use main::{ConnectionId, ConnectionMap};
 use mio::{Poll, PollOpt, Ready, Token};
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::mem;
+use std::net::SocketAddr;
 use std::rc::Rc;
 
-pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Option<Socket>)>;
+/// Called with the socket and what was negotiated once the peer answered, or with its rejection if
+/// it refused us.
+pub type Finish = Box<
+    FnMut(&mut Core, &Poll, Token, Result<(Socket, NegotiatedFeatures), Option<Rejection>>),
+>;
 
 pub struct ExchangeMsg<UID: Uid> {
     token: Token,
     expected_id: UID,
-    expected_nh: NameHash,
+    network: NetworkProver,
     socket: Socket,
     cm: ConnectionMap<UID>,
     msg: Option<(Message<UID>, Priority)>,
+    /// The peer's address and our request without extensions, made over a new connection if the
+    /// peer closes this one without answering, as peers older than them do, see `fall_back`.
+    legacy: Option<(SocketAddr, Message<UID>)>,
+    /// Whether the peer has sent us anything.
+    answered: bool,
+    timestamps: TimestampExtension,
+    encryption: EncryptionExtension,
+    relay: RelayExtension,
+    /// On a connection to a relay, what we prove to the relay we are on its network with, and our
+    /// request to the peer, to be made once the relay has paired us with it.
+    relay_leg: Option<(NetworkProver, Message<UID>)>,
+    /// Whether we have keys, and so refuse peers which don't agree on encryption.
+    require_encryption: bool,
+    /// The peer, what was agreed on with it and its key, once it answered us with encryption
+    /// agreed on, until its `Message::KeyConfirmation` opens.
+    confirming: Option<(UID, NegotiatedFeatures, PublicKey)>,
+    parent: ChildHandle,
     finish: Finish,
 }
 
@@ -35,8 +63,13 @@ impl<UID: Uid> ExchangeMsg<UID> {
         socket: Socket,
         our_id: UID,
         expected_id: UID,
-        name_hash: NameHash,
+        network: NetworkId,
         cm: ConnectionMap<UID>,
+        timestamp_frames: bool,
+        relay: bool,
+        via_relay: bool,
+        both_offer: bool,
+        parent: ChildHandle,
         finish: Finish,
     ) -> ::Res<Token> {
         let token = core.get_new_token();
@@ -64,13 +97,60 @@ impl<UID: Uid> ExchangeMsg<UID> {
             );
         }
 
+        let mut timestamps = TimestampExtension::new(timestamp_frames);
+        let mut encryption = if both_offer {
+            EncryptionExtension::simultaneous(core.keys())
+        } else {
+            EncryptionExtension::new(core.keys())
+        };
+        let mut relay = RelayExtension::new(relay);
+        let offers = offer_extensions(&mut [
+            &mut timestamps,
+            &mut CorrelationExtension,
+            &mut RetirementExtension,
+            &mut ProbeExtension,
+            &mut ContactUpdateExtension,
+            &mut UpgradeExtension,
+            &mut encryption,
+            &mut relay,
+        ]);
+        // Older peers neither prove private networks, agree on encryption, punch holes nor
+        // relay, so they are only fallen back on when none of these is needed.
+        let legacy = match (network, socket.peer_addr()) {
+            (NetworkId::Plain(name_hash), Ok(addr))
+                if core.keys().is_none() && socket.is_tcp() && !both_offer && !via_relay =>
+            {
+                Some((addr, Message::Connect(our_id, name_hash)))
+            }
+            _ => None,
+        };
+        let prover = NetworkProver::new(network);
+        let connect = Message::ExtConnect(our_id, prover.request(), offers);
+        // Through a relay, we ask it for a pipe to the peer first, and make our request to the
+        // peer once it has paired us.
+        let (msg, relay_leg) = if via_relay {
+            let relay_prover = NetworkProver::new(network);
+            let request = Message::RelayRequest(our_id, relay_prover.request(), expected_id);
+            (request, Some((relay_prover, connect)))
+        } else {
+            (connect, None)
+        };
         let state = Self {
             token,
             expected_id,
-            expected_nh: name_hash,
+            network: prover,
             socket,
             cm,
-            msg: Some((Message::Connect(our_id, name_hash), 0)),
+            msg: Some((msg, CONTROL_PRIORITY)),
+            legacy,
+            answered: false,
+            timestamps,
+            encryption,
+            relay,
+            relay_leg,
+            require_encryption: core.keys().is_some(),
+            confirming: None,
+            parent,
             finish,
         };
 
@@ -81,41 +161,229 @@ impl<UID: Uid> ExchangeMsg<UID> {
 
     fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message<UID>, Priority)>) {
         if self.socket.write(poll, self.token, msg).is_err() {
-            self.handle_error(core, poll);
+            self.handle_error(core, poll, None);
         }
     }
 
     fn receive_response(&mut self, core: &mut Core, poll: &Poll) {
-        match self.socket.read::<Message<UID>>() {
+        if self.relay_leg.is_some() {
+            return self.receive_relay_response(core, poll);
+        }
+        if self.confirming.is_some() {
+            return self.receive_key_confirmation(core, poll);
+        }
+        let res = self.socket.read::<Message<UID>>();
+        if let Ok(Some(_)) = res {
+            self.answered = true;
+        }
+        match res {
+            Ok(Some(Message::ExtConnect(their_uid, name_hash, answers))) => {
+                let features = take_extension_answers(
+                    &mut [
+                        &mut self.timestamps,
+                        &mut CorrelationExtension,
+                        &mut RetirementExtension,
+                        &mut ProbeExtension,
+                        &mut ContactUpdateExtension,
+                        &mut UpgradeExtension,
+                        &mut self.encryption,
+                        &mut self.relay,
+                    ],
+                    &answers,
+                );
+                self.connected(core, poll, their_uid, name_hash, features)
+            }
             Ok(Some(Message::Connect(their_uid, name_hash))) => {
-                if their_uid != self.expected_id || name_hash != self.expected_nh {
-                    return self.handle_error(core, poll);
+                self.connected(core, poll, their_uid, name_hash, NegotiatedFeatures::default())
+            }
+            Ok(Some(Message::NetworkChallenge(challenge, proof))) => {
+                match self.network.answer(challenge, &proof) {
+                    Some(answer) => {
+                        let msg = Message::NetworkProof(answer);
+                        self.write(core, poll, Some((msg, CONTROL_PRIORITY)))
+                    }
+                    None => self.handle_error(core, poll, None),
+                }
+            }
+            Ok(Some(Message::Rejection(rejection))) => {
+                self.handle_error(core, poll, Some(rejection))
+            }
+            Ok(None) => (),
+            Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
+        }
+    }
+
+    /// Handles the answers of the relay, until it has paired us with the peer. A relay turning us
+    /// down is no rejection by the peer.
+    fn receive_relay_response(&mut self, core: &mut Core, poll: &Poll) {
+        let res = match self.socket.read::<Message<UID>>() {
+            Ok(Some(Message::NetworkChallenge(challenge, proof))) => {
+                let answer = match self.relay_leg {
+                    Some((ref mut prover, _)) => prover.answer(challenge, &proof),
+                    None => None,
+                };
+                match answer {
+                    Some(answer) => {
+                        let msg = Message::NetworkProof(answer);
+                        self.socket.write(poll, self.token, Some((msg, CONTROL_PRIORITY)))
+                    }
+                    None => return self.handle_error(core, poll, None),
+                }
+            }
+            Ok(Some(Message::RelayChallenge(challenge, relay_key))) => {
+                // The relay pairs only peers proving they hold the key they connected to it with.
+                let proof = match core.keys() {
+                    Some(keys) => keys.prove(&relay_key, &challenge),
+                    None => return self.handle_error(core, poll, None),
+                };
+                let msg = Message::RelayProof(proof);
+                self.socket.write(poll, self.token, Some((msg, CONTROL_PRIORITY)))
+            }
+            Ok(Some(Message::RelayReady(name_hash))) => {
+                let (prover, msg) = unwrap!(self.relay_leg.take());
+                if !prover.accepts(Some(&name_hash)) {
+                    return self.handle_error(core, poll, None);
                 }
-                let _ = core.remove_state(self.token);
-                let token = self.token;
-                let socket = mem::replace(&mut self.socket, Socket::default());
+                #[cfg(feature = "relay")]
+                {
+                    self.socket.set_relayed();
+                }
+                core.set_pending_stage(self.token, HandshakeStage::HandshakeSent);
+                self.socket.write(poll, self.token, Some((msg, CONTROL_PRIORITY)))
+            }
+            Ok(Some(Message::Rejection(rejection))) => {
+                debug!(
+                    "Relay to peer {:?} turned us down: ({:?}) {}",
+                    self.expected_id,
+                    rejection.kind(),
+                    rejection.message
+                );
+                return self.handle_error(core, poll, None);
+            }
+            Ok(None) => return,
+            Ok(Some(_)) | Err(_) => return self.handle_error(core, poll, None),
+        };
+        match res {
+            // The peer's request may have come along with the answer of the relay.
+            Ok(_) if self.relay_leg.is_none() => self.receive_response(core, poll),
+            Ok(_) => (),
+            Err(_) => self.handle_error(core, poll, None),
+        }
+    }
+
+    fn connected(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        their_uid: UID,
+        name_hash: NameHash,
+        features: NegotiatedFeatures,
+    ) {
+        if their_uid != self.expected_id || !self.network.accepts(Some(&name_hash)) {
+            return self.handle_error(core, poll, None);
+        }
+        let keys = match self.encryption.take_session() {
+            Some(keys) => keys,
+            None if self.require_encryption => {
+                debug!("Peer {:?} didn't agree on encryption", their_uid);
+                return self.handle_error(core, poll, None);
+            }
+            None => return self.hand_over(core, poll, features),
+        };
 
-                (*self.finish)(core, poll, token, Some(socket));
+        // Both sides confirm the keys before taking the connection up.
+        self.confirming = Some((their_uid, features, keys.peer_key()));
+        self.socket.enable_encryption(keys);
+        let msg = Some((Message::KeyConfirmation, CONTROL_PRIORITY));
+        if self.socket.write(poll, self.token, msg).is_err() {
+            return self.handle_error(core, poll, None);
+        }
+        // The peer's confirmation may have come along with its answer.
+        self.receive_key_confirmation(core, poll)
+    }
+
+    fn receive_key_confirmation(&mut self, core: &mut Core, poll: &Poll) {
+        match self.socket.read::<Message<UID>>() {
+            Ok(Some(Message::KeyConfirmation)) => {
+                let (their_uid, mut features, peer_key) = unwrap!(self.confirming.take());
+                trace!("Peer {:?} confirmed the keys", their_uid);
+                features.peer_key = Some(peer_key);
+                self.hand_over(core, poll, features)
             }
             Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
+            Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
+        }
+    }
+
+    fn hand_over(&mut self, core: &mut Core, poll: &Poll, features: NegotiatedFeatures) {
+        let _ = core.hand_over_state(self.token);
+        let token = self.token;
+        let socket = mem::replace(&mut self.socket, Socket::default());
+        (*self.finish)(core, poll, token, Ok((socket, features)));
+    }
+
+    /// Makes the request again without extensions over a new connection, if the peer closed ours
+    /// after our request and before answering it: peers older than the extensions can't decode
+    /// the request, and close the connection on it. Returns whether the request was made again.
+    fn fall_back(&mut self, poll: &Poll) -> bool {
+        if self.msg.is_some() || self.answered {
+            return false;
         }
+        let (addr, request) = match self.legacy.take() {
+            Some(legacy) => legacy,
+            None => return false,
+        };
+        let bind_ip = self.socket.local_addr().ok().map(|addr| addr.ip());
+        let socket = match Socket::connect_from(&addr, bind_ip) {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("Failed to connect to peer {:?} again: {:?}", self.expected_id, e);
+                return false;
+            }
+        };
+        let _ = poll.deregister(&self.socket);
+        if let Err(e) = poll.register(
+            &socket,
+            self.token,
+            Ready::error() | Ready::hup() | Ready::writable(),
+            PollOpt::edge(),
+        ) {
+            debug!("Failed to register the new socket to {}: {:?}", addr, e);
+            return false;
+        }
+        debug!(
+            "Peer {:?} closed the connection without answering - asking again without extensions",
+            self.expected_id
+        );
+        self.socket = socket;
+        self.msg = Some((request, CONTROL_PRIORITY));
+        true
     }
 
-    fn handle_error(&mut self, core: &mut Core, poll: &Poll) {
+    fn handle_error(&mut self, core: &mut Core, poll: &Poll, rejection: Option<Rejection>) {
+        if rejection.is_none() && self.fall_back(poll) {
+            return;
+        }
         self.terminate(core, poll);
         let token = self.token;
-        (*self.finish)(core, poll, token, None);
+        (*self.finish)(core, poll, token, Err(rejection));
     }
 }
 
 impl<UID: Uid> State for ExchangeMsg<UID> {
+    fn name(&self) -> &'static str {
+        "connect::ExchangeMsg"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
-            self.handle_error(core, poll);
+            self.handle_error(core, poll, None);
         } else {
             if kind.is_writable() {
                 let req = self.msg.take();
+                if req.is_some() && self.relay_leg.is_none() {
+                    core.set_pending_stage(self.token, HandshakeStage::HandshakeSent);
+                }
                 self.write(core, poll, req);
             }
             if kind.is_readable() {
@@ -125,6 +393,7 @@ impl<UID: Uid> State for ExchangeMsg<UID> {
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.parent.deregister(self.token);
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
 
@@ -10,34 +10,71 @@
 mod exchange_msg;
 
 use self::exchange_msg::ExchangeMsg;
-use common::{Core, CoreTimer, CrustUser, NameHash, Socket, State, Uid};
+use common::{
+    ChildrenSet, ConnectionDirection, Core, CoreTimer, CrustUser, DialHandler, DialSettings,
+    DialTarget, DialVia, Dialer, HandshakeStage, NegotiatedFeatures, NetworkId, Rejection, Socket,
+    State, Uid,
+};
 use main::{
-    ActiveConnection, ConnectionCandidate, ConnectionMap, CrustError, Event, PrivConnectionInfo,
-    PubConnectionInfo,
+    upgrade_dial_over, ActiveConnection, CacheUpdate, CacheWriter, CandidateAddr,
+    ConnectionCandidate, ConnectionMap, ConnectionSettings, ContactFailure, CrustError, Event,
+    EventSink, ParkedPeers, PathHistory, PathKind, PrivConnectionInfo, PubConnectionInfo,
+    Reconnects, RECONNECTS_TOKEN,
 };
-use mio::tcp::{TcpListener, TcpStream};
-use mio::timer::Timeout;
+use mio::tcp::TcpListener;
 use mio::{Poll, PollOpt, Ready, Token};
 use nat;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::{Rc, Weak};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const TIMEOUT_SEC: u64 = 60;
+const TIMEOUT_TIMER_ID: u64 = 0;
+const RELAY_FALLBACK_TIMER_ID: u64 = 1;
+/// Time after dialling the peer directly that its relays are dialled too, unless every direct
+/// attempt failed sooner.
+const RELAY_FALLBACK_SECS: u64 = 5;
+/// Time after dialling a candidate that the next one is dialled, unless the attempt fails sooner.
+pub const CONNECT_STAGGER_MS: u64 = 250;
+
+/// Why a peer we were connected to before is dialled again, which changes what is reported.
+pub enum Redial<UID: Uid> {
+    /// By `Service::unpark`: the peer leaves the parked table once reconnected to.
+    Unpark(ParkedPeers<UID>),
+    /// By `Reconnects`, which is told how the attempt went and reports giving up itself.
+    Reconnect,
+    /// By `Upgrades`, for the connection to the peer to move onto, which is told if the attempt
+    /// failed. Nothing is reported: the connection reports how its upgrade went.
+    Upgrade,
+}
 
 pub struct Connect<UID: Uid> {
     token: Token,
-    timeout: Timeout,
     cm: ConnectionMap<UID>,
-    our_nh: NameHash,
+    network: NetworkId,
     our_id: UID,
     their_id: UID,
     self_weak: Weak<RefCell<Connect<UID>>>,
     listener: Option<TcpListener>,
-    children: HashSet<Token>,
-    event_tx: ::CrustEventSender<UID>,
+    children: ChildrenSet,
+    /// Address each child dialled and when, to keep the health of cached peers up to date.
+    dialled: HashMap<Token, (SocketAddr, Instant)>,
+    event_tx: EventSink<UID>,
+    redial: Option<Redial<UID>>,
+    settings: ConnectionSettings,
+    /// Why the peer refused us, reported if no other attempt succeeds.
+    rejection: Option<Rejection>,
+    /// The child dialling the candidates, until it is done with them.
+    dialer: Option<Token>,
+    /// Kind of path each child dialled, to learn which kinds work.
+    paths: HashMap<Token, PathKind>,
+    /// The relays the peer listed, until they are dialled, see `dial_relays`.
+    relays: Vec<CandidateAddr>,
+    /// See `PrivConnectionInfo::outbound_bind_addr`.
+    bind_ip: Option<IpAddr>,
 }
 
 impl<UID: Uid> Connect<UID> {
@@ -47,44 +84,98 @@ impl<UID: Uid> Connect<UID> {
         our_ci: PrivConnectionInfo<UID>,
         their_ci: PubConnectionInfo<UID>,
         cm: ConnectionMap<UID>,
-        our_nh: NameHash,
-        event_tx: ::CrustEventSender<UID>,
+        network: NetworkId,
+        event_tx: EventSink<UID>,
+        redial: Option<Redial<UID>>,
+        settings: ConnectionSettings,
     ) -> ::Res<()> {
         let their_id = their_ci.id;
-        let their_direct = their_ci.for_direct;
-        let their_hole_punch = their_ci.for_hole_punch;
+        let (relays, mut candidates): (Vec<_>, Vec<_>) = their_ci
+            .candidates
+            .into_iter()
+            .filter(|candidate| match *candidate {
+                CandidateAddr::TcpAsserted(_)
+                | CandidateAddr::TcpDirect(_)
+                | CandidateAddr::TcpMapped(_) => true,
+                CandidateAddr::Utp(_) if cfg!(feature = "utp") && settings.enable_utp => true,
+                CandidateAddr::Utp(_) => {
+                    debug!("Skipping unsupported connection candidate {:?}", candidate);
+                    false
+                }
+                #[cfg(feature = "relay")]
+                CandidateAddr::Relay(_) if settings.use_relays => true,
+                #[cfg(feature = "relay")]
+                CandidateAddr::Relay(_) => {
+                    debug!("Skipping unsupported connection candidate {:?}", candidate);
+                    false
+                }
+            })
+            .partition(is_relay);
 
-        if their_direct.is_empty() && their_hole_punch.is_empty() {
-            let _ = event_tx.send(Event::ConnectFailure(their_id));
+        if candidates.is_empty() && relays.is_empty() {
+            event_tx.send(Event::ConnectFailure(their_id));
             return Err(CrustError::InsufficientConnectionInfo);
         }
 
+        // The endpoints asserted by the peer's operator first, then direct candidates, unless the
+        // peer's history says otherwise. uTP is only tried after TCP.
+        candidates.sort_by_key(|candidate| match *candidate {
+            CandidateAddr::TcpAsserted(_) => 0,
+            CandidateAddr::TcpMapped(_) => 2,
+            CandidateAddr::Utp(_) => 3,
+            _ => 1,
+        });
+        let _ = PathHistory::with(core, &settings.bootstrap_cache_name, |history| {
+            history.order(&their_id, &mut candidates)
+        });
+
         let token = core.get_new_token();
+        let timer = CoreTimer::new(token, TIMEOUT_TIMER_ID);
+        core.set_timeout(Duration::from_secs(TIMEOUT_SEC), timer)?;
+        if !candidates.is_empty() && !relays.is_empty() {
+            let timer = CoreTimer::new(token, RELAY_FALLBACK_TIMER_ID);
+            core.set_timeout(Duration::from_secs(RELAY_FALLBACK_SECS), timer)?;
+        }
 
         let state = Rc::new(RefCell::new(Self {
             token,
-            timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0))?,
             cm,
-            our_nh,
+            network,
             our_id: our_ci.id,
             their_id,
             self_weak: Weak::new(),
             listener: None,
-            children: HashSet::with_capacity(their_direct.len() + their_hole_punch.len()),
+            children: ChildrenSet::with_capacity(candidates.len()),
+            dialled: HashMap::with_capacity(candidates.len()),
             event_tx,
+            redial,
+            settings,
+            rejection: None,
+            dialer: None,
+            paths: HashMap::with_capacity(candidates.len()),
+            relays,
+            bind_ip: our_ci.outbound_bind_addr,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
 
-        let mut sockets = their_direct
-            .into_iter()
-            .filter_map(|elt| Socket::connect(&elt).ok())
-            .collect::<Vec<_>>();
+        // Without anything to dial directly, the relays are dialled right away.
+        if candidates.is_empty() {
+            state.borrow_mut().dial_relays(core, poll)?;
+            let _ = core.insert_state(token, state);
+            return Ok(());
+        }
 
+        let mapped = candidates
+            .iter()
+            .filter(|candidate| match **candidate {
+                CandidateAddr::TcpMapped(_) => true,
+                _ => false,
+            })
+            .count();
+        let mut nat_sockets = Vec::new();
         if let Some(hole_punch_sock) = our_ci.hole_punch_socket {
-            if let Ok((listener, nat_sockets)) =
-                nat::get_sockets(&hole_punch_sock, their_hole_punch.len())
-            {
+            if let Ok((listener, sockets)) = nat::get_sockets(&hole_punch_sock, mapped) {
                 poll.register(
                     &listener,
                     token,
@@ -92,19 +183,42 @@ impl<UID: Uid> Connect<UID> {
                     PollOpt::edge(),
                 )?;
                 state.borrow_mut().listener = Some(listener);
-                sockets.extend(
-                    nat_sockets
-                        .into_iter()
-                        .zip(their_hole_punch.into_iter().map(|elt| elt))
-                        .filter_map(|elt| TcpStream::connect_stream(elt.0, &elt.1).ok())
-                        .map(Socket::wrap)
-                        .collect::<Vec<_>>(),
-                );
+                nat_sockets = sockets;
             }
         }
 
-        for socket in sockets {
-            state.borrow_mut().exchange_msg(core, poll, socket);
+        let mut nat_sockets = nat_sockets.into_iter();
+        let targets = candidates
+            .into_iter()
+            .filter_map(|candidate| {
+                let via = match candidate {
+                    CandidateAddr::TcpMapped(_) => DialVia::TcpFrom(nat_sockets.next()?),
+                    #[cfg(feature = "utp")]
+                    CandidateAddr::Utp(_) => DialVia::Utp,
+                    _ => DialVia::Tcp,
+                };
+                Some(DialTarget {
+                    addr: candidate.addr(),
+                    via,
+                    context: candidate,
+                })
+            })
+            .collect();
+        let settings = dial_settings(our_ci.outbound_bind_addr);
+        let handler: Rc<RefCell<DialHandler<CandidateAddr>>> = state.clone();
+        let children = state.borrow().children.handle();
+        let dialer = Dialer::start(
+            core,
+            poll,
+            targets,
+            settings,
+            children,
+            Rc::downgrade(&handler),
+        )?;
+        {
+            let mut connect = state.borrow_mut();
+            connect.children.insert(dialer);
+            connect.dialer = Some(dialer);
         }
 
         let _ = core.insert_state(token, state);
@@ -112,29 +226,59 @@ impl<UID: Uid> Connect<UID> {
         Ok(())
     }
 
-    fn exchange_msg(&mut self, core: &mut Core, poll: &Poll, socket: Socket) {
+    fn exchange_msg(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        socket: Socket,
+        addr: SocketAddr,
+        stage: HandshakeStage,
+        via_relay: bool,
+    ) -> Option<Token> {
+        // Over a punched hole or through a relay, the peer makes its request as we do ours
+        // rather than answer it.
+        let both_offer = match stage {
+            HandshakeStage::HolePunching => true,
+            #[cfg(feature = "relay")]
+            HandshakeStage::RelayPairing => true,
+            _ => false,
+        };
+        // The connection is then made by both of us rather than just us.
+        let direction = if both_offer {
+            None
+        } else {
+            Some(ConnectionDirection::Outbound)
+        };
         let self_weak = self.self_weak.clone();
         let handler = move |core: &mut Core, poll: &Poll, child, res| {
             if let Some(self_rc) = self_weak.upgrade() {
                 self_rc
                     .borrow_mut()
-                    .handle_exchange_msg(core, poll, child, res);
+                    .handle_exchange_msg(core, poll, child, res, direction);
             }
         };
 
-        if let Ok(child) = ExchangeMsg::start(
+        let child = ExchangeMsg::start(
             core,
             poll,
             socket,
             self.our_id,
             self.their_id,
-            self.our_nh,
+            self.network,
             self.cm.clone(),
+            self.settings.timestamp_frames,
+            self.settings.relay,
+            via_relay,
+            both_offer,
+            self.children.handle(),
             Box::new(handler),
-        ) {
-            let _ = self.children.insert(child);
+        ).ok()?;
+        self.children.insert(child);
+        core.add_pending(child, addr, ConnectionDirection::Outbound, stage);
+        if stage == HandshakeStage::TcpConnecting {
+            let _ = self.dialled.insert(child, (addr, core.now()));
         }
-        self.maybe_terminate(core, poll);
+        Some(child)
     }
 
     fn handle_exchange_msg(
@@ -142,16 +286,47 @@ impl<UID: Uid> Connect<UID> {
         core: &mut Core,
         poll: &Poll,
         child: Token,
-        res: Option<Socket>,
+        res: Result<(Socket, NegotiatedFeatures), Option<Rejection>>,
+        direction: Option<ConnectionDirection>,
     ) {
-        let _ = self.children.remove(&child);
-        if let Some(socket) = res {
+        let _ = self.children.remove(child);
+        if let Some((addr, started)) = self.dialled.remove(&child) {
+            let outcome = match res {
+                Ok(_) => Ok(core.now() - started),
+                Err(Some(_)) => Err(ContactFailure::Denied),
+                Err(None) => Err(ContactFailure::Unreachable),
+            };
+            self.record_attempt(core, addr, outcome);
+        }
+        if let Some(kind) = self.paths.remove(&child) {
+            match res {
+                Ok(_) => self.record_path(core, kind, true),
+                Err(None) => self.record_path(core, kind, false),
+                // The peer was reached, and refused us.
+                Err(Some(_)) => (),
+            }
+        }
+        let connected = match res {
+            Ok(connected) => Some(connected),
+            Err(Some(rejection)) => {
+                info!(
+                    "Peer {:?} rejected our connection: ({:?}) {}",
+                    self.their_id,
+                    rejection.kind(),
+                    rejection.message
+                );
+                self.rejection = Some(rejection);
+                None
+            }
+            Err(None) => None,
+        };
+        if let Some((socket, features)) = connected {
             let self_weak = self.self_weak.clone();
             let handler = move |core: &mut Core, poll: &Poll, child, res| {
                 if let Some(self_rc) = self_weak.upgrade() {
                     self_rc
                         .borrow_mut()
-                        .handle_connection_candidate(core, poll, child, res);
+                        .handle_connection_candidate(core, poll, child, res, features, direction);
                 }
             };
 
@@ -163,10 +338,13 @@ impl<UID: Uid> Connect<UID> {
                 self.cm.clone(),
                 self.our_id,
                 self.their_id,
+                self.children.handle(),
                 Box::new(handler),
             ) {
-                let _ = self.children.insert(child);
+                self.children.insert(child);
             }
+        } else if let Some(dialer) = self.dialer {
+            Dialer::<CandidateAddr>::hurry(core, poll, dialer);
         }
         self.maybe_terminate(core, poll);
     }
@@ -177,11 +355,21 @@ impl<UID: Uid> Connect<UID> {
         poll: &Poll,
         child: Token,
         res: Option<Socket>,
+        features: NegotiatedFeatures,
+        direction: Option<ConnectionDirection>,
     ) {
-        let _ = self.children.remove(&child);
+        let _ = self.children.remove(child);
         if let Some(socket) = res {
+            let redial = self.redial.take();
             self.terminate(core, poll);
-            return ActiveConnection::start(
+            let event = match redial {
+                Some(Redial::Unpark(_)) => Event::PeerUnparked(self.their_id),
+                Some(Redial::Reconnect) => Event::PeerReconnected(self.their_id),
+                Some(Redial::Upgrade) | None => {
+                    Event::ConnectSuccess(self.their_id, features.peer_key)
+                }
+            };
+            ActiveConnection::start(
                 core,
                 poll,
                 child,
@@ -191,63 +379,263 @@ impl<UID: Uid> Connect<UID> {
                 self.their_id,
                 // Note; We connect only to Nodes
                 CrustUser::Node,
-                Event::ConnectSuccess(self.their_id),
+                event,
                 self.event_tx.clone(),
+                self.settings.clone(),
+                features,
+                direction,
             );
+            match redial {
+                Some(Redial::Unpark(parked)) => self.finish_unpark(core, poll, child, &parked),
+                Some(Redial::Reconnect) => reconnect_over::<UID>(core, self.their_id, true),
+                Some(Redial::Upgrade) | None => (),
+            }
+            return;
         }
         self.maybe_terminate(core, poll);
     }
 
+    fn finish_unpark(&self, core: &mut Core, poll: &Poll, child: Token, parked: &ParkedPeers<UID>) {
+        let (stats, pending) = match unwrap!(parked.lock()).finish_unpark(&self.their_id) {
+            Some(res) => res,
+            None => return,
+        };
+        let state = match core.get_state(child) {
+            Some(state) => state,
+            None => return,
+        };
+
+        let mut state = state.borrow_mut();
+        if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+            active_connection.restore_stats(stats);
+        }
+        for (msg, priority) in pending {
+            state.write(core, poll, msg, priority);
+        }
+    }
+
     fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
-        if self.children.is_empty() {
-            self.terminate(core, poll);
+        if !self.children.is_empty() {
+            return;
+        }
+        // Every direct attempt failed, which leaves the relays.
+        if !self.relays.is_empty() {
+            match self.dial_relays(core, poll) {
+                Ok(()) => return,
+                Err(e) => debug!("Failed to dial the relays of {:?}: {:?}", self.their_id, e),
+            }
         }
+        self.terminate(core, poll);
+    }
+
+    /// Dials the relays the peer listed, once every direct attempt failed or `RELAY_FALLBACK_SECS`
+    /// passed without one succeeding. Each is asked for a pipe to the peer, which the handshake
+    /// is then made over.
+    fn dial_relays(&mut self, core: &mut Core, poll: &Poll) -> ::Res<()> {
+        if self.relays.is_empty() {
+            return Ok(());
+        }
+        let state = match self.self_weak.upgrade() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        debug!("Connecting to peer {:?} through its relays", self.their_id);
+        let targets = self
+            .relays
+            .drain(..)
+            .map(|candidate| DialTarget {
+                addr: candidate.addr(),
+                via: DialVia::Tcp,
+                context: candidate,
+            })
+            .collect();
+        let handler: Rc<RefCell<DialHandler<CandidateAddr>>> = state;
+        let dialer = Dialer::start(
+            core,
+            poll,
+            targets,
+            dial_settings(self.bind_ip),
+            self.children.handle(),
+            Rc::downgrade(&handler),
+        )?;
+        self.children.insert(dialer);
+        Ok(())
     }
 
     fn accept(&mut self, core: &mut Core, poll: &Poll) {
         loop {
             match unwrap!(self.listener.as_ref()).accept() {
-                Ok((socket, _)) => self.exchange_msg(core, poll, Socket::wrap(socket)),
-                Err(_) => return,
+                Ok((socket, addr)) => {
+                    let _ = self.exchange_msg(
+                        core,
+                        poll,
+                        Socket::wrap(socket),
+                        addr,
+                        HandshakeStage::HolePunching,
+                        false,
+                    );
+                }
+                Err(_) => break,
             }
         }
+        self.maybe_terminate(core, poll);
     }
 
-    fn terminate_children(&mut self, core: &mut Core, poll: &Poll) {
-        for child in self.children.drain() {
-            let child = match core.get_state(child) {
-                Some(state) => state,
-                None => continue,
-            };
+    /// Updates the health of `addr` in the bootstrap cache, in case it is cached.
+    fn record_attempt(
+        &self,
+        core: &mut Core,
+        addr: SocketAddr,
+        outcome: Result<Duration, ContactFailure>,
+    ) {
+        let update = CacheUpdate::Attempt(addr, outcome);
+        CacheWriter::send(core, &self.settings.bootstrap_cache_name, update);
+    }
+
+    /// Records in the peer's path history whether dialling a path of the given kind worked.
+    fn record_path(&self, core: &mut Core, kind: PathKind, succeeded: bool) {
+        let _ = PathHistory::with(core, &self.settings.bootstrap_cache_name, |history| {
+            history.record(&self.their_id, kind, succeeded)
+        });
+    }
+}
+
+impl<UID: Uid> DialHandler<CandidateAddr> for Connect<UID> {
+    fn established(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        candidate: CandidateAddr,
+        addr: SocketAddr,
+        socket: Socket,
+    ) {
+        let (stage, via_relay) = match candidate {
+            CandidateAddr::TcpMapped(_) => (HandshakeStage::HolePunching, false),
+            #[cfg(feature = "utp")]
+            CandidateAddr::Utp(_) => (HandshakeStage::UtpConnecting, false),
+            #[cfg(feature = "relay")]
+            CandidateAddr::Relay(_) => (HandshakeStage::RelayPairing, true),
+            _ => (HandshakeStage::TcpConnecting, false),
+        };
+        if let Some(child) = self.exchange_msg(core, poll, socket, addr, stage, via_relay) {
+            if is_tcp(&candidate) {
+                let _ = self.paths.insert(child, PathKind::of(&candidate));
+            }
+        }
+    }
 
-            child.borrow_mut().terminate(core, poll);
+    fn failed(
+        &mut self,
+        core: &mut Core,
+        _poll: &Poll,
+        candidate: CandidateAddr,
+        addr: SocketAddr,
+    ) {
+        match candidate {
+            // Peers aren't cached under their mapped addresses, nor by whether they answer uTP.
+            CandidateAddr::TcpMapped(_) | CandidateAddr::Utp(_) => (),
+            // A relay is a peer of ours, which our own connection to it keeps track of.
+            #[cfg(feature = "relay")]
+            CandidateAddr::Relay(_) => (),
+            _ => self.record_attempt(core, addr, Err(ContactFailure::Unreachable)),
+        }
+        if is_tcp(&candidate) {
+            self.record_path(core, PathKind::of(&candidate), false);
         }
     }
+
+    fn finished(&mut self, core: &mut Core, poll: &Poll) {
+        // The dialer of the relays may be the one done, while the direct one is still at it.
+        let children = self.children.handle();
+        if self.dialer.map_or(false, |dialer| !children.is_registered(dialer)) {
+            self.dialer = None;
+        }
+        self.maybe_terminate(core, poll);
+    }
+}
+
+/// The candidates are dialled `CONNECT_STAGGER_MS` apart, unless an attempt fails first.
+fn dial_settings(bind_ip: Option<IpAddr>) -> DialSettings {
+    DialSettings {
+        stagger: Duration::from_millis(CONNECT_STAGGER_MS),
+        attempt_timeout: None,
+        deadline: None,
+        max_parallel: usize::max_value(),
+        bind_ip,
+    }
+}
+
+/// Whether the candidate is a relay of the peer, which is only dialled once dialling the peer
+/// directly failed, see `Connect::dial_relays`.
+fn is_relay(candidate: &CandidateAddr) -> bool {
+    match *candidate {
+        #[cfg(feature = "relay")]
+        CandidateAddr::Relay(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether the candidate is dialled over TCP to the peer itself. The path history only tells
+/// apart paths of TCP.
+fn is_tcp(candidate: &CandidateAddr) -> bool {
+    match *candidate {
+        CandidateAddr::Utp(_) => false,
+        #[cfg(feature = "relay")]
+        CandidateAddr::Relay(_) => false,
+        _ => true,
+    }
 }
 
 impl<UID: Uid> State for Connect<UID> {
+    fn name(&self) -> &'static str {
+        "Connect"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if !kind.is_error() && !kind.is_hup() && kind.is_readable() {
             self.accept(core, poll);
         }
     }
 
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u64) {
+        if timer_id == RELAY_FALLBACK_TIMER_ID {
+            if let Err(e) = self.dial_relays(core, poll) {
+                debug!("Failed to dial the relays of {:?}: {:?}", self.their_id, e);
+            }
+            return;
+        }
         debug!("Connect to peer {:?} timed out", self.their_id);
         self.terminate(core, poll);
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        self.terminate_children(core, poll);
+        self.children.terminate_all(core, poll);
 
         if let Some(listener) = self.listener.take() {
             let _ = poll.deregister(&listener);
         }
-        let _ = core.cancel_timeout(&self.timeout);
         let _ = core.remove_state(self.token);
 
+        match self.redial {
+            Some(Redial::Reconnect) => {
+                // Retried later or given up on by `Reconnects`, which reports it then.
+                self.redial = None;
+                return reconnect_over::<UID>(core, self.their_id, false);
+            }
+            Some(Redial::Upgrade) => {
+                self.redial = None;
+                return upgrade_over::<UID>(core, &self.cm, self.their_id);
+            }
+            _ => (),
+        }
         if !unwrap!(self.cm.lock()).contains_key(&self.their_id) {
-            let _ = self.event_tx.send(Event::ConnectFailure(self.their_id));
+            if let Some(Redial::Unpark(parked)) = self.redial.take() {
+                unwrap!(parked.lock()).unpark_failed(&self.their_id);
+            }
+            if let Some(rejection) = self.rejection.take() {
+                self.event_tx.send(Event::ConnectRejected(self.their_id, rejection));
+            }
+            self.event_tx.send(Event::ConnectFailure(self.their_id));
         }
     }
 
@@ -255,3 +643,35 @@ impl<UID: Uid> State for Connect<UID> {
         self
     }
 }
+
+/// Tells `Reconnects` that an attempt at reconnecting to `peer` is over, in the next iteration of
+/// the event loop: an attempt which fails as it starts does so while `Reconnects` is still busy
+/// starting it.
+fn reconnect_over<UID: Uid>(core: &mut Core, peer: UID, reconnected: bool) {
+    if !core.has_state(RECONNECTS_TOKEN) {
+        return;
+    }
+    let res = core.post(move |core, _| {
+        let state = match core.get_state(RECONNECTS_TOKEN) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.borrow_mut();
+        if let Some(reconnects) = state.as_any().downcast_mut::<Reconnects<UID>>() {
+            reconnects.dial_over(core, &peer, reconnected);
+        }
+    });
+    if let Err(e) = res {
+        debug!("Failed to report reconnecting to {:?}: {:?}", peer, e);
+    }
+}
+
+/// Tells the connection to `peer` that dialling it to upgrade the connection failed, in the next
+/// iteration of the event loop, as the connection may be the one which started the attempt.
+fn upgrade_over<UID: Uid>(core: &mut Core, cm: &ConnectionMap<UID>, peer: UID) {
+    let cm = cm.clone();
+    let res = core.post(move |core, poll| upgrade_dial_over(core, poll, &cm, &peer));
+    if let Err(e) = res {
+        debug!("Failed to report upgrading the connection to {:?}: {:?}", peer, e);
+    }
+}
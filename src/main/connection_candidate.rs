@@ -7,8 +7,10 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::{Core, Message, Priority, Socket, State, Uid};
-use main::{ConnectionId, ConnectionMap};
+use common::{
+    ChildHandle, Core, HandshakeStage, Message, Priority, Socket, State, Uid, CONTROL_PRIORITY,
+};
+use main::{awaits_upgrade, ConnectionId, ConnectionMap};
 use mio::{Poll, PollOpt, Ready, Token};
 use std::any::Any;
 use std::cell::RefCell;
@@ -25,6 +27,7 @@ pub struct ConnectionCandidate<UID: Uid> {
     our_id: UID,
     their_id: UID,
     msg: Option<(Message<UID>, Priority)>,
+    parent: ChildHandle,
     finish: Finish,
 }
 
@@ -37,6 +40,7 @@ impl<UID: Uid> ConnectionCandidate<UID> {
         cm: ConnectionMap<UID>,
         our_id: UID,
         their_id: UID,
+        parent: ChildHandle,
         finish: Finish,
     ) -> ::Res<Token> {
         let state = Rc::new(RefCell::new(ConnectionCandidate {
@@ -45,11 +49,13 @@ impl<UID: Uid> ConnectionCandidate<UID> {
             socket,
             our_id,
             their_id,
-            msg: Some((Message::ChooseConnection, 0)),
+            msg: Some((Message::ChooseConnection, CONTROL_PRIORITY)),
+            parent,
             finish,
         }));
 
         let _ = core.insert_state(token, state.clone());
+        core.set_pending_stage(token, HandshakeStage::AwaitingChoice);
 
         if let Err(e) = poll.reregister(
             &state.borrow().socket,
@@ -73,13 +79,15 @@ impl<UID: Uid> ConnectionCandidate<UID> {
     }
 
     fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message<UID>, Priority)>) {
-        let terminate = match unwrap!(self.cm.lock()).get(&self.their_id) {
+        let existing = match unwrap!(self.cm.lock()).get(&self.their_id) {
             Some(&ConnectionId {
-                active_connection: Some(_),
+                active_connection: Some(existing),
                 ..
-            }) => true,
-            _ => false,
+            }) => Some(existing),
+            _ => None,
         };
+        // Unless the connection to the peer is to move onto this one, see `Upgrade`.
+        let terminate = existing.map_or(false, |existing| !awaits_upgrade::<UID>(core, existing));
         if terminate {
             return self.handle_error(core, poll);
         }
@@ -104,7 +112,7 @@ impl<UID: Uid> ConnectionCandidate<UID> {
     }
 
     fn done(&mut self, core: &mut Core, poll: &Poll) {
-        let _ = core.remove_state(self.token);
+        let _ = core.hand_over_state(self.token);
         let token = self.token;
         let socket = mem::replace(&mut self.socket, Socket::default());
 
@@ -119,6 +127,10 @@ impl<UID: Uid> ConnectionCandidate<UID> {
 }
 
 impl<UID: Uid> State for ConnectionCandidate<UID> {
+    fn name(&self) -> &'static str {
+        "ConnectionCandidate"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             return self.handle_error(core, poll);
@@ -133,6 +145,7 @@ impl<UID: Uid> State for ConnectionCandidate<UID> {
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.parent.deregister(self.token);
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
 
@@ -0,0 +1,305 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Text forms of `PubConnectionInfo` for exchanging it by hand: base64 to be pasted or put in a QR
+// code, and words to be read aloud or written down.
+
+use base64;
+use common::Uid;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use main::{ConnectionInfoTextError, PubConnectionInfo};
+
+/// Number of words at the end of the word form carrying its checksum.
+const CHECKSUM_WORDS: usize = 2;
+
+/// Connection info of a peer, in any of the forms it may be exchanged in.
+#[derive(Debug)]
+pub enum ConnectionInfoSource<UID> {
+    /// The info itself.
+    Info(PubConnectionInfo<UID>),
+    /// The info as given by `PubConnectionInfo::to_base64`.
+    Base64(String),
+    /// The info as given by `PubConnectionInfo::to_words`.
+    Words(String),
+}
+
+impl<UID: Uid> ConnectionInfoSource<UID> {
+    /// Decodes the connection info.
+    pub fn into_info(self) -> Result<PubConnectionInfo<UID>, ConnectionInfoTextError> {
+        match self {
+            ConnectionInfoSource::Info(info) => Ok(info),
+            ConnectionInfoSource::Base64(text) => PubConnectionInfo::from_base64(&text),
+            ConnectionInfoSource::Words(text) => PubConnectionInfo::from_words(&text),
+        }
+    }
+}
+
+impl<UID> From<PubConnectionInfo<UID>> for ConnectionInfoSource<UID> {
+    fn from(info: PubConnectionInfo<UID>) -> Self {
+        ConnectionInfoSource::Info(info)
+    }
+}
+
+impl<UID: Uid> PubConnectionInfo<UID> {
+    /// Encodes the info in compact, URL-safe base64.
+    pub fn to_base64(&self) -> ::Res<String> {
+        Ok(base64::encode_config(&serialise(self)?, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Decodes info given by `to_base64`.
+    pub fn from_base64(text: &str) -> Result<Self, ConnectionInfoTextError> {
+        let bytes = base64::decode_config(text.trim(), base64::URL_SAFE_NO_PAD)
+            .map_err(|_| ConnectionInfoTextError::InvalidBase64)?;
+        deserialise(&bytes).map_err(|_| ConnectionInfoTextError::Malformed)
+    }
+
+    /// Encodes the info as words, one per byte of its compact encoding followed by a checksum,
+    /// taken alternately from two lists. A word changed in transcription fails the checksum, and
+    /// two neighbours swapped each end up in the other list.
+    pub fn to_words(&self) -> ::Res<String> {
+        let mut bytes = serialise(self)?;
+        let checksum = crc16(&bytes);
+        bytes.push((checksum >> 8) as u8);
+        bytes.push(checksum as u8);
+        let words: Vec<&str> = bytes
+            .iter()
+            .enumerate()
+            .map(|(index, &byte)| word_list(index)[byte as usize])
+            .collect();
+        Ok(words.join(" "))
+    }
+
+    /// Decodes info given by `to_words`, regardless of case and of the spacing between words.
+    /// Errors give the position of the offending word, counting from 1.
+    pub fn from_words(text: &str) -> Result<Self, ConnectionInfoTextError> {
+        let mut bytes = Vec::new();
+        for (index, word) in text.split_whitespace().enumerate() {
+            let lower = word.to_lowercase();
+            match word_list(index).iter().position(|known| *known == lower) {
+                Some(byte) => bytes.push(byte as u8),
+                None if word_list(index + 1).contains(&&lower[..]) => {
+                    return Err(ConnectionInfoTextError::MisplacedWord(
+                        index + 1,
+                        word.to_string(),
+                    ))
+                }
+                None => {
+                    return Err(ConnectionInfoTextError::UnknownWord(
+                        index + 1,
+                        word.to_string(),
+                    ))
+                }
+            }
+        }
+        if bytes.len() <= CHECKSUM_WORDS {
+            return Err(ConnectionInfoTextError::TooFewWords(bytes.len()));
+        }
+        let len = bytes.len() - CHECKSUM_WORDS;
+        let checksum = u16::from(bytes[len]) << 8 | u16::from(bytes[len + 1]);
+        if crc16(&bytes[..len]) != checksum {
+            return Err(ConnectionInfoTextError::ChecksumMismatch);
+        }
+        deserialise(&bytes[..len]).map_err(|_| ConnectionInfoTextError::Malformed)
+    }
+}
+
+fn word_list(index: usize) -> &'static [&'static str] {
+    if index % 2 == 0 {
+        &EVEN_WORDS
+    } else {
+        &ODD_WORDS
+    }
+}
+
+/// CRC-16/CCITT-FALSE, which detects any single byte changed and any two neighbours swapped.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Words encoding the bytes at even positions.
+static EVEN_WORDS: [&str; 256] = [
+    "aardvark", "absurd", "accrue", "acme", "adrift", "adult", "afflict", "ahead", "aimless",
+    "algol", "allow", "alone", "ammo", "ancient", "apple", "artist", "assume", "athens", "atlas",
+    "aztec", "baboon", "backfield", "backward", "banjo", "beaming", "bedlamp", "beehive", "beeswax",
+    "befriend", "belfast", "berserk", "billiard", "bison", "blackjack", "blockade", "blowtorch",
+    "bluebird", "bombast", "bookshelf", "brackish", "breadline", "breakup", "brickyard",
+    "briefcase", "burbank", "button", "buzzard", "cement", "chairlift", "chatter", "checkup",
+    "chisel", "choking", "chopper", "christmas", "clamshell", "classic", "classroom", "cleanup",
+    "clockwork", "cobra", "commence", "concert", "cowbell", "crackdown", "cranky", "crowfoot",
+    "crucial", "crumpled", "crusade", "cubic", "dashboard", "deadbolt", "deckhand", "dogsled",
+    "dragnet", "drainage", "dreadful", "drifter", "dropper", "drumbeat", "drunken", "dupont",
+    "dwelling", "eating", "edict", "egghead", "eightball", "endorse", "endow", "enlist", "erase",
+    "escape", "exceed", "eyeglass", "eyetooth", "facial", "fallout", "flagpole", "flatfoot",
+    "flytrap", "fracture", "framework", "freedom", "frighten", "gazelle", "geiger", "glitter",
+    "glucose", "goggles", "goldfish", "gremlin", "guidance", "hamlet", "highchair", "hockey",
+    "indoors", "indulge", "inverse", "involve", "island", "jawbone", "keyboard", "kickoff", "kiwi",
+    "klaxon", "locale", "lockup", "merit", "minnow", "miser", "mohawk", "mural", "music",
+    "necklace", "neptune", "newborn", "nightbird", "oakland", "obtuse", "offload", "optic", "orca",
+    "payday", "peachy", "pheasant", "physique", "playhouse", "pluto", "preclude", "prefer",
+    "preshrunk", "printer", "prowler", "pupil", "puppy", "python", "quadrant", "quiver", "quota",
+    "ragtime", "ratchet", "rebirth", "reform", "regain", "reindeer", "rematch", "repay", "retouch",
+    "revenge", "reward", "rhythm", "ribcage", "ringbolt", "robust", "rocker", "ruffled", "sailboat",
+    "sawdust", "scallion", "scenic", "scorecard", "scotland", "seabird", "select", "sentence",
+    "shadow", "shamrock", "showgirl", "skullcap", "skydive", "slingshot", "slowdown", "snapline",
+    "snapshot", "snowcap", "snowslide", "solo", "southward", "soybean", "spaniel", "spearhead",
+    "spellbind", "spheroid", "spigot", "spindle", "spyglass", "stagehand", "stagnate", "stairway",
+    "standard", "stapler", "steamship", "sterling", "stockman", "stopwatch", "stormy", "sugar",
+    "surmount", "suspense", "sweatband", "swelter", "tactics", "talon", "tapeworm", "tempest",
+    "tiger", "tissue", "tonic", "topmost", "tracker", "transit", "trauma", "treadmill", "trojan",
+    "trouble", "tumor", "tunnel", "tycoon", "uncut", "unearth", "unwind", "uproot", "upset",
+    "upshot", "vapor", "village", "virus", "vulcan", "waffle", "wallet", "watchword", "wayside",
+    "willow", "woodlark", "zulu",
+];
+
+/// Words encoding the bytes at odd positions. They have three syllables where the even ones have
+/// two, so that a word read out of place stands out.
+static ODD_WORDS: [&str; 256] = [
+    "adroitness", "adviser", "aftermath", "aggregate", "alkali", "almighty", "amulet", "amusement",
+    "antenna", "applicant", "apollo", "armistice", "article", "asteroid", "atlantic", "atmosphere",
+    "autopsy", "babylon", "backwater", "barbecue", "belowground", "bifocals", "bodyguard",
+    "bookseller", "borderline", "bottomless", "bradbury", "bravado", "brazilian", "breakaway",
+    "burlington", "businessman", "butterfat", "camelot", "candidate", "cannonball", "capricorn",
+    "caravan", "caretaker", "celebrate", "cellulose", "certify", "chambermaid", "cherokee",
+    "chicago", "clergyman", "coherence", "combustion", "commando", "company", "component",
+    "concurrent", "confidence", "conformist", "congregate", "consensus", "consulting", "corporate",
+    "corrosion", "councilman", "crossover", "crucifix", "cumbersome", "customer", "dakota",
+    "decadence", "december", "decimal", "designing", "detector", "detergent", "determine",
+    "dictator", "dinosaur", "direction", "disable", "disbelief", "disruptive", "distortion",
+    "document", "embezzle", "enchanting", "enrollment", "enterprise", "equation", "equipment",
+    "escapade", "eskimo", "everyday", "examine", "existence", "exodus", "fascinate", "filament",
+    "finicky", "forever", "fortitude", "frequency", "gadgetry", "galveston", "getaway", "glossary",
+    "gossamer", "graduate", "gravity", "guitarist", "hamburger", "hamilton", "handiwork",
+    "hazardous", "headwaters", "hemisphere", "hesitate", "hideaway", "holiness", "hurricane",
+    "hydraulic", "impartial", "impetus", "inception", "indigo", "inertia", "infancy", "inferno",
+    "informant", "insincere", "insurgent", "integrate", "intention", "inventive", "istanbul",
+    "jamaica", "jupiter", "leprosy", "letterhead", "liberty", "maritime", "matchmaker", "maverick",
+    "medusa", "megaton", "microscope", "microwave", "midsummer", "millionaire", "miracle",
+    "misnomer", "molasses", "molecule", "montana", "monument", "mosquito", "narrative", "nebula",
+    "newsletter", "norwegian", "october", "ohio", "onlooker", "opulent", "orlando", "outfielder",
+    "pacific", "pandemic", "pandora", "paperweight", "paragon", "paragraph", "paramount",
+    "passenger", "pedigree", "pegasus", "penetrate", "perceptive", "performance", "pharmacy",
+    "phonetic", "photograph", "pioneer", "pocketful", "politeness", "positive", "potato",
+    "processor", "provincial", "proximate", "puberty", "publisher", "pyramid", "quantity",
+    "racketeer", "rebellion", "recipe", "recover", "repellent", "replica", "reproduce", "resistor",
+    "responsive", "retraction", "retrieval", "retrospect", "revenue", "revival", "revolver",
+    "sandalwood", "sardonic", "saturday", "savagery", "scavenger", "sensation", "sociable",
+    "souvenir", "specialist", "speculate", "stethoscope", "stupendous", "supportive", "surrender",
+    "suspicious", "sympathy", "tambourine", "telephone", "therapist", "tobacco", "tolerance",
+    "tomorrow", "torpedo", "tradition", "travesty", "trombonist", "truncated", "typewriter",
+    "ultimate", "undaunted", "underfoot", "unicorn", "unify", "universe", "unravel", "upcoming",
+    "vacancy", "vagabond", "vertigo", "virginia", "visitor", "vocalist", "voyager", "warranty",
+    "waterloo", "whimsical", "wichita", "wilmington", "wyoming", "yesteryear", "yucatan",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use main::CandidateAddr;
+    use rand;
+    use tests::UniqueId;
+
+    fn info() -> PubConnectionInfo<UniqueId> {
+        PubConnectionInfo {
+            id: rand::random(),
+            candidates: vec![
+                CandidateAddr::TcpDirect(unwrap!("10.0.0.1:5483".parse())),
+                CandidateAddr::TcpMapped(unwrap!("203.0.113.7:41000".parse())),
+            ],
+            issued_at: Some(1_500_000_000),
+            ttl_secs: Some(600),
+        }
+    }
+
+    fn assert_same(decoded: &PubConnectionInfo<UniqueId>, info: &PubConnectionInfo<UniqueId>) {
+        assert_eq!(decoded.id, info.id);
+        assert_eq!(decoded.candidates, info.candidates);
+        assert_eq!(decoded.issued_at, info.issued_at);
+        assert_eq!(decoded.ttl_secs, info.ttl_secs);
+    }
+
+    #[test]
+    fn text_forms_round_trip() {
+        let info = info();
+        let base64 = unwrap!(info.to_base64());
+        assert_same(&unwrap!(PubConnectionInfo::from_base64(&base64)), &info);
+        let words = unwrap!(info.to_words());
+        assert_same(&unwrap!(PubConnectionInfo::from_words(&words)), &info);
+
+        // Case and spacing don't matter when words are transcribed.
+        let transcribed = words.to_uppercase().replace(' ', "\n  ");
+        assert_same(&unwrap!(PubConnectionInfo::from_words(&transcribed)), &info);
+
+        for source in vec![
+            ConnectionInfoSource::Base64(base64),
+            ConnectionInfoSource::Words(words),
+            ConnectionInfoSource::from(info()),
+        ] {
+            let _ = unwrap!(source.into_info());
+        }
+        assert_eq!(
+            unwrap!(PubConnectionInfo::<UniqueId>::from_base64("not base64!").err()),
+            ConnectionInfoTextError::InvalidBase64
+        );
+    }
+
+    fn words_error(words: &[&str]) -> ConnectionInfoTextError {
+        unwrap!(PubConnectionInfo::<UniqueId>::from_words(&words.join(" ")).err())
+    }
+
+    #[test]
+    fn corrupt_words_are_detected() {
+        let words = unwrap!(info().to_words());
+        let words: Vec<&str> = words.split(' ').collect();
+
+        // Any word changed for another of its list fails the checksum.
+        for index in 0..words.len() {
+            let mut corrupt = words.clone();
+            let byte = unwrap!(word_list(index).iter().position(|word| *word == words[index]));
+            corrupt[index] = word_list(index)[(byte + 1) % 256];
+            assert_eq!(words_error(&corrupt), ConnectionInfoTextError::ChecksumMismatch);
+        }
+
+        let mut corrupt = words.clone();
+        corrupt[6] = "xyzzy";
+        let error = words_error(&corrupt);
+        assert_eq!(error, ConnectionInfoTextError::UnknownWord(7, "xyzzy".to_string()));
+        assert_eq!(format!("{}", error), "word 7 (\"xyzzy\") not in word list");
+
+        assert_eq!(
+            words_error(&words[..CHECKSUM_WORDS]),
+            ConnectionInfoTextError::TooFewWords(CHECKSUM_WORDS)
+        );
+    }
+
+    #[test]
+    fn swapped_words_are_detected() {
+        let words = unwrap!(info().to_words());
+        let words: Vec<&str> = words.split(' ').collect();
+
+        for index in 0..words.len() - 1 {
+            let mut swapped = words.clone();
+            swapped.swap(index, index + 1);
+            assert_eq!(
+                words_error(&swapped),
+                ConnectionInfoTextError::MisplacedWord(index + 1, words[index + 1].to_string())
+            );
+        }
+    }
+}
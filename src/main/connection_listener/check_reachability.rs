@@ -8,7 +8,6 @@
 // Software.
 
 use common::{Core, CoreTimer, Socket, State};
-use mio::timer::Timeout;
 use mio::{Poll, PollOpt, Ready, Token};
 use std::any::Any;
 use std::cell::RefCell;
@@ -23,7 +22,6 @@ pub type Finish<T> = Box<FnMut(&mut Core, &Poll, Token, Result<T, ()>)>;
 pub struct CheckReachability<T> {
     token: Token,
     socket: Socket,
-    timeout: Timeout,
     finish: Finish<T>,
     t: T,
 }
@@ -49,7 +47,7 @@ where
             PollOpt::edge(),
         )?;
 
-        let timeout = core.set_timeout(
+        core.set_timeout(
             Duration::from_secs(CHECK_REACHABILITY_TIMEOUT_SEC),
             CoreTimer::new(token, 0),
         )?;
@@ -57,7 +55,6 @@ where
         let state = CheckReachability {
             token,
             socket,
-            timeout,
             finish,
             t,
         };
@@ -85,6 +82,10 @@ impl<T> State for CheckReachability<T>
 where
     T: 'static + Clone,
 {
+    fn name(&self) -> &'static str {
+        "CheckReachability"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() || !kind.is_writable() {
             self.handle_error(core, poll);
@@ -94,12 +95,11 @@ where
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        let _ = core.cancel_timeout(&self.timeout);
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
     }
 
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
         trace!(
             "Bootstrapper's external reachability check timed out to one of its given IP's. \
              Erroring out for this remote endpoint."
@@ -8,15 +8,24 @@
 // Software.
 
 use super::check_reachability::CheckReachability;
+use super::handshake::{decode_handshake_request, HandshakeRequest};
 use common::{
-    BootstrapDenyReason, Core, CoreTimer, CrustUser, ExternalReachability, Message, NameHash,
-    Priority, Socket, State, Uid,
+    self, answer_extensions, AuditEvent, AuditRecord, BootstrapDenyReason, ChildHandle,
+    ConnectionDirection, ContactUpdateExtension, Core, CoreTimer, CorrelationExtension, CrustUser,
+    EncryptionExtension, Extensions, ExternalReachability, HandshakeStage, Message, NameHash,
+    NegotiatedFeatures, NetworkId, NetworkKey, NetworkNonce, PowChallenge, PowExtension, Priority,
+    ProbeExtension, PublicKey, RecordedEventKind, Rejection, RejectionCode, RelayExtension,
+    RetirementExtension, RoleExtension, SessionKeys, Socket, State, TimestampExtension, Uid,
+    UpgradeExtension, CONTROL_PRIORITY,
 };
 use main::{
-    read_config_file, ActiveConnection, ConnectionCandidate, ConnectionId, ConnectionMap,
-    CrustConfig, Event,
+    read_config, ActiveConnection, ConnectionCandidate, ConnectionId, ConnectionMap,
+    ConnectionSettings, CrustConfig, Event, EventSink,
 };
-use mio::timer::Timeout;
+#[cfg(feature = "relay")]
+use common::KeyChallenge;
+#[cfg(feature = "relay")]
+use main::{RelayState, RELAY_TOKEN};
 use mio::{Poll, PollOpt, Ready, Token};
 use nat::ip_addr_is_global;
 use std::any::Any;
@@ -24,27 +33,120 @@ use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::mem;
+#[cfg(feature = "relay")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
 use std::time::Duration;
 
 pub const EXCHANGE_MSG_TIMEOUT_SEC: u64 = 10 * 60;
+/// How long peers are told to wait before bootstrapping off us again while we don't take
+/// bootstraps or have as many peers of their kind as we take.
+const FULL_RETRY_AFTER_SECS: u64 = 60;
+
+/// Called once the handshake is over, whichever way it ended, to free its handshake slot.
+pub type Finish = Box<FnMut(&mut Core, &Poll)>;
 
 pub struct ExchangeMsg<UID: Uid> {
     token: Token,
     cm: ConnectionMap<UID>,
     config: CrustConfig,
-    event_tx: ::CrustEventSender<UID>,
-    name_hash: NameHash,
+    event_tx: EventSink<UID>,
+    network: NetworkId,
     next_state: NextState<UID>,
     our_uid: UID,
     socket: Socket,
-    timeout: Timeout,
+    /// For the audit log, as the socket can't tell any more once the connection is lost.
+    peer_addr: Option<SocketAddr>,
     reachability_children: HashSet<Token>,
     accept_bootstrap: bool,
+    /// The peers to send bootstrapping peers to instead, once we are retiring.
+    retiring: Option<Vec<SocketAddr>>,
     require_reachability: bool,
+    require_pow: Option<u8>,
+    timestamp_frames: bool,
+    /// Whether peers are told we relay for them, see `Config::relay`.
+    relay: bool,
+    pending_pow: Option<PendingPow<UID>>,
+    pending_network: Option<PendingNetwork<UID>>,
+    #[cfg(feature = "relay")]
+    pending_relay: Option<PendingRelay<UID>>,
+    /// On a private network, the nonce of the challenge the peer proved to be on it with.
+    proven_challenge: Option<NetworkNonce>,
+    /// Our answers to the extensions the peer offered, to be sent along with our acceptance.
+    /// `None` for peers which offer none.
+    answers: Option<Extensions>,
+    features: NegotiatedFeatures,
+    /// Set for peers which sent a request without extensions, and so may predate
+    /// `Message::Rejection`. They are refused the way they were before it.
+    legacy: Option<LegacyRequest>,
+    /// The kind of the peer once taken within its limit, counted in the handshakes of the stats
+    /// of the event loop until it is connected or the handshake fails.
+    reserved: Option<CrustUser>,
+    /// Whether we have keys, and so refuse peers which don't agree on encryption.
+    require_encryption: bool,
+    /// The keys agreed on with the peer, taken up once our acceptance has been written.
+    session: Option<SessionKeys>,
+    /// The key of the peer once the keys are taken up, until its `Message::KeyConfirmation`
+    /// opens.
+    confirming: Option<PublicKey>,
+    finish: Option<Finish>,
     self_weak: Weak<RefCell<ExchangeMsg<UID>>>,
 }
 
+/// A bootstrap request put on hold until the client solves the challenge we sent it.
+struct PendingPow<UID> {
+    challenge: PowChallenge,
+    difficulty: u8,
+    their_uid: UID,
+    name_hash: NameHash,
+    ext_reachability: ExternalReachability,
+}
+
+/// A handshake request put on hold until the peer proves to be on our private network.
+struct PendingNetwork<UID> {
+    request: HandshakeRequest<UID>,
+    /// Sent by the peer in place of the name hash of its request.
+    request_nonce: NetworkNonce,
+    challenge: NetworkNonce,
+}
+
+/// A relay request put on hold until the peer proves it holds the key of the peer it claims to be.
+#[cfg(feature = "relay")]
+struct PendingRelay<UID> {
+    their_uid: UID,
+    peer_uid: UID,
+    /// The key the peer it claims to be authenticated its connection to us with.
+    their_key: PublicKey,
+    challenge: KeyChallenge,
+}
+
+/// A request of a peer which may predate `Message::Rejection`, see `ExchangeMsg::legacy`.
+#[derive(Clone, Copy)]
+enum LegacyRequest {
+    Bootstrap(CrustUser),
+    Connect,
+}
+
+impl LegacyRequest {
+    /// What such a peer is sent in place of a rejection of `code`. `None` if it is to be closed
+    /// on without a word.
+    fn denial(self, code: RejectionCode) -> Option<BootstrapDenyReason> {
+        match (self, code) {
+            (LegacyRequest::Bootstrap(_), RejectionCode::WrongNetwork) => {
+                Some(BootstrapDenyReason::InvalidNameHash)
+            }
+            (LegacyRequest::Bootstrap(CrustUser::Node), RejectionCode::NotWhitelisted) => {
+                Some(BootstrapDenyReason::NodeNotWhitelisted)
+            }
+            (LegacyRequest::Bootstrap(CrustUser::Client), RejectionCode::NotWhitelisted) => {
+                Some(BootstrapDenyReason::ClientNotWhitelisted)
+            }
+            _ => None,
+        }
+    }
+}
+
 impl<UID: Uid> ExchangeMsg<UID> {
     pub fn start(
         core: &mut Core,
@@ -52,91 +154,390 @@ impl<UID: Uid> ExchangeMsg<UID> {
         timeout_sec: Option<u64>,
         socket: Socket,
         accept_bootstrap: bool,
+        retiring: Option<Vec<SocketAddr>>,
         our_uid: UID,
-        name_hash: NameHash,
+        network: NetworkId,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
-        event_tx: ::CrustEventSender<UID>,
+        event_tx: EventSink<UID>,
+        finish: Finish,
     ) -> ::Res<()> {
         let token = core.get_new_token();
 
         let kind = Ready::error() | Ready::hup() | Ready::readable();
         poll.register(&socket, token, kind, PollOpt::edge())?;
 
-        let timeout = core.set_timeout(
+        core.set_timeout(
             Duration::from_secs(timeout_sec.unwrap_or(EXCHANGE_MSG_TIMEOUT_SEC)),
             CoreTimer::new(token, 0),
         )?;
 
         // Cache the reachability requirement config option, to make sure that it won't be updated
-        // with the rest of the configuration.
-        let require_reachability = unwrap!(config.lock())
-            .cfg
-            .dev
-            .as_ref()
-            .map_or(true, |dev_cfg| {
+        // with the rest of the configuration. Peers on a LAN-only network have no external address
+        // to be reached at.
+        let require_reachability = {
+            let guard = unwrap!(config.lock());
+            !guard.cfg.lan_only && guard.cfg.dev.as_ref().map_or(true, |dev_cfg| {
                 !dev_cfg.disable_external_reachability_requirement
-            });
+            })
+        };
+        let require_pow = unwrap!(config.lock()).cfg.require_pow;
+        let timestamp_frames = unwrap!(config.lock()).cfg.timestamp_frames;
+        let relay = unwrap!(config.lock()).cfg.relay.is_some();
+        let peer_addr = socket.peer_addr().ok();
 
         let state = Rc::new(RefCell::new(Self {
             token,
             cm,
             config,
             event_tx,
-            name_hash,
+            network,
             next_state: NextState::None,
             our_uid,
             socket,
-            timeout,
+            peer_addr,
             reachability_children: HashSet::with_capacity(4),
             accept_bootstrap,
+            retiring,
             require_reachability,
+            require_pow,
+            timestamp_frames,
+            relay,
+            pending_pow: None,
+            pending_network: None,
+            #[cfg(feature = "relay")]
+            pending_relay: None,
+            proven_challenge: None,
+            answers: None,
+            legacy: None,
+            reserved: None,
+            features: NegotiatedFeatures::default(),
+            require_encryption: core.keys().is_some(),
+            session: None,
+            confirming: None,
+            finish: Some(finish),
             self_weak: Default::default(),
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
 
+        if let Some(addr) = peer_addr {
+            core.add_pending(
+                token,
+                addr,
+                ConnectionDirection::Inbound,
+                HandshakeStage::AwaitingHandshake,
+            );
+        }
         let _ = core.insert_state(token, state);
+        core.record(token, RecordedEventKind::Accepted);
+        core.audit(|| AuditRecord::new(AuditEvent::Accepted, peer_addr));
 
         Ok(())
     }
 
     fn read(&mut self, core: &mut Core, poll: &Poll) {
-        match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::BootstrapRequest(their_uid, name_hash, ext_reachability))) => {
-                if !self.accept_bootstrap {
-                    trace!("Bootstrapping off us is not allowed");
-                    return self.terminate(core, poll);
-                }
+        let frame = match self.socket.read_frame() {
+            Ok(Some((frame, _))) => frame,
+            Ok(None) => return,
+            Err(e) => {
+                trace!("Failed to read from socket: {:?}", e);
+                return self.terminate(core, poll);
+            }
+        };
+
+        if let Some(peer_key) = self.confirming.take() {
+            return self.handle_key_confirmation(core, poll, peer_key, &frame);
+        }
+        if let Some(pending_pow) = self.pending_pow.take() {
+            return self.handle_pow_solution(core, poll, pending_pow, &frame);
+        }
+        if let Some(pending_network) = self.pending_network.take() {
+            return self.handle_network_proof(core, poll, pending_network, &frame);
+        }
+        #[cfg(feature = "relay")]
+        {
+            if let Some(pending_relay) = self.pending_relay.take() {
+                return self.handle_relay_proof(core, poll, pending_relay, &frame);
+            }
+        }
 
+        let request = match decode_handshake_request(&frame) {
+            Ok(request) => request,
+            Err(e) => {
+                trace!("Invalid handshake request: {:?}", e);
+                return self.terminate(core, poll);
+            }
+        };
+        match (self.network, request.name_hash()) {
+            (NetworkId::Keyed(key), Some(request_nonce)) => {
+                self.send_network_challenge(core, poll, &key, request, request_nonce)
+            }
+            _ => self.handle_request(core, poll, request),
+        }
+    }
+
+    fn handle_request(&mut self, core: &mut Core, poll: &Poll, request: HandshakeRequest<UID>) {
+        match request {
+            HandshakeRequest::Bootstrap(their_uid, name_hash, ext_reachability) => {
+                // Peers which proved to be on our private network know of rejections.
+                if self.proven_challenge.is_none() {
+                    let peer_kind = match ext_reachability {
+                        ExternalReachability::Required { .. } => CrustUser::Node,
+                        ExternalReachability::NotRequired => CrustUser::Client,
+                    };
+                    self.legacy = Some(LegacyRequest::Bootstrap(peer_kind));
+                }
+                self.handle_bootstrap(core, poll, their_uid, name_hash, ext_reachability, true)
+            }
+            HandshakeRequest::ExtBootstrap(their_uid, name_hash, offers) => {
+                let mut role = RoleExtension::answering();
+                let mut pow = PowExtension::answering(self.require_pow);
+                let mut timestamps = TimestampExtension::new(self.timestamp_frames);
+                let mut encryption = EncryptionExtension::new(core.keys());
+                let (answers, features) = answer_extensions(
+                    &mut [
+                        &mut role,
+                        &mut pow,
+                        &mut timestamps,
+                        &mut CorrelationExtension,
+                        &mut RetirementExtension,
+                        &mut ProbeExtension,
+                        &mut ContactUpdateExtension,
+                        &mut UpgradeExtension,
+                        &mut encryption,
+                        &mut RelayExtension::new(self.relays()),
+                    ],
+                    &offers,
+                );
+                self.answers = Some(answers);
+                self.features = features;
+                self.session = encryption.take_session();
+                let can_solve_pow = !pow.peer_falls_short();
+                let ext_reachability = role.into_reachability();
+                self.handle_bootstrap(
+                    core,
+                    poll,
+                    their_uid,
+                    name_hash,
+                    ext_reachability,
+                    can_solve_pow,
+                )
+            }
+            HandshakeRequest::Connect(their_uid, name_hash, offers) => {
+                if offers.is_none() && self.proven_challenge.is_none() {
+                    self.legacy = Some(LegacyRequest::Connect);
+                }
                 match self.validate_peer_uid(their_uid) {
-                    Ok(their_uid) => self.handle_bootstrap_req(
+                    Ok(their_uid) => self.handle_connect(core, poll, their_uid, name_hash, offers),
+                    Err(()) => self.terminate(core, poll),
+                }
+            }
+            HandshakeRequest::EchoAddr => self.handle_echo_addr_req(core, poll),
+            #[cfg(feature = "relay")]
+            HandshakeRequest::Relay(their_uid, name_hash, peer_uid) => {
+                self.handle_relay(core, poll, their_uid, name_hash, peer_uid)
+            }
+            #[cfg(not(feature = "relay"))]
+            HandshakeRequest::Relay(..) => {
+                self.reject(core, poll, RejectionCode::NotRelaying, None, "We don't relay")
+            }
+        }
+    }
+
+    /// Challenges the peer to prove it is on our private network before handling its request.
+    fn send_network_challenge(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        key: &NetworkKey,
+        request: HandshakeRequest<UID>,
+        request_nonce: NetworkNonce,
+    ) {
+        let challenge = common::new_network_nonce();
+        let proof = key.accepting_proof(&request_nonce, &challenge);
+        self.pending_network = Some(PendingNetwork {
+            request,
+            request_nonce,
+            challenge,
+        });
+        let msg = Message::NetworkChallenge(challenge, proof);
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    fn handle_network_proof(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        pending_network: PendingNetwork<UID>,
+        frame: &[u8],
+    ) {
+        let PendingNetwork {
+            request,
+            request_nonce,
+            challenge,
+        } = pending_network;
+        let expected = match self.network {
+            NetworkId::Keyed(ref key) => key.connecting_proof(&request_nonce, &challenge),
+            NetworkId::Plain(_) => return self.terminate(core, poll),
+        };
+
+        match common::decode_message::<Message<UID>>(frame) {
+            Ok(Message::NetworkProof(ref proof)) if common::proofs_match(&expected, proof) => {
+                self.proven_challenge = Some(challenge);
+                self.handle_request(core, poll, request)
+            }
+            Ok(message) => {
+                trace!("Peer answered our network challenge with {:?}", message);
+                self.reject(
+                    core,
+                    poll,
+                    RejectionCode::WrongNetwork,
+                    None,
+                    "Peer failed to prove it is on our network",
+                )
+            }
+            Err(e) => {
+                trace!("Invalid network proof: {:?}", e);
+                self.terminate(core, poll)
+            }
+        }
+    }
+
+    /// Handles a bootstrap request, challenging the peer first if we require a proof of work. Peers
+    /// which told us they can't solve the difficulty we require are denied right away.
+    fn handle_bootstrap(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        their_uid: UID,
+        name_hash: NameHash,
+        ext_reachability: ExternalReachability,
+        can_solve_pow: bool,
+    ) {
+        if let Some(alternatives) = self.retiring.take() {
+            // Only peers on our network are told of the others.
+            if !self.is_valid_name_hash(name_hash) {
+                return self.reject(
+                    core,
+                    poll,
+                    RejectionCode::WrongNetwork,
+                    None,
+                    "Bootstrapper has an invalid name hash",
+                );
+            }
+            return self.refuse_retiring(core, poll, alternatives);
+        }
+        if !self.accept_bootstrap {
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::Full,
+                Some(FULL_RETRY_AFTER_SECS),
+                "Bootstrapping off us is not allowed",
+            );
+        }
+        if self.require_encryption && self.session.is_none() {
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::EncryptionRequired,
+                None,
+                "Bootstrapper didn't agree on encryption",
+            );
+        }
+
+        let their_uid = match self.validate_peer_uid(their_uid) {
+            Ok(their_uid) => their_uid,
+            Err(()) => return self.terminate(core, poll),
+        };
+        match self.require_pow {
+            Some(difficulty) if !can_solve_pow => {
+                trace!("Bootstrapper can't solve our proof of work. Denying bootstrap.");
+                let reason = BootstrapDenyReason::PowRequired(difficulty);
+                self.write(core, poll, Some((Message::BootstrapDenied(reason), CONTROL_PRIORITY)))
+            }
+            Some(difficulty) => self.send_pow_challenge(
+                core,
+                poll,
+                PendingPow {
+                    challenge: common::new_pow_challenge(),
+                    difficulty,
+                    their_uid,
+                    name_hash,
+                    ext_reachability,
+                },
+            ),
+            None => self.handle_bootstrap_req(core, poll, their_uid, name_hash, ext_reachability),
+        }
+    }
+
+    /// Refuses a bootstrap request as we are retiring, passing the peers to try instead to those
+    /// which can take them.
+    fn refuse_retiring(&mut self, core: &mut Core, poll: &Poll, alternatives: Vec<SocketAddr>) {
+        if !self.features.retirement {
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::Retiring,
+                None,
+                "We are retiring, bootstrap off another peer",
+            );
+        }
+        trace!("We are retiring. Referring bootstrapper to {:?}.", alternatives);
+        self.next_state = NextState::None;
+        self.write(core, poll, Some((Message::Retiring(alternatives), CONTROL_PRIORITY)));
+    }
+
+    fn send_pow_challenge(&mut self, core: &mut Core, poll: &Poll, pending_pow: PendingPow<UID>) {
+        let msg = Message::PowChallenge(pending_pow.challenge, pending_pow.difficulty);
+        self.pending_pow = Some(pending_pow);
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    fn handle_pow_solution(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        pending_pow: PendingPow<UID>,
+        frame: &[u8],
+    ) {
+        let PendingPow {
+            challenge,
+            difficulty,
+            their_uid,
+            name_hash,
+            ext_reachability,
+        } = pending_pow;
+
+        let reason = match common::decode_message::<Message<UID>>(frame) {
+            Ok(Message::PowSolution(nonce)) => {
+                if common::is_valid_pow(&challenge, difficulty, nonce) {
+                    return self.handle_bootstrap_req(
                         core,
                         poll,
                         their_uid,
                         name_hash,
                         ext_reachability,
-                    ),
-                    Err(()) => self.terminate(core, poll),
-                }
-            }
-            Ok(Some(Message::Connect(their_uid, name_hash))) => {
-                match self.validate_peer_uid(their_uid) {
-                    Ok(their_uid) => self.handle_connect(core, poll, their_uid, name_hash),
-                    Err(()) => self.terminate(core, poll),
+                    );
                 }
+                trace!("Bootstrapper sent an invalid proof of work. Denying bootstrap.");
+                BootstrapDenyReason::InvalidPow
             }
-            Ok(Some(Message::EchoAddrReq)) => self.handle_echo_addr_req(core, poll),
-            Ok(Some(message)) => {
-                trace!("Unexpected message in direct connect: {:?}", message);
-                self.terminate(core, poll)
+            Ok(message) => {
+                trace!(
+                    "Bootstrapper answered our proof of work challenge with {:?}. Denying \
+                     bootstrap.",
+                    message
+                );
+                BootstrapDenyReason::PowRequired(difficulty)
             }
-            Ok(None) => (),
             Err(e) => {
-                trace!("Failed to read from socket: {:?}", e);
-                self.terminate(core, poll);
+                trace!("Invalid proof of work solution: {:?}", e);
+                return self.terminate(core, poll);
             }
-        }
+        };
+        self.write(core, poll, Some((Message::BootstrapDenied(reason), CONTROL_PRIORITY)));
     }
 
     fn handle_bootstrap_req(
@@ -148,25 +549,37 @@ impl<UID: Uid> ExchangeMsg<UID> {
         ext_reachability: ExternalReachability,
     ) {
         if !self.is_valid_name_hash(name_hash) {
-            trace!("Rejecting Bootstrapper with an invalid name hash.");
-            return self.write(
+            return self.reject(
                 core,
                 poll,
-                Some((
-                    Message::BootstrapDenied(BootstrapDenyReason::InvalidNameHash),
-                    0,
-                )),
+                RejectionCode::WrongNetwork,
+                None,
+                "Bootstrapper has an invalid name hash",
             );
         }
 
         self.try_update_crust_config();
 
+        if self.is_peer_banned(core) {
+            return self.refuse(
+                core,
+                poll,
+                their_uid,
+                RejectionCode::Banned,
+                "Bootstrapper is banned",
+            );
+        }
+
         match ext_reachability {
             ExternalReachability::Required { direct_listeners } => {
                 if !self.is_peer_whitelisted(CrustUser::Node) {
-                    trace!("Bootstrapper Node is not whitelisted. Denying bootstrap.");
-                    let reason = BootstrapDenyReason::NodeNotWhitelisted;
-                    return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                    return self.refuse(
+                        core,
+                        poll,
+                        their_uid,
+                        RejectionCode::NotWhitelisted,
+                        "Bootstrapper Node is not whitelisted",
+                    );
                 }
 
                 if !self.require_reachability {
@@ -203,14 +616,19 @@ impl<UID: Uid> ExchangeMsg<UID> {
                          recheability. Denying bootstrap."
                     );
                     let reason = BootstrapDenyReason::FailedExternalReachability;
-                    self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                    let msg = Message::BootstrapDenied(reason);
+                    self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
                 }
             }
             ExternalReachability::NotRequired => {
                 if !self.is_peer_whitelisted(CrustUser::Client) {
-                    trace!("Bootstrapper Client is not whitelisted. Denying bootstrap.");
-                    let reason = BootstrapDenyReason::ClientNotWhitelisted;
-                    return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                    return self.refuse(
+                        core,
+                        poll,
+                        their_uid,
+                        RejectionCode::NotWhitelisted,
+                        "Bootstrapper Client is not whitelisted",
+                    );
                 }
 
                 self.send_bootstrap_grant(core, poll, their_uid, CrustUser::Client)
@@ -230,18 +648,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
             }
         };
 
-        let res = match peer_kind {
-            CrustUser::Node => unwrap!(self.config.lock())
-                .cfg
-                .whitelisted_node_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
-            CrustUser::Client => unwrap!(self.config.lock())
-                .cfg
-                .whitelisted_client_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
-        };
+        let res = unwrap!(self.config.lock()).whitelists.allows(peer_kind, &peer_ip);
 
         if !res {
             trace!("IP: {} is not whitelisted.", peer_ip);
@@ -250,6 +657,51 @@ impl<UID: Uid> ExchangeMsg<UID> {
         res
     }
 
+    /// Whether the peer's IP has been banned since we accepted the connection, e.g. by
+    /// `Service::ban_peer` for another connection from it.
+    fn is_peer_banned(&self, core: &Core) -> bool {
+        self.peer_addr.map_or(false, |addr| core.bans().is_banned(&addr.ip(), core.now()))
+    }
+
+    /// Whether as many peers of the given kind as we take are connected or handshaking already,
+    /// counting the peer as rejected if so.
+    fn is_full(&self, core: &mut Core, peer_kind: CrustUser) -> bool {
+        let max_peers = match unwrap!(self.config.lock()).peer_limits.max_peers(peer_kind) {
+            Some(max_peers) => max_peers,
+            None => return false,
+        };
+        let stats = core.stats_mut();
+        let (connected, rejected) = match peer_kind {
+            CrustUser::Client => (
+                stats.client_peers + stats.client_handshakes,
+                &mut stats.clients_rejected,
+            ),
+            CrustUser::Node => (
+                stats.node_peers + stats.node_handshakes,
+                &mut stats.nodes_rejected,
+            ),
+        };
+        if connected < max_peers {
+            return false;
+        }
+        *rejected += 1;
+        true
+    }
+
+    fn reject_as_full(&mut self, core: &mut Core, poll: &Poll, peer_kind: CrustUser) {
+        let message = match peer_kind {
+            CrustUser::Client => "Too many clients connected",
+            CrustUser::Node => "Too many nodes connected",
+        };
+        self.reject(
+            core,
+            poll,
+            RejectionCode::Full,
+            Some(FULL_RETRY_AFTER_SECS),
+            message,
+        )
+    }
+
     fn handle_check_reachability(
         &mut self,
         core: &mut Core,
@@ -268,7 +720,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
                  Denying bootstrap."
             );
             let reason = BootstrapDenyReason::FailedExternalReachability;
-            self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+            self.write(core, poll, Some((Message::BootstrapDenied(reason), CONTROL_PRIORITY)));
         }
     }
 
@@ -279,11 +731,20 @@ impl<UID: Uid> ExchangeMsg<UID> {
         their_uid: UID,
         peer_kind: CrustUser,
     ) {
+        if self.is_full(core, peer_kind) {
+            return self.reject_as_full(core, poll, peer_kind);
+        }
+
+        self.reserve(core, peer_kind);
         self.enter_handshaking_mode(their_uid);
 
         let our_uid = self.our_uid;
         self.next_state = NextState::ActiveConnection(their_uid, peer_kind);
-        self.write(core, poll, Some((Message::BootstrapGranted(our_uid), 0)))
+        let msg = match self.answers.take() {
+            Some(answers) => Message::ExtBootstrapGranted(our_uid, answers),
+            None => Message::BootstrapGranted(our_uid),
+        };
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)))
     }
 
     fn handle_connect(
@@ -292,36 +753,325 @@ impl<UID: Uid> ExchangeMsg<UID> {
         poll: &Poll,
         their_uid: UID,
         name_hash: NameHash,
+        offers: Option<Extensions>,
     ) {
         if !self.is_valid_name_hash(name_hash) {
-            trace!("Invalid name hash given. Denying connection.");
-            return self.terminate(core, poll);
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::WrongNetwork,
+                None,
+                "Connecting Node has an invalid name hash",
+            );
         }
 
         self.try_update_crust_config();
 
+        if self.is_peer_banned(core) {
+            return self.refuse(
+                core,
+                poll,
+                their_uid,
+                RejectionCode::Banned,
+                "Connecting Node is banned",
+            );
+        }
         if !self.is_peer_whitelisted(CrustUser::Node) {
-            trace!("Connecting Node is not whitelisted. Denying connection.");
-            return self.terminate(core, poll);
+            return self.refuse(
+                core,
+                poll,
+                their_uid,
+                RejectionCode::NotWhitelisted,
+                "Connecting Node is not whitelisted",
+            );
+        }
+
+        if self.is_full(core, CrustUser::Node) {
+            return self.reject_as_full(core, poll, CrustUser::Node);
+        }
+
+        let answers = offers.map(|offers| {
+            let mut timestamps = TimestampExtension::new(self.timestamp_frames);
+            let mut encryption = EncryptionExtension::new(core.keys());
+            let (answers, features) = answer_extensions(
+                &mut [
+                    &mut timestamps,
+                    &mut CorrelationExtension,
+                    &mut RetirementExtension,
+                    &mut ProbeExtension,
+                    &mut ContactUpdateExtension,
+                    &mut UpgradeExtension,
+                    &mut encryption,
+                    &mut RelayExtension::new(self.relays()),
+                ],
+                &offers,
+            );
+            self.features = features;
+            self.session = encryption.take_session();
+            answers
+        });
+        if self.require_encryption && self.session.is_none() {
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::EncryptionRequired,
+                None,
+                "Connecting Node didn't agree on encryption",
+            );
         }
 
+        self.reserve(core, CrustUser::Node);
         self.enter_handshaking_mode(their_uid);
 
         let our_uid = self.our_uid;
-        let name_hash = self.name_hash;
+        let name_hash = self.answer_name_hash();
         self.next_state = NextState::ConnectionCandidate(their_uid);
-        self.write(core, poll, Some((Message::Connect(our_uid, name_hash), 0)));
+        let msg = match answers {
+            Some(answers) => Message::ExtConnect(our_uid, name_hash, answers),
+            None => Message::Connect(our_uid, name_hash),
+        };
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    /// Whether peers are told we relay for them: not once we are retiring, as we won't for long.
+    fn relays(&self) -> bool {
+        self.relay && self.retiring.is_none()
     }
 
     fn handle_echo_addr_req(&mut self, core: &mut Core, poll: &Poll) {
         self.next_state = NextState::None;
         if let Ok(peer_addr) = self.socket.peer_addr() {
-            self.write(core, poll, Some((Message::EchoAddrResp(peer_addr), 0)));
+            self.write(core, poll, Some((Message::EchoAddrResp(peer_addr), CONTROL_PRIORITY)));
         } else {
             self.terminate(core, poll);
         }
     }
 
+    /// Challenges the peer to prove it is the first of the peers it asks for a pipe between, by
+    /// the key that peer authenticated its connection to us with. Both have to be connected to
+    /// us already.
+    #[cfg(feature = "relay")]
+    fn handle_relay(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        their_uid: UID,
+        name_hash: NameHash,
+        peer_uid: UID,
+    ) {
+        if !self.is_valid_name_hash(name_hash) {
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::WrongNetwork,
+                None,
+                "Relayed peer has an invalid name hash",
+            );
+        }
+        if self.retiring.is_some() {
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::Retiring,
+                None,
+                "We are retiring, relay through another peer",
+            );
+        }
+
+        let tokens = {
+            let guard = unwrap!(self.cm.lock());
+            let token = |uid: &UID| guard.get(uid).and_then(|id| id.active_connection);
+            (token(&their_uid), token(&peer_uid))
+        };
+        let their_token = match tokens {
+            (Some(their_token), Some(_)) => their_token,
+            _ => {
+                return self.reject(
+                    core,
+                    poll,
+                    RejectionCode::NotRelaying,
+                    None,
+                    "We relay only between peers connected to us",
+                )
+            }
+        };
+        let their_key = core.get_state(their_token).and_then(|state| {
+            let mut state = state.borrow_mut();
+            let connection = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+            connection.peer_public_key()
+        });
+        let (their_key, our_key) = match (their_key, core.keys()) {
+            (Some(their_key), Some(keys)) => (their_key, keys.public_key()),
+            _ => {
+                return self.reject(
+                    core,
+                    poll,
+                    RejectionCode::NotRelaying,
+                    None,
+                    "We relay only for peers authenticated by their key",
+                )
+            }
+        };
+        if self.relay_admits(core, poll, their_uid, peer_uid).is_none() {
+            return;
+        }
+
+        let challenge = common::new_key_challenge();
+        self.pending_relay = Some(PendingRelay {
+            their_uid,
+            peer_uid,
+            their_key,
+            challenge,
+        });
+        let msg = Message::RelayChallenge(challenge, our_key);
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    /// Hands the connection over to the `RelayState` once the peer proved its key, to be piped to
+    /// the peer asked for once that peer asks for a pipe to this one.
+    #[cfg(feature = "relay")]
+    fn handle_relay_proof(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        pending_relay: PendingRelay<UID>,
+        frame: &[u8],
+    ) {
+        let PendingRelay {
+            their_uid,
+            peer_uid,
+            their_key,
+            challenge,
+        } = pending_relay;
+        let proven = match common::decode_message::<Message<UID>>(frame) {
+            Ok(Message::RelayProof(ref proof)) => core
+                .keys()
+                .map_or(false, |keys| keys.verify_proof(&their_key, &challenge, proof)),
+            Ok(message) => {
+                trace!("Peer answered our relay challenge with {:?}", message);
+                false
+            }
+            Err(e) => {
+                trace!("Invalid relay proof: {:?}", e);
+                return self.terminate(core, poll);
+            }
+        };
+        if !proven {
+            return self.reject(
+                core,
+                poll,
+                RejectionCode::NotRelaying,
+                None,
+                "Relayed peer failed to prove its key",
+            );
+        }
+        // Others may have been taken while we waited for the proof.
+        let source = match self.relay_admits(core, poll, their_uid, peer_uid) {
+            Some(source) => source,
+            None => return,
+        };
+
+        let name_hash = self.answer_name_hash();
+        let _ = poll.deregister(&self.socket);
+        let socket = mem::replace(&mut self.socket, Socket::default());
+        core.record(self.token, RecordedEventKind::HandshakeSucceeded);
+        let peer_addr = self.peer_addr;
+        core.audit(|| {
+            AuditRecord::new(AuditEvent::HandshakeSucceeded, peer_addr).peer(&their_uid)
+        });
+        self.release_handshake_slot(core, poll);
+        self.terminate(core, poll);
+
+        if let Some(state) = core.get_state(RELAY_TOKEN) {
+            if let Some(relay) = state.borrow_mut().as_any().downcast_mut::<RelayState<UID>>() {
+                relay.pair(core, poll, socket, (their_uid, peer_uid), name_hash, source);
+            }
+        }
+    }
+
+    /// Whether the `RelayState` takes a request of `from` for a pipe to `to` from this peer,
+    /// returning the IP the request came from if so. Rejects the peer if not.
+    #[cfg(feature = "relay")]
+    fn relay_admits(&mut self, core: &mut Core, poll: &Poll, from: UID, to: UID) -> Option<IpAddr> {
+        let source = match self.peer_addr {
+            Some(addr) => addr.ip(),
+            None => {
+                self.terminate(core, poll);
+                return None;
+            }
+        };
+        let refusal = core.get_state(RELAY_TOKEN).and_then(|state| {
+            let mut state = state.borrow_mut();
+            let relay = state.as_any().downcast_mut::<RelayState<UID>>()?;
+            Some(relay.refusal(from, to, source))
+        });
+        let (message, retry_after_secs) = match refusal {
+            Some(None) => return Some(source),
+            Some(Some(message)) => (message, Some(FULL_RETRY_AFTER_SECS)),
+            None => ("We don't relay", None),
+        };
+        self.reject(core, poll, RejectionCode::NotRelaying, retry_after_secs, message);
+        None
+    }
+
+    /// Tells the peer why we refuse it and closes the connection once that has been sent.
+    fn reject(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        code: RejectionCode,
+        retry_after_secs: Option<u64>,
+        message: &str,
+    ) {
+        self.next_state = NextState::None;
+        let msg = match self.legacy {
+            None => Message::Rejection(Rejection::new(code, retry_after_secs, message)),
+            Some(request) => match request.denial(code) {
+                Some(reason) => Message::BootstrapDenied(reason),
+                None => {
+                    trace!("{}. Closing the connection of a legacy peer.", message);
+                    return self.terminate(core, poll);
+                }
+            },
+        };
+        trace!("{}. Rejecting peer with {:?}.", message, msg);
+        self.write(core, poll, Some((msg, CONTROL_PRIORITY)));
+    }
+
+    /// Rejects a peer we won't have anything to do with, telling the upper layers so that they can
+    /// keep track of abuse.
+    fn refuse(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        their_uid: UID,
+        code: RejectionCode,
+        message: &str,
+    ) {
+        if let Some(addr) = self.peer_addr {
+            self.event_tx.send(Event::PeerRejected {
+                addr,
+                peer_id: Some(their_uid),
+                reason: code,
+            });
+        }
+        self.reject(core, poll, code, None, message)
+    }
+
+    /// Counts the peer in the handshakes of its kind until `release` is called.
+    fn reserve(&mut self, core: &mut Core, peer_kind: CrustUser) {
+        if self.reserved.is_none() {
+            *handshakes(core, peer_kind) += 1;
+            self.reserved = Some(peer_kind);
+        }
+    }
+
+    fn release(&mut self, core: &mut Core) {
+        if let Some(peer_kind) = self.reserved.take() {
+            *handshakes(core, peer_kind) -= 1;
+        }
+    }
+
     fn enter_handshaking_mode(&self, their_uid: UID) {
         let mut guard = unwrap!(self.cm.lock());
         guard
@@ -339,7 +1089,20 @@ impl<UID: Uid> ExchangeMsg<UID> {
     }
 
     fn is_valid_name_hash(&self, name_hash: NameHash) -> bool {
-        self.name_hash == name_hash
+        match self.network {
+            NetworkId::Plain(ref ours) => *ours == name_hash,
+            // The peer sent a nonce instead, and answered our challenge before we got here.
+            NetworkId::Keyed(_) => self.proven_challenge.is_some(),
+        }
+    }
+
+    /// What to send in the name hash of our answer to a connect request: the hash of our network
+    /// name, or on a private network the nonce of the challenge the peer answered.
+    fn answer_name_hash(&self) -> NameHash {
+        match self.network {
+            NetworkId::Plain(name_hash) => name_hash,
+            NetworkId::Keyed(_) => self.proven_challenge.unwrap_or_default(),
+        }
     }
 
     fn validate_peer_uid(&self, their_uid: UID) -> Result<UID, ()> {
@@ -352,7 +1115,8 @@ impl<UID: Uid> ExchangeMsg<UID> {
     }
 
     fn try_update_crust_config(&self) {
-        match read_config_file() {
+        let config_path = unwrap!(self.config.lock()).config_path.clone();
+        match read_config(&config_path) {
             Ok(cfg) => unwrap!(self.config.lock()).check_for_update_and_mark_modified(cfg),
             Err(e) => debug!("Could not read Crust config file: {:?}", e),
         }
@@ -374,6 +1138,8 @@ impl<UID: Uid> ExchangeMsg<UID> {
         }
 
         match self.socket.write(poll, self.token, msg) {
+            // Keep waiting for the answer to our challenge.
+            Ok(true) if self.awaits_answer() => (),
             Ok(true) => self.done(core, poll),
             Ok(false) => (),
             Err(e) => {
@@ -383,15 +1149,80 @@ impl<UID: Uid> ExchangeMsg<UID> {
         }
     }
 
+    /// Whether we wait for the peer to answer a challenge of ours.
+    fn awaits_answer(&self) -> bool {
+        #[cfg(feature = "relay")]
+        {
+            if self.pending_relay.is_some() {
+                return true;
+            }
+        }
+        self.pending_pow.is_some() || self.pending_network.is_some() || self.confirming.is_some()
+    }
+
+    /// Called once our acceptance has been written in full, everything after which is encrypted
+    /// if agreed on. The connection is then taken up once the peer confirmed the keys.
     fn done(&mut self, core: &mut Core, poll: &Poll) {
-        let _ = core.remove_state(self.token);
-        let _ = core.cancel_timeout(&self.timeout);
+        match self.session.take() {
+            Some(keys) => {
+                self.confirming = Some(keys.peer_key());
+                self.socket.enable_encryption(keys);
+                self.write(core, poll, Some((Message::KeyConfirmation, CONTROL_PRIORITY)))
+            }
+            None => self.hand_over(core, poll),
+        }
+    }
+
+    fn handle_key_confirmation(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        peer_key: PublicKey,
+        frame: &[u8],
+    ) {
+        match common::decode_message::<Message<UID>>(frame) {
+            Ok(Message::KeyConfirmation) => {
+                self.features.peer_key = Some(peer_key);
+                self.hand_over(core, poll)
+            }
+            Ok(message) => {
+                trace!("Peer answered our key confirmation with {:?}", message);
+                self.terminate(core, poll)
+            }
+            Err(e) => {
+                trace!("Invalid key confirmation: {:?}", e);
+                self.terminate(core, poll)
+            }
+        }
+    }
+
+    fn hand_over(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.hand_over_state(self.token);
+
+        core.record(self.token, RecordedEventKind::HandshakeSucceeded);
+        let peer_addr = self.peer_addr;
+        let their_uid = match self.next_state {
+            NextState::ActiveConnection(their_uid, _)
+            | NextState::ConnectionCandidate(their_uid) => Some(their_uid),
+            NextState::None => None,
+        };
+        core.audit(|| {
+            let record = AuditRecord::new(AuditEvent::HandshakeSucceeded, peer_addr);
+            match their_uid {
+                Some(their_uid) => record.peer(&their_uid),
+                None => record,
+            }
+        });
+        self.release_handshake_slot(core, poll);
 
         let our_uid = self.our_uid;
         let event_tx = self.event_tx.clone();
+        let settings = ConnectionSettings::from_config(&unwrap!(self.config.lock()).cfg);
+        let features = self.features;
 
         match self.next_state {
             NextState::ActiveConnection(their_uid, peer_kind) => {
+                self.release(core);
                 let socket = mem::replace(&mut self.socket, Socket::default());
                 ActiveConnection::start(
                     core,
@@ -402,13 +1233,21 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     our_uid,
                     their_uid,
                     peer_kind,
-                    Event::BootstrapAccept(their_uid, peer_kind),
+                    Event::BootstrapAccept(their_uid, peer_kind, features.peer_key),
                     event_tx,
+                    settings,
+                    features,
+                    Some(ConnectionDirection::Inbound),
                 );
             }
             NextState::ConnectionCandidate(their_uid) => {
                 let cm = self.cm.clone();
+                // The node is counted as handshaking until it chose the connection.
+                let mut reserved = self.reserved.take();
                 let handler = move |core: &mut Core, poll: &Poll, token, res| {
+                    if let Some(peer_kind) = reserved.take() {
+                        *handshakes(core, peer_kind) -= 1;
+                    }
                     if let Some(socket) = res {
                         ActiveConnection::start(
                             core,
@@ -421,8 +1260,11 @@ impl<UID: Uid> ExchangeMsg<UID> {
                             // Note; We enter ConnectionCandidate only with
                             //       Nodes
                             CrustUser::Node,
-                            Event::ConnectSuccess(their_uid),
+                            Event::ConnectSuccess(their_uid, features.peer_key),
                             event_tx.clone(),
+                            settings.clone(),
+                            features,
+                            Some(ConnectionDirection::Inbound),
                         );
                     }
                 };
@@ -436,6 +1278,8 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     self.cm.clone(),
                     our_uid,
                     their_uid,
+                    // No parent to deregister from: it takes our place under our token.
+                    ChildHandle::default(),
                     Box::new(handler),
                 );
             }
@@ -443,6 +1287,12 @@ impl<UID: Uid> ExchangeMsg<UID> {
         }
     }
 
+    fn release_handshake_slot(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(mut finish) = self.finish.take() {
+            (*finish)(core, poll);
+        }
+    }
+
     fn terminate_childern(&mut self, core: &mut Core, poll: &Poll) {
         for child in self.reachability_children.drain() {
             core.get_state(child)
@@ -452,6 +1302,10 @@ impl<UID: Uid> ExchangeMsg<UID> {
 }
 
 impl<UID: Uid> State for ExchangeMsg<UID> {
+    fn name(&self) -> &'static str {
+        "connection_listener::ExchangeMsg"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             self.terminate(core, poll);
@@ -466,7 +1320,13 @@ impl<UID: Uid> State for ExchangeMsg<UID> {
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if self.finish.is_some() {
+            core.record(self.token, RecordedEventKind::HandshakeFailed);
+            let peer_addr = self.peer_addr;
+            core.audit(|| AuditRecord::new(AuditEvent::HandshakeFailed, peer_addr));
+        }
         self.terminate_childern(core, poll);
+        self.release(core);
         let _ = core.remove_state(self.token);
 
         match self.next_state {
@@ -488,11 +1348,12 @@ impl<UID: Uid> State for ExchangeMsg<UID> {
             NextState::None => (),
         }
 
-        let _ = core.cancel_timeout(&self.timeout);
         let _ = poll.deregister(&self.socket);
+
+        self.release_handshake_slot(core, poll);
     }
 
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
         debug!("Exchange message timed out. Terminating direct connection request.");
         self.terminate(core, poll)
     }
@@ -502,6 +1363,16 @@ impl<UID: Uid> State for ExchangeMsg<UID> {
     }
 }
 
+/// Gauge of the peers of the given kind handshaking within their limit, see
+/// `ExchangeMsg::reserved`.
+fn handshakes(core: &mut Core, kind: CrustUser) -> &mut usize {
+    let stats = core.stats_mut();
+    match kind {
+        CrustUser::Client => &mut stats.client_handshakes,
+        CrustUser::Node => &mut stats.node_handshakes,
+    }
+}
+
 enum NextState<UID> {
     None,
     ActiveConnection(UID, CrustUser),
@@ -0,0 +1,157 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// What the listeners of a service share, so that its limits hold for the service as a whole
+// however many ports it listens on.
+
+use common::Core;
+use mio::Token;
+use std::sync::{Arc, Mutex};
+
+/// The listeners of a service: the primary one and those on `additional_acceptor_ports`. They
+/// share the handshake slots of `Config::max_concurrent_inbound_handshakes`, and the connections
+/// any of them parks count against `MAX_PARKED_HANDSHAKES` and `Config::max_peers` for all.
+#[derive(Clone, Default)]
+pub struct ListenerGroup {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Handshakes running on connections accepted by any of the listeners.
+    active: usize,
+    /// Connections parked by any of the listeners.
+    parked: usize,
+    /// The listeners running, in the order they started.
+    listeners: Vec<Token>,
+    /// Additional listeners which have neither started nor failed yet.
+    starting: usize,
+    /// Port of the primary listener, which started before the additional ones had.
+    held_start: Option<u16>,
+}
+
+impl ListenerGroup {
+    /// Takes note that `count` additional listeners are starting. The primary listener's start
+    /// is reported once they all have started or failed, see `primary_started`.
+    pub fn starting(&self, count: usize) {
+        unwrap!(self.inner.lock()).starting += count;
+    }
+
+    /// Takes note that the primary listener started on `port`. Returns whether to report it now.
+    pub fn primary_started(&self, port: u16) -> bool {
+        let mut inner = unwrap!(self.inner.lock());
+        if inner.starting == 0 {
+            return true;
+        }
+        inner.held_start = Some(port);
+        false
+    }
+
+    /// Takes note that an additional listener started or failed. Returns the port of the primary
+    /// listener if its start is to be reported now.
+    pub fn additional_settled(&self) -> Option<u16> {
+        let mut inner = unwrap!(self.inner.lock());
+        inner.starting = inner.starting.saturating_sub(1);
+        if inner.starting == 0 {
+            inner.held_start.take()
+        } else {
+            None
+        }
+    }
+
+    /// Adds the listener of `token` to the group, once it has started.
+    pub fn join(&self, token: Token) {
+        unwrap!(self.inner.lock()).listeners.push(token);
+    }
+
+    /// Removes the listener of `token` from the group, as it stops.
+    pub fn leave(&self, token: Token) {
+        unwrap!(self.inner.lock())
+            .listeners
+            .retain(|listener| *listener != token);
+    }
+
+    /// The listeners running.
+    pub fn listeners(&self) -> Vec<Token> {
+        unwrap!(self.inner.lock()).listeners.clone()
+    }
+
+    /// Handshakes running on the connections of all the listeners.
+    pub fn active_handshakes(&self) -> usize {
+        unwrap!(self.inner.lock()).active
+    }
+
+    /// Connections parked by all the listeners.
+    pub fn parked(&self) -> usize {
+        unwrap!(self.inner.lock()).parked
+    }
+
+    /// Takes note that a handshake started, and updates `CoreStats::handshakes_active`.
+    pub fn handshake_started(&self, core: &mut Core) {
+        let active = {
+            let mut inner = unwrap!(self.inner.lock());
+            inner.active += 1;
+            inner.active
+        };
+        core.stats_mut().handshakes_active = active;
+    }
+
+    /// Takes note that a handshake ended, and updates `CoreStats::handshakes_active`.
+    pub fn handshake_done(&self, core: &mut Core) {
+        let active = {
+            let mut inner = unwrap!(self.inner.lock());
+            inner.active = inner.active.saturating_sub(1);
+            inner.active
+        };
+        core.stats_mut().handshakes_active = active;
+    }
+
+    /// Takes note that a connection was parked, and updates `CoreStats::handshakes_parked`.
+    pub fn park(&self, core: &mut Core) {
+        let parked = {
+            let mut inner = unwrap!(self.inner.lock());
+            inner.parked += 1;
+            inner.parked
+        };
+        core.stats_mut().handshakes_parked = parked;
+    }
+
+    /// Takes note that `count` parked connections left the queue, for a handshake slot or not.
+    pub fn unpark(&self, core: &mut Core, count: usize) {
+        let parked = {
+            let mut inner = unwrap!(self.inner.lock());
+            inner.parked = inner.parked.saturating_sub(count);
+            inner.parked
+        };
+        core.stats_mut().handshakes_parked = parked;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primary_start_is_reported_once_additional_listeners_settled() {
+        let group = ListenerGroup::default();
+        assert!(group.primary_started(5483));
+
+        let group = ListenerGroup::default();
+        group.starting(2);
+        assert!(!group.primary_started(5483));
+        assert_eq!(group.additional_settled(), None);
+        assert_eq!(group.additional_settled(), Some(5483));
+
+        // Additional listeners settling before the primary one started don't report anything.
+        let group = ListenerGroup::default();
+        group.starting(1);
+        assert_eq!(group.additional_settled(), None);
+        assert!(group.primary_started(5483));
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{self, CommonError, Extensions, ExternalReachability, Message, NameHash, Uid};
+
+/// First message sent to us on an inbound connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandshakeRequest<UID> {
+    /// A bootstrap request of a peer of protocol version 2, which offers no extensions.
+    Bootstrap(UID, NameHash, ExternalReachability),
+    ExtBootstrap(UID, NameHash, Extensions),
+    /// A connect request, with the offered extensions unless the peer is of protocol version 2.
+    Connect(UID, NameHash, Option<Extensions>),
+    EchoAddr,
+    /// A request for a pipe to the peer of the second id, see `Message::RelayRequest`.
+    Relay(UID, NameHash, UID),
+}
+
+impl<UID> HandshakeRequest<UID> {
+    /// The name hash the request carries, if it is one which does.
+    pub fn name_hash(&self) -> Option<NameHash> {
+        match *self {
+            HandshakeRequest::Bootstrap(_, name_hash, _)
+            | HandshakeRequest::ExtBootstrap(_, name_hash, _)
+            | HandshakeRequest::Connect(_, name_hash, _)
+            | HandshakeRequest::Relay(_, name_hash, _) => Some(name_hash),
+            HandshakeRequest::EchoAddr => None,
+        }
+    }
+}
+
+/// Decodes the body of the first frame of an inbound connection.
+pub fn decode_handshake_request<UID: Uid>(frame: &[u8]) -> common::Result<HandshakeRequest<UID>> {
+    match common::decode_message(frame)? {
+        Message::BootstrapRequest(their_uid, name_hash, ext_reachability) => Ok(
+            HandshakeRequest::Bootstrap(their_uid, name_hash, ext_reachability),
+        ),
+        Message::ExtBootstrapRequest(their_uid, name_hash, offers) => Ok(
+            HandshakeRequest::ExtBootstrap(their_uid, name_hash, offers),
+        ),
+        Message::Connect(their_uid, name_hash) => {
+            Ok(HandshakeRequest::Connect(their_uid, name_hash, None))
+        }
+        Message::ExtConnect(their_uid, name_hash, offers) => Ok(
+            HandshakeRequest::Connect(their_uid, name_hash, Some(offers)),
+        ),
+        Message::EchoAddrReq => Ok(HandshakeRequest::EchoAddr),
+        Message::RelayRequest(their_uid, name_hash, peer_uid) => Ok(HandshakeRequest::Relay(
+            their_uid, name_hash, peer_uid,
+        )),
+        message => {
+            trace!("Unexpected message in direct connect: {:?}", message);
+            Err(CommonError::UnexpectedMessage)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::serialisation::serialise;
+    use tests::UniqueId;
+
+    #[test]
+    fn decode_requests() {
+        let id: UniqueId = [3; 20];
+        let name_hash = [7; common::HASH_SIZE];
+        let cases = vec![
+            (
+                Message::BootstrapRequest(id, name_hash, ExternalReachability::NotRequired),
+                HandshakeRequest::Bootstrap(id, name_hash, ExternalReachability::NotRequired),
+            ),
+            (
+                Message::ExtBootstrapRequest(id, name_hash, Extensions::default()),
+                HandshakeRequest::ExtBootstrap(id, name_hash, Extensions::default()),
+            ),
+            (
+                Message::Connect(id, name_hash),
+                HandshakeRequest::Connect(id, name_hash, None),
+            ),
+            (
+                Message::ExtConnect(id, name_hash, Extensions::default()),
+                HandshakeRequest::Connect(id, name_hash, Some(Extensions::default())),
+            ),
+            (Message::EchoAddrReq, HandshakeRequest::EchoAddr),
+            (
+                Message::RelayRequest(id, name_hash, [4; 20]),
+                HandshakeRequest::Relay(id, name_hash, [4; 20]),
+            ),
+        ];
+
+        for (msg, expected) in cases {
+            let frame = unwrap!(serialise(&msg));
+            assert_eq!(
+                unwrap!(decode_handshake_request::<UniqueId>(&frame)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn reject_malformed_requests() {
+        let frame = unwrap!(serialise(&Message::Heartbeat::<UniqueId>));
+        match decode_handshake_request::<UniqueId>(&frame) {
+            Err(CommonError::UnexpectedMessage) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let mut frame = unwrap!(serialise(&Message::Connect::<UniqueId>(
+            [3; 20],
+            [7; common::HASH_SIZE]
+        )));
+        let len = frame.len();
+        for truncated in 0..len {
+            match decode_handshake_request::<UniqueId>(&frame[..truncated]) {
+                Err(CommonError::Serialisation(_)) => (),
+                res => panic!("Unexpected result for {} bytes: {:?}", truncated, res),
+            }
+        }
+
+        // An unknown message variant.
+        frame[0] = 0xff;
+        match decode_handshake_request::<UniqueId>(&frame) {
+            Err(CommonError::Serialisation(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
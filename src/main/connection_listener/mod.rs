@@ -9,37 +9,124 @@
 
 mod check_reachability;
 mod exchange_msg;
+mod group;
+mod handshake;
+mod parked_handshake;
+mod sniff;
+
+pub use self::check_reachability::CheckReachability;
+pub use self::group::ListenerGroup;
+pub use self::handshake::{decode_handshake_request, HandshakeRequest};
 
 use self::exchange_msg::ExchangeMsg;
-use common::{Core, NameHash, Socket, State, Uid};
-use main::{ConnectionMap, CrustConfig, Event};
-use mio::tcp::TcpListener;
+use self::parked_handshake::ParkedHandshake;
+use self::sniff::{Protocol, Sniff};
+#[cfg(feature = "utp")]
+use common::{UtpEndpoint, UtpStream};
+#[cfg(feature = "websocket")]
+use common::{CrustUser, WsEndpoint, WsStream};
+use common::{
+    Core, CoreTimer, IoErrorClass, IoShim, IoSite, MemoryPressure, NetworkId, RecordedEventKind,
+    RejectionCode, Socket, State, Uid,
+};
+use main::{
+    advertise_listeners, contradicting_endpoints, fd_limit, fd_soft_limit, is_fd_exhaustion,
+    max_connections, with_asserted_endpoints, ConnectionMap, CrustConfig, Event, EventSink,
+    ReserveFd, ResourceKind, FD_SAFETY_MARGIN,
+};
+use mio::tcp::{TcpListener, TcpStream};
 use mio::{Poll, PollOpt, Ready, Token};
-use nat::ip_addr_is_global;
-use nat::{MappedTcpSocket, MappingContext};
+use nat::{ip_addr_is_global, new_reusable_tcp_socket};
+use nat::{MappedTcpSocket, MappingContext, PortMapping};
 use net2::TcpBuilder;
 use std::any::Any;
 use std::cell::RefCell;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
-use std::rc::Rc;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::rc::{Rc, Weak};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_LISTEN_BACKLOG: u32 = 100;
+const DEFAULT_ACCEPT_BATCH_SIZE: usize = 64;
+/// Maximum number of accepted connections waiting for a free handshake slot, over all the
+/// listeners of a service.
+const MAX_PARKED_HANDSHAKES: usize = 512;
+/// How long an accepted connection may wait for a free handshake slot before it is dropped.
+#[cfg(not(test))]
+const PARKED_HANDSHAKE_EXPIRY_SEC: u64 = 10;
+#[cfg(test)]
+const PARKED_HANDSHAKE_EXPIRY_SEC: u64 = 2;
+const PARKED_EXPIRY_TIMER_ID: u64 = 0;
+const RESOURCE_CHECK_TIMER_ID: u64 = PARKED_EXPIRY_TIMER_ID + 1;
+const MAPPING_RENEWAL_TIMER_ID: u64 = RESOURCE_CHECK_TIMER_ID + 1;
+/// Interval at which a listener which stopped accepting for lack of file descriptors checks
+/// whether it can resume.
+const RESOURCE_CHECK_INTERVAL_MS: u64 = 1000;
+
+/// Socket options a listener is restarted with, see `Service::restart_listener`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListenerOptions {
+    /// Length of the queue of connections waiting to be accepted. Defaults to
+    /// `Config::listen_backlog`.
+    pub backlog: Option<u32>,
+    /// Time-to-live of the packets sent on the connections accepted. Defaults to the system's.
+    pub ttl: Option<u32>,
+}
 
-const LISTENER_BACKLOG: i32 = 100;
+struct ParkedSocket {
+    token: Token,
+    socket: Socket,
+    parked_at: Instant,
+}
 
 pub struct ConnectionListener<UID: Uid> {
     token: Token,
     cm: ConnectionMap<UID>,
     config: CrustConfig,
-    event_tx: ::CrustEventSender<UID>,
+    event_tx: EventSink<UID>,
     listener: TcpListener,
-    name_hash: NameHash,
+    local_addr: SocketAddr,
+    /// Addresses of this listener among `our_listeners`.
+    addrs: Vec<SocketAddr>,
+    our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    /// Mappings of our port on gateways, renewed before their lease runs out.
+    port_mappings: Vec<PortMapping>,
+    network: NetworkId,
     our_uid: UID,
     timeout_sec: Option<u64>,
     accept_bootstrap: bool,
+    /// The peers bootstrapping peers are sent to instead, once we are retiring.
+    retiring: Option<Vec<SocketAddr>>,
+    /// The other listeners of the service, with which the handshake slots are shared.
+    group: ListenerGroup,
+    /// The connections this listener accepted which wait for a handshake slot.
+    parked: VecDeque<ParkedSocket>,
+    /// Soft limit on the file descriptors of the process, queried when the listener started.
+    fd_soft_limit: Option<usize>,
+    reserve_fd: ReserveFd,
+    /// Whether we stopped accepting connections because too many are open.
+    paused: bool,
+    primary: bool,
+    shim: IoShim,
+    /// The endpoint accepting uTP connections on our port, see `Config::enable_utp`.
+    #[cfg(feature = "utp")]
+    utp: Option<Token>,
+    /// The endpoint accepting WebSocket connections, see `Config::websocket_port`.
+    #[cfg(feature = "websocket")]
+    websocket: Option<Token>,
+    self_weak: Weak<RefCell<ConnectionListener<UID>>>,
 }
 
 impl<UID: Uid> ConnectionListener<UID> {
+    /// Starts listening on `port`. Only the primary listener, on `tcp_acceptor_port`, reports its
+    /// start and failure with events, and its start only once the additional listeners of its
+    /// `group` have started or failed, see `ListenerGroup::starting`. Those on
+    /// `additional_acceptor_ports` are best effort: if one can't be bound, e.g. because the port
+    /// is privileged, it is skipped with a warning.
     pub fn start(
         core: &mut Core,
         poll: &Poll,
@@ -47,55 +134,85 @@ impl<UID: Uid> ConnectionListener<UID> {
         port: u16,
         force_include_port: bool,
         our_uid: UID,
-        name_hash: NameHash,
+        network: NetworkId,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
         mc: Arc<MappingContext>,
         our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+        group: ListenerGroup,
         token: Token,
-        event_tx: ::CrustEventSender<UID>,
+        primary: bool,
+        event_tx: EventSink<UID>,
     ) {
+        let bind_ip = if unwrap!(config.lock()).cfg.dual_stack {
+            Some(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)))
+        } else {
+            None
+        };
         let event_tx_0 = event_tx.clone();
-        let finish =
-            move |core: &mut Core, poll: &Poll, socket, mut mapped_addrs: Vec<SocketAddr>| {
-                let checker = |s: &SocketAddr| ip_addr_is_global(&s.ip()) && s.port() == port;
-                if force_include_port && port != 0 && !mapped_addrs.iter().any(checker) {
-                    let global_addrs: Vec<_> = mapped_addrs
-                        .iter()
-                        .filter_map(|s| {
-                            if ip_addr_is_global(&s.ip()) {
-                                let mut s = *s;
-                                s.set_port(port);
-                                Some(s)
-                            } else {
-                                None
-                            }
-                        })
-                        .collect();
-                    mapped_addrs.extend(global_addrs);
-                }
-                if let Err(e) = Self::handle_mapped_socket(
-                    core,
-                    poll,
-                    handshake_timeout_sec,
-                    socket,
-                    mapped_addrs,
-                    our_uid,
-                    name_hash,
-                    cm,
-                    config,
-                    our_listeners,
-                    token,
-                    event_tx.clone(),
-                ) {
+        let group_0 = group.clone();
+        let finish = move |core: &mut Core,
+                           poll: &Poll,
+                           socket,
+                           mut mapped_addrs: Vec<SocketAddr>,
+                           port_mappings| {
+            let checker = |s: &SocketAddr| ip_addr_is_global(&s.ip()) && s.port() == port;
+            if force_include_port && port != 0 && !mapped_addrs.iter().any(checker) {
+                let global_addrs: Vec<_> = mapped_addrs
+                    .iter()
+                    .filter_map(|s| {
+                        if ip_addr_is_global(&s.ip()) {
+                            let mut s = *s;
+                            s.set_port(port);
+                            Some(s)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                mapped_addrs.extend(global_addrs);
+            }
+            if let Err(e) = Self::handle_mapped_socket(
+                core,
+                poll,
+                handshake_timeout_sec,
+                socket,
+                mapped_addrs,
+                port_mappings,
+                our_uid,
+                network,
+                cm,
+                config,
+                our_listeners,
+                group.clone(),
+                token,
+                primary,
+                event_tx.clone(),
+            ) {
+                if primary {
                     error!("TCP Listener failed to handle mapped socket: {:?}", e);
-                    let _ = event_tx.send(Event::ListenerFailed);
+                    event_tx.send(Event::ListenerFailed);
+                } else {
+                    warn!("Not accepting connections on port {}: {:?}", port, e);
                 }
-            };
+            }
+            if !primary {
+                if let Some(primary_port) = group.additional_settled() {
+                    event_tx.send(Event::ListenerStarted(primary_port));
+                }
+            }
+        };
 
-        if let Err(e) = MappedTcpSocket::<_, UID>::start(core, poll, port, &mc, finish) {
-            error!("Error starting tcp_listening_socket: {:?}", e);
-            let _ = event_tx_0.send(Event::ListenerFailed);
+        if let Err(e) = MappedTcpSocket::<_, UID>::start(core, poll, port, bind_ip, &mc, finish) {
+            if primary {
+                error!("Error starting tcp_listening_socket: {:?}", e);
+                event_tx_0.send(Event::ListenerFailed);
+            } else {
+                warn!("Not accepting connections on port {}: {:?}", port, e);
+                if let Some(primary_port) = group_0.additional_settled() {
+                    event_tx_0.send(Event::ListenerStarted(primary_port));
+                }
+            }
         }
     }
 
@@ -103,21 +220,55 @@ impl<UID: Uid> ConnectionListener<UID> {
         self.accept_bootstrap = accept;
     }
 
+    /// Refuses every bootstrap request from now on, referring the peer to `alternatives`, see
+    /// `Service::begin_draining`.
+    pub fn set_retiring(&mut self, alternatives: Vec<SocketAddr>) {
+        self.retiring = Some(alternatives);
+    }
+
     fn handle_mapped_socket(
         core: &mut Core,
         poll: &Poll,
         timeout_sec: Option<u64>,
         socket: TcpBuilder,
-        mapped_addrs: Vec<SocketAddr>,
+        mut mapped_addrs: Vec<SocketAddr>,
+        port_mappings: Vec<PortMapping>,
         our_uid: UID,
-        name_hash: NameHash,
+        network: NetworkId,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
         our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+        group: ListenerGroup,
         token: Token,
-        event_tx: ::CrustEventSender<UID>,
+        primary: bool,
+        event_tx: EventSink<UID>,
     ) -> ::Res<()> {
-        let listener = socket.listen(LISTENER_BACKLOG)?;
+        let (backlog, additional_ports, lan_only, asserted, enable_utp) = {
+            let guard = unwrap!(config.lock());
+            let backlog = guard.cfg.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG);
+            (
+                backlog,
+                guard.cfg.additional_acceptor_ports.clone(),
+                guard.cfg.lan_only,
+                guard.external_endpoints.clone(),
+                guard.cfg.enable_utp,
+            )
+        };
+        if lan_only {
+            mapped_addrs.retain(|addr| !ip_addr_is_global(&addr.ip()));
+        }
+        let contradicting = contradicting_endpoints(&asserted, &mapped_addrs);
+        if !contradicting.is_empty() {
+            warn!(
+                "Detected public addresses {:?} contradict our external endpoints {:?}",
+                contradicting, asserted
+            );
+            event_tx.send(Event::ExternalEndpointMismatch {
+                asserted: asserted.clone(),
+                detected: contradicting,
+            });
+        }
+        let listener = socket.listen(cmp::min(backlog, i32::max_value() as u32) as i32)?;
         let local_addr = listener.local_addr()?;
 
         let listener = TcpListener::from_listener(listener, &local_addr)?;
@@ -128,222 +279,1084 @@ impl<UID: Uid> ConnectionListener<UID> {
             PollOpt::edge(),
         )?;
 
-        *unwrap!(our_listeners.lock()) = mapped_addrs.into_iter().collect();
+        // The primary listener replaces all of our addresses but those of the additional
+        // listeners, which only replace their own. Asserted endpoints stay ahead of them all.
+        let (old_listeners, new_listeners) = {
+            let mut listeners = unwrap!(our_listeners.lock());
+            let mut new_listeners = if primary {
+                mapped_addrs.clone()
+            } else {
+                Vec::new()
+            };
+            for addr in listeners.iter().filter(|addr| !asserted.contains(addr)) {
+                let keep = if primary {
+                    additional_ports.contains(&addr.port())
+                } else {
+                    addr.port() != local_addr.port()
+                };
+                if keep && !new_listeners.contains(addr) && !mapped_addrs.contains(addr) {
+                    new_listeners.push(*addr);
+                }
+            }
+            if !primary {
+                new_listeners.extend(mapped_addrs.iter().cloned());
+            }
+            let new_listeners = with_asserted_endpoints(&asserted, &new_listeners);
+            (
+                mem::replace(&mut *listeners, new_listeners.clone()),
+                new_listeners,
+            )
+        };
+        // Peers we are already connected to learn about the listener, e.g. after a rebind.
+        if old_listeners != new_listeners {
+            advertise_listeners(core, poll, &cm, &new_listeners);
+        }
 
-        let state = Self {
+        let state = Rc::new(RefCell::new(Self {
             token,
             cm,
             config,
             event_tx: event_tx.clone(),
             listener,
-            name_hash,
+            local_addr,
+            addrs: mapped_addrs,
+            our_listeners,
+            port_mappings,
+            network,
             our_uid,
             timeout_sec,
             accept_bootstrap: false,
-        };
+            retiring: None,
+            group: group.clone(),
+            parked: VecDeque::new(),
+            fd_soft_limit: fd_soft_limit(),
+            reserve_fd: ReserveFd::new(),
+            paused: false,
+            primary,
+            shim: IoShim::default(),
+            #[cfg(feature = "utp")]
+            utp: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+            self_weak: Weak::new(),
+        }));
+
+        state.borrow_mut().self_weak = Rc::downgrade(&state);
+        if enable_utp {
+            #[cfg(feature = "utp")]
+            {
+                let utp = Self::listen_utp(core, poll, &local_addr, Rc::downgrade(&state));
+                state.borrow_mut().utp = utp;
+            }
+        }
+        #[cfg(feature = "websocket")]
+        {
+            let websocket_port = unwrap!(state.borrow().config.lock()).cfg.websocket_port;
+            if let (true, Some(port)) = (primary, websocket_port) {
+                let addr = SocketAddr::new(local_addr.ip(), port);
+                let websocket = Self::listen_ws(core, poll, &addr, Rc::downgrade(&state));
+                state.borrow_mut().websocket = websocket;
+            }
+        }
 
-        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
-        let _ = event_tx.send(Event::ListenerStarted(local_addr.port()));
+        state.borrow_mut().schedule_mapping_renewal(core);
+        let _ = core.insert_state(token, state);
+        group.join(token);
+        if primary && group.primary_started(local_addr.port()) {
+            event_tx.send(Event::ListenerStarted(local_addr.port()));
+        }
 
         Ok(())
     }
 
-    fn accept(&self, core: &mut Core, poll: &Poll) {
+    /// Returns whether `addr` is one of the addresses this listener accepts connections on.
+    pub fn listens_on(&self, addr: &SocketAddr) -> bool {
+        if self.addrs.contains(addr) {
+            return true;
+        }
+        addr.port() == self.local_addr.port()
+            && (self.local_addr.ip().is_unspecified() || addr.ip() == self.local_addr.ip())
+    }
+
+    /// Stops accepting connections and withdraws the listener's addresses from those we advertise
+    /// and from the gateways which mapped them. Connections accepted already carry on, even
+    /// those still waiting for a handshake slot.
+    pub fn stop(&mut self, core: &mut Core, poll: &Poll) {
+        let parked = mem::replace(&mut self.parked, VecDeque::new());
+        self.group.unpark(core, parked.len());
+        for parked in parked {
+            let _ = poll.deregister(&parked.socket);
+            let _ = core.remove_state(parked.token);
+            self.start_handshake(core, poll, parked.socket);
+        }
+
+        let asserted = unwrap!(self.config.lock()).external_endpoints.clone();
+        let new_listeners = {
+            let mut listeners = unwrap!(self.our_listeners.lock());
+            let addrs = &self.addrs;
+            listeners.retain(|addr| !addrs.contains(addr) || asserted.contains(addr));
+            listeners.clone()
+        };
+        advertise_listeners(core, poll, &self.cm, &new_listeners);
+        for mapping in self.port_mappings.drain(..) {
+            mapping.remove();
+        }
+
+        self.terminate(core, poll);
+    }
+
+    /// Replaces the listening socket by a new one set up with `options`, on the same address.
+    /// Where two sockets may listen on the same port, see `SO_REUSEPORT`, the new one listens
+    /// before the old one is closed, so that no connection is refused meanwhile. Elsewhere the old
+    /// one has to be closed first, and connections are refused until the new one listens. Either
+    /// way, those waiting in the backlog of the old socket are accepted as usual before it is
+    /// closed. If the new socket can't listen after the old one was closed, the listener fails.
+    pub fn restart(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        options: &ListenerOptions,
+    ) -> io::Result<()> {
+        let backlog = match options.backlog {
+            Some(backlog) => backlog,
+            None => unwrap!(self.config.lock())
+                .cfg
+                .listen_backlog
+                .unwrap_or(DEFAULT_LISTEN_BACKLOG),
+        };
+        let backlog = cmp::min(backlog, i32::max_value() as u32) as i32;
+        let socket = new_reusable_tcp_socket(&self.local_addr)?;
+        if let Some(ttl) = options.ttl {
+            let _ = socket.ttl(ttl)?;
+        }
+
+        let (old, listening) = match socket.bind(&self.local_addr) {
+            Ok(_) => {
+                let listener = socket.listen(backlog)?;
+                let listener = TcpListener::from_listener(listener, &self.local_addr)?;
+                (mem::replace(&mut self.listener, listener), true)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+                debug!(
+                    "Can't listen on {} twice. Refusing connections while restarting.",
+                    self.local_addr
+                );
+                // Shares the new socket, which is bound and listens once the old one is closed.
+                let listener =
+                    TcpListener::from_listener(socket.to_tcp_listener()?, &self.local_addr)?;
+                (mem::replace(&mut self.listener, listener), false)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let _ = poll.deregister(&old);
+        self.accept_backlog(core, poll, &old);
+        drop(old);
+        let res = if listening {
+            Ok(())
+        } else {
+            socket.bind(&self.local_addr).and_then(|_| socket.listen(backlog)).map(|_| ())
+        };
+        let res = res.and_then(|()| {
+            if self.paused {
+                // Registered once the listener resumes.
+                return Ok(());
+            }
+            poll.register(
+                &self.listener,
+                self.token,
+                Ready::readable() | Ready::error() | Ready::hup(),
+                PollOpt::edge(),
+            )
+        });
+        if let Err(ref e) = res {
+            error!("Failed to restart the listener on {}: {:?}", self.local_addr, e);
+            self.fail(core, poll);
+        }
+        res
+    }
+
+    /// Accepts the connections waiting in the backlog of `listener`, which is about to be closed.
+    fn accept_backlog(&mut self, core: &mut Core, poll: &Poll, listener: &TcpListener) {
         loop {
-            match self.listener.accept() {
-                Ok((socket, _)) => {
-                    if let Err(e) = ExchangeMsg::start(
-                        core,
-                        poll,
-                        self.timeout_sec,
-                        Socket::wrap(socket),
-                        self.accept_bootstrap,
-                        self.our_uid,
-                        self.name_hash,
-                        self.cm.clone(),
-                        self.config.clone(),
-                        self.event_tx.clone(),
-                    ) {
-                        debug!("Error accepting direct connection: {:?}", e);
-                    }
+            match listener.accept() {
+                Ok((socket, addr)) => {
+                    let _ = self.handle_accepted(core, poll, Socket::wrap(socket), addr);
                 }
-                Err(ref e)
-                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted =>
-                {
-                    return
+                Err(e) => match IoErrorClass::of(&e) {
+                    IoErrorClass::WouldBlock => return,
+                    IoErrorClass::Retry | IoErrorClass::RemoteClosed => {
+                        trace!("Failed to accept new socket: {:?}", e)
+                    }
+                    IoErrorClass::Fatal => {
+                        debug!("Failed to accept new socket: {:?}", e);
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    fn accept(&mut self, core: &mut Core, poll: &Poll) {
+        let batch_size = unwrap!(self.config.lock())
+            .cfg
+            .accept_batch_size
+            .unwrap_or(DEFAULT_ACCEPT_BATCH_SIZE);
+        core.stats_mut().accept_batches += 1;
+
+        let mut connections = self.connections_in_use(core);
+        for _ in 0..cmp::max(1, batch_size) {
+            if self.over_connection_limit(connections) {
+                return self.pause(core, poll, connections);
+            }
+            if over_memory_budget(core) {
+                return self.pause_accepting(core, poll, None);
+            }
+            match self.accept_one() {
+                Ok((socket, addr)) => {
+                    if self.handle_accepted(core, poll, Socket::wrap(socket), addr) {
+                        connections += 1;
+                    }
                 }
-                Err(ref e) => {
-                    debug!("Failed to accept new socket: {:?}", e);
-                    return;
+                Err(ref e) if is_fd_exhaustion(e) => {
+                    return self.recover_from_fd_exhaustion(core, poll);
                 }
+                Err(e) => match IoErrorClass::of(&e) {
+                    IoErrorClass::WouldBlock => return,
+                    // Interrupted, or a connection which was reset or aborted, e.g. with
+                    // `ECONNABORTED`, while in the backlog. Only that connection is lost, and the
+                    // next ones are accepted as usual.
+                    IoErrorClass::Retry | IoErrorClass::RemoteClosed => {
+                        trace!("Failed to accept new socket: {:?}", e)
+                    }
+                    // Left for the next readable event rather than tried again right away, as
+                    // the error may well come back.
+                    IoErrorClass::Fatal => {
+                        debug!("Failed to accept new socket: {:?}", e);
+                        return;
+                    }
+                },
             }
         }
-    }
-}
 
-impl<UID: Uid> State for ConnectionListener<UID> {
-    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
-        if kind.is_error() || kind.is_hup() {
-            self.terminate(core, poll);
-            let _ = self.event_tx.send(Event::ListenerFailed);
-        } else if kind.is_readable() {
-            self.accept(core, poll);
+        // The batch limit cut us short. Re-registering raises a new readable event, which is only
+        // handled once the other pending events have had their turn.
+        if let Err(e) = poll.reregister(
+            &self.listener,
+            self.token,
+            Ready::readable() | Ready::error() | Ready::hup(),
+            PollOpt::edge(),
+        ) {
+            debug!("Failed to re-register listener: {:?}", e);
         }
     }
 
-    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        let _ = poll.deregister(&self.listener);
-        let _ = core.remove_state(self.token);
+    /// Starts the handshake on a connection just accepted, or parks it until there is a slot for
+    /// it. Returns whether the connection was kept, rather than dropped for coming from a banned
+    /// address.
+    fn handle_accepted(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        socket: Socket,
+        addr: SocketAddr,
+    ) -> bool {
+        if core.bans().is_banned(&addr.ip(), core.now()) {
+            debug!("Dropping connection from banned {}", addr);
+            core.stats_mut().connections_banned += 1;
+            self.event_tx.send(Event::PeerRejected {
+                addr,
+                peer_id: None,
+                reason: RejectionCode::Banned,
+            });
+            return false;
+        }
+        core.stats_mut().connections_accepted += 1;
+        if self.has_free_handshake_slot() {
+            self.start_handshake(core, poll, socket);
+        } else {
+            self.park(core, poll, socket);
+        }
+        true
     }
 
-    fn as_any(&mut self) -> &mut Any {
-        self
+    /// Accepts uTP connections at `local_addr`, handling them like those accepted over TCP. They
+    /// have no backlog to wait in while we don't accept, so they are dropped instead. Returns the
+    /// token of the endpoint, or `None` if UDP can't be bound there, which only costs us uTP.
+    #[cfg(feature = "utp")]
+    fn listen_utp(
+        core: &mut Core,
+        poll: &Poll,
+        local_addr: &SocketAddr,
+        listener: Weak<RefCell<Self>>,
+    ) -> Option<Token> {
+        let accept = move |core: &mut Core, poll: &Poll, stream: UtpStream| {
+            let listener = match listener.upgrade() {
+                Some(listener) => listener,
+                None => return,
+            };
+            let mut listener = listener.borrow_mut();
+            let addr = match stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => return,
+            };
+            let connections = listener.connections_in_use(core);
+            if listener.paused
+                || listener.over_connection_limit(connections)
+                || over_memory_budget(core)
+            {
+                debug!("Not accepting uTP connection from {} for now", addr);
+                return;
+            }
+            let _ = listener.handle_accepted(core, poll, Socket::wrap_utp(stream), addr);
+        };
+        match UtpEndpoint::listen(core, poll, local_addr, Box::new(accept)) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                warn!("Not accepting uTP connections on {}: {:?}", local_addr, e);
+                None
+            }
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::exchange_msg::EXCHANGE_MSG_TIMEOUT_SEC;
-    use super::*;
-    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-    use common::{
-        self, CoreMessage, CrustUser, EventLoop, ExternalReachability, Message, NameHash, HASH_SIZE,
-    };
-    use maidsafe_utilities::event_sender::MaidSafeEventCategory;
-    use maidsafe_utilities::serialisation::{deserialise, serialise};
-    use main::Event;
-    use mio::Token;
-    use nat::MappingContext;
-    use rand;
-    use serde::de::DeserializeOwned;
-    use serde::ser::Serialize;
-    use std::collections::HashMap;
-    use std::io::{Cursor, Read, Write};
-    use std::mem;
-    use std::net::SocketAddr as StdSocketAddr;
-    use std::net::TcpStream;
-    use std::sync::mpsc;
-    use std::sync::{Arc, Mutex};
-    use std::time::Duration;
-    use tests::UniqueId;
 
-    type ConnectionListener = super::ConnectionListener<UniqueId>;
+    /// Accepts WebSocket connections at `addr`, handling them like those accepted over TCP once
+    /// their opening handshake is over. Those which wouldn't be accepted then are dropped before
+    /// it, see `admits_ws`. Returns the token of the endpoint, or `None` if the port can't be
+    /// bound, which only costs us the peers which can't connect otherwise.
+    #[cfg(feature = "websocket")]
+    fn listen_ws(
+        core: &mut Core,
+        poll: &Poll,
+        addr: &SocketAddr,
+        listener: Weak<RefCell<Self>>,
+    ) -> Option<Token> {
+        let admitting = listener.clone();
+        let admit = move |core: &mut Core, addr: &SocketAddr| {
+            let listener = match admitting.upgrade() {
+                Some(listener) => listener,
+                None => return false,
+            };
+            let listener = listener.borrow();
+            listener.admits_ws(core, addr)
+        };
+        let accept = move |core: &mut Core, poll: &Poll, stream: WsStream| {
+            let listener = match listener.upgrade() {
+                Some(listener) => listener,
+                None => return,
+            };
+            let mut listener = listener.borrow_mut();
+            let addr = match stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => return,
+            };
+            let connections = listener.connections_in_use(core);
+            if listener.paused
+                || listener.over_connection_limit(connections)
+                || over_memory_budget(core)
+            {
+                debug!("Not accepting WebSocket connection from {} for now", addr);
+                return;
+            }
+            let _ = listener.handle_accepted(core, poll, Socket::wrap_ws(stream), addr);
+        };
+        match WsEndpoint::listen(core, poll, addr, Box::new(admit), Box::new(accept)) {
+            Ok((token, local_addr)) => {
+                info!("Accepting WebSocket connections on {}", local_addr);
+                Some(token)
+            }
+            Err(e) => {
+                warn!("Not accepting WebSocket connections on {}: {:?}", addr, e);
+                None
+            }
+        }
+    }
 
-    // Make sure this is < EXCHANGE_MSG_TIMEOUT_SEC else blocking reader socket in this test will
-    // exit with an EAGAIN error (unless this is what is wanted).
-    const HANDSHAKE_TIMEOUT_SEC: u64 = 5;
-    const LISTENER_TOKEN: usize = 0;
-    const NAME_HASH: NameHash = [1; HASH_SIZE];
-    const NAME_HASH_2: NameHash = [2; HASH_SIZE];
+    /// Whether a WebSocket connection from `addr` is worth its opening handshake: we accept
+    /// connections for now, and the address is neither banned nor left out by both whitelists.
+    /// Whether the peer is a node or a client is only known once the handshake is over.
+    #[cfg(feature = "websocket")]
+    fn admits_ws(&self, core: &mut Core, addr: &SocketAddr) -> bool {
+        let connections = self.connections_in_use(core);
+        if self.paused || self.over_connection_limit(connections) || over_memory_budget(core) {
+            debug!("Not accepting WebSocket connection from {} for now", addr);
+            return false;
+        }
+        if core.bans().is_banned(&addr.ip(), core.now()) {
+            debug!("Dropping WebSocket connection from banned {}", addr);
+            core.stats_mut().connections_banned += 1;
+            self.event_tx.send(Event::PeerRejected {
+                addr: *addr,
+                peer_id: None,
+                reason: RejectionCode::Banned,
+            });
+            return false;
+        }
+        let config = unwrap!(self.config.lock());
+        let (whitelists, ip) = (&config.whitelists, addr.ip());
+        if !whitelists.allows(CrustUser::Node, &ip) && !whitelists.allows(CrustUser::Client, &ip) {
+            debug!("Dropping WebSocket connection from {}: not whitelisted", addr);
+            return false;
+        }
+        true
+    }
 
-    struct Listener {
-        _el: EventLoop,
-        uid: UniqueId,
-        addr: SocketAddr,
-        event_rx: mpsc::Receiver<Event<UniqueId>>,
+    /// Returns the number of connections we have open or in their handshake, each of which
+    /// holds a file descriptor.
+    fn connections_in_use(&self, core: &Core) -> usize {
+        let active = unwrap!(self.cm.lock())
+            .values()
+            .filter(|cid| cid.active_connection.is_some())
+            .count();
+        active + core.pending_count() + self.group.parked()
     }
 
-    fn start_listener(accept_bootstrap: bool) -> Listener {
-        let el = unwrap!(common::spawn_event_loop(
-            LISTENER_TOKEN + 1,
-            Some("Connection Listener Test"),
-        ));
+    fn over_connection_limit(&self, connections: usize) -> bool {
+        let config = unwrap!(self.config.lock());
+        max_connections(&config.cfg, self.fd_soft_limit).map_or(false, |max| connections >= max)
+    }
 
-        let (event_tx, event_rx) = mpsc::channel();
-        let crust_sender =
-            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+    /// Stops accepting connections until enough of them have closed, see `check_resume`. The
+    /// pending ones wait in the listen backlog. Reports `Event::ResourceLow` if it is the file
+    /// descriptors which run out, rather than `Config::max_peers` being reached.
+    fn pause(&mut self, core: &mut Core, poll: &Poll, connections: usize) {
+        let available = {
+            let config = unwrap!(self.config.lock());
+            match fd_limit(&config.cfg, self.fd_soft_limit) {
+                Some(limit)
+                    if config.cfg.max_peers.map_or(true, |max_peers| {
+                        limit.saturating_sub(FD_SAFETY_MARGIN) <= max_peers
+                    }) =>
+                {
+                    Some(limit.saturating_sub(connections))
+                }
+                _ => None,
+            }
+        };
+        self.pause_accepting(core, poll, available);
+    }
 
-        let cm = Arc::new(Mutex::new(HashMap::new()));
-        let mc = Arc::new(unwrap!(MappingContext::new(), "Could not get MC"));
-        let config = Arc::new(Mutex::new(Default::default()));
-        let listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
+    fn pause_accepting(&mut self, core: &mut Core, poll: &Poll, fds_available: Option<usize>) {
+        if self.paused {
+            return;
+        }
+        if !self.schedule_resource_check(core) {
+            return;
+        }
+        self.paused = true;
+        let _ = poll.deregister(&self.listener);
 
-        let listeners_clone = listeners.clone();
-        let uid = rand::random();
-        unwrap!(
-            el.send(CoreMessage::new(move |core, poll| {
-                ConnectionListener::start(
-                    core,
-                    poll,
-                    Some(HANDSHAKE_TIMEOUT_SEC),
-                    0,
-                    false,
-                    uid,
-                    NAME_HASH,
-                    cm,
-                    config,
-                    mc,
-                    listeners_clone,
-                    Token(LISTENER_TOKEN),
-                    crust_sender,
+        match fds_available {
+            Some(available) => {
+                warn!(
+                    "Running out of file descriptors, {} left. Not accepting connections for now.",
+                    available
                 );
-            })),
-            "Could not send to tx"
-        );
-
-        for it in event_rx.iter() {
-            match it {
-                Event::ListenerStarted(_port) => break,
-                _ => panic!("Unexpected event notification - {:?}", it),
+                if self.primary {
+                    self.event_tx.send(Event::ResourceLow {
+                        kind: ResourceKind::FileDescriptors,
+                        available,
+                    });
+                }
             }
+            None if over_memory_budget(core) => {
+                debug!("Running out of memory. Not accepting connections for now.")
+            }
+            None => debug!("Too many peers. Not accepting connections for now."),
         }
+    }
 
-        let (tx, rx) = mpsc::channel();
-        unwrap!(
-            el.send(CoreMessage::new(move |core, _| {
-                let state = match core.get_state(Token(LISTENER_TOKEN)) {
-                    Some(state) => state,
-                    None => panic!("Listener not initialised"),
-                };
-                let mut state = state.borrow_mut();
-                let listener = match state.as_any().downcast_mut::<ConnectionListener>() {
-                    Some(l) => l,
-                    None => panic!("Token reserved for ConnectionListener has something else."),
-                };
-                listener.set_accept_bootstrap(accept_bootstrap);
-                unwrap!(tx.send(()));
-            })),
-            "Could not send to tx"
-        );
-        unwrap!(rx.recv());
-
-        let addr = unwrap!(listeners.lock())[0];
-
-        Listener {
-            _el: el,
-            uid,
-            addr,
-            event_rx,
+    /// Returns whether the check was scheduled.
+    fn schedule_resource_check(&mut self, core: &mut Core) -> bool {
+        match core.set_timeout(
+            Duration::from_millis(RESOURCE_CHECK_INTERVAL_MS),
+            CoreTimer::new(self.token, RESOURCE_CHECK_TIMER_ID),
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Could not schedule resuming the listener: {:?}", e);
+                false
+            }
         }
     }
 
-    fn connect_to_listener(listener: &Listener) -> TcpStream {
-        let listener_addr = StdSocketAddr::new(listener.addr.ip(), listener.addr.port());
-        let stream = unwrap!(
-            TcpStream::connect(listener_addr),
-            "Could not connect to listener"
-        );
-        unwrap!(
-            stream.set_read_timeout(Some(Duration::from_secs(EXCHANGE_MSG_TIMEOUT_SEC + 1))),
-            "Could not set read timeout."
-        );
+    /// Listens for connections again once enough of them have closed, and there is memory for
+    /// more. Those which queued up in the backlog meanwhile make the listener readable straight
+    /// away.
+    fn check_resume(&mut self, core: &mut Core, poll: &Poll) {
+        let connections = self.connections_in_use(core);
+        if (self.over_connection_limit(connections) || over_memory_budget(core))
+            && self.schedule_resource_check(core)
+        {
+            return;
+        }
 
-        stream
+        self.paused = false;
+        if let Err(e) = poll.register(
+            &self.listener,
+            self.token,
+            Ready::readable() | Ready::error() | Ready::hup(),
+            PollOpt::edge(),
+        ) {
+            debug!("Failed to re-register listener: {:?}", e);
+        }
     }
 
-    fn write(stream: &mut TcpStream, message: &[u8]) -> ::Res<()> {
-        let mut size_vec = Vec::with_capacity(mem::size_of::<u32>());
-        unwrap!(size_vec.write_u32::<LittleEndian>(message.len() as u32));
-
-        stream.write_all(&size_vec)?;
-        stream.write_all(message)?;
+    fn accept_one(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.shim.check(IoSite::Accept)?;
+        self.listener.accept()
+    }
 
-        Ok(())
+    /// Makes the next accept fail with the given error, see `IoShim`.
+    #[cfg(test)]
+    fn inject_fault(&mut self, kind: io::ErrorKind) {
+        self.shim.inject(IoSite::Accept, kind);
     }
 
-    #[allow(unsafe_code)]
-    fn read<T: DeserializeOwned + Serialize>(stream: &mut TcpStream) -> ::Res<T> {
-        let mut payload_size_buffer = [0; 4];
+    /// Called when `accept` failed because we are out of file descriptors. The connection it
+    /// couldn't accept would keep the listener readable, so one descriptor is freed from the
+    /// reserve to accept it and close it straight away. We then stop accepting for a while.
+    fn recover_from_fd_exhaustion(&mut self, core: &mut Core, poll: &Poll) {
+        if self.reserve_fd.release() {
+            match self.accept_one() {
+                Ok((socket, _)) => {
+                    debug!("Out of file descriptors. Dropping new connection.");
+                    drop(socket);
+                }
+                Err(e) => debug!("Failed to accept new socket: {:?}", e),
+            }
+            self.reserve_fd.refill();
+        }
+        self.pause_accepting(core, poll, Some(0));
+    }
+
+    fn has_free_handshake_slot(&self) -> bool {
+        match unwrap!(self.config.lock()).cfg.max_concurrent_inbound_handshakes {
+            Some(max) => self.group.active_handshakes() < max,
+            None => true,
+        }
+    }
+
+    /// Starts the handshake of an accepted connection in a slot of its own. A bare TCP connection
+    /// may speak any of the protocols we take on our port, which its first bytes tell, see
+    /// `Sniff`.
+    fn start_handshake(&mut self, core: &mut Core, poll: &Poll, socket: Socket) {
+        let started = if socket.is_tcp() {
+            match Sniff::start(core, poll, socket, self.self_weak.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Error accepting direct connection: {:?}", e);
+                    false
+                }
+            }
+        } else {
+            self.exchange_msg(core, poll, socket)
+        };
+        if started {
+            self.group.handshake_started(core);
+        }
+    }
+
+    /// Goes on with a connection in its handshake slot once `Sniff` told the protocol it speaks.
+    /// The slot is freed if it speaks none.
+    pub fn handle_sniffed(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        socket: Socket,
+        protocol: Option<Protocol>,
+    ) {
+        match protocol {
+            Some(Protocol::Crust) => {
+                if !self.exchange_msg(core, poll, socket) {
+                    self.handle_handshake_done(core, poll);
+                }
+            }
+            #[cfg(feature = "websocket")]
+            Some(Protocol::WebSocket) => self.upgrade_ws(core, poll, socket),
+            None => self.handle_handshake_done(core, poll),
+        }
+    }
+
+    /// Opens a WebSocket connection over a connection accepted on our port, which keeps its
+    /// handshake slot until the opening handshake is over. Crust's handshake then goes over it in
+    /// a slot of its own, like over those accepted on `Config::websocket_port`.
+    #[cfg(feature = "websocket")]
+    fn upgrade_ws(&mut self, core: &mut Core, poll: &Poll, socket: Socket) {
+        let (addr, stream) = match (socket.peer_addr(), socket.into_tcp()) {
+            (Ok(addr), Some(stream)) => (addr, stream),
+            _ => return self.handle_handshake_done(core, poll),
+        };
+        let listener = self.self_weak.clone();
+        let done = move |core: &mut Core, poll: &Poll, _: Token, stream: Option<WsStream>| {
+            let listener = match listener.upgrade() {
+                Some(listener) => listener,
+                None => return,
+            };
+            let mut listener = listener.borrow_mut();
+            listener.handle_handshake_done(core, poll);
+            if let Some(stream) = stream {
+                let _ = listener.handle_accepted(core, poll, Socket::wrap_ws(stream), addr);
+            }
+        };
+        if let Err(e) = WsEndpoint::upgrade_accepted(core, poll, stream, addr, Box::new(done)) {
+            debug!("Failed to start WebSocket handshake with {}: {:?}", addr, e);
+            self.handle_handshake_done(core, poll);
+        }
+    }
+
+    /// Starts Crust's handshake on an accepted connection, returning whether it started.
+    fn exchange_msg(&mut self, core: &mut Core, poll: &Poll, socket: Socket) -> bool {
+        let self_weak = self.self_weak.clone();
+        let finish = move |core: &mut Core, poll: &Poll| {
+            if let Some(self_rc) = self_weak.upgrade() {
+                self_rc.borrow_mut().handle_handshake_done(core, poll);
+            }
+        };
+
+        match ExchangeMsg::start(
+            core,
+            poll,
+            self.timeout_sec,
+            socket,
+            self.accept_bootstrap,
+            self.retiring.clone(),
+            self.our_uid,
+            self.network,
+            self.cm.clone(),
+            self.config.clone(),
+            self.event_tx.clone(),
+            Box::new(finish),
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Error accepting direct connection: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Frees the slot of a handshake which finished, for a connection parked by this listener or
+    /// else by another one of the group.
+    fn handle_handshake_done(&mut self, core: &mut Core, poll: &Poll) {
+        self.group.handshake_done(core);
+        self.admit_parked(core, poll);
+
+        for token in self.group.listeners() {
+            if token == self.token || !self.has_free_handshake_slot() {
+                continue;
+            }
+            let other = match core.get_state(token) {
+                Some(other) => other,
+                None => continue,
+            };
+            let mut other = match other.try_borrow_mut() {
+                Ok(other) => other,
+                Err(_) => continue,
+            };
+            if let Some(listener) = other.as_any().downcast_mut::<Self>() {
+                listener.admit_parked(core, poll);
+            }
+        }
+    }
+
+    /// Starts the handshakes of the connections parked longest, as long as there are slots.
+    fn admit_parked(&mut self, core: &mut Core, poll: &Poll) {
+        while self.has_free_handshake_slot() {
+            let parked = match self.parked.pop_front() {
+                Some(parked) => parked,
+                None => break,
+            };
+            self.group.unpark(core, 1);
+            let _ = poll.deregister(&parked.socket);
+            let _ = core.remove_state(parked.token);
+            self.start_handshake(core, poll, parked.socket);
+        }
+    }
+
+    fn park(&mut self, core: &mut Core, poll: &Poll, socket: Socket) {
+        if self.group.parked() >= MAX_PARKED_HANDSHAKES {
+            debug!("Too many connections waiting for a handshake slot. Dropping new connection.");
+            core.stats_mut().handshakes_rejected += 1;
+            return;
+        }
+
+        let token = core.get_new_token();
+        if let Err(e) = poll.register(
+            &socket,
+            token,
+            Ready::error() | Ready::hup(),
+            PollOpt::edge(),
+        ) {
+            debug!("Error parking direct connection: {:?}", e);
+            return;
+        }
+        ParkedHandshake::start(core, token, self.self_weak.clone());
+        core.record(token, RecordedEventKind::HandshakeParked);
+
+        if !core.has_timeout(self.token, PARKED_EXPIRY_TIMER_ID) {
+            self.schedule_parked_expiry(core, Duration::from_secs(PARKED_HANDSHAKE_EXPIRY_SEC));
+        }
+
+        self.parked.push_back(ParkedSocket {
+            token,
+            socket,
+            parked_at: core.now(),
+        });
+        self.group.park(core);
+    }
+
+    /// Drops a parked connection whose peer hung up before it got a handshake slot.
+    pub fn drop_parked(&mut self, core: &mut Core, poll: &Poll, token: Token) {
+        if let Some(pos) = self.parked.iter().position(|parked| parked.token == token) {
+            if let Some(parked) = self.parked.remove(pos) {
+                trace!("Parked connection hung up while waiting for a handshake slot.");
+                discard_parked(core, poll, parked);
+                self.group.unpark(core, 1);
+            }
+        }
+    }
+
+    fn fail(&mut self, core: &mut Core, poll: &Poll) {
+        self.terminate(core, poll);
+        if self.primary {
+            self.event_tx.send(Event::ListenerFailed);
+        }
+    }
+
+    fn schedule_mapping_renewal(&mut self, core: &mut Core) {
+        let after = match self.port_mappings.iter().map(PortMapping::renew_after).min() {
+            Some(after) => after,
+            None => return,
+        };
+        let timer = CoreTimer::new(self.token, MAPPING_RENEWAL_TIMER_ID);
+        if let Err(e) = core.set_timeout(after, timer) {
+            debug!("Could not schedule renewal of port mappings: {:?}", e);
+        }
+    }
+
+    /// Renews every mapping of our port, as they are all about as old. Each comes back once the
+    /// gateway answered, see `handle_renewed_mapping`.
+    fn renew_mappings(&mut self, core: &mut Core) {
+        let token = self.token;
+        for mapping in self.port_mappings.drain(..) {
+            let old_addr = mapping.ext_addr();
+            mapping.renew(core.sender().clone(), move |core, poll, renewed| {
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(listener) = state.as_any().downcast_mut::<Self>() {
+                        return listener.handle_renewed_mapping(core, poll, old_addr, renewed);
+                    }
+                }
+                // The listener stopped meanwhile.
+                if let Some(mapping) = renewed {
+                    mapping.remove();
+                }
+            });
+        }
+    }
+
+    /// Takes a mapping back from renewal. If the gateway moved it to another external address, or
+    /// no longer maps our port, our connection info and the peers connected to us learn about it.
+    fn handle_renewed_mapping(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        old_addr: SocketAddr,
+        renewed: Option<PortMapping>,
+    ) {
+        let new_addr = renewed.as_ref().map(PortMapping::ext_addr);
+        if new_addr != Some(old_addr) {
+            debug!("Port mapping of {} moved to {:?}", old_addr, new_addr);
+            let asserted = unwrap!(self.config.lock()).external_endpoints.clone();
+            self.addrs.retain(|addr| *addr != old_addr);
+            self.addrs.extend(new_addr);
+            let new_listeners = {
+                let mut listeners = unwrap!(self.our_listeners.lock());
+                listeners.retain(|addr| *addr != old_addr || asserted.contains(addr));
+                if let Some(new_addr) = new_addr {
+                    if !listeners.contains(&new_addr) {
+                        listeners.push(new_addr);
+                    }
+                }
+                listeners.clone()
+            };
+            advertise_listeners(core, poll, &self.cm, &new_listeners);
+        }
+        if let Some(mapping) = renewed {
+            self.port_mappings.push(mapping);
+            if !core.has_timeout(self.token, MAPPING_RENEWAL_TIMER_ID) {
+                self.schedule_mapping_renewal(core);
+            }
+        }
+    }
+
+    fn schedule_parked_expiry(&mut self, core: &mut Core, after: Duration) {
+        let timer = CoreTimer::new(self.token, PARKED_EXPIRY_TIMER_ID);
+        if let Err(e) = core.set_timeout(after, timer) {
+            debug!("Could not schedule expiry of parked connections: {:?}", e);
+        }
+    }
+}
+
+/// Whether the memory budget is too short to take on more connections, see
+/// `MemoryPressure::RejectAccepts`.
+fn over_memory_budget(core: &Core) -> bool {
+    core.memory_budget().pressure() >= MemoryPressure::RejectAccepts
+}
+
+fn discard_parked(core: &mut Core, poll: &Poll, parked: ParkedSocket) {
+    let _ = poll.deregister(&parked.socket);
+    let _ = core.remove_state(parked.token);
+}
+
+impl<UID: Uid> State for ConnectionListener<UID> {
+    fn name(&self) -> &'static str {
+        "ConnectionListener"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.fail(core, poll);
+        } else if kind.is_readable() {
+            self.accept(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.group.unpark(core, self.parked.len());
+        for parked in self.parked.drain(..) {
+            discard_parked(core, poll, parked);
+        }
+        self.group.leave(self.token);
+
+        #[cfg(feature = "utp")]
+        {
+            if let Some(utp) = self.utp.take() {
+                UtpEndpoint::stop_listening(core, poll, utp);
+            }
+        }
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(websocket) = self.websocket.take() {
+                WsEndpoint::stop(core, poll, websocket);
+            }
+        }
+
+        let _ = poll.deregister(&self.listener);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u64) {
+        if timer_id == RESOURCE_CHECK_TIMER_ID {
+            return self.check_resume(core, poll);
+        }
+        if timer_id == MAPPING_RENEWAL_TIMER_ID {
+            return self.renew_mappings(core);
+        }
+
+        let expiry = Duration::from_secs(PARKED_HANDSHAKE_EXPIRY_SEC);
+        let now = core.now();
+        while self
+            .parked
+            .front()
+            .map_or(false, |parked| now - parked.parked_at >= expiry)
+        {
+            if let Some(parked) = self.parked.pop_front() {
+                debug!("Connection waited too long for a handshake slot. Dropping it.");
+                core.record(parked.token, RecordedEventKind::HandshakeExpired);
+                discard_parked(core, poll, parked);
+                self.group.unpark(core, 1);
+                core.stats_mut().handshakes_expired += 1;
+            }
+        }
+
+        let next_expiry = self.parked.front().map(|parked| {
+            expiry
+                .checked_sub(now - parked.parked_at)
+                .unwrap_or_else(|| Duration::from_secs(0))
+        });
+        if let Some(next_expiry) = next_expiry {
+            self.schedule_parked_expiry(core, next_expiry);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::exchange_msg::EXCHANGE_MSG_TIMEOUT_SEC;
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+    use common::{
+        self, offer_extensions, take_extension_answers, BootstrapDenyReason, CoreMessage,
+        CoreStats, CrustUser, EncryptionExtension, EventLoop, Extension, Extensions,
+        ExternalReachability, FrameCipher, KeyPair, Message, NameHash, NetworkKey, PowChallenge,
+        Rejection, RejectionCode, RoleExtension, HASH_SIZE,
+    };
+    use maidsafe_utilities::event_sender::MaidSafeEventCategory;
+    use maidsafe_utilities::serialisation::{deserialise, serialise};
+    use main::{Config, ConfigWrapper, Event};
+    use mio::Token;
+    use nat::MappingContext;
+    use rand;
+    use serde::de::DeserializeOwned;
+    use serde::ser::Serialize;
+    use std::collections::{HashMap, HashSet};
+    use std::io::{Cursor, Read, Write};
+    use std::mem;
+    use std::net::IpAddr;
+    use std::net::SocketAddr as StdSocketAddr;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use tests::UniqueId;
+
+    type ConnectionListener = super::ConnectionListener<UniqueId>;
+
+    // Make sure this is < EXCHANGE_MSG_TIMEOUT_SEC else blocking reader socket in this test will
+    // exit with an EAGAIN error (unless this is what is wanted).
+    const HANDSHAKE_TIMEOUT_SEC: u64 = 5;
+    const LISTENER_TOKEN: usize = 0;
+    const NAME_HASH: NameHash = [1; HASH_SIZE];
+    const NAME_HASH_2: NameHash = [2; HASH_SIZE];
+
+    struct Listener {
+        el: EventLoop,
+        uid: UniqueId,
+        addr: SocketAddr,
+        event_rx: mpsc::Receiver<Event<UniqueId>>,
+    }
+
+    fn start_listener(accept_bootstrap: bool) -> Listener {
+        start_listener_with_config(accept_bootstrap, Config::default())
+    }
+
+    fn start_listener_with_config(accept_bootstrap: bool, config: Config) -> Listener {
+        start_listener_on_network(accept_bootstrap, config, NetworkId::Plain(NAME_HASH))
+    }
+
+    fn start_listener_on_network(
+        accept_bootstrap: bool,
+        config: Config,
+        network: NetworkId,
+    ) -> Listener {
+        let el = unwrap!(common::spawn_event_loop(
+            LISTENER_TOKEN + 1,
+            Some("Connection Listener Test"),
+        ));
+
+        let (event_tx, event_rx) = mpsc::channel();
+        let crust_sender =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let crust_sender = EventSink::new(crust_sender, el.sender());
+
+        let cm = Arc::new(Mutex::new(HashMap::new()));
+        let mc = Arc::new(unwrap!(MappingContext::new(), "Could not get MC"));
+        let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
+        let listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
+
+        let listeners_clone = listeners.clone();
+        let uid = rand::random();
+        unwrap!(
+            el.send(CoreMessage::new(move |core, poll| {
+                ConnectionListener::start(
+                    core,
+                    poll,
+                    Some(HANDSHAKE_TIMEOUT_SEC),
+                    0,
+                    false,
+                    uid,
+                    network,
+                    cm,
+                    config,
+                    mc,
+                    listeners_clone,
+                    ListenerGroup::default(),
+                    Token(LISTENER_TOKEN),
+                    true,
+                    crust_sender,
+                );
+            })),
+            "Could not send to tx"
+        );
+
+        for it in event_rx.iter() {
+            match it {
+                Event::ListenerStarted(_port) => break,
+                _ => panic!("Unexpected event notification - {:?}", it),
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        unwrap!(
+            el.send(CoreMessage::new(move |core, _| {
+                let state = match core.get_state(Token(LISTENER_TOKEN)) {
+                    Some(state) => state,
+                    None => panic!("Listener not initialised"),
+                };
+                let mut state = state.borrow_mut();
+                let listener = match state.as_any().downcast_mut::<ConnectionListener>() {
+                    Some(l) => l,
+                    None => panic!("Token reserved for ConnectionListener has something else."),
+                };
+                listener.set_accept_bootstrap(accept_bootstrap);
+                unwrap!(tx.send(()));
+            })),
+            "Could not send to tx"
+        );
+        unwrap!(rx.recv());
+
+        let addr = unwrap!(listeners.lock())[0];
+
+        Listener {
+            el,
+            uid,
+            addr,
+            event_rx,
+        }
+    }
+
+    fn connect_to_listener(listener: &Listener) -> TcpStream {
+        let listener_addr = StdSocketAddr::new(listener.addr.ip(), listener.addr.port());
+        let stream = unwrap!(
+            TcpStream::connect(listener_addr),
+            "Could not connect to listener"
+        );
+        unwrap!(
+            stream.set_read_timeout(Some(Duration::from_secs(EXCHANGE_MSG_TIMEOUT_SEC + 1))),
+            "Could not set read timeout."
+        );
+
+        stream
+    }
+
+    fn core_stats(listener: &Listener) -> CoreStats {
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            unwrap!(tx.send(core.stats().clone()));
+        })));
+        unwrap!(rx.recv())
+    }
+
+    fn write(stream: &mut TcpStream, message: &[u8]) -> ::Res<()> {
+        let mut size_vec = Vec::with_capacity(mem::size_of::<u32>());
+        unwrap!(size_vec.write_u32::<LittleEndian>(message.len() as u32));
+
+        stream.write_all(&size_vec)?;
+        stream.write_all(message)?;
+
+        Ok(())
+    }
+
+    #[allow(unsafe_code)]
+    fn read<T: DeserializeOwned + Serialize>(stream: &mut TcpStream) -> ::Res<T> {
+        let mut payload_size_buffer = [0; 4];
         stream.read_exact(&mut payload_size_buffer)?;
 
         let payload_size =
@@ -379,112 +1392,507 @@ mod tests {
         unwrap!(write(&mut us, &message), "Could not write.");
 
         match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
-            Message::BootstrapGranted(peer_uid) => assert_eq!(peer_uid, listener.uid),
+            Message::BootstrapGranted(peer_uid) => assert_eq!(peer_uid, listener.uid),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
+            Event::BootstrapAccept(peer_id, peer_kind, _) => {
+                assert_eq!(peer_id, our_uid);
+                assert_eq!(peer_kind, expected_kind);
+            }
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+    }
+
+    fn connect(name_hash: NameHash, our_uid: UniqueId, listener: &Listener) {
+        let mut us = connect_to_listener(listener);
+
+        let message = unwrap!(serialise(&Message::Connect(our_uid, name_hash)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+
+        let their_uid = match unwrap!(read(&mut us), "Could not read.") {
+            Message::Connect(peer_uid, peer_hash) => {
+                assert_eq!(peer_uid, listener.uid);
+                assert_eq!(peer_hash, NAME_HASH);
+                peer_uid
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        };
+
+        if our_uid > their_uid {
+            let message = unwrap!(serialise(&Message::ChooseConnection::<UniqueId>));
+            unwrap!(write(&mut us, &message), "Could not write.");
+        }
+
+        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, our_uid),
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn bootstrap_with_correct_parameters() {
+        let listener = start_listener(true);
+        let uid = rand::random();
+        bootstrap(NAME_HASH, ExternalReachability::NotRequired, uid, &listener);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bootstrap_when_bootstrapping_is_disabled() {
+        let listener = start_listener(false);
+        let uid = rand::random();
+        bootstrap(NAME_HASH, ExternalReachability::NotRequired, uid, &listener);
+    }
+
+    #[test]
+    fn connect_with_correct_parameters() {
+        let listener = start_listener(false);
+        let uid = rand::random();
+        connect(NAME_HASH, uid, &listener);
+    }
+
+    #[test]
+    #[should_panic]
+    fn connect_to_self() {
+        let listener = start_listener(true);
+        connect(NAME_HASH, listener.uid, &listener);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bootstrap_with_invalid_version_hash() {
+        let listener = start_listener(true);
+        let uid = rand::random();
+        bootstrap(
+            NAME_HASH_2,
+            ExternalReachability::NotRequired,
+            uid,
+            &listener,
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn connect_with_invalid_version_hash() {
+        let listener = start_listener(true);
+        let uid = rand::random();
+        connect(NAME_HASH_2, uid, &listener);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bootstrap_with_invalid_pub_key() {
+        let listener = start_listener(true);
+        bootstrap(
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+            listener.uid,
+            &listener,
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn connect_with_invalid_pub_key() {
+        let listener = start_listener(true);
+        connect(NAME_HASH, listener.uid, &listener);
+    }
+
+    /// Sends `request` and returns the message it is answered with, checking that the listener
+    /// closes the connection afterwards.
+    fn expect_last_message(listener: &Listener, request: &Message<UniqueId>) -> Message<UniqueId> {
+        let mut us = connect_to_listener(listener);
+        unwrap!(write(&mut us, &unwrap!(serialise(request))), "Could not write.");
+
+        let msg = unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.");
+        let mut buf = [0; 512];
+        assert_eq!(0, unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+        msg
+    }
+
+    /// Sends `request` and returns the rejection it is answered with, checking that the listener
+    /// closes the connection afterwards.
+    fn expect_rejection(listener: &Listener, request: &Message<UniqueId>) -> Rejection {
+        match expect_last_message(listener, request) {
+            Message::Rejection(rejection) => rejection,
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    /// Checks that the listener closes the connection without a word on `request`.
+    fn expect_closed(listener: &Listener, request: &Message<UniqueId>) {
+        let mut us = connect_to_listener(listener);
+        unwrap!(write(&mut us, &unwrap!(serialise(request))), "Could not write.");
+
+        let mut buf = [0; 512];
+        assert_eq!(0, unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+    }
+
+    fn ext_bootstrap_request(
+        uid: UniqueId,
+        name_hash: NameHash,
+        ext_reachability: ExternalReachability,
+    ) -> Message<UniqueId> {
+        let mut role = RoleExtension::offering(ext_reachability);
+        Message::ExtBootstrapRequest(uid, name_hash, offer_extensions(&mut [&mut role]))
+    }
+
+    fn set_keys(listener: &Listener, keys: KeyPair) {
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            core.set_keys(keys);
+            unwrap!(tx.send(()));
+        })));
+        unwrap!(rx.recv());
+    }
+
+    fn read_raw_frame(stream: &mut TcpStream) -> ::Res<Vec<u8>> {
+        let len = stream.read_u32::<LittleEndian>()? as usize;
+        let mut frame = vec![0; len];
+        stream.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+
+    #[test]
+    fn peers_are_taken_up_once_they_confirmed_the_keys() {
+        let listener = start_listener(true);
+        set_keys(&listener, KeyPair::generate());
+        let bootstrap = |encryption: &mut EncryptionExtension| {
+            let mut us = connect_to_listener(&listener);
+            let uid: UniqueId = rand::random();
+            let mut role = RoleExtension::offering(ExternalReachability::NotRequired);
+            let offers = offer_extensions(&mut [&mut role, &mut *encryption]);
+            let request = Message::ExtBootstrapRequest(uid, NAME_HASH, offers);
+            unwrap!(write(&mut us, &unwrap!(serialise(&request))));
+            let answers = match unwrap!(read::<Message<UniqueId>>(&mut us)) {
+                Message::ExtBootstrapGranted(_, answers) => answers,
+                msg => panic!("Unexpected message: {:?}", msg),
+            };
+            let _ = take_extension_answers(&mut [&mut *encryption], &answers);
+            (us, uid)
+        };
+        let confirmation = unwrap!(serialise(&Message::KeyConfirmation::<UniqueId>));
+
+        let mut encryption = EncryptionExtension::new(Some(&KeyPair::generate()));
+        let (mut us, uid) = bootstrap(&mut encryption);
+        let mut cipher = FrameCipher::new(unwrap!(encryption.take_session()));
+        let sealed = unwrap!(read_raw_frame(&mut us));
+        let msg: Message<UniqueId> = unwrap!(deserialise(&unwrap!(cipher.open(&sealed))));
+        assert_eq!(msg, Message::KeyConfirmation);
+        assert!(
+            listener
+                .event_rx
+                .recv_timeout(Duration::from_millis(500))
+                .is_err()
+        );
+
+        unwrap!(write(&mut us, &cipher.seal(&confirmation)));
+        match unwrap!(listener.event_rx.recv()) {
+            Event::BootstrapAccept(peer_id, _, _) => assert_eq!(peer_id, uid),
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+
+        // A confirmation which isn't sealed with the keys doesn't open.
+        let mut encryption = EncryptionExtension::new(Some(&KeyPair::generate()));
+        let (mut us, _) = bootstrap(&mut encryption);
+        let _ = unwrap!(read_raw_frame(&mut us));
+        unwrap!(write(&mut us, &confirmation));
+        let mut buf = [0; 512];
+        assert_eq!(0, unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+        assert!(
+            listener
+                .event_rx
+                .recv_timeout(Duration::from_millis(500))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejections_say_why() {
+        let uid: UniqueId = rand::random();
+        let bootstrap_request =
+            |name_hash| ext_bootstrap_request(uid, name_hash, ExternalReachability::NotRequired);
+
+        let listener = start_listener(false);
+        let rejection = expect_rejection(&listener, &bootstrap_request(NAME_HASH));
+        assert_eq!(rejection.kind(), RejectionCode::Full);
+        assert!(rejection.retry_after_secs.is_some());
+
+        let listener = start_listener(true);
+        let rejection = expect_rejection(&listener, &bootstrap_request(NAME_HASH_2));
+        assert_eq!(rejection.kind(), RejectionCode::WrongNetwork);
+        assert_eq!(rejection.retry_after_secs, None);
+
+        let connect_request = Message::ExtConnect(uid, NAME_HASH_2, Extensions::default());
+        let rejection = expect_rejection(&listener, &connect_request);
+        assert_eq!(rejection.kind(), RejectionCode::WrongNetwork);
+        assert_eq!(rejection.retry_after_secs, None);
+    }
+
+    #[test]
+    fn peers_which_are_not_whitelisted_are_rejected() {
+        let mut config = Config::default();
+        let elsewhere: HashSet<IpAddr> = vec![unwrap!("8.8.8.8".parse())].into_iter().collect();
+        config.whitelisted_node_ips = Some(elsewhere.clone());
+        config.whitelisted_client_ips = Some(elsewhere);
+        let listener = start_listener_with_config(true, config);
+        let uid: UniqueId = rand::random();
+
+        let node = ExternalReachability::Required {
+            direct_listeners: vec![],
+        };
+        for ext_reachability in vec![ExternalReachability::NotRequired, node] {
+            let request = ext_bootstrap_request(uid, NAME_HASH, ext_reachability);
+            let rejection = expect_rejection(&listener, &request);
+            assert_eq!(rejection.kind(), RejectionCode::NotWhitelisted);
+            assert_eq!(rejection.retry_after_secs, None);
+        }
+
+        let connect_request = Message::ExtConnect(uid, NAME_HASH, Extensions::default());
+        let rejection = expect_rejection(&listener, &connect_request);
+        assert_eq!(rejection.kind(), RejectionCode::NotWhitelisted);
+    }
+
+    #[test]
+    fn peers_without_extensions_are_denied_the_legacy_way() {
+        let mut config = Config::default();
+        let elsewhere: HashSet<IpAddr> = vec![unwrap!("8.8.8.8".parse())].into_iter().collect();
+        config.whitelisted_node_ips = Some(elsewhere.clone());
+        config.whitelisted_client_ips = Some(elsewhere);
+        let listener = start_listener_with_config(true, config);
+        let uid: UniqueId = rand::random();
+        let node = ExternalReachability::Required {
+            direct_listeners: vec![],
+        };
+
+        let request = Message::BootstrapRequest(uid, NAME_HASH, ExternalReachability::NotRequired);
+        match expect_last_message(&listener, &request) {
+            Message::BootstrapDenied(BootstrapDenyReason::ClientNotWhitelisted) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        let request = Message::BootstrapRequest(uid, NAME_HASH, node.clone());
+        match expect_last_message(&listener, &request) {
+            Message::BootstrapDenied(BootstrapDenyReason::NodeNotWhitelisted) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        let request = Message::BootstrapRequest(uid, NAME_HASH_2, node);
+        match expect_last_message(&listener, &request) {
+            Message::BootstrapDenied(BootstrapDenyReason::InvalidNameHash) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        expect_closed(&listener, &Message::Connect(uid, NAME_HASH));
+
+        let listener = start_listener(false);
+        let request = Message::BootstrapRequest(uid, NAME_HASH, ExternalReachability::NotRequired);
+        expect_closed(&listener, &request);
+    }
+
+    #[test]
+    fn private_networks_challenge_every_request() {
+        let name = Some("private network".to_owned());
+        let key = NetworkKey::new(&name, "secret");
+        let listener = start_listener_on_network(true, Config::default(), NetworkId::Keyed(key));
+
+        // Sends a request with `request_nonce` in place of the name hash and reads the challenge,
+        // checking the listener's proof.
+        let challenged = |message: &Message<UniqueId>, request_nonce| {
+            let mut us = connect_to_listener(&listener);
+            unwrap!(write(&mut us, &unwrap!(serialise(message))));
+            match unwrap!(read::<Message<UniqueId>>(&mut us)) {
+                Message::NetworkChallenge(challenge, proof) => {
+                    let expected = key.accepting_proof(&request_nonce, &challenge);
+                    assert!(common::proofs_match(&proof, &expected));
+                    (us, challenge)
+                }
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        };
+
+        // With the proof, the request is handled as on any network.
+        let uid: UniqueId = rand::random();
+        let request_nonce = common::new_network_nonce();
+        let request =
+            Message::BootstrapRequest(uid, request_nonce, ExternalReachability::NotRequired);
+        let (mut us, challenge) = challenged(&request, request_nonce);
+        let captured = key.connecting_proof(&request_nonce, &challenge);
+        unwrap!(write(&mut us, &unwrap!(serialise(&Message::NetworkProof::<UniqueId>(captured)))));
+        match unwrap!(read::<Message<UniqueId>>(&mut us)) {
+            Message::BootstrapGranted(peer_uid) => assert_eq!(peer_uid, listener.uid),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        match unwrap!(listener.event_rx.recv()) {
+            Event::BootstrapAccept(peer_id, CrustUser::Client, _) => assert_eq!(peer_id, uid),
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+
+        // Replaying the captured request and proof fails against the fresh challenge.
+        let proof_of = |proof| unwrap!(serialise(&Message::NetworkProof::<UniqueId>(proof)));
+        let (mut us, fresh) = challenged(&request, request_nonce);
+        assert_ne!(fresh, challenge);
+        unwrap!(write(&mut us, &proof_of(captured)));
+        match unwrap!(read::<Message<UniqueId>>(&mut us)) {
+            Message::Rejection(rejection) => {
+                assert_eq!(rejection.kind(), RejectionCode::WrongNetwork)
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        // So does a proof made with another secret.
+        let other_key = NetworkKey::new(&name, "other secret");
+        let uid: UniqueId = rand::random();
+        let request_nonce = common::new_network_nonce();
+        let (mut us, challenge) = challenged(&Message::Connect(uid, request_nonce), request_nonce);
+        unwrap!(write(&mut us, &proof_of(other_key.connecting_proof(&request_nonce, &challenge))));
+        match unwrap!(read::<Message<UniqueId>>(&mut us)) {
+            Message::Rejection(rejection) => {
+                assert_eq!(rejection.kind(), RejectionCode::WrongNetwork)
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        // A connect request is answered with the nonce of the challenge in place of the name hash.
+        let uid: UniqueId = rand::random();
+        let request_nonce = common::new_network_nonce();
+        let (mut us, challenge) = challenged(&Message::Connect(uid, request_nonce), request_nonce);
+        unwrap!(write(&mut us, &proof_of(key.connecting_proof(&request_nonce, &challenge))));
+        match unwrap!(read::<Message<UniqueId>>(&mut us)) {
+            Message::Connect(peer_uid, peer_hash) => {
+                assert_eq!(peer_uid, listener.uid);
+                assert_eq!(peer_hash, challenge);
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn unknown_extensions_are_echoed_as_unsupported() {
+        let listener = start_listener(true);
+        let future = Extension {
+            id: 0x7fff,
+            payload: b"from the future".to_vec(),
+        };
+
+        let mut us = connect_to_listener(&listener);
+        let our_uid: UniqueId = rand::random();
+        let mut role = RoleExtension::offering(ExternalReachability::NotRequired);
+        let mut offers = offer_extensions(&mut [&mut role]);
+        offers.entries.insert(0, future.clone());
+        let message = unwrap!(serialise(&Message::ExtBootstrapRequest(our_uid, NAME_HASH, offers)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+        match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
+            Message::ExtBootstrapGranted(peer_uid, answers) => {
+                assert_eq!(peer_uid, listener.uid);
+                assert_eq!(answers.unsupported, vec![future.id]);
+                assert_eq!(answers.entries.len(), 1);
+            }
             msg => panic!("Unexpected message: {:?}", msg),
         }
-
         match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::BootstrapAccept(peer_id, peer_kind) => {
-                assert_eq!(peer_id, our_uid);
-                assert_eq!(peer_kind, expected_kind);
-            }
+            Event::BootstrapAccept(peer_id, CrustUser::Client, _) => assert_eq!(peer_id, our_uid),
             event => panic!("Unexpected event notification: {:?}", event),
         }
-    }
 
-    fn connect(name_hash: NameHash, our_uid: UniqueId, listener: &Listener) {
-        let mut us = connect_to_listener(listener);
-
-        let message = unwrap!(serialise(&Message::Connect(our_uid, name_hash)));
+        let mut us = connect_to_listener(&listener);
+        let our_uid: UniqueId = rand::random();
+        let offers = Extensions {
+            entries: vec![future.clone()],
+            unsupported: vec![],
+        };
+        let message = unwrap!(serialise(&Message::ExtConnect(our_uid, NAME_HASH, offers)));
         unwrap!(write(&mut us, &message), "Could not write.");
-
-        let their_uid = match unwrap!(read(&mut us), "Could not read.") {
-            Message::Connect(peer_uid, peer_hash) => {
+        match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
+            Message::ExtConnect(peer_uid, _, answers) => {
                 assert_eq!(peer_uid, listener.uid);
-                assert_eq!(peer_hash, NAME_HASH);
-                peer_uid
+                assert_eq!(answers.unsupported, vec![future.id]);
+                assert!(answers.entries.is_empty());
             }
             msg => panic!("Unexpected message: {:?}", msg),
-        };
-
-        if our_uid > their_uid {
+        }
+        if our_uid > listener.uid {
             let message = unwrap!(serialise(&Message::ChooseConnection::<UniqueId>));
             unwrap!(write(&mut us, &message), "Could not write.");
         }
-
         match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::ConnectSuccess(id) => assert_eq!(id, our_uid),
+            Event::ConnectSuccess(peer_id, _) => assert_eq!(peer_id, our_uid),
             event => panic!("Unexpected event notification: {:?}", event),
         }
     }
 
+    #[cfg(feature = "relay")]
     #[test]
-    fn bootstrap_with_correct_parameters() {
-        let listener = start_listener(true);
-        let uid = rand::random();
-        bootstrap(NAME_HASH, ExternalReachability::NotRequired, uid, &listener);
-    }
-
-    #[test]
-    #[should_panic]
-    fn bootstrap_when_bootstrapping_is_disabled() {
-        let listener = start_listener(false);
-        let uid = rand::random();
-        bootstrap(NAME_HASH, ExternalReachability::NotRequired, uid, &listener);
-    }
-
-    #[test]
-    fn connect_with_correct_parameters() {
-        let listener = start_listener(false);
-        let uid = rand::random();
-        connect(NAME_HASH, uid, &listener);
-    }
-
-    #[test]
-    #[should_panic]
-    fn connect_to_self() {
-        let listener = start_listener(true);
-        connect(NAME_HASH, listener.uid, &listener);
+    fn relay_requests_for_peers_not_connected_are_rejected() {
+        use main::RelayConfig;
+
+        let mut config = Config::default();
+        config.relay = Some(RelayConfig {
+            max_sessions: 4,
+            max_pair_bytes_per_sec: None,
+            max_bytes_per_sec: None,
+            window_bytes: None,
+        });
+        let listener = start_listener_with_config(true, config);
+
+        // Neither the peer asking nor the one asked for is connected to us.
+        let request = Message::RelayRequest(rand::random(), NAME_HASH, rand::random());
+        let rejection = expect_rejection(&listener, &request);
+        assert_eq!(rejection.kind(), RejectionCode::NotRelaying);
+        assert_eq!(rejection.retry_after_secs, None);
+
+        // Nor is the one asked for when the peer asking is.
+        let our_uid: UniqueId = rand::random();
+        connect(NAME_HASH, our_uid, &listener);
+        let request = Message::RelayRequest(our_uid, NAME_HASH, rand::random());
+        let rejection = expect_rejection(&listener, &request);
+        assert_eq!(rejection.kind(), RejectionCode::NotRelaying);
     }
 
+    #[cfg(feature = "relay")]
     #[test]
-    #[should_panic]
-    fn bootstrap_with_invalid_version_hash() {
-        let listener = start_listener(true);
-        let uid = rand::random();
-        bootstrap(
-            NAME_HASH_2,
-            ExternalReachability::NotRequired,
-            uid,
-            &listener,
-        );
-    }
+    fn retiring_listeners_stop_relaying() {
+        use common::RelayExtension;
+        use main::RelayConfig;
+
+        fn peer_relays(listener: &Listener) -> bool {
+            let mut us = connect_to_listener(listener);
+            let offers = offer_extensions(&mut [&mut RelayExtension::new(false)]);
+            let message = Message::ExtConnect(rand::random(), NAME_HASH, offers);
+            unwrap!(write(&mut us, &unwrap!(serialise(&message))));
+            match unwrap!(read::<Message<UniqueId>>(&mut us)) {
+                Message::ExtConnect(_, _, answers) => {
+                    let mut relay = RelayExtension::new(false);
+                    take_extension_answers(&mut [&mut relay], &answers).peer_relays
+                }
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
 
-    #[test]
-    #[should_panic]
-    fn connect_with_invalid_version_hash() {
-        let listener = start_listener(true);
-        let uid = rand::random();
-        connect(NAME_HASH_2, uid, &listener);
-    }
+        let mut config = Config::default();
+        config.relay = Some(RelayConfig {
+            max_sessions: 4,
+            max_pair_bytes_per_sec: None,
+            max_bytes_per_sec: None,
+            window_bytes: None,
+        });
+        let listener = start_listener_with_config(true, config);
+        assert!(peer_relays(&listener));
 
-    #[test]
-    #[should_panic]
-    fn bootstrap_with_invalid_pub_key() {
-        let listener = start_listener(true);
-        bootstrap(
-            NAME_HASH,
-            ExternalReachability::NotRequired,
-            listener.uid,
-            &listener,
-        );
-    }
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(Token(LISTENER_TOKEN)));
+            let mut state = state.borrow_mut();
+            let listener = unwrap!(state.as_any().downcast_mut::<ConnectionListener>());
+            listener.set_retiring(Vec::new());
+            unwrap!(tx.send(()));
+        })));
+        unwrap!(rx.recv());
 
-    #[test]
-    #[should_panic]
-    fn connect_with_invalid_pub_key() {
-        let listener = start_listener(true);
-        connect(NAME_HASH, listener.uid, &listener);
+        // Retiring, we no longer tell peers we relay, and turn down their requests for pipes.
+        assert!(!peer_relays(&listener));
+        let request = Message::RelayRequest(rand::random(), NAME_HASH, rand::random());
+        let rejection = expect_rejection(&listener, &request);
+        assert_eq!(rejection.kind(), RejectionCode::Retiring);
     }
 
     #[test]
@@ -540,4 +1948,467 @@ mod tests {
             unwrap!(us.read(&mut buf), "read should have returned EOF (0)")
         );
     }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn one_port_takes_crust_and_websocket() {
+        let listener = start_listener(true);
+
+        // Crust's handshake straight over TCP.
+        let mut us = connect_to_listener(&listener);
+        let message = unwrap!(serialise(&Message::EchoAddrReq::<UniqueId>));
+        unwrap!(write(&mut us, &message), "Could not write.");
+        match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
+            Message::EchoAddrResp(addr) => assert_eq!(addr, unwrap!(us.local_addr())),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        // The same over WebSocket, once the opening handshake is over.
+        let mut us = connect_to_listener(&listener);
+        let request = "GET / HTTP/1.1\r\nHost: crust\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        unwrap!(us.write_all(request.as_bytes()));
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            unwrap!(us.read_exact(&mut byte));
+            head.push(byte[0]);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 "));
+
+        // A binary frame of the client is masked.
+        let mut frame = Vec::new();
+        unwrap!(frame.write_u32::<LittleEndian>(message.len() as u32));
+        frame.extend_from_slice(&message);
+        let mask = [1, 2, 3, 4];
+        let mut ws_frame = vec![0x82, 0x80 | frame.len() as u8];
+        ws_frame.extend_from_slice(&mask);
+        ws_frame.extend(frame.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        unwrap!(us.write_all(&ws_frame));
+
+        // Those of the server aren't, and are short enough for the length to fit the header.
+        let complete = |payload: &[u8]| {
+            payload.len() >= 4
+                && payload.len() - 4 >= LittleEndian::read_u32(&payload[..4]) as usize
+        };
+        let mut payload = Vec::new();
+        while !complete(&payload) {
+            let mut header = [0; 2];
+            unwrap!(us.read_exact(&mut header));
+            assert_eq!(header[0], 0x82);
+            let mut chunk = vec![0; header[1] as usize];
+            unwrap!(us.read_exact(&mut chunk));
+            payload.extend(chunk);
+        }
+        match unwrap!(deserialise::<Message<UniqueId>>(&payload[4..])) {
+            Message::EchoAddrResp(addr) => assert_eq!(addr, unwrap!(us.local_addr())),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn garbage_and_silence_are_dropped_in_time() {
+        let listener = start_listener(true);
+        let before = core_stats(&listener);
+        let mut buf = [0; 512];
+
+        // Bytes of no protocol we speak are dropped as they arrive.
+        let mut us = connect_to_listener(&listener);
+        let start = Instant::now();
+        unwrap!(us.write_all(b"\xde\xad\xbe\xefgarbage"));
+        assert_eq!(0, unwrap!(us.read(&mut buf)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // A silent connection is dropped well before the handshake would time out.
+        let mut us = connect_to_listener(&listener);
+        let start = Instant::now();
+        assert_eq!(0, unwrap!(us.read(&mut buf)));
+        assert!(start.elapsed() < Duration::from_secs(HANDSHAKE_TIMEOUT_SEC));
+
+        let stats = core_stats(&listener);
+        assert_eq!(
+            stats.connections_unrecognised - before.connections_unrecognised,
+            2
+        );
+        assert_eq!(stats.handshakes_active, 0);
+    }
+
+    #[test]
+    fn accepts_queued_connections_in_batches() {
+        const CONNECTIONS: usize = 50;
+        const BATCH_SIZE: usize = 16;
+
+        let mut config = Config::default();
+        config.accept_batch_size = Some(BATCH_SIZE);
+        config.listen_backlog = Some(128);
+        let listener = start_listener_with_config(true, config);
+        let before = core_stats(&listener);
+
+        // Pause the event loop while the connections queue up in the backlog.
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        unwrap!(listener.el.send(CoreMessage::new(move |_, _| {
+            let _ = resume_rx.recv();
+        })));
+        let _streams: Vec<_> = (0..CONNECTIONS)
+            .map(|_| connect_to_listener(&listener))
+            .collect();
+        unwrap!(resume_tx.send(()));
+
+        let mut stats = core_stats(&listener);
+        for _ in 0..50 {
+            if stats.connections_accepted - before.connections_accepted >= CONNECTIONS as u64 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            stats = core_stats(&listener);
+        }
+        assert_eq!(
+            stats.connections_accepted - before.connections_accepted,
+            CONNECTIONS as u64
+        );
+
+        let batches = stats.accept_batches - before.accept_batches;
+        let min_batches = ((CONNECTIONS + BATCH_SIZE - 1) / BATCH_SIZE) as u64;
+        assert!(
+            batches >= min_batches && batches <= min_batches + 1,
+            "Accepted in {} batches",
+            batches
+        );
+    }
+
+    // Sends a bootstrap request to a listener requiring a proof of work, and returns the challenge
+    // it answers with.
+    fn request_pow_challenge(us: &mut TcpStream, difficulty: u8) -> PowChallenge {
+        let message = unwrap!(serialise(&Message::BootstrapRequest(
+            rand::random::<UniqueId>(),
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+        )));
+        unwrap!(write(us, &message), "Could not write.");
+
+        match unwrap!(read::<Message<UniqueId>>(us), "Could not read.") {
+            Message::PowChallenge(challenge, their_difficulty) => {
+                assert_eq!(their_difficulty, difficulty);
+                challenge
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn bootstrap_without_pow_offer_is_denied_up_front() {
+        let mut config = Config::default();
+        config.require_pow = Some(8);
+        let listener = start_listener_with_config(true, config);
+
+        let mut us = connect_to_listener(&listener);
+        let message = unwrap!(serialise(&Message::ExtBootstrapRequest(
+            rand::random::<UniqueId>(),
+            NAME_HASH,
+            Extensions::default(),
+        )));
+        unwrap!(write(&mut us, &message), "Could not write.");
+        match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
+            Message::BootstrapDenied(BootstrapDenyReason::PowRequired(8)) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn bootstrap_with_pow() {
+        const DIFFICULTY: u8 = 8;
+
+        let mut config = Config::default();
+        config.require_pow = Some(DIFFICULTY);
+        let listener = start_listener_with_config(true, config);
+
+        // A correct solution is admitted.
+        let mut us = connect_to_listener(&listener);
+        let challenge = request_pow_challenge(&mut us, DIFFICULTY);
+        let nonce = common::solve_pow(&challenge, DIFFICULTY);
+        let message = unwrap!(serialise(&Message::PowSolution::<UniqueId>(nonce)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+        match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
+            Message::BootstrapGranted(peer_uid) => assert_eq!(peer_uid, listener.uid),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
+            Event::BootstrapAccept(_, CrustUser::Client, _) => (),
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+
+        // A wrong nonce is rejected.
+        let mut us = connect_to_listener(&listener);
+        let challenge = request_pow_challenge(&mut us, DIFFICULTY);
+        let wrong_nonce =
+            unwrap!((0..).find(|nonce| { !common::is_valid_pow(&challenge, DIFFICULTY, *nonce) }));
+        let message = unwrap!(serialise(&Message::PowSolution::<UniqueId>(wrong_nonce)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+        match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
+            Message::BootstrapDenied(BootstrapDenyReason::InvalidPow) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        // A client ignoring the challenge is told which difficulty is required.
+        let mut us = connect_to_listener(&listener);
+        let _ = request_pow_challenge(&mut us, DIFFICULTY);
+        let message = unwrap!(serialise(&Message::Heartbeat::<UniqueId>));
+        unwrap!(write(&mut us, &message), "Could not write.");
+        match unwrap!(read::<Message<UniqueId>>(&mut us), "Could not read.") {
+            Message::BootstrapDenied(BootstrapDenyReason::PowRequired(DIFFICULTY)) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        assert!(listener.event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn handshakes_beyond_limit_are_parked() {
+        let mut config = Config::default();
+        config.max_concurrent_inbound_handshakes = Some(2);
+        let listener = start_listener_with_config(true, config);
+
+        let mut admitted: Vec<_> = (0..2).map(|_| connect_to_listener(&listener)).collect();
+        let mut parked = Vec::new();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(100));
+            parked.push(connect_to_listener(&listener));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let stats = core_stats(&listener);
+        assert_eq!(stats.handshakes_active, 2);
+        assert_eq!(stats.handshakes_parked, 3);
+
+        // Queue a request on the oldest parked connection, then free a slot by ending one of the
+        // admitted handshakes. The parked connection is admitted and served first; once it is
+        // done, the next one in line takes its slot.
+        let message = unwrap!(serialise(&Message::EchoAddrReq::<UniqueId>));
+        unwrap!(write(&mut parked[0], &message), "Could not write.");
+        let message = unwrap!(serialise(&Message::Heartbeat::<UniqueId>));
+        unwrap!(write(&mut admitted[0], &message), "Could not write.");
+
+        match unwrap!(read::<Message<UniqueId>>(&mut parked[0]), "Could not read.") {
+            Message::EchoAddrResp(addr) => assert_eq!(addr, unwrap!(parked[0].local_addr())),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        let mut buf = [0; 512];
+        assert_eq!(0, unwrap!(parked[0].read(&mut buf)));
+
+        let stats = core_stats(&listener);
+        assert_eq!(stats.handshakes_active, 2);
+        assert_eq!(stats.handshakes_parked, 1);
+        assert_eq!(stats.handshakes_expired, 0);
+
+        // The last connection never gets a slot and is dropped once it has waited too long.
+        assert_eq!(0, unwrap!(parked[2].read(&mut buf)));
+
+        let stats = core_stats(&listener);
+        assert_eq!(stats.handshakes_active, 2);
+        assert_eq!(stats.handshakes_parked, 0);
+        assert_eq!(stats.handshakes_expired, 1);
+    }
+
+    // Waits for the listener to have accepted `count` connections in total.
+    fn wait_for_accepted(listener: &Listener, count: u64) {
+        for _ in 0..50 {
+            if core_stats(listener).connections_accepted >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(core_stats(listener).connections_accepted, count);
+    }
+
+    #[test]
+    fn accepting_pauses_while_out_of_file_descriptors() {
+        let mut config = Config::default();
+        config.max_file_descriptors = Some(FD_SAFETY_MARGIN + 2);
+        let listener = start_listener_with_config(true, config);
+        let accepted = core_stats(&listener).connections_accepted;
+
+        let first = connect_to_listener(&listener);
+        let _second = connect_to_listener(&listener);
+        match unwrap!(listener.event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::ResourceLow {
+                kind: ResourceKind::FileDescriptors,
+                available,
+            } => assert_eq!(available, FD_SAFETY_MARGIN),
+            event => panic!("Unexpected event notification - {:?}", event),
+        }
+
+        // The third connection waits in the backlog until one of the others closes.
+        let _third = connect_to_listener(&listener);
+        thread::sleep(Duration::from_millis(RESOURCE_CHECK_INTERVAL_MS + 500));
+        assert_eq!(core_stats(&listener).connections_accepted, accepted + 2);
+
+        drop(first);
+        wait_for_accepted(&listener, accepted + 3);
+        assert!(listener.event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn accept_recovers_from_fd_exhaustion() {
+        let listener = start_listener(true);
+        let accepted = core_stats(&listener).connections_accepted;
+
+        // Pretend the next accept fails for lack of file descriptors, with a connection pending.
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, poll| {
+            let _ = resume_rx.recv();
+            let state = unwrap!(core.get_state(Token(LISTENER_TOKEN)));
+            let mut state = state.borrow_mut();
+            let listener = unwrap!(state.as_any().downcast_mut::<ConnectionListener>());
+            listener.recover_from_fd_exhaustion(core, poll);
+        })));
+        let mut dropped = connect_to_listener(&listener);
+        unwrap!(resume_tx.send(()));
+
+        let mut buf = [0; 512];
+        assert_eq!(0, unwrap!(dropped.read(&mut buf)));
+        match unwrap!(listener.event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::ResourceLow {
+                kind: ResourceKind::FileDescriptors,
+                available: 0,
+            } => (),
+            event => panic!("Unexpected event notification - {:?}", event),
+        }
+        assert_eq!(core_stats(&listener).connections_accepted, accepted);
+
+        // The listener resumes by itself.
+        let _next = connect_to_listener(&listener);
+        wait_for_accepted(&listener, accepted + 1);
+    }
+
+    #[test]
+    fn failed_accepts_dont_stop_the_listener() {
+        let listener = start_listener(true);
+        let accepted = core_stats(&listener).connections_accepted;
+
+        // The connection is accepted in the same batch as the failures, as they don't end it.
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(Token(LISTENER_TOKEN)));
+            let mut state = state.borrow_mut();
+            let listener = unwrap!(state.as_any().downcast_mut::<ConnectionListener>());
+            listener.inject_fault(io::ErrorKind::ConnectionAborted);
+            listener.inject_fault(io::ErrorKind::Interrupted);
+            listener.inject_fault(io::ErrorKind::ConnectionReset);
+            unwrap!(tx.send(()));
+        })));
+        unwrap!(rx.recv());
+        bootstrap(
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+            rand::random(),
+            &listener,
+        );
+
+        // Another error leaves the connection for the next readable event, raised by the next
+        // connection.
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(Token(LISTENER_TOKEN)));
+            let mut state = state.borrow_mut();
+            let listener = unwrap!(state.as_any().downcast_mut::<ConnectionListener>());
+            listener.inject_fault(io::ErrorKind::PermissionDenied);
+            unwrap!(tx.send(()));
+        })));
+        unwrap!(rx.recv());
+        let _first = connect_to_listener(&listener);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(core_stats(&listener).connections_accepted, accepted + 1);
+        let _second = connect_to_listener(&listener);
+        wait_for_accepted(&listener, accepted + 3);
+    }
+
+    // Two sockets may listen on the same port with `SO_REUSEPORT`, so none are refused.
+    #[cfg(unix)]
+    #[test]
+    fn restarting_refuses_no_connections() {
+        let listener = start_listener(true);
+        let restart = |options: ListenerOptions| {
+            let (tx, rx) = mpsc::channel();
+            unwrap!(listener.el.send(CoreMessage::new(move |core, poll| {
+                let state = unwrap!(core.get_state(Token(LISTENER_TOKEN)));
+                let mut state = state.borrow_mut();
+                let listener = unwrap!(state.as_any().downcast_mut::<ConnectionListener>());
+                unwrap!(tx.send(listener.restart(core, poll, &options)));
+            })));
+            unwrap!(unwrap!(rx.recv()));
+        };
+
+        let done = Arc::new(AtomicBool::new(false));
+        let connecting = {
+            let done = done.clone();
+            let addr = StdSocketAddr::new(listener.addr.ip(), listener.addr.port());
+            thread::spawn(move || {
+                let mut attempts = 0;
+                let mut refused = 0;
+                while !done.load(Ordering::Relaxed) {
+                    attempts += 1;
+                    if let Err(e) = TcpStream::connect(addr) {
+                        debug!("Connection attempt failed: {:?}", e);
+                        refused += 1;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+                (attempts, refused)
+            })
+        };
+        for i in 0..5 {
+            thread::sleep(Duration::from_millis(50));
+            restart(ListenerOptions {
+                backlog: Some(50 + 10 * i),
+                ttl: Some(64),
+            });
+        }
+        thread::sleep(Duration::from_millis(50));
+        done.store(true, Ordering::Relaxed);
+        let (attempts, refused) = unwrap!(connecting.join());
+        assert!(attempts > 0);
+        assert_eq!(refused, 0);
+
+        // The new socket carries on accepting connections.
+        bootstrap(
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+            rand::random(),
+            &listener,
+        );
+    }
+
+    #[test]
+    fn connections_from_banned_addresses_are_dropped() {
+        let listener = start_listener(true);
+        let accepted = core_stats(&listener).connections_accepted;
+        let ip = listener.addr.ip();
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            let now = core.now();
+            core.bans_mut().ban(ip, now + Duration::from_secs(60), now);
+            unwrap!(tx.send(()));
+        })));
+        unwrap!(rx.recv());
+
+        let mut stream = connect_to_listener(&listener);
+        let mut buf = [0; 1];
+        match stream.read(&mut buf) {
+            Ok(0) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => (),
+            res => panic!("Banned connection wasn't closed: {:?}", res),
+        }
+        let stats = core_stats(&listener);
+        assert_eq!(stats.connections_banned, 1);
+        assert_eq!(stats.connections_accepted, accepted);
+        match unwrap!(listener.event_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::PeerRejected {
+                addr,
+                peer_id: None,
+                reason: RejectionCode::Banned,
+            } => assert_eq!(addr.ip(), ip),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
 }
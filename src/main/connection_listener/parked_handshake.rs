@@ -0,0 +1,51 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::ConnectionListener;
+use common::{Core, State, Uid};
+use mio::{Poll, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+/// Placeholder state for an accepted connection waiting for a free handshake slot. The socket
+/// itself is owned by the listener and is only registered for hangups, so that peers which give up
+/// waiting don't hold on to a place in the queue.
+pub struct ParkedHandshake<UID: Uid> {
+    token: Token,
+    listener: Weak<RefCell<ConnectionListener<UID>>>,
+}
+
+impl<UID: Uid> ParkedHandshake<UID> {
+    pub fn start(core: &mut Core, token: Token, listener: Weak<RefCell<ConnectionListener<UID>>>) {
+        let state = Rc::new(RefCell::new(ParkedHandshake { token, listener }));
+        let _ = core.insert_state(token, state);
+    }
+}
+
+impl<UID: Uid> State for ParkedHandshake<UID> {
+    fn name(&self) -> &'static str {
+        "ParkedHandshake"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, _kind: Ready) {
+        match self.listener.upgrade() {
+            Some(listener) => listener.borrow_mut().drop_parked(core, poll, self.token),
+            None => self.terminate(core, poll),
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
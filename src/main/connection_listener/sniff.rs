@@ -0,0 +1,258 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::ConnectionListener;
+use byteorder::{ByteOrder, LittleEndian};
+use common::{
+    ConnectionDirection, Core, CoreTimer, HandshakeStage, IoErrorClass, Socket, State, Uid,
+    FRAME_HEADER_SIZE,
+};
+use mio::{Poll, PollOpt, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+#[cfg(feature = "websocket")]
+use std::cmp;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+/// How long an accepted connection may stay silent before it is dropped, rather than hold on to
+/// its handshake slot until the handshake times out.
+#[cfg(not(test))]
+const SNIFF_TIMEOUT_SECS: u64 = 10;
+#[cfg(test)]
+const SNIFF_TIMEOUT_SECS: u64 = 3;
+/// Bytes looked at to tell the protocol: the length and the variant index of the first frame of
+/// Crust's handshake.
+const SNIFF_LEN: usize = FRAME_HEADER_SIZE + 4;
+/// The start of the opening handshake request of a WebSocket client. Read as the length of a
+/// frame, it is far longer than any request of Crust's handshake.
+#[cfg(feature = "websocket")]
+const WEBSOCKET_MAGIC: &[u8] = b"GET ";
+/// Longest first frame of Crust's handshake we take.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// Variant indices of the messages opening Crust's handshake, see `decode_handshake_request`:
+/// `BootstrapRequest`, `EchoAddrReq`, `Connect`, `ExtBootstrapRequest`, `ExtConnect` and
+/// `RelayRequest`.
+const REQUEST_VARIANTS: [u32; 6] = [1, 4, 7, 18, 20, 26];
+
+/// The protocol an accepted connection speaks, told by its first bytes, see `sniff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Crust's handshake, straight over TCP.
+    Crust,
+    /// The opening handshake of a WebSocket connection, over which Crust's then goes.
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
+
+/// Tells the protocol of a connection from the first bytes received. Those starting with a magic
+/// prefix are handed to its handshake, and the others have to start a frame of Crust's. Returns
+/// `Ok(None)` while it takes more bytes to tell, and `Err(())` for bytes of no protocol we speak.
+pub fn sniff(bytes: &[u8]) -> Result<Option<Protocol>, ()> {
+    #[cfg(feature = "websocket")]
+    {
+        let len = cmp::min(bytes.len(), WEBSOCKET_MAGIC.len());
+        if bytes[..len] == WEBSOCKET_MAGIC[..len] {
+            return Ok(if len == WEBSOCKET_MAGIC.len() {
+                Some(Protocol::WebSocket)
+            } else {
+                None
+            });
+        }
+    }
+
+    if bytes.len() >= FRAME_HEADER_SIZE {
+        let len = LittleEndian::read_u32(&bytes[..FRAME_HEADER_SIZE]) as usize;
+        if len < SNIFF_LEN - FRAME_HEADER_SIZE || len > MAX_REQUEST_LEN {
+            return Err(());
+        }
+    }
+    if bytes.len() < SNIFF_LEN {
+        return Ok(None);
+    }
+    let variant = LittleEndian::read_u32(&bytes[FRAME_HEADER_SIZE..SNIFF_LEN]);
+    if REQUEST_VARIANTS.contains(&variant) {
+        Ok(Some(Protocol::Crust))
+    } else {
+        Err(())
+    }
+}
+
+/// An accepted connection in its handshake slot until its first bytes tell the protocol it
+/// speaks, which are only peeked at, for the handshake it goes on with to read them. Connections
+/// opening with bytes of no protocol we speak are dropped straight away, and those which stay
+/// silent after `SNIFF_TIMEOUT_SECS`.
+pub struct Sniff<UID: Uid> {
+    token: Token,
+    socket: Option<Socket>,
+    listener: Weak<RefCell<ConnectionListener<UID>>>,
+}
+
+impl<UID: Uid> Sniff<UID> {
+    pub fn start(
+        core: &mut Core,
+        poll: &Poll,
+        socket: Socket,
+        listener: Weak<RefCell<ConnectionListener<UID>>>,
+    ) -> ::Res<()> {
+        let token = core.get_new_token();
+
+        let kind = Ready::error() | Ready::hup() | Ready::readable();
+        poll.register(&socket, token, kind, PollOpt::edge())?;
+
+        core.set_timeout(
+            Duration::from_secs(SNIFF_TIMEOUT_SECS),
+            CoreTimer::new(token, 0),
+        )?;
+
+        if let Ok(addr) = socket.peer_addr() {
+            core.add_pending(
+                token,
+                addr,
+                ConnectionDirection::Inbound,
+                HandshakeStage::AwaitingHandshake,
+            );
+        }
+        let state = Rc::new(RefCell::new(Sniff {
+            token,
+            socket: Some(socket),
+            listener,
+        }));
+        let _ = core.insert_state(token, state);
+        Ok(())
+    }
+
+    /// Drops a connection whose first bytes are of no protocol we speak.
+    fn reject(&mut self, core: &mut Core, poll: &Poll) {
+        core.stats_mut().connections_unrecognised += 1;
+        if let Some(socket) = self.socket.as_mut() {
+            socket.discard_received();
+        }
+        self.finish(core, poll, None);
+    }
+
+    /// Hands the connection back to the listener, with its protocol if it was told.
+    fn finish(&mut self, core: &mut Core, poll: &Poll, protocol: Option<Protocol>) {
+        let _ = core.remove_state(self.token);
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => return,
+        };
+        let _ = poll.deregister(&socket);
+        if let Some(listener) = self.listener.upgrade() {
+            listener
+                .borrow_mut()
+                .handle_sniffed(core, poll, socket, protocol);
+        }
+    }
+}
+
+impl<UID: Uid> State for Sniff<UID> {
+    fn name(&self) -> &'static str {
+        "Sniff"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        let mut buf = [0; SNIFF_LEN];
+        let res = loop {
+            let res = match self.socket {
+                Some(ref socket) => socket.peek(&mut buf),
+                None => return,
+            };
+            match res {
+                Err(ref e) if IoErrorClass::of(e) == IoErrorClass::Retry => (),
+                res => break res,
+            }
+        };
+        match res {
+            // The peer hung up without a word, as those checking that we can be reached do.
+            Ok(0) => self.finish(core, poll, None),
+            Ok(len) => match sniff(&buf[..len]) {
+                Ok(Some(protocol)) => self.finish(core, poll, Some(protocol)),
+                // Nothing more is coming.
+                Ok(None) if kind.is_error() || kind.is_hup() => self.finish(core, poll, None),
+                Ok(None) => (),
+                Err(()) => {
+                    debug!("Dropping connection speaking no protocol we know");
+                    self.reject(core, poll);
+                }
+            },
+            Err(e) => match IoErrorClass::of(&e) {
+                IoErrorClass::WouldBlock => (),
+                IoErrorClass::Retry | IoErrorClass::RemoteClosed | IoErrorClass::Fatal => {
+                    trace!("Failed to read from accepted connection: {:?}", e);
+                    self.finish(core, poll, None);
+                }
+            },
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        debug!(
+            "Dropping connection which didn't say a word within {} s",
+            SNIFF_TIMEOUT_SECS
+        );
+        core.stats_mut().connections_unrecognised += 1;
+        self.finish(core, poll, None);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.finish(core, poll, None);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{encode_frame, ExternalReachability, Extensions, Message, HASH_SIZE};
+    use tests::UniqueId;
+
+    #[test]
+    fn requests_are_told_apart_from_garbage() {
+        let id: UniqueId = [3; 20];
+        let name_hash = [7; HASH_SIZE];
+        let requests = vec![
+            Message::BootstrapRequest(id, name_hash, ExternalReachability::NotRequired),
+            Message::EchoAddrReq,
+            Message::Connect(id, name_hash),
+            Message::ExtBootstrapRequest(id, name_hash, Extensions::default()),
+            Message::ExtConnect(id, name_hash, Extensions::default()),
+            Message::RelayRequest(id, name_hash, [4; 20]),
+        ];
+        for request in requests {
+            let frame = unwrap!(encode_frame(&request));
+            for len in 0..SNIFF_LEN {
+                assert_eq!(sniff(&frame[..len]), Ok(None));
+            }
+            assert_eq!(sniff(&frame), Ok(Some(Protocol::Crust)));
+        }
+
+        // Messages which don't open the handshake, and frames too long for a request.
+        let frame = unwrap!(encode_frame(&Message::Heartbeat::<UniqueId>));
+        assert_eq!(sniff(&frame), Err(()));
+        let frame = unwrap!(encode_frame(&Message::Data::<UniqueId>(vec![0; MAX_REQUEST_LEN])));
+        assert_eq!(sniff(&frame[..FRAME_HEADER_SIZE]), Err(()));
+        assert_eq!(sniff(b"\xde\xad\xbe\xefgarbage"), Err(()));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn websocket_requests_are_told_by_their_magic() {
+        let request = b"GET / HTTP/1.1\r\n";
+        for len in 0..WEBSOCKET_MAGIC.len() {
+            assert_eq!(sniff(&request[..len]), Ok(None));
+        }
+        assert_eq!(sniff(request), Ok(Some(Protocol::WebSocket)));
+        assert_eq!(sniff(b"GEX / HTTP/1.1\r\n"), Err(()));
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! The checks of `Service::run_diagnostics`. Each finds out one prerequisite of joining the
+//! network without going through with it: nothing is sent to the peers it reaches.
+
+use main::config_handler::{self, Config};
+use nat::{self, MappingContext};
+use std::cmp;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time within which a hard-coded contact has to accept our TCP connection.
+pub const DIAGNOSTICS_CONNECT_TIMEOUT_SECS: u64 = 3;
+/// Most hard-coded contacts connected to at a time.
+const DIAGNOSTICS_MAX_CONNECTING: usize = 8;
+
+/// A prerequisite checked by `Service::run_diagnostics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticCheck {
+    /// The config file parses and passes `Config::validate`.
+    Config,
+    /// `Config::tcp_acceptor_port` can be bound.
+    AcceptorPort,
+    /// The port of service discovery can be bound.
+    DiscoveryPort,
+    /// At least one of `Config::hard_coded_contacts` accepts a TCP connection.
+    HardCodedContacts,
+    /// An IGD gateway answered on one of our interfaces.
+    IgdGateway,
+    /// The addresses our listeners are advertised at can be reached from elsewhere.
+    ExternalAddresses,
+}
+
+/// How a check turned out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticStatus {
+    /// The prerequisite is met.
+    Pass,
+    /// The prerequisite isn't met, see `DiagnosticResult::detail`.
+    Fail,
+    /// The check doesn't apply to this service, e.g. there are no hard-coded contacts.
+    Skip,
+}
+
+/// The outcome of one check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticResult {
+    /// The check this is the outcome of.
+    pub check: DiagnosticCheck,
+    /// Whether the check passed.
+    pub status: DiagnosticStatus,
+    /// Why the check failed or was skipped.
+    pub detail: Option<String>,
+    /// Time the check took.
+    pub elapsed: Duration,
+}
+
+/// The outcomes of `Service::run_diagnostics`, one per `DiagnosticCheck`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticsReport {
+    /// The outcomes, in the order the checks were run.
+    pub results: Vec<DiagnosticResult>,
+}
+
+impl DiagnosticsReport {
+    /// The outcome of the given check.
+    pub fn result(&self, check: DiagnosticCheck) -> Option<&DiagnosticResult> {
+        self.results.iter().find(|result| result.check == check)
+    }
+
+    /// Whether no check failed.
+    pub fn passed(&self) -> bool {
+        self.results
+            .iter()
+            .all(|result| result.status != DiagnosticStatus::Fail)
+    }
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+fn timed<F: FnOnce() -> Outcome>(check: DiagnosticCheck, f: F) -> DiagnosticResult {
+    let started = Instant::now();
+    let (status, detail) = match f() {
+        Outcome::Pass => (DiagnosticStatus::Pass, None),
+        Outcome::Fail(detail) => (DiagnosticStatus::Fail, Some(detail)),
+        Outcome::Skip(detail) => (DiagnosticStatus::Skip, Some(detail)),
+    };
+    DiagnosticResult {
+        check,
+        status,
+        detail,
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Re-reads the config file the service was started with, if any, or else validates the config
+/// it was given.
+pub fn check_config(config_path: &Option<PathBuf>, current: &Config) -> DiagnosticResult {
+    timed(DiagnosticCheck::Config, || {
+        let res = match *config_path {
+            Some(_) => config_handler::read_config(config_path).and_then(|cfg| cfg.validate()),
+            None => current.validate(),
+        };
+        match res {
+            Ok(()) => Outcome::Pass,
+            Err(e) => Outcome::Fail(format!("{}", e)),
+        }
+    })
+}
+
+/// Binds the acceptor port, unless one of our listeners holds it already.
+pub fn check_acceptor_port(port: Option<u16>, our_ports: &[u16]) -> DiagnosticResult {
+    timed(DiagnosticCheck::AcceptorPort, || match port {
+        None | Some(0) => Outcome::Skip("No acceptor port is configured".to_owned()),
+        Some(port) if our_ports.contains(&port) => Outcome::Pass,
+        Some(port) => match TcpListener::bind(any_addr(port)) {
+            Ok(_) => Outcome::Pass,
+            Err(e) => Outcome::Fail(format!("Could not bind TCP port {}: {}", port, e)),
+        },
+    })
+}
+
+/// Binds the port of service discovery, unless our service discovery holds it already. `None`
+/// without the `service-discovery` feature.
+pub fn check_discovery_port(port: Option<(u16, bool)>) -> DiagnosticResult {
+    timed(DiagnosticCheck::DiscoveryPort, || match port {
+        None => Outcome::Skip("Service discovery is compiled out".to_owned()),
+        Some((port, ours)) => match UdpSocket::bind(any_addr(port)) {
+            Ok(_) => Outcome::Pass,
+            Err(_) if ours => Outcome::Pass,
+            Err(e) => Outcome::Fail(format!("Could not bind UDP port {}: {}", port, e)),
+        },
+    })
+}
+
+fn any_addr(port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port)
+}
+
+/// Connects to the contacts, dropping each connection as soon as it is made. Passes if any of
+/// them accepted within `timeout`. At most `DIAGNOSTICS_MAX_CONNECTING` connections are
+/// attempted at a time, each by a helper thread of its own.
+pub fn check_contacts(contacts: &[SocketAddr], timeout: Duration) -> DiagnosticResult {
+    timed(DiagnosticCheck::HardCodedContacts, || {
+        if contacts.is_empty() {
+            return Outcome::Skip("No hard-coded contacts are configured".to_owned());
+        }
+        let queue = Arc::new(Mutex::new(contacts.to_vec().into_iter()));
+        let (tx, rx) = mpsc::channel();
+        let mut helpers = 0;
+        while helpers < cmp::min(contacts.len(), DIAGNOSTICS_MAX_CONNECTING) {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let res = thread::Builder::new()
+                .name("Diagnostics-Connect".to_owned())
+                .spawn(move || loop {
+                    let contact = match unwrap!(queue.lock()).next() {
+                        Some(contact) => contact,
+                        None => break,
+                    };
+                    // Stops once the report is made without us.
+                    let res = TcpStream::connect_timeout(&contact, timeout);
+                    if tx.send((contact, res)).is_err() {
+                        break;
+                    }
+                });
+            match res {
+                Ok(_) => helpers += 1,
+                // Those started already go through the contacts left.
+                Err(_) if helpers > 0 => break,
+                Err(e) => return Outcome::Fail(format!("Could not start connecting: {}", e)),
+            }
+        }
+        drop(tx);
+
+        // The helpers give up by themselves, but a stuck one must not hold the report back.
+        let rounds = (contacts.len() + helpers - 1) / helpers;
+        let deadline = Instant::now() + timeout * rounds as u32 + Duration::from_secs(1);
+        let mut errors = Vec::with_capacity(contacts.len());
+        while errors.len() < contacts.len() {
+            let now = Instant::now();
+            let wait = if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            };
+            match rx.recv_timeout(wait) {
+                Ok((_, Ok(_))) => return Outcome::Pass,
+                Ok((contact, Err(e))) => errors.push(format!("{}: {}", contact, e)),
+                Err(_) => {
+                    errors.push("the remaining contacts timed out".to_owned());
+                    break;
+                }
+            }
+        }
+        Outcome::Fail(format!("No contact accepted: {}", errors.join(", ")))
+    })
+}
+
+/// Looks up the gateways found when the service started, or last refreshed its interfaces.
+pub fn check_igd(mc: &MappingContext, lan_only: bool) -> DiagnosticResult {
+    timed(DiagnosticCheck::IgdGateway, || {
+        if lan_only {
+            Outcome::Skip("Gateways aren't used in LAN-only mode".to_owned())
+        } else if !cfg!(feature = "nat-traversal") {
+            Outcome::Skip("NAT traversal is compiled out".to_owned())
+        } else if mc.ifv4s().iter().any(|&(_, ref gateway)| gateway.is_some()) {
+            Outcome::Pass
+        } else {
+            Outcome::Fail("No IGD gateway answered on any of our interfaces".to_owned())
+        }
+    })
+}
+
+/// Checks the addresses our listeners are advertised at. Peers on other hosts can only reach
+/// us if one of them is neither loopback nor unspecified, and in LAN-only mode it can't be
+/// global either.
+pub fn check_external_addrs(listeners: &[SocketAddr], lan_only: bool) -> DiagnosticResult {
+    timed(DiagnosticCheck::ExternalAddresses, || {
+        if listeners.is_empty() {
+            return Outcome::Skip("Not listening".to_owned());
+        }
+        let reachable = listeners.iter().any(|addr| {
+            let ip = addr.ip();
+            addr.port() != 0
+                && !ip.is_loopback()
+                && !ip.is_unspecified()
+                && !(lan_only && nat::ip_addr_is_global(&ip))
+        });
+        if reachable {
+            Outcome::Pass
+        } else {
+            Outcome::Fail(format!("No address reachable by other hosts in {:?}", listeners))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_addresses_are_classified() {
+        let loopback: SocketAddr = unwrap!("127.0.0.1:5483".parse());
+        let private: SocketAddr = unwrap!("192.168.1.7:5483".parse());
+        let global: SocketAddr = unwrap!("8.8.8.8:5483".parse());
+
+        fn status(listeners: &[SocketAddr], lan_only: bool) -> DiagnosticStatus {
+            check_external_addrs(listeners, lan_only).status
+        }
+        assert_eq!(status(&[], false), DiagnosticStatus::Skip);
+        assert_eq!(status(&[loopback], false), DiagnosticStatus::Fail);
+        assert_eq!(status(&[loopback, private], false), DiagnosticStatus::Pass);
+        assert_eq!(status(&[global], false), DiagnosticStatus::Pass);
+        assert_eq!(status(&[global], true), DiagnosticStatus::Fail);
+        assert_eq!(status(&[global, private], true), DiagnosticStatus::Pass);
+    }
+
+    #[test]
+    fn contacts_beyond_the_helpers_are_tried_too() {
+        // Ports just freed refuse connections right away.
+        let refusing: Vec<SocketAddr> = (0..3 * DIAGNOSTICS_MAX_CONNECTING)
+            .map(|_| unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr()))
+            .collect();
+        let timeout = Duration::from_secs(DIAGNOSTICS_CONNECT_TIMEOUT_SECS);
+        let result = check_contacts(&refusing, timeout);
+        assert_eq!(result.status, DiagnosticStatus::Fail);
+
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let mut contacts = refusing;
+        contacts.push(unwrap!(listener.local_addr()));
+        assert_eq!(check_contacts(&contacts, timeout).status, DiagnosticStatus::Pass);
+    }
+}
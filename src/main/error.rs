@@ -7,13 +7,15 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::{self, CoreMessage};
+use common::{self, CoreMessage, Priority};
 use config_file_handler;
 use maidsafe_utilities::serialisation::SerialisationError;
 use mio;
 use nat;
 use service_discovery;
 use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::mpsc;
 
 quick_error! {
@@ -34,6 +36,22 @@ quick_error! {
             cause(e)
             from()
         }
+        /// No config file was found at any of the paths tried.
+        ConfigFileNotFound(tried: Vec<PathBuf>) {
+            description("Config file not found")
+            display("No config file found, tried: {}", display_paths(tried))
+        }
+        /// The config file could not be parsed.
+        InvalidConfigFile(path: PathBuf, reason: String) {
+            description("Invalid config file")
+            display("Invalid config file {}: {}", path.display(), reason)
+        }
+        /// The config file is of a version newer than this Crust knows how to read.
+        NewerConfigVersion(path: PathBuf, version: u32) {
+            description("Config from a newer Crust")
+            display("Config file {} is from a newer Crust: version {}, while at most {} is \
+                     supported", path.display(), version, ::main::CONFIG_VERSION)
+        }
         /// Wrapper for a `std::io::Error`
         Io(e: io::Error) {
             description("IO error")
@@ -75,6 +93,10 @@ quick_error! {
         PeerNotFound {
             description("Peer not found")
         }
+        /// The event receiver has been dropped, so the service is shutting down.
+        ShuttingDown {
+            description("Service is shutting down")
+        }
         /// Serialisation error
         Serialisation(e: SerialisationError) {
             description("Serialisation error")
@@ -92,5 +114,101 @@ quick_error! {
             description("Listener is not initialised yet")
             display("Listener is not initialised yet")
         }
+        /// No listener accepts connections on the address.
+        ListenerNotFound(addr: SocketAddr) {
+            description("Listener not found")
+            display("No listener accepts connections on {}", addr)
+        }
+        /// Peer is parked and sending to parked peers doesn't unpark them.
+        PeerParked {
+            description("Peer is parked")
+            display("Peer is parked, unpark it before sending")
+        }
+        /// The priority is reserved for the control messages of Crust, see `CONTROL_PRIORITY`.
+        ReservedPriority(priority: Priority) {
+            description("Reserved priority")
+            display("Priority {} is reserved for Crust's control messages", priority)
+        }
+        /// Connection info is older than its TTL allows.
+        ConnectionInfoExpired {
+            description("Connection info expired")
+            display("Connection info expired, prepare and exchange it again")
+        }
+        /// Serialised message is larger than the configured limit.
+        MessageTooLarge(size: usize, limit: usize) {
+            description("Message too large")
+            display("Message of {} bytes exceeds the limit of {} bytes", size, limit)
+        }
+        /// Message does not carry the expected schema tag.
+        SchemaTagMismatch(expected: u16, actual: Option<u16>) {
+            description("Schema tag mismatch")
+            display("Expected schema tag {}, got {:?}", expected, actual)
+        }
+        /// Address is public while `Config::lan_only` is set.
+        LanOnlyViolation(addr: SocketAddr) {
+            description("Public address in LAN-only mode")
+            display("{} is not a private address, which LAN-only mode forbids", addr)
+        }
+        /// The config sets a field of a subsystem which was compiled out.
+        FeatureDisabled(field: &'static str, feature: &'static str) {
+            description("Config needs a feature which was compiled out")
+            display("Config sets `{}`, which needs the `{}` feature this build lacks", field,
+                    feature)
+        }
+        /// `Config::max_buffered_bytes` is below `MIN_MEMORY_BUDGET`.
+        MemoryBudgetTooSmall(bytes: usize, min: usize) {
+            description("Memory budget too small")
+            display("A memory budget of {} bytes is below the minimum of {} bytes", bytes, min)
+        }
+        /// Connection info given as text could not be decoded.
+        ConnectionInfoText(e: ConnectionInfoTextError) {
+            description("Invalid connection info text")
+            display("Invalid connection info text: {}", e)
+            from()
+        }
+    }
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<_> = paths.iter().map(|path| path.display().to_string()).collect();
+    paths.join(", ")
+}
+
+quick_error! {
+    /// Why connection info given as text, see `ConnectionInfoSource`, could not be decoded.
+    /// Positions of words count from 1.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ConnectionInfoTextError {
+        /// The text is not valid base64.
+        InvalidBase64 {
+            description("Invalid base64")
+            display("not valid base64")
+        }
+        /// A word is in neither word list, e.g. because it was misspelt.
+        UnknownWord(position: usize, word: String) {
+            description("Unknown word")
+            display("word {} ({:?}) not in word list", position, word)
+        }
+        /// A word belongs to the positions next to it, as when two neighbours are swapped.
+        MisplacedWord(position: usize, word: String) {
+            description("Misplaced word")
+            display("word {} ({:?}) out of place, it may be swapped with a neighbour", position,
+                    word)
+        }
+        /// There are no words besides the checksum.
+        TooFewWords(count: usize) {
+            description("Too few words")
+            display("only {} words", count)
+        }
+        /// The checksum doesn't match, so a word was changed or dropped.
+        ChecksumMismatch {
+            description("Checksum mismatch")
+            display("checksum mismatch, a word may be wrong or missing")
+        }
+        /// The text decodes, but not to connection info.
+        Malformed {
+            description("Malformed connection info")
+            display("not connection info")
+        }
     }
 }
@@ -7,36 +7,272 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use super::ConnectionInfoResult;
+use super::{ConnectionInfoResult, RequestId, Transport};
 
-use common::{CrustUser, Uid};
-use std::net::SocketAddr;
+use common::{
+    CrustUser, MemoryPressure, PublicKey, Rejection, RejectionCode, SharedBuffer, Uid,
+};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Why the connection to a peer was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// We disconnected from the peer, telling it the given application-supplied reason code.
+    LocalRequested(u32),
+    /// The peer disconnected from us, giving the contained reason code.
+    RemoteRequested(u32),
+    /// The connection failed or timed out, or the peer went away without saying goodbye.
+    ConnectionLost,
+    /// The peer, or something on the way to it, reset or aborted the connection.
+    RemoteClosed,
+    /// Reading from or writing to the socket failed with the given error.
+    TransportError(ErrorKind),
+    /// The system was suspended, and the peer didn't answer the probe sent when it resumed.
+    SuspendDetected,
+    /// The peer was parked and got evicted to make room for another one.
+    ParkedPeerEvicted,
+    /// The peer broke the protocol in the way described, e.g. with a "slow frame" which didn't
+    /// arrive in full within `Config::frame_completion_timeout_secs`.
+    ProtocolError(&'static str),
+    /// The peer sent a message which made no sense at that point, and
+    /// `Config::protocol_violation_policy` is to disconnect it.
+    ProtocolViolation(ProtocolViolation),
+}
+
+/// A message from a peer which decodes fine, but breaks the protocol where it is received. What is
+/// done about it is up to `Config::protocol_violation_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    /// A message of the handshake, such as a `BootstrapRequest`, once the connection is up.
+    HandshakeMessage,
+    /// A response to a request we never sent to the peer.
+    UnsolicitedResponse,
+    /// A refusal to promote us to a node, which we didn't ask for.
+    UnsolicitedPromotionFailed,
+}
+
+/// Kind of resource reported by `Event::ResourceLow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// File descriptors, or handles on Windows, see `Config::max_file_descriptors`.
+    FileDescriptors,
+}
 
 /// Enum representing different events that will be sent over the asynchronous channel to the user
 /// of this module.
 #[derive(Debug)]
 pub enum Event<UID: Uid> {
-    /// Invoked when a bootstrap peer connects to us
-    BootstrapAccept(UID, CrustUser),
-    /// Invoked when we bootstrap to a new peer.
-    BootstrapConnect(UID, SocketAddr),
+    /// Invoked when a bootstrap peer connects to us. Carries the long-term public key the peer
+    /// proved it owns, see `Service::with_keys`, or `None` if the connection isn't encrypted.
+    BootstrapAccept(UID, CrustUser, Option<PublicKey>),
+    /// Invoked when we bootstrap to a new peer. Carries the peer's public key like
+    /// `BootstrapAccept`.
+    BootstrapConnect(UID, SocketAddr, Option<PublicKey>),
     /// Invoked when we failed to connect to all bootstrap contacts.
     BootstrapFailed,
+    /// Invoked when a bootstrap contact refused us, saying why. A contact which asked us to retry
+    /// later is skipped by bootstraps until then.
+    BootstrapAttemptFailed(SocketAddr, Rejection),
     /// Invoked when we are ready to listen for incomming connection. Contains
-    /// the listening port.
+    /// the listening port. Comes once the `additional_acceptor_ports` are listened on as well.
     ListenerStarted(u16),
     /// Invoked when listener failed to start.
     ListenerFailed,
+    /// Invoked when the listener on the given address has been stopped by
+    /// `Service::stop_listener`.
+    ListenerStopped(SocketAddr),
     /// Invoked as a result to the call of `Service::prepare_contact_info`.
     ConnectionInfoPrepared(ConnectionInfoResult<UID>),
-    /// Invoked when connection to a new peer has been established.
-    ConnectSuccess(UID),
+    /// Invoked when connection to a new peer has been established. Carries the peer's public key
+    /// like `BootstrapAccept`.
+    ConnectSuccess(UID, Option<PublicKey>),
     /// Invoked when connection to a new peer has failed.
     ConnectFailure(UID),
-    /// Invoked when a peer disconnects or can no longer be contacted.
-    LostPeer(UID),
-    /// Invoked when a new message is received. Passes the message.
-    NewMessage(UID, CrustUser, Vec<u8>),
+    /// Invoked right before `ConnectFailure` if the peer refused the connection, saying why.
+    ConnectRejected(UID, Rejection),
+    /// Invoked when a peer disconnects or can no longer be contacted. Carries the connection's tag,
+    /// see `Service::set_peer_tag`.
+    LostPeer(UID, DisconnectReason, u64),
+    /// Invoked when the connection to a peer has been closed by `Service::park`. The peer is
+    /// remembered until it is unparked or evicted; eviction is reported as `LostPeer`.
+    PeerParked(UID),
+    /// Invoked when a parked peer has been reconnected to by `Service::unpark`.
+    PeerUnparked(UID),
+    /// Invoked when a node whose connection was lost has been reconnected to, see
+    /// `Config::reconnect`.
+    PeerReconnected(UID),
+    /// Invoked when every attempt at reconnecting to a node, see `Config::reconnect`, has failed.
+    ReconnectFailed(UID),
+    /// Invoked when a connected peer has advertised new listener addresses, once they have been
+    /// dialled. Its bootstrap cache entry has been replaced by those which could be reached, and
+    /// the address `Service::unpark` would re-dial has been updated.
+    PeerContactInfoUpdated(UID),
+    /// Invoked when a peer connected as a client has been found to accept connections at one of
+    /// the listeners it announced with `Service::promote_to_node`, and is treated as a node from
+    /// now on.
+    PeerPromoted {
+        /// The peer.
+        peer_id: UID,
+    },
+    /// Invoked when a peer could reach none of our listeners after `Service::promote_to_node`, and
+    /// keeps treating us as a client.
+    PromotionFailed {
+        /// The peer.
+        peer_id: UID,
+    },
+    /// Invoked when the connection to a peer was moved onto a direct one, see
+    /// `Service::upgrade_connection`, on both ends. Messages sent before go out first.
+    ConnectionUpgraded {
+        /// The peer.
+        peer_id: UID,
+        /// How we were connected to the peer until now.
+        from: Transport,
+        /// How we are connected to the peer from now on.
+        to: Transport,
+    },
+    /// Invoked when `Service::upgrade_connection` couldn't move the connection to a peer onto a
+    /// direct one, which is kept as it was.
+    ConnectionUpgradeFailed {
+        /// The peer.
+        peer_id: UID,
+    },
+    /// Invoked when a peer connecting to us was refused for being banned, see `Service::ban_peer`,
+    /// or not whitelisted, see `Service::whitelist_ip`, so that abuse can be logged. Connections
+    /// from banned IPs are dropped as they are accepted, before the peer could tell us its id.
+    PeerRejected {
+        /// Where the peer connected from.
+        addr: SocketAddr,
+        /// The peer, if it got as far as telling us.
+        peer_id: Option<UID>,
+        /// Why it was refused.
+        reason: RejectionCode,
+    },
+    /// Invoked when a connected peer announced it is about to shut down for good, see
+    /// `Service::begin_draining`. Passes the peers it suggests turning to instead.
+    PeerRetiring(UID, Vec<SocketAddr>),
+    /// Invoked when a new message is received. Passes the message and the connection's tag, see
+    /// `Service::set_peer_tag`.
+    NewMessage(UID, CrustUser, Vec<u8>, u64),
+    /// Like `NewMessage`, for several messages from the same peer in the order they were received,
+    /// if `Config::event_batching` is set.
+    NewMessages(UID, CrustUser, Vec<Vec<u8>>, u64),
+    /// Like `NewMessage`, for payloads of at least `Config::shared_payload_min_size` bytes, which
+    /// are passed on in the buffer they were received in.
+    NewSharedMessage(UID, CrustUser, SharedBuffer, u64),
+    /// Invoked when a peer sends us a request with `Service::send_request`, to be answered with
+    /// `Service::send_response`.
+    Request {
+        /// The peer.
+        peer_id: UID,
+        /// The id to answer the request under.
+        request_id: RequestId,
+        /// The request.
+        data: Vec<u8>,
+    },
+    /// Invoked when a request of ours has been answered, see `Service::send_request`.
+    Response {
+        /// The peer.
+        peer_id: UID,
+        /// The request answered.
+        request_id: RequestId,
+        /// The response.
+        data: Vec<u8>,
+    },
+    /// Invoked when a request of ours wasn't answered within `Config::request_timeout_secs` or
+    /// before the connection was lost, or couldn't be sent because the peer doesn't support
+    /// requests. A response arriving later is dropped.
+    ResponseTimedOut {
+        /// The peer.
+        peer_id: UID,
+        /// The request.
+        request_id: RequestId,
+    },
+    /// Invoked when a peer sends us more messages or bytes per second than
+    /// `Config::max_inbound_msgs_per_sec` or `Config::max_inbound_bytes_per_sec` allow. Raised at
+    /// most once every 10 seconds per connection.
+    PeerOverRate {
+        /// The peer.
+        peer_id: UID,
+        /// Messages received from the peer over the last second.
+        msgs_rate: u64,
+        /// Bytes received from the peer over the last second.
+        bytes_rate: u64,
+    },
+    /// Invoked when sending to a peer connected through a relay stops because the relay has as
+    /// much queued for the peer as it takes, see `RelayConfig::window_bytes`, and again once it
+    /// goes on. Messages sent meanwhile are queued, as for a slow peer.
+    #[cfg(feature = "relay")]
+    PeerCongested {
+        /// The peer.
+        peer_id: UID,
+        /// Whether sending stopped rather than went on.
+        congested: bool,
+    },
+    /// Invoked when messages which were retained for a lost peer, see
+    /// `Config::retention_window_secs`, are given up on, because the peer didn't reconnect in time
+    /// or too many were retained. Carries the payloads of the messages.
+    UnsentMessagesDropped(UID, Vec<Vec<u8>>),
     /// Invoked when trying to sending a too large data.
     WriteMsgSizeProhibitive(UID, Vec<u8>),
+    /// Invoked when the addresses of our network interfaces have changed. Our advertised listener
+    /// addresses have already been updated; connections over a removed address are reported as
+    /// lost.
+    NetworkInterfacesChanged {
+        /// Addresses which have appeared.
+        added: Vec<IpAddr>,
+        /// Addresses which have disappeared.
+        removed: Vec<IpAddr>,
+    },
+    /// Invoked when our listeners stop accepting connections because a resource is running low.
+    /// They resume by themselves once enough of it is available again, e.g. after connections
+    /// have closed.
+    ResourceLow {
+        /// The resource running low.
+        kind: ResourceKind,
+        /// How much of it is left.
+        available: usize,
+    },
+    /// Invoked when the socket of service discovery failed and was closed. Peers aren't discovered
+    /// on the LAN until it has been rebuilt, which is retried in the background.
+    #[cfg(feature = "service-discovery")]
+    ServiceDiscoveryDegraded,
+    /// Invoked when the socket of service discovery has been rebuilt after
+    /// `ServiceDiscoveryDegraded`.
+    #[cfg(feature = "service-discovery")]
+    ServiceDiscoveryRecovered,
+    /// Invoked when an iteration of the event loop took longer than `Config::loop_lag_warn_ms` to
+    /// handle its events, delaying everything else. Raised at most once every 10 seconds.
+    EventLoopLagging {
+        /// How long the iteration took.
+        duration: Duration,
+    },
+    /// Invoked when the bytes buffered by the service cross into another level of pressure on
+    /// `Config::max_buffered_bytes`, whether up or down.
+    MemoryPressure {
+        /// The level now in force.
+        level: MemoryPressure,
+    },
+    /// Invoked when the public addresses we detect for our listeners are on none of the IPs of
+    /// `Config::external_endpoints`. The asserted endpoints are still advertised, but the
+    /// forwarding behind them has usually broken.
+    ExternalEndpointMismatch {
+        /// The endpoints asserted.
+        asserted: Vec<SocketAddr>,
+        /// The public addresses detected which contradict them.
+        detected: Vec<SocketAddr>,
+    },
+    /// Invoked when the config file changed and the new config has been applied, see
+    /// `Service::config`. Peers no longer whitelisted have been dropped, and the listeners moved
+    /// if their ports changed, as reported by `Event::ListenerStarted` and the like.
+    ConfigUpdated,
+    /// Invoked every second while draining after `Service::begin_draining`, and once more as the
+    /// grace period ends and the service shuts down.
+    DrainingProgress {
+        /// Number of peers still connected.
+        remaining_peers: usize,
+        /// Time left before the service shuts down.
+        remaining_time: Duration,
+    },
 }
@@ -0,0 +1,132 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{CoreMessage, Uid};
+use maidsafe_utilities::event_sender::EventSenderError;
+use main::Event;
+use mio::channel::Sender;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Where every event of a service is sent through, in place of the application's
+/// `CrustEventSender`.
+///
+/// Once the application has dropped the receiving end, the sink closes: it warns once, asks the
+/// event loop to drain (see `Core::drain`) and drops every event from then on. The clones of a
+/// sink share whether it is closed, and the count of events handed over.
+#[derive(Clone)]
+pub struct EventSink<UID: Uid> {
+    tx: ::CrustEventSender<UID>,
+    closed: Arc<AtomicBool>,
+    emitted: Arc<AtomicUsize>,
+    el_tx: Sender<CoreMessage>,
+}
+
+impl<UID: Uid> EventSink<UID> {
+    /// Wraps the application's sender. `el_tx` posts to the event loop the service runs on.
+    pub fn new(tx: ::CrustEventSender<UID>, el_tx: Sender<CoreMessage>) -> Self {
+        EventSink {
+            tx,
+            closed: Arc::new(AtomicBool::new(false)),
+            emitted: Arc::new(AtomicUsize::new(0)),
+            el_tx,
+        }
+    }
+
+    pub fn send(&self, event: Event<UID>) {
+        if self.is_closed() {
+            return;
+        }
+        // Only the events themselves going nowhere closes the sink: the category channel is of
+        // no use to us, and an application which only listens to the events may well drop it.
+        if let Err(EventSenderError::EventSubset(_)) = self.tx.send(event) {
+            if self.closed.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            warn!("The event receiver has been dropped - shutting the service down");
+            let msg = CoreMessage::new(|core, poll| core.drain(poll));
+            if self.el_tx.send(msg).is_err() {
+                debug!("The event loop is gone already");
+            }
+            return;
+        }
+        let _ = self.emitted.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of events handed to the application's sender so far, which is also the sequence
+    /// number of the last of them, counting from 1. The channel keeps every event, in order, so
+    /// the application missed none if it took as many off it.
+    pub fn emitted(&self) -> u64 {
+        self.emitted.load(Ordering::SeqCst) as u64
+    }
+
+    /// Whether the application has dropped the receiving end, at least as far as we found out
+    /// by sending to it.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::event_sender::MaidSafeEventCategory;
+    use mio::channel;
+    use std::sync::mpsc;
+    use tests::UniqueId;
+
+    #[test]
+    fn closes_once_when_the_receiver_is_dropped() {
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let (el_tx, el_rx) = channel::channel();
+        let sink = EventSink::<UniqueId>::new(event_tx, el_tx);
+        let clone = sink.clone();
+
+        // A dropped category receiver doesn't matter.
+        sink.send(Event::BootstrapFailed);
+        assert!(!sink.is_closed());
+        assert!(el_rx.try_recv().is_err());
+        let _ = unwrap!(event_rx.try_recv());
+
+        drop(event_rx);
+        for _ in 0..10 {
+            clone.send(Event::BootstrapFailed);
+            sink.send(Event::BootstrapFailed);
+        }
+        assert!(sink.is_closed());
+        assert!(el_rx.try_recv().is_ok());
+        assert!(el_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn counts_the_events_handed_over() {
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx =
+            ::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let (el_tx, _el_rx) = channel::channel();
+        let sink = EventSink::<UniqueId>::new(event_tx, el_tx);
+        let clone = sink.clone();
+        assert_eq!(sink.emitted(), 0);
+
+        for _ in 0..3 {
+            sink.send(Event::BootstrapFailed);
+            clone.send(Event::ListenerFailed);
+        }
+        assert_eq!(clone.emitted(), 6);
+        assert_eq!(event_rx.try_iter().count() as u64, sink.emitted());
+
+        // Events going nowhere aren't counted.
+        drop(event_rx);
+        sink.send(Event::BootstrapFailed);
+        sink.send(Event::BootstrapFailed);
+        assert_eq!(sink.emitted(), 6);
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! The endpoints of `Config::external_endpoints`, which the operator asserts we are reachable
+//! at. They lead our listener addresses, and whatever rewrites those keeps them in place.
+
+use nat;
+use std::net::SocketAddr;
+
+/// Returns `asserted` followed by those of `listeners` which aren't asserted.
+pub fn with_asserted_endpoints(
+    asserted: &[SocketAddr],
+    listeners: &[SocketAddr],
+) -> Vec<SocketAddr> {
+    let mut merged = Vec::with_capacity(asserted.len() + listeners.len());
+    for addr in asserted.iter().chain(listeners) {
+        if !merged.contains(addr) {
+            merged.push(*addr);
+        }
+    }
+    merged
+}
+
+/// Returns those of the public addresses `detected` for our listeners which are on none of the
+/// public IPs asserted. None do if no public endpoint is asserted.
+pub fn contradicting_endpoints(
+    asserted: &[SocketAddr],
+    detected: &[SocketAddr],
+) -> Vec<SocketAddr> {
+    let asserted_ips: Vec<_> = asserted
+        .iter()
+        .map(|addr| addr.ip())
+        .filter(|ip| nat::ip_addr_is_global(ip))
+        .collect();
+    if asserted_ips.is_empty() {
+        return Vec::new();
+    }
+    detected
+        .iter()
+        .filter(|addr| nat::ip_addr_is_global(&addr.ip()) && !asserted_ips.contains(&addr.ip()))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asserted_endpoints_survive_conflicting_observations() {
+        let asserted: SocketAddr = unwrap!("203.0.113.7:40000".parse());
+        let lan: SocketAddr = unwrap!("192.168.1.7:5483".parse());
+        let observed: SocketAddr = unwrap!("198.51.100.9:5483".parse());
+
+        // The asserted endpoint leads, even where it was observed too.
+        let listeners = with_asserted_endpoints(&[asserted], &[lan, asserted, observed]);
+        assert_eq!(listeners, vec![asserted, lan, observed]);
+        assert_eq!(with_asserted_endpoints(&[], &[lan]), vec![lan]);
+
+        // Only public addresses on other IPs contradict it.
+        let same_ip = SocketAddr::new(asserted.ip(), 5483);
+        assert!(contradicting_endpoints(&[asserted], &[lan, same_ip]).is_empty());
+        assert_eq!(contradicting_endpoints(&[asserted], &[lan, observed]), vec![observed]);
+        assert!(contradicting_endpoints(&[lan], &[observed]).is_empty());
+    }
+}
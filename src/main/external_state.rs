@@ -0,0 +1,347 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! States of the application's own, run on the event loop of a `Service` alongside those of Crust,
+//! see `Service::register_state`.
+//!
+//! They are kept apart from the states of Crust: an `ExternalCore` only looks up, removes and sets
+//! the timers of states registered through it, so an external state can't disturb a connection
+//! even by mistake.
+
+use common::{Core, CoreMessage, CoreTimer, State};
+use mio::{Poll, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// A state of the application's own on the event loop of a `Service`, registered under a token
+/// with `ExternalCore::insert_state`. `Poll`, `Ready` and `Token` are those of mio 0.6.
+///
+/// The event loop calls a state with the events of the sockets it registered with `Poll` under
+/// its token, and as its timers expire. While it is called, the state isn't in the core, so
+/// `ExternalCore::get_state` doesn't reach it, but reaches any other external state. A state
+/// removing itself is dropped as soon as it returns, and its pending timers are cancelled.
+pub trait ExternalState {
+    /// Lets the state be downcast to its concrete type after `ExternalCore::get_state`.
+    fn as_any(&mut self) -> &mut Any;
+
+    /// Kind of the state, under which the event loop accounts for its work in `CoreStats`.
+    fn name(&self) -> &'static str {
+        "External"
+    }
+
+    /// Called with the readiness of the sockets registered under the state's token.
+    fn ready(&mut self, _core: &mut ExternalCore, _poll: &Poll, _kind: Ready) {}
+
+    /// Called as the timer `timer_id` of the state, set with `ExternalCore::set_timeout`, expires.
+    fn timeout(&mut self, _core: &mut ExternalCore, _poll: &Poll, _timer_id: u64) {}
+
+    /// Called as the service shuts down. The state should deregister its sockets; it is removed
+    /// from the core afterwards if it hasn't removed itself.
+    fn terminate(&mut self, _core: &mut ExternalCore, _poll: &Poll) {}
+}
+
+/// The event loop as seen by external states: a view of the core which only reaches the states
+/// registered through it.
+pub struct ExternalCore<'a> {
+    core: &'a mut Core,
+    /// The token of the state being called, which is out of the core meanwhile.
+    current: Option<Token>,
+}
+
+impl<'a> ExternalCore<'a> {
+    /// Restricts `core` to the external states.
+    pub fn new(core: &'a mut Core) -> Self {
+        ExternalCore {
+            core,
+            current: None,
+        }
+    }
+
+    /// Returns a token no other state has, to register a state and its sockets under.
+    pub fn get_new_token(&mut self) -> Token {
+        self.core.get_new_token()
+    }
+
+    /// Registers `state` under `token`, which should come from `get_new_token`. Returns `false`
+    /// without registering it if there is a state under `token` already.
+    pub fn insert_state(&mut self, token: Token, state: Rc<RefCell<ExternalState>>) -> bool {
+        if self.core.has_state(token) {
+            return false;
+        }
+        let registered = Registered { token, state };
+        let _ = self.core.insert_state(token, Rc::new(RefCell::new(registered)));
+        true
+    }
+
+    /// Removes the external state of `token` and cancels its timers. Returns whether there was
+    /// one.
+    pub fn remove_state(&mut self, token: Token) -> bool {
+        self.is_external(token) && self.core.remove_state(token)
+    }
+
+    /// Returns the external state of `token`, unless it is the state being called.
+    pub fn get_state(&self, token: Token) -> Option<Rc<RefCell<ExternalState>>> {
+        let state = self.core.get_state(token)?;
+        let mut state = state.try_borrow_mut().ok()?;
+        state
+            .as_any()
+            .downcast_mut::<Registered>()
+            .map(|registered| registered.state.clone())
+    }
+
+    /// Sets the timer `timer_id` of the external state of `token` to expire after `interval`,
+    /// replacing it if it is pending already. Returns whether it was set.
+    pub fn set_timeout(&mut self, interval: Duration, token: Token, timer_id: u64) -> bool {
+        if !self.is_external(token) {
+            return false;
+        }
+        match self.core.set_timeout(interval, CoreTimer::new(token, timer_id)) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Could not set timer {} of {:?}: {:?}", timer_id, token, e);
+                false
+            }
+        }
+    }
+
+    /// Cancels the timer `timer_id` of the external state of `token`. Returns whether it was
+    /// pending.
+    pub fn cancel_timeout(&mut self, token: Token, timer_id: u64) -> bool {
+        self.is_external(token) && self.core.cancel_timeout(token, timer_id)
+    }
+
+    /// Whether the timer `timer_id` of the external state of `token` is pending.
+    pub fn has_timeout(&self, token: Token, timer_id: u64) -> bool {
+        self.is_external(token) && self.core.has_timeout(token, timer_id)
+    }
+
+    /// Returns the current time of the event loop.
+    pub fn now(&self) -> Instant {
+        self.core.now()
+    }
+
+    /// Runs `f` on the event loop once the current event has been handled, e.g. to call a state
+    /// outside of its own dispatch. Returns whether the event loop is still running.
+    pub fn post<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&mut ExternalCore, &Poll) + Send + 'static,
+    {
+        let msg = CoreMessage::new(move |core: &mut Core, poll: &Poll| {
+            f(&mut ExternalCore::new(core), poll)
+        });
+        self.core.sender().send(msg).is_ok()
+    }
+
+    fn is_external(&self, token: Token) -> bool {
+        self.current == Some(token) || self.get_state(token).is_some()
+    }
+}
+
+/// An external state as registered with the core.
+struct Registered {
+    token: Token,
+    state: Rc<RefCell<ExternalState>>,
+}
+
+impl Registered {
+    fn view<'a>(&self, core: &'a mut Core) -> ExternalCore<'a> {
+        ExternalCore {
+            core,
+            current: Some(self.token),
+        }
+    }
+}
+
+impl State for Registered {
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+
+    fn name(&self) -> &'static str {
+        self.state.borrow().name()
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        self.state.borrow_mut().ready(&mut self.view(core), poll, kind)
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u64) {
+        self.state
+            .borrow_mut()
+            .timeout(&mut self.view(core), poll, timer_id)
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.state
+            .borrow_mut()
+            .terminate(&mut self.view(core), poll);
+        let _ = core.remove_state(self.token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{ManualEventLoop, VirtualClock};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+
+    /// Counts its timeouts and terminations, and removes itself on the timeout of `LAST_TIMER`.
+    struct Echo {
+        token: Token,
+        timeouts: Arc<AtomicUsize>,
+        terminations: Arc<AtomicUsize>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    const LAST_TIMER: u64 = 1;
+
+    impl Echo {
+        fn start(core: &mut ExternalCore) -> (Token, Arc<AtomicUsize>, Arc<AtomicBool>) {
+            let token = core.get_new_token();
+            let timeouts = Arc::new(AtomicUsize::new(0));
+            let dropped = Arc::new(AtomicBool::new(false));
+            let echo = Echo {
+                token,
+                timeouts: timeouts.clone(),
+                terminations: Arc::new(AtomicUsize::new(0)),
+                dropped: dropped.clone(),
+            };
+            assert!(core.insert_state(token, Rc::new(RefCell::new(echo))));
+            (token, timeouts, dropped)
+        }
+    }
+
+    impl Drop for Echo {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl ExternalState for Echo {
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+
+        fn timeout(&mut self, core: &mut ExternalCore, _poll: &Poll, timer_id: u64) {
+            let _ = self.timeouts.fetch_add(1, Ordering::SeqCst);
+            // Being called, the state is out of reach, but its token is its own all the same.
+            assert!(core.get_state(self.token).is_none());
+            if timer_id == LAST_TIMER {
+                assert!(core.set_timeout(Duration::from_secs(1), self.token, 0));
+                assert!(core.remove_state(self.token));
+            }
+        }
+
+        fn terminate(&mut self, _core: &mut ExternalCore, _poll: &Poll) {
+            let _ = self.terminations.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Internal;
+
+    impl State for Internal {
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    #[test]
+    fn internal_states_are_out_of_reach() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let token = core.get_new_token();
+            let _ = core.insert_state(token, Rc::new(RefCell::new(Internal)));
+            unwrap!(core.set_timeout(Duration::from_secs(1), CoreTimer::new(token, 0)));
+
+            {
+                let mut external = ExternalCore::new(core);
+                assert!(external.get_state(token).is_none());
+                assert!(!external.remove_state(token));
+                assert!(!external.set_timeout(Duration::from_secs(2), token, 0));
+                assert!(!external.cancel_timeout(token, 0));
+                assert!(!external.has_timeout(token, 0));
+                let (echo, _, _) = Echo::start(&mut external);
+                let echo = unwrap!(external.get_state(echo));
+                assert!(!external.insert_state(token, echo));
+            }
+
+            assert!(core.has_state(token));
+            assert!(core.has_timeout(token, 0));
+            let _ = tx.send(());
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(100))));
+        unwrap!(rx.try_recv());
+    }
+
+    #[test]
+    fn state_removing_itself_is_dropped_with_its_timers() {
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let mut external = ExternalCore::new(core);
+            let (token, timeouts, dropped) = Echo::start(&mut external);
+            assert!(external.set_timeout(Duration::from_secs(1), token, 0));
+            assert!(external.set_timeout(Duration::from_secs(2), token, LAST_TIMER));
+            let _ = tx.send((token, timeouts, dropped));
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        let (token, timeouts, dropped) = unwrap!(rx.try_recv());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(timeouts.load(Ordering::SeqCst), 1);
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(timeouts.load(Ordering::SeqCst), 2);
+        assert!(dropped.load(Ordering::SeqCst));
+
+        // The timer set just before the state removed itself went with it.
+        clock.advance(Duration::from_secs(1));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let _ = tx.send((core.has_state(token), core.has_timeout(token, 0)));
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        assert_eq!(unwrap!(rx.try_recv()), (false, false));
+        assert_eq!(timeouts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn draining_terminates_and_removes_external_states() {
+        let (mut el, handle) = unwrap!(ManualEventLoop::new(0));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, poll| {
+            let terminations = Arc::new(AtomicUsize::new(0));
+            let (token, _, dropped) = {
+                let mut external = ExternalCore::new(core);
+                let started = Echo::start(&mut external);
+                let state = unwrap!(external.get_state(started.0));
+                let mut state = state.borrow_mut();
+                unwrap!(state.as_any().downcast_mut::<Echo>()).terminations = terminations.clone();
+                started
+            };
+            // `Echo` doesn't remove itself as it terminates.
+            core.drain(poll);
+            let _ = tx.send((
+                core.has_state(token),
+                terminations.load(Ordering::SeqCst),
+                dropped.load(Ordering::SeqCst),
+            ));
+        })));
+        assert!(!unwrap!(el.run_once(Duration::from_millis(100))));
+        assert_eq!(unwrap!(rx.try_recv()), (false, 1, true));
+    }
+}
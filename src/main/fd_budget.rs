@@ -0,0 +1,147 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Keeps the number of connections within the file descriptors (handles on Windows) the process
+// may open.
+
+#[cfg(unix)]
+use common::nofile_soft_limit;
+use main::Config;
+use std::cmp;
+use std::fs::File;
+use std::io;
+
+/// File descriptors kept free for everything but connections: the event loop, listeners, the
+/// bootstrap cache, config files and the like.
+pub const FD_SAFETY_MARGIN: usize = 32;
+
+#[cfg(unix)]
+const NULL_DEVICE: &str = "/dev/null";
+#[cfg(windows)]
+const NULL_DEVICE: &str = "NUL";
+
+#[cfg(windows)]
+const WSAEMFILE: i32 = 10024;
+
+/// Returns the soft limit on the file descriptors of the process, if it has one.
+#[cfg(unix)]
+pub fn fd_soft_limit() -> Option<usize> {
+    match nofile_soft_limit() {
+        Ok(limit) => limit.map(|limit| limit as usize),
+        Err(e) => {
+            debug!("Could not get file descriptor limit: {}", e);
+            None
+        }
+    }
+}
+
+/// Windows has no practical per-process limit on handles, only the configured ceiling applies.
+#[cfg(not(unix))]
+pub fn fd_soft_limit() -> Option<usize> {
+    None
+}
+
+/// Number of file descriptors we may use: the lower of `Config::max_file_descriptors` and the
+/// soft limit of the process, as queried at startup.
+pub fn fd_limit(config: &Config, soft_limit: Option<usize>) -> Option<usize> {
+    match (config.max_file_descriptors, soft_limit) {
+        (Some(ceiling), Some(limit)) => Some(cmp::min(ceiling, limit)),
+        (ceiling, limit) => ceiling.or(limit),
+    }
+}
+
+/// Maximum number of connections: the lower of `Config::max_peers` and what the file descriptor
+/// budget allows once the safety margin is set aside.
+pub fn max_connections(config: &Config, soft_limit: Option<usize>) -> Option<usize> {
+    let fd_budget =
+        fd_limit(config, soft_limit).map(|limit| limit.saturating_sub(FD_SAFETY_MARGIN));
+    match (config.max_peers, fd_budget) {
+        (Some(max_peers), Some(budget)) => Some(cmp::min(max_peers, budget)),
+        (max_peers, budget) => max_peers.or(budget),
+    }
+}
+
+/// Returns whether `e` means that we, or the whole system, ran out of file descriptors.
+pub fn is_fd_exhaustion(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        use libc;
+        e.raw_os_error()
+            .map_or(false, |code| code == libc::EMFILE || code == libc::ENFILE)
+    }
+    #[cfg(windows)]
+    {
+        e.raw_os_error() == Some(WSAEMFILE)
+    }
+}
+
+/// A file descriptor held in reserve, so that one can be freed when we run out of them. This lets
+/// a listener accept, and close, the connection it couldn't accept rather than having it reported
+/// readable again and again.
+pub struct ReserveFd(Option<File>);
+
+impl ReserveFd {
+    pub fn new() -> Self {
+        let mut reserve = ReserveFd(None);
+        reserve.refill();
+        reserve
+    }
+
+    /// Frees the descriptor. Returns whether there was one.
+    pub fn release(&mut self) -> bool {
+        self.0.take().is_some()
+    }
+
+    /// Takes a descriptor again, if there is none held yet.
+    pub fn refill(&mut self) {
+        if self.0.is_none() {
+            match File::open(NULL_DEVICE) {
+                Ok(file) => self.0 = Some(file),
+                Err(e) => debug!("Could not reserve a file descriptor: {:?}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_limited_by_peers_and_fds() {
+        let mut config = Config::default();
+        assert_eq!(max_connections(&config, None), None);
+        assert_eq!(
+            max_connections(&config, Some(1024)),
+            Some(1024 - FD_SAFETY_MARGIN)
+        );
+
+        config.max_file_descriptors = Some(FD_SAFETY_MARGIN + 10);
+        assert_eq!(max_connections(&config, None), Some(10));
+        assert_eq!(max_connections(&config, Some(1024)), Some(10));
+        assert_eq!(max_connections(&config, Some(FD_SAFETY_MARGIN + 5)), Some(5));
+        assert_eq!(max_connections(&config, Some(FD_SAFETY_MARGIN - 5)), Some(0));
+
+        config.max_peers = Some(3);
+        assert_eq!(max_connections(&config, Some(1024)), Some(3));
+        config.max_file_descriptors = None;
+        assert_eq!(max_connections(&config, None), Some(3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn emfile_is_fd_exhaustion() {
+        use libc;
+
+        assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(libc::ENFILE)));
+        assert!(!is_fd_exhaustion(&io::Error::from_raw_os_error(libc::EAGAIN)));
+        assert!(!is_fd_exhaustion(&io::Error::new(io::ErrorKind::Other, "")));
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Heartbeat intervals learned per network path, see `Config::adaptive_heartbeat`.
+
+use common::{Core, State};
+use config_file_handler;
+use main::{data_file_path, AdaptiveHeartbeat, WriteBehind, INACTIVITY_TIMEOUT_MS};
+use mio::{Poll, Token};
+use serde_json;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Token of the `HeartbeatIntervals` state, which the connections take their heartbeat interval
+/// from.
+pub const HEARTBEAT_INTERVALS_TOKEN: Token = Token(6);
+
+/// Maximum number of paths learned about. Connections on further paths keep the initial interval.
+const MAX_PATHS: usize = 1024;
+/// Silences stop being lengthened once the next one would add less than this fraction of the
+/// interval.
+const MIN_STEP_DIVISOR: u64 = 20;
+/// Longest time what is learned waits to be written.
+const FLUSH_INTERVAL_SECS: u64 = 10;
+
+/// What has been learned about the NAT bindings on the paths to an address prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct PathRecord {
+    prefix: IpAddr,
+    /// Longest silence, in milliseconds, connections on the path are known to survive.
+    alive_ms: u64,
+    /// Shortest silence, in milliseconds, known to have killed a connection on the path.
+    dead_ms: Option<u64>,
+}
+
+/// A silence longer than the heartbeat interval being tried on a connection.
+struct Experiment {
+    token: Token,
+    prefix: IpAddr,
+    gap_ms: u64,
+}
+
+/// The heartbeat intervals learned per path, kept in a file across restarts. One connection at a
+/// time stays silent for longer than its interval and ends the silence with a probe: if it is
+/// answered the path's interval grows, otherwise the silence is known to kill the path's NAT
+/// bindings. The file is written on a thread of its own, with the last of the tables learned
+/// within the flush interval.
+pub struct HeartbeatIntervals {
+    token: Token,
+    settings: AdaptiveHeartbeat,
+    writer: Option<WriteBehind<Vec<PathRecord>>>,
+    paths: HashMap<IpAddr, PathRecord>,
+    experiment: Option<Experiment>,
+    last_experiment: Option<Instant>,
+}
+
+impl HeartbeatIntervals {
+    pub fn start(core: &mut Core, token: Token, settings: AdaptiveHeartbeat) {
+        let path = match table_path(&settings.table_name) {
+            Ok(path) => Some(path),
+            Err(e) => {
+                debug!("Could not resolve the heartbeat interval table: {:?}", e);
+                None
+            }
+        };
+        let paths = path.as_ref().map_or_else(HashMap::new, |path| load(path));
+        let state = Rc::new(RefCell::new(HeartbeatIntervals::new(
+            token, settings, path, paths,
+        )));
+        let _ = core.insert_state(token, state);
+    }
+
+    fn new(
+        token: Token,
+        settings: AdaptiveHeartbeat,
+        path: Option<PathBuf>,
+        paths: HashMap<IpAddr, PathRecord>,
+    ) -> Self {
+        let writer = path.and_then(|path| {
+            let interval = Duration::from_secs(FLUSH_INTERVAL_SECS);
+            let write = move |mut tables: Vec<Vec<PathRecord>>| {
+                if let Some(records) = tables.pop() {
+                    let res = write_atomically(&path, &unwrap!(serde_json::to_vec(&records)));
+                    if let Err(e) = res {
+                        debug!("Could not write heartbeat interval table {:?}: {:?}", path, e);
+                    }
+                }
+            };
+            match WriteBehind::start("Heartbeat-Intervals-Writer", interval, write) {
+                Ok(writer) => Some(writer),
+                Err(e) => {
+                    debug!("Could not start the heartbeat interval writer: {:?}", e);
+                    None
+                }
+            }
+        });
+        HeartbeatIntervals {
+            token,
+            settings,
+            writer,
+            paths,
+            experiment: None,
+            last_experiment: None,
+        }
+    }
+
+    /// Heartbeat interval of a connection to `peer`.
+    pub fn interval(&self, peer: IpAddr) -> Duration {
+        Duration::from_millis(self.interval_ms(&prefix(peer)))
+    }
+
+    /// Lets the connection `token` to `peer` try the next longer silence on its path, unless
+    /// another connection is trying one, the last one was too recent or the path's interval has
+    /// been learned. Returns how long to stay silent for.
+    pub fn start_experiment(
+        &mut self,
+        token: Token,
+        peer: IpAddr,
+        now: Instant,
+    ) -> Option<Duration> {
+        if self.experiment.is_some() {
+            return None;
+        }
+        let cool_down = Duration::from_secs(self.settings.experiment_interval_secs);
+        if self.last_experiment.map_or(false, |last| now - last < cool_down) {
+            return None;
+        }
+        let prefix = prefix(peer);
+        let gap_ms = self.next_gap_ms(&prefix)?;
+        self.experiment = Some(Experiment {
+            token,
+            prefix,
+            gap_ms,
+        });
+        self.last_experiment = Some(now);
+        Some(Duration::from_millis(gap_ms))
+    }
+
+    /// The connection `token` survived its silence. Returns its new heartbeat interval.
+    pub fn survived(&mut self, token: Token) -> Option<Duration> {
+        let experiment = self.take_experiment(token)?;
+        {
+            let record = self.record_mut(experiment.prefix)?;
+            record.alive_ms = cmp::max(record.alive_ms, experiment.gap_ms);
+        }
+        self.save();
+        Some(Duration::from_millis(self.interval_ms(&experiment.prefix)))
+    }
+
+    /// The connection `token` was lost during its silence.
+    pub fn died(&mut self, token: Token) {
+        let experiment = match self.take_experiment(token) {
+            Some(experiment) => experiment,
+            None => return,
+        };
+        {
+            let record = match self.record_mut(experiment.prefix) {
+                Some(record) => record,
+                None => return,
+            };
+            let gap_ms = experiment.gap_ms;
+            record.dead_ms = Some(
+                record
+                    .dead_ms
+                    .map_or(gap_ms, |dead_ms| cmp::min(dead_ms, gap_ms)),
+            );
+            // The bindings on the path don't last as long as they used to.
+            if record.alive_ms >= gap_ms {
+                record.alive_ms = gap_ms * 2 / 3;
+            }
+        }
+        self.save();
+    }
+
+    /// The connection `token` broke its silence, or was closed, before anything was learned.
+    pub fn abandoned(&mut self, token: Token) {
+        let _ = self.take_experiment(token);
+    }
+
+    /// The hard ceiling of the interval, which keeps well clear of the inactivity timeout of the
+    /// peer.
+    fn ceiling_ms(&self) -> u64 {
+        cmp::min(self.settings.max_interval_ms, INACTIVITY_TIMEOUT_MS / 2)
+    }
+
+    fn interval_ms(&self, prefix: &IpAddr) -> u64 {
+        let alive_ms = self
+            .paths
+            .get(prefix)
+            .map_or(self.settings.initial_interval_ms, |record| record.alive_ms);
+        cmp::min(alive_ms, self.ceiling_ms())
+    }
+
+    /// The next silence to try on the path: half as long again as its interval, but short of the
+    /// shortest one known to kill it.
+    fn next_gap_ms(&self, prefix: &IpAddr) -> Option<u64> {
+        let interval_ms = self.interval_ms(prefix);
+        let mut gap_ms = cmp::min(interval_ms * 3 / 2, self.ceiling_ms());
+        if let Some(dead_ms) = self.paths.get(prefix).and_then(|record| record.dead_ms) {
+            gap_ms = cmp::min(gap_ms, (interval_ms + dead_ms) / 2);
+        }
+        if gap_ms <= interval_ms + interval_ms / MIN_STEP_DIVISOR {
+            None
+        } else {
+            Some(gap_ms)
+        }
+    }
+
+    fn take_experiment(&mut self, token: Token) -> Option<Experiment> {
+        if self
+            .experiment
+            .as_ref()
+            .map_or(false, |experiment| experiment.token == token)
+        {
+            self.experiment.take()
+        } else {
+            None
+        }
+    }
+
+    fn record_mut(&mut self, prefix: IpAddr) -> Option<&mut PathRecord> {
+        if self.paths.len() >= MAX_PATHS && !self.paths.contains_key(&prefix) {
+            return None;
+        }
+        let alive_ms = self.settings.initial_interval_ms;
+        Some(self.paths.entry(prefix).or_insert(PathRecord {
+            prefix,
+            alive_ms,
+            dead_ms: None,
+        }))
+    }
+
+    fn save(&self) {
+        if let Some(ref writer) = self.writer {
+            writer.send(self.paths.values().cloned().collect());
+        }
+    }
+}
+
+impl State for HeartbeatIntervals {
+    fn name(&self) -> &'static str {
+        "HeartbeatIntervals"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Prefix under which what is learned about the path to `ip` is kept: its /24 for IPv4 and its /48
+/// for IPv6, which tend to sit behind the same NATs.
+fn prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], 0))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                0,
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+    }
+}
+
+/// Resolves the file of the table like the bootstrap cache's, see `Cache::new`.
+fn table_path(name: &Option<String>) -> ::Res<PathBuf> {
+    let name = match *name {
+        Some(ref name) => OsString::from(name.clone()),
+        None => {
+            let mut name = config_file_handler::exe_file_stem()?;
+            name.push(".heartbeat.intervals");
+            name
+        }
+    };
+    data_file_path(&name)
+}
+
+/// Reads the table, starting afresh if there is none or it can't be parsed.
+fn load(path: &Path) -> HashMap<IpAddr, PathRecord> {
+    let mut contents = String::new();
+    let res = File::open(path).and_then(|mut file| file.read_to_string(&mut contents));
+    if let Err(e) = res {
+        if e.kind() != io::ErrorKind::NotFound {
+            debug!("Could not read heartbeat interval table {:?}: {:?}", path, e);
+        }
+        return HashMap::new();
+    }
+    if contents.trim().is_empty() {
+        return HashMap::new();
+    }
+    match serde_json::from_str::<Vec<PathRecord>>(&contents) {
+        Ok(records) => records
+            .into_iter()
+            .take(MAX_PATHS)
+            .map(|record| (record.prefix, record))
+            .collect(),
+        Err(e) => {
+            debug!("Could not parse heartbeat interval table {:?}: {:?}", path, e);
+            HashMap::new()
+        }
+    }
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::env;
+
+    const KILL_AFTER_MS: u64 = 400;
+
+    fn settings(max_interval_ms: u64) -> AdaptiveHeartbeat {
+        AdaptiveHeartbeat {
+            initial_interval_ms: 100,
+            max_interval_ms,
+            experiment_interval_secs: 60,
+            table_name: None,
+        }
+    }
+
+    fn peer(last_octet: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(203, 0, 113, last_octet))
+    }
+
+    /// Runs experiments over a NAT which drops the bindings of links idle for `kill_after_ms`, one
+    /// connection after the other, until there is nothing left to learn. Returns the number run.
+    fn learn(intervals: &mut HeartbeatIntervals, kill_after_ms: u64, now: &mut Instant) -> usize {
+        let cool_down = Duration::from_secs(intervals.settings.experiment_interval_secs);
+        let mut experiments = 0;
+        loop {
+            let token = Token(experiments);
+            let gap = match intervals.start_experiment(token, peer(experiments as u8), *now) {
+                Some(gap) => gap,
+                None => return experiments,
+            };
+            assert!(gap <= Duration::from_millis(intervals.ceiling_ms()));
+            // Another connection can't experiment meanwhile.
+            assert!(intervals.start_experiment(Token(999), peer(0), *now).is_none());
+            if gap < Duration::from_millis(kill_after_ms) {
+                let interval = unwrap!(intervals.survived(token));
+                assert_eq!(interval, gap);
+            } else {
+                intervals.died(token);
+            }
+            experiments += 1;
+            assert!(experiments < 100, "Experiments don't converge");
+            // Too soon for the next one.
+            assert!(intervals.start_experiment(token, peer(0), *now).is_none());
+            *now += cool_down;
+        }
+    }
+
+    #[test]
+    fn interval_converges_below_nat_timeout() {
+        let mut intervals =
+            HeartbeatIntervals::new(Token(0), settings(60_000), None, HashMap::new());
+        let mut now = Instant::now();
+        assert_eq!(intervals.interval(peer(1)), Duration::from_millis(100));
+
+        let _ = learn(&mut intervals, KILL_AFTER_MS, &mut now);
+        let interval = intervals.interval(peer(1));
+        assert!(interval < Duration::from_millis(KILL_AFTER_MS));
+        assert!(interval > Duration::from_millis(KILL_AFTER_MS * 9 / 10));
+        // Consulted for any connection on the path, but not for others.
+        assert_eq!(intervals.interval(peer(200)), interval);
+        let other_path = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        assert_eq!(intervals.interval(other_path), Duration::from_millis(100));
+
+        // A silence broken by traffic teaches nothing.
+        let mut intervals =
+            HeartbeatIntervals::new(Token(0), settings(60_000), None, HashMap::new());
+        assert!(intervals.start_experiment(Token(1), peer(1), now).is_some());
+        intervals.abandoned(Token(1));
+        assert!(intervals.survived(Token(1)).is_none());
+        assert_eq!(intervals.interval(peer(1)), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn interval_never_exceeds_ceiling() {
+        let mut intervals = HeartbeatIntervals::new(Token(0), settings(250), None, HashMap::new());
+        let mut now = Instant::now();
+        let _ = learn(&mut intervals, u64::max_value(), &mut now);
+        assert_eq!(intervals.interval(peer(1)), Duration::from_millis(250));
+        assert!(intervals.start_experiment(Token(1), peer(1), now).is_none());
+    }
+
+    #[test]
+    fn learned_intervals_are_kept_across_restarts() {
+        let path = env::temp_dir().join(format!("crust-heartbeat-{}", rand::random::<u64>()));
+        let mut now = Instant::now();
+        let mut intervals =
+            HeartbeatIntervals::new(Token(0), settings(60_000), Some(path.clone()), load(&path));
+        let experiments = learn(&mut intervals, KILL_AFTER_MS, &mut now);
+        let learned = intervals.interval(peer(1));
+        // What is left to write is written once the table is dropped, as when the event loop exits.
+        drop(intervals);
+
+        let mut intervals =
+            HeartbeatIntervals::new(Token(0), settings(60_000), Some(path.clone()), load(&path));
+        assert_eq!(intervals.interval(peer(1)), learned);
+        // Nothing left to learn about the path.
+        assert!(experiments > 0);
+        assert_eq!(learn(&mut intervals, KILL_AFTER_MS, &mut now), 0);
+
+        unwrap!(fs::remove_file(&path));
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use main::Config;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two reports of the same connection being over the inbound rate limits.
+const OVER_RATE_REPORT_INTERVAL_SECS: u64 = 10;
+
+/// The rates are measured over the last second, in slots of this length.
+const SLOT_MS: u64 = 100;
+const SLOTS: usize = 10;
+
+/// Inbound traffic above which a peer is reported, see `Config::max_inbound_msgs_per_sec`.
+#[derive(Debug, Clone, Copy)]
+pub struct InboundRateLimits {
+    pub max_msgs_per_sec: Option<u64>,
+    pub max_bytes_per_sec: Option<u64>,
+    /// How long to stop reading from a peer over the limits, if at all.
+    pub penalty: Option<Duration>,
+}
+
+impl InboundRateLimits {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.max_inbound_msgs_per_sec.is_none() && config.max_inbound_bytes_per_sec.is_none() {
+            return None;
+        }
+        Some(InboundRateLimits {
+            max_msgs_per_sec: config.max_inbound_msgs_per_sec,
+            max_bytes_per_sec: config.max_inbound_bytes_per_sec,
+            penalty: config.over_rate_penalty_secs.map(Duration::from_secs),
+        })
+    }
+
+    fn exceeded_by(&self, msgs_rate: u64, bytes_rate: u64) -> bool {
+        self.max_msgs_per_sec.map_or(false, |max| msgs_rate > max)
+            || self.max_bytes_per_sec.map_or(false, |max| bytes_rate > max)
+    }
+}
+
+/// Inbound traffic of a connection, checked against its limits.
+pub struct InboundRate {
+    limits: InboundRateLimits,
+    meter: RateMeter,
+    reported_at: Option<Instant>,
+}
+
+impl InboundRate {
+    pub fn new(limits: InboundRateLimits, now: Instant) -> Self {
+        InboundRate {
+            limits,
+            meter: RateMeter::new(now),
+            reported_at: None,
+        }
+    }
+
+    pub fn limits(&self) -> &InboundRateLimits {
+        &self.limits
+    }
+
+    /// Records a message of `len` bytes and returns the message and byte rates if they are over
+    /// the limits.
+    pub fn record(&mut self, now: Instant, len: usize) -> Option<(u64, u64)> {
+        let (msgs_rate, bytes_rate) = self.meter.record(now, len);
+        if self.limits.exceeded_by(msgs_rate, bytes_rate) {
+            Some((msgs_rate, bytes_rate))
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether being over the limits should be reported now, which is at most once per
+    /// `OVER_RATE_REPORT_INTERVAL_SECS`.
+    pub fn should_report(&mut self, now: Instant) -> bool {
+        let interval = Duration::from_secs(OVER_RATE_REPORT_INTERVAL_SECS);
+        match self.reported_at {
+            Some(reported_at) if now.duration_since(reported_at) < interval => false,
+            _ => {
+                self.reported_at = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Counts messages and bytes over a sliding window of one second.
+struct RateMeter {
+    started: Instant,
+    slot: u64,
+    msgs: [u64; SLOTS],
+    bytes: [u64; SLOTS],
+}
+
+impl RateMeter {
+    fn new(now: Instant) -> Self {
+        RateMeter {
+            started: now,
+            slot: 0,
+            msgs: [0; SLOTS],
+            bytes: [0; SLOTS],
+        }
+    }
+
+    /// Records a message of `len` bytes and returns the message and byte counts of the last
+    /// second.
+    fn record(&mut self, now: Instant, len: usize) -> (u64, u64) {
+        self.advance(now);
+        let index = (self.slot % SLOTS as u64) as usize;
+        self.msgs[index] += 1;
+        self.bytes[index] += len as u64;
+        (self.msgs.iter().sum(), self.bytes.iter().sum())
+    }
+
+    /// Clears the slots which have gone out of the window since the last call.
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.started);
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000);
+        let slot = elapsed_ms / SLOT_MS;
+        if slot <= self.slot {
+            return;
+        }
+
+        let expired = cmp::min(slot - self.slot, SLOTS as u64);
+        for i in 1..expired + 1 {
+            let index = ((self.slot + i) % SLOTS as u64) as usize;
+            self.msgs[index] = 0;
+            self.bytes[index] = 0;
+        }
+        self.slot = slot;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn rates_cover_the_last_second() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+
+        for i in 0..10 {
+            let _ = meter.record(start + ms(i * 50), 100);
+        }
+        assert_eq!(meter.record(start + ms(500), 100), (11, 1100));
+
+        // The messages of the first 100ms have left the window, the others not yet.
+        assert_eq!(meter.record(start + ms(1050), 1), (10, 901));
+        // Nothing but the last message is left after a long pause.
+        assert_eq!(meter.record(start + ms(5000), 7), (1, 7));
+    }
+
+    #[test]
+    fn limits_and_reports() {
+        let limits = InboundRateLimits {
+            max_msgs_per_sec: Some(3),
+            max_bytes_per_sec: Some(1000),
+            penalty: None,
+        };
+        let start = Instant::now();
+        let mut rate = InboundRate::new(limits, start);
+
+        for _ in 0..3 {
+            assert_eq!(rate.record(start, 10), None);
+        }
+        assert_eq!(rate.record(start, 10), Some((4, 40)));
+        assert_eq!(rate.record(start + ms(2000), 1001), Some((1, 1001)));
+
+        assert!(rate.should_report(start));
+        assert!(!rate.should_report(start + ms(9000)));
+        assert!(rate.should_report(start + ms(10_000)));
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Core, CoreMessage, CoreTimer, State, Uid};
+use get_if_addrs;
+use main::{
+    advertise_listeners, ActiveConnection, ConnectionMap, CrustConfig, CrustError, Event,
+    EventSink,
+};
+use mio::{Poll, Token};
+use nat::{self, MappingContext};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Lists the IP addresses of our network interfaces.
+pub trait InterfaceLister: Send {
+    /// Returns the addresses of all interfaces which are currently up.
+    fn local_ips(&mut self) -> io::Result<Vec<IpAddr>>;
+}
+
+/// Lists interfaces using the operating system.
+pub struct IfAddrsLister;
+
+impl InterfaceLister for IfAddrsLister {
+    fn local_ips(&mut self) -> io::Result<Vec<IpAddr>> {
+        Ok(get_if_addrs::get_if_addrs()?
+            .into_iter()
+            .map(|interface| interface.ip())
+            .collect())
+    }
+}
+
+/// Result of listing the interfaces, see `InterfaceLister::local_ips`.
+type Scan = io::Result<Vec<IpAddr>>;
+
+/// Periodically scans our network interfaces. When addresses appear or disappear, our advertised
+/// listener addresses are updated, connections going over a vanished address are dropped straight
+/// away rather than when their heartbeat times out, and the mapping context is rebuilt if the IPv4
+/// interfaces (and thus potentially the gateways) changed.
+///
+/// Listing the interfaces can take a while, so it is left to a thread of its own, see
+/// `spawn_scanner`, rather than holding up the event loop.
+pub struct InterfaceMonitor<UID: Uid> {
+    token: Token,
+    timer: CoreTimer,
+    interval: Duration,
+    lan_only: bool,
+    scan_tx: Sender<()>,
+    scan_rx: Receiver<Scan>,
+    scanning: bool,
+    /// Addresses of the last scan, `None` until the first one is in.
+    known_ips: Option<HashSet<IpAddr>>,
+    /// Told how the first scan went, which the others are compared with.
+    started: Option<Sender<::Res<()>>>,
+    cm: ConnectionMap<UID>,
+    mc: Arc<Mutex<Arc<MappingContext>>>,
+    our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    config: CrustConfig,
+    event_tx: EventSink<UID>,
+}
+
+impl<UID: Uid> InterfaceMonitor<UID> {
+    pub fn start(
+        core: &mut Core,
+        token: Token,
+        interval: Duration,
+        lan_only: bool,
+        lister: Box<InterfaceLister>,
+        cm: ConnectionMap<UID>,
+        mc: Arc<Mutex<Arc<MappingContext>>>,
+        our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+        config: CrustConfig,
+        event_tx: EventSink<UID>,
+        started: Sender<::Res<()>>,
+    ) -> ::Res<()> {
+        trace!("Entered state InterfaceMonitor");
+
+        let (scan_tx, scan_rx) = spawn_scanner::<UID>(core, token, lister)?;
+        let _ = scan_tx.send(());
+
+        let timer = CoreTimer::new(token, 0);
+        core.set_periodic_timeout(interval, timer)?;
+
+        let state = Rc::new(RefCell::new(InterfaceMonitor {
+            token,
+            timer,
+            interval,
+            lan_only,
+            scan_tx,
+            scan_rx,
+            scanning: true,
+            known_ips: None,
+            started: Some(started),
+            cm,
+            mc,
+            our_listeners,
+            config,
+            event_tx,
+        }));
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+
+    /// Handles the scans the scanner thread has finished.
+    fn take_scans(&mut self, core: &mut Core, poll: &Poll) {
+        while let Ok(scan) = self.scan_rx.try_recv() {
+            self.scanning = false;
+            match scan {
+                Ok(ips) => self.handle_scan(core, poll, ips.into_iter().collect()),
+                Err(e) => {
+                    debug!("Could not list network interfaces: {:?}", e);
+                    // Without a first scan there is nothing to compare the others with.
+                    if let Some(started) = self.started.take() {
+                        let _ = started.send(Err(CrustError::Io(e)));
+                        return self.terminate(core, poll);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_scan(&mut self, core: &mut Core, poll: &Poll, current_ips: HashSet<IpAddr>) {
+        let (mut added, mut removed): (Vec<_>, Vec<_>) = match self.known_ips {
+            Some(ref known_ips) => (
+                current_ips.difference(known_ips).cloned().collect(),
+                known_ips.difference(&current_ips).cloned().collect(),
+            ),
+            None => {
+                self.known_ips = Some(current_ips);
+                if let Some(started) = self.started.take() {
+                    let _ = started.send(Ok(()));
+                }
+                return;
+            }
+        };
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        added.sort();
+        removed.sort();
+        self.known_ips = Some(current_ips);
+
+        info!(
+            "Network interfaces changed - added: {:?}, removed: {:?}",
+            added, removed
+        );
+
+        let dual_stack = unwrap!(self.config.lock()).cfg.dual_stack;
+        let listeners = self.refresh_listeners(&added, &removed, dual_stack);
+        if added
+            .iter()
+            .chain(removed.iter())
+            .any(|ip| (ip.is_ipv4() || dual_stack) && !ip.is_loopback())
+        {
+            refresh_mapping_context(&self.mc, self.lan_only);
+        }
+
+        self.event_tx.send(Event::NetworkInterfacesChanged {
+            added,
+            removed: removed.clone(),
+        });
+
+        self.drop_stale_connections(core, poll, &removed);
+        if let Some(listeners) = listeners {
+            advertise_listeners(core, poll, &self.cm, &listeners);
+        }
+    }
+
+    /// Returns our new listeners if they have changed. Asserted endpoints are neither ours to
+    /// withdraw nor do their ports say anything about those we listen on.
+    fn refresh_listeners(
+        &self,
+        added: &[IpAddr],
+        removed: &[IpAddr],
+        dual_stack: bool,
+    ) -> Option<Vec<SocketAddr>> {
+        let asserted = unwrap!(self.config.lock()).external_endpoints.clone();
+        let mut our_listeners = unwrap!(self.our_listeners.lock());
+        let mut ports = Vec::new();
+        for addr in our_listeners.iter().filter(|addr| !asserted.contains(addr)) {
+            if !ports.contains(&addr.port()) {
+                ports.push(addr.port());
+            }
+        }
+        let old_listeners = our_listeners.clone();
+
+        our_listeners.retain(|addr| asserted.contains(addr) || !removed.contains(&addr.ip()));
+        // Only dual-stack listeners accept connections on IPv6 addresses.
+        let listenable = |ip: &IpAddr| match *ip {
+            IpAddr::V4(..) => true,
+            IpAddr::V6(ref ip) => dual_stack && !nat::ipv6_addr_is_link_local(ip),
+        };
+        for ip in added.iter().filter(|ip| {
+            listenable(ip) && !ip.is_loopback() && !(self.lan_only && nat::ip_addr_is_global(ip))
+        }) {
+            for port in &ports {
+                let addr = SocketAddr::new(*ip, *port);
+                if !our_listeners.contains(&addr) {
+                    our_listeners.push(addr);
+                }
+            }
+        }
+
+        if *our_listeners == old_listeners {
+            None
+        } else {
+            Some(our_listeners.clone())
+        }
+    }
+
+    fn drop_stale_connections(&self, core: &mut Core, poll: &Poll, removed: &[IpAddr]) {
+        if removed.is_empty() {
+            return;
+        }
+
+        // Peers collected to avoid keeping the mutex lock alive which might lead to deadlock
+        let tokens: Vec<_> = unwrap!(self.cm.lock())
+            .values()
+            .filter_map(|cid| cid.active_connection)
+            .collect();
+
+        let stale_peers: Vec<_> = tokens
+            .into_iter()
+            .filter_map(|token| core.get_state(token))
+            .filter(|peer| {
+                let mut state = peer.borrow_mut();
+                match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    Some(ac) => ac
+                        .local_addr()
+                        .map_or(true, |addr| removed.contains(&addr.ip())),
+                    None => false,
+                }
+            })
+            .collect();
+
+        for peer in stale_peers {
+            peer.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+/// Starts the thread listing the interfaces with `lister` each time it is asked to through the
+/// returned sender. It hands each list over through the returned receiver, and wakes the monitor
+/// at `token` up to take it. The thread ends along with the monitor, which drops both channels.
+fn spawn_scanner<UID: Uid>(
+    core: &Core,
+    token: Token,
+    mut lister: Box<InterfaceLister>,
+) -> ::Res<(Sender<()>, Receiver<Scan>)> {
+    let (request_tx, request_rx) = mpsc::channel::<()>();
+    let (scan_tx, scan_rx) = mpsc::channel();
+    let core_tx = core.sender().clone();
+    let _ = thread::Builder::new()
+        .name("CrustInterfaceScan".to_owned())
+        .spawn(move || {
+            for () in request_rx {
+                if scan_tx.send(lister.local_ips()).is_err() {
+                    break;
+                }
+                let _ = core_tx.send(CoreMessage::new(move |core, poll| {
+                    let state = match core.get_state(token) {
+                        Some(state) => state,
+                        None => return,
+                    };
+                    let mut state = state.borrow_mut();
+                    if let Some(monitor) = state.as_any().downcast_mut::<InterfaceMonitor<UID>>() {
+                        monitor.take_scans(core, poll);
+                    }
+                }));
+            }
+        })?;
+    Ok((request_tx, scan_rx))
+}
+
+/// Rebuilds the mapping context in the background, for gateways which may have changed. The peers
+/// known to run STUN are kept.
+pub fn refresh_mapping_context(mc: &Arc<Mutex<Arc<MappingContext>>>, lan_only: bool) {
+    let mc = mc.clone();
+    let peer_stuns = unwrap!(mc.lock()).peer_stuns().clone();
+    let res = thread::Builder::new()
+        .name("CrustMappingContextRefresh".to_owned())
+        .spawn(move || {
+            let new_mc = if lan_only {
+                MappingContext::without_igd()
+            } else {
+                MappingContext::new()
+            };
+            match new_mc {
+                Ok(mut new_mc) => {
+                    new_mc.add_peer_stuns(peer_stuns);
+                    *unwrap!(mc.lock()) = Arc::new(new_mc);
+                }
+                Err(e) => debug!("Could not refresh mapping context: {:?}", e),
+            }
+        });
+    if let Err(e) = res {
+        debug!("Could not spawn mapping context refresh: {:?}", e);
+    }
+}
+
+impl<UID: Uid> State for InterfaceMonitor<UID> {
+    fn name(&self) -> &'static str {
+        "InterfaceMonitor"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        if let Err(e) = core.set_periodic_timeout(self.interval, self.timer) {
+            debug!("Interface Monitor Timer Errored out: {:?}", e);
+            return self.terminate(core, poll);
+        }
+
+        // A scan which takes longer than the interval isn't piled up on.
+        if !self.scanning {
+            self.scanning = self.scan_tx.send(()).is_ok();
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Lower bound of the first octave of the histogram. Faster round trips share a single bucket.
+const MIN_US: u64 = 128;
+/// Octaves covered above `MIN_US`, up to about 134 seconds. Slower round trips are counted in the
+/// last bucket.
+const OCTAVES: usize = 20;
+/// Buckets per octave: each is reported with at most 25% error.
+const SUB_BUCKETS: usize = 4;
+const SUB_BUCKET_BITS: u32 = 2;
+
+/// Probes whose answers we wait for at most. Any sent while this many are outstanding aren't
+/// timed.
+pub const MAX_TIMED_PROBES: usize = 8;
+
+/// Histogram of the round trip times to a peer, in fixed log-linear buckets, so that it takes the
+/// same few hundred bytes however many samples it holds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    below_min: u32,
+    octaves: [[u32; SUB_BUCKETS]; OCTAVES],
+}
+
+impl LatencyHistogram {
+    /// Counts a round trip of the given duration.
+    pub fn record(&mut self, rtt: Duration) {
+        let (octave, sub) = bucket_of(rtt);
+        let count = match octave {
+            Some(octave) => &mut self.octaves[octave][sub],
+            None => &mut self.below_min,
+        };
+        *count = count.saturating_add(1);
+    }
+
+    /// Number of round trips counted.
+    pub fn samples(&self) -> u64 {
+        self.counts().into_iter().map(u64::from).sum()
+    }
+
+    /// The round trip time which `percent` percent of the samples didn't exceed, rounded up to the
+    /// upper bound of its bucket. `None` if nothing has been recorded yet.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let samples = self.samples();
+        if samples == 0 {
+            return None;
+        }
+        let percent = percent.max(0.0).min(100.0);
+        let rank = cmp::max(1, (samples as f64 * percent / 100.0).ceil() as u64);
+        let mut seen = 0;
+        for (index, count) in self.counts().into_iter().enumerate() {
+            seen += u64::from(count);
+            if seen >= rank {
+                return Some(upper_bound(index));
+            }
+        }
+        Some(upper_bound(OCTAVES * SUB_BUCKETS))
+    }
+
+    /// The median round trip time, see `percentile`.
+    pub fn p50(&self) -> Option<Duration> {
+        self.percentile(50.0)
+    }
+
+    /// The 95th percentile of the round trip times, see `percentile`.
+    pub fn p95(&self) -> Option<Duration> {
+        self.percentile(95.0)
+    }
+
+    /// The 99th percentile of the round trip times, see `percentile`.
+    pub fn p99(&self) -> Option<Duration> {
+        self.percentile(99.0)
+    }
+
+    /// The buckets holding any samples, as the upper bound of each and its count, fastest first.
+    /// The last bucket also counts everything slower than its bound.
+    pub fn buckets(&self) -> Vec<(Duration, u32)> {
+        self.counts()
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .map(|(index, count)| (upper_bound(index), count))
+            .collect()
+    }
+
+    /// Counts of all buckets, fastest first.
+    fn counts(&self) -> Vec<u32> {
+        let mut counts = Vec::with_capacity(1 + OCTAVES * SUB_BUCKETS);
+        counts.push(self.below_min);
+        for octave in &self.octaves {
+            counts.extend_from_slice(octave);
+        }
+        counts
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1_000_000)
+        .saturating_add(u64::from(duration.subsec_nanos() / 1_000))
+}
+
+fn from_micros(us: u64) -> Duration {
+    Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1_000)
+}
+
+/// The octave above `MIN_US` and the bucket within it which `rtt` falls into, or no octave if it
+/// is below `MIN_US`.
+fn bucket_of(rtt: Duration) -> (Option<usize>, usize) {
+    let us = as_micros(rtt);
+    if us < MIN_US {
+        return (None, 0);
+    }
+    let msb = 63 - us.leading_zeros();
+    let octave = (msb - MIN_US.trailing_zeros()) as usize;
+    if octave >= OCTAVES {
+        return (Some(OCTAVES - 1), SUB_BUCKETS - 1);
+    }
+    let sub = (us >> (msb - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (Some(octave), sub)
+}
+
+/// Upper bound of the bucket at `index`, where 0 is the one below `MIN_US`.
+fn upper_bound(index: usize) -> Duration {
+    if index == 0 {
+        return from_micros(MIN_US);
+    }
+    let octave = (index - 1) / SUB_BUCKETS;
+    let sub = (index - 1) % SUB_BUCKETS;
+    let base = MIN_US << octave;
+    from_micros(base + (sub as u64 + 1) * (base >> SUB_BUCKET_BITS))
+}
+
+/// Send times of the probes whose answers are outstanding, to time their round trips. The peer
+/// answers probes in order, so each answer belongs to the oldest probe outstanding.
+#[derive(Default)]
+pub struct ProbeTimes {
+    sent: VecDeque<Instant>,
+    /// Answers still due to probes which weren't timed.
+    untimed: u32,
+}
+
+impl ProbeTimes {
+    pub fn sent(&mut self, now: Instant) {
+        if self.untimed == 0 && self.sent.len() < MAX_TIMED_PROBES {
+            self.sent.push_back(now);
+        } else {
+            self.untimed += 1;
+        }
+    }
+
+    /// Returns the round trip time of the probe this answers, if it was timed.
+    pub fn answered(&mut self, now: Instant) -> Option<Duration> {
+        if let Some(sent_at) = self.sent.pop_front() {
+            return Some(now - sent_at);
+        }
+        self.untimed = self.untimed.saturating_sub(1);
+        None
+    }
+}
+
+/// Folds a round trip time into the smoothed one, weighing it by an eighth as TCP does.
+pub fn smooth_rtt(smoothed: Option<Duration>, rtt: Duration) -> Duration {
+    match smoothed {
+        Some(smoothed) => (smoothed * 7 + rtt) / 8,
+        None => rtt,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn percentiles_bracket_recorded_latencies() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.p50(), None);
+        assert!(histogram.buckets().is_empty());
+
+        for _ in 0..90 {
+            histogram.record(ms(10));
+        }
+        for _ in 0..9 {
+            histogram.record(ms(200));
+        }
+        histogram.record(ms(3_000));
+        assert_eq!(histogram.samples(), 100);
+
+        let bracket = |reported: Option<Duration>, latency: Duration| {
+            let reported = unwrap!(reported);
+            assert!(reported > latency, "{:?} <= {:?}", reported, latency);
+            assert!(reported <= latency * 5 / 4, "{:?} too far off {:?}", reported, latency);
+        };
+        bracket(histogram.p50(), ms(10));
+        bracket(histogram.p95(), ms(200));
+        bracket(histogram.p99(), ms(200));
+        bracket(histogram.percentile(100.0), ms(3_000));
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(
+            buckets.iter().map(|&(_, count)| count).collect::<Vec<_>>(),
+            vec![90, 9, 1]
+        );
+    }
+
+    #[test]
+    fn out_of_range_latencies_are_clamped() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(from_micros(5));
+        assert_eq!(histogram.p99(), Some(from_micros(MIN_US)));
+
+        histogram.record(Duration::from_secs(1_000_000));
+        histogram.record(Duration::from_secs(u64::max_value()));
+        let top = unwrap!(histogram.percentile(100.0));
+        assert_eq!(top, upper_bound(OCTAVES * SUB_BUCKETS));
+        assert_eq!(histogram.buckets().last(), Some(&(top, 2)));
+    }
+
+    #[test]
+    fn answers_are_matched_to_probes_in_order() {
+        let start = Instant::now();
+        let mut probes = ProbeTimes::default();
+        assert_eq!(probes.answered(start), None);
+
+        probes.sent(start);
+        probes.sent(start + ms(5));
+        assert_eq!(probes.answered(start + ms(10)), Some(ms(10)));
+        assert_eq!(probes.answered(start + ms(10)), Some(ms(5)));
+
+        // Once too many are outstanding, later probes aren't timed until all have been answered.
+        for i in 0..MAX_TIMED_PROBES + 2 {
+            probes.sent(start + ms(i as u64));
+        }
+        for _ in 0..MAX_TIMED_PROBES {
+            assert!(probes.answered(start + ms(100)).is_some());
+        }
+        probes.sent(start + ms(100));
+        assert_eq!(probes.answered(start + ms(100)), None);
+        assert_eq!(probes.answered(start + ms(100)), None);
+        assert_eq!(probes.answered(start + ms(100)), None);
+        probes.sent(start + ms(100));
+        assert_eq!(probes.answered(start + ms(101)), Some(ms(1)));
+    }
+}
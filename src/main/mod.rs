@@ -7,19 +7,65 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-pub use self::active_connection::{ActiveConnection, INACTIVITY_TIMEOUT_MS};
-pub use self::bootstrap::Bootstrap;
-pub use self::config_handler::{Config, DevConfig};
+pub use self::active_connection::{
+    advertise_listeners, announce_retirement, probe_after_resume, promote_to_node,
+    shed_droppable_msgs, ActiveConnection, ConnectionSettings, HEARTBEAT_PERIOD_MS,
+    INACTIVITY_TIMEOUT_MS,
+};
+pub use self::bootstrap::{
+    sort_by_score, Bootstrap, BootstrapCacheEntry, Cache, CacheUpdate, CacheWriter, ContactFailure,
+    ContactHealth, PathHistory, PathKind, RetryAfter, BOOTSTRAP_TIMEOUT_SEC,
+};
+pub use self::config_handler::{
+    AdaptiveHeartbeat, AuditConfig, Config, DevConfig, DuplicateConnectionPolicy, EventBatching,
+    ReconnectPolicy, RelayConfig, ViolationPolicy,
+};
 pub use self::config_refresher::ConfigRefresher;
-pub use self::connect::Connect;
+pub use self::connect::{Connect, Redial, CONNECT_STAGGER_MS};
 pub use self::connection_candidate::ConnectionCandidate;
-pub use self::connection_listener::ConnectionListener;
-pub use self::error::CrustError;
-pub use self::event::Event;
-pub use self::service::Service;
+pub use self::connection_listener::{
+    decode_handshake_request, CheckReachability, ConnectionListener, HandshakeRequest,
+    ListenerGroup, ListenerOptions,
+};
+pub use self::connection_info_text::ConnectionInfoSource;
+pub use self::diagnostics::{
+    DiagnosticCheck, DiagnosticResult, DiagnosticStatus, DiagnosticsReport,
+    DIAGNOSTICS_CONNECT_TIMEOUT_SECS,
+};
+pub use self::error::{ConnectionInfoTextError, CrustError};
+pub use self::event::{DisconnectReason, Event, ProtocolViolation, ResourceKind};
+pub use self::event_sink::EventSink;
+pub use self::external_endpoints::{contradicting_endpoints, with_asserted_endpoints};
+pub use self::external_state::{ExternalCore, ExternalState};
+pub use self::fd_budget::{
+    fd_limit, fd_soft_limit, is_fd_exhaustion, max_connections, ReserveFd, FD_SAFETY_MARGIN,
+};
+pub use self::heartbeat_intervals::{HeartbeatIntervals, HEARTBEAT_INTERVALS_TOKEN};
+pub use self::inbound_rate::{InboundRate, InboundRateLimits};
+pub use self::interface_monitor::{
+    refresh_mapping_context, IfAddrsLister, InterfaceLister, InterfaceMonitor,
+};
+pub use self::latency::{smooth_rtt, LatencyHistogram, ProbeTimes};
+pub use self::parked_peers::{ParkedPeers, ParkedTable, PeerStats};
+pub use self::promotion::{ListenerChecks, Promotion, PromotionCheck, ReachabilityChecks};
+#[cfg(feature = "relay")]
+pub use self::relay::{relays_of_peers, RelayState, RELAY_TOKEN};
+pub use self::reconnects::{Reconnects, RECONNECTS_TOKEN};
+pub use self::requests::{PendingRequests, RequestId, ResponseMatch, DEFAULT_REQUEST_TIMEOUT_SECS};
+pub use self::retained_queues::{RetainedQueues, RETAINED_QUEUES_TOKEN};
+pub use self::retirement::{Retirement, MAX_RETIREMENT_ALTERNATIVES, RETIREMENT_TOKEN};
+pub use self::service::{Service, ServiceCore};
+pub use self::shutdown::Shutdown;
+pub use self::snapshot::{PeerContact, ServiceSnapshot};
+pub use self::suspend_monitor::{SuspendMonitor, SUSPEND_MONITOR_TOKEN};
 pub use self::types::{
-    ConfigWrapper, ConnectionId, ConnectionInfoResult, PrivConnectionInfo, PubConnectionInfo,
+    now_secs, CandidateAddr, ConfigWrapper, ConnectedPeer, ConnectionId, ConnectionInfoResult,
+    NetworkStats, PeerLimits, PrivConnectionInfo, PubConnectionInfo, Transport,
 };
+pub use self::upgrades::{
+    with_upgrades, Stage, Switch, Upgrade, UpgradeLeg, Upgrades, UPGRADES_TOKEN,
+};
+pub use self::write_behind::WriteBehind;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -32,10 +78,37 @@ mod config_handler;
 mod config_refresher;
 mod connect;
 mod connection_candidate;
+mod connection_info_text;
 mod connection_listener;
+mod diagnostics;
 mod error;
 mod event;
+mod event_sink;
+mod external_endpoints;
+mod external_state;
+mod fd_budget;
+mod heartbeat_intervals;
+mod inbound_rate;
+mod interface_monitor;
+mod latency;
+mod parked_peers;
+mod promotion;
+mod reconnects;
+#[cfg(feature = "relay")]
+mod relay;
+mod requests;
+mod retained_queues;
+mod retirement;
 mod service;
+mod shutdown;
+mod snapshot;
+mod suspend_monitor;
+mod tagged_message;
 mod types;
+mod upgrades;
+mod write_behind;
 
-pub use self::config_handler::read_config_file;
+pub use self::config_handler::{
+    config_search_paths, data_file_path, read_config, read_config_file, CONFIG_PATH_ENV_VAR,
+    CONFIG_VERSION,
+};
@@ -0,0 +1,252 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{CrustUser, NegotiatedFeatures, OneWayLatency, Priority, Uid};
+use main::{LatencyHistogram, PeerContact};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Maximum number of messages queued for a parked peer while it is being unparked.
+pub const MAX_PENDING_MSGS: usize = 64;
+
+pub type ParkedPeers<UID> = Arc<Mutex<ParkedTable<UID>>>;
+
+/// Traffic statistics of a peer, kept across parking and unparking.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Number of messages sent to the peer.
+    pub msgs_sent: u64,
+    /// Number of messages received from the peer.
+    pub msgs_received: u64,
+    /// Number of bytes sent to the peer, framing included.
+    pub bytes_sent: u64,
+    /// Number of bytes received from the peer, framing included.
+    pub bytes_received: u64,
+    /// Bytes of the messages queued for the peer which haven't been written yet.
+    pub queued_bytes: u64,
+    /// Round trip times of the probes answered by the peer, see
+    /// `Config::latency_probe_interval_secs`.
+    pub latency: LatencyHistogram,
+    /// Round trip time of the probes smoothed over the recent ones, `None` until one is answered.
+    pub smoothed_rtt: Option<Duration>,
+    /// Latencies of either direction, estimated from timestamps on the frames if agreed on, see
+    /// `Config::timestamp_frames`.
+    pub one_way_latency: OneWayLatency,
+    /// What was agreed on in the handshake of the connection the stats are read from.
+    pub features: NegotiatedFeatures,
+    /// Time since the connection the stats are read from was made. Not reset with the rest.
+    pub connected_for: Duration,
+    /// Number of responses from the peer dropped because they came after the request timed out.
+    pub unmatched_responses: u64,
+    /// Number of responses from the peer dropped because the request had been answered already.
+    pub duplicate_responses: u64,
+    /// Number of messages from the peer which broke the protocol, see `ProtocolViolation`.
+    pub protocol_violations: u64,
+}
+
+/// What we retain about a peer whose connection was closed by `Service::park`.
+pub struct ParkedPeer {
+    pub addr: SocketAddr,
+    pub kind: CrustUser,
+    pub stats: PeerStats,
+    pub parked_at: Instant,
+    pub unparking: bool,
+    pub pending: Vec<(Vec<u8>, Priority)>,
+    last_used: u64,
+}
+
+/// Bounded table of parked peers. When full, the least recently used peer is evicted.
+pub struct ParkedTable<UID> {
+    capacity: usize,
+    peers: HashMap<UID, ParkedPeer>,
+    clock: u64,
+}
+
+impl<UID: Uid> ParkedTable<UID> {
+    pub fn new(capacity: usize) -> Self {
+        ParkedTable {
+            capacity,
+            peers: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Parks the given peer and returns the peer evicted to make room for it, if any.
+    pub fn insert(
+        &mut self,
+        uid: UID,
+        addr: SocketAddr,
+        kind: CrustUser,
+        stats: PeerStats,
+        now: Instant,
+    ) -> Option<UID> {
+        let mut evicted = None;
+        if !self.peers.contains_key(&uid) && self.peers.len() >= self.capacity {
+            evicted = self
+                .peers
+                .iter()
+                .min_by_key(|&(_, peer)| peer.last_used)
+                .map(|(uid, _)| *uid);
+            if let Some(ref evicted) = evicted {
+                let _ = self.peers.remove(evicted);
+            }
+        }
+
+        if self.capacity == 0 {
+            return Some(uid);
+        }
+
+        let last_used = self.tick();
+        let _ = self.peers.insert(
+            uid,
+            ParkedPeer {
+                addr,
+                kind,
+                stats,
+                parked_at: now,
+                unparking: false,
+                pending: Vec::new(),
+                last_used,
+            },
+        );
+        evicted
+    }
+
+    pub fn contains(&self, uid: &UID) -> bool {
+        self.peers.contains_key(uid)
+    }
+
+    pub fn get(&self, uid: &UID) -> Option<&ParkedPeer> {
+        self.peers.get(uid)
+    }
+
+    /// Clears the stats of the given peer. Returns `false` if it isn't parked.
+    pub fn reset_stats(&mut self, uid: &UID) -> bool {
+        match self.peers.get_mut(uid) {
+            Some(peer) => {
+                peer.stats = PeerStats::default();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the parked nodes with the addresses they would be re-dialled at.
+    pub fn node_contacts(&self) -> Vec<PeerContact<UID>> {
+        self.peers
+            .iter()
+            .filter(|&(_, peer)| peer.kind == CrustUser::Node)
+            .map(|(uid, peer)| PeerContact {
+                id: *uid,
+                addr: peer.addr,
+            })
+            .collect()
+    }
+
+    /// Marks the peer as being unparked and returns the address to re-dial, unless it is being
+    /// unparked already.
+    pub fn start_unpark(&mut self, uid: &UID) -> Option<SocketAddr> {
+        let last_used = self.tick();
+        let peer = self.peers.get_mut(uid)?;
+        peer.last_used = last_used;
+        if peer.unparking {
+            return None;
+        }
+        peer.unparking = true;
+        Some(peer.addr)
+    }
+
+    /// Keeps the peer parked after a failed unpark. Messages queued for it are dropped.
+    pub fn unpark_failed(&mut self, uid: &UID) {
+        if let Some(peer) = self.peers.get_mut(uid) {
+            peer.unparking = false;
+            peer.pending.clear();
+        }
+    }
+
+    /// Queues a message to be sent once the peer has been unparked. Returns `false` if the peer
+    /// isn't parked or too many messages are queued already.
+    pub fn queue(&mut self, uid: &UID, msg: Vec<u8>, priority: Priority) -> bool {
+        let last_used = self.tick();
+        let peer = match self.peers.get_mut(uid) {
+            Some(peer) => peer,
+            None => return false,
+        };
+        peer.last_used = last_used;
+        if peer.pending.len() >= MAX_PENDING_MSGS {
+            return false;
+        }
+        peer.pending.push((msg, priority));
+        true
+    }
+
+    /// Removes the peer once it has been unparked, returning its stats and queued messages.
+    pub fn finish_unpark(&mut self, uid: &UID) -> Option<(PeerStats, Vec<(Vec<u8>, Priority)>)> {
+        self.peers
+            .remove(uid)
+            .map(|peer| (peer.stats, peer.pending))
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::UniqueId;
+
+    fn id(n: u8) -> UniqueId {
+        [n; 20]
+    }
+
+    fn park(table: &mut ParkedTable<UniqueId>, n: u8) -> Option<UniqueId> {
+        let addr = unwrap!(format!("127.0.0.1:{}", 5000 + u16::from(n)).parse());
+        table.insert(id(n), addr, CrustUser::Node, PeerStats::default(), Instant::now())
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut table = ParkedTable::new(3);
+        assert_eq!(park(&mut table, 1), None);
+        assert_eq!(park(&mut table, 2), None);
+        assert_eq!(park(&mut table, 3), None);
+
+        // Using peer 1 makes peer 2 the least recently used one.
+        assert!(table.queue(&id(1), vec![1], 0));
+        assert_eq!(park(&mut table, 4), Some(id(2)));
+        assert_eq!(table.peers.len(), 3);
+        assert!(table.contains(&id(1)));
+        assert!(!table.contains(&id(2)));
+    }
+
+    #[test]
+    fn unpark() {
+        let mut table = ParkedTable::new(3);
+        let _ = park(&mut table, 1);
+
+        let addr = unwrap!(table.start_unpark(&id(1)));
+        assert_eq!(addr.port(), 5001);
+        assert_eq!(table.start_unpark(&id(1)), None);
+        assert!(table.queue(&id(1), vec![1], 0));
+
+        table.unpark_failed(&id(1));
+        assert!(unwrap!(table.get(&id(1))).pending.is_empty());
+
+        assert!(table.start_unpark(&id(1)).is_some());
+        assert!(table.queue(&id(1), vec![2], 0));
+        let (_, pending) = unwrap!(table.finish_unpark(&id(1)));
+        assert_eq!(pending, vec![(vec![2], 0)]);
+        assert!(!table.contains(&id(1)));
+    }
+}
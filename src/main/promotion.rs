@@ -0,0 +1,340 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Bookkeeping of `Service::promote_to_node` on a connection, on both ends of it.
+
+use common::{Core, State};
+use mio::{Poll, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two promotions sent on a connection, and between two checks of the
+/// promotions received on one. Requests coming sooner are ignored.
+#[cfg(not(test))]
+pub const MIN_PROMOTION_INTERVAL_MS: u64 = 30_000;
+#[cfg(test)]
+pub const MIN_PROMOTION_INTERVAL_MS: u64 = 300;
+
+/// Token of the `ReachabilityChecks` state, which the connections checking listeners share.
+pub const REACHABILITY_CHECKS_TOKEN: Token = Token(11);
+
+/// Maximum number of listeners dialled at once to check promotions and contact info updates, over
+/// all connections.
+pub const MAX_REACHABILITY_CHECKS: usize = 16;
+
+/// Our promotion to the peer. The same listeners are only announced again after the peer failed
+/// to reach them, so asking for a promotion twice does no harm.
+#[derive(Default)]
+pub struct Promotion {
+    announced: Option<Vec<SocketAddr>>,
+    last_sent: Option<Instant>,
+}
+
+impl Promotion {
+    /// Whether to announce `listeners` now, taking note of them if so.
+    pub fn should_send(&mut self, listeners: &[SocketAddr], now: Instant) -> bool {
+        if self
+            .announced
+            .as_ref()
+            .map_or(false, |announced| announced[..] == listeners[..])
+        {
+            return false;
+        }
+        if self.last_sent.map_or(false, |sent_at| {
+            now - sent_at < Duration::from_millis(MIN_PROMOTION_INTERVAL_MS)
+        }) {
+            return false;
+        }
+        self.announced = Some(listeners.to_vec());
+        self.last_sent = Some(now);
+        true
+    }
+
+    /// The peer couldn't reach any of the listeners we announced.
+    pub fn refused(&mut self) {
+        self.announced = None;
+    }
+
+    /// Whether we asked the peer to promote us, and haven't been refused since.
+    pub fn is_announced(&self) -> bool {
+        self.announced.is_some()
+    }
+}
+
+/// The reachability checks of the listeners the peer announced in its promotion, one per
+/// listener. The first to succeed promotes the peer.
+#[derive(Default)]
+pub struct PromotionCheck {
+    children: HashSet<Token>,
+    listeners: Vec<SocketAddr>,
+    last_started: Option<Instant>,
+}
+
+impl PromotionCheck {
+    /// Whether a new check can start: none is running and the last one started long enough ago.
+    pub fn may_start(&self, now: Instant) -> bool {
+        self.children.is_empty() && self.last_started.map_or(true, |started| {
+            now - started >= Duration::from_millis(MIN_PROMOTION_INTERVAL_MS)
+        })
+    }
+
+    pub fn started(&mut self, children: HashSet<Token>, listeners: Vec<SocketAddr>, now: Instant) {
+        self.children = children;
+        self.listeners = listeners;
+        self.last_started = Some(now);
+    }
+
+    /// Takes note that the given check has finished. Returns false if it isn't one of ours.
+    pub fn finished(&mut self, child: Token) -> bool {
+        self.children.remove(&child)
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.children.is_empty()
+    }
+
+    /// Time since the check started.
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        self.last_started.map_or(Duration::from_secs(0), |started| now - started)
+    }
+
+    /// Ends the check, returning the checks still running and the listeners announced.
+    pub fn take(&mut self) -> (Vec<Token>, Vec<SocketAddr>) {
+        (
+            self.children.drain().collect(),
+            mem::replace(&mut self.listeners, Vec::new()),
+        )
+    }
+}
+
+/// The reachability checks of the listeners the peer advertised in its contact info updates. What
+/// the peer advertises is only cached once it could be reached, so that it can't have us cache
+/// somebody else's address.
+#[derive(Default)]
+pub struct ListenerChecks {
+    children: HashMap<Token, SocketAddr>,
+    /// The addresses of the peer which we may have cached: the one we reached it at and the
+    /// listeners checked since.
+    cached: Vec<SocketAddr>,
+    /// Contact info updates which are told of once the checks are over.
+    updates: usize,
+}
+
+impl ListenerChecks {
+    /// Takes note of a contact info update, and of the address the peer was reached at.
+    pub fn updated(&mut self, peer_addr: Option<SocketAddr>) {
+        self.updates += 1;
+        if self.cached.is_empty() {
+            self.cached.extend(peer_addr);
+        }
+    }
+
+    pub fn started(&mut self, child: Token, listener: SocketAddr) {
+        let _ = self.children.insert(child, listener);
+    }
+
+    /// Takes note that the given check has finished. Returns false if it isn't one of ours.
+    pub fn finished(&mut self, child: Token) -> bool {
+        self.children.remove(&child).is_some()
+    }
+
+    /// Takes note that `listener` could be reached, and returns the addresses of the peer which
+    /// may be cached and those to cache instead: the ones still `advertised` and `listener`.
+    pub fn reached(
+        &mut self,
+        listener: SocketAddr,
+        advertised: &[SocketAddr],
+    ) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+        let stale = self.cached.clone();
+        self.cached.retain(|addr| advertised.contains(addr));
+        if !self.cached.contains(&listener) {
+            self.cached.push(listener);
+        }
+        (stale, self.cached.clone())
+    }
+
+    /// The number of updates to tell of now: none while checks are running.
+    pub fn take_updates(&mut self) -> usize {
+        if self.children.is_empty() {
+            mem::replace(&mut self.updates, 0)
+        } else {
+            0
+        }
+    }
+
+    /// Ends the checks, returning those still running.
+    pub fn take(&mut self) -> Vec<Token> {
+        self.children.drain().map(|(child, _)| child).collect()
+    }
+}
+
+/// The listeners being dialled to check promotions and contact info updates. However many peers
+/// announce a listener it is
+/// dialled by one check at a time, and no more than `MAX_REACHABILITY_CHECKS` are dialled at once.
+#[derive(Clone, Default)]
+pub struct ReachabilityChecks {
+    dialling: Rc<RefCell<HashSet<SocketAddr>>>,
+}
+
+impl ReachabilityChecks {
+    /// Those of the event loop, set up the first time they are asked for.
+    pub fn of(core: &mut Core) -> Self {
+        if let Some(state) = core.get_state(REACHABILITY_CHECKS_TOKEN) {
+            let mut state = state.borrow_mut();
+            if let Some(checks) = state.as_any().downcast_mut::<ReachabilityChecks>() {
+                return checks.clone();
+            }
+        }
+        let checks = ReachabilityChecks::default();
+        let state = Rc::new(RefCell::new(checks.clone()));
+        let _ = core.insert_state(REACHABILITY_CHECKS_TOKEN, state);
+        checks
+    }
+
+    /// Takes note that `addr` is dialled until the returned `Dialling` is dropped. `None` if it is
+    /// dialled already, or as many listeners as allowed are.
+    pub fn reserve(&self, addr: SocketAddr) -> Option<Dialling> {
+        let mut dialling = self.dialling.borrow_mut();
+        if dialling.len() >= MAX_REACHABILITY_CHECKS || !dialling.insert(addr) {
+            return None;
+        }
+        Some(Dialling {
+            dialling: self.dialling.clone(),
+            addr,
+        })
+    }
+}
+
+impl State for ReachabilityChecks {
+    fn name(&self) -> &'static str {
+        "ReachabilityChecks"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(REACHABILITY_CHECKS_TOKEN);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// A listener being dialled, see `ReachabilityChecks::reserve`.
+pub struct Dialling {
+    dialling: Rc<RefCell<HashSet<SocketAddr>>>,
+    addr: SocketAddr,
+}
+
+impl Drop for Dialling {
+    fn drop(&mut self) {
+        let _ = self.dialling.borrow_mut().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn promotions_are_idempotent_and_rate_limited() {
+        let start = Instant::now();
+        let later = start + Duration::from_millis(MIN_PROMOTION_INTERVAL_MS);
+        let listeners0 = vec![unwrap!("192.168.0.1:5483".parse())];
+        let listeners1 = vec![unwrap!("192.168.0.1:5484".parse())];
+        let mut promotion = Promotion::default();
+
+        assert!(promotion.should_send(&listeners0, start));
+        assert!(!promotion.should_send(&listeners0, start));
+        assert!(!promotion.should_send(&listeners1, start));
+        // The same listeners aren't announced again, however long we wait...
+        assert!(!promotion.should_send(&listeners0, later));
+        // ...unless the peer refused them.
+        promotion.refused();
+        assert!(promotion.should_send(&listeners0, later));
+        let even_later = later + Duration::from_millis(MIN_PROMOTION_INTERVAL_MS);
+        assert!(promotion.should_send(&listeners1, even_later));
+    }
+
+    #[test]
+    fn checks_are_rate_limited() {
+        let start = Instant::now();
+        let later = start + Duration::from_millis(MIN_PROMOTION_INTERVAL_MS);
+        let listeners = vec![unwrap!("192.168.0.1:5483".parse())];
+        let mut check = PromotionCheck::default();
+        assert!(check.may_start(start));
+
+        let children = vec![Token(1), Token(2)].into_iter().collect();
+        check.started(children, listeners.clone(), start);
+        assert!(!check.may_start(later));
+        assert!(!check.finished(Token(3)));
+        assert!(check.finished(Token(1)));
+        assert!(check.is_running());
+        assert_eq!(check.take(), (vec![Token(2)], listeners));
+        assert!(!check.is_running());
+
+        assert!(!check.may_start(start));
+        assert!(check.may_start(later));
+    }
+
+    #[test]
+    fn listeners_are_dialled_once_at_a_time_and_in_bounded_numbers() {
+        let checks = ReachabilityChecks::default();
+        let addr = |port| SocketAddr::from(([192, 168, 0, 1], port));
+
+        let first = unwrap!(checks.reserve(addr(5483)));
+        assert!(checks.reserve(addr(5483)).is_none());
+        drop(first);
+        let first = unwrap!(checks.reserve(addr(5483)));
+
+        let others: Vec<_> = (1..MAX_REACHABILITY_CHECKS as u16)
+            .map(|port| unwrap!(checks.reserve(addr(port))))
+            .collect();
+        assert!(checks.reserve(addr(5484)).is_none());
+        drop(first);
+        assert!(checks.reserve(addr(5484)).is_some());
+        drop(others);
+    }
+
+    #[test]
+    fn only_listeners_reached_replace_the_cached_ones() {
+        let addr = |port| SocketAddr::from(([192, 168, 0, 1], port));
+        let mut checks = ListenerChecks::default();
+
+        // The peer advertises two listeners, one of which can't be reached.
+        checks.updated(Some(addr(5483)));
+        checks.started(Token(1), addr(5484));
+        checks.started(Token(2), addr(5485));
+        assert_eq!(checks.take_updates(), 0);
+        assert!(checks.finished(Token(2)));
+        assert!(!checks.finished(Token(2)));
+        assert_eq!(checks.take_updates(), 0);
+        assert!(checks.finished(Token(1)));
+        let advertised = vec![addr(5484), addr(5485)];
+        assert_eq!(
+            checks.reached(addr(5484), &advertised),
+            (vec![addr(5483)], vec![addr(5484)])
+        );
+        assert_eq!(checks.take_updates(), 1);
+        assert_eq!(checks.take_updates(), 0);
+
+        // The address the peer was reached at first is only cached until a listener is reached.
+        checks.updated(Some(addr(5483)));
+        let advertised = vec![addr(5484), addr(5486)];
+        assert_eq!(
+            checks.reached(addr(5486), &advertised),
+            (vec![addr(5484)], vec![addr(5484), addr(5486)])
+        );
+        assert_eq!(checks.take_updates(), 1);
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Core, CoreTimer, NetworkId, State, Uid};
+use main::{
+    now_secs, CandidateAddr, Connect, ConnectionMap, ConnectionSettings, CrustConfig, Event,
+    EventSink, PrivConnectionInfo, PubConnectionInfo, ReconnectPolicy, Redial,
+};
+use mio::{Poll, Token};
+use rand::{self, Rng};
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Token of the `Reconnects` state, which the connections hand the nodes they lost to.
+pub const RECONNECTS_TOKEN: Token = Token(10);
+
+/// Nodes whose connection was lost to the network, dialled again at the address they were last
+/// reached at until an attempt succeeds or `ReconnectPolicy::max_attempts` failed, see
+/// `Config::reconnect`. Each peer gets a timer of its own, whose id is kept with it.
+pub struct Reconnects<UID: Uid> {
+    token: Token,
+    policy: ReconnectPolicy,
+    peers: HashMap<UID, Lost>,
+    next_timer_id: u64,
+    our_id: UID,
+    our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    config: CrustConfig,
+    cm: ConnectionMap<UID>,
+    network: NetworkId,
+    event_tx: EventSink<UID>,
+}
+
+/// A lost peer, waiting for its next attempt or being dialled.
+struct Lost {
+    addr: SocketAddr,
+    failures: u32,
+    timer_id: u64,
+}
+
+impl<UID: Uid> Reconnects<UID> {
+    pub fn start(
+        core: &mut Core,
+        token: Token,
+        policy: ReconnectPolicy,
+        our_id: UID,
+        our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+        config: CrustConfig,
+        cm: ConnectionMap<UID>,
+        network: NetworkId,
+        event_tx: EventSink<UID>,
+    ) {
+        let state = Rc::new(RefCell::new(Reconnects {
+            token,
+            policy,
+            peers: HashMap::new(),
+            next_timer_id: 0,
+            our_id,
+            our_listeners,
+            config,
+            cm,
+            network,
+            event_tx,
+        }));
+        let _ = core.insert_state(token, state);
+    }
+
+    /// Schedules the first attempt at reconnecting to a node just lost, at `addr`. A peer lost
+    /// again while being reconnected to starts over.
+    pub fn lost(&mut self, core: &mut Core, peer: UID, addr: SocketAddr) {
+        if core.is_draining() {
+            return;
+        }
+        let timer_id = self.new_timer_id();
+        let _ = self.peers.insert(
+            peer,
+            Lost {
+                addr,
+                failures: 0,
+                timer_id,
+            },
+        );
+        let delay = backoff(&self.policy, 0);
+        self.schedule(core, peer, timer_id, delay);
+    }
+
+    /// Called as the `Connect` started for `peer` is over. Unless it `reconnected` or the peer
+    /// connected otherwise meanwhile, the next attempt is scheduled, or the peer given up on.
+    pub fn dial_over(&mut self, core: &mut Core, peer: &UID, reconnected: bool) {
+        if reconnected || unwrap!(self.cm.lock()).contains_key(peer) {
+            let _ = self.peers.remove(peer);
+            return;
+        }
+        let timer_id = self.new_timer_id();
+        let delay = match self.peers.get_mut(peer) {
+            Some(lost) => {
+                lost.timer_id = timer_id;
+                lost.failed(&self.policy)
+            }
+            None => return,
+        };
+        match delay {
+            Some(delay) => self.schedule(core, *peer, timer_id, delay),
+            None => {
+                debug!("Giving up on reconnecting to {:?}", peer);
+                let _ = self.peers.remove(peer);
+                self.event_tx.send(Event::ReconnectFailed(*peer));
+            }
+        }
+    }
+
+    fn dial(&mut self, core: &mut Core, poll: &Poll, peer: UID, addr: SocketAddr) {
+        // Nothing new is dialled once the service is shutting down.
+        if core.is_draining() {
+            let _ = self.peers.remove(&peer);
+            return;
+        }
+        // The peer may have connected to us, or the application to it, in the meantime.
+        if unwrap!(self.cm.lock()).contains_key(&peer) {
+            let _ = self.peers.remove(&peer);
+            return;
+        }
+
+        let (settings, outbound_bind_addr) = {
+            let config = unwrap!(self.config.lock());
+            (
+                ConnectionSettings::from_config(&config.cfg),
+                config.cfg.outbound_bind_addr,
+            )
+        };
+        let our_ci = PrivConnectionInfo {
+            id: self.our_id,
+            for_asserted: Vec::new(),
+            for_direct: unwrap!(self.our_listeners.lock()).clone(),
+            for_hole_punch: Vec::new(),
+            for_utp: Vec::new(),
+            for_relay: Vec::new(),
+            hole_punch_socket: None,
+            issued_at: now_secs(),
+            ttl_secs: 0,
+            outbound_bind_addr,
+        };
+        let their_ci = PubConnectionInfo {
+            id: peer,
+            candidates: vec![CandidateAddr::TcpDirect(addr)],
+            issued_at: None,
+            ttl_secs: None,
+        };
+
+        trace!("Reconnecting to {:?} at {}", peer, addr);
+        if let Err(e) = Connect::start(
+            core,
+            poll,
+            our_ci,
+            their_ci,
+            self.cm.clone(),
+            self.network,
+            self.event_tx.clone(),
+            Some(Redial::Reconnect),
+            settings,
+        ) {
+            debug!("Failed to reconnect to {:?}: {:?}", peer, e);
+            self.dial_over(core, &peer, false);
+        }
+    }
+
+    fn new_timer_id(&mut self) -> u64 {
+        let timer_id = self.next_timer_id;
+        self.next_timer_id = self.next_timer_id.wrapping_add(1);
+        timer_id
+    }
+
+    fn schedule(&mut self, core: &mut Core, peer: UID, timer_id: u64, delay: Duration) {
+        if let Err(e) = core.set_timeout(delay, CoreTimer::new(self.token, timer_id)) {
+            debug!("Failed to schedule reconnecting to {:?}: {:?}", peer, e);
+            let _ = self.peers.remove(&peer);
+            self.event_tx.send(Event::ReconnectFailed(peer));
+        }
+    }
+}
+
+impl Lost {
+    /// Counts a failed attempt and returns how long to wait before the next one, or `None` if
+    /// none is left.
+    fn failed(&mut self, policy: &ReconnectPolicy) -> Option<Duration> {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= policy.max_attempts {
+            return None;
+        }
+        Some(backoff(policy, self.failures))
+    }
+}
+
+/// Time to wait before the attempt following `failures` failed ones: the initial delay doubled
+/// per failure, up to the ceiling, less a random share of up to half of it, so that peers which
+/// lost each other at once don't dial in lockstep.
+fn backoff(policy: &ReconnectPolicy, failures: u32) -> Duration {
+    let ceiling = cmp::max(policy.max_delay_ms, policy.initial_delay_ms);
+    let delay = cmp::min(
+        policy
+            .initial_delay_ms
+            .saturating_mul(1 << cmp::min(failures, 16)),
+        ceiling,
+    );
+    let jitter = rand::thread_rng().gen_range(0, delay / 2 + 1);
+    Duration::from_millis(delay - jitter)
+}
+
+impl<UID: Uid> State for Reconnects<UID> {
+    fn name(&self) -> &'static str {
+        "Reconnects"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u64) {
+        let due = self
+            .peers
+            .iter()
+            .find(|&(_, lost)| lost.timer_id == timer_id)
+            .map(|(peer, lost)| (*peer, lost.addr));
+        if let Some((peer, addr)) = due {
+            self.dial(core, poll, peer, addr);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attempts_back_off_with_jitter_until_given_up() {
+        let policy = ReconnectPolicy {
+            initial_delay_ms: 1000,
+            max_delay_ms: 5000,
+            max_attempts: 5,
+        };
+        let ms = Duration::from_millis;
+        for _ in 0..100 {
+            let delay = backoff(&policy, 0);
+            assert!(delay >= ms(500) && delay <= ms(1000));
+        }
+
+        let mut lost = Lost {
+            addr: unwrap!("127.0.0.1:5483".parse()),
+            failures: 0,
+            timer_id: 0,
+        };
+        let ceilings = [2000, 4000, 5000, 5000];
+        for ceiling in &ceilings {
+            let delay = unwrap!(lost.failed(&policy));
+            assert!(delay >= ms(ceiling / 2) && delay <= ms(*ceiling));
+        }
+        assert_eq!(lost.failed(&policy), None);
+
+        // Past the point where doubling would overflow.
+        let delay = backoff(&policy, u32::max_value());
+        assert!(delay >= ms(2500) && delay <= ms(5000));
+    }
+}
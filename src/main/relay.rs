@@ -0,0 +1,734 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Relaying connections between peers connected to us which can't reach each other any other way,
+//! see `Config::relay`. Each of the two peers dials one of our listeners and asks, with a
+//! `Message::RelayRequest`, for a pipe to the other. We challenge it to prove, with a
+//! `Message::RelayProof`, that it holds the key of the peer it claims to be, as authenticated by
+//! its connection to us, so we only relay for peers which have keys. Once both have asked, both
+//! are answered with a `Message::RelayReady`, after which the frames of either are forwarded to
+//! the other as they are. The peers then handshake over the pipe as over a punched hole, and
+//! encrypt their frames end to end, so we see no more of them than their length.
+//!
+//! What we take on for others is bounded. No more than `RelayConfig::max_sessions` pairs are
+//! relayed at once, `MAX_PER_PEER` of them for any one peer and `MAX_PER_IP` for any one IP, and
+//! we stop relaying once retiring. Each side is granted a window, `RelayConfig::window_bytes`, of
+//! what it may have sent us that the other hasn't read yet, and more of it as the other reads,
+//! with a `RelayControl::Credit`, so a sender faster than its peer receives stops rather than
+//! being buffered by us. Frames beyond the window are dropped, and a side is read from only while
+//! less than the window waits to be written to the other, so what we hold is bounded whatever the
+//! peers do. What is queued is charged to the memory budget. The bytes forwarded each second are
+//! held to `RelayConfig::max_pair_bytes_per_sec` for each pair and `RelayConfig::max_bytes_per_sec`
+//! for all of them, and frames beyond are dropped too, which both sides are told of with a
+//! `RelayControl::QuotaExceeded`. Relayed frames count in neither peer's `PeerStats`, as the pipes
+//! aren't connections of ours, but in `CoreStats`.
+
+use common::{
+    fits_relay_window, Core, CoreTimer, Message, NameHash, Priority, RelayControl, Socket, State,
+    Uid, WireFormat, CONTROL_PRIORITY, FRAME_HEADER_SIZE,
+};
+use main::{ActiveConnection, ConnectionMap, RelayConfig, INACTIVITY_TIMEOUT_MS};
+use mio::{Poll, PollOpt, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Token of the `RelayState` state.
+pub const RELAY_TOKEN: Token = Token(9);
+
+/// Time a request for a pipe waits for the peer's before it is dropped.
+const PAIRING_TIMEOUT_SECS: u64 = 20;
+/// Window of each side of a session if `RelayConfig::window_bytes` doesn't set one.
+const DEFAULT_WINDOW_BYTES: u64 = 256 * 1024;
+/// Priority the frames are forwarded at, behind nothing of ours but never dropped.
+const RELAY_PRIORITY: Priority = CONTROL_PRIORITY + 1;
+/// Most requests waiting and sessions open at a time for any one peer.
+const MAX_PER_PEER: usize = 4;
+/// Most requests waiting and sessions open at a time for the peers at any one IP.
+const MAX_PER_IP: usize = 8;
+
+/// Pairs the requests for pipes between two peers, see the module docs, and keeps count of the
+/// sessions relayed.
+pub struct RelayState<UID: Uid> {
+    token: Token,
+    config: RelayConfig,
+    /// Requests waiting for the peer's, by the id of the peer which made them and of the peer it
+    /// asks for.
+    waiting: HashMap<(UID, UID), Waiting>,
+    /// The sessions open, counted down by each as it closes.
+    sessions: Rc<RefCell<Sessions<UID>>>,
+    /// The bytes forwarded by all the sessions, see `RelayConfig::max_bytes_per_sec`.
+    traffic: Rc<RefCell<Quota>>,
+}
+
+struct Waiting {
+    socket: Socket,
+    /// What the request is answered with once paired.
+    name_hash: NameHash,
+    /// The IP the request came from.
+    source: IpAddr,
+    since: Instant,
+}
+
+/// Count of the sessions open, in all and per peer and IP on either side.
+struct Sessions<UID: Uid> {
+    open: usize,
+    per_peer: HashMap<UID, usize>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl<UID: Uid> Sessions<UID> {
+    fn new() -> Self {
+        Sessions {
+            open: 0,
+            per_peer: HashMap::new(),
+            per_ip: HashMap::new(),
+        }
+    }
+
+    fn opened(&mut self, ids: &[UID; 2], ips: &[IpAddr; 2]) {
+        self.open += 1;
+        for (id, ip) in ids.iter().zip(ips.iter()) {
+            *self.per_peer.entry(*id).or_insert(0) += 1;
+            *self.per_ip.entry(*ip).or_insert(0) += 1;
+        }
+    }
+
+    fn closed(&mut self, ids: &[UID; 2], ips: &[IpAddr; 2]) {
+        self.open -= 1;
+        for (id, ip) in ids.iter().zip(ips.iter()) {
+            count_down(&mut self.per_peer, *id);
+            count_down(&mut self.per_ip, *ip);
+        }
+    }
+}
+
+/// Bytes forwarded over the current second, held to a quota.
+struct Quota {
+    bytes_per_sec: Option<u64>,
+    second: Instant,
+    spent: u64,
+}
+
+impl Quota {
+    fn new(bytes_per_sec: Option<u64>, now: Instant) -> Self {
+        Quota {
+            bytes_per_sec,
+            second: now,
+            spent: 0,
+        }
+    }
+
+    /// Whether `len` more bytes fit in the quota of the second `now` falls in. The first frame of
+    /// a second always does, however large, so that no frame is kept from ever going through.
+    fn admits(&mut self, len: u64, now: Instant) -> bool {
+        let bytes_per_sec = match self.bytes_per_sec {
+            Some(bytes_per_sec) => bytes_per_sec,
+            None => return true,
+        };
+        if now.duration_since(self.second) >= Duration::from_secs(1) {
+            self.second = now;
+            self.spent = 0;
+        }
+        self.spent == 0 || self.spent + len <= bytes_per_sec
+    }
+
+    fn spend(&mut self, len: u64) {
+        self.spent += len;
+    }
+}
+
+fn count_down<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Entry::Occupied(mut entry) = counts.entry(key) {
+        *entry.get_mut() -= 1;
+        if *entry.get() == 0 {
+            let _ = entry.remove();
+        }
+    }
+}
+
+impl<UID: Uid> RelayState<UID> {
+    pub fn start(core: &mut Core, token: Token, config: RelayConfig) -> ::Res<()> {
+        trace!("Entered state RelayState");
+
+        let timer = CoreTimer::new(token, 0);
+        core.set_timeout(Duration::from_secs(PAIRING_TIMEOUT_SECS), timer)?;
+
+        let traffic = Quota::new(config.max_bytes_per_sec, core.now());
+        let state = RelayState {
+            token,
+            config,
+            waiting: HashMap::new(),
+            sessions: Rc::new(RefCell::new(Sessions::new())),
+            traffic: Rc::new(RefCell::new(traffic)),
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    /// Why a request of `from`, made from `source`, for a pipe to `to` can't be taken, if it
+    /// can't. It can if it answers one waiting already or replaces an earlier one of its own,
+    /// or else if there is room for another session, as long as neither the peer nor its IP has
+    /// its share of requests and sessions already. A request waiting is only replaced by one
+    /// from the same IP.
+    pub fn refusal(&self, from: UID, to: UID, source: IpAddr) -> Option<&'static str> {
+        match self.waiting.get(&(from, to)) {
+            Some(waiting) if waiting.source != source => {
+                return Some("A request of the peer from another address waits already")
+            }
+            Some(_) => return None,
+            None => (),
+        }
+
+        let sessions = self.sessions.borrow();
+        let pairs = self.waiting.contains_key(&(to, from));
+        if !pairs && self.waiting.len() + sessions.open >= self.config.max_sessions {
+            return Some("Too many connections relayed");
+        }
+        let of_peer = self.waiting.keys().filter(|&&(id, _)| id == from).count()
+            + sessions.per_peer.get(&from).cloned().unwrap_or(0);
+        if of_peer >= MAX_PER_PEER {
+            return Some("Too many connections relayed for the peer");
+        }
+        let of_ip = self
+            .waiting
+            .values()
+            .filter(|waiting| waiting.source == source)
+            .count()
+            + sessions.per_ip.get(&source).cloned().unwrap_or(0);
+        if of_ip >= MAX_PER_IP {
+            return Some("Too many connections relayed for the address");
+        }
+        None
+    }
+
+    /// Takes the connection over which `from` asked, from `source`, for a pipe to `to`,
+    /// deregistered from the poll, and opens the session if `to` asked for one already.
+    /// Otherwise it waits for `to` to. Only to be called for requests which `refusal` takes.
+    pub fn pair(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        socket: Socket,
+        (from, to): (UID, UID),
+        name_hash: NameHash,
+        source: IpAddr,
+    ) {
+        let peer = match self.waiting.remove(&(to, from)) {
+            Some(peer) => peer,
+            None => {
+                trace!("Relay request from {:?} to {:?} waits for its peer", from, to);
+                // An earlier request of the same peer is dropped, as the peer gave up on it.
+                let _ = self.waiting.insert(
+                    (from, to),
+                    Waiting {
+                        socket,
+                        name_hash,
+                        source,
+                        since: core.now(),
+                    },
+                );
+                return;
+            }
+        };
+        let ours = (socket, from, name_hash, source);
+        let theirs = (peer.socket, to, peer.name_hash, peer.source);
+        let quota = Quota::new(self.config.max_pair_bytes_per_sec, core.now());
+        let window = self.config.window_bytes.unwrap_or(DEFAULT_WINDOW_BYTES);
+        let shared = (self.sessions.clone(), self.traffic.clone());
+        match RelaySession::start(core, poll, ours, theirs, (quota, window), shared) {
+            Ok(()) => debug!("Relaying between {:?} and {:?}", from, to),
+            Err(e) => debug!("Failed to relay between {:?} and {:?}: {:?}", from, to, e),
+        }
+    }
+}
+
+impl<UID: Uid> State for RelayState<UID> {
+    fn name(&self) -> &'static str {
+        "RelayState"
+    }
+
+    fn timeout(&mut self, core: &mut Core, _poll: &Poll, _timer_id: u64) {
+        let now = core.now();
+        let timeout = Duration::from_secs(PAIRING_TIMEOUT_SECS);
+        self.waiting.retain(|&(from, to), waiting| {
+            let keep = now - waiting.since < timeout;
+            if !keep {
+                debug!("Relay request from {:?} to {:?} timed out", from, to);
+            }
+            keep
+        });
+        if let Err(e) = core.set_timeout(timeout, CoreTimer::new(self.token, 0)) {
+            debug!("Failed to time the relay requests out: {:?}", e);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        self.waiting.clear();
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// A pipe between two peers, registered under a token per side.
+struct RelaySession<UID: Uid> {
+    tokens: [Token; 2],
+    sockets: [Socket; 2],
+    their_ids: [UID; 2],
+    their_ips: [IpAddr; 2],
+    sessions: Rc<RefCell<Sessions<UID>>>,
+    /// The bytes forwarded for this pair, see `RelayConfig::max_pair_bytes_per_sec`.
+    quota: Quota,
+    traffic: Rc<RefCell<Quota>>,
+    /// See `RelayConfig::window_bytes`.
+    window: u64,
+    /// Bytes, on the wire, each side sent us, and granted it to in all, by side.
+    received: [u64; 2],
+    granted: [u64; 2],
+    /// When the last frame was forwarded either way. Peers heartbeat their connections, so a
+    /// session silent for longer than they tolerate is dead.
+    last_forwarded: Instant,
+    closed: bool,
+}
+
+impl<UID: Uid> RelaySession<UID> {
+    fn start(
+        core: &mut Core,
+        poll: &Poll,
+        (mut socket0, id0, name_hash0, ip0): (Socket, UID, NameHash, IpAddr),
+        (mut socket1, id1, name_hash1, ip1): (Socket, UID, NameHash, IpAddr),
+        (quota, window): (Quota, u64),
+        (sessions, traffic): (Rc<RefCell<Sessions<UID>>>, Rc<RefCell<Quota>>),
+    ) -> ::Res<()> {
+        // The frames forwarded are charged to the memory budget as any other, and the queues
+        // timed by the event loop's clock.
+        for socket in &mut [&mut socket0, &mut socket1] {
+            socket.set_memory_budget(core.memory_budget().clone());
+            socket.set_clock(core.wall_clock());
+        }
+        let tokens = [core.get_new_token(), core.get_new_token()];
+        let kind = Ready::error() | Ready::hup() | Ready::readable() | Ready::writable();
+        poll.register(&socket0, tokens[0], kind, PollOpt::edge())?;
+        if let Err(e) = poll.register(&socket1, tokens[1], kind, PollOpt::edge()) {
+            let _ = poll.deregister(&socket0);
+            return Err(From::from(e));
+        }
+        let timer = CoreTimer::new(tokens[0], 0);
+        core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS), timer)?;
+
+        sessions.borrow_mut().opened(&[id0, id1], &[ip0, ip1]);
+        core.stats_mut().relayed_pairs += 1;
+        let mut session = RelaySession {
+            tokens,
+            sockets: [socket0, socket1],
+            their_ids: [id0, id1],
+            their_ips: [ip0, ip1],
+            sessions,
+            quota,
+            traffic,
+            window,
+            received: [0, 0],
+            granted: [window, window],
+            last_forwarded: core.now(),
+            closed: false,
+        };
+        for &(side, name_hash) in &[(0, name_hash0), (1, name_hash1)] {
+            let msg = Some((Message::RelayReady::<UID>(name_hash), CONTROL_PRIORITY));
+            let res = session.sockets[side]
+                .write(poll, tokens[side], msg)
+                .and_then(|_| session.write_control(poll, side, RelayControl::Window(window)));
+            if let Err(e) = res {
+                session.close(core, poll);
+                return Err(e);
+            }
+        }
+
+        let state = Rc::new(RefCell::new(session));
+        let _ = core.insert_state(tokens[0], state.clone());
+        let _ = core.insert_state(tokens[1], state);
+
+        Ok(())
+    }
+
+    /// Writes out what is queued for either side, and forwards to it what the other side sent for
+    /// as long as its queue has room. Frames over the window or the quotas are dropped instead,
+    /// and both sides told how many once the other side has nothing more to read. The other side
+    /// is then granted what its queue has room for, see `RelayControl::Credit`.
+    fn pump(&mut self, core: &mut Core, poll: &Poll) {
+        for &(from, to) in &[(0, 1), (1, 0)] {
+            if let Err(e) = self.sockets[to].write::<Message<UID>>(poll, self.tokens[to], None) {
+                debug!("Failed to relay to {:?}: {:?}", self.their_ids[to], e);
+                return self.terminate(core, poll);
+            }
+            let mut dropped = 0;
+            while self.sockets[to].queued_ahead_of(RELAY_PRIORITY) < self.window {
+                let body = match self.sockets[from].read_frame() {
+                    Ok(Some((ref body, _))) if body.is_empty() => {
+                        debug!("{:?} sent a frame only relays send", self.their_ids[from]);
+                        return self.terminate(core, poll);
+                    }
+                    Ok(Some((body, _))) => body,
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("Failed to relay from {:?}: {:?}", self.their_ids[from], e);
+                        return self.terminate(core, poll);
+                    }
+                };
+                let now = core.now();
+                self.last_forwarded = now;
+                let len = body.len() as u64;
+                let wire_len = len + FRAME_HEADER_SIZE as u64;
+                let left = self.granted[from].saturating_sub(self.received[from]);
+                let in_window = fits_relay_window(self.window, left, wire_len);
+                self.received[from] += wire_len;
+                let admitted = in_window && {
+                    let mut traffic = self.traffic.borrow_mut();
+                    let admitted = self.quota.admits(len, now) && traffic.admits(len, now);
+                    if admitted {
+                        self.quota.spend(len);
+                        traffic.spend(len);
+                    }
+                    admitted
+                };
+                if !admitted {
+                    dropped += 1;
+                    core.stats_mut().relayed_frames_dropped += 1;
+                    continue;
+                }
+                core.stats_mut().relayed_bytes += len;
+                let res = self.sockets[to].write_raw(poll, self.tokens[to], body, RELAY_PRIORITY);
+                if let Err(e) = res {
+                    debug!("Failed to relay to {:?}: {:?}", self.their_ids[to], e);
+                    return self.terminate(core, poll);
+                }
+            }
+            let queued = self.sockets[to].queued_ahead_of(RELAY_PRIORITY);
+            {
+                let stats = core.stats_mut();
+                stats.relayed_max_queued_bytes = cmp::max(stats.relayed_max_queued_bytes, queued);
+            }
+            if dropped > 0 {
+                debug!(
+                    "Dropped {} frames from {:?} to {:?} over the relay window or quota",
+                    dropped, self.their_ids[from], self.their_ids[to]
+                );
+                let told = self
+                    .write_control(poll, to, RelayControl::QuotaExceeded(dropped, 0))
+                    .and_then(|()| {
+                        self.write_control(poll, from, RelayControl::QuotaExceeded(0, dropped))
+                    });
+                if let Err(e) = told {
+                    debug!("Failed to report relay drops: {:?}", e);
+                    return self.terminate(core, poll);
+                }
+            }
+            // `from` is granted what the queue of `to` has room for, a good part of the window at a
+            // time. A sender waits for no more than half the window to be left, see
+            // `fits_relay_window`, which the grant always makes up for once the queue is empty.
+            let queued = self.sockets[to].queued_ahead_of(RELAY_PRIORITY);
+            let room = self.window.saturating_sub(queued);
+            let more = (self.received[from] + room).saturating_sub(self.granted[from]);
+            if more > 0 && more >= self.window / 4 {
+                self.granted[from] += more;
+                if let Err(e) = self.write_control(poll, from, RelayControl::Credit(more)) {
+                    debug!("Failed to grant {:?} more: {:?}", self.their_ids[from], e);
+                    return self.terminate(core, poll);
+                }
+            }
+        }
+    }
+
+    /// Queues `control` for `side`, after the frames forwarded to it so far, see `RelayControl`.
+    fn write_control(&mut self, poll: &Poll, side: usize, control: RelayControl) -> ::Res<()> {
+        let mut body = Vec::new();
+        control.encode_to(&mut body)?;
+        let token = self.tokens[side];
+        let _ = self.sockets[side].write_raw(poll, token, Vec::new(), RELAY_PRIORITY)?;
+        let _ = self.sockets[side].write_raw(poll, token, body, RELAY_PRIORITY)?;
+        Ok(())
+    }
+
+    fn close(&mut self, core: &mut Core, poll: &Poll) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        for (token, socket) in self.tokens.iter().zip(self.sockets.iter()) {
+            let _ = poll.deregister(socket);
+            let _ = core.remove_state(*token);
+        }
+        core.stats_mut().relayed_pairs -= 1;
+        self.sessions
+            .borrow_mut()
+            .closed(&self.their_ids, &self.their_ips);
+    }
+}
+
+impl<UID: Uid> State for RelaySession<UID> {
+    fn name(&self) -> &'static str {
+        "RelaySession"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() {
+            return self.terminate(core, poll);
+        }
+        // What a closing side sent last is forwarded before the session is closed.
+        self.pump(core, poll);
+        if kind.is_hup() {
+            self.terminate(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        let timeout = Duration::from_millis(INACTIVITY_TIMEOUT_MS);
+        let silent_for = core.now() - self.last_forwarded;
+        if silent_for >= timeout {
+            debug!(
+                "Relay between {:?} and {:?} went silent",
+                self.their_ids[0], self.their_ids[1]
+            );
+            return self.terminate(core, poll);
+        }
+        let timer = CoreTimer::new(self.tokens[0], 0);
+        if let Err(e) = core.set_timeout(timeout - silent_for, timer) {
+            debug!("Failed to time the relay out: {:?}", e);
+            self.terminate(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if !self.closed {
+            trace!(
+                "Closing the relay between {:?} and {:?}",
+                self.their_ids[0],
+                self.their_ids[1]
+            );
+        }
+        self.close(core, poll);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// The addresses at which the peers connected to us relay for us, for our connection info, see
+/// `Config::use_relays`.
+pub fn relays_of_peers<UID: Uid>(core: &Core, cm: &ConnectionMap<UID>) -> Vec<SocketAddr> {
+    let tokens: Vec<Token> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|id| id.active_connection)
+        .collect();
+    tokens
+        .into_iter()
+        .filter_map(|token| {
+            let state = core.get_state(token)?;
+            let mut state = state.borrow_mut();
+            let active_connection = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+            active_connection.relay_addr()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoreMessage, EventLoop, ManualEventLoop, VirtualClock};
+    use rand;
+    use std::sync::mpsc;
+    use tests::UniqueId;
+
+    fn config(max_sessions: usize) -> RelayConfig {
+        RelayConfig {
+            max_sessions,
+            max_pair_bytes_per_sec: None,
+            max_bytes_per_sec: None,
+            window_bytes: None,
+        }
+    }
+
+    fn relay(max_sessions: usize) -> RelayState<UniqueId> {
+        RelayState {
+            token: RELAY_TOKEN,
+            config: config(max_sessions),
+            waiting: HashMap::new(),
+            sessions: Rc::new(RefCell::new(Sessions::new())),
+            traffic: Rc::new(RefCell::new(Quota::new(None, Instant::now()))),
+        }
+    }
+
+    fn wait(relay: &mut RelayState<UniqueId>, from: UniqueId, to: UniqueId, source: IpAddr) {
+        let waiting = Waiting {
+            socket: Socket::default(),
+            name_hash: [0; 32],
+            source,
+            since: Instant::now(),
+        };
+        let _ = relay.waiting.insert((from, to), waiting);
+    }
+
+    #[test]
+    fn requests_waiting_are_replaced_only_from_their_address() {
+        let mut relay = relay(4);
+        let (a, b): (UniqueId, UniqueId) = (rand::random(), rand::random());
+        let ip = unwrap!("1.2.3.4".parse());
+        let other_ip = unwrap!("5.6.7.8".parse());
+        wait(&mut relay, a, b, ip);
+
+        assert!(relay.refusal(a, b, ip).is_none());
+        assert!(relay.refusal(a, b, other_ip).is_some());
+        // Answering the request waiting is up to its peer, from wherever.
+        assert!(relay.refusal(b, a, other_ip).is_none());
+    }
+
+    #[test]
+    fn requests_are_capped_per_peer_and_address() {
+        let mut relay = relay(100);
+        let a: UniqueId = rand::random();
+        let ip = unwrap!("1.2.3.4".parse());
+        for _ in 0..MAX_PER_PEER {
+            assert!(relay.refusal(a, rand::random(), ip).is_none());
+            wait(&mut relay, a, rand::random(), ip);
+        }
+        assert!(relay.refusal(a, rand::random(), ip).is_some());
+
+        // Others at the same address share its quota.
+        for _ in MAX_PER_PEER..MAX_PER_IP {
+            let other = rand::random();
+            assert!(relay.refusal(other, rand::random(), ip).is_none());
+            wait(&mut relay, other, rand::random(), ip);
+        }
+        let elsewhere = unwrap!("5.6.7.8".parse());
+        assert!(relay.refusal(rand::random(), rand::random(), ip).is_some());
+        assert!(relay.refusal(rand::random(), rand::random(), elsewhere).is_none());
+
+        // Sessions count as much as requests waiting.
+        let mut relay = relay_with_session(a, ip);
+        for _ in 1..MAX_PER_PEER {
+            wait(&mut relay, a, rand::random(), ip);
+        }
+        assert!(relay.refusal(a, rand::random(), elsewhere).is_some());
+    }
+
+    #[test]
+    fn requests_beyond_capacity_are_refused_unless_they_pair() {
+        let mut relay = relay(2);
+        let (a, b): (UniqueId, UniqueId) = (rand::random(), rand::random());
+        let ip = unwrap!("1.2.3.4".parse());
+        let elsewhere = unwrap!("5.6.7.8".parse());
+        wait(&mut relay, a, b, ip);
+        assert!(relay.refusal(rand::random(), rand::random(), elsewhere).is_none());
+        wait(&mut relay, rand::random(), rand::random(), elsewhere);
+
+        assert_eq!(
+            relay.refusal(rand::random(), rand::random(), elsewhere),
+            Some("Too many connections relayed")
+        );
+        // Answering a request waiting takes no more room.
+        assert!(relay.refusal(b, a, elsewhere).is_none());
+
+        // Sessions open take room as requests waiting do.
+        let mut relay = relay_with_session(a, ip);
+        relay.config.max_sessions = 2;
+        wait(&mut relay, rand::random(), b, elsewhere);
+        assert!(relay.refusal(rand::random(), rand::random(), elsewhere).is_some());
+    }
+
+    // Fires the timers due, then runs `f` on the `RelayState` of the event loop, returning the
+    // requests waiting after.
+    fn on_relay<F>(el: &mut ManualEventLoop, handle: &EventLoop, f: F) -> Vec<(UniqueId, UniqueId)>
+    where
+        F: FnOnce(&mut RelayState<UniqueId>, &mut Core) + Send + 'static,
+    {
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(handle.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(RELAY_TOKEN));
+            let mut state = state.borrow_mut();
+            let relay = unwrap!(state.as_any().downcast_mut::<RelayState<UniqueId>>());
+            f(relay, core);
+            unwrap!(tx.send(relay.waiting.keys().cloned().collect()));
+        })));
+        assert!(unwrap!(el.run_once(Duration::from_millis(10))));
+        unwrap!(rx.try_recv())
+    }
+
+    #[test]
+    fn requests_waiting_for_their_peer_time_out() {
+        let clock = VirtualClock::new();
+        let (mut el, handle) = unwrap!(ManualEventLoop::with_virtual_clock(0, clock.clone()));
+        unwrap!(handle.send(CoreMessage::new(|core, _| {
+            unwrap!(RelayState::<UniqueId>::start(core, RELAY_TOKEN, config(4)));
+        })));
+        let ip = unwrap!("1.2.3.4".parse());
+        let wait_now = move |pair: (UniqueId, UniqueId)| {
+            move |relay: &mut RelayState<UniqueId>, core: &mut Core| {
+                let waiting = Waiting {
+                    socket: Socket::default(),
+                    name_hash: [0; 32],
+                    source: ip,
+                    since: core.now(),
+                };
+                let _ = relay.waiting.insert(pair, waiting);
+            }
+        };
+        let first: (UniqueId, UniqueId) = (rand::random(), rand::random());
+        let second: (UniqueId, UniqueId) = (rand::random(), rand::random());
+        assert_eq!(on_relay(&mut el, &handle, wait_now(first)), vec![first]);
+
+        clock.advance(Duration::from_secs(PAIRING_TIMEOUT_SECS / 2));
+        assert_eq!(on_relay(&mut el, &handle, wait_now(second)).len(), 2);
+
+        // Each is dropped once it waited for as long as a request may.
+        clock.advance(Duration::from_secs(PAIRING_TIMEOUT_SECS / 2));
+        assert_eq!(on_relay(&mut el, &handle, |_, _| ()), vec![second]);
+        clock.advance(Duration::from_secs(PAIRING_TIMEOUT_SECS));
+        assert!(on_relay(&mut el, &handle, |_, _| ()).is_empty());
+    }
+
+    #[test]
+    fn quotas_admit_a_second_worth_of_bytes_at_a_time() {
+        let start = Instant::now();
+        let mut quota = Quota::new(Some(1000), start);
+        assert!(quota.admits(600, start));
+        quota.spend(600);
+        assert!(quota.admits(400, start));
+        assert!(!quota.admits(401, start));
+
+        // The first frame of a second goes through however large it is.
+        let next = start + Duration::from_secs(1);
+        assert!(quota.admits(5000, next));
+        quota.spend(5000);
+        assert!(!quota.admits(1, next + Duration::from_millis(999)));
+        assert!(quota.admits(1000, next + Duration::from_secs(1)));
+
+        assert!(Quota::new(None, start).admits(u64::max_value(), start));
+    }
+
+    fn relay_with_session(a: UniqueId, ip: IpAddr) -> RelayState<UniqueId> {
+        let relay = relay(100);
+        let other_ip = unwrap!("9.9.9.9".parse());
+        relay
+            .sessions
+            .borrow_mut()
+            .opened(&[a, rand::random()], &[ip, other_ip]);
+        relay
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::time::Instant;
+
+/// Time within which a request has to be answered, unless configured.
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+/// Number of answered requests remembered per connection, so that a second response to one of
+/// them is told apart from a response to no request of ours.
+const MAX_ANSWERED: usize = 256;
+
+/// Identifies a request sent with `Service::send_request`, and the response to it. Unique among
+/// the requests of a `Service`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(pub u64);
+
+/// What a response received turned out to answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseMatch {
+    /// A pending request, which is done with.
+    Matched,
+    /// A request which was answered already.
+    Duplicate,
+    /// No pending request: one which timed out, or was answered too long ago to tell.
+    Unmatched,
+    /// A request we never sent on the connection, going by its id.
+    Unsolicited,
+}
+
+/// The requests sent on a connection which haven't been answered yet, with their deadlines.
+#[derive(Debug, Default)]
+pub struct PendingRequests {
+    pending: HashMap<u64, Instant>,
+    /// The deadlines, earliest first. Those of requests answered, or whose deadline was replaced,
+    /// stay until they come first, so an entry counts only if `pending` agrees with it.
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    answered: VecDeque<u64>,
+    /// The highest id sent. Ids are handed out in increasing order, so none above it was sent.
+    highest: Option<u64>,
+}
+
+impl PendingRequests {
+    pub fn insert(&mut self, id: u64, deadline: Instant) {
+        let _ = self.pending.insert(id, deadline);
+        self.deadlines.push(Reverse((deadline, id)));
+        if self.highest.map_or(true, |highest| highest < id) {
+            self.highest = Some(id);
+        }
+    }
+
+    /// Matches the response to the request of the given id.
+    pub fn answer(&mut self, id: u64) -> ResponseMatch {
+        if self.pending.remove(&id).is_some() {
+            if self.answered.len() == MAX_ANSWERED {
+                let _ = self.answered.pop_front();
+            }
+            self.answered.push_back(id);
+            ResponseMatch::Matched
+        } else if self.answered.contains(&id) {
+            ResponseMatch::Duplicate
+        } else if self.highest.map_or(true, |highest| highest < id) {
+            ResponseMatch::Unsolicited
+        } else {
+            ResponseMatch::Unmatched
+        }
+    }
+
+    /// Removes the requests whose deadline has passed by `now` and returns their ids, oldest
+    /// first.
+    pub fn expire(&mut self, now: Instant) -> Vec<u64> {
+        let mut expired = Vec::new();
+        while let Some((deadline, id)) = self.peek_deadline() {
+            if deadline > now {
+                break;
+            }
+            let _ = self.deadlines.pop();
+            let _ = self.pending.remove(&id);
+            expired.push(id);
+        }
+        expired.sort();
+        expired
+    }
+
+    /// Removes all the pending requests and returns their ids, oldest first.
+    pub fn take_all(&mut self) -> Vec<u64> {
+        self.deadlines.clear();
+        let mut ids: Vec<u64> = self.pending.drain().map(|(id, _)| id).collect();
+        ids.sort();
+        ids
+    }
+
+    /// The earliest deadline of the pending requests.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        self.peek_deadline().map(|(deadline, _)| deadline)
+    }
+
+    /// The earliest deadline of the pending requests and the id of its request, dropping the
+    /// entries which no longer count on the way.
+    fn peek_deadline(&mut self) -> Option<(Instant, u64)> {
+        loop {
+            let (deadline, id) = match self.deadlines.peek() {
+                Some(&Reverse(entry)) => entry,
+                None => return None,
+            };
+            if self.pending.get(&id) == Some(&deadline) {
+                return Some((deadline, id));
+            }
+            let _ = self.deadlines.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn responses_are_matched_once() {
+        let now = Instant::now();
+        let mut requests = PendingRequests::default();
+        requests.insert(1, now + Duration::from_secs(1));
+        requests.insert(2, now + Duration::from_secs(2));
+        requests.insert(3, now + Duration::from_secs(1));
+        assert_eq!(requests.next_deadline(), Some(now + Duration::from_secs(1)));
+
+        assert_eq!(requests.answer(2), ResponseMatch::Matched);
+        assert_eq!(requests.answer(2), ResponseMatch::Duplicate);
+        assert_eq!(requests.answer(7), ResponseMatch::Unsolicited);
+
+        assert!(requests.expire(now).is_empty());
+        assert_eq!(requests.expire(now + Duration::from_secs(1)), vec![1, 3]);
+        assert_eq!(requests.next_deadline(), None);
+        // Too late.
+        assert_eq!(requests.answer(1), ResponseMatch::Unmatched);
+
+        // Only the latest answers are remembered.
+        for id in 10..(10 + MAX_ANSWERED as u64 + 1) {
+            requests.insert(id, now);
+            assert_eq!(requests.answer(id), ResponseMatch::Matched);
+        }
+        assert_eq!(requests.answer(10), ResponseMatch::Unmatched);
+        assert_eq!(requests.answer(11), ResponseMatch::Duplicate);
+
+        requests.insert(5, now);
+        requests.insert(4, now + Duration::from_secs(1));
+        assert_eq!(requests.take_all(), vec![4, 5]);
+        assert_eq!(requests.next_deadline(), None);
+    }
+
+    #[test]
+    fn answered_requests_no_longer_set_the_deadline() {
+        let now = Instant::now();
+        let mut requests = PendingRequests::default();
+        requests.insert(1, now + Duration::from_secs(1));
+        requests.insert(2, now + Duration::from_secs(2));
+        assert_eq!(requests.answer(1), ResponseMatch::Matched);
+        assert_eq!(requests.next_deadline(), Some(now + Duration::from_secs(2)));
+        assert!(requests.expire(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(requests.expire(now + Duration::from_secs(2)), vec![2]);
+    }
+}
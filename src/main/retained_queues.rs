@@ -0,0 +1,352 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Charge, Core, CoreTimer, MemoryBudget, Priority, State, Uid, MSG_DROP_PRIORITY};
+use main::{Event, EventSink};
+use mio::{Poll, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Token of the `RetainedQueues` state, which the connections hand their unsent messages to.
+pub const RETAINED_QUEUES_TOKEN: Token = Token(5);
+
+/// Maximum number of unsent messages retained for a lost peer. Further ones are dropped straight
+/// away, as are those there is no memory for, see `MemoryBudget::charge_msg`.
+pub const MAX_RETAINED_MSGS: usize = 256;
+
+const EXPIRY_TIMER_ID: u64 = 0;
+
+/// Messages which were still queued for peers whose connection was lost, kept for
+/// `Config::retention_window_secs` in case they reconnect.
+pub struct RetainedQueues<UID: Uid> {
+    token: Token,
+    window: Duration,
+    peers: HashMap<UID, Retained>,
+    event_tx: EventSink<UID>,
+}
+
+struct Retained {
+    expires_at: Instant,
+    msgs: Vec<(Vec<u8>, Priority)>,
+    /// What the messages are charged to the memory budget.
+    charge: Charge,
+}
+
+impl<UID: Uid> RetainedQueues<UID> {
+    pub fn start(
+        core: &mut Core,
+        token: Token,
+        window: Duration,
+        event_tx: EventSink<UID>,
+    ) {
+        let state = Rc::new(RefCell::new(RetainedQueues {
+            token,
+            window,
+            peers: HashMap::new(),
+            event_tx,
+        }));
+        let _ = core.insert_state(token, state);
+    }
+
+    /// Keeps the unsent messages of a lost peer, in the order they are to be sent again, until it
+    /// reconnects or the window has passed.
+    pub fn retain(&mut self, core: &mut Core, peer: UID, msgs: Vec<(Vec<u8>, Priority)>) {
+        if msgs.is_empty() {
+            return;
+        }
+        let overflow = self.insert(peer, msgs, core.now(), core.memory_budget());
+        if !overflow.is_empty() {
+            self.event_tx.send(Event::UnsentMessagesDropped(peer, overflow));
+        }
+        if !core.has_timeout(self.token, EXPIRY_TIMER_ID) {
+            self.schedule(core, self.window);
+        }
+    }
+
+    /// Returns the messages retained for a peer which has reconnected in time.
+    pub fn take(&mut self, peer: &UID, now: Instant) -> Vec<(Vec<u8>, Priority)> {
+        match self.peers.get(peer) {
+            // Reported by the next sweep.
+            Some(retained) if retained.expires_at <= now => return Vec::new(),
+            Some(_) => (),
+            None => return Vec::new(),
+        }
+        self.peers
+            .remove(peer)
+            .map_or_else(Vec::new, |retained| retained.msgs)
+    }
+
+    fn insert(
+        &mut self,
+        peer: UID,
+        msgs: Vec<(Vec<u8>, Priority)>,
+        now: Instant,
+        budget: &MemoryBudget,
+    ) -> Vec<Vec<u8>> {
+        let retained = self.peers.entry(peer).or_insert_with(|| Retained {
+            expires_at: now,
+            msgs: Vec::new(),
+            charge: Charge::default(),
+        });
+        retained.expires_at = now + self.window;
+        let mut overflow = Vec::new();
+        for (msg, priority) in msgs {
+            let charge = if retained.msgs.len() < MAX_RETAINED_MSGS {
+                budget.charge_msg(msg.len(), priority)
+            } else {
+                None
+            };
+            match charge {
+                Some(charge) => {
+                    retained.charge.absorb(charge);
+                    retained.msgs.push((msg, priority));
+                }
+                None => overflow.push(msg),
+            }
+        }
+        overflow
+    }
+
+    /// Drops the retained messages which may be dropped, see `MSG_DROP_PRIORITY`, to free memory,
+    /// reporting them with `Event::UnsentMessagesDropped`.
+    pub fn shed_droppable(&mut self) {
+        let mut emptied = Vec::new();
+        for (peer, retained) in &mut self.peers {
+            let (dropped, kept): (Vec<_>, Vec<_>) = retained
+                .msgs
+                .drain(..)
+                .partition(|&(_, priority)| priority >= MSG_DROP_PRIORITY);
+            retained.msgs = kept;
+            if retained.msgs.is_empty() {
+                emptied.push(*peer);
+            }
+            if dropped.is_empty() {
+                continue;
+            }
+            let dropped: Vec<_> = dropped.into_iter().map(|(msg, _)| msg).collect();
+            retained.charge.release(dropped.iter().map(Vec::len).sum());
+            self.event_tx.send(Event::UnsentMessagesDropped(*peer, dropped));
+        }
+        for peer in emptied {
+            let _ = self.peers.remove(&peer);
+        }
+    }
+
+    /// Removes and returns the peers whose window has passed, with their messages.
+    fn expire(&mut self, now: Instant) -> Vec<(UID, Vec<Vec<u8>>)> {
+        let expired: Vec<UID> = self
+            .peers
+            .iter()
+            .filter(|&(_, retained)| retained.expires_at <= now)
+            .map(|(peer, _)| *peer)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|peer| {
+                let retained = self.peers.remove(&peer)?;
+                Some((peer, retained.msgs.into_iter().map(|(msg, _)| msg).collect()))
+            })
+            .collect()
+    }
+
+    fn schedule(&mut self, core: &mut Core, delay: Duration) {
+        if let Err(e) = core.set_timeout(delay, CoreTimer::new(self.token, EXPIRY_TIMER_ID)) {
+            debug!("Failed to schedule expiry of retained messages: {:?}", e);
+        }
+    }
+}
+
+impl<UID: Uid> State for RetainedQueues<UID> {
+    fn name(&self) -> &'static str {
+        "RetainedQueues"
+    }
+
+    fn timeout(&mut self, core: &mut Core, _poll: &Poll, _timer_id: u64) {
+        let now = core.now();
+        for (peer, msgs) in self.expire(now) {
+            self.event_tx.send(Event::UnsentMessagesDropped(peer, msgs));
+        }
+        if let Some(next) = self.peers.values().map(|retained| retained.expires_at).min() {
+            let delay = if next > now {
+                next - now
+            } else {
+                Duration::from_secs(0)
+            };
+            self.schedule(core, delay);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use common::{MemoryPressure, Socket, FRAME_HEADER_SIZE};
+    use mio::channel;
+    use mio::tcp::TcpStream;
+    use mio::{PollOpt, Ready};
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use tests::{get_event_sender, UniqueId};
+
+    #[test]
+    fn retention_is_bounded_and_expires() {
+        let (event_tx, _event_rx) = get_event_sender();
+        let window = Duration::from_secs(2);
+        let mut queues = RetainedQueues::<UniqueId> {
+            token: Token(0),
+            window,
+            peers: HashMap::new(),
+            event_tx: EventSink::new(event_tx, channel::channel().0),
+        };
+        let now = Instant::now();
+        let peer_0 = [0; 20];
+        let peer_1 = [1; 20];
+
+        let msgs: Vec<_> = (0..MAX_RETAINED_MSGS + 2)
+            .map(|i| (vec![i as u8], 0))
+            .collect();
+        let budget = MemoryBudget::unlimited();
+        let overflow = queues.insert(peer_0, msgs, now, &budget);
+        assert_eq!(
+            overflow,
+            vec![vec![MAX_RETAINED_MSGS as u8], vec![MAX_RETAINED_MSGS as u8 + 1]]
+        );
+        let overflow = queues.insert(peer_1, vec![(vec![1], 3)], now + window / 2, &budget);
+        assert!(overflow.is_empty());
+
+        assert!(queues.expire(now + window / 2).is_empty());
+        let expired = queues.expire(now + window);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, peer_0);
+        assert_eq!(expired[0].1.len(), MAX_RETAINED_MSGS);
+        assert_eq!(expired[0].1[1], vec![1]);
+
+        assert_eq!(queues.take(&peer_1, now + window), vec![(vec![1], 3)]);
+        assert!(queues.take(&peer_1, now + window).is_empty());
+    }
+
+    #[test]
+    fn subsystems_give_way_in_order_under_one_budget() {
+        const LIMIT: usize = 1_000_000;
+        const MSG: usize = 10_000;
+
+        fn retain(
+            queues: &mut RetainedQueues<UniqueId>,
+            budget: &MemoryBudget,
+            priority: Priority,
+        ) -> bool {
+            let msgs = vec![(vec![0; MSG], priority)];
+            queues.insert([0; 20], msgs, Instant::now(), budget).is_empty()
+        }
+
+        let budget = MemoryBudget::new(LIMIT);
+        let (level_tx, level_rx) = mpsc::channel();
+        budget.set_on_change(Box::new(move |level: MemoryPressure| unwrap!(level_tx.send(level))));
+
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap!(listener.local_addr());
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+
+        // Nobody reads what `writer` sends, so it piles up in its queue.
+        let stream = unwrap!(TcpStream::connect(&addr));
+        let (_writer_peer, _) = unwrap!(listener.accept());
+        unwrap!(stream.set_send_buffer_size(8 * 1024));
+        let mut writer = Socket::wrap(stream);
+        writer.set_memory_budget(budget.clone());
+        unwrap!(writer.register(&poll, token, Ready::writable(), PollOpt::edge()));
+
+        let mut reader = Socket::wrap(unwrap!(TcpStream::connect(&addr)));
+        let (mut reader_peer, _) = unwrap!(listener.accept());
+        reader.set_memory_budget(budget.clone());
+
+        let (event_tx, _event_rx) = get_event_sender();
+        let mut queues = RetainedQueues::<UniqueId> {
+            token: Token(1),
+            window: Duration::from_secs(60),
+            peers: HashMap::new(),
+            event_tx: EventSink::new(event_tx, channel::channel().0),
+        };
+
+        // Low-priority messages are the first to be turned away, short of 70%, while the others
+        // still go.
+        let mut sent = 0;
+        while writer.take_dropped_msgs() == 0 {
+            let _ = unwrap!(writer.write_data(&poll, token, vec![0; MSG], MSG_DROP_PRIORITY));
+            sent += 1;
+            assert!(sent < 10 * LIMIT / MSG);
+        }
+        assert_eq!(budget.pressure(), MemoryPressure::Normal);
+        assert!(budget.used() + 2 * MSG > LIMIT * 7 / 10);
+        let _ = unwrap!(writer.write_data(&poll, token, vec![0; MSG], 0));
+        assert_eq!(writer.take_dropped_msgs(), 0);
+
+        // The same goes for retention.
+        while budget.pressure() < MemoryPressure::RefuseReassembly {
+            assert!(!retain(&mut queues, &budget, MSG_DROP_PRIORITY));
+            assert!(retain(&mut queues, &budget, 0));
+        }
+
+        // Beyond 80%, the header of a new frame is read but nothing is allocated for its body.
+        let mut frame = vec![7; FRAME_HEADER_SIZE + MSG];
+        LittleEndian::write_u32(&mut frame, MSG as u32);
+        unwrap!(reader_peer.write_all(&frame));
+        for _ in 0..100 {
+            assert!(unwrap!(reader.read_frame()).is_none());
+            if reader.is_read_stalled() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(reader.is_read_stalled());
+        assert_eq!(unwrap!(reader.partial_frame()).received, 0);
+
+        // Retention takes the rest, but never more.
+        while retain(&mut queues, &budget, 0) {}
+        assert!(budget.used() > LIMIT - MSG);
+        assert!(budget.peak() <= LIMIT);
+        assert!(unwrap!(reader.read_frame()).is_none());
+        assert!(reader.is_read_stalled());
+
+        let levels: Vec<_> = level_rx.try_iter().collect();
+        assert_eq!(
+            levels,
+            vec![
+                MemoryPressure::DropLowPriority,
+                MemoryPressure::RefuseReassembly,
+                MemoryPressure::PauseReads,
+                MemoryPressure::RejectAccepts,
+            ]
+        );
+
+        // Once memory is freed, the frame is let in.
+        writer.shed_droppable();
+        assert!(writer.take_dropped_msgs() > 0);
+        assert!(!queues.take(&[0; 20], Instant::now()).is_empty());
+        assert_eq!(budget.pressure(), MemoryPressure::Normal);
+        let (body, charge) = unwrap!(unwrap!(reader.read_frame()));
+        assert_eq!(body, vec![7; MSG]);
+        assert_eq!(charge.bytes(), MSG);
+        assert_eq!(level_rx.try_iter().last(), Some(MemoryPressure::Normal));
+    }
+}
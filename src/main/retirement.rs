@@ -0,0 +1,116 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Core, CoreTimer, State, Uid};
+use main::{ConnectionMap, Event, EventSink};
+use mio::{Poll, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Token of the `Retirement` state.
+pub const RETIREMENT_TOKEN: Token = Token(8);
+/// Largest number of peers a retiring service refers its peers to.
+pub const MAX_RETIREMENT_ALTERNATIVES: usize = 3;
+
+/// How often `Event::DrainingProgress` is raised.
+const PROGRESS_INTERVAL_MS: u64 = 1000;
+
+/// Counts down the grace period of `Service::begin_draining`, reporting how many peers are still
+/// connected, and drains the event loop once it is over.
+pub struct Retirement<UID: Uid> {
+    token: Token,
+    timer: CoreTimer,
+    deadline: Instant,
+    cm: ConnectionMap<UID>,
+    event_tx: EventSink<UID>,
+}
+
+impl<UID: Uid> Retirement<UID> {
+    pub fn start(
+        core: &mut Core,
+        token: Token,
+        grace: Duration,
+        cm: ConnectionMap<UID>,
+        event_tx: EventSink<UID>,
+    ) -> ::Res<()> {
+        trace!("Entered state Retirement");
+
+        let timer = CoreTimer::new(token, 0);
+        core.set_timeout(next_tick(grace), timer)?;
+
+        let state = Retirement {
+            token,
+            timer,
+            deadline: core.now() + grace,
+            cm,
+            event_tx,
+        };
+        state.report_progress(grace);
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    fn report_progress(&self, remaining_time: Duration) {
+        let remaining_peers = unwrap!(self.cm.lock())
+            .values()
+            .filter(|cid| cid.active_connection.is_some())
+            .count();
+        self.event_tx.send(Event::DrainingProgress {
+            remaining_peers,
+            remaining_time,
+        });
+    }
+
+    /// Ends the grace period: the service shuts down as gracefully as when the application drops
+    /// its event receiver, see `Core::drain`.
+    fn shut_down(&mut self, core: &mut Core, poll: &Poll) {
+        self.report_progress(Duration::from_secs(0));
+        info!("Grace period of the retirement is over - shutting the service down");
+        // Off the core first, as draining it drains every state still on it.
+        self.terminate(core, poll);
+        core.drain(poll);
+    }
+}
+
+impl<UID: Uid> State for Retirement<UID> {
+    fn name(&self) -> &'static str {
+        "Retirement"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        let now = core.now();
+        if now >= self.deadline {
+            return self.shut_down(core, poll);
+        }
+
+        let remaining_time = self.deadline - now;
+        if let Err(e) = core.set_timeout(next_tick(remaining_time), self.timer) {
+            debug!("Retirement Timer Errored out: {:?}", e);
+            return self.shut_down(core, poll);
+        }
+        self.report_progress(remaining_time);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// When to report progress next, with `remaining_time` left of the grace period.
+fn next_tick(remaining_time: Duration) -> Duration {
+    cmp::min(remaining_time, Duration::from_millis(PROGRESS_INTERVAL_MS))
+}
@@ -8,31 +8,66 @@
 // Software.
 
 use common::{
-    self, Core, CoreMessage, CrustUser, EventLoop, ExternalReachability, NameHash, Priority, Uid,
-    HASH_SIZE,
+    self, AuditLog, Core, CoreMessage, CoreStats, CrustUser, EventLoop, ExternalReachability,
+    KeyPair, LagWatchdog, ManualEventLoop, MemoryBudget, MemoryPressure, NetworkId,
+    PendingConnInfo, PowerMode, Priority, PublicKey, RotatingFile, SendReceipt, Uid,
+    MAX_PAYLOAD_SIZE,
 };
-use main::config_handler::{self, Config};
+#[cfg(test)]
+use common::VirtualClock;
+#[cfg(feature = "flight-recorder")]
+use common::{FlightRecorder, RecordedEvent};
+use main::config_handler::{self, data_file_path, Config};
+use main::diagnostics::{self, DiagnosticsReport, DIAGNOSTICS_CONNECT_TIMEOUT_SECS};
+use main::tagged_message;
 use main::{
-    ActiveConnection, Bootstrap, ConfigRefresher, ConfigWrapper, Connect, ConnectionId,
-    ConnectionInfoResult, ConnectionListener, ConnectionMap, CrustConfig, CrustError, Event,
-    PrivConnectionInfo, PubConnectionInfo,
+    advertise_listeners, announce_retirement, now_secs, promote_to_node, shed_droppable_msgs,
+    sort_by_score, with_asserted_endpoints, ActiveConnection, Bootstrap, BootstrapCacheEntry, Cache,
+    CacheWriter, CandidateAddr, ConfigRefresher, ConfigWrapper, Connect, ConnectedPeer,
+    ConnectionId, ConnectionInfoResult, ConnectionInfoSource, ConnectionListener, ConnectionMap,
+    ConnectionSettings, CrustConfig, CrustError, Event, EventSink, ExternalCore, HeartbeatIntervals,
+    IfAddrsLister, InterfaceLister, InterfaceMonitor, ListenerGroup, ListenerOptions, NetworkStats,
+    ParkedPeers, ParkedTable, PeerContact, PeerLimits, PeerStats, PrivConnectionInfo,
+    PubConnectionInfo, Reconnects, Redial, RequestId, RetainedQueues, Retirement, RetryAfter,
+    ServiceSnapshot, Shutdown, SuspendMonitor, Transport, Upgrades, HEARTBEAT_INTERVALS_TOKEN,
+    MAX_RETIREMENT_ALTERNATIVES, RECONNECTS_TOKEN, RETAINED_QUEUES_TOKEN, RETIREMENT_TOKEN,
+    SUSPEND_MONITOR_TOKEN, UPGRADES_TOKEN,
 };
+#[cfg(feature = "relay")]
+use main::{relays_of_peers, RelayState, RELAY_TOKEN};
 use mio::{Poll, Token};
 use nat;
 use nat::{MappedTcpSocket, MappingContext};
 use rust_sodium;
-use service_discovery::ServiceDiscovery;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+#[cfg(feature = "service-discovery")]
+use service_discovery::{HealthChange, ServiceDiscovery};
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::mem;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
-use tiny_keccak::sha3_256;
+use std::time::Duration;
 
 const BOOTSTRAP_TOKEN: Token = Token(0);
 const SERVICE_DISCOVERY_TOKEN: Token = Token(1);
 const LISTENER_TOKEN: Token = Token(2);
 const CONFIG_REFRESHER_TOKEN: Token = Token(3);
+const INTERFACE_MONITOR_TOKEN: Token = Token(4);
+/// Tokens from here on are numbered by the event loop, past those of the states above and the
+/// others registered under a fixed one, up to `UPGRADES_TOKEN`.
+const FIRST_LOOP_TOKEN: usize = 15;
+
+const DEFAULT_INTERFACE_SCAN_INTERVAL_SEC: u64 = 10;
+const DEFAULT_CONNECTION_INFO_TTL_SEC: u64 = 10 * 60;
+const DEFAULT_MAX_PARKED_PEERS: usize = 256;
+const DEFAULT_SNAPSHOT_MAX_AGE_SECS: u64 = 60 * 60;
 
+#[cfg(feature = "service-discovery")]
 const SERVICE_DISCOVERY_DEFAULT_PORT: u16 = 5484;
 
 const DISABLE_NAT: bool = true;
@@ -42,12 +77,58 @@ const DISABLE_NAT: bool = true;
 pub struct Service<UID: Uid> {
     config: CrustConfig,
     cm: ConnectionMap<UID>,
-    event_tx: ::CrustEventSender<UID>,
-    mc: Arc<MappingContext>,
+    event_tx: EventSink<UID>,
+    mc: Arc<Mutex<Arc<MappingContext>>>,
     el: EventLoop,
-    name_hash: NameHash,
+    network: NetworkId,
     our_uid: UID,
     our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    /// Tokens of the listeners on `additional_acceptor_ports`.
+    additional_listeners: Arc<Mutex<Vec<Token>>>,
+    /// What the primary and additional listeners share, see `ListenerGroup`.
+    listener_group: ListenerGroup,
+    parked: ParkedPeers<UID>,
+    /// Peers which asked us not to bootstrap off them again for a while.
+    bootstrap_retry_after: RetryAfter,
+    next_request_id: AtomicUsize,
+}
+
+/// The event loop of a `Service` constructed by `Service::with_external_loop`, run by its owner.
+pub struct ServiceCore {
+    el: ManualEventLoop,
+}
+
+impl ServiceCore {
+    /// Runs the event loop for `max_duration`, then returns. Returns `false` once the `Service`
+    /// has been dropped, or its event receiver has and the loop has drained, and there is nothing
+    /// left to run.
+    ///
+    /// This can be called at any intervals; timers which expired in between fire on the next
+    /// call.
+    pub fn run_once(&mut self, max_duration: Duration) -> ::Res<bool> {
+        Ok(self.el.run_once(max_duration)?)
+    }
+
+    /// Returns a snapshot of the event loop's counters, like `Service::core_stats` but without
+    /// waiting for the loop, so that it can be called from the thread running it.
+    pub fn core_stats(&self) -> CoreStats {
+        self.el.stats().clone()
+    }
+
+    /// Runs the event loop until `rx` receives a value.
+    fn run_until<T>(&mut self, rx: &mpsc::Receiver<T>) -> ::Res<T> {
+        loop {
+            match rx.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(mpsc::TryRecvError::Empty) => {
+                    if !self.run_once(Duration::from_millis(10))? {
+                        return Err(mpsc::RecvError.into());
+                    }
+                }
+                Err(mpsc::TryRecvError::Disconnected) => return Err(mpsc::RecvError.into()),
+            }
+        }
+    }
 }
 
 impl<UID: Uid> Service<UID> {
@@ -64,39 +145,400 @@ impl<UID: Uid> Service<UID> {
         event_tx: ::CrustEventSender<UID>,
         config: Config,
         our_uid: UID,
+    ) -> ::Res<Self> {
+        let el = common::spawn_event_loop(FIRST_LOOP_TOKEN, Some(&format!("{:?}", our_uid)))?;
+        trace!("Event loop started");
+
+        let service = Service::with_event_loop(event_tx, config, our_uid, el)?;
+        service.start_states(|rx| rx.recv()?)?;
+        Ok(service)
+    }
+
+    /// Constructs a service like `with_config`, which authenticates itself to its peers with
+    /// `keys` and encrypts every connection after the handshake. Peers which don't agree on
+    /// encryption, for want of keys of their own or because they are of an older version, are
+    /// refused, as are peers which fail to confirm the keys agreed on. The public key each peer
+    /// authenticated itself with comes with the event of its connection, such as
+    /// `Event::ConnectSuccess`.
+    pub fn with_keys(
+        event_tx: ::CrustEventSender<UID>,
+        config: Config,
+        our_uid: UID,
+        keys: KeyPair,
+    ) -> ::Res<Self> {
+        let service = Service::with_config(event_tx, config, our_uid)?;
+        service.post(move |core, _| core.set_keys(keys))?;
+        Ok(service)
+    }
+
+    /// Constructs a service with the config read from the file at `config_path`, instead of the
+    /// one found by searching the default locations, see `config_search_paths`. Refreshes of the
+    /// config re-read the same file.
+    pub fn with_config_path(
+        event_tx: ::CrustEventSender<UID>,
+        config_path: PathBuf,
+        our_uid: UID,
+    ) -> ::Res<Self> {
+        let config = config_handler::read_config(&Some(config_path.clone()))?;
+        let el = common::spawn_event_loop(FIRST_LOOP_TOKEN, Some(&format!("{:?}", our_uid)))?;
+        trace!("Event loop started");
+
+        let service = Service::with_event_loop(event_tx, config, our_uid, el)?;
+        unwrap!(service.config.lock()).config_path = Some(config_path);
+        service.start_states(|rx| rx.recv()?)?;
+        Ok(service)
+    }
+
+    /// Constructs a service like `with_config`, taking over the ID and the knowledge of the
+    /// network of the service the snapshot was exported from, see `Service::export_state`.
+    ///
+    /// The snapshot's contacts are added to the bootstrap cache, so that the first bootstrap draws
+    /// from them. Its listener addresses are advertised until the listener has been started and
+    /// has found out the current ones, unless the snapshot is older than
+    /// `snapshot_max_age_secs`.
+    pub fn with_snapshot(
+        event_tx: ::CrustEventSender<UID>,
+        config: Config,
+        snapshot: ServiceSnapshot<UID>,
+    ) -> ::Res<Self> {
+        Cache::new(&config.bootstrap_cache_name)?.merge_entries(&snapshot.contacts())?;
+        let max_age = config
+            .snapshot_max_age_secs
+            .unwrap_or(DEFAULT_SNAPSHOT_MAX_AGE_SECS);
+
+        let service = Service::with_config(event_tx, config, snapshot.our_uid)?;
+        if snapshot.age_secs() <= max_age {
+            let mut our_listeners = unwrap!(service.our_listeners.lock());
+            if our_listeners.is_empty() {
+                *our_listeners = snapshot.our_listeners;
+            }
+        }
+        Ok(service)
+    }
+
+    /// Constructs a service like `with_config`, but without spawning a thread for its event loop.
+    /// Instead the event loop is run by the caller, on a thread of their choosing, through the
+    /// returned `ServiceCore`. Nothing happens between calls to `ServiceCore::run_once`.
+    ///
+    /// The methods of the service which return a result from the event loop, such as
+    /// `set_accept_bootstrap` or `core_stats`, wait for it to run, so they must not be called from
+    /// the thread running it.
+    pub fn with_external_loop(
+        event_tx: ::CrustEventSender<UID>,
+        config: Config,
+        our_uid: UID,
+    ) -> ::Res<(Self, ServiceCore)> {
+        let (el, handle) = ManualEventLoop::new(FIRST_LOOP_TOKEN)?;
+        Service::with_manual_loop(event_tx, config, our_uid, el, handle)
+    }
+
+    /// Constructs a service like `with_external_loop`, whose timers run on the given virtual
+    /// clock. They fire when the `ServiceCore` is run after the clock has been advanced past them.
+    #[cfg(test)]
+    pub fn with_virtual_clock(
+        event_tx: ::CrustEventSender<UID>,
+        config: Config,
+        our_uid: UID,
+        clock: VirtualClock,
+    ) -> ::Res<(Self, ServiceCore)> {
+        let (el, handle) = ManualEventLoop::with_virtual_clock(FIRST_LOOP_TOKEN, clock)?;
+        Service::with_manual_loop(event_tx, config, our_uid, el, handle)
+    }
+
+    fn with_manual_loop(
+        event_tx: ::CrustEventSender<UID>,
+        config: Config,
+        our_uid: UID,
+        el: ManualEventLoop,
+        handle: EventLoop,
+    ) -> ::Res<(Self, ServiceCore)> {
+        let mut core = ServiceCore { el };
+
+        let service = Service::with_event_loop(event_tx, config, our_uid, handle)?;
+        service.start_states(|rx| core.run_until(&rx)?)?;
+        Ok((service, core))
+    }
+
+    fn with_event_loop(
+        event_tx: ::CrustEventSender<UID>,
+        config: Config,
+        our_uid: UID,
+        el: EventLoop,
     ) -> ::Res<Self> {
         let _ = rust_sodium::init();
 
-        let name_hash = name_hash(&config.network_name);
+        config.validate()?;
+
+        trace!(
+            "Network name: {:?}, private: {}",
+            config.network_name,
+            config.network_secret.is_some()
+        );
+        let network = NetworkId::new(&config.network_name, &config.network_secret);
 
         // Form our initial contact info
         let our_listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
-        let mut mc = MappingContext::new()?;
-        mc.add_peer_stuns(config.hard_coded_contacts.iter().cloned());
+        let mc = if config.lan_only {
+            MappingContext::without_igd()?
+        } else {
+            let mut mc = MappingContext::new()?;
+            mc.add_peer_stuns(config.hard_coded_contacts.iter().cloned());
+            mc
+        };
 
-        let el = common::spawn_event_loop(4, Some(&format!("{:?}", our_uid)))?;
-        trace!("Event loop started");
+        let max_parked_peers = config.max_parked_peers.unwrap_or(DEFAULT_MAX_PARKED_PEERS);
+        let event_tx = EventSink::new(event_tx, el.sender());
 
-        let service = Service {
+        Ok(Service {
             cm: Arc::new(Mutex::new(HashMap::new())),
             config: Arc::new(Mutex::new(ConfigWrapper::new(config))),
             event_tx,
-            mc: Arc::new(mc),
+            mc: Arc::new(Mutex::new(Arc::new(mc))),
             el,
-            name_hash,
+            network,
             our_uid,
             our_listeners,
+            additional_listeners: Arc::new(Mutex::new(Vec::new())),
+            listener_group: ListenerGroup::default(),
+            parked: Arc::new(Mutex::new(ParkedTable::new(max_parked_peers))),
+            bootstrap_retry_after: Arc::new(Mutex::new(HashMap::new())),
+            next_request_id: AtomicUsize::new(0),
+        })
+    }
+
+    /// Starts the states which run for the whole life of the service. `wait` waits for the result
+    /// of starting one of them.
+    fn start_states<F>(&self, mut wait: F) -> ::Res<()>
+    where
+        F: FnMut(mpsc::Receiver<::Res<()>>) -> ::Res<()>,
+    {
+        #[cfg(feature = "flight-recorder")]
+        {
+            self.start_flight_recorder()?;
+        }
+        self.start_lag_watchdog()?;
+        self.start_memory_budget()?;
+        self.start_bandwidth_limits()?;
+        self.start_audit_log()?;
+        #[cfg(feature = "stall-watchdog")]
+        {
+            self.start_stall_watchdog()?;
+        }
+        self.start_retained_queues()?;
+        self.start_reconnects()?;
+        self.start_upgrades()?;
+        self.start_heartbeat_intervals()?;
+        #[cfg(feature = "relay")]
+        {
+            self.start_relay()?;
+        }
+        wait(self.start_config_refresher()?)?;
+        if !unwrap!(self.config.lock()).cfg.disable_interface_monitor {
+            wait(self.start_interface_monitor(Box::new(IfAddrsLister))?)?;
+        }
+        if !unwrap!(self.config.lock()).cfg.disable_suspend_detection {
+            wait(self.start_suspend_monitor()?)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "flight-recorder")]
+    fn start_flight_recorder(&self) -> ::Res<()> {
+        let recorder = {
+            let config = unwrap!(self.config.lock());
+            match config.cfg.flight_recorder_kb {
+                Some(kb) => FlightRecorder::new(
+                    kb * 1024,
+                    config
+                        .cfg
+                        .flight_recorder_dump_path
+                        .clone()
+                        .map(PathBuf::from),
+                ),
+                None => return Ok(()),
+            }
         };
+        self.post(move |core, _| core.set_flight_recorder(recorder))
+    }
 
-        service.start_config_refresher()?;
+    /// Opens the audit log, failing the start of the service if it can't be opened, as an
+    /// operator who asked for one shouldn't find out only once it is needed.
+    fn start_audit_log(&self) -> ::Res<()> {
+        let config = match unwrap!(self.config.lock()).cfg.audit_log.clone() {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let path = data_file_path(config.path.as_ref())?;
+        let file = RotatingFile::open(path, config.max_size)?;
+        let audit_log = AuditLog::start(file, config.redact_ips);
+        self.post(move |core, _| core.set_audit_log(audit_log))
+    }
 
-        Ok(service)
+    fn start_lag_watchdog(&self) -> ::Res<()> {
+        let warn_after = match unwrap!(self.config.lock()).cfg.loop_lag_warn_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => return Ok(()),
+        };
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, _| {
+            let on_lag = move |duration| {
+                event_tx.send(Event::EventLoopLagging { duration });
+            };
+            core.set_lag_watchdog(LagWatchdog::new(warn_after, Box::new(on_lag)));
+        })
+    }
+
+    /// Sets the budget every state charges what it buffers to, reporting its changes of level and
+    /// dropping what may be dropped as it starts to run short.
+    fn start_memory_budget(&self) -> ::Res<()> {
+        let budget = match unwrap!(self.config.lock()).cfg.max_buffered_bytes {
+            Some(limit) => MemoryBudget::new(limit),
+            None => return Ok(()),
+        };
+        let event_tx = self.event_tx.clone();
+        let el_tx = self.el.sender();
+        let cm = self.cm.clone();
+        budget.set_on_change(Box::new(move |level: MemoryPressure| {
+            event_tx.send(Event::MemoryPressure { level });
+            if level >= MemoryPressure::DropLowPriority {
+                let cm = cm.clone();
+                let msg = CoreMessage::new(move |core, _| shed_droppable_msgs(core, &cm));
+                if el_tx.send(msg).is_err() {
+                    debug!("The event loop is gone already");
+                }
+            }
+        }));
+        self.post(move |core, _| core.set_memory_budget(budget))
+    }
+
+    fn start_bandwidth_limits(&self) -> ::Res<()> {
+        let (upload_bps, download_bps) = {
+            let config = unwrap!(self.config.lock());
+            (
+                config.cfg.max_upload_bytes_per_sec,
+                config.cfg.max_download_bytes_per_sec,
+            )
+        };
+        if upload_bps.is_none() && download_bps.is_none() {
+            return Ok(());
+        }
+        self.set_bandwidth_limits(upload_bps, download_bps)
+    }
+
+    /// Starts after the flight recorder, whose record it writes.
+    #[cfg(feature = "stall-watchdog")]
+    fn start_stall_watchdog(&self) -> ::Res<()> {
+        let dump_after = match unwrap!(self.config.lock()).cfg.loop_stall_dump_secs {
+            Some(secs) => Duration::from_secs(secs),
+            None => return Ok(()),
+        };
+        self.post(move |core, _| core.start_stall_watchdog(dump_after))
+    }
+
+    fn start_retained_queues(&self) -> ::Res<()> {
+        let window = match unwrap!(self.config.lock()).cfg.retention_window_secs {
+            Some(secs) => Duration::from_secs(secs),
+            None => return Ok(()),
+        };
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, _| {
+            if core.get_state(RETAINED_QUEUES_TOKEN).is_none() {
+                RetainedQueues::start(core, RETAINED_QUEUES_TOKEN, window, event_tx);
+            }
+        })
+    }
+
+    fn start_reconnects(&self) -> ::Res<()> {
+        let policy = match unwrap!(self.config.lock()).cfg.reconnect {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let our_uid = self.our_uid;
+        let our_listeners = self.our_listeners.clone();
+        let config = self.config.clone();
+        let cm = self.cm.clone();
+        let network = self.network;
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, _| {
+            if core.get_state(RECONNECTS_TOKEN).is_none() {
+                Reconnects::start(
+                    core,
+                    RECONNECTS_TOKEN,
+                    policy,
+                    our_uid,
+                    our_listeners,
+                    config,
+                    cm,
+                    network,
+                    event_tx,
+                );
+            }
+        })
+    }
+
+    fn start_upgrades(&self) -> ::Res<()> {
+        let our_uid = self.our_uid;
+        let our_listeners = self.our_listeners.clone();
+        let config = self.config.clone();
+        let cm = self.cm.clone();
+        let network = self.network;
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, _| {
+            if core.get_state(UPGRADES_TOKEN).is_none() {
+                Upgrades::start(
+                    core,
+                    UPGRADES_TOKEN,
+                    our_uid,
+                    our_listeners,
+                    config,
+                    cm,
+                    network,
+                    event_tx,
+                );
+            }
+        })
+    }
+
+    fn start_heartbeat_intervals(&self) -> ::Res<()> {
+        let settings = match unwrap!(self.config.lock()).cfg.adaptive_heartbeat.clone() {
+            Some(settings) => settings,
+            None => return Ok(()),
+        };
+        self.post(move |core, _| {
+            if core.get_state(HEARTBEAT_INTERVALS_TOKEN).is_none() {
+                HeartbeatIntervals::start(core, HEARTBEAT_INTERVALS_TOKEN, settings);
+            }
+        })
+    }
+
+    #[cfg(feature = "relay")]
+    fn start_relay(&self) -> ::Res<()> {
+        let config = match unwrap!(self.config.lock()).cfg.relay {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        self.post(move |core, _| {
+            if core.get_state(RELAY_TOKEN).is_none() {
+                if let Err(e) = RelayState::<UID>::start(core, RELAY_TOKEN, config) {
+                    debug!("Failed to start relaying: {:?}", e);
+                }
+            }
+        })
     }
 
-    fn start_config_refresher(&self) -> ::Res<()> {
+    fn start_config_refresher(&self) -> ::Res<mpsc::Receiver<::Res<()>>> {
         let (tx, rx) = mpsc::channel();
         let config = self.config.clone();
         let cm = self.cm.clone();
+        let event_tx = self.event_tx.clone();
+        let start_listeners = self.listener_starter();
+        let additional_listeners = self.additional_listeners.clone();
+        let restart_listeners = Box::new(move |core: &mut Core, poll: &Poll| {
+            if core.has_state(LISTENER_TOKEN) {
+                stop_listeners::<UID>(core, poll, &additional_listeners);
+                start_listeners(core, poll);
+            }
+        });
         self.post(move |core, _| {
             if core.get_state(CONFIG_REFRESHER_TOKEN).is_none() {
                 let _ = tx.send(ConfigRefresher::start(
@@ -104,33 +546,104 @@ impl<UID: Uid> Service<UID> {
                     CONFIG_REFRESHER_TOKEN,
                     cm,
                     config,
+                    event_tx,
+                    restart_listeners,
                 ));
             }
             let _ = tx.send(Ok(()));
         })?;
-        rx.recv()?
+        Ok(rx)
+    }
+
+    fn start_interface_monitor(
+        &self,
+        lister: Box<InterfaceLister>,
+    ) -> ::Res<mpsc::Receiver<::Res<()>>> {
+        let interval = Duration::from_secs(
+            unwrap!(self.config.lock())
+                .cfg
+                .interface_scan_interval_sec
+                .unwrap_or(DEFAULT_INTERFACE_SCAN_INTERVAL_SEC),
+        );
+        let lan_only = unwrap!(self.config.lock()).cfg.lan_only;
+        let (tx, rx) = mpsc::channel();
+        let cm = self.cm.clone();
+        let mc = self.mc.clone();
+        let our_listeners = self.our_listeners.clone();
+        let config = self.config.clone();
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(INTERFACE_MONITOR_TOKEN) {
+                state.borrow_mut().terminate(core, poll);
+            }
+            // Answered once the interfaces have been listed for the first time.
+            if let Err(e) = InterfaceMonitor::start(
+                core,
+                INTERFACE_MONITOR_TOKEN,
+                interval,
+                lan_only,
+                lister,
+                cm,
+                mc,
+                our_listeners,
+                config,
+                event_tx,
+                tx.clone(),
+            ) {
+                let _ = tx.send(Err(e));
+            }
+        })?;
+        Ok(rx)
+    }
+
+    fn start_suspend_monitor(&self) -> ::Res<mpsc::Receiver<::Res<()>>> {
+        let lan_only = unwrap!(self.config.lock()).cfg.lan_only;
+        let (tx, rx) = mpsc::channel();
+        let cm = self.cm.clone();
+        let mc = self.mc.clone();
+        self.post(move |core, _| {
+            if core.get_state(SUSPEND_MONITOR_TOKEN).is_none() {
+                let _ = tx.send(SuspendMonitor::start(
+                    core,
+                    SUSPEND_MONITOR_TOKEN,
+                    lan_only,
+                    cm,
+                    mc,
+                ));
+            }
+            let _ = tx.send(Ok(()));
+        })?;
+        Ok(rx)
+    }
+
+    /// Restart watching network interfaces, listing them with the given lister.
+    #[cfg(test)]
+    pub fn set_interface_lister(&self, lister: Box<InterfaceLister>) -> ::Res<()> {
+        self.start_interface_monitor(lister)?.recv()?
     }
 
     /// Allow (or disallow) peers from bootstrapping off us.
     pub fn set_accept_bootstrap(&self, accept: bool) -> ::Res<()> {
         let (tx, rx) = mpsc::channel();
+        let additional_listeners = self.additional_listeners.clone();
         let _ = self.post(move |core, _| {
-            let state = match core.get_state(LISTENER_TOKEN) {
-                Some(state) => state,
-                None => {
-                    let _ = tx.send(Err(CrustError::ListenerNotIntialised));
-                    return;
-                }
-            };
-            let mut state = state.borrow_mut();
-            let listener = match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
-                Some(l) => l,
-                None => {
-                    warn!("Token reserved for ConnectionListener has something else.");
-                    return;
+            if core.get_state(LISTENER_TOKEN).is_none() {
+                let _ = tx.send(Err(CrustError::ListenerNotIntialised));
+                return;
+            }
+            let mut tokens = vec![LISTENER_TOKEN];
+            tokens.extend(unwrap!(additional_listeners.lock()).iter().cloned());
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
+                    Some(listener) => listener.set_accept_bootstrap(accept),
+                    None => warn!("Token reserved for ConnectionListener has something else."),
                 }
-            };
-            listener.set_accept_bootstrap(accept);
+            }
             let _ = tx.send(Ok(()));
         });
 
@@ -138,22 +651,34 @@ impl<UID: Uid> Service<UID> {
     }
 
     /// Initialises Service Discovery module and starts listening for responses to our beacon
-    /// broadcasts.
+    /// broadcasts. Should its socket fail later on, it is rebuilt in the background, as reported
+    /// by `Event::ServiceDiscoveryDegraded` and `Event::ServiceDiscoveryRecovered`. Only with the
+    /// `service-discovery` feature.
+    #[cfg(feature = "service-discovery")]
     pub fn start_service_discovery(&mut self) {
         let our_listeners = self.our_listeners.clone();
         let port = unwrap!(self.config.lock())
             .cfg
             .service_discovery_port
             .unwrap_or(SERVICE_DISCOVERY_DEFAULT_PORT);
+        let event_tx = self.event_tx.clone();
 
         let _ = self.post(move |core, poll| {
             if core.get_state(SERVICE_DISCOVERY_TOKEN).is_none() {
+                let on_health = move |change| {
+                    let event = match change {
+                        HealthChange::Degraded => Event::ServiceDiscoveryDegraded,
+                        HealthChange::Recovered => Event::ServiceDiscoveryRecovered,
+                    };
+                    event_tx.send(event);
+                };
                 if let Err(e) = ServiceDiscovery::start(
                     core,
                     poll,
                     our_listeners,
                     SERVICE_DISCOVERY_TOKEN,
                     port,
+                    Box::new(on_health),
                 ) {
                     debug!("Could not start ServiceDiscovery: {:?}", e);
                 }
@@ -163,7 +688,8 @@ impl<UID: Uid> Service<UID> {
     }
 
     /// Enable (or disable) listening and responding to peers searching for us. This can be used to
-    /// allow others to discover us on the local network.
+    /// allow others to discover us on the local network. Only with the `service-discovery` feature.
+    #[cfg(feature = "service-discovery")]
     pub fn set_service_discovery_listen(&self, listen: bool) {
         let _ = self.post(move |core, _| {
             let state = match core.get_state(SERVICE_DISCOVERY_TOKEN) {
@@ -182,7 +708,91 @@ impl<UID: Uid> Service<UID> {
         });
     }
 
-    fn get_peer_socket_addr(&self, peer_uid: &UID) -> ::Res<SocketAddr> {
+    /// Retires the service gracefully over `grace`. Service discovery stops answering at once,
+    /// and every bootstrap request is refused with `RejectionCode::Retiring`, referring the peer
+    /// to a few of the best peers of our bootstrap cache. Connected peers are told the same with
+    /// `Event::PeerRetiring`, so they can find replacements while the connection still works.
+    /// Progress is reported with `Event::DrainingProgress`, and once `grace` is over the service
+    /// shuts down, flushing what is queued to its peers.
+    ///
+    /// Calling this again while draining changes nothing.
+    pub fn begin_draining(&self, grace: Duration) -> ::Res<()> {
+        self.check_running()?;
+        #[cfg(feature = "service-discovery")]
+        {
+            self.set_service_discovery_listen(false);
+        }
+
+        let our_listeners = unwrap!(self.our_listeners.lock()).clone();
+        let entries = self.bootstrap_cache_snapshot().unwrap_or_else(|e| {
+            debug!("Retiring without alternatives, as the bootstrap cache failed: {:?}", e);
+            Vec::new()
+        });
+        let alternatives: Vec<SocketAddr> = entries
+            .into_iter()
+            .map(|entry| entry.addr)
+            .filter(|addr| !our_listeners.contains(addr))
+            .take(MAX_RETIREMENT_ALTERNATIVES)
+            .collect();
+
+        let (tx, rx) = mpsc::channel();
+        let cm = self.cm.clone();
+        let event_tx = self.event_tx.clone();
+        let additional_listeners = self.additional_listeners.clone();
+        self.post(move |core, poll| {
+            if core.get_state(RETIREMENT_TOKEN).is_some() {
+                let _ = tx.send(Ok(()));
+                return;
+            }
+            let mut tokens = vec![LISTENER_TOKEN];
+            tokens.extend(unwrap!(additional_listeners.lock()).iter().cloned());
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
+                    Some(listener) => listener.set_retiring(alternatives.clone()),
+                    None => warn!("Token reserved for ConnectionListener has something else."),
+                }
+            }
+            announce_retirement(core, poll, &cm, &alternatives);
+            let _ = tx.send(Retirement::start(core, RETIREMENT_TOKEN, grace, cm, event_tx));
+        })?;
+
+        rx.recv()?
+    }
+
+    /// Shuts the service down gracefully, unlike dropping it: stops bootstrapping and accepting
+    /// connections, says goodbye to every connected peer once what is queued for it has been
+    /// written, and waits up to `timeout` for the connections to close before stopping the event
+    /// loop. Peers are reported lost with `DisconnectReason::LocalRequested(0)` as usual.
+    ///
+    /// Returns the number of peers drained cleanly like that. Those whose connections were still
+    /// open by the timeout are cut off.
+    pub fn shutdown(self, timeout: Duration) -> ::Res<usize> {
+        let (tx, rx) = mpsc::channel();
+        let cm = self.cm.clone();
+        let additional_listeners = self.additional_listeners.clone();
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(BOOTSTRAP_TOKEN) {
+                state.borrow_mut().terminate(core, poll);
+            }
+            stop_listeners::<UID>(core, poll, &additional_listeners);
+            Shutdown::start(core, poll, &cm, timeout, tx);
+        })?;
+
+        Ok(rx.recv()?)
+    }
+
+    /// Runs `f` on the `ActiveConnection` to the given peer inside the event loop and returns its
+    /// result. Peers which are still mid-handshake are reported as `PeerNotFound`.
+    fn with_active_connection<F, R>(&self, peer_uid: &UID, f: F) -> ::Res<R>
+    where
+        F: FnOnce(&mut ActiveConnection<UID>, &Core) -> R + Send + 'static,
+        R: Send + 'static,
+    {
         let token = match unwrap!(self.cm.lock()).get(peer_uid) {
             Some(&ConnectionId {
                 active_connection: Some(token),
@@ -193,7 +803,7 @@ impl<UID: Uid> Service<UID> {
 
         let (tx, rx) = mpsc::channel();
 
-        let _ = self.post(move |core, _| {
+        self.post(move |core, _| {
             let state = match core.get_state(token) {
                 Some(state) => state,
                 None => {
@@ -201,42 +811,70 @@ impl<UID: Uid> Service<UID> {
                     return;
                 }
             };
-            match state
-                .borrow_mut()
-                .as_any()
-                .downcast_mut::<ActiveConnection<UID>>()
-            {
+            let mut state = state.borrow_mut();
+            match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
                 Some(active_connection) => {
-                    let _ = tx.send(Some(active_connection.peer_addr()));
+                    let _ = tx.send(Some(f(active_connection, core)));
                 }
                 None => {
                     debug!("Expected token {:?} to be ActiveConnection", token);
                     let _ = tx.send(None);
                 }
             };
-        });
+        })?;
 
         match rx.recv() {
-            Ok(Some(ip)) => ip,
+            Ok(Some(res)) => Ok(res),
             Ok(None) => Err(CrustError::PeerNotFound),
             Err(e) => Err(CrustError::ChannelRecv(e)),
         }
     }
 
-    /// Return the ip address of the peer.
-    pub fn get_peer_ip_addr(&self, peer_uid: &UID) -> ::Res<IpAddr> {
-        self.get_peer_socket_addr(peer_uid).map(|s| s.ip())
+    /// Return the remote socket address the peer is connected from. For relayed peers this is the
+    /// address of the relay (see `Service::peer_transport`).
+    pub fn peer_addr(&self, peer_uid: &UID) -> ::Res<SocketAddr> {
+        self.with_active_connection(peer_uid, |ac, _| ac.peer_addr())?
     }
 
-    /// Returns whether the given peer's IP is in the config file's hard-coded contacts list.
-    pub fn is_peer_hard_coded(&self, peer_uid: &UID) -> bool {
-        match self.get_peer_socket_addr(peer_uid) {
-            Ok(s) => {
-                let config = unwrap!(self.config.lock());
-                config
-                    .cfg
+    /// Return the transport over which we are connected to the peer.
+    pub fn peer_transport(&self, peer_uid: &UID) -> ::Res<Transport> {
+        self.with_active_connection(peer_uid, |ac, _| ac.transport())
+    }
+
+    /// Returns the long-term public key the peer authenticated itself with, see
+    /// `Service::with_keys`, or `None` if the connection isn't encrypted.
+    pub fn peer_public_key(&self, peer_uid: &UID) -> ::Res<Option<PublicKey>> {
+        self.with_active_connection(peer_uid, |ac, _| ac.peer_public_key())
+    }
+
+    /// Attaches an opaque tag to the connection to the given peer, which is passed back in every
+    /// later `Event::NewMessage`, `Event::NewSharedMessage` and `Event::LostPeer` for it. Tags
+    /// start out as 0, last until the peer disconnects and are never sent to the peer.
+    pub fn set_peer_tag(&self, peer_uid: &UID, tag: u64) -> ::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, _| ac.set_tag(tag))
+    }
+
+    /// Returns the tag of the connection to the given peer, see `Service::set_peer_tag`.
+    pub fn peer_tag(&self, peer_uid: &UID) -> ::Res<u64> {
+        self.with_active_connection(peer_uid, |ac, _| ac.tag())
+    }
+
+    /// Return the ip address of the peer.
+    pub fn get_peer_ip_addr(&self, peer_uid: &UID) -> ::Res<IpAddr> {
+        self.peer_addr(peer_uid).map(|s| s.ip())
+    }
+
+    /// Returns whether the given peer's IP is in the config file's hard-coded contacts list, or
+    /// among its WebSocket contacts.
+    pub fn is_peer_hard_coded(&self, peer_uid: &UID) -> bool {
+        match self.peer_addr(peer_uid) {
+            Ok(s) => {
+                let config = unwrap!(self.config.lock());
+                config
+                    .cfg
                     .hard_coded_contacts
                     .iter()
+                    .chain(config.cfg.websocket_contacts.iter())
                     .any(|addr| addr.ip() == s.ip())
             }
             Err(e) => {
@@ -248,12 +886,13 @@ impl<UID: Uid> Service<UID> {
 
     // TODO temp remove
     /// Check if we have peers on LAN
+    #[cfg(feature = "service-discovery")]
     pub fn has_peers_on_lan(&self) -> bool {
         use std::thread;
         use std::time::Duration;
 
         let (obs, rx) = mpsc::channel();
-        let _ = self.post(move |core, _| {
+        let _ = self.post(move |core, poll| {
             let state = match core.get_state(SERVICE_DISCOVERY_TOKEN) {
                 Some(state) => state,
                 None => return,
@@ -267,7 +906,7 @@ impl<UID: Uid> Service<UID> {
                 }
             };
             service_discovery.register_observer(obs);
-            let _ = service_discovery.seek_peers();
+            let _ = service_discovery.seek_peers(core, poll);
         });
 
         thread::sleep(Duration::from_secs(1));
@@ -283,9 +922,10 @@ impl<UID: Uid> Service<UID> {
     ) -> ::Res<()> {
         let config = self.config.clone();
         let our_uid = self.our_uid;
-        let name_hash = self.name_hash;
+        let network = self.network;
         let cm = self.cm.clone();
         let event_tx = self.event_tx.clone();
+        let retry_after = self.bootstrap_retry_after.clone();
         let ext_reachability = match crust_user {
             CrustUser::Node => ExternalReachability::Required {
                 direct_listeners: unwrap!(self.our_listeners.lock()).iter().cloned().collect(),
@@ -298,24 +938,26 @@ impl<UID: Uid> Service<UID> {
                 if let Err(e) = Bootstrap::start(
                     core,
                     poll,
-                    name_hash,
+                    network,
                     ext_reachability,
                     our_uid,
                     cm,
                     config,
                     blacklist,
+                    retry_after,
                     BOOTSTRAP_TOKEN,
                     SERVICE_DISCOVERY_TOKEN,
                     event_tx.clone(),
                 ) {
                     error!("Could not bootstrap: {:?}", e);
-                    let _ = event_tx.send(Event::BootstrapFailed);
+                    event_tx.send(Event::BootstrapFailed);
                 }
             }
         })
     }
 
-    /// Stop the bootstraping procedure explicitly
+    /// Stop the bootstraping procedure explicitly. Nothing more is reported for the bootstrap
+    /// unless it had connected already, in which case `Event::BootstrapConnect` has been sent.
     pub fn stop_bootstrap(&mut self) -> ::Res<()> {
         self.post(move |core, poll| {
             if let Some(state) = core.get_state(BOOTSTRAP_TOKEN) {
@@ -324,25 +966,54 @@ impl<UID: Uid> Service<UID> {
         })
     }
 
-    /// Starts accepting TCP connections. This is persistant until it errors out or is stopped
-    /// explicitly.
+    /// Starts accepting TCP connections, on `tcp_acceptor_port` as well as on the
+    /// `additional_acceptor_ports`. This is persistant until it errors out or is stopped
+    /// explicitly. Only the listener on `tcp_acceptor_port` is reported with
+    /// `Event::ListenerStarted` or `Event::ListenerFailed`.
     pub fn start_listening_tcp(&mut self) -> ::Res<()> {
+        let start_listeners = self.listener_starter();
+        self.post(move |core, poll| start_listeners(core, poll))
+    }
+
+    /// Starts the listeners which aren't running yet, on the ports in the config at the time it
+    /// is called, see `start_listening_tcp`. Also called to move the listeners once the ports in
+    /// the config file change.
+    fn listener_starter(&self) -> Box<Fn(&mut Core, &Poll) + Send> {
         let cm = self.cm.clone();
-        let mc = self.mc.clone();
+        let mc = unwrap!(self.mc.lock()).clone();
         let config = self.config.clone();
-        let port = unwrap!(self.config.lock())
-            .cfg
-            .tcp_acceptor_port
-            .unwrap_or(0);
-        let force_include_port = unwrap!(self.config.lock())
-            .cfg
-            .force_acceptor_port_in_ext_ep;
         let our_uid = self.our_uid;
-        let name_hash = self.name_hash;
+        let network = self.network;
         let our_listeners = self.our_listeners.clone();
+        let additional_listeners = self.additional_listeners.clone();
+        let group = self.listener_group.clone();
         let event_tx = self.event_tx.clone();
 
-        self.post(move |core, poll| {
+        Box::new(move |core: &mut Core, poll: &Poll| {
+            let (port, force_include_port, configured_ports) = {
+                let config = unwrap!(config.lock());
+                (
+                    config.cfg.tcp_acceptor_port.unwrap_or(0),
+                    config.cfg.force_acceptor_port_in_ext_ep,
+                    config.cfg.additional_acceptor_ports.clone(),
+                )
+            };
+            let mut additional_ports = Vec::new();
+            for additional_port in configured_ports {
+                if additional_port != 0
+                    && additional_port != port
+                    && !additional_ports.contains(&additional_port)
+                {
+                    additional_ports.push(additional_port);
+                }
+            }
+
+            let mut additional_listeners = unwrap!(additional_listeners.lock());
+            let start_additional = additional_listeners.is_empty();
+            if start_additional {
+                group.starting(additional_ports.len());
+            }
+
             if core.get_state(LISTENER_TOKEN).is_none() {
                 ConnectionListener::start(
                     core,
@@ -351,38 +1022,243 @@ impl<UID: Uid> Service<UID> {
                     port,
                     force_include_port,
                     our_uid,
-                    name_hash,
-                    cm,
-                    config,
-                    mc,
-                    our_listeners,
+                    network,
+                    cm.clone(),
+                    config.clone(),
+                    mc.clone(),
+                    our_listeners.clone(),
+                    group.clone(),
                     LISTENER_TOKEN,
-                    event_tx,
+                    true,
+                    event_tx.clone(),
                 );
             }
+
+            if !start_additional {
+                return;
+            }
+            for additional_port in additional_ports {
+                let token = core.get_new_token();
+                ConnectionListener::start(
+                    core,
+                    poll,
+                    None,
+                    additional_port,
+                    force_include_port,
+                    our_uid,
+                    network,
+                    cm.clone(),
+                    config.clone(),
+                    mc.clone(),
+                    our_listeners.clone(),
+                    group.clone(),
+                    token,
+                    false,
+                    event_tx.clone(),
+                );
+                additional_listeners.push(token);
+            }
         })
     }
 
-    /// Stops Listener explicitly and stops accepting TCP connections.
+    /// Stops Listener explicitly and stops accepting TCP connections, including those on the
+    /// additional acceptor ports.
     pub fn stop_tcp_listener(&mut self) -> ::Res<()> {
+        let additional_listeners = self.additional_listeners.clone();
         self.post(move |core, poll| {
             if let Some(state) = core.get_state(LISTENER_TOKEN) {
                 state.borrow_mut().terminate(core, poll);
             }
+            let tokens = mem::replace(&mut *unwrap!(additional_listeners.lock()), Vec::new());
+            for token in tokens {
+                if let Some(state) = core.get_state(token) {
+                    state.borrow_mut().terminate(core, poll);
+                }
+            }
+        })
+    }
+
+    /// Stops the listener accepting connections on `addr`, which may be any of the addresses it
+    /// is advertised at, and reports it with `Event::ListenerStopped`. The listener's addresses
+    /// are withdrawn from our connection info and from connected peers, and its IGD mappings are
+    /// removed. Connections it accepted already are kept.
+    pub fn stop_listener(&mut self, addr: SocketAddr) -> ::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let additional_listeners = self.additional_listeners.clone();
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, poll| {
+            let mut additional_listeners = unwrap!(additional_listeners.lock());
+            let mut tokens = vec![LISTENER_TOKEN];
+            tokens.extend(additional_listeners.iter().cloned());
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                let stopped = match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
+                    Some(listener) if listener.listens_on(&addr) => {
+                        listener.stop(core, poll);
+                        true
+                    }
+                    _ => false,
+                };
+                if stopped {
+                    additional_listeners.retain(|other| *other != token);
+                    event_tx.send(Event::ListenerStopped(addr));
+                    let _ = tx.send(Ok(()));
+                    return;
+                }
+            }
+            let _ = tx.send(Err(CrustError::ListenerNotFound(addr)));
+        })?;
+
+        rx.recv()?
+    }
+
+    /// Restarts the listener accepting connections on `addr`, which may be any of the addresses
+    /// it is advertised at, on a new socket set up with `options`, e.g. to apply a longer backlog.
+    /// Connections accepted already are kept. Where the platform lets two sockets listen on the
+    /// same port, none are refused while the listener restarts; elsewhere there is a brief outage
+    /// between the old socket closing and the new one listening. Fails with
+    /// `CrustError::ListenerNotFound` if no listener accepts connections on `addr`. A listener
+    /// which fails to listen again is stopped, the primary one reporting `Event::ListenerFailed`.
+    pub fn restart_listener(&mut self, addr: SocketAddr, options: ListenerOptions) -> ::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let additional_listeners = self.additional_listeners.clone();
+        self.post(move |core, poll| {
+            let mut tokens = vec![LISTENER_TOKEN];
+            tokens.extend(unwrap!(additional_listeners.lock()).iter().cloned());
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
+                    Some(listener) if listener.listens_on(&addr) => {
+                        let res = listener.restart(core, poll, &options);
+                        let _ = tx.send(res.map_err(CrustError::from));
+                        return;
+                    }
+                    _ => (),
+                }
+            }
+            let _ = tx.send(Err(CrustError::ListenerNotFound(addr)));
+        })?;
+
+        rx.recv()?
+    }
+
+    /// Advertises `addr` as an endpoint we are reachable at, as if it were listed in
+    /// `Config::external_endpoints`, and tells the peers we are connected to. Fails with
+    /// `CrustError::LanOnlyViolation` for a public address in LAN-only mode.
+    pub fn add_external_endpoint(&self, addr: SocketAddr) -> ::Res<()> {
+        if unwrap!(self.config.lock()).cfg.lan_only && nat::ip_addr_is_global(&addr.ip()) {
+            return Err(CrustError::LanOnlyViolation(addr));
+        }
+        self.update_external_endpoints(move |endpoints| {
+            if !endpoints.contains(&addr) {
+                endpoints.push(addr);
+            }
+        })
+    }
+
+    /// Stops advertising `addr` as an endpoint we are reachable at. It is withdrawn from our
+    /// connection info, the responses of service discovery and the peers we are connected to.
+    pub fn remove_external_endpoint(&self, addr: SocketAddr) -> ::Res<()> {
+        self.update_external_endpoints(move |endpoints| endpoints.retain(|other| *other != addr))
+    }
+
+    fn update_external_endpoints<F>(&self, update: F) -> ::Res<()>
+    where
+        F: FnOnce(&mut Vec<SocketAddr>) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let config = self.config.clone();
+        let our_listeners = self.our_listeners.clone();
+        let cm = self.cm.clone();
+        self.post(move |core, poll| {
+            let new_listeners = {
+                let mut guard = unwrap!(config.lock());
+                let old_endpoints = guard.external_endpoints.clone();
+                update(&mut guard.external_endpoints);
+                let mut listeners = unwrap!(our_listeners.lock());
+                let detected: Vec<_> = listeners
+                    .iter()
+                    .filter(|addr| !old_endpoints.contains(addr))
+                    .cloned()
+                    .collect();
+                let new_listeners = with_asserted_endpoints(&guard.external_endpoints, &detected);
+                if *listeners == new_listeners {
+                    None
+                } else {
+                    *listeners = new_listeners.clone();
+                    Some(new_listeners)
+                }
+            };
+            if let Some(listeners) = new_listeners {
+                advertise_listeners(core, poll, &cm, &listeners);
+            }
+            let _ = tx.send(());
+        })?;
+
+        Ok(rx.recv()?)
+    }
+
+    /// Asks the peers we are connected to to treat us as a node rather than a client, e.g. after
+    /// bootstrapping as a client and then starting to listen. Each peer checks that it can reach
+    /// us at one of our listeners and reports `Event::PeerPromoted`; those which can't report
+    /// back with `Event::PromotionFailed`. Calling this again only reaches peers which haven't
+    /// been asked for the current listeners yet or failed, and at most every 30 seconds each.
+    /// Fails with `CrustError::ListenerNotIntialised` until a listener is up.
+    pub fn promote_to_node(&self) -> ::Res<()> {
+        let listeners = unwrap!(self.our_listeners.lock()).clone();
+        if listeners.is_empty() {
+            return Err(CrustError::ListenerNotIntialised);
+        }
+        let cm = self.cm.clone();
+        self.post(move |core, poll| {
+            promote_to_node(core, poll, &cm, &listeners);
+        })
+    }
+
+    /// Moves the connection to the given peer onto a direct one, e.g. off a relay. We swap our
+    /// listeners with the peer over the connection, and whichever of us can reach the other's
+    /// dials them. Once the new connection is up both of us report
+    /// `Event::ConnectionUpgraded`, and the old one is closed after everything sent on it has
+    /// arrived, so messages are neither lost nor reordered. Otherwise `ConnectionUpgradeFailed` is
+    /// reported and the connection is kept as it was. Peers which don't support upgrades fail
+    /// right away.
+    pub fn upgrade_connection(&self, peer_uid: &UID) -> ::Res<()> {
+        self.check_running()?;
+        let token = self.active_connection_token(peer_uid)?;
+        self.post(move |core, poll| {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>()
+            {
+                active_connection.upgrade(core, poll);
+            }
         })
     }
 
     /// Connect to a peer. To call this method you must follow these steps:
     ///  * Generate a `PrivConnectionInfo` via `Service::prepare_connection_info`.
     ///  * Create a `PubConnectionInfo` via `PrivConnectionInfo::to_pub_connection_info`.
-    ///  * Swap `PubConnectionInfo`s out-of-band with the peer you are connecting to.
+    ///  * Swap `PubConnectionInfo`s out-of-band with the peer you are connecting to, as they are
+    ///    or in one of their text forms, `PubConnectionInfo::to_base64` or `to_words`.
     ///  * Call `Service::connect` using your `PrivConnectionInfo` and the `PubConnectionInfo`
-    ///    obtained from the peer
-    pub fn connect(
+    ///    obtained from the peer, or a `ConnectionInfoSource` wrapping its text form.
+    pub fn connect<S: Into<ConnectionInfoSource<UID>>>(
         &self,
         our_ci: PrivConnectionInfo<UID>,
-        mut their_ci: PubConnectionInfo<UID>,
+        their_ci: S,
     ) -> ::Res<()> {
+        let mut their_ci = their_ci.into().into_info()?;
         if their_ci.id == self.our_uid {
             debug!(
                 "Requested connect to {:?}, which is our peer ID",
@@ -391,6 +1267,11 @@ impl<UID: Uid> Service<UID> {
             return Err(CrustError::RequestedConnectToSelf);
         }
 
+        if their_ci.is_expired() {
+            debug!("Connection info of {:?} has expired", their_ci.id);
+            return Err(CrustError::ConnectionInfoExpired);
+        }
+
         if unwrap!(self.cm.lock()).contains_key(&their_ci.id) {
             debug!(
                 "Already connected OR already in process of connecting to {:?}",
@@ -399,37 +1280,45 @@ impl<UID: Uid> Service<UID> {
             return Ok(());
         }
 
-        {
+        let settings = {
             let guard = unwrap!(self.config.lock());
-            if let Some(ref whitelisted_node_ips) = guard.cfg.whitelisted_node_ips {
-                let their_direct = their_ci
-                    .for_direct
-                    .drain(..)
-                    .filter(|s| whitelisted_node_ips.contains(&s.ip()))
-                    .collect();
-                their_ci.for_direct = their_direct;
-
-                let their_hole_punch = their_ci
-                    .for_hole_punch
-                    .drain(..)
-                    .filter(|s| whitelisted_node_ips.contains(&s.ip()))
-                    .collect();
-                their_ci.for_hole_punch = their_hole_punch;
+            if guard.cfg.lan_only {
+                if let Some(candidate) = their_ci
+                    .candidates
+                    .iter()
+                    .find(|candidate| nat::ip_addr_is_global(&candidate.addr().ip()))
+                {
+                    debug!(
+                        "Refusing to connect to {:?} at public address {}",
+                        their_ci.id,
+                        candidate.addr()
+                    );
+                    return Err(CrustError::LanOnlyViolation(candidate.addr()));
+                }
             }
-        }
+            if let Some(whitelisted_node_ips) = guard.whitelists.ips(CrustUser::Node) {
+                their_ci
+                    .candidates
+                    .retain(|candidate| whitelisted_node_ips.contains(&candidate.addr().ip()));
+            }
+            ConnectionSettings::from_config(&guard.cfg)
+        };
 
         let event_tx = self.event_tx.clone();
         let cm = self.cm.clone();
-        let our_nh = self.name_hash;
+        let network = self.network;
 
         self.post(move |core, poll| {
-            let _ = Connect::start(core, poll, our_ci, their_ci, cm, our_nh, event_tx);
+            let _ = Connect::start(
+                core, poll, our_ci, their_ci, cm, network, event_tx, None, settings,
+            );
         })?;
 
         Ok(())
     }
 
-    /// Disconnect from the given peer and returns whether there was a connection at all.
+    /// Disconnect from the given peer and returns whether there was a connection at all. The peer
+    /// is told goodbye with reason code 0.
     pub fn disconnect(&self, peer_uid: &UID) -> bool {
         let token = match unwrap!(self.cm.lock()).get(peer_uid) {
             Some(&ConnectionId {
@@ -440,22 +1329,267 @@ impl<UID: Uid> Service<UID> {
         };
 
         let _ = self.post(move |core, poll| {
+            let _ = disconnect_token::<UID>(core, poll, token, 0);
+        });
+
+        true
+    }
+
+    /// Disconnect from the given peers in one go, telling each of them goodbye with the given
+    /// application-supplied reason code. Returns the peers which were actually connected; only
+    /// those are reported with `Event::LostPeer` and `DisconnectReason::LocalRequested(reason)`,
+    /// once the messages already queued for them have been sent.
+    pub fn disconnect_many(&self, peers: Vec<UID>, reason: u32) -> ::Res<Vec<UID>> {
+        let cm = self.cm.clone();
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, poll| {
+            let _ = tx.send(disconnect_peers(core, poll, &cm, peers, reason));
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Disconnect from all connected peers, like `Service::disconnect_many`.
+    pub fn disconnect_all(&self, reason: u32) -> ::Res<Vec<UID>> {
+        let cm = self.cm.clone();
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, poll| {
+            let peers = unwrap!(cm.lock()).keys().cloned().collect();
+            let _ = tx.send(disconnect_peers(core, poll, &cm, peers, reason));
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Disconnects the given peer and refuses connections from the IP it is connected from for
+    /// `duration`. Connections from the IP are dropped as they are accepted, and handshakes from it
+    /// under way are refused, each reported with `Event::PeerRejected`. A peer which broke the
+    /// protocol is banned the same way, see `Config::protocol_violation_policy`.
+    pub fn ban_peer(&self, peer_uid: &UID, duration: Duration) -> ::Res<()> {
+        let token = match unwrap!(self.cm.lock()).get(peer_uid) {
+            Some(&ConnectionId {
+                active_connection: Some(token),
+                ..
+            }) => token,
+            _ => return Err(CrustError::PeerNotFound),
+        };
+
+        let peer_uid = *peer_uid;
+        self.post(move |core, poll| {
+            let addr = core.get_state(token).and_then(|state| {
+                let mut state = state.borrow_mut();
+                let active_connection = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+                active_connection.peer_addr().ok()
+            });
+            match addr {
+                Some(addr) => {
+                    debug!("Banning {:?} at {} for {:?}", peer_uid, addr, duration);
+                    let now = core.now();
+                    core.bans_mut().ban(addr.ip(), now + duration, now);
+                }
+                None => debug!("Could not ban {:?}: its address is unknown", peer_uid),
+            }
+            let _ = disconnect_token::<UID>(core, poll, token, 0);
+        })
+    }
+
+    /// Lets peers of the given kind connect to us from `ip`, and us connect to nodes there, on top
+    /// of the IPs whitelisted in the config. If none are for that kind, only `ip` is from now on,
+    /// and peers connecting from elsewhere are refused with `Event::PeerRejected`. The IPs added
+    /// are kept until the whitelist of that kind changes in the config file.
+    pub fn whitelist_ip(&self, ip: IpAddr, kind: CrustUser) {
+        unwrap!(self.config.lock()).whitelists.add(kind, ip);
+    }
+
+    /// Close the connection to the given peer, after flushing the messages queued for it, but keep
+    /// its identity, address and stats so that it can be reconnected to with `Service::unpark`.
+    /// `Event::PeerParked` is sent instead of `Event::LostPeer` once the connection is closed. The
+    /// peer itself sees an ordinary disconnect.
+    ///
+    /// Unparking re-dials the listener the peer last advertised, or else the address the
+    /// connection went to, so only peers we connected to, which connected to us from their
+    /// listening address or which advertised their listeners can be unparked.
+    pub fn park(&self, peer_uid: &UID) -> ::Res<()> {
+        let token = match unwrap!(self.cm.lock()).get(peer_uid) {
+            Some(&ConnectionId {
+                active_connection: Some(token),
+                ..
+            }) => token,
+            _ => return Err(CrustError::PeerNotFound),
+        };
+
+        let parked = self.parked.clone();
+        self.post(move |core, poll| {
             if let Some(state) = core.get_state(token) {
-                state.borrow_mut().terminate(core, poll);
+                let mut state = state.borrow_mut();
+                if let Some(active_connection) =
+                    state.as_any().downcast_mut::<ActiveConnection<UID>>()
+                {
+                    active_connection.park(core, poll, parked);
+                }
+            }
+        })
+    }
+
+    /// Reconnect to a parked peer. `Event::PeerUnparked` is sent once the peer has been
+    /// re-authenticated, or `Event::ConnectFailure` if it couldn't be reached, in which case it
+    /// stays parked.
+    pub fn unpark(&self, peer_uid: &UID) -> ::Res<()> {
+        let addr = {
+            let mut parked = unwrap!(self.parked.lock());
+            if !parked.contains(peer_uid) {
+                return Err(CrustError::PeerNotFound);
+            }
+            match parked.start_unpark(peer_uid) {
+                Some(addr) => addr,
+                // Already being unparked.
+                None => return Ok(()),
+            }
+        };
+
+        let our_ci = PrivConnectionInfo {
+            id: self.our_uid,
+            for_asserted: Vec::new(),
+            for_direct: unwrap!(self.our_listeners.lock()).clone(),
+            for_hole_punch: Vec::new(),
+            for_utp: Vec::new(),
+            for_relay: Vec::new(),
+            hole_punch_socket: None,
+            issued_at: now_secs(),
+            ttl_secs: 0,
+            outbound_bind_addr: unwrap!(self.config.lock()).cfg.outbound_bind_addr,
+        };
+        let their_ci = PubConnectionInfo {
+            id: *peer_uid,
+            candidates: vec![CandidateAddr::TcpDirect(addr)],
+            issued_at: None,
+            ttl_secs: None,
+        };
+
+        let peer_uid = *peer_uid;
+        let event_tx = self.event_tx.clone();
+        let cm = self.cm.clone();
+        let network = self.network;
+        let parked = self.parked.clone();
+        let settings = ConnectionSettings::from_config(&unwrap!(self.config.lock()).cfg);
+
+        let res = self.post(move |core, poll| {
+            let redial = Some(Redial::Unpark(parked.clone()));
+            if let Err(e) = Connect::start(
+                core, poll, our_ci, their_ci, cm, network, event_tx, redial, settings,
+            ) {
+                debug!("Failed to unpark {:?}: {:?}", peer_uid, e);
+                unwrap!(parked.lock()).unpark_failed(&peer_uid);
             }
         });
+        if res.is_err() {
+            unwrap!(self.parked.lock()).unpark_failed(&peer_uid);
+        }
+        res
+    }
 
-        true
+    /// Returns the stats retained for the given peer if it is parked.
+    pub fn parked_peer_stats(&self, peer_uid: &UID) -> Option<PeerStats> {
+        unwrap!(self.parked.lock())
+            .get(peer_uid)
+            .map(|peer| peer.stats)
+    }
+
+    /// Returns the traffic and latency stats of the given connected peer.
+    pub fn peer_stats(&self, peer_uid: &UID) -> ::Res<PeerStats> {
+        self.with_active_connection(peer_uid, |ac, core| ac.stats(core.now()))
+    }
+
+    /// Clears the stats of the given peer, connected or parked, starting them over from now.
+    pub fn reset_peer_stats(&self, peer_uid: &UID) -> ::Res<()> {
+        if unwrap!(self.parked.lock()).reset_stats(peer_uid) {
+            return Ok(());
+        }
+        self.with_active_connection(peer_uid, |ac, _| ac.reset_stats())
+    }
+
+    /// Limits the bytes per second written to and read from the given connected peer, on top of
+    /// the limits of the service, see `set_bandwidth_limits`. `None` or 0 lifts a limit. The
+    /// limits go with the connection: a new connection to the peer starts out without them.
+    pub fn set_peer_bandwidth_limits(
+        &self,
+        peer_uid: &UID,
+        upload_bps: Option<u64>,
+        download_bps: Option<u64>,
+    ) -> ::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, core| {
+            ac.set_bandwidth_limits(upload_bps, download_bps, core.now())
+        })
+    }
+
+    /// Returns the limits up and down of the given connected peer, see
+    /// `set_peer_bandwidth_limits`.
+    pub fn peer_bandwidth_limits(&self, peer_uid: &UID) -> ::Res<(Option<u64>, Option<u64>)> {
+        self.with_active_connection(peer_uid, |ac, _| ac.bandwidth_limits())
+    }
+
+    /// Returns the peers we have an active connection to, with the kind and stats of each.
+    pub fn connected_peers(&self) -> ::Res<Vec<ConnectedPeer<UID>>> {
+        let cm = self.cm.clone();
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(connected_peers(core, &cm));
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Returns the config in effect, which follows the changes to the config file, see
+    /// `Event::ConfigUpdated`.
+    pub fn config(&self) -> Config {
+        unwrap!(self.config.lock()).cfg.clone()
+    }
+
+    /// Returns the traffic of all connected peers added up, for dashboards. See `connected_peers`
+    /// for that of each.
+    pub fn network_stats(&self) -> ::Res<NetworkStats> {
+        let mut network_stats = NetworkStats::default();
+        for peer in self.connected_peers()? {
+            network_stats.add(&peer.stats);
+        }
+        Ok(network_stats)
+    }
+
+    /// Changes the maximum number of clients and of nodes connected at a time, see
+    /// `Config::max_client_peers` and `Config::max_node_peers`. Only peers connecting from now on
+    /// are held to the new limits: those connected already stay so, even beyond them.
+    pub fn set_peer_limits(&self, max_client_peers: Option<usize>, max_node_peers: Option<usize>) {
+        unwrap!(self.config.lock()).peer_limits = PeerLimits {
+            max_client_peers,
+            max_node_peers,
+        };
     }
 
     /// Send data to a peer.
+    ///
+    /// Sending to a parked peer fails with `CrustError::PeerParked`, unless `auto_unpark_on_send`
+    /// is set in the config, in which case the message is queued and the peer is unparked.
+    ///
+    /// Messages of the same priority sent to the same peer are written in the order the calls
+    /// returned, also when several threads share the service behind a lock: each call hands the
+    /// message to the event loop before it returns, and the event loop takes them in the order
+    /// they were handed over. A message of a higher priority may overtake those queued before it,
+    /// and ones of `MSG_DROP_PRIORITY` and above may be dropped. Nothing is guaranteed across
+    /// different peers, or across a connection to the peer being lost and made again.
+    ///
+    /// `CONTROL_PRIORITY` is kept for the control messages of Crust, so that they get through
+    /// however much is queued; sending at it fails with `CrustError::ReservedPriority`.
     pub fn send(&self, peer_uid: &UID, msg: Vec<u8>, priority: Priority) -> ::Res<()> {
+        // Once shutting down the peer is gone too, which isn't what the caller should be told.
+        self.check_running()?;
+        check_user_priority(priority)?;
         let token = match unwrap!(self.cm.lock()).get(peer_uid) {
             Some(&ConnectionId {
                 active_connection: Some(token),
                 ..
-            }) => token,
-            _ => return Err(CrustError::PeerNotFound),
+            }) => Some(token),
+            _ => None,
+        };
+        let token = match token {
+            Some(token) => token,
+            None => return self.send_to_parked(peer_uid, msg, priority),
         };
 
         self.post(move |core, poll| {
@@ -465,35 +1599,266 @@ impl<UID: Uid> Service<UID> {
         })
     }
 
+    /// Like `Service::send`, but waits for the event loop to queue the message and returns where
+    /// it stood in the queue of the connection: how many bytes are to be written before it, and
+    /// how long that should take at the rate the connection drained at lately. Lets the caller
+    /// send less, or less often, before the queue builds up, rather than find out from messages
+    /// being dropped. The estimates are cheap to make, but only as good as the recent past.
+    ///
+    /// A message queued for a parked peer has no connection to wait for yet, and gets an empty
+    /// receipt without an estimate.
+    pub fn send_with_receipt(
+        &self,
+        peer_uid: &UID,
+        msg: Vec<u8>,
+        priority: Priority,
+    ) -> ::Res<SendReceipt> {
+        self.check_running()?;
+        check_user_priority(priority)?;
+        let token = match self.active_connection_token(peer_uid) {
+            Ok(token) => token,
+            Err(_) => {
+                self.send_to_parked(peer_uid, msg, priority)?;
+                return Ok(SendReceipt::default());
+            }
+        };
+
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if let Some(active_connection) =
+                    state.as_any().downcast_mut::<ActiveConnection<UID>>()
+                {
+                    let _ = tx.send(active_connection.send_with_receipt(core, poll, msg, priority));
+                }
+            }
+        })?;
+        // The connection was lost before the message could be queued.
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Like `Service::send`, for a message which is to go to several peers. Each of their
+    /// connections queues and writes the one buffer passed in, so broadcasting it costs no copy
+    /// per peer; it is freed once the last connection is done with it.
+    ///
+    /// A message queued for a parked peer has to outlive the connections, and is copied unless no
+    /// one else holds it any more.
+    pub fn send_shared(&self, peer_uid: &UID, msg: Arc<Vec<u8>>, priority: Priority) -> ::Res<()> {
+        self.check_running()?;
+        check_user_priority(priority)?;
+        let token = match self.active_connection_token(peer_uid) {
+            Ok(token) => token,
+            Err(e) => {
+                // Not worth a copy unless the message is to be queued.
+                if !unwrap!(self.parked.lock()).contains(peer_uid) {
+                    return Err(e);
+                }
+                let msg = Arc::try_unwrap(msg).unwrap_or_else(|msg| (*msg).clone());
+                return self.send_to_parked(peer_uid, msg, priority);
+            }
+        };
+
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if let Some(active_connection) =
+                    state.as_any().downcast_mut::<ActiveConnection<UID>>()
+                {
+                    active_connection.send_shared(core, poll, msg, priority);
+                }
+            }
+        })
+    }
+
+    /// Sends a request to a connected peer, which answers it with `Service::send_response`.
+    /// Exactly one of `Event::Response` and `Event::ResponseTimedOut` follows for the returned id:
+    /// the latter if no response came within `Config::request_timeout_secs`, if the connection
+    /// was lost first or if the peer runs a version of Crust without requests. Responses coming in
+    /// too late or more than once are dropped and counted in the peer's stats.
+    ///
+    /// Requests are separate from the plain messages of `Service::send`, which are delivered as
+    /// before.
+    pub fn send_request(
+        &self,
+        peer_uid: &UID,
+        data: Vec<u8>,
+        priority: Priority,
+    ) -> ::Res<RequestId> {
+        self.check_running()?;
+        check_user_priority(priority)?;
+        let token = self.active_connection_token(peer_uid)?;
+        let request_id = RequestId(self.next_request_id.fetch_add(1, Ordering::SeqCst) as u64);
+
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if let Some(active_connection) =
+                    state.as_any().downcast_mut::<ActiveConnection<UID>>()
+                {
+                    active_connection.send_request(core, poll, request_id, data, priority);
+                }
+            }
+        })?;
+        Ok(request_id)
+    }
+
+    /// Answers the request of the given id received from the peer in `Event::Request`.
+    pub fn send_response(&self, peer_uid: &UID, request_id: RequestId, data: Vec<u8>) -> ::Res<()> {
+        self.check_running()?;
+        let token = self.active_connection_token(peer_uid)?;
+
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if let Some(active_connection) =
+                    state.as_any().downcast_mut::<ActiveConnection<UID>>()
+                {
+                    active_connection.send_response(core, poll, request_id, data);
+                }
+            }
+        })
+    }
+
+    fn active_connection_token(&self, peer_uid: &UID) -> ::Res<Token> {
+        match unwrap!(self.cm.lock()).get(peer_uid) {
+            Some(&ConnectionId {
+                active_connection: Some(token),
+                ..
+            }) => Ok(token),
+            _ => Err(CrustError::PeerNotFound),
+        }
+    }
+
+    fn send_to_parked(&self, peer_uid: &UID, msg: Vec<u8>, priority: Priority) -> ::Res<()> {
+        if !unwrap!(self.parked.lock()).contains(peer_uid) {
+            return Err(CrustError::PeerNotFound);
+        }
+        if !unwrap!(self.config.lock()).cfg.auto_unpark_on_send {
+            return Err(CrustError::PeerParked);
+        }
+        if !unwrap!(self.parked.lock()).queue(peer_uid, msg, priority) {
+            return Err(CrustError::PeerParked);
+        }
+        self.unpark(peer_uid)
+    }
+
+    /// Serialise `msg`, tag it with the caller-chosen `schema_tag` and send it to the given peer.
+    /// The receiver should decode it with `Service::deserialize_message` using the same tag.
+    /// Fails with `CrustError::MessageTooLarge` if the result exceeds the configured limit.
+    pub fn send_serialized<T: Serialize>(
+        &self,
+        peer_uid: &UID,
+        schema_tag: u16,
+        msg: &T,
+        priority: Priority,
+    ) -> ::Res<()> {
+        let bytes = tagged_message::serialise_tagged(schema_tag, msg, self.message_size_limit())?;
+        self.send(peer_uid, bytes, priority)
+    }
+
+    /// Decode a message sent with `Service::send_serialized`. Messages exceeding the configured
+    /// size limit or carrying a different schema tag are rejected before being deserialised.
+    pub fn deserialize_message<T: DeserializeOwned>(
+        &self,
+        schema_tag: u16,
+        bytes: &[u8],
+    ) -> ::Res<T> {
+        tagged_message::deserialise_tagged(schema_tag, bytes, self.message_size_limit())
+    }
+
+    fn message_size_limit(&self) -> usize {
+        unwrap!(self.config.lock())
+            .cfg
+            .max_serialised_message_size
+            .map_or(MAX_PAYLOAD_SIZE, |limit| cmp::min(limit, MAX_PAYLOAD_SIZE))
+    }
+
     /// Generate connection info. The connection info is returned via the `ConnectionInfoPrepared`
     /// event on the event channel. Calling this method is the first step of connecting to another
     /// peer, see `Service::connect` for more info.
     // TODO: immediate return in case of sender.send() returned with NotificationError
     pub fn prepare_connection_info(&self, result_token: u32) {
-        let our_listeners = unwrap!(self.our_listeners.lock()).iter().cloned().collect();
-        if DISABLE_NAT {
-            let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                result_token,
-                result: Ok(PrivConnectionInfo {
-                    id: self.our_uid,
-                    for_direct: our_listeners,
-                    for_hole_punch: Default::default(),
-                    hole_punch_socket: None,
-                }),
-            });
-            let _ = self.event_tx.send(event);
+        let issued_at = now_secs();
+        let (ttl_secs, outbound_bind_addr, lan_only, asserted, enable_utp, use_relays) = {
+            let guard = unwrap!(self.config.lock());
+            let ttl_secs = guard
+                .cfg
+                .connection_info_ttl_secs
+                .unwrap_or(DEFAULT_CONNECTION_INFO_TTL_SEC);
+            (
+                ttl_secs,
+                guard.cfg.outbound_bind_addr,
+                guard.cfg.lan_only,
+                guard.external_endpoints.clone(),
+                guard.cfg.enable_utp,
+                guard.cfg.use_relays,
+            )
+        };
+        let (our_asserted, our_listeners): (Vec<_>, Vec<_>) = unwrap!(self.our_listeners.lock())
+            .iter()
+            .filter(|addr| !lan_only || !nat::ip_addr_is_global(&addr.ip()))
+            .cloned()
+            .partition(|addr| asserted.contains(addr));
+        // The listeners accept uTP on their ports too. The gateways which mapped a port may only
+        // forward TCP on it, in which case dialling it over uTP merely fails.
+        let for_utp = if cfg!(feature = "utp") && enable_utp {
+            our_listeners.clone()
+        } else {
+            Vec::new()
+        };
+        if DISABLE_NAT || lan_only || !cfg!(feature = "nat-traversal") {
+            let info = PrivConnectionInfo {
+                id: self.our_uid,
+                for_asserted: our_asserted,
+                for_direct: our_listeners,
+                for_hole_punch: Default::default(),
+                for_utp,
+                for_relay: Vec::new(),
+                hole_punch_socket: None,
+                issued_at,
+                ttl_secs,
+                outbound_bind_addr,
+            };
+            if !use_relays {
+                self.event_tx
+                    .send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                        result_token,
+                        result: Ok(info),
+                    }));
+                return;
+            }
+            // The relays are only known to the event loop.
+            let event_tx = self.event_tx.clone();
+            let cm = self.cm.clone();
+            if let Err(e) = self.post(move |core, _| {
+                let mut info = info;
+                info.for_relay = our_relays(core, &cm, use_relays);
+                event_tx.send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                    result_token,
+                    result: Ok(info),
+                }));
+            }) {
+                self.event_tx
+                    .send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                        result_token,
+                        result: Err(e),
+                    }));
+            }
         } else {
             let event_tx = self.event_tx.clone();
             let our_uid = self.our_uid;
-            let mc = self.mc.clone();
+            let cm = self.cm.clone();
+            let mc = unwrap!(self.mc.lock()).clone();
             if let Err(e) = self.post(move |core, poll| {
                 let event_tx_clone = event_tx.clone();
                 match MappedTcpSocket::<_, UID>::start(
                     core,
                     poll,
                     0,
+                    outbound_bind_addr,
                     &mc,
-                    move |_, _, socket, addrs| {
+                    move |core, _, socket, addrs, _| {
                         let hole_punch_addrs = addrs
                             .into_iter()
                             .filter(|elt| nat::ip_addr_is_global(&elt.ip()))
@@ -503,27 +1868,31 @@ impl<UID: Uid> Service<UID> {
                             result_token,
                             result: Ok(PrivConnectionInfo {
                                 id: our_uid,
+                                for_asserted: our_asserted,
                                 for_direct: our_listeners,
                                 for_hole_punch: hole_punch_addrs,
+                                for_utp,
+                                for_relay: our_relays(core, &cm, use_relays),
                                 hole_punch_socket: Some(socket),
+                                issued_at,
+                                ttl_secs,
+                                outbound_bind_addr,
                             }),
                         });
-                        let _ = event_tx.send(event);
+                        event_tx.send(event);
                     },
                 ) {
                     Ok(()) => (),
                     Err(e) => {
                         debug!("Error mapping tcp socket: {}", e);
-                        let _ =
-                            event_tx.send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                                result_token,
-                                result: Err(From::from(e)),
-                            }));
+                        event_tx.send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                            result_token,
+                            result: Err(From::from(e)),
+                        }));
                     }
                 };
             }) {
-                let _ = self
-                    .event_tx
+                self.event_tx
                     .send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
                         result_token,
                         result: Err(e),
@@ -532,54 +1901,382 @@ impl<UID: Uid> Service<UID> {
         }
     }
 
-    /// Check if we are connected to the given peer
-    pub fn is_connected(&self, peer_uid: &UID) -> bool {
-        match unwrap!(self.cm.lock()).get(peer_uid) {
-            Some(&ConnectionId {
-                active_connection: Some(_),
-                ..
-            }) => true,
-            _ => false,
-        }
+    /// Check if we are connected to the given peer
+    pub fn is_connected(&self, peer_uid: &UID) -> bool {
+        match unwrap!(self.cm.lock()).get(peer_uid) {
+            Some(&ConnectionId {
+                active_connection: Some(_),
+                ..
+            }) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns our ID.
+    pub fn id(&self) -> UID {
+        self.our_uid
+    }
+
+    /// Returns the sequence number of the last event sent to the application, counting from 1, or
+    /// 0 if none was. Every event sent is numbered, one after the other, and the channel keeps
+    /// them all in order: the `n`th event taken off it is event `n`. An application persisting
+    /// the events can number them as it takes them off and check against this for any it lost
+    /// on its own side.
+    pub fn last_emitted_seq(&self) -> u64 {
+        self.event_tx.emitted()
+    }
+
+    /// Returns the entries of the bootstrap cache, best scoring first, which is the order they are
+    /// tried in when bootstrapping, bar the odd low-scoring one given a chance to recover. The
+    /// cache may be shared with other services, so this also includes peers they have
+    /// bootstrapped off.
+    pub fn bootstrap_cache_snapshot(&self) -> ::Res<Vec<BootstrapCacheEntry>> {
+        self.flush_bootstrap_cache();
+        let cache_name = unwrap!(self.config.lock()).cfg.bootstrap_cache_name.clone();
+        let mut entries = Cache::new(&cache_name)?.snapshot()?;
+        sort_by_score(&mut entries);
+        Ok(entries)
+    }
+
+    /// Exports what we know about the network, to seed a restarted service with, see
+    /// `Service::with_snapshot`. Only the peers which are nodes are included.
+    pub fn export_state(&self) -> ::Res<ServiceSnapshot<UID>> {
+        let cm = self.cm.clone();
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(node_contacts(core, &cm));
+        })?;
+        let mut peers = rx.recv()?;
+        for contact in unwrap!(self.parked.lock()).node_contacts() {
+            if !peers.iter().any(|peer| peer.id == contact.id) {
+                peers.push(contact);
+            }
+        }
+
+        self.flush_bootstrap_cache();
+        let cache_name = unwrap!(self.config.lock()).cfg.bootstrap_cache_name.clone();
+        Ok(ServiceSnapshot {
+            taken_at: now_secs(),
+            our_uid: self.our_uid,
+            our_listeners: unwrap!(self.our_listeners.lock()).clone(),
+            bootstrap_contacts: Cache::new(&cache_name)?.snapshot()?,
+            peers,
+        })
+    }
+
+    /// Returns the events kept by the flight recorder, oldest first. Empty unless
+    /// `flight_recorder_kb` is set in the config. Only with the `flight-recorder` feature.
+    #[cfg(feature = "flight-recorder")]
+    pub fn dump_flight_record(&self) -> ::Res<Vec<RecordedEvent>> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(core.flight_record());
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Trades the precision of periodic timers for fewer wakeups of the event loop, e.g. on
+    /// battery, see `PowerMode`. The heartbeats of idle connections are stretched towards half the
+    /// inactivity timeout, or `AdaptiveHeartbeat::max_interval_ms` if lower, and back as soon as
+    /// a message of the application is sent or received on them. `CoreStats::timer_wakeups` tells
+    /// how often the event loop woke up for its timers.
+    pub fn set_power_mode(&self, mode: PowerMode) -> ::Res<()> {
+        self.post(move |core, _| core.set_power_mode(mode))
+    }
+
+    /// Limits the bytes per second written to and read from all connected peers together, see
+    /// `Config::max_upload_bytes_per_sec` and `Config::max_download_bytes_per_sec`. `None` or 0
+    /// lifts a limit. Takes effect on the connections made already too. Handshakes aren't held to
+    /// the limits.
+    pub fn set_bandwidth_limits(
+        &self,
+        upload_bps: Option<u64>,
+        download_bps: Option<u64>,
+    ) -> ::Res<()> {
+        self.post(move |core, _| {
+            let now = core.now();
+            core.bandwidth().set_limits(upload_bps, download_bps, now)
+        })
+    }
+
+    /// Returns the limits up and down of the service, `None` where unlimited, see
+    /// `set_bandwidth_limits`.
+    pub fn bandwidth_limits(&self) -> ::Res<(Option<u64>, Option<u64>)> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(core.bandwidth().limits());
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Returns a snapshot of the event loop's counters.
+    pub fn core_stats(&self) -> ::Res<CoreStats> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(core.stats().clone());
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Returns the connections whose handshake is still in progress, oldest first. Those made by
+    /// `connect` and those accepted by our listeners are included, bootstrap attempts aren't.
+    pub fn pending_connections(&self) -> ::Res<Vec<PendingConnInfo>> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(core.pending_connections());
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Runs `f` on the event loop with a view of it through which states of the application's
+    /// own are registered, see `ExternalState`. They run alongside the connections of the service
+    /// without access to them, and are terminated as the service shuts down.
+    pub fn register_state<F>(&self, f: F) -> ::Res<()>
+    where
+        F: FnOnce(&mut ExternalCore, &Poll) + Send + 'static,
+    {
+        self.post(move |core, poll| f(&mut ExternalCore::new(core), poll))
+    }
+
+    /// Checks the prerequisites of joining the network without joining it, see
+    /// `DiagnosticCheck`. The hard-coded contacts are connected to and dropped straight away, and
+    /// no check takes longer than `DIAGNOSTICS_CONNECT_TIMEOUT_SECS` and a second. With a service
+    /// made by `with_external_loop`, the loop should be run meanwhile on another thread.
+    pub fn run_diagnostics(&self) -> DiagnosticsReport {
+        let (config_path, config) = {
+            let guard = unwrap!(self.config.lock());
+            (guard.config_path.clone(), guard.cfg.clone())
+        };
+        let our_listeners = unwrap!(self.our_listeners.lock()).clone();
+        let our_ports: Vec<u16> = our_listeners.iter().map(|addr| addr.port()).collect();
+        let mc = unwrap!(self.mc.lock()).clone();
+        let timeout = Duration::from_secs(DIAGNOSTICS_CONNECT_TIMEOUT_SECS);
+
+        DiagnosticsReport {
+            results: vec![
+                diagnostics::check_config(&config_path, &config),
+                diagnostics::check_acceptor_port(config.tcp_acceptor_port, &our_ports),
+                diagnostics::check_discovery_port(self.discovery_port()),
+                diagnostics::check_contacts(&config.hard_coded_contacts, timeout),
+                diagnostics::check_igd(&mc, config.lan_only),
+                diagnostics::check_external_addrs(&our_listeners, config.lan_only),
+            ],
+        }
+    }
+
+    /// The port of service discovery, and whether our service discovery is running on it.
+    #[cfg(feature = "service-discovery")]
+    fn discovery_port(&self) -> Option<(u16, bool)> {
+        let port = unwrap!(self.config.lock())
+            .cfg
+            .service_discovery_port
+            .unwrap_or(SERVICE_DISCOVERY_DEFAULT_PORT);
+        let (tx, rx) = mpsc::channel();
+        let res = self.post(move |core, _| {
+            let _ = tx.send(core.has_state(SERVICE_DISCOVERY_TOKEN));
+        });
+        let running = res.is_ok() && rx.recv_timeout(Duration::from_secs(1)).unwrap_or(false);
+        Some((port, running))
+    }
+
+    #[cfg(not(feature = "service-discovery"))]
+    fn discovery_port(&self) -> Option<(u16, bool)> {
+        None
     }
 
-    /// Returns our ID.
-    pub fn id(&self) -> UID {
-        self.our_uid
+    /// Waits for the updates of the bootstrap cache made on the event loop to be written, see
+    /// `CacheWriter`.
+    fn flush_bootstrap_cache(&self) {
+        let (tx, rx) = mpsc::channel();
+        let res = self.post(move |core, _| {
+            let _ = tx.send(CacheWriter::flush(core));
+        });
+        if res.is_err() {
+            return;
+        }
+        if let Ok(Some(flushed)) = rx.recv() {
+            let _ = flushed.recv();
+        }
     }
 
+    /// Runs `f` on the event loop, see `check_running`.
     fn post<F>(&self, f: F) -> ::Res<()>
     where
         F: FnOnce(&mut Core, &Poll) + Send + 'static,
     {
+        self.check_running()?;
         self.el.send(CoreMessage::new(f))?;
         Ok(())
     }
+
+    /// Fails with `CrustError::ShuttingDown` once the event receiver has been dropped, from when
+    /// the event loop drains and exits.
+    fn check_running(&self) -> ::Res<()> {
+        if self.event_tx.is_closed() {
+            Err(CrustError::ShuttingDown)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The listeners of the peers relaying for us, for our connection info, see `Config::use_relays`.
+#[cfg(feature = "relay")]
+fn our_relays<UID: Uid>(core: &Core, cm: &ConnectionMap<UID>, use_relays: bool) -> Vec<SocketAddr> {
+    if use_relays {
+        relays_of_peers(core, cm)
+    } else {
+        Vec::new()
+    }
+}
+
+#[cfg(not(feature = "relay"))]
+fn our_relays<UID: Uid>(_core: &Core, _cm: &ConnectionMap<UID>, _: bool) -> Vec<SocketAddr> {
+    Vec::new()
+}
+
+/// Stops the listeners, withdrawing their addresses from the peers connected to us, so that they
+/// can be started again on other ports.
+fn stop_listeners<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    additional_listeners: &Mutex<Vec<Token>>,
+) {
+    let mut tokens = vec![LISTENER_TOKEN];
+    tokens.extend(mem::replace(&mut *unwrap!(additional_listeners.lock()), Vec::new()));
+    for token in tokens {
+        let state = match core.get_state(token) {
+            Some(state) => state,
+            None => continue,
+        };
+        let mut state = state.borrow_mut();
+        match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
+            Some(listener) => listener.stop(core, poll),
+            None => warn!("Token reserved for ConnectionListener has something else."),
+        }
+    }
+}
+
+/// Fails sends of the application at the priority kept for our control messages.
+fn check_user_priority(priority: Priority) -> ::Res<()> {
+    if priority == CONTROL_PRIORITY {
+        Err(CrustError::ReservedPriority(priority))
+    } else {
+        Ok(())
+    }
+}
+
+/// Says goodbye to each of the given peers which has an active connection and returns those.
+fn disconnect_peers<UID: Uid>(
+    core: &mut Core,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    peers: Vec<UID>,
+    reason: u32,
+) -> Vec<UID> {
+    // Tokens collected to avoid keeping the mutex lock alive which might lead to deadlock
+    let tokens: Vec<_> = {
+        let guard = unwrap!(cm.lock());
+        peers
+            .into_iter()
+            .filter_map(|peer| {
+                guard
+                    .get(&peer)
+                    .and_then(|cid| cid.active_connection)
+                    .map(|token| (peer, token))
+            })
+            .collect()
+    };
+
+    let mut disconnected = Vec::with_capacity(tokens.len());
+    for (peer, token) in tokens {
+        if !disconnected.contains(&peer) && disconnect_token::<UID>(core, poll, token, reason) {
+            disconnected.push(peer);
+        }
+    }
+    disconnected
 }
 
-/// Returns a hash of the network name.
-fn name_hash(network_name: &Option<String>) -> NameHash {
-    trace!("Network name: {:?}", network_name);
-    match *network_name {
-        Some(ref name) => sha3_256(name.as_bytes()),
-        None => [0; HASH_SIZE],
+/// Says goodbye on the active connection with the given token, returning whether there was one.
+fn disconnect_token<UID: Uid>(core: &mut Core, poll: &Poll, token: Token, reason: u32) -> bool {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return false,
+    };
+    let mut state = state.borrow_mut();
+    match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+        Some(active_connection) => {
+            active_connection.disconnect(core, poll, reason);
+            true
+        }
+        None => false,
     }
 }
 
+/// Returns the nodes we have an active connection to, with their addresses.
+fn node_contacts<UID: Uid>(core: &mut Core, cm: &ConnectionMap<UID>) -> Vec<PeerContact<UID>> {
+    // Tokens collected to avoid keeping the mutex lock alive which might lead to deadlock
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    tokens
+        .into_iter()
+        .filter_map(|token| {
+            let state = core.get_state(token)?;
+            let mut state = state.borrow_mut();
+            let contact = state
+                .as_any()
+                .downcast_mut::<ActiveConnection<UID>>()?
+                .node_contact();
+            contact
+        })
+        .collect()
+}
+
+fn connected_peers<UID: Uid>(core: &mut Core, cm: &ConnectionMap<UID>) -> Vec<ConnectedPeer<UID>> {
+    // Tokens collected to avoid keeping the mutex lock alive which might lead to deadlock
+    let peers: Vec<_> = unwrap!(cm.lock())
+        .iter()
+        .filter_map(|(id, cid)| cid.active_connection.map(|token| (*id, token)))
+        .collect();
+
+    peers
+        .into_iter()
+        .filter_map(|(id, token)| {
+            let state = core.get_state(token)?;
+            let mut state = state.borrow_mut();
+            let peer = state
+                .as_any()
+                .downcast_mut::<ActiveConnection<UID>>()
+                .map(|ac| ConnectedPeer {
+                    id,
+                    kind: ac.peer_kind(),
+                    stats: ac.stats(core.now()),
+                });
+            peer
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use common::CrustUser;
     use maidsafe_utilities;
     use maidsafe_utilities::thread::Joiner;
-    use main::{self, Event};
+    use main::{
+        self, now_secs, CandidateAddr, ConnectionInfoSource, ConnectionInfoTextError, Event,
+    };
     use rand;
     use std::collections::{hash_map, HashMap};
+    use std::net::TcpListener;
     use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
     use std::sync::mpsc::Receiver;
-    use std::sync::{mpsc, Arc, Barrier};
+    use std::sync::{mpsc, Arc, Barrier, Mutex};
     use std::thread;
     use std::time::Duration;
-    use tests::{get_event_sender, timebomb, UniqueId};
+    use tests::{gen_config, get_event_sender, timebomb, UniqueId};
     use CrustError;
 
     type Service = super::Service<UniqueId>;
@@ -610,6 +2307,148 @@ mod tests {
         })
     }
 
+    #[test]
+    fn connect_with_expired_info() {
+        timebomb(Duration::from_secs(30), || {
+            let mut config = gen_config();
+            config.connection_info_ttl_secs = Some(42);
+
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(
+                event_tx_0,
+                config.clone(),
+                rand::random()
+            ));
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::with_config(event_tx_1, config, rand::random()));
+
+            let our_ci = prepare_connection_info(&mut service_0, &event_rx_0);
+            let their_ci = prepare_connection_info(&mut service_1, &event_rx_1);
+            assert_eq!(their_ci.ttl_secs, 42);
+            assert!(their_ci.issued_at + 5 >= now_secs());
+
+            let mut their_ci = their_ci.to_pub_connection_info();
+            assert!(!their_ci.is_expired());
+            their_ci.issued_at = Some(now_secs() - 3600);
+
+            match service_0.connect(our_ci, their_ci) {
+                Err(CrustError::ConnectionInfoExpired) => (),
+                res => panic!("Expected CrustError::ConnectionInfoExpired, got {:?}", res),
+            }
+        })
+    }
+
+    #[test]
+    fn lan_only_refuses_public_candidates() {
+        timebomb(Duration::from_secs(30), || {
+            let mut config = gen_config();
+            config.lan_only = true;
+
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(
+                event_tx_0,
+                config.clone(),
+                rand::random()
+            ));
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::with_config(event_tx_1, config, rand::random()));
+
+            let our_ci = prepare_connection_info(&mut service_0, &event_rx_0);
+            let mut their_ci =
+                prepare_connection_info(&mut service_1, &event_rx_1).to_pub_connection_info();
+            let public_addr = unwrap!("8.8.8.8:5483".parse());
+            their_ci.candidates.push(CandidateAddr::TcpDirect(public_addr));
+
+            match service_0.connect(our_ci, their_ci) {
+                Err(CrustError::LanOnlyViolation(addr)) => assert_eq!(addr, public_addr),
+                res => panic!("Expected CrustError::LanOnlyViolation, got {:?}", res),
+            }
+        })
+    }
+
+    #[test]
+    fn lan_only_config_with_public_contact_is_refused() {
+        let mut config = gen_config();
+        config.lan_only = true;
+        config.hard_coded_contacts = vec![unwrap!("192.168.0.1:5483".parse())];
+        assert!(config.validate().is_ok());
+
+        config
+            .hard_coded_contacts
+            .push(unwrap!("8.8.8.8:5483".parse()));
+        let (event_tx, _event_rx) = get_event_sender();
+        match Service::with_config(event_tx, config, rand::random()) {
+            Err(CrustError::LanOnlyViolation(addr)) => assert_eq!(addr.port(), 5483),
+            Err(e) => panic!("Expected CrustError::LanOnlyViolation, got {:?}", e),
+            Ok(_) => panic!("Expected CrustError::LanOnlyViolation"),
+        }
+    }
+
+    #[test]
+    fn pending_connections_are_reported_until_done() {
+        use common::{ConnectionDirection, HandshakeStage, PendingConnInfo};
+        use std::net::TcpStream;
+
+        fn wait_for_pending<F>(service: &Service, pred: F) -> Vec<PendingConnInfo>
+        where
+            F: Fn(&[PendingConnInfo]) -> bool,
+        {
+            loop {
+                let pending = unwrap!(service.pending_connections());
+                if pred(&pending) {
+                    return pending;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::with_config(event_tx, gen_config(), rand::random()));
+            unwrap!(service.start_listening_tcp());
+            let port = expect_event!(event_rx, Event::ListenerStarted(port) => port);
+
+            // A peer whose listener never accepts: the TCP connection is established, but our
+            // handshake is never answered.
+            let deaf_listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+            let deaf_addr = unwrap!(deaf_listener.local_addr());
+            let their_id = rand::random();
+            let their_ci = PubConnectionInfo {
+                id: their_id,
+                candidates: vec![CandidateAddr::TcpDirect(deaf_addr)],
+                issued_at: None,
+                ttl_secs: None,
+            };
+            let our_ci = prepare_connection_info(&mut service, &event_rx);
+            unwrap!(service.connect(our_ci, their_ci));
+
+            let pending = wait_for_pending(&service, |pending| {
+                pending.len() == 1 && pending[0].stage == HandshakeStage::HandshakeSent
+            });
+            assert_eq!(pending[0].addr, deaf_addr);
+            assert_eq!(pending[0].direction, ConnectionDirection::Outbound);
+
+            // And one which connects to us, but never sends its handshake.
+            let silent_stream = unwrap!(TcpStream::connect(("127.0.0.1", port)));
+            let pending = wait_for_pending(&service, |pending| pending.len() == 2);
+            assert_eq!(pending[1].addr, unwrap!(silent_stream.local_addr()));
+            assert_eq!(pending[1].direction, ConnectionDirection::Inbound);
+            assert_eq!(pending[1].stage, HandshakeStage::AwaitingHandshake);
+
+            thread::sleep(Duration::from_millis(100));
+            let later = unwrap!(service.pending_connections());
+            assert_eq!(later.len(), 2);
+            assert!(later[0].elapsed >= pending[0].elapsed + Duration::from_millis(100));
+            assert!(later[1].elapsed >= pending[1].elapsed + Duration::from_millis(100));
+
+            // Both handshakes fail once the peers go away.
+            drop(deaf_listener);
+            expect_event!(event_rx, Event::ConnectFailure(id) => assert_eq!(id, their_id));
+            drop(silent_stream);
+            let _ = wait_for_pending(&service, |pending| pending.is_empty());
+        })
+    }
+
     #[test]
     fn direct_connect_two_peers() {
         timebomb(Duration::from_secs(30), || {
@@ -630,6 +2469,420 @@ mod tests {
         })
     }
 
+    #[test]
+    fn direct_connect_on_private_network() {
+        timebomb(Duration::from_secs(30), || {
+            let config = || {
+                let mut config = gen_config();
+                config.network_name = Some("private network".to_owned());
+                config.network_secret = Some("secret".to_owned());
+                config
+            };
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(event_tx_0, config(), rand::random()));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::with_config(event_tx_1, config(), rand::random()));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+        })
+    }
+
+    #[test]
+    fn sends_from_many_threads_arrive_in_order() {
+        const THREADS: u8 = 4;
+        const SENDS_PER_THREAD: u32 = 1000;
+
+        timebomb(Duration::from_secs(60), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0, rand::random()));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1, rand::random()));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            let id_1 = service_1.id();
+
+            // The messages in the order their `send` returned, which is recorded while the
+            // service is still locked by the sender.
+            let returned = Arc::new(Mutex::new(Vec::new()));
+            let service_0 = Arc::new(Mutex::new(service_0));
+            let start = Arc::new(Barrier::new(THREADS as usize));
+            let senders: Vec<_> = (0..THREADS)
+                .map(|thread_index| {
+                    let returned = returned.clone();
+                    let service_0 = service_0.clone();
+                    let start = start.clone();
+                    thread::spawn(move || {
+                        let _ = start.wait();
+                        for i in 0..SENDS_PER_THREAD {
+                            let msg = vec![thread_index, (i >> 8) as u8, i as u8];
+                            let service_0 = unwrap!(service_0.lock());
+                            unwrap!(service_0.send(&id_1, msg.clone(), 1));
+                            unwrap!(returned.lock()).push(msg);
+                        }
+                    })
+                })
+                .collect();
+            for sender in senders {
+                unwrap!(sender.join());
+            }
+
+            let sent = THREADS as usize * SENDS_PER_THREAD as usize;
+            let mut received = Vec::with_capacity(sent);
+            while received.len() < sent {
+                match unwrap!(event_rx_1.recv_timeout(Duration::from_secs(30))) {
+                    Event::NewMessage(_, _, msg, _) => received.push(msg),
+                    Event::NewMessages(_, _, msgs, _) => received.extend(msgs),
+                    event => panic!("Unexpected event: {:?}", event),
+                }
+            }
+            assert!(*unwrap!(returned.lock()) == received);
+        })
+    }
+
+    #[test]
+    fn direct_connect_with_text_connection_info() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0, rand::random()));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1, rand::random()));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            let mut priv_infos = Vec::new();
+            for &(service, event_rx) in &[(&service_0, &event_rx_0), (&service_1, &event_rx_1)] {
+                service.prepare_connection_info(0);
+                let result = expect_event!(event_rx, Event::ConnectionInfoPrepared(res) => res);
+                priv_infos.push(unwrap!(result.result));
+            }
+            let priv_info_1 = unwrap!(priv_infos.pop());
+            let priv_info_0 = unwrap!(priv_infos.pop());
+            let words_0 = unwrap!(priv_info_0.to_pub_connection_info().to_words());
+            let base64_1 = unwrap!(priv_info_1.to_pub_connection_info().to_base64());
+
+            // A misheard word is caught before connecting.
+            service_1.prepare_connection_info(1);
+            let result = expect_event!(event_rx_1, Event::ConnectionInfoPrepared(res) => res);
+            let misheard = words_0.replacen(' ', " xyzzy ", 1);
+            let res = service_1.connect(
+                unwrap!(result.result),
+                ConnectionInfoSource::Words(misheard),
+            );
+            match res {
+                Err(CrustError::ConnectionInfoText(error)) => assert_eq!(
+                    error,
+                    ConnectionInfoTextError::UnknownWord(2, "xyzzy".to_string())
+                ),
+                Ok(()) | Err(..) => panic!("Expected the misheard word to be reported"),
+            }
+
+            unwrap!(service_0.connect(priv_info_0, ConnectionInfoSource::Base64(base64_1)));
+            unwrap!(service_1.connect(priv_info_1, ConnectionInfoSource::Words(words_0)));
+            expect_event!(event_rx_0, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_1.id());
+            });
+            expect_event!(event_rx_1, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_0.id());
+            });
+        })
+    }
+
+    #[test]
+    fn connect_via_additional_acceptor_port() {
+        timebomb(Duration::from_secs(30), || {
+            // Ports which nothing listens on, once the listeners are dropped.
+            let free_ports: Vec<u16> = (0..3)
+                .map(|_| unwrap!(TcpListener::bind("127.0.0.1:0")))
+                .collect::<Vec<_>>()
+                .iter()
+                .map(|listener| unwrap!(listener.local_addr()).port())
+                .collect();
+            let blocked_port = free_ports[0];
+            let additional_ports = free_ports[1..].to_vec();
+
+            let mut config = gen_config();
+            config.additional_acceptor_ports = additional_ports.clone();
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::with_config(event_tx_1, config, rand::random()));
+            unwrap!(service_1.start_listening_tcp());
+            let primary_port = expect_event!(event_rx_1, Event::ListenerStarted(port) => port);
+
+            // The start is reported once the additional listeners are up as well.
+            let their_ci = prepare_connection_info(&mut service_1, &event_rx_1);
+            for port in additional_ports.iter().chain(Some(&primary_port)) {
+                assert!(their_ci.for_direct.iter().any(|addr| addr.port() == *port));
+            }
+
+            // A firewall lets nothing through to the primary port.
+            let mut their_ci = their_ci.to_pub_connection_info();
+            for candidate in &mut their_ci.candidates {
+                if let CandidateAddr::TcpDirect(ref mut addr) = *candidate {
+                    if addr.port() == primary_port {
+                        addr.set_port(blocked_port);
+                    }
+                }
+            }
+
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(
+                event_tx_0,
+                gen_config(),
+                rand::random()
+            ));
+            let our_ci = prepare_connection_info(&mut service_0, &event_rx_0);
+            unwrap!(service_0.connect(our_ci, their_ci));
+
+            expect_event!(event_rx_0, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_1.id());
+            });
+            expect_event!(event_rx_1, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_0.id());
+            });
+            let peer_port = unwrap!(service_0.peer_addr(&service_1.id())).port();
+            assert!(additional_ports.contains(&peer_port));
+
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+        })
+    }
+
+    #[test]
+    fn stop_one_of_two_listeners() {
+        use std::net::{SocketAddr, TcpStream};
+
+        timebomb(Duration::from_secs(30), || {
+            let additional_port =
+                unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr()).port();
+            let mut config = gen_config();
+            config.additional_acceptor_ports = vec![additional_port];
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::with_config(event_tx_1, config, rand::random()));
+            unwrap!(service_1.start_listening_tcp());
+            let primary_port = expect_event!(event_rx_1, Event::ListenerStarted(port) => port);
+
+            let mut their_ci = prepare_connection_info(&mut service_1, &event_rx_1);
+            while !their_ci
+                .for_direct
+                .iter()
+                .any(|addr| addr.port() == additional_port)
+            {
+                thread::sleep(Duration::from_millis(50));
+                their_ci = prepare_connection_info(&mut service_1, &event_rx_1);
+            }
+
+            // A peer connects through the listener which is about to be stopped.
+            let mut their_ci = their_ci.to_pub_connection_info();
+            their_ci.candidates.retain(|candidate| match *candidate {
+                CandidateAddr::TcpDirect(addr) => addr.port() == additional_port,
+                _ => false,
+            });
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(
+                event_tx_0,
+                gen_config(),
+                rand::random()
+            ));
+            let our_ci = prepare_connection_info(&mut service_0, &event_rx_0);
+            unwrap!(service_0.connect(our_ci, their_ci));
+            expect_event!(event_rx_0, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_1.id());
+            });
+            expect_event!(event_rx_1, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_0.id());
+            });
+
+            let additional_addr = SocketAddr::new(unwrap!("127.0.0.1".parse()), additional_port);
+            unwrap!(service_1.stop_listener(additional_addr));
+            expect_event!(event_rx_1, Event::ListenerStopped(addr) => {
+                assert_eq!(addr, additional_addr)
+            });
+            match service_1.stop_listener(additional_addr) {
+                Err(CrustError::ListenerNotFound(addr)) => assert_eq!(addr, additional_addr),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+
+            // New dials to it are refused, the other listener keeps accepting.
+            assert!(TcpStream::connect(additional_addr).is_err());
+            let primary_addr = SocketAddr::new(unwrap!("127.0.0.1".parse()), primary_port);
+            let _ = unwrap!(TcpStream::connect(primary_addr));
+
+            let our_listeners = prepare_connection_info(&mut service_1, &event_rx_1).for_direct;
+            assert!(our_listeners.iter().all(|addr| addr.port() != additional_port));
+            assert!(our_listeners.iter().any(|addr| addr.port() == primary_port));
+
+            // The connection it accepted carries on, and learns of the change.
+            expect_event!(event_rx_0, Event::PeerContactInfoUpdated(id) => {
+                assert_eq!(id, service_1.id())
+            });
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+        })
+    }
+
+    #[test]
+    fn external_endpoints_are_asserted_in_our_connection_info() {
+        use main::InterfaceLister;
+        use std::io;
+        use std::net::{IpAddr, SocketAddr};
+        use std::sync::Mutex;
+
+        struct MockLister(Arc<Mutex<Vec<IpAddr>>>);
+
+        impl InterfaceLister for MockLister {
+            fn local_ips(&mut self) -> io::Result<Vec<IpAddr>> {
+                Ok(unwrap!(self.0.lock()).clone())
+            }
+        }
+
+        timebomb(Duration::from_secs(30), || {
+            let forwarded: SocketAddr = unwrap!("203.0.113.7:40000".parse());
+            let balancer: SocketAddr = unwrap!("198.51.100.2:443".parse());
+            let mut config = gen_config();
+            config.external_endpoints = vec![forwarded];
+            config.interface_scan_interval_sec = Some(1);
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+            let ips = Arc::new(Mutex::new(vec![unwrap!("127.0.0.1".parse())]));
+            unwrap!(service.set_interface_lister(Box::new(MockLister(ips.clone()))));
+            unwrap!(service.start_listening_tcp());
+            let port = expect_event!(event_rx, Event::ListenerStarted(port) => port);
+
+            let our_ci = prepare_connection_info(&mut service, &event_rx);
+            assert_eq!(our_ci.for_asserted, vec![forwarded]);
+            assert!(!our_ci.for_direct.contains(&forwarded));
+            assert_eq!(
+                our_ci.to_pub_connection_info().candidates()[0],
+                CandidateAddr::TcpAsserted(forwarded)
+            );
+
+            // A new interface is advertised with the port we listen on, and doesn't displace the
+            // asserted endpoint.
+            let new_ip: IpAddr = unwrap!("10.9.9.9".parse());
+            unwrap!(ips.lock()).push(new_ip);
+            expect_event!(event_rx, Event::NetworkInterfacesChanged { .. });
+            let our_ci = prepare_connection_info(&mut service, &event_rx);
+            assert_eq!(our_ci.for_asserted, vec![forwarded]);
+            assert!(our_ci.for_direct.contains(&SocketAddr::new(new_ip, port)));
+            assert!(!our_ci
+                .for_direct
+                .contains(&SocketAddr::new(new_ip, forwarded.port())));
+
+            unwrap!(service.add_external_endpoint(balancer));
+            let our_ci = prepare_connection_info(&mut service, &event_rx);
+            assert_eq!(our_ci.for_asserted, vec![forwarded, balancer]);
+
+            unwrap!(service.remove_external_endpoint(forwarded));
+            let our_ci = prepare_connection_info(&mut service, &event_rx);
+            assert_eq!(our_ci.for_asserted, vec![balancer]);
+            assert!(!our_ci.for_direct.contains(&forwarded));
+        })
+    }
+
+    #[test]
+    fn core_stats_split_by_state_kind() {
+        const MSGS: usize = 100;
+
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::with_config(
+                event_tx_1,
+                gen_config(),
+                rand::random()
+            ));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_port));
+            let their_ci = prepare_connection_info(&mut service_1, &event_rx_1);
+
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(
+                event_tx_0,
+                gen_config(),
+                rand::random()
+            ));
+            let our_ci = prepare_connection_info(&mut service_0, &event_rx_0);
+            unwrap!(service_0.connect(our_ci, their_ci.to_pub_connection_info()));
+            expect_event!(event_rx_0, Event::ConnectSuccess(_id, _));
+            expect_event!(event_rx_1, Event::ConnectSuccess(_id, _));
+
+            for _ in 0..MSGS {
+                unwrap!(service_0.send(&service_1.id(), vec![1; 32], 1));
+                expect_event!(
+                    event_rx_1,
+                    Event::NewMessage(_id, CrustUser::Node, _data, _at)
+                );
+            }
+
+            let stats = unwrap!(service_1.core_stats());
+            let active = &stats.states["ActiveConnection"];
+            assert_eq!(active.live, 1);
+            assert!(active.dispatches >= MSGS as u64 / 2);
+            for (name, kind) in &stats.states {
+                if *name != "ActiveConnection" {
+                    assert!(kind.dispatches < active.dispatches, "{}: {:?}", name, kind);
+                }
+            }
+            let listener = &stats.states["ConnectionListener"];
+            assert_eq!(listener.live, 1);
+            assert!(listener.dispatches > 0);
+            assert!(stats.connections_accepted > 0);
+        })
+    }
+
+    #[test]
+    #[cfg(feature = "service-discovery")]
+    fn service_discovery_rebuilds_failed_socket() {
+        use super::SERVICE_DISCOVERY_TOKEN;
+        use service_discovery::ServiceDiscovery;
+        use std::net::UdpSocket;
+
+        timebomb(Duration::from_secs(30), || {
+            let port = unwrap!(unwrap!(UdpSocket::bind("0.0.0.0:0")).local_addr()).port();
+            let mut config = gen_config();
+            config.service_discovery_port = Some(port);
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+            service.start_service_discovery();
+
+            unwrap!(service.post(|core, poll| {
+                let state = unwrap!(core.get_state(SERVICE_DISCOVERY_TOKEN));
+                let mut state = state.borrow_mut();
+                let sd = unwrap!(state.as_any().downcast_mut::<ServiceDiscovery>());
+                sd.fail_socket(core, poll);
+            }));
+            expect_event!(event_rx, Event::ServiceDiscoveryDegraded);
+
+            // Something else takes the port meanwhile, so the socket can't be rebuilt yet.
+            let squatter = unwrap!(UdpSocket::bind(("0.0.0.0", port)));
+            unwrap!(service.start_listening_tcp());
+            expect_event!(event_rx, Event::ListenerStarted(_));
+            assert!(!service.has_peers_on_lan());
+            assert!(event_rx.try_recv().is_err());
+
+            drop(squatter);
+            expect_event!(event_rx, Event::ServiceDiscoveryRecovered);
+            let (tx, rx) = mpsc::channel();
+            unwrap!(service.post(move |core, poll| {
+                let state = unwrap!(core.get_state(SERVICE_DISCOVERY_TOKEN));
+                let mut state = state.borrow_mut();
+                let sd = unwrap!(state.as_any().downcast_mut::<ServiceDiscovery>());
+                let _ = tx.send(sd.seek_peers(core, poll).is_ok());
+            }));
+            assert!(unwrap!(rx.recv_timeout(Duration::from_secs(5))));
+        })
+    }
+
     #[test]
     #[ignore]
     fn rendezvous_connect_two_peers() {
@@ -670,8 +2923,8 @@ mod tests {
         unwrap!(service_0.connect(priv_info_0, pub_info_1));
         unwrap!(service_1.connect(priv_info_1, pub_info_0));
 
-        expect_event!(event_rx_0, Event::ConnectSuccess(id) => assert_eq!(id, service_1.id()));
-        expect_event!(event_rx_1, Event::ConnectSuccess(id) => assert_eq!(id, service_0.id()));
+        expect_event!(event_rx_0, Event::ConnectSuccess(id, _) => assert_eq!(id, service_1.id()));
+        expect_event!(event_rx_1, Event::ConnectSuccess(id, _) => assert_eq!(id, service_0.id()));
     }
 
     fn exchange_messages(
@@ -691,15 +2944,15 @@ mod tests {
         let data_1: Vec<u8> = iter::repeat(()).take(32).map(|()| rand::random()).collect();
         let send_1 = data_1.clone();
 
-        unwrap!(service_0.send(&id_1, data_0, 0));
-        unwrap!(service_1.send(&id_0, data_1, 0));
+        unwrap!(service_0.send(&id_1, data_0, 1));
+        unwrap!(service_1.send(&id_0, data_1, 1));
 
-        let recv_1 = expect_event!(event_rx_0, Event::NewMessage(id, CrustUser::Node, recv) => {
+        let recv_1 = expect_event!(event_rx_0, Event::NewMessage(id, CrustUser::Node, recv, _) => {
             assert_eq!(id, id_1);
             recv
         });
 
-        let recv_0 = expect_event!(event_rx_1, Event::NewMessage(id, CrustUser::Node, recv) => {
+        let recv_0 = expect_event!(event_rx_1, Event::NewMessage(id, CrustUser::Node, recv, _) => {
             assert_eq!(id, id_0);
             recv
         });
@@ -726,6 +2979,88 @@ mod tests {
         }
     }
 
+    #[test]
+    fn diagnostics_find_what_is_broken() {
+        use main::{DiagnosticCheck, DiagnosticStatus};
+        use serde_json;
+        use std::env;
+        use std::fs::{self, File};
+        use std::io::Write;
+
+        timebomb(Duration::from_secs(30), || {
+            // Someone else holds the acceptor port, and nobody listens on the contact's.
+            let squatter = unwrap!(TcpListener::bind("0.0.0.0:0"));
+            let contact = unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr());
+            let mut config = gen_config();
+            config.tcp_acceptor_port = Some(unwrap!(squatter.local_addr()).port());
+            config.hard_coded_contacts = vec![contact];
+
+            let dir = env::temp_dir().join(format!("crust-diagnostics-{}", rand::random::<u64>()));
+            unwrap!(fs::create_dir_all(&dir));
+            let path = dir.join("test.crust.config");
+            unwrap!(unwrap!(File::create(&path)).write_all(&unwrap!(serde_json::to_vec(&config))));
+            let (event_tx, _event_rx) = get_event_sender();
+            let service = unwrap!(Service::with_config_path(
+                event_tx,
+                path.clone(),
+                rand::random()
+            ));
+
+            let report = service.run_diagnostics();
+            assert_eq!(report.results.len(), 6);
+            let status = |check| unwrap!(report.result(check)).status;
+            assert_eq!(status(DiagnosticCheck::Config), DiagnosticStatus::Pass);
+            assert_eq!(status(DiagnosticCheck::AcceptorPort), DiagnosticStatus::Fail);
+            assert_eq!(status(DiagnosticCheck::HardCodedContacts), DiagnosticStatus::Fail);
+            assert!(!report.passed());
+
+            // The file has been broken since the service started.
+            unwrap!(unwrap!(File::create(&path)).write_all(b"{ not json"));
+            let report = service.run_diagnostics();
+            let result = unwrap!(report.result(DiagnosticCheck::Config));
+            assert_eq!(result.status, DiagnosticStatus::Fail);
+            assert!(result.detail.is_some());
+            unwrap!(fs::remove_dir_all(&dir));
+        })
+    }
+
+    #[test]
+    fn diagnostics_skip_what_does_not_apply() {
+        use main::{DiagnosticCheck, DiagnosticStatus};
+
+        timebomb(Duration::from_secs(30), || {
+            let mut config = gen_config();
+            config.lan_only = true;
+            let (event_tx, _event_rx) = get_event_sender();
+            let service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+
+            let report = service.run_diagnostics();
+            let status = |check| unwrap!(report.result(check)).status;
+            assert_eq!(status(DiagnosticCheck::Config), DiagnosticStatus::Pass);
+            assert_eq!(status(DiagnosticCheck::AcceptorPort), DiagnosticStatus::Skip);
+            assert_eq!(status(DiagnosticCheck::HardCodedContacts), DiagnosticStatus::Skip);
+            assert_eq!(status(DiagnosticCheck::IgdGateway), DiagnosticStatus::Skip);
+            assert_eq!(status(DiagnosticCheck::ExternalAddresses), DiagnosticStatus::Skip);
+        })
+    }
+
+    #[cfg(feature = "service-discovery")]
+    #[test]
+    fn diagnostics_find_the_discovery_port_taken() {
+        use main::{DiagnosticCheck, DiagnosticStatus};
+        use std::net::UdpSocket;
+
+        let squatter = unwrap!(UdpSocket::bind("0.0.0.0:0"));
+        let mut config = gen_config();
+        config.service_discovery_port = Some(unwrap!(squatter.local_addr()).port());
+        let (event_tx, _event_rx) = get_event_sender();
+        let service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+
+        let report = service.run_diagnostics();
+        let result = unwrap!(report.result(DiagnosticCheck::DiscoveryPort));
+        assert_eq!(result.status, DiagnosticStatus::Fail);
+    }
+
     #[test]
     #[ignore]
     fn sending_receiving_multiple_services() {
@@ -791,7 +3126,7 @@ mod tests {
                     let mut their_ids = HashMap::new();
                     for _ in 0..NUM_SERVICES - 1 {
                         let their_id = match unwrap!(self.event_rx.recv()) {
-                            Event::ConnectSuccess(their_id) => their_id,
+                            Event::ConnectSuccess(their_id, _) => their_id,
                             m => panic!("Expected ConnectSuccess message. Got message {:?}", m),
                         };
                         if their_ids.insert(their_id, 0u32).is_some() {
@@ -809,13 +3144,13 @@ mod tests {
                             for _ in 0..MSG_SIZE {
                                 msg.push(n as u8);
                             }
-                            let _ = self.service.send(their_id, msg, 0);
+                            let _ = self.service.send(their_id, msg, 1);
                         }
                     }
 
                     for _ in 0..((NUM_SERVICES - 1) * NUM_MSGS) {
                         match unwrap!(self.event_rx.recv()) {
-                            Event::NewMessage(their_id, CrustUser::Node, msg) => {
+                            Event::NewMessage(their_id, CrustUser::Node, msg, _) => {
                                 let n = msg[0];
                                 assert_eq!(msg.len(), MSG_SIZE);
                                 for m in msg {
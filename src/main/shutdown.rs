@@ -0,0 +1,122 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Core, CoreTimer, State, Uid};
+use main::{ActiveConnection, ConnectionMap};
+use mio::{Poll, Token};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+/// How often the connections are checked on.
+const CHECK_INTERVAL_MS: u64 = 50;
+
+/// Waits out `Service::shutdown`: the connections it said goodbye on close once everything queued
+/// for them has been written, counting themselves as drained. Once none is left, or the timeout is
+/// over, the count is passed on to the service.
+pub struct Shutdown {
+    token: Token,
+    connections: Vec<Token>,
+    drained: Rc<Cell<usize>>,
+    deadline: Instant,
+    done_tx: Sender<usize>,
+}
+
+impl Shutdown {
+    pub fn start<UID: Uid>(
+        core: &mut Core,
+        poll: &Poll,
+        cm: &ConnectionMap<UID>,
+        timeout: Duration,
+        done_tx: Sender<usize>,
+    ) {
+        trace!("Entered state Shutdown");
+
+        // Tokens collected to avoid keeping the mutex lock alive which might lead to deadlock
+        let tokens: Vec<Token> = unwrap!(cm.lock())
+            .values()
+            .filter_map(|id| id.active_connection)
+            .collect();
+        let drained = Rc::new(Cell::new(0));
+        let mut connections = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => continue,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>()
+            {
+                active_connection.shut_down(core, poll, drained.clone());
+                connections.push(token);
+            }
+        }
+
+        let mut state = Shutdown {
+            token: core.get_new_token(),
+            connections,
+            drained,
+            deadline: core.now() + timeout,
+            done_tx,
+        };
+        if state.is_over(core) {
+            return state.finish();
+        }
+        let timer = CoreTimer::new(state.token, 0);
+        if let Err(e) = core.set_timeout(Duration::from_millis(CHECK_INTERVAL_MS), timer) {
+            debug!("Failed to wait for the connections to drain: {:?}", e);
+            return state.finish();
+        }
+        let _ = core.insert_state(state.token, Rc::new(RefCell::new(state)));
+    }
+
+    fn is_over(&mut self, core: &Core) -> bool {
+        self.connections
+            .retain(|token| core.get_state(*token).is_some());
+        self.connections.is_empty() || core.now() >= self.deadline
+    }
+
+    fn finish(&self) {
+        let drained = self.drained.get();
+        debug!(
+            "Drained {} connections, cutting off {}",
+            drained,
+            self.connections.len()
+        );
+        let _ = self.done_tx.send(drained);
+    }
+}
+
+impl State for Shutdown {
+    fn name(&self) -> &'static str {
+        "Shutdown"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        if self.is_over(core) {
+            return self.terminate(core, poll);
+        }
+        let timer = CoreTimer::new(self.token, 0);
+        if let Err(e) = core.set_timeout(Duration::from_millis(CHECK_INTERVAL_MS), timer) {
+            debug!("Failed to wait for the connections to drain: {:?}", e);
+            self.terminate(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        self.finish();
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
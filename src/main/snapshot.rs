@@ -0,0 +1,60 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use main::{now_secs, BootstrapCacheEntry};
+use std::net::SocketAddr;
+
+/// What a `Service` knows about the network, exported by `Service::export_state` so that a
+/// restarted service can pick up where it left off, see `Service::with_snapshot`. It holds no
+/// secrets and can be written to a file with any serde format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceSnapshot<UID> {
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    pub taken_at: u64,
+    /// ID of the service.
+    pub our_uid: UID,
+    /// Our listener addresses, as advertised to peers.
+    pub our_listeners: Vec<SocketAddr>,
+    /// Entries of the bootstrap cache, with their health.
+    pub bootstrap_contacts: Vec<BootstrapCacheEntry>,
+    /// Nodes we were connected to or had parked, with the address they can be reached at.
+    pub peers: Vec<PeerContact<UID>>,
+}
+
+/// A node and the address it can be reached at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerContact<UID> {
+    /// The node's ID.
+    pub id: UID,
+    /// Its listener, or the address we were connected to.
+    pub addr: SocketAddr,
+}
+
+impl<UID> ServiceSnapshot<UID> {
+    /// Returns the age of the snapshot in seconds.
+    pub fn age_secs(&self) -> u64 {
+        now_secs().saturating_sub(self.taken_at)
+    }
+
+    /// Returns the bootstrap cache entries followed by new ones for the peers which aren't cached.
+    /// The latter count as last seen when the snapshot was taken.
+    pub fn contacts(&self) -> Vec<BootstrapCacheEntry> {
+        let mut contacts = self.bootstrap_contacts.clone();
+        for peer in &self.peers {
+            if !contacts.iter().any(|entry| entry.addr == peer.addr) {
+                contacts.push(BootstrapCacheEntry {
+                    addr: peer.addr,
+                    last_seen: self.taken_at,
+                    health: Default::default(),
+                });
+            }
+        }
+        contacts
+    }
+}
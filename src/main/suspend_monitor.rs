@@ -0,0 +1,117 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Core, CoreTimer, State, Uid};
+use main::{probe_after_resume, refresh_mapping_context, ConnectionMap, HEARTBEAT_PERIOD_MS};
+use mio::{Poll, Token};
+use nat::MappingContext;
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token of the `SuspendMonitor` state.
+pub const SUSPEND_MONITOR_TOKEN: Token = Token(7);
+
+/// Notices that the system was suspended, by the time passing between two of its ticks on a clock
+/// which counts the time suspended and on one which doesn't. On resume every connection probes its
+/// peer, as its NAT bindings may have expired meanwhile, and the mapping context is rebuilt.
+///
+/// Only works where the time suspended is known, see `Clock::now_including_suspend`.
+pub struct SuspendMonitor<UID: Uid> {
+    token: Token,
+    timer: CoreTimer,
+    interval: Duration,
+    last_tick: Instant,
+    last_tick_including_suspend: Instant,
+    lan_only: bool,
+    cm: ConnectionMap<UID>,
+    mc: Arc<Mutex<Arc<MappingContext>>>,
+}
+
+impl<UID: Uid> SuspendMonitor<UID> {
+    pub fn start(
+        core: &mut Core,
+        token: Token,
+        lan_only: bool,
+        cm: ConnectionMap<UID>,
+        mc: Arc<Mutex<Arc<MappingContext>>>,
+    ) -> ::Res<()> {
+        trace!("Entered state SuspendMonitor");
+
+        let interval = Duration::from_millis(HEARTBEAT_PERIOD_MS);
+        let timer = CoreTimer::new(token, 0);
+        core.set_periodic_timeout(interval, timer)?;
+
+        let state = Rc::new(RefCell::new(SuspendMonitor {
+            token,
+            timer,
+            interval,
+            last_tick: core.now(),
+            last_tick_including_suspend: core.now_including_suspend(),
+            lan_only,
+            cm,
+            mc,
+        }));
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+
+    /// Returns how long the system was suspended for since the last tick, if long enough to matter:
+    /// longer than the heartbeat interval, which NAT bindings are expected to survive.
+    fn suspended_for(&mut self, core: &Core) -> Option<Duration> {
+        let (now, now_including_suspend) = (core.now(), core.now_including_suspend());
+        let elapsed = now - self.last_tick;
+        let elapsed_including_suspend = now_including_suspend - self.last_tick_including_suspend;
+        self.last_tick = now;
+        self.last_tick_including_suspend = now_including_suspend;
+
+        elapsed_including_suspend
+            .checked_sub(elapsed)
+            .and_then(|suspended| {
+                if suspended > self.interval {
+                    Some(suspended)
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+impl<UID: Uid> State for SuspendMonitor<UID> {
+    fn name(&self) -> &'static str {
+        "SuspendMonitor"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        if let Err(e) = core.set_periodic_timeout(self.interval, self.timer) {
+            debug!("Suspend Monitor Timer Errored out: {:?}", e);
+            return self.terminate(core, poll);
+        }
+
+        if let Some(suspended) = self.suspended_for(core) {
+            info!(
+                "Resumed from a suspend of {:?} - probing connected peers",
+                suspended
+            );
+            probe_after_resume(core, poll, &self.cm);
+            refresh_mapping_context(&self.mc, self.lan_only);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use bincode::{self, Bounded, ErrorKind};
+use byteorder::{ByteOrder, LittleEndian};
+use maidsafe_utilities::serialisation::{
+    deserialise_with_limit, serialise_with_limit, SerialisationError,
+};
+use main::CrustError;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+
+/// Size of the schema tag prepended to every tagged message.
+pub const SCHEMA_TAG_SIZE: usize = 2;
+
+/// Serialises `msg` and prepends `schema_tag`, failing as soon as the result exceeds `size_limit`
+/// bytes.
+pub fn serialise_tagged<T: Serialize>(
+    schema_tag: u16,
+    msg: &T,
+    size_limit: usize,
+) -> ::Res<Vec<u8>> {
+    let body_limit = Bounded(size_limit.saturating_sub(SCHEMA_TAG_SIZE) as u64);
+    let body = match serialise_with_limit(msg, body_limit) {
+        Ok(body) => body,
+        Err(SerialisationError::Serialise(ref e)) if is_size_limit(e) => {
+            let size = SCHEMA_TAG_SIZE + bincode::serialized_size(msg) as usize;
+            return Err(CrustError::MessageTooLarge(size, size_limit));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut bytes = vec![0; SCHEMA_TAG_SIZE];
+    LittleEndian::write_u16(&mut bytes, schema_tag);
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+/// Checks the size and schema tag of a message produced by `serialise_tagged` before
+/// deserialising it, reading no more of it than `size_limit` allows for.
+pub fn deserialise_tagged<T: DeserializeOwned>(
+    schema_tag: u16,
+    bytes: &[u8],
+    size_limit: usize,
+) -> ::Res<T> {
+    if bytes.len() > size_limit {
+        return Err(CrustError::MessageTooLarge(bytes.len(), size_limit));
+    }
+    if bytes.len() < SCHEMA_TAG_SIZE {
+        return Err(CrustError::SchemaTagMismatch(schema_tag, None));
+    }
+
+    let their_tag = LittleEndian::read_u16(&bytes[..SCHEMA_TAG_SIZE]);
+    if their_tag != schema_tag {
+        return Err(CrustError::SchemaTagMismatch(schema_tag, Some(their_tag)));
+    }
+
+    let body_limit = Bounded((size_limit - SCHEMA_TAG_SIZE) as u64);
+    Ok(deserialise_with_limit(&bytes[SCHEMA_TAG_SIZE..], body_limit)?)
+}
+
+fn is_size_limit(e: &bincode::Error) -> bool {
+    match **e {
+        ErrorKind::SizeLimit => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAG: u16 = 0x0102;
+    const LIMIT: usize = 1024;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        id: u64,
+        name: String,
+        data: Vec<u8>,
+    }
+
+    fn sample(data_len: usize) -> Sample {
+        Sample {
+            id: 42,
+            name: "sample".to_owned(),
+            data: vec![7; data_len],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = sample(100);
+        let bytes = unwrap!(serialise_tagged(TAG, &msg, LIMIT));
+        assert_eq!(LittleEndian::read_u16(&bytes), TAG);
+        let decoded: Sample = unwrap!(deserialise_tagged(TAG, &bytes, LIMIT));
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn exceeding_size_limit() {
+        match serialise_tagged(TAG, &sample(LIMIT), LIMIT) {
+            Err(CrustError::MessageTooLarge(size, LIMIT)) => assert!(size > LIMIT),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let bytes = unwrap!(serialise_tagged(TAG, &sample(LIMIT), 2 * LIMIT));
+        match deserialise_tagged::<Sample>(TAG, &bytes, LIMIT) {
+            Err(CrustError::MessageTooLarge(size, LIMIT)) => assert_eq!(size, bytes.len()),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn wrong_schema_tag() {
+        let bytes = unwrap!(serialise_tagged(TAG, &sample(10), LIMIT));
+        match deserialise_tagged::<Sample>(TAG + 1, &bytes, LIMIT) {
+            Err(CrustError::SchemaTagMismatch(expected, Some(actual))) => {
+                assert_eq!(expected, TAG + 1);
+                assert_eq!(actual, TAG);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        match deserialise_tagged::<Sample>(TAG, &bytes[..1], LIMIT) {
+            Err(CrustError::SchemaTagMismatch(TAG, None)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn garbage_with_correct_tag() {
+        let mut bytes = unwrap!(serialise_tagged(TAG, &sample(10), LIMIT));
+        bytes.truncate(SCHEMA_TAG_SIZE + 4);
+        match deserialise_tagged::<Sample>(TAG, &bytes, LIMIT) {
+            Err(CrustError::Serialisation(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // A name claiming to be longer than the limit isn't read, let alone allocated.
+        let mut bytes = unwrap!(serialise_tagged(TAG, &sample(10), LIMIT));
+        LittleEndian::write_u64(&mut bytes[SCHEMA_TAG_SIZE + 8..], u64::max_value());
+        match deserialise_tagged::<Sample>(TAG, &bytes, LIMIT) {
+            Err(CrustError::Serialisation(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -7,11 +7,18 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::Uid;
-use main::Config;
+use common::{CrustUser, Uid};
+use main::{Config, PeerStats};
 use mio::Token;
 use net2::TcpBuilder;
-use std::net::SocketAddr;
+use serde::de::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Allowance for clock differences between peers when checking whether connection info expired.
+const CONNECTION_INFO_EXPIRY_GRACE_SECS: u64 = 60;
 
 // ========================================================================================
 //                                     ConnectionId
@@ -22,6 +29,43 @@ pub struct ConnectionId {
     pub currently_handshaking: usize,
 }
 
+// ========================================================================================
+//                                       Transport
+// ========================================================================================
+/// The transport over which we are connected to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// A direct TCP connection to the peer.
+    Tcp,
+    /// A direct uTP connection to the peer, see `Config::enable_utp`.
+    #[cfg(feature = "utp")]
+    Utp,
+    /// A direct WebSocket connection, see `Config::websocket_port` and
+    /// `Config::websocket_contacts`.
+    #[cfg(feature = "websocket")]
+    WebSocket,
+    /// A TCP connection to a node relaying our frames to the peer and back, see
+    /// `Config::use_relays`. Only with the `relay` feature.
+    #[cfg(feature = "relay")]
+    Relayed,
+}
+
+impl Transport {
+    /// Returns whether traffic to the peer is relayed through another node, in which case the
+    /// peer's address is the address of the relay. Only with the `relay` feature.
+    #[cfg(feature = "relay")]
+    pub fn is_relayed(&self) -> bool {
+        match *self {
+            Transport::Tcp => false,
+            #[cfg(feature = "utp")]
+            Transport::Utp => false,
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket => false,
+            Transport::Relayed => true,
+        }
+    }
+}
+
 // ========================================================================================
 //                                   ConnectionInfoResult
 // ========================================================================================
@@ -43,21 +87,94 @@ pub struct PrivConnectionInfo<UID> {
     #[doc(hidden)]
     pub id: UID,
     #[doc(hidden)]
+    pub for_asserted: Vec<SocketAddr>,
+    #[doc(hidden)]
     pub for_direct: Vec<SocketAddr>,
     #[doc(hidden)]
     pub for_hole_punch: Vec<SocketAddr>,
     #[doc(hidden)]
+    pub for_utp: Vec<SocketAddr>,
+    #[doc(hidden)]
+    pub for_relay: Vec<SocketAddr>,
+    #[doc(hidden)]
     pub hole_punch_socket: Option<TcpBuilder>,
+    #[doc(hidden)]
+    pub issued_at: u64,
+    #[doc(hidden)]
+    pub ttl_secs: u64,
+    /// Local address the direct connections made with this info are bound to. Initialised from
+    /// `Config::outbound_bind_addr`, and can be changed before calling `Service::connect`.
+    pub outbound_bind_addr: Option<IpAddr>,
 }
 
 impl<UID: Uid> PrivConnectionInfo<UID> {
     /// Use private connection info to create public connection info that can be shared with the
     /// peer.
     pub fn to_pub_connection_info(&self) -> PubConnectionInfo<UID> {
+        let candidates = self
+            .for_asserted
+            .iter()
+            .map(|addr| CandidateAddr::TcpAsserted(*addr))
+            .chain(
+                self.for_direct
+                    .iter()
+                    .map(|addr| CandidateAddr::TcpDirect(*addr)),
+            )
+            .chain(
+                self.for_hole_punch
+                    .iter()
+                    .map(|addr| CandidateAddr::TcpMapped(*addr)),
+            )
+            .chain(self.for_utp.iter().map(|addr| CandidateAddr::Utp(*addr)));
+        #[cfg(feature = "relay")]
+        let candidates = candidates.chain(
+            self.for_relay
+                .iter()
+                .map(|addr| CandidateAddr::Relay(*addr)),
+        );
+        let candidates = candidates.collect();
+
         PubConnectionInfo {
-            for_hole_punch: self.for_hole_punch.clone(),
-            for_direct: self.for_direct.clone(),
             id: self.id,
+            candidates,
+            issued_at: Some(self.issued_at),
+            ttl_secs: Some(self.ttl_secs),
+        }
+    }
+}
+
+// ========================================================================================
+//                                     CandidateAddr
+// ========================================================================================
+/// An address at which a peer may be reached, tagged with the transport to use for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CandidateAddr {
+    /// A TCP listener accepting direct connections.
+    TcpDirect(SocketAddr),
+    /// An external TCP endpoint, mapped through a NAT, to be used for hole punching.
+    TcpMapped(SocketAddr),
+    /// A uTP endpoint.
+    Utp(SocketAddr),
+    /// A TCP endpoint from `Config::external_endpoints`, which the peer's operator asserts it
+    /// accepts direct connections at, e.g. through manual port forwarding. Peers built before it
+    /// was added can't decode connection info listing one.
+    TcpAsserted(SocketAddr),
+    /// A relay node through which the peer can be reached. Only with the `relay` feature; peers
+    /// built without it can't decode connection info listing one.
+    #[cfg(feature = "relay")]
+    Relay(SocketAddr),
+}
+
+impl CandidateAddr {
+    /// Returns the address of the candidate.
+    pub fn addr(&self) -> SocketAddr {
+        match *self {
+            CandidateAddr::TcpDirect(addr)
+            | CandidateAddr::TcpMapped(addr)
+            | CandidateAddr::Utp(addr)
+            | CandidateAddr::TcpAsserted(addr) => addr,
+            #[cfg(feature = "relay")]
+            CandidateAddr::Relay(addr) => addr,
         }
     }
 }
@@ -66,14 +183,19 @@ impl<UID: Uid> PrivConnectionInfo<UID> {
 //                                     PubConnectionInfo
 // ========================================================================================
 /// Contact info used to connect to another peer.
-#[derive(Debug, Serialize, Deserialize)]
+///
+/// Connection info serialised by older versions, which only carried untagged direct and hole
+/// punch addresses and no expiry, still deserialises from human readable formats such as JSON.
+#[derive(Debug, Serialize)]
 pub struct PubConnectionInfo<UID> {
     #[doc(hidden)]
     pub id: UID,
     #[doc(hidden)]
-    pub for_hole_punch: Vec<SocketAddr>,
+    pub candidates: Vec<CandidateAddr>,
     #[doc(hidden)]
-    pub for_direct: Vec<SocketAddr>,
+    pub issued_at: Option<u64>,
+    #[doc(hidden)]
+    pub ttl_secs: Option<u64>,
 }
 
 impl<UID: Uid> PubConnectionInfo<UID> {
@@ -81,6 +203,141 @@ impl<UID: Uid> PubConnectionInfo<UID> {
     pub fn id(&self) -> UID {
         self.id
     }
+
+    /// Returns the addresses at which the peer may be reached.
+    pub fn candidates(&self) -> &[CandidateAddr] {
+        &self.candidates
+    }
+
+    /// Returns whether this connection info is clearly too old to be used, allowing for some
+    /// clock difference with the peer which issued it. Info without a TTL, or whose expiry is out
+    /// of range, never expires.
+    pub fn is_expired(&self) -> bool {
+        match (self.issued_at, self.ttl_secs) {
+            (Some(issued_at), Some(ttl_secs)) => issued_at
+                .checked_add(ttl_secs)
+                .and_then(|expiry| expiry.checked_add(CONNECTION_INFO_EXPIRY_GRACE_SECS))
+                .map_or(false, |expiry| now_secs() > expiry),
+            _ => false,
+        }
+    }
+}
+
+impl<'de, UID: Uid> Deserialize<'de> for PubConnectionInfo<UID> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(bound = "UID: Uid")]
+        struct Current<UID> {
+            id: UID,
+            candidates: Vec<CandidateAddr>,
+            issued_at: Option<u64>,
+            ttl_secs: Option<u64>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(bound = "UID: Uid")]
+        struct Legacy<UID> {
+            id: UID,
+            for_hole_punch: Vec<SocketAddr>,
+            for_direct: Vec<SocketAddr>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        #[serde(bound = "UID: Uid")]
+        enum AnyFormat<UID> {
+            Current(Current<UID>),
+            Legacy(Legacy<UID>),
+        }
+
+        // Telling the formats apart needs a self-describing format; compact binary formats can
+        // only carry the current one.
+        let current = if deserializer.is_human_readable() {
+            match AnyFormat::<UID>::deserialize(deserializer)? {
+                AnyFormat::Current(current) => current,
+                AnyFormat::Legacy(legacy) => Current {
+                    id: legacy.id,
+                    candidates: legacy
+                        .for_direct
+                        .into_iter()
+                        .map(CandidateAddr::TcpDirect)
+                        .chain(
+                            legacy
+                                .for_hole_punch
+                                .into_iter()
+                                .map(CandidateAddr::TcpMapped),
+                        )
+                        .collect(),
+                    issued_at: None,
+                    ttl_secs: None,
+                },
+            }
+        } else {
+            Current::<UID>::deserialize(deserializer)?
+        };
+
+        Ok(PubConnectionInfo {
+            id: current.id,
+            candidates: current.candidates,
+            issued_at: current.issued_at,
+            ttl_secs: current.ttl_secs,
+        })
+    }
+}
+
+/// Current time in seconds since the Unix epoch.
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// ========================================================================================
+//                                     ConnectedPeer
+// ========================================================================================
+/// A peer we have an active connection to, see `Service::connected_peers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectedPeer<UID> {
+    /// The id the peer handshaked with.
+    pub id: UID,
+    /// Whether the peer is a node or a client. Clients promoted while connected are nodes.
+    pub kind: CrustUser,
+    /// The traffic of the peer so far, as `Service::peer_stats` would return it.
+    pub stats: PeerStats,
+}
+
+// ========================================================================================
+//                                     NetworkStats
+// ========================================================================================
+/// The stats of the peers we have an active connection to added up, see
+/// `Service::network_stats`. Peers parked or lost don't count.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkStats {
+    /// Number of peers whose stats were added up.
+    pub connected_peers: usize,
+    /// Number of messages sent to all the peers.
+    pub msgs_sent: u64,
+    /// Number of messages received from all the peers.
+    pub msgs_received: u64,
+    /// Number of bytes sent to all the peers, framing included.
+    pub bytes_sent: u64,
+    /// Number of bytes received from all the peers, framing included.
+    pub bytes_received: u64,
+    /// Bytes queued for all the peers which haven't been written yet.
+    pub queued_bytes: u64,
+}
+
+impl NetworkStats {
+    /// Counts one more peer, adding its traffic to the totals.
+    pub(crate) fn add(&mut self, stats: &PeerStats) {
+        self.connected_peers += 1;
+        self.msgs_sent += stats.msgs_sent;
+        self.msgs_received += stats.msgs_received;
+        self.bytes_sent += stats.bytes_sent;
+        self.bytes_received += stats.bytes_received;
+        self.queued_bytes += stats.queued_bytes;
+    }
 }
 
 // ========================================================================================
@@ -90,19 +347,35 @@ impl<UID: Uid> PubConnectionInfo<UID> {
 pub struct ConfigWrapper {
     pub cfg: Config,
     pub is_modified_for_next_refresh: bool,
+    /// Limits on the peers of each kind, kept apart from `cfg` so that those set by
+    /// `Service::set_peer_limits` outlive config file refreshes which don't change them.
+    pub peer_limits: PeerLimits,
+    /// The IPs peers of each kind are taken from, kept apart from `cfg` so that those added by
+    /// `Service::whitelist_ip` outlive config file refreshes which don't change the whitelists.
+    pub whitelists: Whitelists,
+    /// The config file given to `Service::with_config_path`, re-read in place of the default one.
+    pub config_path: Option<PathBuf>,
+    /// The endpoints we advertise as asserted, from `cfg.external_endpoints` as the service was
+    /// constructed and changed by `Service::add_external_endpoint` and `remove_external_endpoint`
+    /// only, so that our listeners and those who rewrite them never disagree about them.
+    pub external_endpoints: Vec<SocketAddr>,
 }
 
 impl ConfigWrapper {
     pub fn new(cfg: Config) -> Self {
         Self {
+            peer_limits: PeerLimits::from_config(&cfg),
+            whitelists: Whitelists::from_config(&cfg),
+            external_endpoints: cfg.external_endpoints.clone(),
             cfg,
             is_modified_for_next_refresh: false,
+            config_path: None,
         }
     }
 
     pub fn check_for_update_and_mark_modified(&mut self, new_cfg: Config) {
         if self.cfg != new_cfg {
-            self.cfg = new_cfg;
+            self.replace_cfg(new_cfg);
             self.is_modified_for_next_refresh = true;
         }
     }
@@ -110,7 +383,7 @@ impl ConfigWrapper {
     /// Checks if `ActiveConnection` refresh is needed.
     pub fn check_for_refresh_and_reset_modified(&mut self, new_cfg: Config) -> bool {
         let should_refresh = if self.cfg != new_cfg {
-            self.cfg = new_cfg;
+            self.replace_cfg(new_cfg);
             true
         } else {
             self.is_modified_for_next_refresh
@@ -119,4 +392,171 @@ impl ConfigWrapper {
         self.is_modified_for_next_refresh = false;
         should_refresh
     }
+
+    fn replace_cfg(&mut self, new_cfg: Config) {
+        let new_limits = PeerLimits::from_config(&new_cfg);
+        if new_limits != PeerLimits::from_config(&self.cfg) {
+            self.peer_limits = new_limits;
+        }
+        let new_whitelists = Whitelists::from_config(&new_cfg);
+        if new_whitelists != Whitelists::from_config(&self.cfg) {
+            self.whitelists = new_whitelists;
+        }
+        self.cfg = new_cfg;
+    }
+}
+
+/// Maximum number of connected peers of each kind, see `Config::max_client_peers` and
+/// `Config::max_node_peers`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerLimits {
+    /// Most clients connected at a time, `None` for no limit.
+    pub max_client_peers: Option<usize>,
+    /// Most nodes connected at a time, `None` for no limit.
+    pub max_node_peers: Option<usize>,
+}
+
+impl PeerLimits {
+    /// The limits set in `cfg`.
+    pub fn from_config(cfg: &Config) -> Self {
+        PeerLimits {
+            max_client_peers: cfg.max_client_peers,
+            max_node_peers: cfg.max_node_peers,
+        }
+    }
+
+    /// The limit on peers of the given kind.
+    pub fn max_peers(&self, kind: CrustUser) -> Option<usize> {
+        match kind {
+            CrustUser::Client => self.max_client_peers,
+            CrustUser::Node => self.max_node_peers,
+        }
+    }
+}
+
+/// The IPs peers of each kind may connect to us from, or we to them, see
+/// `Config::whitelisted_node_ips` and `Config::whitelisted_client_ips`. `None` takes any.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Whitelists {
+    pub node_ips: Option<HashSet<IpAddr>>,
+    pub client_ips: Option<HashSet<IpAddr>>,
+}
+
+impl Whitelists {
+    pub fn from_config(cfg: &Config) -> Self {
+        Whitelists {
+            node_ips: cfg.whitelisted_node_ips.clone(),
+            client_ips: cfg.whitelisted_client_ips.clone(),
+        }
+    }
+
+    pub fn allows(&self, kind: CrustUser, ip: &IpAddr) -> bool {
+        self.ips(kind).map_or(true, |ips| ips.contains(ip))
+    }
+
+    pub fn ips(&self, kind: CrustUser) -> Option<&HashSet<IpAddr>> {
+        match kind {
+            CrustUser::Client => self.client_ips.as_ref(),
+            CrustUser::Node => self.node_ips.as_ref(),
+        }
+    }
+
+    /// Adds `ip` to the whitelist of `kind`, turning whitelisting on for that kind if it was off.
+    pub fn add(&mut self, kind: CrustUser, ip: IpAddr) {
+        let ips = match kind {
+            CrustUser::Client => &mut self.client_ips,
+            CrustUser::Node => &mut self.node_ips,
+        };
+        let _ = ips.get_or_insert_with(HashSet::new).insert(ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::serialisation::{deserialise, serialise};
+    use rand;
+    use serde_json;
+    use tests::UniqueId;
+
+    fn priv_info(issued_at: u64, ttl_secs: u64) -> PrivConnectionInfo<UniqueId> {
+        PrivConnectionInfo {
+            id: rand::random(),
+            for_asserted: vec![unwrap!("198.51.100.2:40000".parse())],
+            for_direct: vec![unwrap!("10.0.0.1:5483".parse())],
+            for_hole_punch: vec![unwrap!("203.0.113.7:41000".parse())],
+            for_utp: vec![unwrap!("10.0.0.1:5483".parse())],
+            for_relay: Vec::new(),
+            hole_punch_socket: None,
+            issued_at,
+            ttl_secs,
+            outbound_bind_addr: None,
+        }
+    }
+
+    #[test]
+    fn pub_connection_info_round_trip() {
+        let info = priv_info(now_secs(), 600).to_pub_connection_info();
+        assert_eq!(
+            info.candidates(),
+            &[
+                CandidateAddr::TcpAsserted(unwrap!("198.51.100.2:40000".parse())),
+                CandidateAddr::TcpDirect(unwrap!("10.0.0.1:5483".parse())),
+                CandidateAddr::TcpMapped(unwrap!("203.0.113.7:41000".parse())),
+                CandidateAddr::Utp(unwrap!("10.0.0.1:5483".parse())),
+            ]
+        );
+
+        let json = unwrap!(serde_json::to_string(&info));
+        let decoded: PubConnectionInfo<UniqueId> = unwrap!(serde_json::from_str(&json));
+        assert_eq!(decoded.id, info.id);
+        assert_eq!(decoded.candidates, info.candidates);
+        assert_eq!(decoded.issued_at, info.issued_at);
+        assert_eq!(decoded.ttl_secs, Some(600));
+
+        let bytes = unwrap!(serialise(&info));
+        let decoded: PubConnectionInfo<UniqueId> = unwrap!(deserialise(&bytes));
+        assert_eq!(decoded.candidates, info.candidates);
+        assert_eq!(decoded.ttl_secs, Some(600));
+    }
+
+    #[test]
+    fn legacy_pub_connection_info() {
+        let id: UniqueId = rand::random();
+        let json = format!(
+            r#"{{"id":{},"for_hole_punch":["203.0.113.7:41000"],"for_direct":["10.0.0.1:5483"]}}"#,
+            unwrap!(serde_json::to_string(&id))
+        );
+        let decoded: PubConnectionInfo<UniqueId> = unwrap!(serde_json::from_str(&json));
+        assert_eq!(decoded.id, id);
+        assert_eq!(
+            decoded.candidates(),
+            &[
+                CandidateAddr::TcpDirect(unwrap!("10.0.0.1:5483".parse())),
+                CandidateAddr::TcpMapped(unwrap!("203.0.113.7:41000".parse())),
+            ]
+        );
+        assert_eq!(decoded.ttl_secs, None);
+        assert!(!decoded.is_expired());
+    }
+
+    #[test]
+    fn expiry() {
+        let now = now_secs();
+        assert!(!priv_info(now, 600).to_pub_connection_info().is_expired());
+        // Still within the allowance for clock differences.
+        assert!(!priv_info(now - 610, 600)
+            .to_pub_connection_info()
+            .is_expired());
+        assert!(priv_info(now - 3600, 600)
+            .to_pub_connection_info()
+            .is_expired());
+        // An expiry out of range doesn't overflow, and never comes.
+        assert!(!priv_info(now, u64::max_value())
+            .to_pub_connection_info()
+            .is_expired());
+        assert!(!priv_info(u64::max_value(), 600)
+            .to_pub_connection_info()
+            .is_expired());
+    }
 }
@@ -0,0 +1,319 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use common::{Core, NetworkId, Socket, State, Uid};
+use main::{
+    now_secs, ActiveConnection, CandidateAddr, Connect, ConnectionMap, ConnectionSettings,
+    CrustConfig, EventSink, PrivConnectionInfo, PubConnectionInfo, Redial,
+};
+use mio::{Poll, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// Token of the `Upgrades` state, which dials the direct connections asked for by
+/// `Service::upgrade_connection`.
+pub const UPGRADES_TOKEN: Token = Token(14);
+
+/// Dials the listeners of the peers whose connection is moved onto a direct one, see
+/// `Service::upgrade_connection`, and knows our own listeners to offer them. The connection to
+/// each peer keeps track of how far its upgrade got, see `Upgrade`.
+pub struct Upgrades<UID: Uid> {
+    token: Token,
+    our_id: UID,
+    our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    config: CrustConfig,
+    cm: ConnectionMap<UID>,
+    network: NetworkId,
+    event_tx: EventSink<UID>,
+}
+
+impl<UID: Uid> Upgrades<UID> {
+    pub fn start(
+        core: &mut Core,
+        token: Token,
+        our_id: UID,
+        our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+        config: CrustConfig,
+        cm: ConnectionMap<UID>,
+        network: NetworkId,
+        event_tx: EventSink<UID>,
+    ) {
+        let state = Rc::new(RefCell::new(Upgrades {
+            token,
+            our_id,
+            our_listeners,
+            config,
+            cm,
+            network,
+            event_tx,
+        }));
+        let _ = core.insert_state(token, state);
+    }
+
+    /// Our listeners, which the peer dials should it be the one to.
+    pub fn listeners(&self) -> Vec<SocketAddr> {
+        unwrap!(self.our_listeners.lock()).clone()
+    }
+
+    /// Dials the peer at its listeners, returning whether that started. The connection to the
+    /// peer is told once the attempt is over, unless it succeeded, see `ActiveConnection::adopt`.
+    pub fn dial(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        peer: UID,
+        addrs: Vec<SocketAddr>,
+    ) -> bool {
+        if addrs.is_empty() || core.is_draining() {
+            return false;
+        }
+
+        let (settings, outbound_bind_addr) = {
+            let config = unwrap!(self.config.lock());
+            (
+                ConnectionSettings::from_config(&config.cfg),
+                config.cfg.outbound_bind_addr,
+            )
+        };
+        let our_ci = PrivConnectionInfo {
+            id: self.our_id,
+            for_asserted: Vec::new(),
+            for_direct: self.listeners(),
+            for_hole_punch: Vec::new(),
+            for_utp: Vec::new(),
+            for_relay: Vec::new(),
+            hole_punch_socket: None,
+            issued_at: now_secs(),
+            ttl_secs: 0,
+            outbound_bind_addr,
+        };
+        let their_ci = PubConnectionInfo {
+            id: peer,
+            candidates: addrs.into_iter().map(CandidateAddr::TcpDirect).collect(),
+            issued_at: None,
+            ttl_secs: None,
+        };
+
+        trace!("Dialling {:?} directly to upgrade the connection", peer);
+        match Connect::start(
+            core,
+            poll,
+            our_ci,
+            their_ci,
+            self.cm.clone(),
+            self.network,
+            self.event_tx.clone(),
+            Some(Redial::Upgrade),
+            settings,
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Failed to dial {:?} to upgrade the connection: {:?}", peer, e);
+                false
+            }
+        }
+    }
+}
+
+impl<UID: Uid> State for Upgrades<UID> {
+    fn name(&self) -> &'static str {
+        "Upgrades"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Runs `f` on the `Upgrades` state, if it runs.
+pub fn with_upgrades<UID, T, F>(core: &mut Core, f: F) -> Option<T>
+where
+    UID: Uid,
+    F: FnOnce(&mut Upgrades<UID>, &mut Core) -> T,
+{
+    let state = core.get_state(UPGRADES_TOKEN)?;
+    let mut state = state.borrow_mut();
+    let upgrades = state.as_any().downcast_mut::<Upgrades<UID>>()?;
+    Some(f(upgrades, core))
+}
+
+/// How far the upgrade of a connection got, kept by the connection. Whichever of us asks first
+/// dials the listeners the other offers in answer, and should that fail the other dials ours. Once
+/// the new connection is up, each of us writes `Message::UpgradeSwitch` last on the old one and
+/// reads it until the other's arrives, so no message is lost or overtaken.
+#[derive(Default)]
+pub struct Upgrade {
+    stage: Stage,
+    /// Whether the application asked for the upgrade, and is to be told how it went.
+    pub asked: bool,
+    /// Whether we asked for the upgrade, rather than the peer.
+    pub requester: bool,
+    /// Whether we offered the peer our listeners, to dial should we fail to dial its own.
+    pub listeners_sent: bool,
+    /// The listeners the peer offered us.
+    pub their_listeners: Vec<SocketAddr>,
+    /// Whether the peer switched to the new connection before we took it up, in which case the
+    /// old one isn't read any more.
+    pub their_switch: bool,
+}
+
+/// Stage of the upgrade of a connection.
+pub enum Stage {
+    Idle,
+    /// We asked the peer, which hasn't answered yet.
+    Requested,
+    /// We dial the peer.
+    Dialling,
+    /// The peer dials us.
+    Awaiting,
+    /// The connection moved onto the new socket, while the old one is read until the peer's
+    /// `Message::UpgradeSwitch` and written until ours.
+    Switching(Switch),
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Stage::Idle
+    }
+}
+
+/// The old socket of a connection which moved onto a new one.
+pub struct Switch {
+    pub old: Socket,
+    /// Token the old socket is registered under, see `UpgradeLeg`.
+    pub leg: Token,
+    /// Whether our `Message::UpgradeSwitch` has been written in full.
+    pub sent: bool,
+    /// Whether the peer's `Message::UpgradeSwitch` has been read.
+    pub received: bool,
+}
+
+impl Upgrade {
+    pub fn stage(&self) -> &Stage {
+        &self.stage
+    }
+
+    pub fn set_stage(&mut self, stage: Stage) {
+        self.stage = stage;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        match self.stage {
+            Stage::Idle => true,
+            _ => false,
+        }
+    }
+
+    /// Whether a new connection to the peer is expected, which is then taken up.
+    pub fn awaits_connection(&self) -> bool {
+        match self.stage {
+            Stage::Dialling | Stage::Awaiting => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the old socket is read from, as the peer may still write on it.
+    pub fn reads_old(&self) -> bool {
+        match self.stage {
+            Stage::Switching(ref switch) => !switch.received,
+            _ => false,
+        }
+    }
+
+    /// The old socket while the peer may still write on it.
+    pub fn unread_old(&mut self) -> Option<&mut Socket> {
+        match self.stage {
+            Stage::Switching(ref mut switch) if !switch.received => Some(&mut switch.old),
+            _ => None,
+        }
+    }
+
+    pub fn switch_mut(&mut self) -> Option<&mut Switch> {
+        match self.stage {
+            Stage::Switching(ref mut switch) => Some(switch),
+            _ => None,
+        }
+    }
+
+    /// Ends the upgrade, returning the old socket if the connection was moving off it.
+    pub fn finish(&mut self) -> Option<Switch> {
+        self.their_switch = false;
+        self.requester = false;
+        self.listeners_sent = false;
+        self.their_listeners.clear();
+        match mem::replace(&mut self.stage, Stage::Idle) {
+            Stage::Switching(switch) => Some(switch),
+            _ => None,
+        }
+    }
+}
+
+/// Takes the events of the old socket of a connection being upgraded, under a token of its own,
+/// to the connection.
+pub struct UpgradeLeg<UID: Uid> {
+    token: Token,
+    owner: Token,
+    _uid: PhantomData<UID>,
+}
+
+impl<UID: Uid> UpgradeLeg<UID> {
+    pub fn start(core: &mut Core, token: Token, owner: Token) {
+        let state = Rc::new(RefCell::new(UpgradeLeg::<UID> {
+            token,
+            owner,
+            _uid: PhantomData,
+        }));
+        let _ = core.insert_state(token, state);
+    }
+}
+
+impl<UID: Uid> State for UpgradeLeg<UID> {
+    fn name(&self) -> &'static str {
+        "UpgradeLeg"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        let state = match core.get_state(self.owner) {
+            Some(state) => state,
+            None => return self.terminate(core, poll),
+        };
+        let mut state = state.borrow_mut();
+        if let Some(connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+            connection.old_socket_ready(core, poll, kind);
+        }
+    }
+
+    // The old socket is closed along with the connection, which drains on its own.
+    fn drain(&mut self, _core: &mut Core, _poll: &Poll) {}
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let state = match core.get_state(self.owner) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.borrow_mut();
+        if let Some(connection) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+            connection.drop_old_socket(core, poll);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
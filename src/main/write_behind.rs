@@ -0,0 +1,145 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Updates of the files kept across restarts, batched and written on a thread of their own so that
+// the event loop waits neither for a file lock nor for the disk.
+
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+enum Item<U> {
+    Update(U),
+    /// Has the updates sent so far written, and tells once they are.
+    Flush(Sender<()>),
+}
+
+/// Hands updates over to a writer thread, which passes them on in batches, at most one per
+/// interval. What is still queued is written when this is dropped, which waits for it.
+pub struct WriteBehind<U> {
+    tx: Option<Sender<Item<U>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl<U: Send + 'static> WriteBehind<U> {
+    /// Starts the writer thread, called `name`, which calls `write` with the updates sent within
+    /// `interval` of the first of them.
+    pub fn start<F>(name: &str, interval: Duration, mut write: F) -> io::Result<Self>
+    where
+        F: FnMut(Vec<U>) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                let mut batch = Vec::new();
+                let mut deadline = None;
+                loop {
+                    let item = match deadline {
+                        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                        Some(deadline) => {
+                            let now = Instant::now();
+                            if now >= deadline {
+                                Err(RecvTimeoutError::Timeout)
+                            } else {
+                                rx.recv_timeout(deadline - now)
+                            }
+                        }
+                    };
+                    match item {
+                        Ok(Item::Update(update)) => {
+                            if batch.is_empty() {
+                                deadline = Some(Instant::now() + interval);
+                            }
+                            batch.push(update);
+                        }
+                        Ok(Item::Flush(done)) => {
+                            if !batch.is_empty() {
+                                write(batch.split_off(0));
+                            }
+                            deadline = None;
+                            let _ = done.send(());
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            write(batch.split_off(0));
+                            deadline = None;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            if !batch.is_empty() {
+                                write(batch);
+                            }
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(WriteBehind {
+            tx: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    /// Queues `update` for the next batch.
+    pub fn send(&self, update: U) {
+        if let Some(ref tx) = self.tx {
+            if tx.send(Item::Update(update)).is_err() {
+                debug!("The writer thread is gone - dropping an update");
+            }
+        }
+    }
+
+    /// Has the updates sent so far written without waiting for the interval to be over. The
+    /// returned receiver is told once they are, or disconnected if the writer thread is gone.
+    pub fn flush(&self) -> Receiver<()> {
+        let (done_tx, done_rx) = mpsc::channel();
+        if let Some(ref tx) = self.tx {
+            let _ = tx.send(Item::Flush(done_tx));
+        }
+        done_rx
+    }
+}
+
+impl<U> Drop for WriteBehind<U> {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                debug!("The writer thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn updates_are_written_in_batches_on_flush_and_on_drop() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let batches_clone = batches.clone();
+        let writer = unwrap!(WriteBehind::start(
+            "Test-Writer",
+            Duration::from_millis(200),
+            move |batch| unwrap!(batches_clone.lock()).push(batch),
+        ));
+        writer.send(1);
+        writer.send(2);
+        thread::sleep(Duration::from_millis(600));
+        writer.send(3);
+        unwrap!(writer.flush().recv());
+        assert_eq!(*unwrap!(batches.lock()), vec![vec![1, 2], vec![3]]);
+
+        writer.send(4);
+        drop(writer);
+        assert_eq!(*unwrap!(batches.lock()), vec![vec![1, 2], vec![3], vec![4]]);
+    }
+}
@@ -7,7 +7,7 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use common::{Core, Message, Priority, Socket, State, Uid};
+use common::{self, Core, Message, Priority, Socket, State, Uid, CONTROL_PRIORITY};
 use mio::tcp::TcpStream;
 use mio::{Poll, PollOpt, Ready, Token};
 use nat::{util, NatError};
@@ -35,7 +35,8 @@ impl<UID: Uid> GetExtAddr<UID> {
     ) -> Result<Token, NatError> {
         let query_socket = util::new_reusably_bound_tcp_socket(&local_addr)?;
         let query_socket = query_socket.to_tcp_stream()?;
-        let socket = TcpStream::connect_stream(query_socket, peer_stun)?;
+        let peer_stun = common::addr_for_family(peer_stun, &local_addr);
+        let socket = TcpStream::connect_stream(query_socket, &peer_stun)?;
 
         let socket = Socket::wrap(socket);
         let token = core.get_new_token();
@@ -43,7 +44,7 @@ impl<UID: Uid> GetExtAddr<UID> {
         let state = Self {
             token,
             socket,
-            request: Some((Message::EchoAddrReq, 0)),
+            request: Some((Message::EchoAddrReq, CONTROL_PRIORITY)),
             finish,
         };
 
@@ -85,6 +86,10 @@ impl<UID: Uid> GetExtAddr<UID> {
 }
 
 impl<UID: Uid> State for GetExtAddr<UID> {
+    fn name(&self) -> &'static str {
+        "GetExtAddr"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             self.handle_error(core, poll);
@@ -9,60 +9,215 @@
 
 use self::get_ext_addr::GetExtAddr;
 use common::{Core, CoreMessage, CoreTimer, State, Uid};
+#[cfg(feature = "nat-traversal")]
 use igd::PortMappingProtocol;
 use maidsafe_utilities::thread;
-use mio::timer::Timeout;
+use mio::channel::Sender;
 use mio::{Poll, Token};
+use nat::mapping_context::Gateway;
+use nat::nat_pmp::NatPmpGateway;
 use nat::{util, MappingContext, NatError};
 use net2::TcpBuilder;
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::marker::PhantomData;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::rc::Rc;
 use std::time::Duration;
 
 mod get_ext_addr;
 
 const TIMEOUT_SEC: u64 = 3;
+/// Lease asked for a port mapping. Gateways forget mappings after their lease, so those of our
+/// listeners are renewed halfway through it, and those nobody renews don't outlive us for long.
+const MAPPING_LEASE_SECS: u64 = 60 * 60;
+
+/// A port mapping made for a socket, on an IGD gateway or over NAT-PMP, which the gateway keeps
+/// for its lease unless renewed.
+pub struct PortMapping {
+    mapper: Mapper,
+    local_addr: SocketAddrV4,
+    ext_addr: SocketAddrV4,
+    lease: Duration,
+}
+
+enum Mapper {
+    Igd(Gateway),
+    NatPmp(NatPmpGateway),
+}
+
+impl PortMapping {
+    /// The address the socket is reached at from outside.
+    pub fn ext_addr(&self) -> SocketAddr {
+        SocketAddr::V4(self.ext_addr)
+    }
+
+    /// How long after it was made or last renewed the mapping should be renewed.
+    pub fn renew_after(&self) -> Duration {
+        self.lease / 2
+    }
+
+    /// Asks the gateway to renew the mapping, for the same external port if it lets us, in the
+    /// background. `done` is then called on the event loop with the renewed mapping, whose
+    /// external address may differ, or `None` if the gateway no longer maps the socket.
+    pub fn renew<F>(self, tx: Sender<CoreMessage>, done: F)
+    where
+        F: FnOnce(&mut Core, &Poll, Option<PortMapping>) + Send + 'static,
+    {
+        let _ = thread::named("Port-Mapping-Renewal", move || {
+            let (local_addr, ext_addr) = (self.local_addr, self.ext_addr);
+            let renewed = match self.mapper {
+                Mapper::Igd(ref gateway) => renew_port_mapping(gateway, local_addr, ext_addr),
+                Mapper::NatPmp(ref gateway) => {
+                    let lease = Duration::from_secs(MAPPING_LEASE_SECS);
+                    match gateway.map_tcp(local_addr.port(), ext_addr.port(), lease) {
+                        Ok(renewed) => Some(renewed),
+                        Err(e) => {
+                            debug!("Could not renew NAT-PMP mapping of {}: {}", ext_addr, e);
+                            None
+                        }
+                    }
+                }
+            };
+            let renewed = renewed.map(|(ext_addr, lease)| PortMapping {
+                ext_addr,
+                lease,
+                ..self
+            });
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                done(core, poll, renewed)
+            }));
+        });
+    }
+
+    /// Asks the gateway to remove the mapping, in the background.
+    pub fn remove(self) {
+        let _ = thread::named("Port-Mapping-Removal", move || match self.mapper {
+            Mapper::Igd(ref gateway) => remove_port_mapping(gateway, self.ext_addr),
+            Mapper::NatPmp(ref gateway) => {
+                if let Err(e) = gateway.unmap_tcp(self.local_addr.port()) {
+                    debug!(
+                        "Could not remove NAT-PMP mapping of {}: {}",
+                        self.ext_addr, e
+                    );
+                }
+            }
+        });
+    }
+}
+
+#[cfg(feature = "nat-traversal")]
+fn add_port_mapping(gateway: &Gateway, local_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+    gateway
+        .get_any_address(
+            PortMappingProtocol::TCP,
+            local_addr,
+            MAPPING_LEASE_SECS as u32,
+            "MaidSafeNat",
+        )
+        .ok()
+}
+
+#[cfg(not(feature = "nat-traversal"))]
+fn add_port_mapping(gateway: &Gateway, _local_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+    match *gateway {}
+}
+
+/// Renews the mapping of `local_addr` for the same external port, or any other if that was taken
+/// in the meantime, as a gateway which restarted may have done.
+#[cfg(feature = "nat-traversal")]
+fn renew_port_mapping(
+    gateway: &Gateway,
+    local_addr: SocketAddrV4,
+    ext_addr: SocketAddrV4,
+) -> Option<(SocketAddrV4, Duration)> {
+    let lease = Duration::from_secs(MAPPING_LEASE_SECS);
+    match gateway.add_port(
+        PortMappingProtocol::TCP,
+        ext_addr.port(),
+        local_addr,
+        MAPPING_LEASE_SECS as u32,
+        "MaidSafeNat",
+    ) {
+        Ok(()) => Some((ext_addr, lease)),
+        Err(e) => {
+            debug!("Could not renew IGD mapping of {}: {:?}", ext_addr, e);
+            add_port_mapping(gateway, local_addr).map(|ext_addr| (ext_addr, lease))
+        }
+    }
+}
+
+#[cfg(not(feature = "nat-traversal"))]
+fn renew_port_mapping(
+    gateway: &Gateway,
+    _local_addr: SocketAddrV4,
+    _ext_addr: SocketAddrV4,
+) -> Option<(SocketAddrV4, Duration)> {
+    match *gateway {}
+}
+
+#[cfg(feature = "nat-traversal")]
+fn remove_port_mapping(gateway: &Gateway, ext_addr: SocketAddrV4) {
+    if let Err(e) = gateway.remove_port(PortMappingProtocol::TCP, ext_addr.port()) {
+        debug!("Could not remove IGD mapping of {}: {:?}", ext_addr, e);
+    }
+}
+
+#[cfg(not(feature = "nat-traversal"))]
+fn remove_port_mapping(gateway: &Gateway, _ext_addr: SocketAddrV4) {
+    match *gateway {}
+}
 
 /// A state which represents the in-progress mapping of a tcp socket.
 pub struct MappedTcpSocket<F, UID> {
     token: Token,
     socket: Option<TcpBuilder>,
-    igd_children: usize,
+    mapping_children: usize,
+    port_mappings: Vec<PortMapping>,
     stun_children: HashSet<Token>,
     mapped_addrs: Vec<SocketAddr>,
-    timeout: Timeout,
     finish: Option<F>,
     phantom: PhantomData<UID>,
 }
 
 impl<F, UID> MappedTcpSocket<F, UID>
 where
-    F: FnOnce(&mut Core, &Poll, TcpBuilder, Vec<SocketAddr>) + Any,
+    F: FnOnce(&mut Core, &Poll, TcpBuilder, Vec<SocketAddr>, Vec<PortMapping>) + Any,
     UID: Uid,
 {
-    /// Start mapping a tcp socket
+    /// Start mapping a tcp socket, bound to `bind_ip` if given, or else to the unspecified IPv4
+    /// address. The unspecified IPv6 address makes it dual-stack, mapped on the interfaces of both
+    /// families, unless IPv6 is unavailable, when it falls back to IPv4.
     pub fn start(
         core: &mut Core,
         poll: &Poll,
         port: u16,
+        bind_ip: Option<IpAddr>,
         mc: &MappingContext,
         finish: F,
     ) -> Result<(), NatError> {
         let token = core.get_new_token();
 
-        // TODO(Spandan) Ipv6 is not supported in Listener so dealing only with ipv4 right now
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        let any_v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        let addr = bind_ip.map_or(any_v4, |bind_ip| SocketAddr::new(bind_ip, port));
 
-        let socket = util::new_reusably_bound_tcp_socket(&addr)?;
+        let socket = match util::new_reusably_bound_tcp_socket(&addr) {
+            Err(ref e) if addr.is_ipv6() && addr.ip().is_unspecified() => {
+                debug!("IPv6 unavailable, binding to {} instead: {}", any_v4, e);
+                util::new_reusably_bound_tcp_socket(&any_v4)?
+            }
+            res => res?,
+        };
         let addr = socket.local_addr()?;
 
         // Ask IGD
-        let mut igd_children = 0;
+        let mut mapping_children = 0;
+        let lease = Duration::from_secs(MAPPING_LEASE_SECS);
         for &(ref ip, ref gateway) in mc.ifv4s() {
+            if !addr.ip().is_unspecified() && addr.ip() != IpAddr::V4(*ip) {
+                continue;
+            }
             let gateway = match *gateway {
                 Some(ref gateway) => gateway.clone(),
                 None => continue,
@@ -70,11 +225,9 @@ where
             let tx = core.sender().clone();
             let addr_igd = SocketAddrV4::new(*ip, addr.port());
             let _ = thread::named("IGD-Address-Mapping", move || {
-                let res =
-                    gateway.get_any_address(PortMappingProtocol::TCP, addr_igd, 0, "MaidSafeNat");
-                let ext_addr = match res {
-                    Ok(ext_addr) => ext_addr,
-                    Err(_) => return,
+                let ext_addr = match add_port_mapping(&gateway, addr_igd) {
+                    Some(ext_addr) => ext_addr,
+                    None => return,
                 };
                 let _ = tx.send(CoreMessage::new(move |core, poll| {
                     let state = match core.get_state(token) {
@@ -88,25 +241,86 @@ where
                             Some(mapping_sock) => mapping_sock,
                             None => return,
                         };
-                    mapping_tcp_sock.handle_igd_resp(core, poll, SocketAddr::V4(ext_addr));
+                    let mapping = PortMapping {
+                        mapper: Mapper::Igd(gateway),
+                        local_addr: addr_igd,
+                        ext_addr,
+                        lease,
+                    };
+                    mapping_tcp_sock.handle_mapping_resp(core, poll, mapping);
                 }));
             });
-            igd_children += 1;
+            mapping_children += 1;
         }
 
-        let mapped_addrs = mc
-            .ifv4s()
-            .iter()
-            .map(|&(ip, _)| SocketAddr::new(IpAddr::V4(ip), addr.port()))
-            .collect();
+        // Gateways without IGD may speak NAT-PMP instead, which maps the port of whichever of our
+        // IPv4 addresses asks.
+        let ask_nat_pmp = mapping_children == 0 && (addr.is_ipv4() || addr.ip().is_unspecified());
+        let nat_pmp_gateway = if ask_nat_pmp {
+            mc.nat_pmp_gateway()
+        } else {
+            None
+        };
+        if let Some(gateway) = nat_pmp_gateway {
+            let tx = core.sender().clone();
+            let local_addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), addr.port());
+            let _ = thread::named("NAT-PMP-Address-Mapping", move || {
+                let (ext_addr, lease) = match gateway.map_tcp(addr.port(), addr.port(), lease) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        debug!("Could not map {} over NAT-PMP: {}", addr, e);
+                        return;
+                    }
+                };
+                let _ = tx.send(CoreMessage::new(move |core, poll| {
+                    let state = match core.get_state(token) {
+                        Some(state) => state,
+                        None => return,
+                    };
 
+                    let mut state = state.borrow_mut();
+                    let mapping_tcp_sock =
+                        match state.as_any().downcast_mut::<MappedTcpSocket<F, UID>>() {
+                            Some(mapping_sock) => mapping_sock,
+                            None => return,
+                        };
+                    let mapping = PortMapping {
+                        mapper: Mapper::NatPmp(gateway),
+                        local_addr,
+                        ext_addr,
+                        lease,
+                    };
+                    mapping_tcp_sock.handle_mapping_resp(core, poll, mapping);
+                }));
+            });
+            mapping_children += 1;
+        }
+
+        let mapped_addrs = if addr.ip().is_unspecified() {
+            // Link-local addresses are of no use to peers without the interface they belong to.
+            let ifv6s: &[Ipv6Addr] = if addr.is_ipv6() { mc.ifv6s() } else { &[] };
+            let ifv6s = ifv6s
+                .iter()
+                .filter(|ip| !util::ipv6_addr_is_link_local(ip))
+                .map(|&ip| IpAddr::V6(ip));
+            mc.ifv4s()
+                .iter()
+                .map(|&(ip, _)| IpAddr::V4(ip))
+                .chain(ifv6s)
+                .map(|ip| SocketAddr::new(ip, addr.port()))
+                .collect()
+        } else {
+            vec![addr]
+        };
+
+        core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0))?;
         let state = Rc::new(RefCell::new(Self {
             token,
             socket: Some(socket),
-            igd_children,
+            mapping_children,
+            port_mappings: Vec::new(),
             stun_children: HashSet::with_capacity(mc.peer_stuns().len()),
             mapped_addrs,
-            timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0))?,
             finish: Some(finish),
             phantom: PhantomData,
         }));
@@ -127,7 +341,7 @@ where
             }
         }
 
-        if state.borrow().stun_children.is_empty() && state.borrow().igd_children == 0 {
+        if state.borrow().stun_children.is_empty() && state.borrow().mapping_children == 0 {
             state.borrow_mut().terminate(core, poll);
             return Ok(());
         }
@@ -148,15 +362,16 @@ where
         if let Ok(our_ext_addr) = res {
             self.mapped_addrs.push(our_ext_addr);
         }
-        if self.stun_children.is_empty() && self.igd_children == 0 {
+        if self.stun_children.is_empty() && self.mapping_children == 0 {
             self.terminate(core, poll);
         }
     }
 
-    fn handle_igd_resp(&mut self, core: &mut Core, poll: &Poll, our_ext_addr: SocketAddr) {
-        self.igd_children -= 1;
-        self.mapped_addrs.push(our_ext_addr);
-        if self.stun_children.is_empty() && self.igd_children == 0 {
+    fn handle_mapping_resp(&mut self, core: &mut Core, poll: &Poll, mapping: PortMapping) {
+        self.mapping_children -= 1;
+        self.mapped_addrs.push(SocketAddr::V4(mapping.ext_addr));
+        self.port_mappings.push(mapping);
+        if self.stun_children.is_empty() && self.mapping_children == 0 {
             self.terminate(core, poll);
         }
     }
@@ -175,21 +390,25 @@ where
 
 impl<F, UID> State for MappedTcpSocket<F, UID>
 where
-    F: FnOnce(&mut Core, &Poll, TcpBuilder, Vec<SocketAddr>) + Any,
+    F: FnOnce(&mut Core, &Poll, TcpBuilder, Vec<SocketAddr>, Vec<PortMapping>) + Any,
     UID: Uid,
 {
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, _: u8) {
+    fn name(&self) -> &'static str {
+        "MappedTcpSocket"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _: u64) {
         self.terminate(core, poll)
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
         self.terminate_children(core, poll);
         let _ = core.remove_state(self.token);
-        let _ = core.cancel_timeout(&self.timeout);
 
         let socket = unwrap!(self.socket.take());
         let mapped_addrs = self.mapped_addrs.drain(..).collect();
-        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs);
+        let port_mappings = self.port_mappings.drain(..).collect();
+        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs, port_mappings);
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -9,37 +9,46 @@
 
 //! Defines the `MappingContext` type
 
+#[cfg(feature = "nat-traversal")]
+use super::nat_pmp;
+use super::nat_pmp::NatPmpGateway;
 use super::NatError;
+#[cfg(feature = "nat-traversal")]
 use crossbeam;
 use get_if_addrs::{self, IfAddr};
-use igd::{self, Gateway};
+#[cfg(feature = "nat-traversal")]
+pub use igd::Gateway;
+#[cfg(feature = "nat-traversal")]
+use igd;
+#[cfg(feature = "nat-traversal")]
 use nat;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "nat-traversal")]
 use std::time::Duration;
 
+/// Stands in for an IGD gateway without the `nat-traversal` feature, where none is ever found.
+#[cfg(not(feature = "nat-traversal"))]
+#[derive(Debug, Clone)]
+pub enum Gateway {}
+
 /// Keeps track of information about external mapping servers
 #[derive(Debug, Clone)]
 pub struct MappingContext {
     our_ifv4s: Vec<(Ipv4Addr, Option<Gateway>)>,
     our_ifv6s: Vec<Ipv6Addr>,
+    nat_pmp_gateway: Option<NatPmpGateway>,
     peer_stuns: Vec<SocketAddr>,
 }
 
 impl MappingContext {
     /// Create a new `MappingContext`
+    #[cfg(feature = "nat-traversal")]
     pub fn new() -> Result<MappingContext, NatError> {
-        let ifs = get_if_addrs::get_if_addrs()?;
-        let (mut ifv4s, mut ifv6s) = (Vec::with_capacity(5), Vec::with_capacity(5));
-        for interface in ifs {
-            match interface.addr {
-                IfAddr::V4(v4_addr) => ifv4s.push((v4_addr.ip, None)),
-                IfAddr::V6(v6_addr) => ifv6s.push(v6_addr.ip),
-            }
-        }
+        let mut mc = Self::without_igd()?;
 
         crossbeam::scope(|scope| {
-            let mut guards = Vec::with_capacity(ifv4s.len());
-            for ifv4 in &mut ifv4s {
+            let mut guards = Vec::with_capacity(mc.our_ifv4s.len());
+            for ifv4 in &mut mc.our_ifv4s {
                 if !ifv4.0.is_loopback() {
                     guards.push(scope.spawn(move || {
                         ifv4.1 =
@@ -48,16 +57,41 @@ impl MappingContext {
                 }
             }
         });
+        mc.nat_pmp_gateway = nat_pmp::default_gateway().map(NatPmpGateway::new);
+
+        Ok(mc)
+    }
+
+    /// Create a new `MappingContext`. Without the `nat-traversal` feature there are no gateways to
+    /// look for, as with `without_igd`.
+    #[cfg(not(feature = "nat-traversal"))]
+    pub fn new() -> Result<MappingContext, NatError> {
+        Self::without_igd()
+    }
+
+    /// Create a `MappingContext` which doesn't look for IGD gateways on our interfaces, nor for a
+    /// NAT-PMP one, so that sockets are only mapped by the peer "STUN" servers, if any.
+    pub fn without_igd() -> Result<MappingContext, NatError> {
+        let ifs = get_if_addrs::get_if_addrs()?;
+        let (mut ifv4s, mut ifv6s) = (Vec::with_capacity(5), Vec::with_capacity(5));
+        for interface in ifs {
+            match interface.addr {
+                IfAddr::V4(v4_addr) => ifv4s.push((v4_addr.ip, None)),
+                IfAddr::V6(v6_addr) => ifv6s.push(v6_addr.ip),
+            }
+        }
 
         Ok(MappingContext {
             our_ifv4s: ifv4s,
             our_ifv6s: ifv6s,
+            nat_pmp_gateway: None,
             peer_stuns: Vec::with_capacity(10),
         })
     }
 
     /// Inform the context about external "STUN" servers. Note that crust does not actually use
     /// STUN but a custom STUN-like protocol.
+    #[cfg(feature = "nat-traversal")]
     pub fn add_peer_stuns<A: IntoIterator<Item = SocketAddr>>(&mut self, stun_addrs: A) {
         let listeners = stun_addrs
             .into_iter()
@@ -65,11 +99,25 @@ impl MappingContext {
         self.peer_stuns.extend(listeners);
     }
 
+    /// Without the `nat-traversal` feature our sockets aren't mapped, so the servers are ignored.
+    #[cfg(not(feature = "nat-traversal"))]
+    pub fn add_peer_stuns<A: IntoIterator<Item = SocketAddr>>(&mut self, _stun_addrs: A) {}
+
     /// Get v4 interfaces
     pub fn ifv4s(&self) -> &Vec<(Ipv4Addr, Option<Gateway>)> {
         &self.our_ifv4s
     }
 
+    /// Get v6 interfaces
+    pub fn ifv6s(&self) -> &Vec<Ipv6Addr> {
+        &self.our_ifv6s
+    }
+
+    /// Get the gateway to map ports over NAT-PMP with, when there is no IGD gateway
+    pub fn nat_pmp_gateway(&self) -> Option<NatPmpGateway> {
+        self.nat_pmp_gateway
+    }
+
     /// Iterate over the known servers
     pub fn peer_stuns(&self) -> &Vec<SocketAddr> {
         &self.peer_stuns
@@ -83,6 +131,7 @@ mod tests {
     // Run with `cargo test igd -- --ignored` to find if IGD is available for you
     #[test]
     #[ignore]
+    #[cfg(feature = "nat-traversal")]
     fn igd_gateway_available() {
         let mc = unwrap!(MappingContext::new(), "Could not instantiate MC");
         assert!(!mc.our_ifv4s.is_empty());
@@ -8,13 +8,14 @@
 // Software.
 
 pub use self::error::NatError;
-pub use self::mapped_tcp_socket::MappedTcpSocket;
+pub use self::mapped_tcp_socket::{MappedTcpSocket, PortMapping};
 pub use self::mapping_context::MappingContext;
 pub use self::punch_hole::get_sockets;
-pub use self::util::ip_addr_is_global;
+pub use self::util::{ip_addr_is_global, ipv6_addr_is_link_local, new_reusable_tcp_socket};
 
 mod error;
 mod mapped_tcp_socket;
 mod mapping_context;
+mod nat_pmp;
 mod punch_hole;
 mod util;
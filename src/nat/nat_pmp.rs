@@ -0,0 +1,290 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! A client of NAT-PMP, RFC 6886, which gateways without IGD may speak instead to map ports for
+//! the hosts behind them. Requests go over UDP to our default gateway, and are sent again with the
+//! timeout doubling each time, as the RFC asks, though they are given up on far sooner.
+
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(all(feature = "nat-traversal", target_os = "linux"))]
+use std::fs::File;
+use std::io;
+#[cfg(all(feature = "nat-traversal", target_os = "linux"))]
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+#[cfg(feature = "nat-traversal")]
+const NAT_PMP_PORT: u16 = 5351;
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+/// Added to the opcode of a request for that of its response.
+const RESPONSE_BIT: u8 = 128;
+const EXTERNAL_ADDRESS_RESPONSE_SIZE: usize = 12;
+const MAPPING_RESPONSE_SIZE: usize = 16;
+const INITIAL_TIMEOUT_MS: u64 = 250;
+const MAX_TRIES: u32 = 3;
+
+/// A gateway asked to map ports over NAT-PMP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatPmpGateway {
+    addr: SocketAddrV4,
+}
+
+impl NatPmpGateway {
+    #[cfg(feature = "nat-traversal")]
+    pub fn new(ip: Ipv4Addr) -> Self {
+        NatPmpGateway {
+            addr: SocketAddrV4::new(ip, NAT_PMP_PORT),
+        }
+    }
+
+    /// Maps TCP `internal_port` on our side to an external port, `suggested_port` if the gateway
+    /// lets us, for `lease`. Returns the external address and the lease granted, which may be
+    /// shorter. A zero lease with a zero suggested port removes the mapping.
+    pub fn map_tcp(
+        &self,
+        internal_port: u16,
+        suggested_port: u16,
+        lease: Duration,
+    ) -> io::Result<(SocketAddrV4, Duration)> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(self.addr)?;
+
+        let request = [VERSION, OP_EXTERNAL_ADDRESS];
+        let response = exchange(&socket, &request, EXTERNAL_ADDRESS_RESPONSE_SIZE)?;
+        let ext_ip = decode_external_address(&response)?;
+
+        let request = encode_mapping_request(internal_port, suggested_port, lease);
+        let response = exchange(&socket, &request, MAPPING_RESPONSE_SIZE)?;
+        let (ext_port, lease) = decode_mapping_response(&response, internal_port)?;
+        Ok((SocketAddrV4::new(ext_ip, ext_port), lease))
+    }
+
+    /// Removes the mapping of TCP `internal_port`.
+    pub fn unmap_tcp(&self, internal_port: u16) -> io::Result<()> {
+        self.map_tcp(internal_port, 0, Duration::from_secs(0))
+            .map(|_| ())
+    }
+}
+
+/// Sends `request` until the gateway answers it, doubling the timeout each time.
+fn exchange(socket: &UdpSocket, request: &[u8], response_size: usize) -> io::Result<Vec<u8>> {
+    let mut timeout = Duration::from_millis(INITIAL_TIMEOUT_MS);
+    let mut buf = [0; MAPPING_RESPONSE_SIZE];
+    for _ in 0..MAX_TRIES {
+        let _ = socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buf) {
+            // Answers to an earlier request are skipped.
+            Ok(len) if len >= response_size && buf[1] == request[1] + RESPONSE_BIT => {
+                return Ok(buf[..response_size].to_vec());
+            }
+            Ok(_) => (),
+            Err(ref e) if is_timeout(e) => (),
+            Err(e) => return Err(e),
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "The gateway doesn't answer NAT-PMP",
+    ))
+}
+
+/// Whether a read failed for its timeout, which is told apart differently on different platforms.
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn encode_mapping_request(internal_port: u16, suggested_port: u16, lease: Duration) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[0] = VERSION;
+    request[1] = OP_MAP_TCP;
+    BigEndian::write_u16(&mut request[4..6], internal_port);
+    BigEndian::write_u16(&mut request[6..8], suggested_port);
+    let lease_secs = if lease.as_secs() > u64::from(u32::max_value()) {
+        u32::max_value()
+    } else {
+        lease.as_secs() as u32
+    };
+    BigEndian::write_u32(&mut request[8..12], lease_secs);
+    request
+}
+
+/// Checks the version, opcode and result code every response starts with.
+fn check_header(response: &[u8], op: u8) -> io::Result<()> {
+    if response[0] != VERSION || response[1] != op + RESPONSE_BIT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed NAT-PMP response",
+        ));
+    }
+    match BigEndian::read_u16(&response[2..4]) {
+        0 => Ok(()),
+        code => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "The gateway refused the NAT-PMP request with result code {}",
+                code
+            ),
+        )),
+    }
+}
+
+fn decode_external_address(response: &[u8]) -> io::Result<Ipv4Addr> {
+    check_header(response, OP_EXTERNAL_ADDRESS)?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+/// The external port and the lease of a mapping, which has to be of `internal_port`.
+fn decode_mapping_response(response: &[u8], internal_port: u16) -> io::Result<(u16, Duration)> {
+    check_header(response, OP_MAP_TCP)?;
+    if BigEndian::read_u16(&response[8..10]) != internal_port {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "NAT-PMP response for another port",
+        ));
+    }
+    let ext_port = BigEndian::read_u16(&response[10..12]);
+    let lease = Duration::from_secs(u64::from(BigEndian::read_u32(&response[12..16])));
+    Ok((ext_port, lease))
+}
+
+/// Our default IPv4 gateway, from the routing table of the kernel. Only known on Linux.
+#[cfg(all(feature = "nat-traversal", target_os = "linux"))]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let mut table = String::new();
+    let _ = File::open("/proc/net/route")
+        .ok()?
+        .read_to_string(&mut table)
+        .ok()?;
+    parse_default_gateway(&table)
+}
+
+#[cfg(all(feature = "nat-traversal", not(target_os = "linux")))]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// Finds the gateway of the default route in a routing table in the format of `/proc/net/route`:
+/// a line of headings, then a route per line starting with its interface, destination, gateway
+/// and flags, the addresses in hex as the bytes in network order read in native order.
+#[cfg(all(feature = "nat-traversal", any(target_os = "linux", test)))]
+fn parse_default_gateway(table: &str) -> Option<Ipv4Addr> {
+    const RTF_UP: u32 = 0x1;
+    const RTF_GATEWAY: u32 = 0x2;
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[1] != "00000000" {
+                return None;
+            }
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            let flags = u32::from_str_radix(fields[3], 16).ok()?;
+            if flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+                return None;
+            }
+            Some(Ipv4Addr::from(u32::from_be(gateway)))
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::thread;
+
+    /// Answers the two requests of a mapping as a gateway mapping port 5483 to 41000 would.
+    fn fake_gateway(result_code: u16) -> (NatPmpGateway, thread::JoinHandle<()>) {
+        let socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = match unwrap!(socket.local_addr()) {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 16];
+            let (len, peer) = unwrap!(socket.recv_from(&mut buf));
+            assert_eq!(&buf[..len], &[VERSION, OP_EXTERNAL_ADDRESS]);
+            let mut response = [0; 12];
+            response[1] = OP_EXTERNAL_ADDRESS + RESPONSE_BIT;
+            response[8..12].copy_from_slice(&[203, 0, 113, 7]);
+            let _ = unwrap!(socket.send_to(&response, peer));
+
+            let (len, peer) = unwrap!(socket.recv_from(&mut buf));
+            assert_eq!(
+                &buf[..len],
+                &encode_mapping_request(5483, 5483, Duration::from_secs(3600))[..]
+            );
+            let mut response = [0; 16];
+            response[1] = OP_MAP_TCP + RESPONSE_BIT;
+            BigEndian::write_u16(&mut response[2..4], result_code);
+            BigEndian::write_u16(&mut response[8..10], 5483);
+            BigEndian::write_u16(&mut response[10..12], 41000);
+            BigEndian::write_u32(&mut response[12..16], 1800);
+            let _ = unwrap!(socket.send_to(&response, peer));
+        });
+        (NatPmpGateway { addr }, handle)
+    }
+
+    #[test]
+    fn map_through_gateway() {
+        let (gateway, handle) = fake_gateway(0);
+        let (ext_addr, lease) = unwrap!(gateway.map_tcp(5483, 5483, Duration::from_secs(3600)));
+        assert_eq!(ext_addr, unwrap!("203.0.113.7:41000".parse()));
+        // The gateway may grant a shorter lease than asked for.
+        assert_eq!(lease, Duration::from_secs(1800));
+        unwrap!(handle.join());
+    }
+
+    #[test]
+    fn refused_mapping() {
+        let (gateway, handle) = fake_gateway(2);
+        let res = gateway.map_tcp(5483, 5483, Duration::from_secs(3600));
+        assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::Other);
+        unwrap!(handle.join());
+    }
+
+    #[test]
+    fn silent_gateway() {
+        // Bound, but never answers.
+        let socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = match unwrap!(socket.local_addr()) {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let gateway = NatPmpGateway { addr };
+        let res = gateway.map_tcp(5483, 5483, Duration::from_secs(3600));
+        assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[cfg(feature = "nat-traversal")]
+    #[test]
+    fn default_gateway_of_routing_table() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                     eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\n";
+        let gateway = Ipv4Addr::from(u32::from_be(0x0101_A8C0));
+        assert_eq!(parse_default_gateway(table), Some(gateway));
+
+        // A default route without a gateway, as on a point to point link, has none to ask.
+        let table = "Iface\tDestination\tGateway \tFlags\n\
+                     ppp0\t00000000\t00000000\t0001\n";
+        assert_eq!(parse_default_gateway(table), None);
+    }
+}
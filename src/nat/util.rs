@@ -12,13 +12,27 @@ use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub fn new_reusably_bound_tcp_socket(local_addr: &SocketAddr) -> io::Result<TcpBuilder> {
+    let socket = new_reusable_tcp_socket(local_addr)?;
+    let _ = socket.bind(local_addr)?;
+
+    Ok(socket)
+}
+
+/// Like `new_reusably_bound_tcp_socket`, leaving the socket for `local_addr` to be bound later. A
+/// socket for the unspecified IPv6 address is dual-stack, taking IPv4 connections too.
+pub fn new_reusable_tcp_socket(local_addr: &SocketAddr) -> io::Result<TcpBuilder> {
     let socket = match local_addr.ip() {
         IpAddr::V4(..) => TcpBuilder::new_v4()?,
-        IpAddr::V6(..) => TcpBuilder::new_v6()?,
+        IpAddr::V6(ip) => {
+            let socket = TcpBuilder::new_v6()?;
+            if ip.is_unspecified() {
+                let _ = socket.only_v6(false)?;
+            }
+            socket
+        }
     };
     let _ = socket.reuse_address(true)?;
     enable_so_reuseport(&socket)?;
-    let _ = socket.bind(local_addr)?;
 
     Ok(socket)
 }
@@ -56,6 +70,20 @@ pub fn ipv4_addr_is_global(ipv4: &Ipv4Addr) -> bool {
 
 /// A replacement for `Ipv6Addr::is_global` while we wait for that to enter stable.
 pub fn ipv6_addr_is_global(ipv6: &Ipv6Addr) -> bool {
-    // TODO(canndrew): This function is incomplete and may return false-positives.
-    !(ipv6.is_loopback() || ipv6.is_unspecified())
+    let segments = ipv6.segments();
+    !(ipv6.is_loopback()
+        || ipv6.is_unspecified()
+        || ipv6.is_multicast()
+        || ipv6_addr_is_link_local(ipv6)
+        // Unique local addresses, fc00::/7.
+        || segments[0] & 0xfe00 == 0xfc00
+        // Documentation addresses, 2001:db8::/32.
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        || ipv6.to_ipv4().map_or(false, |ipv4| !ipv4_addr_is_global(&ipv4)))
+}
+
+/// Whether `ipv6` is a unicast link-local address, fe80::/10, which can't be connected to without
+/// the interface it belongs to.
+pub fn ipv6_addr_is_link_local(ipv6: &Ipv6Addr) -> bool {
+    ipv6.segments()[0] & 0xffc0 == 0xfe80
 }
@@ -0,0 +1,828 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::ServiceDiscoveryError;
+use common::{self, Core, CoreTimer, State, WireFormat};
+use maidsafe_utilities::serialisation::{deserialise, serialise_into};
+use mio::udp::UdpSocket;
+use mio::{Poll, PollOpt, Ready, Token};
+use rand;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::u16;
+
+/// Time to wait before rebuilding the socket after an error, doubling with each failed attempt up
+/// to `REBUILD_BACKOFF_MAX_MS`.
+#[cfg(not(test))]
+const REBUILD_BACKOFF_MIN_MS: u64 = 1_000;
+#[cfg(test)]
+const REBUILD_BACKOFF_MIN_MS: u64 = 500;
+#[cfg(not(test))]
+const REBUILD_BACKOFF_MAX_MS: u64 = 5 * 60 * 1_000;
+#[cfg(test)]
+const REBUILD_BACKOFF_MAX_MS: u64 = 1_000;
+/// Attempts to rebuild the socket in any hour. Further ones wait for the oldest to be an hour old.
+const MAX_REBUILDS_PER_HOUR: usize = 20;
+const REBUILD_TIMER_ID: u64 = 0;
+/// Largest datagram processed. Anything larger is no request or response of ours and is dropped
+/// unread.
+const MAX_DATAGRAM_SIZE: usize = 1024;
+/// Datagrams read per readable event, after which the socket is re-registered so that a peer
+/// flooding it can't starve the rest of the event loop.
+const MAX_DATAGRAMS_PER_EVENT: usize = 64;
+/// Dropped datagrams are reported in a single warning at most once in this many seconds.
+const DROP_WARNING_INTERVAL_SECS: u64 = 60;
+
+/// Changes of the health of service discovery, reported to the callback given to
+/// `ServiceDiscovery::start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthChange {
+    /// The socket failed and was closed. Discovery is unavailable until it is rebuilt.
+    Degraded,
+    /// The socket has been rebuilt.
+    Recovered,
+}
+
+/// Datagram broadcast to seek peers on the LAN, and answered by those listening for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiscoveryMsg {
+    Request { guid: u64 },
+    Response(Vec<SocketAddr>),
+}
+
+impl WireFormat for DiscoveryMsg {
+    fn encode_to(&self, out: &mut Vec<u8>) -> common::Result<()> {
+        Ok(serialise_into(self, out)?)
+    }
+
+    fn decode(bytes: &[u8]) -> common::Result<Self> {
+        Ok(deserialise(bytes)?)
+    }
+}
+
+pub struct ServiceDiscovery {
+    token: Token,
+    /// `None` while the socket is being rebuilt after an error.
+    socket: Option<UdpSocket>,
+    /// Port the socket is bound to, which it is rebuilt on.
+    port: u16,
+    remote_addr: SocketAddr,
+    listen: bool,
+    /// One byte larger than `MAX_DATAGRAM_SIZE`, to tell the datagrams which are too large.
+    read_buf: [u8; MAX_DATAGRAM_SIZE + 1],
+    our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    response: CachedResponse,
+    seek_peers_req: Vec<u8>,
+    reply_to: VecDeque<SocketAddr>,
+    observers: Vec<Sender<Vec<SocketAddr>>>,
+    guid: u64,
+    on_health: Box<FnMut(HealthChange)>,
+    rebuilds: RebuildSchedule,
+    drops: DropLog,
+}
+
+impl ServiceDiscovery {
+    /// Starts service discovery on the first free port from `port` up. Errors of its socket later
+    /// on are handled by rebuilding it, and reported to `on_health`.
+    pub fn start(
+        core: &mut Core,
+        poll: &Poll,
+        our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+        token: Token,
+        port: u16,
+        on_health: Box<FnMut(HealthChange)>,
+    ) -> Result<(), ServiceDiscoveryError> {
+        let udp_socket = get_socket(port)?;
+        udp_socket.set_broadcast(true)?;
+        let bound_port = udp_socket.local_addr()?.port();
+        poll.register(
+            &udp_socket,
+            token,
+            Ready::error() | Ready::hup() | Ready::readable(),
+            PollOpt::edge(),
+        )?;
+
+        let guid = rand::random();
+        let mut seek_peers_req = Vec::new();
+        DiscoveryMsg::Request { guid }.encode_to(&mut seek_peers_req)?;
+        let remote_addr = SocketAddr::from_str(&format!("255.255.255.255:{}", port))?;
+
+        let service_discovery = ServiceDiscovery {
+            token,
+            socket: Some(udp_socket),
+            port: bound_port,
+            remote_addr,
+            listen: false,
+            read_buf: [0; MAX_DATAGRAM_SIZE + 1],
+            our_listeners,
+            response: CachedResponse::default(),
+            seek_peers_req,
+            reply_to: VecDeque::new(),
+            observers: Vec::new(),
+            guid,
+            on_health,
+            rebuilds: Default::default(),
+            drops: Default::default(),
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(service_discovery)));
+
+        Ok(())
+    }
+
+    /// Enable/disable listening and responding to peers searching for us. This will allow others
+    /// finding us by interrogating the network.
+    pub fn set_listen(&mut self, listen: bool) {
+        self.listen = listen;
+    }
+
+    /// Interrogate the network to find peers. Fails while the socket is being rebuilt.
+    pub fn seek_peers(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+    ) -> Result<(), ServiceDiscoveryError> {
+        let res = match self.socket {
+            Some(ref socket) => socket.send_to(&self.seek_peers_req, &self.remote_addr),
+            None => return Err(ServiceDiscoveryError::Unavailable),
+        };
+        if let Err(e) = res {
+            self.handle_socket_error(core, poll, &e);
+            return Err(From::from(e));
+        }
+        Ok(())
+    }
+
+    /// Register service discovery observer
+    pub fn register_observer(&mut self, obs: Sender<Vec<SocketAddr>>) {
+        self.observers.push(obs);
+    }
+
+    /// Reads the datagrams which arrived. Those which aren't a valid request or response are
+    /// counted in `CoreStats` and dropped: on a hostile LAN nothing a peer sends may take discovery
+    /// down or flood the log.
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        for _ in 0..MAX_DATAGRAMS_PER_EVENT {
+            let res = match self.socket {
+                Some(ref socket) => socket.recv_from(&mut self.read_buf),
+                None => return,
+            };
+            match res {
+                Ok(Some((bytes_rxd, peer_addr))) => {
+                    self.handle_datagram(core, poll, bytes_rxd, peer_addr)
+                }
+                Ok(None) => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(ref e) if is_oversized(e) => self.dropped(core, DropReason::Oversized),
+                Err(e) => return self.handle_socket_error(core, poll, &e),
+            }
+        }
+        // Re-registering raises another readable event for the datagrams left.
+        if let Err(e) = self.reregister(poll) {
+            self.handle_socket_error(core, poll, &e);
+        }
+    }
+
+    fn handle_datagram(
+        &mut self,
+        core: &mut Core,
+        poll: &Poll,
+        bytes_rxd: usize,
+        peer_addr: SocketAddr,
+    ) {
+        if bytes_rxd > MAX_DATAGRAM_SIZE {
+            trace!("Dropping oversized discovery datagram from {}", peer_addr);
+            return self.dropped(core, DropReason::Oversized);
+        }
+        let decoded = DiscoveryMsg::decode(&self.read_buf[..bytes_rxd]);
+        let msg = match decoded {
+            Ok(msg) => msg,
+            Err(e) => {
+                trace!("Dropping malformed discovery datagram from {}: {:?}", peer_addr, e);
+                return self.dropped(core, DropReason::Malformed);
+            }
+        };
+
+        match msg {
+            DiscoveryMsg::Request { guid } => {
+                if self.listen && self.guid != guid {
+                    self.reply_to.push_back(peer_addr);
+                    self.write(core, poll)
+                }
+            }
+            DiscoveryMsg::Response(peer_listeners) => {
+                if !peer_listeners.iter().all(is_listener_addr) {
+                    trace!("Dropping discovery response from {}: {:?}", peer_addr, peer_listeners);
+                    return self.dropped(core, DropReason::Malformed);
+                }
+                self.observers
+                    .retain(|obs| obs.send(peer_listeners.clone()).is_ok());
+            }
+        }
+    }
+
+    fn dropped(&mut self, core: &mut Core, reason: DropReason) {
+        match reason {
+            DropReason::Malformed => core.stats_mut().discovery_malformed_dropped += 1,
+            DropReason::Oversized => core.stats_mut().discovery_oversized_dropped += 1,
+        }
+        if let Some(dropped) = self.drops.dropped(core.now()) {
+            warn!(
+                "ServiceDiscovery on port {} dropped {} malformed or oversized datagrams",
+                self.port, dropped
+            );
+        }
+    }
+
+    fn write(&mut self, core: &mut Core, poll: &Poll) {
+        if let Err(e) = self.write_impl(poll) {
+            self.handle_socket_error(core, poll, &e);
+        }
+    }
+
+    fn write_impl(&mut self, poll: &Poll) -> io::Result<()> {
+        if let Err(e) = self.response.refresh(&unwrap!(self.our_listeners.lock())) {
+            debug!("Could not serialise our listeners: {:?}", e);
+            return Ok(());
+        }
+        let socket = match self.socket {
+            Some(ref socket) => socket,
+            None => return Ok(()),
+        };
+
+        if let Some(peer_addr) = self.reply_to.pop_front() {
+            match socket.send_to(&self.response.bytes, &peer_addr) {
+                // UDP is all or none so if anything is written we consider it written
+                Ok(Some(_)) => (),
+                Ok(None) => self.reply_to.push_front(peer_addr),
+                Err(ref e)
+                    if e.kind() == ErrorKind::Interrupted || e.kind() == ErrorKind::WouldBlock =>
+                {
+                    self.reply_to.push_front(peer_addr)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.reregister(poll)
+    }
+
+    fn reregister(&self, poll: &Poll) -> io::Result<()> {
+        let socket = match self.socket {
+            Some(ref socket) => socket,
+            None => return Ok(()),
+        };
+        let kind = if self.reply_to.is_empty() {
+            Ready::error() | Ready::hup() | Ready::readable()
+        } else {
+            Ready::error() | Ready::hup() | Ready::readable() | Ready::writable()
+        };
+
+        poll.reregister(socket, self.token, kind, PollOpt::edge())
+    }
+
+    /// Closes the failed socket and schedules rebuilding it, rather than giving up on discovery.
+    fn handle_socket_error(&mut self, core: &mut Core, poll: &Poll, error: &io::Error) {
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => return,
+        };
+        warn!(
+            "ServiceDiscovery socket on port {} failed, rebuilding it: {:?}",
+            self.port, error
+        );
+        let _ = poll.deregister(&socket);
+        self.reply_to.clear();
+        (self.on_health)(HealthChange::Degraded);
+        self.schedule_rebuild(core, poll);
+    }
+
+    /// Simulates an error of the socket.
+    #[cfg(test)]
+    pub fn fail_socket(&mut self, core: &mut Core, poll: &Poll) {
+        let error = io::Error::new(ErrorKind::Other, "simulated failure");
+        self.handle_socket_error(core, poll, &error);
+    }
+
+    fn schedule_rebuild(&mut self, core: &mut Core, poll: &Poll) {
+        let delay = self.rebuilds.next_delay(core.now());
+        let timer = CoreTimer::new(self.token, REBUILD_TIMER_ID);
+        if let Err(e) = core.set_timeout(delay, timer) {
+            warn!("Could not schedule rebuilding ServiceDiscovery: {:?}", e);
+            self.terminate(core, poll);
+        }
+    }
+
+    fn rebuild_socket(&mut self, poll: &Poll) -> Result<(), ServiceDiscoveryError> {
+        let bind_addr = SocketAddr::from_str(&format!("0.0.0.0:{}", self.port))?;
+        let socket = UdpSocket::bind(&bind_addr)?;
+        socket.set_broadcast(true)?;
+        poll.register(
+            &socket,
+            self.token,
+            Ready::error() | Ready::hup() | Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+}
+
+impl State for ServiceDiscovery {
+    fn name(&self) -> &'static str {
+        "ServiceDiscovery"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            let error = io::Error::new(ErrorKind::Other, format!("socket reported {:?}", kind));
+            self.handle_socket_error(core, poll, &error);
+        } else {
+            if kind.is_readable() {
+                self.read(core, poll);
+            }
+            if kind.is_writable() {
+                self.write(core, poll);
+            }
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u64) {
+        self.rebuilds.attempted(core.now());
+        match self.rebuild_socket(poll) {
+            Ok(()) => {
+                info!("ServiceDiscovery socket rebuilt on port {}", self.port);
+                self.rebuilds.succeeded();
+                (self.on_health)(HealthChange::Recovered);
+            }
+            Err(e) => {
+                debug!("Could not rebuild ServiceDiscovery socket: {:?}", e);
+                self.schedule_rebuild(core, poll);
+            }
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(socket) = self.socket.take() {
+            let _ = poll.deregister(&socket);
+        }
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Our response to requests, serialised once for as long as our listeners stay the same. They are
+/// compared with those of the response for each request rather than telling it of their changes,
+/// as the list is shared with everything which adds or removes listeners. Responding to a request
+/// then takes no allocation.
+#[derive(Default)]
+struct CachedResponse {
+    msg: Option<DiscoveryMsg>,
+    bytes: Vec<u8>,
+    /// Times the response was serialised.
+    encodes: u64,
+}
+
+impl CachedResponse {
+    fn refresh(&mut self, listeners: &[SocketAddr]) -> common::Result<()> {
+        if let Some(DiscoveryMsg::Response(ref cached)) = self.msg {
+            if cached[..] == *listeners {
+                return Ok(());
+            }
+        }
+        self.msg = None;
+        self.bytes.clear();
+        self.encodes += 1;
+        let msg = DiscoveryMsg::Response(listeners.to_vec());
+        msg.encode_to(&mut self.bytes)?;
+        self.msg = Some(msg);
+        Ok(())
+    }
+}
+
+/// Attempts to rebuild the socket after errors, backing off exponentially and making at most
+/// `MAX_REBUILDS_PER_HOUR` attempts in any hour.
+#[derive(Default)]
+struct RebuildSchedule {
+    /// Attempts failed since the socket last worked.
+    failures: u32,
+    /// Times of the attempts made in the last hour, oldest first.
+    attempts: VecDeque<Instant>,
+}
+
+impl RebuildSchedule {
+    /// Time to wait before the next attempt.
+    fn next_delay(&mut self, now: Instant) -> Duration {
+        let hour = Duration::from_secs(3600);
+        while self
+            .attempts
+            .front()
+            .map_or(false, |attempt| now - *attempt >= hour)
+        {
+            let _ = self.attempts.pop_front();
+        }
+        let backoff = Duration::from_millis(cmp::min(
+            REBUILD_BACKOFF_MIN_MS << cmp::min(self.failures, 16),
+            REBUILD_BACKOFF_MAX_MS,
+        ));
+        if self.attempts.len() < MAX_REBUILDS_PER_HOUR {
+            return backoff;
+        }
+        let hour_over = self.attempts[0] + hour - now;
+        cmp::max(backoff, hour_over)
+    }
+
+    fn attempted(&mut self, now: Instant) {
+        self.attempts.push_back(now);
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Why a datagram was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DropReason {
+    Malformed,
+    Oversized,
+}
+
+/// Counts the dropped datagrams to report them in a warning at most every
+/// `DROP_WARNING_INTERVAL_SECS`, however fast they arrive.
+#[derive(Default)]
+struct DropLog {
+    /// Datagrams dropped since the last warning.
+    unreported: u64,
+    last_warning: Option<Instant>,
+}
+
+impl DropLog {
+    /// Counts a datagram dropped at `now`. Returns the number to warn of if a warning is due.
+    fn dropped(&mut self, now: Instant) -> Option<u64> {
+        self.unreported += 1;
+        let interval = Duration::from_secs(DROP_WARNING_INTERVAL_SECS);
+        if self.last_warning.map_or(false, |last| now - last < interval) {
+            return None;
+        }
+        self.last_warning = Some(now);
+        Some(mem::replace(&mut self.unreported, 0))
+    }
+}
+
+/// Whether a peer could be listening on `addr`, as a response claims.
+fn is_listener_addr(addr: &SocketAddr) -> bool {
+    let ip = addr.ip();
+    let broadcast = match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_multicast() && !broadcast
+}
+
+/// Whether receiving failed as the datagram was larger than the buffer. Only Windows tells so,
+/// elsewhere the datagram is truncated to the buffer silently.
+#[cfg(windows)]
+fn is_oversized(e: &io::Error) -> bool {
+    const WSAEMSGSIZE: i32 = 10_040;
+    e.raw_os_error() == Some(WSAEMSGSIZE)
+}
+
+#[cfg(not(windows))]
+fn is_oversized(_e: &io::Error) -> bool {
+    false
+}
+
+fn get_socket(mut port: u16) -> Result<UdpSocket, ServiceDiscoveryError> {
+    let mut res;
+    loop {
+        let bind_addr = SocketAddr::from_str(&format!("0.0.0.0:{}", port))?;
+        res = UdpSocket::bind(&bind_addr).map_err(From::from);
+        if res.is_ok() || port == u16::MAX {
+            break;
+        }
+        port += 1;
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{self, CoreMessage};
+    use maidsafe_utilities;
+    use mio::Token;
+    use std::str::FromStr;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use std::{net, thread};
+
+    #[test]
+    fn service_discovery() {
+        const SERVICE_DISCOVERY_TOKEN: usize = 0;
+
+        // Poll-0
+        let el0 = unwrap!(
+            common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL0")),
+            "Could not run el0"
+        );
+
+        let addr = unwrap!(net::SocketAddr::from_str("138.139.140.150:54321"));
+        let listeners_0 = Arc::new(Mutex::new(vec![addr]));
+        let listeners_0_clone = listeners_0.clone();
+
+        // ServiceDiscovery-0
+        {
+            let token_0 = Token(SERVICE_DISCOVERY_TOKEN);
+            unwrap!(
+                el0.send(CoreMessage::new(move |core, poll| {
+                    unwrap!(
+                        ServiceDiscovery::start(
+                            core,
+                            poll,
+                            listeners_0_clone,
+                            token_0,
+                            65_530,
+                            Box::new(|_| ()),
+                        ),
+                        "Could not spawn ServiceDiscovery_0"
+                    );
+                })),
+                "Could not send to el0"
+            );
+
+            // Start listening for peers
+            unwrap!(el0.send(CoreMessage::new(move |core, _| {
+                let state = unwrap!(core.get_state(token_0));
+                let mut inner = state.borrow_mut();
+                unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>()).set_listen(true);
+            })));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+
+        // Poll-1
+        let el1 = unwrap!(
+            common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL1")),
+            "Could not run el1"
+        );
+
+        let (tx, rx) = mpsc::channel();
+
+        // ServiceDiscovery-1
+        {
+            let listeners_1 = Arc::new(Mutex::new(vec![]));
+            let token_1 = Token(SERVICE_DISCOVERY_TOKEN);
+            unwrap!(
+                el1.send(CoreMessage::new(move |core, poll| {
+                    unwrap!(
+                        ServiceDiscovery::start(
+                            core,
+                            poll,
+                            listeners_1,
+                            token_1,
+                            65_530,
+                            Box::new(|_| ()),
+                        ),
+                        "Could not spawn ServiceDiscovery_1"
+                    );
+                })),
+                "Could not send to el1"
+            );
+
+            // Register observer
+            unwrap!(el1.send(CoreMessage::new(move |core, _| {
+                let state = unwrap!(core.get_state(token_1));
+                let mut inner = state.borrow_mut();
+                unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>()).register_observer(tx);
+            })));
+
+            // Seek peers
+            unwrap!(
+                el1.send(CoreMessage::new(move |core, poll| {
+                    let state = unwrap!(core.get_state(token_1));
+                    let mut inner = state.borrow_mut();
+                    let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>());
+                    unwrap!(sd.seek_peers(core, poll));
+                })),
+                "Could not send to el1"
+            );
+        }
+
+        let peer_listeners = unwrap!(rx.recv_timeout(Duration::from_secs(30)));
+        assert_eq!(
+            peer_listeners.into_iter().collect::<Vec<_>>(),
+            *unwrap!(listeners_0.lock())
+        );
+
+        // The response cached by the first one reflects a change of listeners straight away.
+        let addr = unwrap!(net::SocketAddr::from_str("138.139.140.151:54322"));
+        unwrap!(listeners_0.lock()).push(addr);
+        unwrap!(el1.send(CoreMessage::new(move |core, poll| {
+            let state = unwrap!(core.get_state(Token(SERVICE_DISCOVERY_TOKEN)));
+            let mut inner = state.borrow_mut();
+            let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>());
+            unwrap!(sd.seek_peers(core, poll));
+        })));
+        let peer_listeners = unwrap!(rx.recv_timeout(Duration::from_secs(30)));
+        assert_eq!(peer_listeners, *unwrap!(listeners_0.lock()));
+    }
+
+    #[test]
+    fn response_is_serialised_once_per_change_of_listeners() {
+        let addr = |port| net::SocketAddr::new(net::IpAddr::from([10, 0, 0, 1]), port);
+        let mut listeners = vec![addr(1000), addr(1001)];
+        let mut response = CachedResponse::default();
+
+        unwrap!(response.refresh(&listeners));
+        let buffer = response.bytes.as_ptr();
+        for _ in 0..10 {
+            unwrap!(response.refresh(&listeners));
+        }
+        assert_eq!(response.encodes, 1);
+        assert_eq!(response.bytes.as_ptr(), buffer);
+
+        listeners.pop();
+        unwrap!(response.refresh(&listeners));
+        assert_eq!(response.encodes, 2);
+        // Fewer listeners fit in the same buffer.
+        assert_eq!(response.bytes.as_ptr(), buffer);
+        assert_eq!(
+            unwrap!(DiscoveryMsg::decode(&response.bytes)),
+            DiscoveryMsg::Response(listeners)
+        );
+    }
+
+    // Compares serialising a response per request, as before it was cached, with the cache, and
+    // logs both. Run with
+    // `RUST_LOG=crust=info cargo test --release response_benchmark -- --ignored`.
+    #[test]
+    #[ignore]
+    fn response_benchmark() {
+        let _ = maidsafe_utilities::log::init(true);
+        const RESPONSES: u64 = 1_000_000;
+        let listeners: Vec<_> = (0..8)
+            .map(|i| net::SocketAddr::new(net::IpAddr::from([192, 168, 1, i]), 5483))
+            .collect();
+        let ns_per_response = |started: Instant| {
+            let elapsed = started.elapsed();
+            (elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())) as f64
+                / RESPONSES as f64
+        };
+
+        let started = Instant::now();
+        let mut bytes = 0;
+        for _ in 0..RESPONSES {
+            let mut serialised = Vec::new();
+            unwrap!(DiscoveryMsg::Response(listeners.clone()).encode_to(&mut serialised));
+            bytes += serialised.len();
+        }
+        let before = ns_per_response(started);
+
+        let mut response = CachedResponse::default();
+        let started = Instant::now();
+        for _ in 0..RESPONSES {
+            unwrap!(response.refresh(&listeners));
+            bytes -= response.bytes.len();
+        }
+        let after = ns_per_response(started);
+
+        assert_eq!(bytes, 0);
+        info!(
+            "Serialised per response: {:.1} ns with 2 allocations or more. Cached: {:.1} ns, \
+             serialised {} times in {} responses",
+            before, after, response.encodes, RESPONSES
+        );
+    }
+
+    #[test]
+    fn rebuilds_back_off_and_are_rate_limited() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut rebuilds = RebuildSchedule::default();
+        assert_eq!(rebuilds.next_delay(start), ms(REBUILD_BACKOFF_MIN_MS));
+        rebuilds.attempted(start);
+        assert_eq!(rebuilds.next_delay(start), ms(2 * REBUILD_BACKOFF_MIN_MS));
+        for _ in 0..40 {
+            rebuilds.attempted(start);
+        }
+        rebuilds.succeeded();
+        let hour = Duration::from_secs(3600);
+        // The attempts of the last hour are used up, however recently the socket worked.
+        assert_eq!(rebuilds.next_delay(start + ms(10)), hour - ms(10));
+        assert_eq!(rebuilds.next_delay(start + hour), ms(REBUILD_BACKOFF_MIN_MS));
+    }
+
+    #[test]
+    fn bad_datagrams_are_dropped_while_requests_are_answered() {
+        const SERVICE_DISCOVERY_TOKEN: usize = 0;
+        const ROUNDS: u64 = 20;
+        let token = Token(SERVICE_DISCOVERY_TOKEN);
+        let el = unwrap!(common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL")));
+        let listeners = vec![unwrap!(net::SocketAddr::from_str("138.139.140.150:54321"))];
+        let our_listeners = Arc::new(Mutex::new(listeners.clone()));
+
+        let (port_tx, port_rx) = mpsc::channel();
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            unwrap!(ServiceDiscovery::start(
+                core,
+                poll,
+                our_listeners,
+                token,
+                65_520,
+                Box::new(|_| ()),
+            ));
+            let state = unwrap!(core.get_state(token));
+            let mut inner = state.borrow_mut();
+            let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>());
+            sd.set_listen(true);
+            unwrap!(port_tx.send(sd.port));
+        })));
+        let port = unwrap!(port_rx.recv_timeout(Duration::from_secs(30)));
+        let discovery_addr = net::SocketAddr::new(net::IpAddr::from([127, 0, 0, 1]), port);
+
+        let mut request = Vec::new();
+        unwrap!(DiscoveryMsg::Request { guid: 1 }.encode_to(&mut request));
+        let mut bogus_response = Vec::new();
+        let unspecified = unwrap!(net::SocketAddr::from_str("0.0.0.0:0"));
+        unwrap!(DiscoveryMsg::Response(vec![unspecified]).encode_to(&mut bogus_response));
+        let mut oversized = request.clone();
+        oversized.resize(4 * MAX_DATAGRAM_SIZE, 0);
+
+        let client = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        unwrap!(client.set_read_timeout(Some(Duration::from_secs(10))));
+        let mut buf = [0; 2 * MAX_DATAGRAM_SIZE];
+        for _ in 0..ROUNDS {
+            for len in &[1, 100, MAX_DATAGRAM_SIZE] {
+                let random: Vec<u8> = (0..*len).map(|_| rand::random()).collect();
+                let _ = unwrap!(client.send_to(&random, discovery_addr));
+            }
+            let _ = unwrap!(client.send_to(&request[..request.len() - 1], discovery_addr));
+            let _ = unwrap!(client.send_to(&bogus_response, discovery_addr));
+            let _ = unwrap!(client.send_to(&oversized, discovery_addr));
+
+            // The valid request which followed is answered all the same.
+            let _ = unwrap!(client.send_to(&request, discovery_addr));
+            let (len, from) = unwrap!(client.recv_from(&mut buf));
+            assert_eq!(from.port(), port);
+            assert_eq!(
+                unwrap!(DiscoveryMsg::decode(&buf[..len])),
+                DiscoveryMsg::Response(listeners.clone())
+            );
+        }
+
+        let (tx, rx) = mpsc::channel();
+        unwrap!(el.send(CoreMessage::new(move |core, _| {
+            let dropped = {
+                let stats = core.stats();
+                (stats.discovery_malformed_dropped, stats.discovery_oversized_dropped)
+            };
+            let state = unwrap!(core.get_state(token));
+            let mut inner = state.borrow_mut();
+            let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>());
+            unwrap!(tx.send((dropped, sd.socket.is_some(), sd.drops.unreported)));
+        })));
+        let ((malformed, oversized), socket_open, unreported) =
+            unwrap!(rx.recv_timeout(Duration::from_secs(30)));
+        assert_eq!(malformed, 5 * ROUNDS);
+        assert_eq!(oversized, ROUNDS);
+        assert!(socket_open);
+        // A single warning was logged, for the first datagram dropped.
+        assert_eq!(unreported, 6 * ROUNDS - 1);
+    }
+
+    #[test]
+    fn dropped_datagrams_are_warned_of_once_a_minute() {
+        let start = Instant::now();
+        let mut drops = DropLog::default();
+        assert_eq!(drops.dropped(start), Some(1));
+        for ms in 1..1_000 {
+            assert_eq!(drops.dropped(start + Duration::from_millis(ms)), None);
+        }
+        let minute = Duration::from_secs(DROP_WARNING_INTERVAL_SECS);
+        assert_eq!(drops.dropped(start + minute), Some(1_000));
+        assert_eq!(drops.dropped(start + minute), None);
+    }
+}
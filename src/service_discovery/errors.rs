@@ -7,6 +7,7 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use common::CommonError;
 use maidsafe_utilities::serialisation::SerialisationError;
 use std::io;
 use std::net::AddrParseError;
@@ -29,5 +30,13 @@ quick_error! {
             display("Serialisation error during service discovery: {}", e)
             from()
         }
+        Common(e: CommonError) {
+            description("Encoding error during service discovery")
+            display("Encoding error during service discovery: {}", e)
+            from()
+        }
+        Unavailable {
+            description("Service discovery socket is being rebuilt after an error")
+        }
     }
 }
@@ -0,0 +1,261 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Scaffolding for tests of applications built on crust, enabled by the `test-utils` feature:
+//! pairs of services connected over loopback, waits for events with deadlines and readable
+//! failures, payload generators and a teardown which checks that no connection was leaked.
+//!
+//! Every wait gives up after 30 seconds, or as many as the `CRUST_TEST_TIMEOUT_SECS` environment
+//! variable says, for slow machines.
+
+use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
+use rand::{self, Rand, Rng};
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use {Config, CrustUser, Event, Service, Uid};
+
+/// Environment variable overriding the time, in seconds, any one wait of a test may take.
+pub const TIMEOUT_ENV_VAR: &str = "CRUST_TEST_TIMEOUT_SECS";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Kinds of state which live as long as their service rather than a connection, as named in
+/// `CoreStats::states`.
+pub const SERVICE_STATES: &[&str] = &[
+    "CacheWriter",
+    "ConfigRefresher",
+    "ConnectionListener",
+    "HeartbeatIntervals",
+    "InterfaceMonitor",
+    "PathHistory",
+    "ReachabilityChecks",
+    "Reconnects",
+    "RelayState",
+    "RetainedQueues",
+    "Retirement",
+    "ServiceDiscovery",
+    "SuspendMonitor",
+    "UtpEndpoint",
+    "WsEndpoint",
+];
+
+/// Waits for the next event on `$rx` and matches it against `$pattern`, evaluating to `$arm` if it
+/// matches. Panics, naming the pattern, if another event arrives or none does within
+/// `test_utils::timeout()`.
+#[macro_export]
+macro_rules! expect_crust_event {
+    ($rx:expr, $pattern:pat) => {
+        expect_crust_event!($rx, $pattern => ())
+    };
+
+    ($rx:expr, $pattern:pat => $arm:expr) => {
+        match $crate::test_utils::next_event(&$rx, stringify!($pattern)) {
+            $pattern => $arm,
+            event => panic!("Expected {} but got {:?}", stringify!($pattern), event),
+        }
+    };
+}
+
+/// Time a test waits for any one thing to happen, see `TIMEOUT_ENV_VAR`.
+pub fn timeout() -> Duration {
+    let secs = env::var(TIMEOUT_ENV_VAR)
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Returns the next event from `rx`. Panics, saying what was expected, if none arrives in time.
+pub fn next_event<UID: Uid>(rx: &Receiver<Event<UID>>, expected: &str) -> Event<UID> {
+    let timeout = timeout();
+    match rx.recv_timeout(timeout) {
+        Ok(event) => event,
+        Err(RecvTimeoutError::Timeout) => {
+            panic!("Expected {} but got no event within {:?}", expected, timeout)
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            panic!("Expected {} but the service is gone", expected)
+        }
+    }
+}
+
+/// Skips events until one for which `f` returns `Some`, and returns what it returned. Panics,
+/// saying what was expected, if none arrives in time.
+pub fn wait_for_event<UID, T, F>(rx: &Receiver<Event<UID>>, expected: &str, mut f: F) -> T
+where
+    UID: Uid,
+    F: FnMut(Event<UID>) -> Option<T>,
+{
+    let deadline = Instant::now() + timeout();
+    loop {
+        let now = Instant::now();
+        let left = if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        };
+        match rx.recv_timeout(left) {
+            Ok(event) => if let Some(res) = f(event) {
+                return res;
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                panic!("Expected {} but it didn't come within {:?}", expected, timeout())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                panic!("Expected {} but the service is gone", expected)
+            }
+        }
+    }
+}
+
+/// Returns an event sender to create a service with, and the receiving end of its events.
+pub fn event_channel<UID: Uid>() -> (::CrustEventSender<UID>, Receiver<Event<UID>>) {
+    let (category_tx, _) = mpsc::channel();
+    let (event_tx, event_rx) = mpsc::channel();
+    (
+        MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx),
+        event_rx,
+    )
+}
+
+/// Returns the default config, with a bootstrap cache of its own so that tests running at the same
+/// time don't bootstrap off each other's peers.
+pub fn test_config() -> Config {
+    let mut config = Config::default();
+    config.bootstrap_cache_name = Some(format!(
+        "crust-test-{:016x}.bootstrap.cache",
+        rand::random::<u64>()
+    ));
+    config
+}
+
+/// Returns `len` random bytes.
+pub fn random_payload(len: usize) -> Vec<u8> {
+    rand::thread_rng().gen_iter().take(len).collect()
+}
+
+/// Returns `count` payloads of `len` bytes, each filled with a pattern of its own so that a
+/// mix-up or a corrupted byte shows.
+pub fn numbered_payloads(count: usize, len: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|n| (0..len).map(|i| (n.wrapping_mul(31) ^ i) as u8).collect())
+        .collect()
+}
+
+/// Two services on loopback: the first listens and the second is bootstrapped off it.
+pub struct ServicePair<UID: Uid> {
+    /// The service which listens.
+    pub service0: Service<UID>,
+    /// The events of `service0` not taken yet.
+    pub events0: Receiver<Event<UID>>,
+    /// The service bootstrapped off `service0`.
+    pub service1: Service<UID>,
+    /// The events of `service1` not taken yet.
+    pub events1: Receiver<Event<UID>>,
+}
+
+impl<UID: Uid + Rand> ServicePair<UID> {
+    /// Connects two services with `test_config`, the second as a client.
+    pub fn new() -> Self {
+        Self::with_configs(test_config(), test_config(), CrustUser::Client)
+    }
+
+    /// Connects two services with the given configs, the second bootstrapping as `role`. The
+    /// hard-coded contacts of the second are replaced with the listener of the first.
+    pub fn with_configs(config0: Config, config1: Config, role: CrustUser) -> Self {
+        let (event_tx0, events0) = event_channel();
+        let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+        unwrap!(service0.start_listening_tcp());
+        let port = expect_crust_event!(events0, Event::ListenerStarted(port) => port);
+        unwrap!(service0.set_accept_bootstrap(true));
+
+        let mut config1 = config1;
+        config1.hard_coded_contacts = vec![SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port,
+        )];
+        let (event_tx1, events1) = event_channel();
+        let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+        unwrap!(service1.start_bootstrap(Default::default(), role));
+
+        let id0 = expect_crust_event!(events1, Event::BootstrapConnect(id, _, _) => id);
+        assert_eq!(id0, service0.id());
+        let id1 = expect_crust_event!(events0, Event::BootstrapAccept(id, _, _) => id);
+        assert_eq!(id1, service1.id());
+
+        ServicePair {
+            service0,
+            events0,
+            service1,
+            events1,
+        }
+    }
+}
+
+impl<UID: Uid + Rand> Default for ServicePair<UID> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<UID: Uid> ServicePair<UID> {
+    /// The id of `service0`.
+    pub fn id0(&self) -> UID {
+        self.service0.id()
+    }
+
+    /// The id of `service1`.
+    pub fn id1(&self) -> UID {
+        self.service1.id()
+    }
+
+    /// Disconnects the services and checks that both lose each other, then that neither event loop
+    /// is left with any state of a connection. Events not seen yet are skipped.
+    pub fn teardown(self) {
+        let (id0, id1) = (self.id0(), self.id1());
+        assert!(
+            self.service1.disconnect(&id0),
+            "The services were no longer connected"
+        );
+        wait_for_event(&self.events1, "the first service to be lost", |event| match event {
+            Event::LostPeer(id, ..) if id == id0 => Some(()),
+            _ => None,
+        });
+        wait_for_event(&self.events0, "the second service to be lost", |event| match event {
+            Event::LostPeer(id, ..) if id == id1 => Some(()),
+            _ => None,
+        });
+        assert_no_connection_states(&self.service0);
+        assert_no_connection_states(&self.service1);
+    }
+}
+
+/// Waits for the event loop of `service` to be left with nothing but the states it always runs.
+/// Panics, listing the others, if they don't go in time.
+pub fn assert_no_connection_states<UID: Uid>(service: &Service<UID>) {
+    let deadline = Instant::now() + timeout();
+    loop {
+        let stats = unwrap!(service.core_stats());
+        let leaked: Vec<_> = stats
+            .states
+            .iter()
+            .filter(|&(name, kind)| kind.live > 0 && !SERVICE_STATES.contains(name))
+            .map(|(name, kind)| format!("{} x{}", name, kind.live))
+            .collect();
+        if leaked.is_empty() {
+            return;
+        }
+        if Instant::now() >= deadline {
+            panic!("States left in the event loop: {}", leaked.join(", "));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,622 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Byte-exact fixtures of the wire protocol, for implementations in other languages and as a guard
+//! against changing the wire format by accident. Not part of the public API.
+//!
+//! Each vector has a single fixture, in `test_vectors/v<since>/` for the protocol version it
+//! appeared in: a `<name>.bin` holding the bytes sent and a `<name>.json` describing them. Later
+//! versions send the same bytes, so they don't get a copy. Stream messages are given in their
+//! frame, datagrams as sent. The `test_vectors` example writes the fixtures missing.
+
+use common::{
+    BootstrapDenyReason, Extension, Extensions, ExternalReachability, Frame, Message, PublicKey,
+    Rejection, RejectionCode, RelayControl, Uid, WireFormat, HASH_SIZE, PROTOCOL_VERSION,
+};
+use serde::ser::Serialize;
+use serde_json;
+use service_discovery::DiscoveryMsg;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+struct VectorUid([u8; 20]);
+
+impl Uid for VectorUid {}
+
+/// A named value of a wire structure, and the protocol version it appeared in, whose fixture it is
+/// encoded for.
+struct Vector {
+    name: &'static str,
+    since: u32,
+    description: &'static str,
+    structure: Structure,
+}
+
+enum Structure {
+    /// A message sent over a TCP stream, in its frame.
+    Frame(Frame<Message<VectorUid>>),
+    /// A service discovery datagram.
+    Datagram(DiscoveryMsg),
+    /// A frame a relay sends of its own, in its frame, which follows an empty frame.
+    RelayFrame(Frame<RelayControl>),
+}
+
+#[derive(Serialize)]
+struct Description<'a, T: 'a> {
+    name: &'a str,
+    since: u32,
+    structure: &'a str,
+    description: &'a str,
+    length: usize,
+    hex: String,
+    value: &'a T,
+}
+
+impl Vector {
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let res = match self.structure {
+            Structure::Frame(ref frame) => frame.encode_to(&mut bytes),
+            Structure::Datagram(ref msg) => msg.encode_to(&mut bytes),
+            Structure::RelayFrame(ref frame) => frame.encode_to(&mut bytes),
+        };
+        res.map_err(|e| invalid_data(format!("Could not encode {}: {}", self.name, e)))?;
+        Ok(bytes)
+    }
+
+    /// Checks that `bytes` decode to our value and that it encodes back to the same bytes.
+    fn round_trip(&self, bytes: &[u8]) -> Result<(), String> {
+        match self.structure {
+            Structure::Frame(ref frame) => decodes_to(bytes, frame)?,
+            Structure::Datagram(ref msg) => decodes_to(bytes, msg)?,
+            Structure::RelayFrame(ref frame) => decodes_to(bytes, frame)?,
+        }
+        match self.encode() {
+            Ok(ref encoded) if encoded[..] == bytes[..] => Ok(()),
+            Ok(encoded) => Err(format!("encodes to {} instead", hex(&encoded))),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    fn describe(&self, bytes: &[u8]) -> io::Result<String> {
+        let json = match self.structure {
+            Structure::Frame(ref frame) => self.description("frame", bytes, &frame.0),
+            Structure::Datagram(ref msg) => self.description("datagram", bytes, msg),
+            Structure::RelayFrame(ref frame) => self.description("relay_frame", bytes, &frame.0),
+        };
+        Ok(json? + "\n")
+    }
+
+    fn description<T: Serialize>(
+        &self,
+        structure: &str,
+        bytes: &[u8],
+        value: &T,
+    ) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&Description {
+            name: self.name,
+            since: self.since,
+            structure,
+            description: self.description,
+            length: bytes.len(),
+            hex: hex(bytes),
+            value,
+        })
+    }
+}
+
+fn decodes_to<T: WireFormat + Debug + PartialEq>(bytes: &[u8], expected: &T) -> Result<(), String> {
+    match T::decode(bytes) {
+        Ok(ref decoded) if decoded == expected => Ok(()),
+        Ok(decoded) => Err(format!("decodes to {:?} instead", decoded)),
+        Err(e) => Err(format!("doesn't decode: {}", e)),
+    }
+}
+
+fn vectors() -> Vec<Vector> {
+    let uid_a = VectorUid([
+        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+    ]);
+    let uid_b = VectorUid([0xb0; 20]);
+    let mut name_hash = [0; HASH_SIZE];
+    for (i, byte) in name_hash.iter_mut().enumerate() {
+        *byte = 0xc0 + i as u8;
+    }
+    let frame = |name, since, description, msg| Vector {
+        name,
+        since,
+        description,
+        structure: Structure::Frame(Frame(msg)),
+    };
+
+    vec![
+        frame(
+            "heartbeat",
+            1,
+            "Sent on an idle connection to keep it alive.",
+            Message::Heartbeat,
+        ),
+        frame(
+            "bootstrap_request_client",
+            1,
+            "First message of a client bootstrapping off a peer: its id, the hash of its network \
+             name and that it needn't be reachable.",
+            Message::BootstrapRequest(uid_a, name_hash, ExternalReachability::NotRequired),
+        ),
+        frame(
+            "bootstrap_granted",
+            1,
+            "Answer to a bootstrap request which is accepted, with the id of the peer.",
+            Message::BootstrapGranted(uid_b),
+        ),
+        frame(
+            "bootstrap_denied",
+            1,
+            "Answer to a bootstrap request of a node none of whose listeners could be reached.",
+            Message::BootstrapDenied(BootstrapDenyReason::FailedExternalReachability),
+        ),
+        frame(
+            "echo_addr_req",
+            1,
+            "Asks the peer for the address it sees us at.",
+            Message::EchoAddrReq,
+        ),
+        frame(
+            "choose_connection",
+            1,
+            "Picks this connection out of those being attempted to the same peer.",
+            Message::ChooseConnection,
+        ),
+        frame(
+            "connect",
+            1,
+            "First message of a direct connection: our id and the hash of our network name.",
+            Message::Connect(uid_a, name_hash),
+        ),
+        frame(
+            "data",
+            1,
+            "A message of the application.",
+            Message::Data(vec![0xde, 0xad, 0xbe, 0xef]),
+        ),
+        frame(
+            "data_empty",
+            1,
+            "An empty message of the application.",
+            Message::Data(vec![]),
+        ),
+        Vector {
+            name: "discovery_request",
+            since: 1,
+            description: "Broadcast to seek peers on the LAN, with a random id to ignore our own.",
+            structure: Structure::Datagram(DiscoveryMsg::Request {
+                guid: 0x0123_4567_89ab_cdef,
+            }),
+        },
+        frame(
+            "goodbye",
+            2,
+            "Sent before closing a connection on purpose, with a reason code of the application.",
+            Message::Goodbye(3),
+        ),
+        frame(
+            "probe",
+            2,
+            "Liveness or latency probe, to be answered right away.",
+            Message::Probe,
+        ),
+        frame("probe_ack", 2, "Answer to a probe.", Message::ProbeAck),
+        frame(
+            "pow_challenge",
+            2,
+            "Asks a bootstrapping peer for a proof of work: the challenge and its difficulty in \
+             leading zero bits.",
+            Message::PowChallenge([0x5a; 32], 12),
+        ),
+        frame(
+            "pow_solution",
+            2,
+            "The nonce solving a proof of work challenge.",
+            Message::PowSolution(0x1122_3344_5566_7788),
+        ),
+        frame(
+            "bootstrap_denied_pow_required",
+            2,
+            "Answer to a bootstrap request which didn't solve the challenge of the given \
+             difficulty.",
+            Message::BootstrapDenied(BootstrapDenyReason::PowRequired(12)),
+        ),
+        frame(
+            "rejection_full",
+            2,
+            "Refuses a peer for now: the rejection code, when to retry in seconds and a message \
+             for the logs.",
+            Message::Rejection(Rejection::new(
+                RejectionCode::Full,
+                Some(60),
+                "Too many clients connected",
+            )),
+        ),
+        frame(
+            "rejection_wrong_network",
+            2,
+            "Refuses a peer for good, without a time to retry after.",
+            Message::Rejection(Rejection::new(
+                RejectionCode::WrongNetwork,
+                None,
+                "Bootstrapper has an invalid name hash",
+            )),
+        ),
+        frame(
+            "promotion_failed",
+            2,
+            "None of the listeners announced in a promotion to node could be reached.",
+            Message::PromotionFailed,
+        ),
+        frame(
+            "ext_bootstrap_request_client",
+            3,
+            "First message of a client bootstrapping off a peer: its id, the hash of its network \
+             name and its extension offers. The role extension declares it a client, the proof of \
+             work extension the highest difficulty it solves.",
+            Message::ExtBootstrapRequest(
+                uid_a,
+                name_hash,
+                Extensions {
+                    entries: vec![
+                        Extension {
+                            id: 1,
+                            payload: vec![0, 0, 0, 0],
+                        },
+                        Extension {
+                            id: 2,
+                            payload: vec![24],
+                        },
+                    ],
+                    unsupported: vec![],
+                },
+            ),
+        ),
+        frame(
+            "ext_bootstrap_granted",
+            3,
+            "Answer to an extended bootstrap request which is accepted: the id of the peer, the \
+             role extension taken up and an offer it didn't know.",
+            Message::ExtBootstrapGranted(
+                uid_b,
+                Extensions {
+                    entries: vec![Extension {
+                        id: 1,
+                        payload: vec![],
+                    }],
+                    unsupported: vec![0x7fff],
+                },
+            ),
+        ),
+        frame(
+            "ext_connect",
+            3,
+            "First message of a direct connection, or the answer to it: our id, the hash of our \
+             network name and the extensions, none here.",
+            Message::ExtConnect(uid_a, name_hash, Extensions::default()),
+        ),
+        frame(
+            "request",
+            4,
+            "A request of the application, to be answered with a response carrying its id: the id \
+             and the payload.",
+            Message::Request(7, vec![0xde, 0xad, 0xbe, 0xef]),
+        ),
+        frame(
+            "response",
+            4,
+            "The answer to a request: the id of the request and the payload.",
+            Message::Response(7, vec![0xde, 0xad, 0xbe, 0xef]),
+        ),
+        frame(
+            "network_challenge",
+            5,
+            "Answers a handshake request on a private network: the nonce of the challenge, and the \
+             proof of knowing the network key over it and the nonce sent in place of the name hash \
+             of the request.",
+            Message::NetworkChallenge([0x6e; 32], [0x70; 32]),
+        ),
+        frame(
+            "network_proof",
+            5,
+            "Answers a network challenge with the proof of knowing the network key.",
+            Message::NetworkProof([0x71; 32]),
+        ),
+        frame(
+            "retiring",
+            6,
+            "Tells a peer we are about to shut down, or refuses its bootstrap request for that \
+             reason: the peers to turn to instead.",
+            Message::Retiring(vec![unwrap!("1.2.3.4:5483".parse())]),
+        ),
+        frame(
+            "relay_request",
+            7,
+            "Asks a relay for a pipe to a peer in place of a connect request: our id, the hash of \
+             our network name and the id of the peer.",
+            Message::RelayRequest(uid_a, name_hash, uid_b),
+        ),
+        frame(
+            "relay_ready",
+            7,
+            "Tells each side of a relayed connection that the relay paired it with the peer: the \
+             name hash a connect request is answered with.",
+            Message::RelayReady(name_hash),
+        ),
+        frame(
+            "relay_challenge",
+            8,
+            "Answers a relay request with a challenge to prove holding the key the peer is \
+             connected to the relay with, and the public key of the relay to seal the proof to.",
+            Message::RelayChallenge([0x6b; 32], PublicKey([0x70; 32])),
+        ),
+        frame(
+            "relay_proof",
+            8,
+            "Answers a relay challenge with the proof: a nonce followed by the challenge sealed \
+             between the keys of the peer and the relay.",
+            Message::RelayProof(vec![0x72; 8]),
+        ),
+        frame(
+            "key_confirmation",
+            9,
+            "The first frame sealed each way once the handshake agreed on encryption, given here \
+             in the clear.",
+            Message::KeyConfirmation,
+        ),
+        Vector {
+            name: "relay_quota_exceeded",
+            since: 10,
+            description: "Sent by a relay to both peers of a pipe, after an empty frame: how many \
+                          frames of the peer it dropped on their way to us for going over its \
+                          quota or the peer's window, and how many of ours.",
+            structure: Structure::RelayFrame(Frame(RelayControl::QuotaExceeded(2, 5))),
+        },
+        Vector {
+            name: "relay_window",
+            since: 10,
+            description: "Sent by a relay to both peers of a pipe right after the relay_ready, \
+                          after an empty frame: the bytes we may have sent it which the peer \
+                          hasn't read.",
+            structure: Structure::RelayFrame(Frame(RelayControl::Window(256 * 1024))),
+        },
+        Vector {
+            name: "relay_credit",
+            since: 10,
+            description: "Sent by a relay after an empty frame once the peer read as many more of \
+                          the bytes we sent, which we may send again.",
+            structure: Structure::RelayFrame(Frame(RelayControl::Credit(64 * 1024))),
+        },
+        frame(
+            "upgrade_request",
+            10,
+            "Asks to move the connection onto a direct one: our listeners, for the peer to dial \
+             should we reach none of its own.",
+            Message::UpgradeRequest(vec![unwrap!("1.2.3.4:5483".parse())]),
+        ),
+        frame(
+            "upgrade_offer",
+            10,
+            "Answers an upgrade request with the listeners to dial us at.",
+            Message::UpgradeOffer(vec![unwrap!("5.6.7.8:5483".parse())]),
+        ),
+        frame(
+            "upgrade_dial",
+            10,
+            "Asks the peer to dial the listeners of our upgrade request, as we reached none of \
+             those it offered.",
+            Message::UpgradeDial,
+        ),
+        frame(
+            "upgrade_failed",
+            10,
+            "Gives up an upgrade, the connection staying as it is.",
+            Message::UpgradeFailed,
+        ),
+        frame(
+            "upgrade_switch",
+            10,
+            "The last frame on a connection an upgrade replaced, after which the new one is read.",
+            Message::UpgradeSwitch,
+        ),
+    ]
+}
+
+/// Writes the fixtures missing from `dir`, each under the version its vector appeared in, and
+/// returns the files written. Fails rather than change any existing fixture: a change to the wire
+/// format needs a new version, and new vectors for it.
+pub fn generate(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for vector in vectors() {
+        let version_dir = vector_dir(dir, &vector)?;
+        fs::create_dir_all(&version_dir)?;
+
+        let bytes = vector.encode()?;
+        let bin_path = version_dir.join(format!("{}.bin", vector.name));
+        if bin_path.exists() {
+            if read_file(&bin_path)? != bytes {
+                return Err(invalid_data(format!(
+                    "{} would change: bump PROTOCOL_VERSION and add a vector instead",
+                    bin_path.display()
+                )));
+            }
+        } else {
+            write_file(&bin_path, &bytes)?;
+            written.push(bin_path);
+        }
+
+        let description = vector.describe(&bytes)?;
+        let json_path = version_dir.join(format!("{}.json", vector.name));
+        if !json_path.exists() || read_file(&json_path)? != description.as_bytes() {
+            write_file(&json_path, description.as_bytes())?;
+            written.push(json_path);
+        }
+    }
+    Ok(written)
+}
+
+/// Checks every fixture in `dir` against the vector of the same name, and that each vector has
+/// its fixture, under the version it appeared in. Returns the number of fixtures checked.
+pub fn verify(dir: &Path) -> io::Result<usize> {
+    let vectors = vectors();
+    let mut checked = HashSet::new();
+
+    for (version, version_dir) in version_dirs(dir)? {
+        if version > PROTOCOL_VERSION {
+            return Err(invalid_data(format!(
+                "{} is newer than PROTOCOL_VERSION {}",
+                version_dir.display(),
+                PROTOCOL_VERSION
+            )));
+        }
+        for entry in fs::read_dir(&version_dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "bin") {
+                continue;
+            }
+            let name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("")
+                .to_owned();
+            let vector = match vectors.iter().find(|vector| vector.name == name) {
+                Some(vector) if vector.since == version => vector,
+                _ => {
+                    return Err(invalid_data(format!(
+                        "{} isn't a vector which appeared in version {}",
+                        path.display(),
+                        version
+                    )))
+                }
+            };
+            if let Err(e) = vector.round_trip(&read_file(&path)?) {
+                return Err(invalid_data(format!(
+                    "{} {}. If the wire format changed on purpose, bump PROTOCOL_VERSION and \
+                     add a vector for it.",
+                    path.display(),
+                    e
+                )));
+            }
+            let _ = checked.insert(name);
+        }
+    }
+
+    if let Some(vector) = vectors
+        .iter()
+        .find(|vector| !checked.contains(vector.name))
+    {
+        return Err(invalid_data(format!(
+            "No fixture of {} for version {}: run the test_vectors example",
+            vector.name, vector.since
+        )));
+    }
+    Ok(checked.len())
+}
+
+/// The directory of the fixture of `vector` in `dir`.
+fn vector_dir(dir: &Path, vector: &Vector) -> io::Result<PathBuf> {
+    if vector.since == 0 || vector.since > PROTOCOL_VERSION {
+        return Err(invalid_data(format!(
+            "{} appeared in version {}, which isn't one up to PROTOCOL_VERSION {}",
+            vector.name, vector.since, PROTOCOL_VERSION
+        )));
+    }
+    Ok(dir.join(format!("v{}", vector.since)))
+}
+
+/// The `v<version>` directories in `dir`.
+fn version_dirs(dir: &Path) -> io::Result<Vec<(u32, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let version = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| {
+                if name.starts_with('v') {
+                    name[1..].parse().ok()
+                } else {
+                    None
+                }
+            });
+        if let Some(version) = version {
+            if path.is_dir() {
+                dirs.push((version, path));
+            }
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let _ = File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    File::create(path)?.write_all(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::env;
+
+    fn fixtures_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("test_vectors")
+    }
+
+    #[test]
+    fn fixtures_round_trip() {
+        assert_eq!(unwrap!(verify(&fixtures_dir())), vectors().len());
+    }
+
+    #[test]
+    fn changed_fixtures_are_caught() {
+        let dir = env::temp_dir().join(format!("crust-test-vectors-{}", rand::random::<u64>()));
+        let written = unwrap!(generate(&dir));
+        assert_eq!(written.len(), 2 * vectors().len());
+        assert!(unwrap!(generate(&dir)).is_empty());
+        assert_eq!(unwrap!(verify(&dir)), vectors().len());
+
+        // Goodbyes appeared in version 2, and later versions don't get a copy of their fixture.
+        assert!(!dir.join(format!("v{}/goodbye.bin", PROTOCOL_VERSION)).exists());
+        let path = dir.join("v2/goodbye.bin");
+        let mut bytes = unwrap!(read_file(&path));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        unwrap!(write_file(&path, &bytes));
+        assert_eq!(unwrap!(verify(&dir).err()).kind(), ErrorKind::InvalidData);
+        assert_eq!(unwrap!(generate(&dir).err()).kind(), ErrorKind::InvalidData);
+
+        unwrap!(fs::remove_file(&path));
+        assert_eq!(unwrap!(verify(&dir).err()).kind(), ErrorKind::InvalidData);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
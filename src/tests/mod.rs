@@ -9,24 +9,26 @@
 
 #[macro_use]
 pub mod utils;
+mod simulation;
 
 pub use self::utils::{gen_config, get_event_sender, timebomb, UniqueId};
 
-use common::CrustUser;
-use main::{self, Config, DevConfig, Event};
+use common::{CrustUser, RejectionCode, VirtualClock, CONTROL_PRIORITY};
+use main::{self, Config, CrustError, DevConfig, DisconnectReason, Event, ServiceCore};
 use mio;
 use rand;
 use std::collections::HashSet;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use test_utils::{self, ServicePair};
 
 type Service = main::Service<UniqueId>;
 
 fn localhost(port: u16) -> SocketAddr {
-    use std::net::IpAddr;
     SocketAddr::new(unwrap!(IpAddr::from_str("127.0.0.1")), port)
 }
 
@@ -34,63 +36,1737 @@ fn localhost_contact_info(port: u16) -> SocketAddr {
     localhost(port)
 }
 
+#[cfg(feature = "service-discovery")]
 fn gen_service_discovery_port() -> u16 {
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
     const BASE: u16 = 40_000;
     static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
 
-    BASE + COUNTER.fetch_add(1, Ordering::Relaxed) as u16
-}
+    BASE + COUNTER.fetch_add(1, Ordering::Relaxed) as u16
+}
+
+// Runs each of the cores once, for a millisecond.
+fn run_cores(cores: &mut [&mut ServiceCore]) {
+    for core in cores.iter_mut() {
+        assert!(unwrap!(core.run_once(Duration::from_millis(1))));
+    }
+}
+
+// Runs the cores in turns until `rx` receives an event. Cores on a virtual clock only fire the
+// timers the clock has been advanced past.
+fn run_cores_until_event(
+    cores: &mut [&mut ServiceCore],
+    rx: &Receiver<Event<UniqueId>>,
+) -> Event<UniqueId> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        if let Ok(event) = rx.try_recv() {
+            return event;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for an event");
+        run_cores(cores);
+    }
+}
+
+// Calls `f` on a thread of its own while running the cores, so that it can call the methods of
+// their services which wait for the event loop.
+fn run_cores_alongside<T, F>(cores: &mut [&mut ServiceCore], f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let _ = thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    loop {
+        if let Ok(value) = rx.try_recv() {
+            return value;
+        }
+        run_cores(cores);
+    }
+}
+
+#[test]
+fn bootstrap_two_services_and_exchange_messages() {
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id0, service0.id());
+
+    let peer_id1 = expect_event!(event_rx0,
+                                 Event::BootstrapAccept(peer_id, CrustUser::Client, _) => peer_id);
+    assert_eq!(peer_id1, service1.id());
+
+    // Both sides agree on what was negotiated in the handshake.
+    let features = unwrap!(service0.peer_stats(&peer_id1)).features;
+    assert!(features.extensions);
+    assert_eq!(features.bootstrap_role, Some(CrustUser::Client));
+    assert_eq!(features, unwrap!(service1.peer_stats(&peer_id0)).features);
+
+    let message0 = b"hello from 0".to_vec();
+    unwrap!(service0.send(&peer_id1, message0.clone(), 1));
+    // The highest priority is kept for Crust's own messages.
+    match service0.send(&peer_id1, message0.clone(), CONTROL_PRIORITY) {
+        Err(CrustError::ReservedPriority(priority)) => assert_eq!(priority, CONTROL_PRIORITY),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, message0);
+    });
+
+    let message1 = b"hello from 1".to_vec();
+    unwrap!(service1.send(&peer_id0, message1.clone(), 1));
+
+    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, message1);
+    });
+}
+
+#[test]
+fn service_pair_exchanges_messages() {
+    let pair = ServicePair::<UniqueId>::new();
+    let (peer_id0, peer_id1) = (pair.id0(), pair.id1());
+
+    let message0 = b"hello from 0".to_vec();
+    unwrap!(pair.service0.send(&peer_id1, message0.clone(), 1));
+
+    expect_crust_event!(pair.events1, Event::NewMessage(peer_id, CrustUser::Node, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, message0);
+    });
+
+    let message1 = b"hello from 1".to_vec();
+    unwrap!(pair.service1.send(&peer_id0, message1.clone(), 1));
+
+    expect_crust_event!(pair.events0, Event::NewMessage(peer_id, CrustUser::Client, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, message1);
+    });
+
+    pair.teardown();
+}
+
+// Run with `cargo test --no-default-features --features tcp-only` to check the minimal build.
+#[test]
+#[cfg(feature = "tcp-only")]
+fn tcp_only_build_connects_over_loopback() {
+    let pair = ServicePair::<UniqueId>::new();
+    let (peer_id0, peer_id1) = (pair.id0(), pair.id1());
+
+    unwrap!(pair.service0.send(&peer_id1, b"direct".to_vec(), 1));
+    expect_crust_event!(pair.events1, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, b"direct".to_vec());
+    });
+    pair.teardown();
+
+    // Settings of what was compiled out are refused rather than ignored.
+    let mut config = gen_config();
+    config.service_discovery_port = Some(5484);
+    let (event_tx, _event_rx) = get_event_sender();
+    match Service::with_config(event_tx, config, rand::random()) {
+        Ok(_) => assert!(cfg!(feature = "service-discovery")),
+        Err(CrustError::FeatureDisabled(field, _)) => {
+            assert!(!cfg!(feature = "service-discovery"));
+            assert_eq!(field, "service_discovery_port");
+        }
+        Err(e) => panic!("Unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn requests_are_answered_with_responses() {
+    let pair = ServicePair::<UniqueId>::new();
+    let (peer_id0, peer_id1) = (pair.id0(), pair.id1());
+    assert!(unwrap!(pair.service0.peer_stats(&peer_id1)).features.correlation);
+
+    let id = unwrap!(pair.service0.send_request(&peer_id1, b"ping".to_vec(), 1));
+    let their_id = expect_event!(pair.events1, Event::Request { peer_id, request_id, data } => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, b"ping".to_vec());
+        request_id
+    });
+    assert_eq!(their_id, id);
+
+    unwrap!(pair.service1.send_response(&peer_id0, id, b"pong".to_vec()));
+    expect_event!(pair.events0, Event::Response { peer_id, request_id, data } => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(request_id, id);
+        assert_eq!(data, b"pong".to_vec());
+    });
+
+    // Plain messages are delivered as before.
+    unwrap!(pair.service0.send(&peer_id1, b"hi".to_vec(), 1));
+    expect_event!(pair.events1, Event::NewMessage(..));
+    assert_ne!(unwrap!(pair.service0.send_request(&peer_id1, vec![], 1)), id);
+
+    pair.teardown();
+}
+
+#[test]
+fn bootstrap_with_external_event_loop() {
+    // Runs `core` in short bursts at irregular intervals until `rx` receives an event.
+    fn run_until_event(core: &mut ServiceCore, rx: &Receiver<Event<UniqueId>>) -> Event<UniqueId> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            if let Ok(event) = rx.try_recv() {
+                return event;
+            }
+            assert!(Instant::now() < deadline, "timed out waiting for an event");
+            assert!(unwrap!(
+                core.run_once(Duration::from_millis(rand::random::<u64>() % 10))
+            ));
+            thread::sleep(Duration::from_millis(rand::random::<u64>() % 50));
+        }
+    }
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (mut service1, mut core1) = unwrap!(Service::with_external_loop(
+        event_tx1,
+        config1,
+        rand::random()
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = match run_until_event(&mut core1, &event_rx1) {
+        Event::BootstrapConnect(peer_id, _, _) => peer_id,
+        event => panic!("unexpected event {:?}", event),
+    };
+    assert_eq!(peer_id0, service0.id());
+    let peer_id1 = match run_until_event(&mut core1, &event_rx0) {
+        Event::BootstrapAccept(peer_id, CrustUser::Client, _) => peer_id,
+        event => panic!("unexpected event {:?}", event),
+    };
+    assert_eq!(peer_id1, service1.id());
+
+    let message0 = b"hello from 0".to_vec();
+    unwrap!(service0.send(&peer_id1, message0.clone(), 1));
+    match run_until_event(&mut core1, &event_rx1) {
+        Event::NewMessage(peer_id, CrustUser::Node, data, _) => {
+            assert_eq!(peer_id, peer_id0);
+            assert_eq!(data, message0);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    let message1 = b"hello from 1".to_vec();
+    unwrap!(service1.send(&peer_id0, message1.clone(), 1));
+    match run_until_event(&mut core1, &event_rx0) {
+        Event::NewMessage(peer_id, CrustUser::Client, data, _) => {
+            assert_eq!(peer_id, peer_id1);
+            assert_eq!(data, message1);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    drop(service1);
+    assert!(!unwrap!(core1.run_once(Duration::from_secs(1))));
+}
+
+#[test]
+fn large_messages_are_delivered_in_shared_buffers() {
+    let mut config0 = gen_config();
+    config0.shared_payload_min_size = Some(64 * 1024);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    let small = b"small".to_vec();
+    let large: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+    unwrap!(service1.send(&peer_id0, small.clone(), 1));
+    unwrap!(service1.send(&peer_id0, large.clone(), 1));
+
+    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, small);
+    });
+    expect_event!(event_rx0, Event::NewSharedMessage(peer_id, CrustUser::Client, payload, _) => {
+        assert_eq!(peer_id, peer_id1);
+        let clone = payload.clone();
+        assert_eq!(clone.as_ptr(), payload.as_ptr());
+        assert_eq!(payload.to_vec(), large);
+    });
+
+    // Without the option everything still comes as `NewMessage`.
+    unwrap!(service0.send(&peer_id1, large.clone(), 1));
+    expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, large);
+    });
+}
+
+#[test]
+fn service_pair_delivers_large_messages_in_shared_buffers() {
+    let mut config0 = test_utils::test_config();
+    config0.shared_payload_min_size = Some(64 * 1024);
+    let pair = ServicePair::<UniqueId>::with_configs(
+        config0,
+        test_utils::test_config(),
+        CrustUser::Client,
+    );
+    let (peer_id0, peer_id1) = (pair.id0(), pair.id1());
+
+    let small = b"small".to_vec();
+    let large = test_utils::random_payload(1024 * 1024);
+    unwrap!(pair.service1.send(&peer_id0, small.clone(), 1));
+    unwrap!(pair.service1.send(&peer_id0, large.clone(), 1));
+
+    expect_crust_event!(pair.events0, Event::NewMessage(peer_id, CrustUser::Client, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, small);
+    });
+    expect_crust_event!(pair.events0,
+                        Event::NewSharedMessage(peer_id, CrustUser::Client, payload, _) => {
+        assert_eq!(peer_id, peer_id1);
+        let clone = payload.clone();
+        assert_eq!(clone.as_ptr(), payload.as_ptr());
+        assert_eq!(payload.to_vec(), large);
+    });
+
+    // Without the option everything still comes as `NewMessage`.
+    unwrap!(pair.service0.send(&peer_id1, large.clone(), 1));
+    expect_crust_event!(pair.events1, Event::NewMessage(peer_id, CrustUser::Node, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, large);
+    });
+
+    pair.teardown();
+}
+
+#[test]
+fn shared_messages_are_sent_as_they_are() {
+    let pair = ServicePair::<UniqueId>::new();
+    let (peer_id0, peer_id1) = (pair.id0(), pair.id1());
+
+    let chunk = Arc::new(test_utils::random_payload(1024 * 1024));
+    for _ in 0..2 {
+        unwrap!(pair.service1.send_shared(&peer_id0, chunk.clone(), 1));
+    }
+    for _ in 0..2 {
+        expect_crust_event!(pair.events0,
+                            Event::NewMessage(peer_id, CrustUser::Client, data, _) => {
+            assert_eq!(peer_id, peer_id1);
+            assert_eq!(data, *chunk);
+        });
+    }
+
+    match pair.service1.send_shared(&rand::random(), chunk, 1) {
+        Err(CrustError::PeerNotFound) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    pair.teardown();
+}
+
+#[test]
+fn peer_tags_are_echoed_in_events() {
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            let mut config = gen_config();
+            config.hard_coded_contacts = vec![localhost_contact_info(port0)];
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+            unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+            expect_event!(event_rx, Event::BootstrapConnect(..));
+            let id = expect_event!(event_rx0, Event::BootstrapAccept(id, _, _) => id);
+            (service, event_rx, id)
+        })
+        .collect();
+
+    assert_eq!(unwrap!(service0.peer_tag(&clients[0].2)), 0);
+    for (i, &(_, _, id)) in clients.iter().enumerate() {
+        unwrap!(service0.set_peer_tag(&id, 100 + i as u64));
+    }
+    assert_eq!(unwrap!(service0.peer_tag(&clients[1].2)), 101);
+
+    // The tags stay local.
+    unwrap!(service0.send(&clients[0].2, b"hi".to_vec(), 1));
+    expect_event!(clients[0].1, Event::NewMessage(_, _, _, 0));
+
+    for (i, client) in clients.iter().enumerate() {
+        unwrap!(client.0.send(&service0.id(), vec![i as u8], 1));
+        expect_event!(event_rx0, Event::NewMessage(id, _, data, tag) => {
+            assert_eq!(id, client.2);
+            assert_eq!(data, vec![i as u8]);
+            assert_eq!(tag, 100 + i as u64);
+        });
+    }
+
+    for (i, client) in clients.into_iter().enumerate().rev() {
+        drop(client.0);
+        expect_event!(event_rx0, Event::LostPeer(id, _, tag) => {
+            assert_eq!(id, client.2);
+            assert_eq!(tag, 100 + i as u64);
+        });
+    }
+}
+
+#[test]
+fn peer_addr_and_transport_of_bootstrapped_peers() {
+    use main::Transport;
+    use CrustError;
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    // Outbound: we see the listener's endpoint we dialled.
+    assert_eq!(unwrap!(service1.peer_addr(&peer_id0)), localhost(port0));
+    assert_eq!(unwrap!(service1.peer_transport(&peer_id0)), Transport::Tcp);
+
+    // Inbound: the listener sees the dialler's ephemeral endpoint on loopback.
+    let inbound_addr = unwrap!(service0.peer_addr(&peer_id1));
+    assert_eq!(inbound_addr.ip(), unwrap!(IpAddr::from_str("127.0.0.1")));
+    assert_ne!(inbound_addr.port(), port0);
+    assert_eq!(unwrap!(service0.peer_transport(&peer_id1)), Transport::Tcp);
+    #[cfg(feature = "relay")]
+    {
+        assert!(!unwrap!(service0.peer_transport(&peer_id1)).is_relayed());
+    }
+
+    let unknown_id = rand::random();
+    match service0.peer_addr(&unknown_id) {
+        Err(CrustError::PeerNotFound) => (),
+        res => panic!("Expected PeerNotFound, got {:?}", res),
+    }
+    match service0.peer_transport(&unknown_id) {
+        Err(CrustError::PeerNotFound) => (),
+        res => panic!("Expected PeerNotFound, got {:?}", res),
+    }
+}
+
+// Bootstraps a client bound to `bind_ip` and returns the source IP seen by the accepting side.
+fn bootstrap_source_ip(bind_ip: IpAddr) -> IpAddr {
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    config1.outbound_bind_addr = Some(bind_ip);
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+    unwrap!(service0.peer_addr(&peer_id1)).ip()
+}
+
+// Other loopback addresses than 127.0.0.1 are only routed by default on Linux.
+#[cfg(target_os = "linux")]
+#[test]
+fn bootstrap_from_outbound_bind_addr() {
+    let bind_ip = unwrap!(IpAddr::from_str("127.0.0.2"));
+    assert_eq!(bootstrap_source_ip(bind_ip), bind_ip);
+}
+
+#[test]
+fn outbound_bind_addr_of_other_family_is_skipped() {
+    let bind_ip = unwrap!(IpAddr::from_str("::1"));
+    assert_eq!(
+        bootstrap_source_ip(bind_ip),
+        unwrap!(IpAddr::from_str("127.0.0.1"))
+    );
+}
+
+#[test]
+fn bootstrap_solving_pow() {
+    let mut config0 = gen_config();
+    config0.require_pow = Some(10);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id0, service0.id());
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id1, service1.id());
+}
+
+#[test]
+fn bootstrap_with_keys_encrypts_and_authenticates() {
+    use common::KeyPair;
+
+    let keys0 = KeyPair::generate();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_keys(
+        event_tx0,
+        gen_config(),
+        rand::random(),
+        keys0.clone(),
+    ));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    let keys1 = KeyPair::generate();
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_keys(
+        event_tx1,
+        config1.clone(),
+        rand::random(),
+        keys1.clone(),
+    ));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    // The keys each peer proved it owns come with the events.
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, key) => {
+        assert_eq!(key, Some(keys0.public_key()));
+        peer_id
+    });
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, key) => {
+        assert_eq!(key, Some(keys1.public_key()));
+        peer_id
+    });
+    assert_eq!(unwrap!(service1.peer_public_key(&peer_id0)), Some(keys0.public_key()));
+    assert_eq!(unwrap!(service0.peer_public_key(&peer_id1)), Some(keys1.public_key()));
+
+    unwrap!(service1.send(&peer_id0, b"sealed".to_vec(), 1));
+    expect_event!(event_rx0, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, b"sealed".to_vec());
+    });
+    unwrap!(service0.send(&peer_id1, b"and delivered".to_vec(), 1));
+    expect_event!(event_rx1, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, b"and delivered".to_vec());
+    });
+
+    // A peer without keys is refused.
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config1, rand::random()));
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapAttemptFailed(_, rejection) => {
+        assert_eq!(rejection.kind(), RejectionCode::EncryptionRequired);
+    });
+    expect_event!(event_rx2, Event::BootstrapFailed);
+}
+
+#[test]
+#[cfg(feature = "flight-recorder")]
+fn flight_record_of_bootstrap() {
+    use common::RecordedEventKind;
+
+    let mut config0 = gen_config();
+    config0.flight_recorder_kb = Some(16);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    config1.flight_recorder_kb = Some(16);
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+    expect_event!(event_rx0, Event::BootstrapAccept(..));
+
+    let kinds: Vec<_> = unwrap!(service0.dump_flight_record())
+        .into_iter()
+        .map(|event| event.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            RecordedEventKind::Accepted,
+            RecordedEventKind::HandshakeSucceeded,
+        ]
+    );
+
+    let kinds: Vec<_> = unwrap!(service1.dump_flight_record())
+        .into_iter()
+        .map(|event| event.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            RecordedEventKind::BootstrapAttempt(localhost(port0)),
+            RecordedEventKind::BootstrapSucceeded(localhost(port0)),
+        ]
+    );
+}
+
+#[test]
+fn interface_change_refreshes_listeners_and_drops_stale_connections() {
+    use main::InterfaceLister;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    struct MockLister(Arc<Mutex<Vec<IpAddr>>>);
+
+    impl InterfaceLister for MockLister {
+        fn local_ips(&mut self) -> io::Result<Vec<IpAddr>> {
+            Ok(unwrap!(self.0.lock()).clone())
+        }
+    }
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    config1.interface_scan_interval_sec = Some(1);
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_listening_tcp());
+    let port1 = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+
+    let loopback = unwrap!(IpAddr::from_str("127.0.0.1"));
+    let kept_ip = unwrap!(IpAddr::from_str("10.1.2.3"));
+    let new_ip = unwrap!(IpAddr::from_str("10.9.9.9"));
+    let ips = Arc::new(Mutex::new(vec![loopback, kept_ip]));
+    unwrap!(service1.set_interface_lister(Box::new(MockLister(ips.clone()))));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    expect_event!(event_rx0, Event::BootstrapAccept(..));
+
+    // Our connection to service0 goes over loopback, so losing it must drop the peer right away.
+    *unwrap!(ips.lock()) = vec![kept_ip, new_ip];
+
+    expect_event!(event_rx1, Event::NetworkInterfacesChanged { added, removed } => {
+        assert_eq!(added, vec![new_ip]);
+        assert_eq!(removed, vec![loopback]);
+    });
+    expect_event!(event_rx1, Event::LostPeer(peer_id, _, _) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx0, Event::LostPeer(..));
+
+    service1.prepare_connection_info(0);
+    let our_info = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result)
+    });
+    assert!(our_info
+        .for_direct
+        .contains(&SocketAddr::new(new_ip, port1)));
+    assert!(our_info.for_direct.iter().all(|addr| addr.ip() != loopback));
+}
+
+#[test]
+fn nodes_lost_to_the_network_are_reconnected_to() {
+    use main::{InterfaceLister, ReconnectPolicy};
+    use std::io;
+    use std::sync::Mutex;
+
+    struct MockLister(Arc<Mutex<Vec<IpAddr>>>);
+
+    impl InterfaceLister for MockLister {
+        fn local_ips(&mut self) -> io::Result<Vec<IpAddr>> {
+            Ok(unwrap!(self.0.lock()).clone())
+        }
+    }
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    config1.interface_scan_interval_sec = Some(1);
+    config1.reconnect = Some(ReconnectPolicy {
+        initial_delay_ms: 200,
+        max_delay_ms: 1000,
+        max_attempts: 3,
+    });
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    let loopback = unwrap!(IpAddr::from_str("127.0.0.1"));
+    let other_ip = unwrap!(IpAddr::from_str("10.1.2.3"));
+    let ips = Arc::new(Mutex::new(vec![loopback, other_ip]));
+    unwrap!(service1.set_interface_lister(Box::new(MockLister(ips.clone()))));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    // Pretending loopback went away drops the connection as if the network had.
+    *unwrap!(ips.lock()) = vec![other_ip];
+    expect_event!(event_rx0, Event::LostPeer(peer_id, _, _) => assert_eq!(peer_id, peer_id1));
+
+    let mut lost = false;
+    loop {
+        match unwrap!(event_rx1.recv_timeout(Duration::from_secs(30))) {
+            Event::LostPeer(peer_id, DisconnectReason::ConnectionLost, _) => {
+                assert_eq!(peer_id, peer_id0);
+                lost = true;
+            }
+            Event::PeerReconnected(peer_id) => {
+                assert!(lost);
+                assert_eq!(peer_id, peer_id0);
+                break;
+            }
+            Event::NetworkInterfacesChanged { .. } => (),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+    expect_event!(event_rx0, Event::ConnectSuccess(peer_id, _) => assert_eq!(peer_id, peer_id1));
+
+    unwrap!(service1.send(&peer_id0, b"after reconnecting".to_vec(), 1));
+    expect_event!(event_rx0, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, b"after reconnecting".to_vec());
+    });
+}
+
+#[test]
+fn restart_from_exported_state() {
+    use serde_json;
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let _ = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    let snapshot = unwrap!(service1.export_state());
+    assert_eq!(snapshot.our_uid, service1.id());
+    assert_eq!(
+        snapshot.peers,
+        vec![main::PeerContact {
+            id: peer_id0,
+            addr: localhost(port0),
+        }]
+    );
+    assert!(
+        snapshot
+            .bootstrap_contacts
+            .iter()
+            .any(|entry| entry.addr == localhost(port0))
+    );
+    let snapshot = unwrap!(serde_json::to_string(&snapshot));
+    drop(service1);
+    expect_event!(event_rx0, Event::LostPeer(..));
+
+    // The restarted service has neither hard-coded contacts nor a bootstrap cache of its own.
+    let mut snapshot: main::ServiceSnapshot<UniqueId> = unwrap!(serde_json::from_str(&snapshot));
+    let advertised = localhost(12_345);
+    snapshot.our_listeners = vec![advertised];
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_snapshot(
+        event_tx2,
+        gen_config(),
+        snapshot.clone()
+    ));
+    assert_eq!(service2.id(), snapshot.our_uid);
+
+    service2.prepare_connection_info(0);
+    expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => {
+        assert_eq!(unwrap!(res.result).for_direct, vec![advertised]);
+    });
+
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapConnect(peer_id, _, _) => {
+        assert_eq!(peer_id, peer_id0);
+    });
+    expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => {
+        assert_eq!(peer_id, snapshot.our_uid)
+    });
+
+    // The listener addresses of a stale snapshot are not advertised, but its contacts are used.
+    let mut config3 = gen_config();
+    config3.snapshot_max_age_secs = Some(60);
+    snapshot.our_uid = rand::random();
+    snapshot.taken_at -= 120;
+    let (event_tx3, event_rx3) = get_event_sender();
+    let mut service3 = unwrap!(Service::with_snapshot(event_tx3, config3, snapshot));
+
+    service3.prepare_connection_info(0);
+    expect_event!(event_rx3, Event::ConnectionInfoPrepared(res) => {
+        assert!(unwrap!(res.result).for_direct.is_empty());
+    });
+
+    unwrap!(service3.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx3, Event::BootstrapConnect(peer_id, _, _) => {
+        assert_eq!(peer_id, peer_id0);
+    });
+}
+
+#[test]
+fn reconnect_dials_the_path_which_worked_first() {
+    use main::{CandidateAddr, PubConnectionInfo, CONNECT_STAGGER_MS};
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, gen_config(), rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let peer_id0 = service0.id();
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let service1 = unwrap!(Service::with_config(event_tx1, gen_config(), rand::random()));
+    let peer_id1 = service1.id();
+
+    // Listed first, over IPv6, and takes connections without ever answering the handshake.
+    let deaf_listener = unwrap!(TcpListener::bind("[::1]:0"));
+    unwrap!(deaf_listener.set_nonblocking(true));
+    let deaf_address = unwrap!(deaf_listener.local_addr());
+
+    let mut latencies = Vec::new();
+    for _ in 0..2 {
+        service1.prepare_connection_info(0);
+        let our_ci = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+            unwrap!(res.result)
+        });
+        let their_ci = PubConnectionInfo {
+            id: peer_id0,
+            candidates: vec![
+                CandidateAddr::TcpDirect(deaf_address),
+                CandidateAddr::TcpDirect(localhost(port0)),
+            ],
+            issued_at: None,
+            ttl_secs: None,
+        };
+        let started = Instant::now();
+        unwrap!(service1.connect(our_ci, their_ci));
+        expect_event!(event_rx1, Event::ConnectSuccess(id, _) => assert_eq!(id, peer_id0));
+        latencies.push(started.elapsed());
+        expect_event!(event_rx0, Event::ConnectSuccess(id, _) => assert_eq!(id, peer_id1));
+
+        assert!(service1.disconnect(&peer_id0));
+        expect_event!(event_rx1, Event::LostPeer(id, _, _) => assert_eq!(id, peer_id0));
+        expect_event!(event_rx0, Event::LostPeer(id, _, _) => assert_eq!(id, peer_id1));
+    }
+
+    // The first time, the IPv4 candidate is only dialled once the deaf one has had its head
+    // start. The second time it is dialled first, and wins before the other one is dialled.
+    let stagger = Duration::from_millis(CONNECT_STAGGER_MS);
+    assert!(latencies[0] >= stagger, "{:?}", latencies);
+    assert!(latencies[1] < stagger, "{:?}", latencies);
+    let _ = unwrap!(deaf_listener.accept());
+    match deaf_listener.accept() {
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+        res => panic!("The deaf candidate was dialled again: {:?}", res),
+    }
+}
+
+#[cfg(feature = "utp")]
+#[test]
+fn connect_over_utp() {
+    use main::{CandidateAddr, PubConnectionInfo, Transport};
+
+    let mut config0 = gen_config();
+    config0.enable_utp = true;
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let peer_id0 = service0.id();
+
+    let mut config1 = gen_config();
+    config1.enable_utp = true;
+    let (event_tx1, event_rx1) = get_event_sender();
+    let service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    let peer_id1 = service1.id();
+
+    service0.prepare_connection_info(0);
+    let info0 = expect_event!(event_rx0, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result)
+    });
+    // The listener accepts uTP on the same addresses as TCP.
+    assert!(!info0.for_utp.is_empty());
+    assert_eq!(info0.for_utp, info0.for_direct);
+
+    // Only the uTP candidate, so the connection can't go over TCP.
+    service1.prepare_connection_info(0);
+    let our_ci = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result)
+    });
+    let their_ci = PubConnectionInfo {
+        id: peer_id0,
+        candidates: vec![CandidateAddr::Utp(localhost(port0))],
+        issued_at: None,
+        ttl_secs: None,
+    };
+    unwrap!(service1.connect(our_ci, their_ci));
+    expect_event!(event_rx1, Event::ConnectSuccess(id, _) => assert_eq!(id, peer_id0));
+    expect_event!(event_rx0, Event::ConnectSuccess(id, _) => assert_eq!(id, peer_id1));
+    assert_eq!(unwrap!(service1.peer_transport(&peer_id0)), Transport::Utp);
+    assert_eq!(unwrap!(service0.peer_transport(&peer_id1)), Transport::Utp);
+    assert_eq!(unwrap!(service1.peer_addr(&peer_id0)), localhost(port0));
+
+    // More than fits a uTP packet.
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    unwrap!(service1.send(&peer_id0, data.clone(), 1));
+    expect_event!(event_rx0, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, peer_id1);
+        assert!(received == data);
+    });
+    unwrap!(service0.send(&peer_id1, b"reply".to_vec(), 1));
+    expect_event!(event_rx1, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, peer_id0);
+        assert_eq!(received, b"reply");
+    });
+
+    assert!(service1.disconnect(&peer_id0));
+    expect_event!(event_rx1, Event::LostPeer(id, _, _) => assert_eq!(id, peer_id0));
+    expect_event!(event_rx0, Event::LostPeer(id, _, _) => assert_eq!(id, peer_id1));
+}
+
+#[cfg(feature = "websocket")]
+#[test]
+fn bootstrap_over_websocket() {
+    use main::Transport;
+    use std::net::TcpListener;
+
+    let websocket_port = unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr()).port();
+    let mut config0 = gen_config();
+    config0.websocket_port = Some(websocket_port);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let _ = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    // Only the WebSocket contact, so the bootstrap can't go over TCP.
+    let mut config1 = gen_config();
+    config1.websocket_contacts = vec![localhost(websocket_port)];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+    assert_eq!(unwrap!(service1.peer_transport(&peer_id0)), Transport::WebSocket);
+    assert_eq!(unwrap!(service0.peer_transport(&peer_id1)), Transport::WebSocket);
+    assert_eq!(unwrap!(service1.peer_addr(&peer_id0)), localhost(websocket_port));
+    assert!(service1.is_peer_hard_coded(&peer_id0));
+
+    // More than fits a WebSocket frame.
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    unwrap!(service1.send(&peer_id0, data.clone(), 1));
+    expect_event!(event_rx0, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, peer_id1);
+        assert!(received == data);
+    });
+    unwrap!(service0.send(&peer_id1, data.clone(), 1));
+    expect_event!(event_rx1, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, peer_id0);
+        assert!(received == data);
+    });
+
+    assert!(service1.disconnect(&peer_id0));
+    expect_event!(event_rx1, Event::LostPeer(id, _, _) => assert_eq!(id, peer_id0));
+    expect_event!(event_rx0, Event::LostPeer(id, _, _) => assert_eq!(id, peer_id1));
+}
+
+/// A relay set up with `relay_config`, and two peers connected to it which are connected to each
+/// other through it, with their ids.
+#[cfg(feature = "relay")]
+fn connect_through_relay(
+    relay_config: main::RelayConfig,
+) -> (
+    Service,
+    Receiver<Event<UniqueId>>,
+    Vec<(Service, Receiver<Event<UniqueId>>, UniqueId)>,
+) {
+    use common::KeyPair;
+    use main::{CandidateAddr, PubConnectionInfo, Transport};
+
+    // The relay pairs only peers proving their key, so all of them have keys.
+    let mut config0 = gen_config();
+    config0.relay = Some(relay_config);
+    let keys0 = KeyPair::generate();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_keys(
+        event_tx0,
+        config0,
+        rand::random(),
+        keys0.clone(),
+    ));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let peer_id0 = service0.id();
+
+    let mut peers = Vec::new();
+    for _ in 0..2 {
+        let mut config = gen_config();
+        config.use_relays = true;
+        let keys = KeyPair::generate();
+        let (event_tx, event_rx) = get_event_sender();
+        let service = unwrap!(Service::with_keys(
+            event_tx,
+            config,
+            rand::random(),
+            keys.clone(),
+        ));
+        let peer_id = service.id();
+
+        service.prepare_connection_info(0);
+        let our_ci = expect_event!(event_rx, Event::ConnectionInfoPrepared(res) => {
+            unwrap!(res.result)
+        });
+        assert!(our_ci.for_relay.is_empty());
+        let their_ci = PubConnectionInfo {
+            id: peer_id0,
+            candidates: vec![CandidateAddr::TcpDirect(localhost(port0))],
+            issued_at: None,
+            ttl_secs: None,
+        };
+        unwrap!(service.connect(our_ci, their_ci));
+        expect_event!(event_rx, Event::ConnectSuccess(id, key) => {
+            assert_eq!(id, peer_id0);
+            assert_eq!(key, Some(keys0.public_key()));
+        });
+        expect_event!(event_rx0, Event::ConnectSuccess(id, key) => {
+            assert_eq!(id, peer_id);
+            assert_eq!(key, Some(keys.public_key()));
+        });
+        peers.push((service, event_rx, peer_id));
+    }
+
+    // Each lists the relay in its info, and is given only the relay to reach the other by.
+    let ids = [peers[0].2, peers[1].2];
+    for (i, &(ref service, ref event_rx, _)) in peers.iter().enumerate() {
+        service.prepare_connection_info(0);
+        let our_ci = expect_event!(event_rx, Event::ConnectionInfoPrepared(res) => {
+            unwrap!(res.result)
+        });
+        assert_eq!(our_ci.for_relay.len(), 1);
+        assert_eq!(our_ci.for_relay[0].port(), port0);
+        let their_ci = PubConnectionInfo {
+            id: ids[1 - i],
+            candidates: vec![CandidateAddr::Relay(our_ci.for_relay[0])],
+            issued_at: None,
+            ttl_secs: None,
+        };
+        unwrap!(service.connect(our_ci, their_ci));
+    }
+    for (i, &(ref service, ref event_rx, _)) in peers.iter().enumerate() {
+        expect_event!(event_rx, Event::ConnectSuccess(id, _) => assert_eq!(id, ids[1 - i]));
+        let transport = unwrap!(service.peer_transport(&ids[1 - i]));
+        assert_eq!(transport, Transport::Relayed);
+        assert!(transport.is_relayed());
+    }
+    (service0, event_rx0, peers)
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn connect_through_a_relay() {
+    use main::RelayConfig;
+
+    let (_relay, _relay_rx, peers) = connect_through_relay(RelayConfig {
+        max_sessions: 4,
+        max_pair_bytes_per_sec: None,
+        max_bytes_per_sec: None,
+        window_bytes: None,
+    });
+    let ids = [peers[0].2, peers[1].2];
+
+    // Messages go through the relay both ways.
+    unwrap!(peers[0].0.send(&ids[1], b"through".to_vec(), 1));
+    expect_event!(peers[1].1, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, ids[0]);
+        assert_eq!(received, b"through");
+    });
+    unwrap!(peers[1].0.send(&ids[0], b"back".to_vec(), 1));
+    expect_event!(peers[0].1, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, ids[1]);
+        assert_eq!(received, b"back");
+    });
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn relays_drop_frames_over_their_quota() {
+    use main::RelayConfig;
+
+    const SENT: usize = 32;
+    const SIZE: usize = 16 * 1024;
+    let (relay, _relay_rx, peers) = connect_through_relay(RelayConfig {
+        max_sessions: 4,
+        max_pair_bytes_per_sec: Some(4 * SIZE as u64),
+        max_bytes_per_sec: None,
+        window_bytes: None,
+    });
+    let ids = [peers[0].2, peers[1].2];
+
+    // Far more is sent at once than the quota lets through in a second. Once the quota is back,
+    // a last message still opens, as the peer was told which frames went missing.
+    for i in 0..SENT {
+        unwrap!(peers[0].0.send(&ids[1], vec![i as u8; SIZE], 1));
+    }
+    thread::sleep(Duration::from_millis(1500));
+    unwrap!(peers[0].0.send(&ids[1], b"after".to_vec(), 1));
+
+    let mut received = Vec::new();
+    loop {
+        match unwrap!(peers[1].1.recv_timeout(Duration::from_secs(10))) {
+            Event::NewMessage(id, _, ref data, _) if data == b"after" => {
+                assert_eq!(id, ids[0]);
+                break;
+            }
+            Event::NewMessage(id, _, data, _) => {
+                assert_eq!(id, ids[0]);
+                assert_eq!(data.len(), SIZE);
+                received.push(data[0] as usize);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+    assert!(!received.is_empty() && received.len() < SENT);
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let stats = unwrap!(relay.core_stats());
+    assert_eq!(stats.relayed_pairs, 1);
+    assert_eq!(stats.relayed_frames_dropped, (SENT - received.len()) as u64);
+    assert!(stats.relayed_bytes > (received.len() * SIZE) as u64);
+    // What went through the relay isn't traffic of the peers with it.
+    assert!(unwrap!(relay.peer_stats(&ids[0])).bytes_received < SIZE as u64);
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn relays_hold_fast_senders_to_their_window() {
+    use main::RelayConfig;
+
+    const WINDOW: u64 = 64 * 1024;
+    const SIZE: usize = 16 * 1024;
+    const BATCH: usize = 64;
+    const MAX_SENT: usize = 4096;
+    let (relay, _relay_rx, peers) = connect_through_relay(RelayConfig {
+        max_sessions: 4,
+        max_pair_bytes_per_sec: None,
+        max_bytes_per_sec: None,
+        window_bytes: Some(WINDOW),
+    });
+    let ids = [peers[0].2, peers[1].2];
+
+    // The receiver reads a few messages a second, so once the buffers of the kernel are full the
+    // relay has as much queued for it as the window takes, and the sender stops.
+    unwrap!(peers[1]
+        .0
+        .set_peer_bandwidth_limits(&ids[0], None, Some(4 * SIZE as u64)));
+    let mut sent = 0;
+    'sending: while sent < MAX_SENT {
+        for _ in 0..BATCH {
+            unwrap!(peers[0].0.send(&ids[1], vec![0; SIZE], 1));
+        }
+        sent += BATCH;
+        while let Ok(event) = peers[0].1.recv_timeout(Duration::from_millis(100)) {
+            match event {
+                Event::PeerCongested {
+                    peer_id,
+                    congested: true,
+                } => {
+                    assert_eq!(peer_id, ids[1]);
+                    break 'sending;
+                }
+                event => panic!("Unexpected event: {:?}", event),
+            }
+        }
+    }
+    assert!(sent < MAX_SENT);
+
+    let stats = unwrap!(relay.core_stats());
+    assert!(stats.relayed_max_queued_bytes > 0);
+    assert!(stats.relayed_max_queued_bytes <= WINDOW);
+    assert_eq!(stats.relayed_frames_dropped, 0);
+}
+
+/// Waits for the outcome of upgrading the connection to a peer, passing over the updates of the
+/// listeners of the peers, and the messages, which are returned alongside in order.
+#[cfg(feature = "relay")]
+fn expect_upgrade_outcome(
+    event_rx: &Receiver<Event<UniqueId>>,
+) -> (Event<UniqueId>, Vec<Vec<u8>>) {
+    let mut received = Vec::new();
+    loop {
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(30))) {
+            Event::PeerContactInfoUpdated(_) => (),
+            Event::NewMessage(_, _, data, _) => received.push(data),
+            event @ Event::ConnectionUpgraded { .. } => return (event, received),
+            event @ Event::ConnectionUpgradeFailed { .. } => return (event, received),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn relayed_connection_is_upgraded_onto_a_direct_one() {
+    use main::{RelayConfig, Transport};
+
+    let (_relay, _relay_rx, mut peers) = connect_through_relay(RelayConfig {
+        max_sessions: 4,
+        max_pair_bytes_per_sec: None,
+        max_bytes_per_sec: None,
+        window_bytes: None,
+    });
+    let ids = [peers[0].2, peers[1].2];
+
+    // The peer listens, so we dial it.
+    unwrap!(peers[1].0.start_listening_tcp());
+    expect_event!(peers[1].1, Event::ListenerStarted(_port));
+
+    unwrap!(peers[0].0.upgrade_connection(&ids[1]));
+    for i in 0..2 {
+        match expect_upgrade_outcome(&peers[i].1) {
+            (Event::ConnectionUpgraded { peer_id, from, to }, _) => {
+                assert_eq!(peer_id, ids[1 - i]);
+                assert_eq!(from, Transport::Relayed);
+                assert_eq!(to, Transport::Tcp);
+            }
+            (event, _) => panic!("Unexpected event: {:?}", event),
+        }
+        assert_eq!(unwrap!(peers[i].0.peer_transport(&ids[1 - i])), Transport::Tcp);
+    }
+
+    unwrap!(peers[0].0.send(&ids[1], b"direct".to_vec(), 1));
+    expect_event!(peers[1].1, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, ids[0]);
+        assert_eq!(received, b"direct");
+    });
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn failed_upgrade_leaves_the_connection_as_it_was() {
+    use main::{RelayConfig, Transport};
+
+    let (_relay, _relay_rx, peers) = connect_through_relay(RelayConfig {
+        max_sessions: 4,
+        max_pair_bytes_per_sec: None,
+        max_bytes_per_sec: None,
+        window_bytes: None,
+    });
+    let ids = [peers[0].2, peers[1].2];
+
+    // Neither peer listens, so there is nothing to dial.
+    unwrap!(peers[0].0.upgrade_connection(&ids[1]));
+    match expect_upgrade_outcome(&peers[0].1) {
+        (Event::ConnectionUpgradeFailed { peer_id }, _) => assert_eq!(peer_id, ids[1]),
+        (event, _) => panic!("Unexpected event: {:?}", event),
+    }
+    assert_eq!(unwrap!(peers[0].0.peer_transport(&ids[1])), Transport::Relayed);
+
+    unwrap!(peers[0].0.send(&ids[1], b"still relayed".to_vec(), 1));
+    expect_event!(peers[1].1, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, ids[0]);
+        assert_eq!(received, b"still relayed");
+    });
+    unwrap!(peers[1].0.send(&ids[0], b"back".to_vec(), 1));
+    expect_event!(peers[0].1, Event::NewMessage(id, _, received, _) => {
+        assert_eq!(id, ids[1]);
+        assert_eq!(received, b"back");
+    });
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn messages_sent_during_an_upgrade_arrive_in_order() {
+    use main::{RelayConfig, Transport};
+
+    const SENT: u32 = 200;
+    let (_relay, _relay_rx, mut peers) = connect_through_relay(RelayConfig {
+        max_sessions: 4,
+        max_pair_bytes_per_sec: None,
+        max_bytes_per_sec: None,
+        window_bytes: None,
+    });
+    let ids = [peers[0].2, peers[1].2];
+
+    // Only we listen, so dialling the peer fails and it dials us instead.
+    unwrap!(peers[0].0.start_listening_tcp());
+    expect_event!(peers[0].1, Event::ListenerStarted(_port));
+
+    let message = |i: u32| format!("{:04}", i).into_bytes();
+    for i in 0..SENT {
+        unwrap!(peers[0].0.send(&ids[1], message(i), 1));
+    }
+    unwrap!(peers[0].0.upgrade_connection(&ids[1]));
+    for i in SENT..2 * SENT {
+        unwrap!(peers[0].0.send(&ids[1], message(i), 1));
+    }
+
+    match expect_upgrade_outcome(&peers[0].1) {
+        (Event::ConnectionUpgraded { peer_id, to, .. }, _) => {
+            assert_eq!(peer_id, ids[1]);
+            assert_eq!(to, Transport::Tcp);
+        }
+        (event, _) => panic!("Unexpected event: {:?}", event),
+    }
+    let (event, mut received) = expect_upgrade_outcome(&peers[1].1);
+    match event {
+        Event::ConnectionUpgraded { peer_id, .. } => assert_eq!(peer_id, ids[0]),
+        event => panic!("Unexpected event: {:?}", event),
+    }
+    while received.len() < 2 * SENT as usize {
+        expect_event!(peers[1].1, Event::NewMessage(id, _, data, _) => {
+            assert_eq!(id, ids[0]);
+            received.push(data);
+        });
+    }
+    let expected: Vec<_> = (0..2 * SENT).map(message).collect();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn dual_stack_listener_accepts_both_address_families() {
+    use main::{CandidateAddr, PubConnectionInfo};
+
+    let mut config0 = gen_config();
+    config0.dual_stack = true;
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let peer_id0 = service0.id();
+
+    service0.prepare_connection_info(0);
+    let info0 = expect_event!(event_rx0, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result)
+    });
+    assert!(info0.for_direct.iter().any(|addr| addr.is_ipv4()));
+    assert!(info0.for_direct.iter().any(|addr| addr.is_ipv6()));
+
+    let v6_localhost = SocketAddr::new(unwrap!(IpAddr::from_str("::1")), port0);
+    for &addr in &[localhost(port0), v6_localhost] {
+        let (event_tx1, event_rx1) = get_event_sender();
+        let service1 = unwrap!(Service::with_config(event_tx1, gen_config(), rand::random()));
+        let peer_id1 = service1.id();
+
+        service1.prepare_connection_info(0);
+        let our_ci = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+            unwrap!(res.result)
+        });
+        let their_ci = PubConnectionInfo {
+            id: peer_id0,
+            candidates: vec![CandidateAddr::TcpDirect(addr)],
+            issued_at: None,
+            ttl_secs: None,
+        };
+        unwrap!(service1.connect(our_ci, their_ci));
+        expect_event!(event_rx1, Event::ConnectSuccess(id, _) => assert_eq!(id, peer_id0));
+        expect_event!(event_rx0, Event::ConnectSuccess(id, _) => assert_eq!(id, peer_id1));
+        // IPv4 peers are seen at their IPv4 address, not at an IPv4-mapped IPv6 one.
+        assert_eq!(unwrap!(service0.peer_addr(&peer_id1)).is_ipv4(), addr.is_ipv4());
+
+        assert!(service1.disconnect(&peer_id0));
+        expect_event!(event_rx0, Event::LostPeer(id, _, _) => assert_eq!(id, peer_id1));
+    }
+}
+
+#[test]
+fn park_and_unpark_peer() {
+    use {CrustError, PeerStats};
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    unwrap!(service1.send(&peer_id0, b"before parking".to_vec(), 1));
+    unwrap!(service1.park(&peer_id0));
+
+    // The queued message is flushed before the socket is closed, which the other side notices.
+    expect_event!(event_rx1, Event::PeerParked(peer_id) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx0, Event::NewMessage(_, _, data, _) => {
+        assert_eq!(data, b"before parking".to_vec());
+    });
+    expect_event!(event_rx0, Event::LostPeer(peer_id, _, _) => assert_eq!(peer_id, peer_id1));
+
+    assert!(!service1.is_connected(&peer_id0));
+    assert_eq!(unwrap!(service1.parked_peer_stats(&peer_id0)).msgs_sent, 1);
+    match service1.send(&peer_id0, b"while parked".to_vec(), 1) {
+        Err(CrustError::PeerParked) => (),
+        res => panic!("Expected CrustError::PeerParked, got {:?}", res),
+    }
+
+    unwrap!(service1.unpark(&peer_id0));
+
+    expect_event!(event_rx1, Event::PeerUnparked(peer_id) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx0, Event::ConnectSuccess(peer_id, _) => assert_eq!(peer_id, peer_id1));
+    assert!(service1.parked_peer_stats(&peer_id0).is_none());
+
+    unwrap!(service1.send(&peer_id0, b"after unparking".to_vec(), 1));
+    expect_event!(event_rx0, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, b"after unparking".to_vec());
+    });
+
+    unwrap!(service0.send(&peer_id1, b"reply".to_vec(), 1));
+    expect_event!(event_rx1, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, b"reply".to_vec());
+    });
+
+    // The stats carried over from before parking count on, until reset. The features are those of
+    // the new connection, which wasn't made by bootstrapping.
+    let stats = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!((stats.msgs_sent, stats.msgs_received), (2, 1));
+    assert!(stats.bytes_sent > 0 && stats.bytes_received > 0);
+    let network_stats = unwrap!(service1.network_stats());
+    assert_eq!(network_stats.connected_peers, 1);
+    assert!(network_stats.bytes_sent >= stats.bytes_sent);
+    assert!(stats.features.extensions);
+    assert_eq!(stats.features.bootstrap_role, None);
+    unwrap!(service1.reset_peer_stats(&peer_id0));
+    let stats = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!(
+        stats,
+        PeerStats {
+            features: stats.features,
+            connected_for: stats.connected_for,
+            ..PeerStats::default()
+        }
+    );
+}
+
+#[test]
+fn sending_to_parked_peer_unparks_it() {
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    config1.auto_unpark_on_send = true;
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    unwrap!(service1.park(&peer_id0));
+    expect_event!(event_rx1, Event::PeerParked(peer_id) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, _, _) => assert_eq!(peer_id, peer_id1));
+
+    unwrap!(service1.send(&peer_id0, b"wake up".to_vec(), 1));
+
+    expect_event!(event_rx1, Event::PeerUnparked(peer_id) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx0, Event::ConnectSuccess(peer_id, _) => assert_eq!(peer_id, peer_id1));
+    expect_event!(event_rx0, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, b"wake up".to_vec());
+    });
+}
+
+#[test]
+fn rebound_listener_is_advertised_to_connected_peers() {
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    let cached_addrs = |service: &Service| -> Vec<SocketAddr> {
+        unwrap!(service.bootstrap_cache_snapshot())
+            .into_iter()
+            .map(|entry| entry.addr)
+            .collect()
+    };
+    assert!(cached_addrs(&service1).contains(&localhost(port0)));
+
+    // Rebind twice in a row: the second change is held back by the rate limit, but still makes it
+    // to the peer.
+    let mut new_port = port0;
+    for _ in 0..2 {
+        unwrap!(service0.stop_tcp_listener());
+        unwrap!(service0.start_listening_tcp());
+        new_port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+        expect_event!(event_rx1, Event::PeerContactInfoUpdated(peer_id) => {
+            assert_eq!(peer_id, peer_id0);
+        });
+    }
+    assert_ne!(new_port, port0);
+
+    let cached = cached_addrs(&service1);
+    assert!(cached.contains(&localhost(new_port)));
+    assert!(!cached.contains(&localhost(port0)));
+
+    // Nothing listens on the old port any more, so reconnecting only works over the new one.
+    unwrap!(service1.park(&peer_id0));
+    expect_event!(event_rx1, Event::PeerParked(peer_id) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, _, _) => assert_eq!(peer_id, peer_id1));
+
+    unwrap!(service1.unpark(&peer_id0));
+    expect_event!(event_rx1, Event::PeerUnparked(peer_id) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx0, Event::ConnectSuccess(peer_id, _) => assert_eq!(peer_id, peer_id1));
+}
+
+#[test]
+fn disconnect_many_and_all_with_reason() {
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let clients: Vec<_> = (0..3)
+        .map(|_| {
+            let mut config = gen_config();
+            config.hard_coded_contacts = vec![localhost_contact_info(port0)];
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+            unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+            expect_event!(event_rx, Event::BootstrapConnect(..));
+            expect_event!(event_rx0, Event::BootstrapAccept(..));
+            (service, event_rx)
+        })
+        .collect();
+    let ids: Vec<_> = clients
+        .iter()
+        .map(|&(ref service, _)| service.id())
+        .collect();
+
+    let unknown_id = rand::random();
+    let disconnected = unwrap!(service0.disconnect_many(vec![ids[0], unknown_id, ids[2]], 7));
+    assert_eq!(disconnected, vec![ids[0], ids[2]]);
+
+    let mut lost = HashSet::new();
+    for _ in 0..2 {
+        expect_event!(event_rx0, Event::LostPeer(id, DisconnectReason::LocalRequested(7), _) => {
+            assert!(lost.insert(id));
+        });
+    }
+    assert_eq!(lost, vec![ids[0], ids[2]].into_iter().collect());
+    for i in &[0, 2] {
+        let reason = DisconnectReason::RemoteRequested(7);
+        expect_event!(clients[*i].1, Event::LostPeer(id, lost_reason, _) => {
+            assert_eq!(id, service0.id());
+            assert_eq!(lost_reason, reason);
+        });
+    }
+
+    assert!(service0.is_connected(&ids[1]));
+    assert!(clients[1].1.try_recv().is_err());
+
+    assert_eq!(unwrap!(service0.disconnect_all(9)), vec![ids[1]]);
+    expect_event!(event_rx0, Event::LostPeer(id, DisconnectReason::LocalRequested(9), _) => {
+        assert_eq!(id, ids[1]);
+    });
+    expect_event!(clients[1].1, Event::LostPeer(id, DisconnectReason::RemoteRequested(9), _) => {
+        assert_eq!(id, service0.id());
+    });
+    assert!(unwrap!(service0.disconnect_all(9)).is_empty());
+}
+
+#[test]
+fn shutdown_drains_the_peers_first() {
+    let pair = ServicePair::<UniqueId>::new();
+    let (peer_id0, peer_id1) = (pair.id0(), pair.id1());
+    let ServicePair {
+        service0, events1, ..
+    } = pair;
+
+    // What was queued before is written ahead of the goodbye.
+    unwrap!(service0.send(&peer_id1, b"last words".to_vec(), 1));
+    assert_eq!(unwrap!(service0.shutdown(Duration::from_secs(10))), 1);
+    expect_crust_event!(events1, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, b"last words".to_vec());
+    });
+    expect_crust_event!(events1, Event::LostPeer(peer_id, reason, _) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(reason, DisconnectReason::RemoteRequested(0));
+    });
+}
+
+// Note: if this test fails, make sure that a firewall on your system allows UDP broadcasts
+#[test]
+#[cfg(feature = "service-discovery")]
+fn bootstrap_two_services_using_service_discovery() {
+    let service_discovery_port = gen_service_discovery_port();
+
+    let mut config = gen_config();
+    config.service_discovery_port = Some(service_discovery_port);
+
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        config.clone(),
+        rand::random()
+    ));
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config, rand::random()));
 
-#[test]
-fn bootstrap_two_services_and_exchange_messages() {
-    let config0 = gen_config();
-    let (event_tx0, event_rx0) = get_event_sender();
-    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service1.start_listening_tcp());
+    let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
 
+    service0.start_service_discovery();
+    service0.set_service_discovery_listen(true);
     unwrap!(service0.start_listening_tcp());
 
-    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    expect_event!(event_rx0, Event::ListenerStarted(_port));
     unwrap!(service0.set_accept_bootstrap(true));
 
-    let mut config1 = gen_config();
-    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
-
-    let (event_tx1, event_rx1) = get_event_sender();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
-
+    service1.start_service_discovery();
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0,
-                                 Event::BootstrapAccept(peer_id, CrustUser::Client) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
-
-    let message0 = b"hello from 0".to_vec();
-    unwrap!(service0.send(&peer_id1, message0.clone(), 0));
-
-    expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data) => {
-        assert_eq!(peer_id, peer_id0);
-        assert_eq!(data, message0);
-    });
-
-    let message1 = b"hello from 1".to_vec();
-    unwrap!(service1.send(&peer_id0, message1.clone(), 0));
-
-    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data) => {
-        assert_eq!(peer_id, peer_id1);
-        assert_eq!(data, message1);
-    });
 }
 
 // Note: if this test fails, make sure that a firewall on your system allows UDP broadcasts
 #[test]
-fn bootstrap_two_services_using_service_discovery() {
+#[cfg(feature = "service-discovery")]
+fn lan_only_nodes_bootstrap_using_service_discovery() {
+    use nat;
+
     let service_discovery_port = gen_service_discovery_port();
 
     let mut config = gen_config();
     config.service_discovery_port = Some(service_discovery_port);
+    config.lan_only = true;
 
     let (event_tx0, event_rx0) = get_event_sender();
     let mut service0 = unwrap!(Service::with_config(
@@ -112,14 +1788,26 @@ fn bootstrap_two_services_using_service_discovery() {
     expect_event!(event_rx0, Event::ListenerStarted(_port));
     unwrap!(service0.set_accept_bootstrap(true));
 
+    // Nodes bootstrapping off us aren't tested for external reachability in LAN-only mode.
     service1.start_service_discovery();
-    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Node));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0,
+                                 Event::BootstrapAccept(peer_id, CrustUser::Node, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
+
+    service0.prepare_connection_info(0);
+    let our_ci = expect_event!(event_rx0,
+                               Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+    assert!(!our_ci.for_direct.is_empty());
+    assert!(our_ci.for_hole_punch.is_empty());
+    assert!(our_ci
+        .for_direct
+        .iter()
+        .all(|addr| !nat::ip_addr_is_global(&addr.ip())));
 }
 
 #[test]
@@ -150,13 +1838,172 @@ fn bootstrap_with_multiple_contact_endpoints() {
     unwrap!(service1.start_listening_tcp());
     let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 }
 
+#[test]
+fn bootstrap_falls_back_on_requests_without_extensions() {
+    use common::{decode_message, encode_frame, FrameDecoder, Message, MAX_PAYLOAD_SIZE};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        loop {
+            let mut input = &buf[..unwrap!(stream.read(&mut buf))];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    return body;
+                }
+            }
+        }
+    }
+
+    // A peer of a version older than the extensions, which closes the connection on requests it
+    // can't decode.
+    let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+    let addr = unwrap!(listener.local_addr());
+    let old_id: UniqueId = rand::random();
+    let old_peer = thread::spawn(move || {
+        let (mut stream, _) = unwrap!(listener.accept());
+        let body = read_message(&mut stream);
+        match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+            Message::ExtBootstrapRequest(..) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        drop(stream);
+
+        let (mut stream, _) = unwrap!(listener.accept());
+        let body = read_message(&mut stream);
+        match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+            Message::BootstrapRequest(..) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        let granted = Message::BootstrapGranted(old_id);
+        unwrap!(stream.write_all(&unwrap!(encode_frame(&granted))));
+        stream
+    });
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![addr];
+    let (event_tx, event_rx) = get_event_sender();
+    let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+    unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id, old_id);
+    assert!(!unwrap!(service.peer_stats(&old_id)).features.extensions);
+    drop(unwrap!(old_peer.join()));
+}
+
+#[test]
+fn peers_without_extensions_are_sent_nothing_they_cannot_decode() {
+    use common::{decode_message, encode_frame, FrameDecoder, Message, MAX_PAYLOAD_SIZE};
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut buf = [0; 1024];
+        loop {
+            let mut input = &buf[..unwrap!(stream.read(&mut buf))];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    return body;
+                }
+            }
+        }
+    }
+
+    // Keeps the connection up with heartbeats until `until`, and returns what it was sent.
+    fn exchange_heartbeats(stream: &mut TcpStream, until: Instant) -> Vec<Message<UniqueId>> {
+        let heartbeat = unwrap!(encode_frame(&Message::Heartbeat::<UniqueId>));
+        unwrap!(stream.set_read_timeout(Some(Duration::from_millis(100))));
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD_SIZE);
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while Instant::now() < until {
+            unwrap!(stream.write_all(&heartbeat));
+            let bytes_read = match stream.read(&mut buf) {
+                Ok(0) => panic!("Connection closed"),
+                Ok(bytes_read) => bytes_read,
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) => panic!("Could not read: {:?}", e),
+            };
+            let mut input = &buf[..bytes_read];
+            while !input.is_empty() {
+                if let Some(body) = unwrap!(decoder.decode(&mut input)) {
+                    received.push(unwrap!(decode_message(&body)));
+                }
+            }
+        }
+        received
+    }
+
+    // A peer of a version older than the extensions, as in the test above, which stays connected
+    // for a few seconds.
+    let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+    let addr = unwrap!(listener.local_addr());
+    let old_id: UniqueId = rand::random();
+    let old_peer = thread::spawn(move || {
+        let (mut stream, _) = unwrap!(listener.accept());
+        let _ = read_message(&mut stream);
+        drop(stream);
+
+        let (mut stream, _) = unwrap!(listener.accept());
+        let body = read_message(&mut stream);
+        match unwrap!(decode_message::<Message<UniqueId>>(&body)) {
+            Message::BootstrapRequest(..) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        let granted = Message::BootstrapGranted(old_id);
+        unwrap!(stream.write_all(&unwrap!(encode_frame(&granted))));
+        let received = exchange_heartbeats(&mut stream, Instant::now() + Duration::from_secs(3));
+        (stream, received)
+    });
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![addr];
+    config.probe_after_idle_secs = Some(1);
+    config.latency_probe_interval_secs = Some(1);
+    let (event_tx, event_rx) = get_event_sender();
+    let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+    unwrap!(service.start_listening_tcp());
+    expect_event!(event_rx, Event::ListenerStarted(_));
+    unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id, old_id);
+
+    // Neither a listener rebound nor the probe interval passing make for a message the peer can't
+    // decode, nor does the peer get dropped for not answering probes.
+    unwrap!(service.stop_tcp_listener());
+    unwrap!(service.start_listening_tcp());
+    expect_event!(event_rx, Event::ListenerStarted(_));
+
+    let (_stream, received) = unwrap!(old_peer.join());
+    assert!(!received.is_empty());
+    for msg in received {
+        assert_eq!(msg, Message::Heartbeat);
+    }
+    let features = unwrap!(service.peer_stats(&old_id)).features;
+    assert!(!features.probes);
+    assert!(!features.contact_updates);
+    for event in event_rx.try_iter() {
+        if let Event::LostPeer(..) = event {
+            panic!("Unexpected event: {:?}", event);
+        }
+    }
+}
+
 #[test]
 fn bootstrap_with_skipped_external_reachability_test() {
     let mut config = Config::default();
@@ -177,13 +2024,64 @@ fn bootstrap_with_skipped_external_reachability_test() {
     let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Node));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 }
 
+#[test]
+fn client_promoted_to_node_after_listening() {
+    use CrustError;
+
+    // Our listeners on this host can only be reached at their private addresses.
+    let mut config0 = gen_config();
+    config0.dev = Some(DevConfig {
+        disable_external_reachability_requirement: true,
+    });
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost(port0)];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0,
+                                 Event::BootstrapAccept(peer_id, CrustUser::Client, _) => peer_id);
+
+    match service1.promote_to_node() {
+        Err(CrustError::ListenerNotIntialised) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    unwrap!(service1.start_listening_tcp());
+    let port1 = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+    // Connected peers learn about new listeners in any case, but nothing more.
+    expect_event!(event_rx0, Event::PeerContactInfoUpdated(peer_id) => {
+        assert_eq!(peer_id, peer_id1)
+    });
+    unwrap!(service1.promote_to_node());
+    expect_event!(event_rx0, Event::PeerPromoted { peer_id } => assert_eq!(peer_id, peer_id1));
+
+    // The listener service 0 reached is cached as the peer's...
+    let cache = unwrap!(service0.bootstrap_cache_snapshot());
+    assert!(cache.iter().any(|entry| entry.addr.port() == port1));
+
+    // ...and its messages now come from a node. Asking again changes nothing.
+    unwrap!(service1.promote_to_node());
+    unwrap!(service1.send(&peer_id0, vec![1], 1));
+    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Node, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, vec![1]);
+    });
+}
+
 #[test]
 fn bootstrap_with_blacklist() {
     use std::net::TcpListener;
@@ -214,10 +2112,10 @@ fn bootstrap_with_blacklist() {
     unwrap!(service1.start_listening_tcp());
     let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 
     let blacklisted_listener = unwrap!(mio::tcp::TcpListener::from_listener(
@@ -268,6 +2166,296 @@ fn bootstrap_fails_if_there_are_no_contacts() {
     expect_event!(event_rx, Event::BootstrapFailed);
 }
 
+#[test]
+fn bootstrap_rejections_are_reported() {
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let addr0 = localhost_contact_info(port0);
+
+    // Not taking bootstraps yet: we are told to come back later and don't try before then.
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![addr0];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapAttemptFailed(addr, rejection) => {
+        assert_eq!(addr, addr0);
+        assert_eq!(rejection.kind(), RejectionCode::Full);
+        assert!(rejection.retry_after_secs.is_some());
+    });
+    expect_event!(event_rx1, Event::BootstrapFailed);
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed);
+
+    // On another network we are told so.
+    unwrap!(service0.set_accept_bootstrap(true));
+    let mut config2 = gen_config();
+    config2.hard_coded_contacts = vec![addr0];
+    config2.network_name = Some("another network".to_owned());
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config2, rand::random()));
+
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapAttemptFailed(addr, rejection) => {
+        assert_eq!(addr, addr0);
+        assert_eq!(rejection.kind(), RejectionCode::WrongNetwork);
+        assert_eq!(rejection.retry_after_secs, None);
+    });
+    expect_event!(event_rx2, Event::BootstrapFailed);
+}
+
+#[test]
+fn draining_refers_peers_elsewhere_and_shuts_down_after_the_grace_period() {
+    // The peer the retiring service bootstrapped off, which it refers its peers to.
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, gen_config(), rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    let addr0 = localhost_contact_info(port0);
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![addr0];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_listening_tcp());
+    let port1 = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+    unwrap!(service1.set_accept_bootstrap(true));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+    let addr1 = localhost_contact_info(port1);
+
+    let mut config2 = gen_config();
+    config2.hard_coded_contacts = vec![addr1];
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config2, rand::random()));
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id1 = expect_event!(event_rx2, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id2 = expect_event!(event_rx1, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    let grace = Duration::from_secs(3);
+    let started = Instant::now();
+    unwrap!(service1.begin_draining(grace));
+    expect_event!(event_rx1, Event::DrainingProgress { remaining_peers, remaining_time } => {
+        assert_eq!(remaining_peers, 2);
+        assert_eq!(remaining_time, grace);
+    });
+    expect_event!(event_rx2, Event::PeerRetiring(peer_id, alternatives) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(alternatives, vec![addr0]);
+    });
+
+    // A new client is refused and referred to the other peer.
+    let mut config3 = gen_config();
+    config3.hard_coded_contacts = vec![addr1];
+    let (event_tx3, event_rx3) = get_event_sender();
+    let mut service3 = unwrap!(Service::with_config(event_tx3, config3, rand::random()));
+    unwrap!(service3.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx3, Event::BootstrapAttemptFailed(addr, rejection) => {
+        assert_eq!(addr, addr1);
+        assert_eq!(rejection.kind(), RejectionCode::Retiring);
+        assert_eq!(rejection.alternatives, vec![addr0]);
+    });
+    expect_event!(event_rx3, Event::BootstrapFailed);
+
+    // The peer connected already keeps exchanging messages meanwhile.
+    unwrap!(service2.send(&peer_id1, b"still there".to_vec(), 1));
+    let received = test_utils::wait_for_event(&event_rx1, "NewMessage", |event| match event {
+        Event::NewMessage(peer_id, _, data, _) => Some((peer_id, data)),
+        _ => None,
+    });
+    assert_eq!(received, (peer_id2, b"still there".to_vec()));
+    unwrap!(service1.send(&peer_id2, b"still here".to_vec(), 1));
+    expect_event!(event_rx2, Event::NewMessage(peer_id, _, data, _) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, b"still here".to_vec());
+    });
+
+    let remaining_peers = test_utils::wait_for_event(&event_rx1, "shutdown", |event| match event {
+        Event::DrainingProgress {
+            remaining_peers,
+            remaining_time,
+        } if remaining_time == Duration::from_secs(0) => Some(remaining_peers),
+        _ => None,
+    });
+    assert!(started.elapsed() >= grace);
+    assert_eq!(remaining_peers, 2);
+    expect_event!(event_rx2, Event::LostPeer(peer_id, _, _) => assert_eq!(peer_id, peer_id1));
+}
+
+#[test]
+fn private_networks_let_in_only_peers_with_their_secret() {
+    let config = |secret: Option<&str>| {
+        let mut config = gen_config();
+        config.network_name = Some("private network".to_owned());
+        config.network_secret = secret.map(str::to_owned);
+        config
+    };
+    // Starts a service taking bootstraps, and returns it along with its address.
+    let start = |config| {
+        let (event_tx, event_rx) = get_event_sender();
+        let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+        unwrap!(service.start_listening_tcp());
+        let port = expect_event!(event_rx, Event::ListenerStarted(port) => port);
+        unwrap!(service.set_accept_bootstrap(true));
+        (service, event_rx, localhost_contact_info(port))
+    };
+    // Returns the kind of rejection of bootstrapping off `contact`, if it is rejected.
+    let bootstrap = |mut config: Config, contact| {
+        config.hard_coded_contacts = vec![contact];
+        let (event_tx, event_rx) = get_event_sender();
+        let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+        unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+        match unwrap!(event_rx.recv_timeout(Duration::from_secs(30))) {
+            Event::BootstrapConnect(..) => None,
+            Event::BootstrapAttemptFailed(_, rejection) => Some(rejection.kind()),
+            event => panic!("unexpected event {:?}", event),
+        }
+    };
+
+    let (_private, private_rx, private_addr) = start(config(Some("secret")));
+    assert_eq!(bootstrap(config(Some("secret")), private_addr), None);
+    expect_event!(private_rx, Event::BootstrapAccept(_, CrustUser::Client, _));
+    let wrong_network = Some(RejectionCode::WrongNetwork);
+    assert_eq!(bootstrap(config(Some("other secret")), private_addr), wrong_network);
+    assert_eq!(bootstrap(config(None), private_addr), wrong_network);
+
+    // Nor does a private network let itself into a public one of the same name.
+    let (_public, _public_rx, public_addr) = start(config(None));
+    assert_eq!(bootstrap(config(Some("secret")), public_addr), wrong_network);
+}
+
+#[test]
+fn clients_and_nodes_are_limited_separately() {
+    let mut config0 = gen_config();
+    config0.dev = Some(DevConfig {
+        disable_external_reachability_requirement: true,
+    });
+    config0.max_client_peers = Some(1);
+    config0.max_node_peers = Some(1);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    let addr0 = localhost_contact_info(port0);
+
+    // Returns the service and whether it was let in.
+    let bootstrap = |kind: CrustUser| {
+        let mut config = gen_config();
+        config.hard_coded_contacts = vec![addr0];
+        let (event_tx, event_rx) = get_event_sender();
+        let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+        unwrap!(service.start_bootstrap(HashSet::new(), kind));
+        let accepted = match unwrap!(event_rx.recv_timeout(Duration::from_secs(30))) {
+            Event::BootstrapConnect(..) => true,
+            Event::BootstrapAttemptFailed(_, rejection) => {
+                assert_eq!(rejection.kind(), RejectionCode::Full);
+                false
+            }
+            event => panic!("unexpected event {:?}", event),
+        };
+        if accepted {
+            expect_event!(event_rx0, Event::BootstrapAccept(_, peer_kind, _) => {
+                assert_eq!(peer_kind, kind);
+            });
+        }
+        (service, accepted)
+    };
+
+    // The client quota being used up doesn't keep a node out...
+    let (client0, accepted) = bootstrap(CrustUser::Client);
+    assert!(accepted);
+    let (_client1, accepted) = bootstrap(CrustUser::Client);
+    assert!(!accepted);
+    let (node0, accepted) = bootstrap(CrustUser::Node);
+    assert!(accepted);
+    let (_node1, accepted) = bootstrap(CrustUser::Node);
+    assert!(!accepted);
+
+    let stats = unwrap!(service0.core_stats());
+    assert_eq!((stats.client_peers, stats.node_peers), (1, 1));
+    assert_eq!((stats.client_handshakes, stats.node_handshakes), (0, 0));
+    assert_eq!((stats.clients_rejected, stats.nodes_rejected), (1, 1));
+    let mut peers: Vec<_> = unwrap!(service0.connected_peers())
+        .into_iter()
+        .map(|peer| (peer.id, peer.kind))
+        .collect();
+    peers.sort_by_key(|&(_, kind)| kind == CrustUser::Node);
+    assert_eq!(
+        peers,
+        vec![(client0.id(), CrustUser::Client), (node0.id(), CrustUser::Node)]
+    );
+
+    // ...nor the other way round. Lowering a limit leaves those connected already alone.
+    service0.set_peer_limits(Some(2), Some(0));
+    let (_client2, accepted) = bootstrap(CrustUser::Client);
+    assert!(accepted);
+    let (_node2, accepted) = bootstrap(CrustUser::Node);
+    assert!(!accepted);
+    assert!(service0.is_connected(&node0.id()));
+
+    let stats = unwrap!(service0.core_stats());
+    assert_eq!((stats.client_peers, stats.node_peers), (2, 1));
+    assert_eq!((stats.client_handshakes, stats.node_handshakes), (0, 0));
+    assert_eq!((stats.clients_rejected, stats.nodes_rejected), (1, 2));
+}
+
+#[test]
+fn banned_and_unwhitelisted_peers_are_rejected() {
+    let mut config0 = gen_config();
+    config0.dev = Some(DevConfig {
+        disable_external_reachability_requirement: true,
+    });
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    let addr0 = localhost_contact_info(port0);
+
+    let bootstrap = |kind: CrustUser| {
+        let mut config = gen_config();
+        config.hard_coded_contacts = vec![addr0];
+        let (event_tx, event_rx) = get_event_sender();
+        let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+        unwrap!(service.start_bootstrap(HashSet::new(), kind));
+        (service, event_rx)
+    };
+
+    // Whitelisting an IP for nodes keeps out the nodes elsewhere, but not the clients.
+    service0.whitelist_ip(unwrap!(IpAddr::from_str("192.0.2.1")), CrustUser::Node);
+    let (node, node_rx) = bootstrap(CrustUser::Node);
+    expect_event!(node_rx, Event::BootstrapAttemptFailed(_, rejection) => {
+        assert_eq!(rejection.kind(), RejectionCode::NotWhitelisted);
+    });
+    expect_event!(event_rx0, Event::PeerRejected { peer_id, reason, .. } => {
+        assert_eq!(peer_id, Some(node.id()));
+        assert_eq!(reason, RejectionCode::NotWhitelisted);
+    });
+
+    let (client, client_rx) = bootstrap(CrustUser::Client);
+    expect_event!(client_rx, Event::BootstrapConnect(..));
+    expect_event!(event_rx0, Event::BootstrapAccept(..));
+
+    // Banning a peer disconnects it, and drops its connections from then on.
+    unwrap!(service0.ban_peer(&client.id(), Duration::from_secs(60)));
+    expect_event!(event_rx0, Event::LostPeer(id, DisconnectReason::LocalRequested(0), _) => {
+        assert_eq!(id, client.id());
+    });
+    let (_client, _client_rx) = bootstrap(CrustUser::Client);
+    expect_event!(event_rx0, Event::PeerRejected { addr, peer_id: None, reason } => {
+        assert!(addr.ip().is_loopback());
+        assert_eq!(reason, RejectionCode::Banned);
+    });
+    assert!(service0.ban_peer(&client.id(), Duration::from_secs(60)).is_err());
+}
+
 #[test]
 fn bootstrap_timeouts_if_there_are_only_invalid_contacts() {
     use std::net::TcpListener;
@@ -278,11 +2466,129 @@ fn bootstrap_timeouts_if_there_are_only_invalid_contacts() {
     let mut config = gen_config();
     config.hard_coded_contacts = vec![address];
 
+    let clock = VirtualClock::new();
     let (event_tx, event_rx) = get_event_sender();
-    let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+    let (mut service, mut core) = unwrap!(Service::with_virtual_clock(
+        event_tx,
+        config,
+        rand::random(),
+        clock.clone()
+    ));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx, Event::BootstrapFailed);
+    for _ in 0..10 {
+        run_cores(&mut [&mut core]);
+    }
+    assert!(event_rx.try_recv().is_err());
+
+    clock.advance(Duration::from_secs(main::BOOTSTRAP_TIMEOUT_SEC));
+    match run_cores_until_event(&mut [&mut core], &event_rx) {
+        Event::BootstrapFailed => (),
+        event => panic!("unexpected event {:?}", event),
+    }
+}
+
+#[test]
+fn stopping_bootstrap_races_reports_at_most_one_outcome() {
+    use rand::Rng;
+
+    const ITERATIONS: usize = 200;
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    let mut rng = rand::thread_rng();
+    for i in 0..ITERATIONS {
+        unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+        let peer_id0 = if i % 4 == 0 {
+            // Left alone, the bootstrap succeeds exactly once.
+            expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => Some(peer_id))
+        } else {
+            // Stopped around the time the handshake completes, it succeeds or is never heard of
+            // again, and never fails.
+            thread::sleep(Duration::new(0, rng.gen_range(0, 2_000_000)));
+            unwrap!(service1.stop_bootstrap());
+            // Whatever the bootstrap reports, it has once the stop has been handled.
+            let _ = unwrap!(service1.core_stats());
+            let mut outcome = None;
+            while let Ok(event) = event_rx1.try_recv() {
+                match event {
+                    Event::BootstrapConnect(peer_id, _, _) if outcome.is_none() => {
+                        outcome = Some(peer_id)
+                    }
+                    event => panic!("Unexpected event in iteration {}: {:?}", i, event),
+                }
+            }
+            outcome
+        };
+
+        if let Some(peer_id0) = peer_id0 {
+            assert_eq!(peer_id0, service0.id());
+            assert!(service1.disconnect(&peer_id0));
+            expect_event!(event_rx1, Event::LostPeer(peer_id, _, _) => {
+                assert_eq!(peer_id, peer_id0)
+            });
+        }
+    }
+
+    // Nothing turns up late either.
+    thread::sleep(Duration::from_millis(500));
+    assert!(event_rx1.try_recv().is_err());
+}
+
+#[test]
+fn dropping_the_event_receiver_drains_the_service() {
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, gen_config(), rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (mut service1, mut core1) = unwrap!(Service::with_external_loop(
+        event_tx1,
+        config1,
+        rand::random()
+    ));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id0 = match run_cores_until_event(&mut [&mut core1], &event_rx1) {
+        Event::BootstrapConnect(peer_id, _, _) => peer_id,
+        event => panic!("unexpected event {:?}", event),
+    };
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    // Messages keep coming in after the receiver is gone.
+    for i in 0..100 {
+        unwrap!(service0.send(&peer_id1, vec![i; 1024], 1));
+    }
+    drop(event_rx1);
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while unwrap!(core1.run_once(Duration::from_millis(10))) {
+        assert!(Instant::now() < deadline, "the event loop didn't exit");
+    }
+    expect_event!(event_rx0, Event::LostPeer(peer_id, _, _) => assert_eq!(peer_id, peer_id1));
+
+    match service1.send(&peer_id0, vec![1], 1) {
+        Err(CrustError::ShuttingDown) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
+    match service1.start_listening_tcp() {
+        Err(CrustError::ShuttingDown) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
 }
 
 #[test]
@@ -303,12 +2609,12 @@ fn drop_disconnects() {
 
     unwrap!(service_1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _) => peer_id);
-    expect_event!(event_rx_0, Event::BootstrapAccept(_peer_id, _));
+    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    expect_event!(event_rx_0, Event::BootstrapAccept(_peer_id, _, _));
 
     // Dropping service_0 should make service_1 receive a LostPeer event.
     drop(service_0);
-    expect_event!(event_rx_1, Event::LostPeer(peer_id) => {
+    expect_event!(event_rx_1, Event::LostPeer(peer_id, _, _) => {
         assert_eq!(peer_id, peer_id_0)
     });
 }
@@ -404,6 +2710,7 @@ mod broken_peer {
 fn drop_peer_when_no_message_received_within_inactivity_period() {
     use self::broken_peer;
     use common::{spawn_event_loop, CoreMessage};
+    use main::INACTIVITY_TIMEOUT_MS;
     use mio::tcp::TcpListener;
     use rust_sodium;
 
@@ -426,43 +2733,87 @@ fn drop_peer_when_no_message_received_within_inactivity_period() {
     let mut config = gen_config();
     config.hard_coded_contacts = vec![address];
 
+    let clock = VirtualClock::new();
     let (event_tx, event_rx) = get_event_sender();
-    let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+    let (mut service, mut core) = unwrap!(Service::with_virtual_clock(
+        event_tx,
+        config,
+        rand::random(),
+        clock.clone()
+    ));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id = match run_cores_until_event(&mut [&mut core], &event_rx) {
+        Event::BootstrapConnect(peer_id, _, _) => peer_id,
+        event => panic!("unexpected event {:?}", event),
+    };
 
     // The peer should drop after inactivity.
-    expect_event!(event_rx, Event::LostPeer(lost_peer_id) => {
-        assert_eq!(lost_peer_id, peer_id)
-    });
+    clock.advance(Duration::from_millis(INACTIVITY_TIMEOUT_MS));
+    match run_cores_until_event(&mut [&mut core], &event_rx) {
+        Event::LostPeer(lost_peer_id, DisconnectReason::ConnectionLost, _) => {
+            assert_eq!(lost_peer_id, peer_id)
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
 }
 
 #[test]
 fn do_not_drop_peer_even_when_no_data_messages_are_exchanged_within_inactivity_period() {
-    use main::INACTIVITY_TIMEOUT_MS;
-    use std::thread;
-    use std::time::Duration;
+    use main::{HEARTBEAT_PERIOD_MS, INACTIVITY_TIMEOUT_MS};
+
+    let clock = VirtualClock::new();
 
     let config0 = gen_config();
     let (event_tx0, event_rx0) = get_event_sender();
-    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    let (mut service0, mut core0) = unwrap!(Service::with_virtual_clock(
+        event_tx0,
+        config0,
+        rand::random(),
+        clock.clone()
+    ));
 
     unwrap!(service0.start_listening_tcp());
-    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
-    unwrap!(service0.set_accept_bootstrap(true));
+    run_cores(&mut [&mut core0]);
+    // Lets the port mapping of the listener give up on the gateway, should there be one.
+    clock.advance(Duration::from_secs(60));
+    let port0 = match run_cores_until_event(&mut [&mut core0], &event_rx0) {
+        Event::ListenerStarted(port) => port,
+        event => panic!("unexpected event {:?}", event),
+    };
+    let _service0 = run_cores_alongside(&mut [&mut core0], move || {
+        unwrap!(service0.set_accept_bootstrap(true));
+        service0
+    });
 
     let mut config1 = gen_config();
     config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
 
     let (event_tx1, event_rx1) = get_event_sender();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    let (mut service1, mut core1) = unwrap!(Service::with_virtual_clock(
+        event_tx1,
+        config1,
+        rand::random(),
+        clock.clone()
+    ));
 
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
-    expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _));
+    match run_cores_until_event(&mut [&mut core0, &mut core1], &event_rx1) {
+        Event::BootstrapConnect(..) => (),
+        event => panic!("unexpected event {:?}", event),
+    }
+    match run_cores_until_event(&mut [&mut core0, &mut core1], &event_rx0) {
+        Event::BootstrapAccept(..) => (),
+        event => panic!("unexpected event {:?}", event),
+    }
 
-    thread::sleep(Duration::from_millis(2 * INACTIVITY_TIMEOUT_MS));
+    // The heartbeats due after each step are exchanged before the next one.
+    for _ in 0..2 * INACTIVITY_TIMEOUT_MS / HEARTBEAT_PERIOD_MS {
+        clock.advance(Duration::from_millis(HEARTBEAT_PERIOD_MS));
+        for _ in 0..10 {
+            run_cores(&mut [&mut core0, &mut core1]);
+        }
+    }
 
     if let Ok(Event::LostPeer(..)) = event_rx0.try_recv() {
         panic!("peer lost unexpectedly");
@@ -0,0 +1,626 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+// Scripted scenarios of many services on one virtual clock, each on an event loop run in turns by
+// the simulation. Whatever a scenario leaves to chance - the ids of the nodes and whom each one
+// bootstraps off - is drawn from its seed, which a failure reports along with the steps taken.
+// The seed only reproduces those steps, not the run: the services' sockets and threads are real,
+// so the order in which their events arrive may differ from one run to the next.
+// There is no simulated network underneath: the services talk over loopback TCP, and a partition
+// is kept by the simulation, which cuts the connections across it and bootstraps no node across
+// it, rather than by dropping packets.
+//
+// After every step the services are run until they settle, and the simulation fails if any
+// reported a connection to a peer it was connected to already or the loss of one it wasn't, if
+// the two ends of a connection disagree, or if a state outlived its connection.
+
+use super::utils::{get_event_sender, UniqueId};
+use super::{localhost, Service};
+use common::{CrustUser, VirtualClock};
+use main::{now_secs, BootstrapCacheEntry, Cache, Config, Event, ServiceCore, HEARTBEAT_PERIOD_MS};
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+use std::cmp;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+use test_utils::SERVICE_STATES;
+
+/// Rounds in a row without an event after which the services count as settled, if they pass the
+/// checks of a settled network.
+const QUIET_ROUNDS: usize = 20;
+/// Rounds after which a step which is still waiting for the services fails the scenario.
+const MAX_ROUNDS: usize = 20_000;
+/// Steps in which `Simulation::advance` moves the clock on, short enough for every heartbeat to
+/// arrive before the peer gives up on it.
+const TICK_MS: u64 = HEARTBEAT_PERIOD_MS / 3;
+
+struct Node {
+    /// Kept across restarts.
+    id: UniqueId,
+    /// Address of the listener of the node.
+    addr: SocketAddr,
+    /// `None` while the node is down.
+    running: Option<Running>,
+    /// Everything the node reported, across restarts.
+    log: Vec<Event<UniqueId>>,
+    /// Events of the log before this one have been looked through by `expect_event`.
+    seen: usize,
+    /// Who the node reported being connected to.
+    peers: HashSet<UniqueId>,
+}
+
+struct Running {
+    /// Lent to another thread while one of its methods waits for the event loop, see
+    /// `Simulation::with_service`.
+    service: Option<Service>,
+    core: ServiceCore,
+    events: Receiver<Event<UniqueId>>,
+}
+
+/// A network of services whose event loops run in turns on the thread of the scenario.
+pub struct Simulation {
+    seed: u64,
+    rng: XorShiftRng,
+    clock: VirtualClock,
+    /// The bootstrap cache shared by the nodes, in which every node is entered as it starts.
+    cache_name: String,
+    nodes: Vec<Node>,
+    /// The group of each node while the network is partitioned.
+    partition: Option<Vec<usize>>,
+    /// The steps so far, for the report of a failure.
+    script: Vec<String>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        // `XorShiftRng` takes no seed of all zeros.
+        let rng_seed = [0x9e37_79b9, (seed >> 32) as u32, seed as u32, 0x7f4a_7c15];
+        Simulation {
+            seed,
+            rng: XorShiftRng::from_seed(rng_seed),
+            clock: VirtualClock::new(),
+            cache_name: format!(
+                "crust-simulation-{:016x}.bootstrap.cache",
+                rand::random::<u64>()
+            ),
+            nodes: Vec::new(),
+            partition: None,
+            script: Vec::new(),
+        }
+    }
+
+    /// Starts a node which bootstraps off one of the running nodes, picked at random, or which
+    /// starts the network if there are none. Returns its index.
+    pub fn spawn_node(&mut self) -> usize {
+        let index = self.nodes.len();
+        self.step(format!("spawn node {}", index));
+        if self.partition.is_some() {
+            self.fail("Nodes can't be spawned into a partitioned network");
+        }
+
+        let id = self.rng.gen();
+        self.nodes.push(Node {
+            id,
+            addr: localhost(0),
+            running: None,
+            log: Vec::new(),
+            seen: 0,
+            peers: HashSet::new(),
+        });
+        self.start(index);
+        if let Some(target) = self.pick_target(index) {
+            self.bootstrap(index, target);
+        }
+        index
+    }
+
+    /// Stops node `index` abruptly, as if its process was killed.
+    pub fn kill(&mut self, index: usize) {
+        self.step(format!("kill node {}", index));
+        {
+            let node = &mut self.nodes[index];
+            // The service goes first, for its loop to still take the message that it's gone.
+            if let Some(mut running) = node.running.take() {
+                drop(running.service.take());
+            }
+            node.peers.clear();
+        }
+        self.settle();
+    }
+
+    /// Kills node `index` and starts it again under the same id, bootstrapping off one of the
+    /// running nodes it can reach, picked at random.
+    pub fn restart(&mut self, index: usize) {
+        self.kill(index);
+        self.step(format!("restart node {}", index));
+        self.start(index);
+        if let Some(target) = self.pick_target(index) {
+            self.bootstrap(index, target);
+        }
+    }
+
+    /// Has node `index` bootstrap off node `target`, which is the only one of the cached peers it
+    /// is let dial.
+    pub fn bootstrap(&mut self, index: usize, target: usize) {
+        self.step(format!("node {} bootstraps off node {}", index, target));
+        if !self.reachable(index, target) {
+            self.fail("The nodes are on different sides of the partition");
+        }
+
+        let target_addr = self.nodes[target].addr;
+        let target_id = self.nodes[target].id;
+        let blacklist: HashSet<SocketAddr> = unwrap!(self.cache().snapshot())
+            .into_iter()
+            .map(|entry| entry.addr)
+            .filter(|addr| *addr != target_addr)
+            .collect();
+        {
+            let service = unwrap!(self.running_mut(index).service.as_mut());
+            unwrap!(service.start_bootstrap(blacklist, CrustUser::Node));
+        }
+
+        let connected = self.expect_event(index, "BootstrapConnect", |event| match *event {
+            Event::BootstrapConnect(peer, _, _) => Some(peer == target_id),
+            Event::BootstrapFailed => Some(false),
+            _ => None,
+        });
+        if !connected {
+            self.fail(&format!("Node {} didn't bootstrap off node {}", index, target));
+        }
+        self.settle();
+    }
+
+    /// Partitions the network into `groups`, which together have to hold every node: connections
+    /// across groups are cut, and no node is bootstrapped off one of another group until `heal`.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        self.step(format!("partition into {:?}", groups));
+        let mut group_of = vec![None; self.nodes.len()];
+        for (group, members) in groups.iter().enumerate() {
+            for &index in members.iter() {
+                group_of[index] = Some(group);
+            }
+        }
+        let group_of = match group_of.into_iter().collect::<Option<Vec<usize>>>() {
+            Some(group_of) => group_of,
+            None => self.fail("Every node has to be in a group of the partition"),
+        };
+        self.partition = Some(group_of);
+
+        for index in 0..self.nodes.len() {
+            for other in self.peers(index) {
+                if other > index && !self.reachable(index, other) {
+                    let other_id = self.nodes[other].id;
+                    let service = unwrap!(self.running_mut(index).service.as_ref());
+                    let _ = service.disconnect(&other_id);
+                }
+            }
+        }
+        self.settle();
+    }
+
+    /// Lifts the partition and joins the network up again, see `join_up`.
+    pub fn heal(&mut self) {
+        self.step("heal the partition".to_owned());
+        self.partition = None;
+        self.join_up();
+    }
+
+    /// Bootstraps a node of the second largest group of connected nodes off one of the largest,
+    /// both picked at random, until all the running nodes are connected.
+    pub fn join_up(&mut self) {
+        loop {
+            let mut components = self.components();
+            if components.len() < 2 {
+                return;
+            }
+            // Stable, so ties are broken by the lowest index.
+            components.sort_by_key(|component| cmp::Reverse(component.len()));
+            let index = *unwrap!(self.rng.choose(&components[1]));
+            let target = *unwrap!(self.rng.choose(&components[0]));
+            self.bootstrap(index, target);
+        }
+    }
+
+    /// Moves the clock on by `duration` in steps of `TICK_MS`, letting the services settle after
+    /// each.
+    pub fn advance(&mut self, duration: Duration) {
+        self.step(format!("advance the clock by {:?}", duration));
+        let tick = Duration::from_millis(TICK_MS);
+        let mut left = duration;
+        while left > Duration::from_secs(0) {
+            let step = cmp::min(left, tick);
+            self.clock.advance(step);
+            left -= step;
+            self.settle();
+        }
+    }
+
+    /// Runs the services until node `index` reports an event for which `f` returns `Some`, and
+    /// returns what it returned. Events the node reported before, but which weren't looked through
+    /// by an earlier call, count too.
+    pub fn expect_event<T, F>(&mut self, index: usize, expected: &str, mut f: F) -> T
+    where
+        F: FnMut(&Event<UniqueId>) -> Option<T>,
+    {
+        for _ in 0..MAX_ROUNDS {
+            while self.nodes[index].seen < self.nodes[index].log.len() {
+                let node = &mut self.nodes[index];
+                node.seen += 1;
+                if let Some(res) = f(&node.log[node.seen - 1]) {
+                    return res;
+                }
+            }
+            let _ = self.round();
+        }
+        self.fail(&format!("Node {} didn't report {}", index, expected))
+    }
+
+    /// The nodes node `index` is connected to.
+    pub fn peers(&self, index: usize) -> Vec<usize> {
+        let mut peers: Vec<usize> = self.nodes[index]
+            .peers
+            .iter()
+            .filter_map(|peer| self.index_of(peer))
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// The groups of running nodes connected to each other, each sorted and ordered by their
+    /// first node.
+    pub fn components(&self) -> Vec<Vec<usize>> {
+        let mut components = Vec::new();
+        let mut visited = HashSet::new();
+        for start in 0..self.nodes.len() {
+            if self.nodes[start].running.is_none() || !visited.insert(start) {
+                continue;
+            }
+            let mut component = vec![start];
+            let mut next = 0;
+            while next < component.len() {
+                for peer in self.peers(component[next]) {
+                    if visited.insert(peer) {
+                        component.push(peer);
+                    }
+                }
+                next += 1;
+            }
+            component.sort();
+            components.push(component);
+        }
+        components
+    }
+
+    /// Every connection, as the pair of the indices of its ends, lower first.
+    pub fn topology(&self) -> BTreeSet<(usize, usize)> {
+        (0..self.nodes.len())
+            .flat_map(|index| {
+                self.peers(index)
+                    .into_iter()
+                    .map(move |peer| (cmp::min(index, peer), cmp::max(index, peer)))
+            })
+            .collect()
+    }
+
+    fn step(&mut self, step: String) {
+        debug!("Simulation step: {}", step);
+        self.script.push(step);
+    }
+
+    fn fail(&self, what: &str) -> ! {
+        panic!(
+            "{}\nSeed: {}\nSteps:\n    {}",
+            what,
+            self.seed,
+            self.script.join("\n    ")
+        )
+    }
+
+    fn cache(&self) -> Cache {
+        unwrap!(Cache::new(&Some(self.cache_name.clone())))
+    }
+
+    fn running_mut(&mut self, index: usize) -> &mut Running {
+        unwrap!(self.nodes[index].running.as_mut())
+    }
+
+    fn index_of(&self, id: &UniqueId) -> Option<usize> {
+        self.nodes.iter().position(|node| node.id == *id)
+    }
+
+    fn describe(&self, id: &UniqueId) -> String {
+        match self.index_of(id) {
+            Some(index) => format!("node {}", index),
+            None => format!("unknown peer {:?}", id),
+        }
+    }
+
+    fn reachable(&self, index: usize, other: usize) -> bool {
+        self.partition
+            .as_ref()
+            .map_or(true, |group_of| group_of[index] == group_of[other])
+    }
+
+    /// Picks a running node other than `index` which `index` can reach, if there is one.
+    fn pick_target(&mut self, index: usize) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.nodes.len())
+            .filter(|&other| {
+                other != index
+                    && self.nodes[other].running.is_some()
+                    && self.reachable(index, other)
+            })
+            .collect();
+        self.rng.choose(&candidates).cloned()
+    }
+
+    /// Starts the service of node `index`, listening and accepting bootstrapping peers, and enters
+    /// it into the bootstrap cache.
+    fn start(&mut self, index: usize) {
+        let (event_tx, events) = get_event_sender();
+        let mut config = Config::default();
+        config.bootstrap_cache_name = Some(self.cache_name.clone());
+        // Nothing to map ports with or to check reachability against.
+        config.lan_only = true;
+        let (mut service, core) = unwrap!(Service::with_virtual_clock(
+            event_tx,
+            config,
+            self.nodes[index].id,
+            self.clock.clone()
+        ));
+        unwrap!(service.start_listening_tcp());
+        {
+            let node = &mut self.nodes[index];
+            node.running = Some(Running {
+                service: Some(service),
+                core,
+                events,
+            });
+            node.seen = node.log.len();
+        }
+
+        let port = self.expect_event(index, "ListenerStarted", |event| match *event {
+            Event::ListenerStarted(port) => Some(port),
+            _ => None,
+        });
+        let addr = localhost(port);
+        self.nodes[index].addr = addr;
+        let entry = BootstrapCacheEntry {
+            addr,
+            last_seen: now_secs(),
+            health: Default::default(),
+        };
+        unwrap!(self.cache().merge_entries(&[entry]));
+        self.with_service(index, |service| unwrap!(service.set_accept_bootstrap(true)));
+        self.settle();
+    }
+
+    /// Calls `f` with the service of node `index` on a thread of its own while running the
+    /// services, so that it can call the methods which wait for the event loop.
+    fn with_service<T, F>(&mut self, index: usize, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&Service) -> T + Send + 'static,
+    {
+        let service = unwrap!(self.running_mut(index).service.take());
+        let (tx, rx) = mpsc::channel();
+        let _ = thread::spawn(move || {
+            let res = f(&service);
+            let _ = tx.send((service, res));
+        });
+
+        for _ in 0..MAX_ROUNDS {
+            if let Ok((service, res)) = rx.try_recv() {
+                self.running_mut(index).service = Some(service);
+                return res;
+            }
+            let _ = self.round();
+        }
+        self.fail(&format!("The service of node {} didn't answer", index))
+    }
+
+    /// Runs every running service once, without waiting for its sockets, and takes in what they
+    /// reported. Returns whether any reported anything.
+    fn round(&mut self) -> bool {
+        let mut reported = false;
+        for index in 0..self.nodes.len() {
+            let events: Vec<_> = match self.nodes[index].running {
+                Some(ref mut running) => {
+                    assert!(unwrap!(running.core.run_once(Duration::from_millis(0))));
+                    running.events.try_iter().collect()
+                }
+                None => continue,
+            };
+            for event in events {
+                reported = true;
+                self.take_in(index, event);
+            }
+        }
+        reported
+    }
+
+    /// Keeps track of the peers of node `index`, failing the scenario if `event` reports a second
+    /// connection to a peer or the loss of one which wasn't connected.
+    fn take_in(&mut self, index: usize, event: Event<UniqueId>) {
+        let violation = match event {
+            Event::BootstrapConnect(ref peer, _, _)
+            | Event::BootstrapAccept(ref peer, _, _)
+            | Event::ConnectSuccess(ref peer, _) => {
+                if self.nodes[index].peers.insert(*peer) {
+                    None
+                } else {
+                    Some(format!("a second connection to {}", self.describe(peer)))
+                }
+            }
+            Event::LostPeer(ref peer, ..) => {
+                if self.nodes[index].peers.remove(peer) {
+                    None
+                } else {
+                    Some(format!(
+                        "the loss of {}, which it wasn't connected to",
+                        self.describe(peer)
+                    ))
+                }
+            }
+            _ => None,
+        };
+        if let Some(violation) = violation {
+            self.fail(&format!("Node {} reported {}: {:?}", index, violation, event));
+        }
+        self.nodes[index].log.push(event);
+    }
+
+    /// Runs the services until they have been quiet for a while and pass the checks of a settled
+    /// network, see `unsettled`.
+    fn settle(&mut self) {
+        let mut quiet_rounds = 0;
+        for _ in 0..MAX_ROUNDS {
+            if self.round() {
+                quiet_rounds = 0;
+            } else {
+                quiet_rounds += 1;
+            }
+            if quiet_rounds >= QUIET_ROUNDS && self.unsettled().is_empty() {
+                return;
+            }
+        }
+        let unsettled = self.unsettled();
+        self.fail(&format!("The network didn't settle: {}", unsettled.join("; ")))
+    }
+
+    /// What is amiss for a settled network: connections only one end reported, and states left
+    /// on an event loop other than those of the service and of its connections.
+    fn unsettled(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let running = match node.running {
+                Some(ref running) => running,
+                None => continue,
+            };
+
+            for peer in &node.peers {
+                let mutual = self
+                    .index_of(peer)
+                    .map_or(false, |other| self.nodes[other].peers.contains(&node.id));
+                if !mutual {
+                    problems.push(format!(
+                        "node {} is connected to {} at its end only",
+                        index,
+                        self.describe(peer)
+                    ));
+                }
+            }
+
+            let stats = running.core.core_stats();
+            for (name, kind) in &stats.states {
+                let expected = if *name == "ActiveConnection" {
+                    node.peers.len()
+                } else if SERVICE_STATES.contains(name) {
+                    continue;
+                } else {
+                    0
+                };
+                if kind.live != expected {
+                    problems.push(format!(
+                        "node {} runs {} x{} rather than x{}",
+                        index, name, kind.live, expected
+                    ));
+                }
+            }
+            if !node.peers.is_empty() && !stats.states.contains_key("ActiveConnection") {
+                problems.push(format!(
+                    "node {} runs no ActiveConnection for its {} peers",
+                    index,
+                    node.peers.len()
+                ));
+            }
+        }
+        problems
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        if let Ok(cache) = Cache::new(&Some(self.cache_name.clone())) {
+            let _ = fs::remove_file(cache.path());
+        }
+    }
+}
+
+#[test]
+fn twenty_nodes_bootstrap_into_one_network() {
+    let seed = rand::random();
+    let run = || {
+        let mut sim = Simulation::new(seed);
+        for _ in 0..20 {
+            let _ = sim.spawn_node();
+        }
+        assert_eq!(sim.components().len(), 1);
+
+        // Heartbeats keep the idle network up.
+        sim.advance(Duration::from_secs(3));
+        assert_eq!(sim.components().len(), 1);
+        (sim.script.clone(), sim.topology())
+    };
+
+    // Each node joined through a connection of its own.
+    let (script, topology) = run();
+    assert_eq!(topology.len(), 19);
+    assert_eq!(run().0, script, "Seed {} drew other steps", seed);
+}
+
+#[test]
+fn partitioned_network_heals() {
+    let mut sim = Simulation::new(rand::random());
+    for _ in 0..10 {
+        let _ = sim.spawn_node();
+    }
+
+    sim.partition(&[&[0, 1, 2, 3, 4], &[5, 6, 7, 8, 9]]);
+    for index in 0..10 {
+        assert!(sim.peers(index).iter().all(|&peer| (peer < 5) == (index < 5)));
+    }
+    sim.advance(Duration::from_secs(2));
+    // A node coming back rejoins its own side.
+    sim.restart(7);
+    assert!(!sim.peers(7).is_empty());
+    assert!(sim.peers(7).iter().all(|&peer| peer >= 5));
+
+    sim.heal();
+    assert_eq!(sim.components(), vec![(0..10).collect::<Vec<_>>()]);
+    sim.advance(Duration::from_secs(1));
+    assert_eq!(sim.components().len(), 1);
+}
+
+#[test]
+fn rolling_restart_keeps_the_network_together() {
+    let mut sim = Simulation::new(rand::random());
+    for _ in 0..8 {
+        let _ = sim.spawn_node();
+    }
+
+    for index in 0..8 {
+        let peers = sim.peers(index);
+        let id = sim.nodes[index].id;
+        sim.restart(index);
+        for peer in peers {
+            sim.expect_event(peer, "LostPeer", |event| match *event {
+                Event::LostPeer(lost, ..) if lost == id => Some(()),
+                _ => None,
+            });
+        }
+        // Back under the same id, through a single connection.
+        assert_eq!(sim.peers(index).len(), 1);
+        sim.join_up();
+        sim.advance(Duration::from_millis(500));
+    }
+    assert_eq!(sim.components().len(), 1);
+}
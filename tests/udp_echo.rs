@@ -0,0 +1,62 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Runs the state of the `udp_echo` example on the event loop of a service.
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate unwrap;
+extern crate crust;
+extern crate maidsafe_utilities;
+extern crate mio;
+extern crate rand;
+
+#[path = "../examples/udp_echo/echo.rs"]
+mod echo;
+
+use crust::{Config, Event, Service, Uid};
+use echo::UdpEcho;
+use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+struct UniqueId(u64);
+impl Uid for UniqueId {}
+
+#[test]
+fn external_state_echoes_until_the_service_is_dropped() {
+    let (event_tx, _event_rx) = mpsc::channel::<Event<UniqueId>>();
+    let (category_tx, _) = mpsc::channel();
+    let event_tx = MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx);
+    let service = unwrap!(Service::with_config(
+        event_tx,
+        Config::default(),
+        UniqueId(rand::random())
+    ));
+    let echo_addr = unwrap!(UdpEcho::start(&service, unwrap!("127.0.0.1:0".parse())));
+
+    let client = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    unwrap!(client.set_read_timeout(Some(Duration::from_secs(5))));
+    let mut buf = [0; 64];
+    for datagram in &[&b"ping"[..], &b"pong"[..]] {
+        let _ = unwrap!(client.send_to(datagram, echo_addr));
+        let (len, from) = unwrap!(client.recv_from(&mut buf));
+        assert_eq!(from, echo_addr);
+        assert_eq!(&buf[..len], *datagram);
+    }
+
+    // The echo is terminated with the rest of the event loop.
+    drop(service);
+    unwrap!(client.set_read_timeout(Some(Duration::from_millis(500))));
+    let _ = client.send_to(b"gone", echo_addr);
+    assert!(client.recv_from(&mut buf).is_err());
+}